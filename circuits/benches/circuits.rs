@@ -0,0 +1,274 @@
+use std::{
+    env,
+    hint::black_box,
+    sync::Arc,
+    time::{ Duration, Instant },
+};
+
+use openplasma_circuits::{
+    block::BlockBuilder,
+    data_structs::{ deposit::Deposit, offchain_deposit::OffchainDeposit },
+    family::BatchConfig,
+    types::{ AccountId, Balance },
+    deposit_circuit::{ DepositCircuit, DepositBatchCircuit },
+    params::{ Params, shared_params, poseidon_params, jubjub_params },
+    tree::account::AccountsTree,
+    tree::leaf::LeafVersion,
+    utils::{ op_type::DEPOSIT_OP, utils::{ usize_to_fr, u128_to_fr } },
+};
+
+use sapling_crypto_ce::{
+    poseidon::poseidon_hash,
+    jubjub::FixedGenerators,
+    eddsa::{ PublicKey, PrivateKey },
+};
+
+use pairing_ce::bn256::{ self, Bn256 };
+
+use ff_ce::Field;
+
+use rand::{ Rng, SeedableRng, XorShiftRng };
+
+const TOKEN_DEPTH: usize = 4;
+const WITNESS_BATCH: usize = 16;
+const WITNESS_DEPTH: usize = 8;
+
+const WARM_UP: Duration = Duration::from_secs(1);
+const MEASUREMENT: Duration = Duration::from_secs(5);
+const MIN_SAMPLES: u32 = 10;
+const MAX_SAMPLES: u32 = 50;
+
+// the measurement criterion does: warm up, then samples of the same number of
+// iterations for the measurement time, reported as the fastest, median and
+// slowest sample per iteration. a name filter can be passed as for criterion:
+// cargo bench --bench circuits [-- filter]
+struct Bencher {
+    filter: Option::<String>,
+}
+
+impl Bencher {
+    fn from_args() -> Self {
+        Bencher { filter: env::args().skip(1).find(|arg| !arg.starts_with('-')) }
+    }
+
+    fn enabled(&self, name: &str) -> bool {
+        self.filter.as_ref().is_none_or(|filter| name.contains(filter.as_str()))
+    }
+
+    fn bench<F: FnMut()>(&self, name: &str, mut routine: F) {
+        if !self.enabled(name) {
+            return;
+        }
+
+        let start = Instant::now();
+        let mut warm_up_iters = 0u32;
+        while warm_up_iters == 0 || start.elapsed() < WARM_UP {
+            routine();
+            warm_up_iters += 1;
+        }
+        let estimate = start.elapsed() / warm_up_iters;
+
+        let samples = (MEASUREMENT.as_nanos() / estimate.as_nanos().max(1))
+            .clamp(MIN_SAMPLES as u128, MAX_SAMPLES as u128) as u32;
+        let iters = (MEASUREMENT / samples).as_nanos() / estimate.as_nanos().max(1);
+        let iters = iters.max(1) as u32;
+
+        let mut times: Vec<_> = (0..samples).map(|_| {
+            let start = Instant::now();
+            for _ in 0..iters {
+                routine();
+            }
+            start.elapsed() / iters
+        }).collect();
+        times.sort();
+
+        println!(
+            "{:<40} time: [{:?} {:?} {:?}] ({} samples of {})",
+            name, times[0], times[times.len() / 2], times[times.len() - 1], samples, iters,
+        );
+    }
+}
+
+// the circuit size is part of the performance, a change of it shows up here
+// even when the timings are noisy
+fn print_constraints(params: &Arc<Params<Bn256>>) {
+    for &account_depth in [8, 16, 24].iter() {
+        let single = DepositBatchCircuit::estimated_constraints(1, account_depth, TOKEN_DEPTH, params).unwrap();
+        let double = DepositBatchCircuit::estimated_constraints(2, account_depth, TOKEN_DEPTH, params).unwrap();
+        println!(
+            "deposit batch, depth {:>2}: {} constraints per deposit, {} fixed",
+            account_depth, double - single, 2 * single - double,
+        );
+    }
+}
+
+fn pubkeys(rng: &mut XorShiftRng, count: usize) -> Vec::<PublicKey::<Bn256>> {
+    (0..count).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    )).collect()
+}
+
+// the witness the operator builds for a full deposit batch
+fn deposit_batch_circuit(
+    tree: &mut AccountsTree,
+    deposits: &[Deposit],
+    account_depth: usize,
+    params: &Arc<Params<Bn256>>,
+) -> DepositBatchCircuit<Bn256> {
+    let old_root = tree.get_root();
+    let updates: Vec<_> = deposits.iter().map(|deposit| deposit.leaf_update()).collect();
+    let account_states = tree.apply_batch(&updates).unwrap();
+
+    let mut accum_hash = bn256::Fr::zero();
+    let deposit_queue: Vec<_> = deposits.iter().zip(account_states).map(|(deposit, account_state)| {
+        let pubkey = deposit.pubkey.clone().unwrap().0;
+        let (pubkey_x, pubkey_y) = pubkey.into_xy();
+        accum_hash = poseidon_hash::<Bn256>(
+            &params.hash_params,
+            &[
+                usize_to_fr(DEPOSIT_OP),
+                accum_hash,
+                pubkey_x,
+                pubkey_y,
+                usize_to_fr(deposit.account_id),
+                usize_to_fr(deposit.token_id),
+                u128_to_fr(deposit.amount),
+            ],
+        )[0];
+
+        DepositCircuit {
+            account_state,
+            pubkey: Some(pubkey),
+            account_id: Some(usize_to_fr(deposit.account_id)),
+            token_id: Some(usize_to_fr(deposit.token_id)),
+            amount: Some(u128_to_fr(deposit.amount)),
+            is_noop: Some(false),
+            is_refund: Some(false),
+        }
+    }).collect();
+
+    DepositBatchCircuit {
+        deposit_batch: deposits.len(),
+        account_depth,
+        token_depth: TOKEN_DEPTH,
+        params: Arc::clone(params),
+        reject_zero_amount: false,
+        leaf_version: LeafVersion::V0,
+        deposit_queue: deposit_queue.into(),
+        old_accum_hash: Some(bn256::Fr::zero()),
+        new_accum_hash: Some(accum_hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    }
+}
+
+fn random_deposits(rng: &mut XorShiftRng, count: usize, account_depth: usize) -> Vec::<Deposit> {
+    pubkeys(rng, count).into_iter().map(|pubkey| Deposit {
+        pubkey: Some(pubkey),
+        account_id: rng.gen_range(0, 1 << account_depth),
+        token_id: rng.gen_range(0, 1 << TOKEN_DEPTH),
+        amount: u128::from(rng.gen_range(1u32, 1000)),
+    }).collect()
+}
+
+// the tree is sparse, building it is depth hashes and an update rehashes one
+// path, the cost grows with the depth alone
+fn tree_update(bencher: &Bencher, account_depth: usize) {
+    let name = format!("accounts tree update, depth {}", account_depth);
+    if !bencher.enabled(&name) {
+        return;
+    }
+
+    let mut tree = AccountsTree::new(account_depth, TOKEN_DEPTH, poseidon_params(), jubjub_params());
+    let mut balance = 0;
+    bencher.bench(&name, || {
+        balance += 1;
+        tree.update_balance(1, 0, usize_to_fr(balance)).unwrap();
+    });
+}
+
+// the skewed block: every deposit to one of two hot accounts, built by the
+// block builder from cached paths and by applying the deposits one by one
+fn hot_account_witness(bencher: &Bencher, rng: &mut XorShiftRng, params: &Arc<Params<Bn256>>) {
+    let account_depth = 16;
+    let config = BatchConfig { deposit_batch: WITNESS_BATCH, account_depth, token_depth: TOKEN_DEPTH, leaf_version: LeafVersion::V0 };
+    let name = format!("hot account deposit witness, batch {}, depth {}", WITNESS_BATCH, account_depth);
+    if !bencher.enabled(&name) {
+        return;
+    }
+
+    let hot_accounts = pubkeys(rng, 2);
+    let deposits: Vec<_> = (0..WITNESS_BATCH).map(|i| OffchainDeposit {
+        account_id: AccountId(1 + (i % 2) as u32 * 1000),
+        pubkey: hot_accounts[i % 2].clone(),
+        token_id: 0,
+        amount: Balance(1),
+    }).collect();
+
+    let mut tree = AccountsTree::new(account_depth, TOKEN_DEPTH, poseidon_params(), jubjub_params());
+    bencher.bench(&format!("{}, cached", name), || {
+        let mut builder = BlockBuilder::new(&mut tree, config, params, bn256::Fr::zero()).unwrap();
+        for deposit in deposits.iter() {
+            builder.push_deposit(deposit.clone()).unwrap();
+        }
+    });
+    bencher.bench(&format!("{}, uncached", name), || {
+        for deposit in deposits.iter() {
+            black_box(deposit.update_tree_and_record_state(&mut tree).unwrap());
+        }
+    });
+}
+
+#[cfg(feature = "expensive-benches")]
+fn proving(bencher: &Bencher, rng: &mut XorShiftRng, params: &Arc<Params<Bn256>>) {
+    use bellman_ce::groth16::generate_random_parameters;
+    use openplasma_circuits::prover::prove_deposit_block;
+
+    let (deposit_batch, account_depth) = (4, 8);
+    let name = format!("groth16 deposit batch {}, depth {}", deposit_batch, account_depth);
+    if !bencher.enabled(&name) {
+        return;
+    }
+
+    let circuit_params = generate_random_parameters(
+        DepositBatchCircuit::for_setup(BatchConfig { deposit_batch, account_depth, token_depth: TOKEN_DEPTH, leaf_version: LeafVersion::V0 }, params),
+        rng,
+    ).unwrap();
+    let mut tree = AccountsTree::new(account_depth, TOKEN_DEPTH, poseidon_params(), jubjub_params());
+    let deposits = random_deposits(rng, deposit_batch, account_depth);
+    let circuit = deposit_batch_circuit(&mut tree, &deposits, account_depth, params);
+
+    bencher.bench(&name, || {
+        black_box(prove_deposit_block(&circuit_params, circuit.clone()).unwrap());
+    });
+}
+
+fn main() {
+    let bencher = Bencher::from_args();
+    let params = shared_params();
+    let mut rng = XorShiftRng::from_seed([0x3dbe_6259, 0x8d31_3d76, 0x3237_db17, 0xe5bc_0654]);
+
+    print_constraints(&params);
+
+    let inputs: Vec<bn256::Fr> = (0..5).map(|_| rng.gen()).collect();
+    bencher.bench("poseidon hash, 5 elements", || {
+        black_box(poseidon_hash::<Bn256>(poseidon_params(), black_box(&inputs)));
+    });
+
+    for &account_depth in [8, 16, 24, 32].iter() {
+        tree_update(&bencher, account_depth);
+    }
+
+    let mut tree = AccountsTree::new(WITNESS_DEPTH, TOKEN_DEPTH, poseidon_params(), jubjub_params());
+    let deposits = random_deposits(&mut rng, WITNESS_BATCH, WITNESS_DEPTH);
+    bencher.bench(&format!("deposit witness, batch {}, depth {}", WITNESS_BATCH, WITNESS_DEPTH), || {
+        black_box(deposit_batch_circuit(&mut tree, &deposits, WITNESS_DEPTH, &params));
+    });
+
+    hot_account_witness(&bencher, &mut rng, &params);
+
+    #[cfg(feature = "expensive-benches")]
+    proving(&bencher, &mut rng, &params);
+}
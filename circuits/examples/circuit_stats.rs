@@ -0,0 +1,34 @@
+use openplasma_circuits::{
+    deposit_circuit::DepositBatchCircuit,
+    params::shared_params,
+};
+
+const TOKEN_DEPTH: usize = 4;
+const ACCOUNT_DEPTHS: [usize; 4] = [8, 16, 24, 32];
+const DEPOSIT_BATCHES: [usize; 7] = [1, 2, 4, 8, 16, 32, 64];
+
+// prints estimated deposit batch constraints, no trusted setup or witness needed:
+// cargo run --release --example circuit_stats
+fn main() {
+    let params = shared_params();
+
+    print!("{:>13}", "depth / batch");
+    for batch in DEPOSIT_BATCHES.iter() {
+        print!("{:>12}", batch);
+    }
+    println!();
+
+    for &account_depth in ACCOUNT_DEPTHS.iter() {
+        print!("{:>13}", account_depth);
+        for &batch in DEPOSIT_BATCHES.iter() {
+            let constraints = DepositBatchCircuit::estimated_constraints(
+                batch,
+                account_depth,
+                TOKEN_DEPTH,
+                &params,
+            ).unwrap();
+            print!("{:>12}", constraints);
+        }
+        println!();
+    }
+}
@@ -0,0 +1,106 @@
+use std::{ env, fs };
+
+use openplasma_circuits::{
+    deposit_circuit::{ DepositCircuit, DepositBatchCircuit, DepositQueue },
+    family::BatchConfig,
+    params::{ shared_params, poseidon_params },
+    prover::{ generate_parameters, load_parameters },
+    tree::leaf::LeafVersion,
+    utils::{ op_type::DEPOSIT_OP, utils::usize_to_fr },
+};
+
+use bellman_ce::groth16::{ create_random_proof, prepare_verifying_key, verify_proof };
+
+use sapling_crypto_ce::poseidon::poseidon_hash;
+
+use pairing_ce::bn256::{ self, Bn256 };
+
+use ff_ce::Field;
+
+use rand::thread_rng;
+
+const TOKEN_DEPTH: usize = 4;
+
+// kB of a line of /proc/self/status, linux only
+fn status_kb(field: &str) -> usize {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|line| line.starts_with(field)).unwrap();
+    line.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+// peak resident memory of proving a batch of noop deposits, the witnesses are
+// of the full path length like real ones. the setup peaks far higher than
+// proving and is done once in a process of its own, so is each mode:
+// cargo run --release --example deposit_memory -- setup [batch, 8] [account depth, 16]
+// cargo run --release --example deposit_memory -- held [batch, 8] [account depth, 16]
+// cargo run --release --example deposit_memory -- lazy [batch, 8] [account depth, 16]
+fn main() {
+    let mode = env::args().nth(1).unwrap_or_else(|| "held".to_string());
+    let deposit_batch = env::args().nth(2).map_or(8, |batch| batch.parse().unwrap());
+    let account_depth = env::args().nth(3).map_or(16, |depth| depth.parse().unwrap());
+
+    let config = BatchConfig { deposit_batch, account_depth, token_depth: TOKEN_DEPTH, leaf_version: LeafVersion::V0 };
+    let path = env::temp_dir().join(format!("openplasma_memory_{}_{}.bin", deposit_batch, account_depth));
+    if mode == "setup" {
+        generate_parameters(config, &path).unwrap();
+        println!("key written to {}", path.display());
+        return;
+    }
+
+    let params = shared_params();
+    let mut rng = thread_rng();
+    let (_, circuit_params) = load_parameters(&path).expect("run the setup mode first");
+
+    // a noop deposit absorbs the zero point (0, 1) and zero id and amount
+    let mut accum_hash = bn256::Fr::zero();
+    for _ in 0..deposit_batch {
+        accum_hash = poseidon_hash::<Bn256>(
+            poseidon_params(),
+            &[
+                usize_to_fr(DEPOSIT_OP),
+                accum_hash,
+                bn256::Fr::zero(),
+                bn256::Fr::one(),
+                bn256::Fr::zero(),
+                bn256::Fr::zero(),
+                bn256::Fr::zero(),
+            ],
+        )[0];
+    }
+    let root = usize_to_fr(0);
+
+    let before = status_kb("VmHWM:");
+
+    let deposit_queue = match mode.as_str() {
+        "held" => DepositQueue::from(
+            vec![DepositCircuit::noop(account_depth, TOKEN_DEPTH); deposit_batch]
+        ),
+        "lazy" => DepositQueue::lazy(
+            move |_| DepositCircuit::noop(account_depth, TOKEN_DEPTH)
+        ),
+        _ => panic!("mode is held or lazy"),
+    };
+    let circuit = DepositBatchCircuit {
+        deposit_batch,
+        account_depth,
+        token_depth: TOKEN_DEPTH,
+        params,
+        reject_zero_amount: false,
+        leaf_version: LeafVersion::V0,
+        deposit_queue,
+        old_accum_hash: Some(bn256::Fr::zero()),
+        new_accum_hash: Some(accum_hash),
+        old_account_root: Some(root),
+        new_account_root: Some(root),
+    };
+    let proof = create_random_proof(circuit, &circuit_params, &mut rng).unwrap();
+    let peak = status_kb("VmHWM:");
+
+    let inputs = [bn256::Fr::zero(), accum_hash, root, root];
+    assert!(verify_proof(&prepare_verifying_key(&circuit_params.vk), &proof, &inputs).unwrap());
+
+    println!(
+        "{} witnesses, batch {}, depth {}: peak rss {} kB with the key loaded, {} kB after proving, {} kB more",
+        mode, deposit_batch, account_depth, before, peak, peak - before,
+    );
+}
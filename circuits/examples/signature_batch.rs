@@ -0,0 +1,71 @@
+use std::{ env, time::Instant };
+
+use openplasma_circuits::{
+    data_structs::{
+        offchain_withdrawal::OffchainWithdrawal,
+        batch_verification::{ verify_signatures_batch, verify_signatures_combined },
+    },
+    types::{ Balance, Nonce, AccountId },
+    keys::SecretKey,
+    utils::domain::SigningDomain,
+    params::{ poseidon_params, jubjub_params },
+};
+
+use sapling_crypto_ce::{
+    eddsa::PrivateKey,
+};
+
+use pairing_ce::bn256::Bn256;
+
+use rand::{ Rng, thread_rng };
+
+// verifies a queue of signed withdrawals one by one, with
+// verify_signatures_batch and with verify_signatures_combined:
+// cargo run --release --example signature_batch [queue length, 256 by default]
+// add --features parallel to spread the batch over threads
+fn main() {
+    let queue_len = env::args().nth(1).map_or(256, |len| len.parse().unwrap());
+
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let domain = SigningDomain::default();
+    let mut rng = thread_rng();
+
+    let requests: Vec<_> = (0..queue_len).map(|account_id| {
+        let seckey = SecretKey::from(PrivateKey::<Bn256>(rng.gen()));
+        let pubkey = seckey.public_key(sign_params);
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(account_id),
+            token_id: 0,
+            amount: Balance(u128::from(rng.gen_range(1u64, 1000))),
+            fee: Balance(1),
+            nonce: Nonce(1),
+            valid_until: 0,
+            eth_address: rng.gen(),
+            sign: None,
+        };
+        withdrawal.sign(&seckey, &domain, None, None, &mut rng);
+        (withdrawal, pubkey)
+    }).collect();
+
+    let started = Instant::now();
+    let sequential: Vec<_> = requests.iter().map(
+        |(withdrawal, pubkey)| withdrawal.verify_signature(pubkey, &domain, None, None)
+    ).collect();
+    let sequential_time = started.elapsed();
+
+    let started = Instant::now();
+    let batched = verify_signatures_batch(&requests, &domain, hash_params, sign_params);
+    let batched_time = started.elapsed();
+
+    let started = Instant::now();
+    let combined = verify_signatures_combined(&requests, &domain, hash_params, sign_params, &mut rng);
+    let combined_time = started.elapsed();
+
+    assert_eq!(sequential, batched);
+    assert_eq!(sequential, combined);
+
+    println!("{} signatures, sequential: {:?}", queue_len, sequential_time);
+    println!("{} signatures, batched:    {:?}", queue_len, batched_time);
+    println!("{} signatures, combined:   {:?}", queue_len, combined_time);
+}
@@ -0,0 +1,73 @@
+use std::{ env, time::Instant };
+
+use openplasma_circuits::{
+    data_structs::deposit::Deposit,
+    tree::account::AccountsTree,
+    params::{ poseidon_params, jubjub_params },
+};
+
+use sapling_crypto_ce::{
+    jubjub::FixedGenerators,
+    eddsa::{ PublicKey, PrivateKey },
+};
+
+use pairing_ce::bn256::Bn256;
+
+use rand::{ Rng, thread_rng };
+
+const TOKEN_DEPTH: usize = 1;
+const DEPOSIT_BATCH: usize = 64;
+
+// applies the same deposit block one by one and with apply_batch:
+// cargo run --release --example tree_batch [account depth, 24 by default]
+// the tree is kept in memory in full and built leaf by leaf, at depth 24 that
+// takes about an hour and several GB before anything is measured
+fn main() {
+    let account_depth = env::args().nth(1).map_or(24, |depth| depth.parse().unwrap());
+
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let mut rng = thread_rng();
+
+    // a few accounts get several deposits, the way real blocks look
+    let pubkeys: Vec<_> = (0..DEPOSIT_BATCH / 4).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+    let account_ids: Vec<_> = (0..pubkeys.len()).map(
+        |_| rng.gen_range(0, 1 << account_depth)
+    ).collect();
+
+    let deposits: Vec<_> = (0..DEPOSIT_BATCH).map(|_| {
+        let owner = rng.gen_range(0, pubkeys.len());
+        Deposit {
+            pubkey: Some(pubkeys[owner].clone()),
+            account_id: account_ids[owner],
+            token_id: rng.gen_range(0, 1 << TOKEN_DEPTH),
+            amount: u128::from(rng.gen_range(1u32, 1000)),
+        }
+    }).collect();
+
+    let started = Instant::now();
+    let mut sequential_tree = AccountsTree::new(account_depth, TOKEN_DEPTH, hash_params, sign_params);
+    let mut batched_tree = sequential_tree.clone();
+    println!("depth {}: tree built in {:?}", account_depth, started.elapsed());
+
+    let started = Instant::now();
+    let sequential: Vec<_> = deposits.iter().map(
+        |deposit| deposit.update_tree_and_record_state(&mut sequential_tree).unwrap()
+    ).collect();
+    let sequential_time = started.elapsed();
+
+    let updates: Vec<_> = deposits.iter().map(|deposit| deposit.leaf_update()).collect();
+    let started = Instant::now();
+    let batched = batched_tree.apply_batch(&updates).unwrap();
+    let batched_time = started.elapsed();
+
+    assert!(sequential == batched);
+    assert_eq!(sequential_tree.get_root(), batched_tree.get_root());
+
+    println!("{} deposits, sequential: {:?}", DEPOSIT_BATCH, sequential_time);
+    println!("{} deposits, batched:    {:?}", DEPOSIT_BATCH, batched_time);
+}
@@ -1,3 +1,8 @@
+use std::{
+    fmt,
+    error::Error,
+};
+
 use bellman_ce::{
     ConstraintSystem,
     SynthesisError,
@@ -7,6 +12,7 @@ use sapling_crypto_ce::{
     poseidon::{
         PoseidonEngine,
         QuinticSBox,
+        bn256::Bn256PoseidonParams,
     },
     jubjub::{
         JubjubEngine,
@@ -15,12 +21,79 @@ use sapling_crypto_ce::{
     },
 };
 
+use super::circuit::merkle::{ MerkleUpdateGadget, MerkleUpdateState };
 use super::utils::tree::{
-    TreeCircuit,
-    TreeState,
+    check_witness_length,
+    path_length,
+    indices_length,
 };
 
-pub const ACCOUNT_LEAF_SIZE: usize = 4;
+use pairing_ce::bn256::{ self, Bn256 };
+
+use ff_ce::Field;
+
+use super::tree::merkle_tree::BINARY_ARITY;
+use super::tree::proof::MerkleProof;
+use super::tree::leaf::{ LeafAccount, LeafVersion, LeafCodec };
+use super::hasher::{ TreeHasher, Poseidon };
+use super::utils::point::pack_point;
+use super::utils::utils::optionalize;
+
+// packed pubkey, nonce and balances root, a versioned leaf hashes its tag
+// before them
+pub const ACCOUNT_LEAF_SIZE: usize = 3;
+pub const LEAF_PUBKEY: usize = 0;
+pub const LEAF_NONCE: usize = 1;
+pub const LEAF_BALANCES_ROOT: usize = 2;
+pub const BALANCE_LEAF_SIZE: usize = 1;
+
+// a witness that would leave a constraint unsatisfied, found off-circuit
+// before proving. slot is the index of the operation in its batch, None for
+// the values of the batch itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WitnessError {
+    MissingField { slot: Option<usize>, field: &'static str },
+    WrongLength { slot: Option<usize>, field: &'static str, expected: usize, actual: usize },
+    // the old leaf and path don't hash to the root the previous slot left
+    OldRootMismatch { slot: usize },
+    // the new leaf doesn't follow from the old one and the operation
+    InconsistentLeaf { slot: usize, reason: &'static str },
+    // every slot chains, but not to the new root or accum hash of the batch
+    NewRootMismatch,
+    AccumHashMismatch,
+}
+
+impl WitnessError {
+    pub fn slot(&self) -> Option<usize> {
+        match self {
+            WitnessError::MissingField { slot, .. } | WitnessError::WrongLength { slot, .. } => *slot,
+            WitnessError::OldRootMismatch { slot } | WitnessError::InconsistentLeaf { slot, .. } => Some(*slot),
+            WitnessError::NewRootMismatch | WitnessError::AccumHashMismatch => None,
+        }
+    }
+}
+
+impl Error for WitnessError {}
+
+impl fmt::Display for WitnessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            WitnessError::MissingField { slot: None, field } => write!(f, "{} is not set", field),
+            WitnessError::MissingField { slot: Some(i), field } => write!(
+                f, "{} of operation {} is not set", field, i),
+            WitnessError::WrongLength { slot: None, field, expected, actual } => write!(
+                f, "{} length is {}, expected {}", field, actual, expected),
+            WitnessError::WrongLength { slot: Some(i), field, expected, actual } => write!(
+                f, "{} length of operation {} is {}, expected {}", field, i, actual, expected),
+            WitnessError::OldRootMismatch { slot } => write!(
+                f, "Old leaf of operation {} is not in the tree it starts from", slot),
+            WitnessError::InconsistentLeaf { slot, reason } => write!(
+                f, "Operation {}: {}", slot, reason),
+            WitnessError::NewRootMismatch => write!(f, "Operations don't end at the new root"),
+            WitnessError::AccumHashMismatch => write!(f, "Operations don't end at the new accum hash"),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct AccountState<E: JubjubEngine> {
@@ -32,70 +105,320 @@ pub struct AccountState<E: JubjubEngine> {
     pub new_nonce: Option<E::Fr>,
     pub account_path: Vec::<Option<E::Fr>>,
     pub account_indices: Vec::<Option<bool>>,
+    pub token_path: Vec::<Option<E::Fr>>,
+    pub token_indices: Vec::<Option<bool>>,
+}
+
+// by hand, derive would require E: PartialEq
+impl<E: JubjubEngine> PartialEq for AccountState<E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.old_balance == other.old_balance
+            && self.new_balance == other.new_balance
+            && self.old_pubkey == other.old_pubkey
+            && self.new_pubkey == other.new_pubkey
+            && self.old_nonce == other.old_nonce
+            && self.new_nonce == other.new_nonce
+            && self.account_path == other.account_path
+            && self.account_indices == other.account_indices
+            && self.token_path == other.token_path
+            && self.token_indices == other.token_indices
+    }
+}
+
+// the values of an account leaf a transition touches, the balance is the
+// leaf of the token in the balances tree
+#[derive(Clone)]
+pub struct AccountLeaf<E: JubjubEngine> {
+    pub pubkey: Point<E, Unknown>,
+    pub nonce: E::Fr,
+    pub balance: E::Fr,
+}
+
+impl<E: JubjubEngine> AccountState<E> {
+    // path and indices are the account ones followed by the token ones
+    pub fn from_transition(
+        old_leaf: AccountLeaf<E>,
+        new_leaf: AccountLeaf<E>,
+        path: (&[E::Fr], &[E::Fr]),
+        indices: (&[bool], &[bool]),
+    ) -> Self {
+        AccountState {
+            old_balance: Some(old_leaf.balance),
+            new_balance: Some(new_leaf.balance),
+            old_pubkey: Some(old_leaf.pubkey),
+            new_pubkey: Some(new_leaf.pubkey),
+            old_nonce: Some(old_leaf.nonce),
+            new_nonce: Some(new_leaf.nonce),
+            account_path: optionalize(path.0.to_vec()),
+            account_indices: optionalize(indices.0.to_vec()),
+            token_path: optionalize(path.1.to_vec()),
+            token_indices: optionalize(indices.1.to_vec()),
+        }
+    }
+
+    // no witness, for circuits synthesized at setup
+    pub fn empty(account_depth: usize, token_depth: usize) -> Self {
+        AccountState {
+            old_balance: None,
+            new_balance: None,
+            old_pubkey: None,
+            new_pubkey: None,
+            old_nonce: None,
+            new_nonce: None,
+            account_path: vec![None; account_depth],
+            account_indices: vec![None; account_depth],
+            token_path: vec![None; token_depth],
+            token_indices: vec![None; token_depth],
+        }
+    }
+
+    // the first field without a value, synthesis would stop there with AssignmentMissing
+    pub fn missing_field(&self) -> Option<&'static str> {
+        if self.old_balance.is_none() {
+            Some("old_balance")
+        } else if self.new_balance.is_none() {
+            Some("new_balance")
+        } else if self.old_pubkey.is_none() {
+            Some("old_pubkey")
+        } else if self.new_pubkey.is_none() {
+            Some("new_pubkey")
+        } else if self.old_nonce.is_none() {
+            Some("old_nonce")
+        } else if self.new_nonce.is_none() {
+            Some("new_nonce")
+        } else if self.account_path.contains(&None) {
+            Some("account_path")
+        } else if self.account_indices.contains(&None) {
+            Some("account_indices")
+        } else if self.token_path.contains(&None) {
+            Some("token_path")
+        } else if self.token_indices.contains(&None) {
+            Some("token_indices")
+        } else {
+            None
+        }
+    }
+}
+
+// little endian index bits, as check_decomposition_le reads them
+pub fn indices_to_fr(indices: &[Option<bool>]) -> Option<bn256::Fr> {
+    indices.iter().rev().try_fold(bn256::Fr::zero(), |mut value, bit| {
+        value.double();
+        if (*bit)? {
+            value.add_assign(&bn256::Fr::one());
+        }
+        Some(value)
+    })
+}
+
+impl AccountState<Bn256> {
+    // the accounts roots before and after the transition, hashed off-circuit
+    // like AccountCircuit does for binary trees of the given depths
+    pub fn calc_roots(
+        &self,
+        slot: usize,
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &Bn256PoseidonParams,
+    ) -> Result<(bn256::Fr, bn256::Fr), WitnessError> {
+        self.calc_versioned_roots(slot, account_depth, token_depth, LeafVersion::V0, hash_params)
+    }
+
+    pub fn calc_versioned_roots(
+        &self,
+        slot: usize,
+        account_depth: usize,
+        token_depth: usize,
+        leaf_version: LeafVersion,
+        hash_params: &Bn256PoseidonParams,
+    ) -> Result<(bn256::Fr, bn256::Fr), WitnessError> {
+        if let Some(field) = self.missing_field() {
+            return Err(WitnessError::MissingField { slot: Some(slot), field });
+        }
+
+        let lengths = [
+            ("account_path", account_depth, self.account_path.len()),
+            ("account_indices", account_depth, self.account_indices.len()),
+            ("token_path", token_depth, self.token_path.len()),
+            ("token_indices", token_depth, self.token_indices.len()),
+        ];
+        for (field, expected, actual) in lengths.iter() {
+            if expected != actual {
+                return Err(WitnessError::WrongLength {
+                    slot: Some(slot), field, expected: *expected, actual: *actual,
+                });
+            }
+        }
+
+        // every value is set, see missing_field
+        let root = |leaf, path: &[Option<bn256::Fr>], indices: &[Option<bool>]| MerkleProof {
+            leaf,
+            path: path.iter().map(|sibling| sibling.unwrap()).collect(),
+            indices: indices.iter().map(|bit| bit.unwrap()).collect(),
+            root: bn256::Fr::zero(),
+        }.calc_root(hash_params);
+
+        let old_balances_root = root(vec![self.old_balance.unwrap()], &self.token_path, &self.token_indices);
+        let new_balances_root = root(vec![self.new_balance.unwrap()], &self.token_path, &self.token_indices);
+
+        let old_leaf = LeafAccount {
+            pubkey: pack_point(self.old_pubkey.as_ref().unwrap()),
+            nonce: self.old_nonce.unwrap(),
+            balances_root: old_balances_root,
+        };
+        let new_leaf = LeafAccount {
+            pubkey: pack_point(self.new_pubkey.as_ref().unwrap()),
+            nonce: self.new_nonce.unwrap(),
+            balances_root: new_balances_root,
+        };
+
+        let old_root = root(leaf_version.encode(&old_leaf), &self.account_path, &self.account_indices);
+        let new_root = root(leaf_version.encode(&new_leaf), &self.account_path, &self.account_indices);
+
+        Ok((old_root, new_root))
+    }
 }
 
 #[derive(Clone)]
-pub struct AccountCircuit<'a, E: JubjubEngine + PoseidonEngine> {
-    pub accounts_tree: TreeCircuit<'a, E>,
+pub struct AccountCircuit<'a, E: JubjubEngine + PoseidonEngine, H: TreeHasher<E> = Poseidon> {
+    pub accounts_tree: MerkleUpdateGadget<'a, E, H>,
+    pub balances_tree: MerkleUpdateGadget<'a, E, H>,
 }
 
 impl<'a, E> AccountCircuit<'a, E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
     pub fn new<CS: ConstraintSystem<E>> (
-        mut cs: CS,
+        cs: CS,
         account_depth: usize,
+        token_depth: usize,
         params: &'a <E as PoseidonEngine>::Params,
         state: &AccountState<E>,
     ) -> Result<Self, SynthesisError> {
+        Self::new_with_arity(cs, account_depth, token_depth, BINARY_ARITY, params, state)
+    }
 
-        let (old_pubkey_x, old_pubkey_y) = match &state.old_pubkey {
-            Some(point) => {
-                let (x, y) = point.into_xy();
-                (Some(x), Some(y))
-            },
-            None => (None, None),
-        };
+    // both trees have the same arity, depths count levels of that arity
+    pub fn new_with_arity<CS: ConstraintSystem<E>> (
+        cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        arity: usize,
+        params: &'a <E as PoseidonEngine>::Params,
+        state: &AccountState<E>,
+    ) -> Result<Self, SynthesisError> {
+        Self::new_with_hasher(cs, account_depth, token_depth, arity, params, state)
+    }
+}
+
+impl<'a, E, H> AccountCircuit<'a, E, H>
+    where E: JubjubEngine + PoseidonEngine,
+          H: TreeHasher<E>,
+{
+    pub fn new_with_hasher<CS: ConstraintSystem<E>> (
+        cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        arity: usize,
+        params: &'a H::Params,
+        state: &AccountState<E>,
+    ) -> Result<Self, SynthesisError> {
+        Self::new_with_version(cs, account_depth, token_depth, arity, params, state, LeafVersion::V0)
+    }
+
+    // the account leaf of the given layout, its fields are at the LEAF_
+    // indices whatever the version
+    pub fn new_with_version<CS: ConstraintSystem<E>> (
+        mut cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        arity: usize,
+        params: &'a H::Params,
+        state: &AccountState<E>,
+        leaf_version: LeafVersion,
+    ) -> Result<Self, SynthesisError> {
+        check_witness_length("account path", path_length(account_depth, arity), state.account_path.len())?;
+        check_witness_length("account indices", indices_length(account_depth, arity), state.account_indices.len())?;
+        check_witness_length("token path", path_length(token_depth, arity), state.token_path.len())?;
+        check_witness_length("token indices", indices_length(token_depth, arity), state.token_indices.len())?;
 
-        let (new_pubkey_x, new_pubkey_y) = match &state.new_pubkey {
-            Some(point) => {
-                let (x, y) = point.into_xy();
-                (Some(x), Some(y))
-            },
-            None => (None, None),
+        // token balances sub-tree, its root is the last element of the account leaf
+
+        let balances_tree_state = MerkleUpdateState {
+            old_leaf: vec![state.old_balance],
+            new_leaf: vec![state.new_balance],
+            path: state.token_path.clone(),
+            indices: state.token_indices.clone(),
         };
 
+        let balances_tree = MerkleUpdateGadget::<'a, E, H>::new_with_hasher(
+            cs.namespace(|| "allocate balances tree"),
+            BALANCE_LEAF_SIZE,
+            token_depth,
+            arity,
+            params,
+            &balances_tree_state,
+        )?;
+
+        let old_balances_root = balances_tree.calc_old_root(
+            cs.namespace(|| "calculate old balances root"),
+        )?;
+
+        let new_balances_root = balances_tree.calc_new_root(
+            cs.namespace(|| "calculate new balances root"),
+        )?;
+
+        // the leaf only holds the packed pubkey, circuits that need its
+        // coordinates check them against it with unpack_point_gadget
+
         let account_old_leaf = vec![
-            old_pubkey_x,
-            old_pubkey_y,
-            state.old_nonce.clone(),
-            state.old_balance.clone(),
+            state.old_pubkey.as_ref().map(pack_point),
+            state.old_nonce,
+            old_balances_root.get_value(),
         ];
 
         let account_new_leaf = vec![
-            new_pubkey_x,
-            new_pubkey_y,
-            state.new_nonce.clone(),
-            state.new_balance.clone(),
+            state.new_pubkey.as_ref().map(pack_point),
+            state.new_nonce,
+            new_balances_root.get_value(),
         ];
 
-        let tree_state = TreeState {
+        let tree_state = MerkleUpdateState {
             old_leaf: account_old_leaf,
             new_leaf: account_new_leaf,
             path: state.account_path.clone(),
             indices: state.account_indices.clone(),
         };
 
-        let accounts_tree = TreeCircuit::<'a, E>::new(
+        let accounts_tree = MerkleUpdateGadget::<'a, E, H>::new_with_hasher(
             cs.namespace(|| "allocate accounts tree"),
             ACCOUNT_LEAF_SIZE,
             account_depth,
+            arity,
             params,
             &tree_state,
+        )?.with_leaf_tag(
+            cs.namespace(|| "accounts tree leaf tag"),
+            leaf_version.tag(),
         )?;
 
+        cs.enforce(
+            || "enforce old balances root",
+            |lc| lc + old_balances_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + accounts_tree.old_leaf_alloc[LEAF_BALANCES_ROOT].get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new balances root",
+            |lc| lc + new_balances_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + accounts_tree.new_leaf_alloc[LEAF_BALANCES_ROOT].get_variable(),
+        );
+
         let circuit = AccountCircuit {
             accounts_tree,
+            balances_tree,
         };
 
         Ok(circuit)
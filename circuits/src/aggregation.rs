@@ -0,0 +1,758 @@
+use std::{
+    fmt,
+    error::Error,
+};
+
+use bellman_ce::groth16::{ Proof, VerifyingKey };
+
+use pairing_ce::{
+    CurveAffine,
+    CurveProjective,
+    Engine,
+    bn256::{ Bn256, Fq, Fq12, Fr, G1Affine, G2Affine },
+};
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+use rand::Rng;
+
+use crate::prover::public_inputs_to_eth_bytes;
+use crate::proof_batch::keccak_challenge;
+use crate::public_inputs::PublicInputs;
+
+// K block proofs aggregated into one proof of O(log K) size, SnarkPack
+// (Gailly, Maller, Nitulescu 2021). with r a challenge over commitments to
+// the A, B and C of the proofs, the groth16 equations fold into
+//   Z_AB = e(alpha, beta)^(sum r^i) e(sum r^i L_i, gamma) e(Z_C, delta)
+// for Z_AB = prod e(A_i, B_i)^(r^i) and Z_C = sum r^i C_i, the way
+// proof_batch::batch_verify folds them. here the proofs are not sent:
+// a TIPP shows Z_AB is the pairing product of the committed A and B, a MIPP
+// that Z_C is the r combination of the committed C. both halve the vectors
+// and the commitment keys with a challenge every round, so the prover ends
+// with one A, B and C and the folded keys, which it opens at a last
+// challenge against the srs
+#[derive(Clone, Debug, PartialEq)]
+pub enum AggregationError {
+    // the vectors fold in halves
+    NotPowerOfTwo(usize),
+    // more proofs than the srs has powers for
+    TooManyProofs { proofs: usize, max: usize },
+}
+
+impl Error for AggregationError {}
+
+impl fmt::Display for AggregationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            AggregationError::NotPowerOfTwo(count) => write!(
+                f, "{} proofs is not a nonzero power of two", count),
+            AggregationError::TooManyProofs { proofs, max } => write!(
+                f, "{} proofs is more than the {} of the srs", proofs, max),
+        }
+    }
+}
+
+// the part of a commitment key, a folded key or an opening for each secret
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyPair<G> {
+    pub alpha: G,
+    pub beta: G,
+}
+
+// the powers of two secrets a and b in both groups. it is a trusted setup
+// like the groth16 keys: whoever knows a or b can open the commitments to
+// other proofs. the commitment keys for K proofs are v_i = h^(a^i), h^(b^i)
+// and w_i = g^(a^(K + i)), g^(b^(K + i))
+#[derive(Clone)]
+pub struct AggregationSrs {
+    // g^(a^i) and g^(b^i) for i < 2 max_proofs
+    pub g_alpha_powers: Vec::<G1Affine>,
+    pub g_beta_powers: Vec::<G1Affine>,
+    // h^(a^i) and h^(b^i) for i <= max_proofs, the last one for a single proof
+    pub h_alpha_powers: Vec::<G2Affine>,
+    pub h_beta_powers: Vec::<G2Affine>,
+}
+
+// the powers the verifier checks openings with
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregationVerifierKey {
+    pub g: G1Affine,
+    pub h: G2Affine,
+    pub g_secrets: KeyPair<G1Affine>,
+    pub h_secrets: KeyPair<G2Affine>,
+}
+
+impl AggregationSrs {
+    // with fresh secrets that are dropped here, for tests and single
+    // operator setups, as prover::generate_parameters does for the keys
+    pub fn generate<R: Rng>(max_proofs: usize, rng: &mut R) -> Result<Self, AggregationError> {
+        if !max_proofs.is_power_of_two() {
+            return Err(AggregationError::NotPowerOfTwo(max_proofs));
+        }
+        let alpha: Fr = rng.gen();
+        let beta: Fr = rng.gen();
+        let g = G1Affine::one();
+        let h = G2Affine::one();
+
+        let alpha_powers = powers(alpha, 2 * max_proofs);
+        let beta_powers = powers(beta, 2 * max_proofs);
+        Ok(AggregationSrs {
+            g_alpha_powers: alpha_powers.iter().map(|power| g.mul(*power).into_affine()).collect(),
+            g_beta_powers: beta_powers.iter().map(|power| g.mul(*power).into_affine()).collect(),
+            h_alpha_powers: alpha_powers[..=max_proofs].iter().map(|power| h.mul(*power).into_affine()).collect(),
+            h_beta_powers: beta_powers[..=max_proofs].iter().map(|power| h.mul(*power).into_affine()).collect(),
+        })
+    }
+
+    pub fn max_proofs(&self) -> usize {
+        self.g_alpha_powers.len() / 2
+    }
+
+    pub fn verifier_key(&self) -> AggregationVerifierKey {
+        AggregationVerifierKey {
+            g: self.g_alpha_powers[0],
+            h: self.h_alpha_powers[0],
+            g_secrets: KeyPair { alpha: self.g_alpha_powers[1], beta: self.g_beta_powers[1] },
+            h_secrets: KeyPair { alpha: self.h_alpha_powers[1], beta: self.h_beta_powers[1] },
+        }
+    }
+}
+
+// prod e(x_i, v_i) prod e(w_i, y_i) under the alpha and the beta key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Commitment {
+    pub alpha: Fq12,
+    pub beta: Fq12,
+}
+
+impl Commitment {
+    // self left^(x^-1) right^x, the commitment to the folded vectors
+    fn fold(&self, left: &Commitment, right: &Commitment, x: &Fr, x_inv: &Fr) -> Commitment {
+        Commitment {
+            alpha: fold_gt(&self.alpha, &left.alpha, &right.alpha, x, x_inv),
+            beta: fold_gt(&self.beta, &left.beta, &right.beta, x, x_inv),
+        }
+    }
+}
+
+// the cross terms of a round: left pairs the left half of A and w with the
+// right half of v and B, and folds with x^-1; right the other way, with x
+#[derive(Clone, Debug, PartialEq)]
+pub struct TippRound {
+    pub com_left: Commitment,
+    pub com_right: Commitment,
+    pub z_left: Fq12,
+    pub z_right: Fq12,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TippProof {
+    pub rounds: Vec::<TippRound>,
+    pub a: G1Affine,
+    pub b: G2Affine,
+    pub v: KeyPair<G2Affine>,
+    pub w: KeyPair<G1Affine>,
+}
+
+// left pairs the left half of C with the right half of v and r^i, right
+// the other way
+#[derive(Clone, Debug, PartialEq)]
+pub struct MippRound {
+    pub com_left: Commitment,
+    pub com_right: Commitment,
+    pub z_left: G1Affine,
+    pub z_right: G1Affine,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MippProof {
+    pub rounds: Vec::<MippRound>,
+    pub c: G1Affine,
+    pub v: KeyPair<G2Affine>,
+}
+
+// g^q(a) or h^q(a) for q = (f - f(z)) / (X - z) of each folded key f
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyOpenings {
+    pub tipp_v: KeyPair<G2Affine>,
+    pub tipp_w: KeyPair<G1Affine>,
+    pub mipp_v: KeyPair<G2Affine>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregatedProof {
+    pub com_ab: Commitment,
+    pub com_c: Commitment,
+    pub z_ab: Fq12,
+    pub z_c: G1Affine,
+    pub tipp: TippProof,
+    pub mipp: MippProof,
+    pub openings: KeyOpenings,
+}
+
+// the proofs in block order, a nonzero power of two of them up to the
+// max_proofs of the srs. the proofs aren't checked, a bad one fails
+// verify_aggregated
+pub fn aggregate(
+    srs: &AggregationSrs,
+    proofs: &[(Proof<Bn256>, PublicInputs<Bn256>)],
+) -> Result<AggregatedProof, AggregationError> {
+    let count = proofs.len();
+    if !count.is_power_of_two() {
+        return Err(AggregationError::NotPowerOfTwo(count));
+    }
+    if count > srs.max_proofs() {
+        return Err(AggregationError::TooManyProofs { proofs: count, max: srs.max_proofs() });
+    }
+
+    let a: Vec<_> = proofs.iter().map(|(proof, _)| proof.a).collect();
+    let b: Vec<_> = proofs.iter().map(|(proof, _)| proof.b).collect();
+    let c: Vec<_> = proofs.iter().map(|(proof, _)| proof.c).collect();
+    let v = KeyPair {
+        alpha: srs.h_alpha_powers[..count].to_vec(),
+        beta: srs.h_beta_powers[..count].to_vec(),
+    };
+    let w = KeyPair {
+        alpha: srs.g_alpha_powers[count..2 * count].to_vec(),
+        beta: srs.g_beta_powers[count..2 * count].to_vec(),
+    };
+
+    let public_inputs: Vec<_> = proofs.iter().map(|(_, inputs)| inputs.clone()).collect();
+    let mut transcript = Transcript::new(&public_inputs);
+    let com_ab = Commitment {
+        alpha: commit(&a, &v.alpha, &w.alpha, &b),
+        beta: commit(&a, &v.beta, &w.beta, &b),
+    };
+    let com_c = Commitment {
+        alpha: commit(&c, &v.alpha, &[], &[]),
+        beta: commit(&c, &v.beta, &[], &[]),
+    };
+    transcript.append_commitment(&com_ab);
+    transcript.append_commitment(&com_c);
+    let r = transcript.challenge();
+    let r_powers = powers(r, count);
+    let r_inv_powers = powers(r.inverse().unwrap(), count);
+
+    // A_i^(r^i) under v_i^(r^-i) is still committed to by com_ab
+    let a_r: Vec<_> = a.iter().zip(r_powers.iter()).map(|(point, power)| point.mul(*power).into_affine()).collect();
+    let v_r = KeyPair {
+        alpha: scale(&v.alpha, &r_inv_powers),
+        beta: scale(&v.beta, &r_inv_powers),
+    };
+    let z_ab = pairing_product(&a_r, &b);
+    let z_c = multiexp(&c, &r_powers).into_affine();
+    transcript.append_fq12(&z_ab);
+    transcript.append_g1(&z_c);
+
+    let (tipp, tipp_challenges) = prove_tipp(&mut transcript, a_r, b, v_r, w);
+    let (mipp, mipp_challenges) = prove_mipp(&mut transcript, c, r_powers, v);
+    let point = transcript.challenge();
+
+    let tipp_inverses = inverses(&tipp_challenges);
+    let mipp_inverses = inverses(&mipp_challenges);
+    let tipp_v = quotient(&key_coefficients(&tipp_inverses, r.inverse().unwrap(), 0), &point);
+    let tipp_w = quotient(&key_coefficients(&tipp_challenges, Fr::one(), count), &point);
+    let mipp_v = quotient(&key_coefficients(&mipp_inverses, Fr::one(), 0), &point);
+    let openings = KeyOpenings {
+        tipp_v: KeyPair {
+            alpha: multiexp(&srs.h_alpha_powers[..tipp_v.len()], &tipp_v).into_affine(),
+            beta: multiexp(&srs.h_beta_powers[..tipp_v.len()], &tipp_v).into_affine(),
+        },
+        tipp_w: KeyPair {
+            alpha: multiexp(&srs.g_alpha_powers[..tipp_w.len()], &tipp_w).into_affine(),
+            beta: multiexp(&srs.g_beta_powers[..tipp_w.len()], &tipp_w).into_affine(),
+        },
+        mipp_v: KeyPair {
+            alpha: multiexp(&srs.h_alpha_powers[..mipp_v.len()], &mipp_v).into_affine(),
+            beta: multiexp(&srs.h_beta_powers[..mipp_v.len()], &mipp_v).into_affine(),
+        },
+    };
+
+    Ok(AggregatedProof { com_ab, com_c, z_ab, z_c, tipp, mipp, openings })
+}
+
+// false for a proof of another number of blocks than public_inputs and for
+// a key of another number of inputs
+pub fn verify_aggregated(
+    vk: &VerifyingKey<Bn256>,
+    srs_vk: &AggregationVerifierKey,
+    public_inputs: &[PublicInputs<Bn256>],
+    proof: &AggregatedProof,
+) -> bool {
+    let count = public_inputs.len();
+    if !count.is_power_of_two() {
+        return false;
+    }
+    let rounds = count.trailing_zeros() as usize;
+    if proof.tipp.rounds.len() != rounds || proof.mipp.rounds.len() != rounds {
+        return false;
+    }
+
+    let mut transcript = Transcript::new(public_inputs);
+    transcript.append_commitment(&proof.com_ab);
+    transcript.append_commitment(&proof.com_c);
+    let r = transcript.challenge();
+    let r_powers = powers(r, count);
+    transcript.append_fq12(&proof.z_ab);
+    transcript.append_g1(&proof.z_c);
+
+    // the groth16 equations of all proofs folded with r
+    let mut ic_coefficients = vec![Fr::zero(); vk.ic.len()];
+    let mut power_sum = Fr::zero();
+    for (inputs, power) in public_inputs.iter().zip(r_powers.iter()) {
+        let inputs = inputs.to_vec();
+        if inputs.len() + 1 != vk.ic.len() {
+            return false;
+        }
+        ic_coefficients[0].add_assign(power);
+        for (coefficient, input) in ic_coefficients[1..].iter_mut().zip(inputs.iter()) {
+            let mut term = *input;
+            term.mul_assign(power);
+            coefficient.add_assign(&term);
+        }
+        power_sum.add_assign(power);
+    }
+    let folded_inputs = multiexp(&vk.ic, &ic_coefficients).into_affine();
+    let expected = pairing_product(
+        &[vk.alpha_g1.mul(power_sum).into_affine(), folded_inputs, proof.z_c],
+        &[vk.beta_g2, vk.gamma_g2, vk.delta_g2],
+    );
+    if expected != proof.z_ab {
+        return false;
+    }
+
+    let tipp_challenges = match verify_tipp(&mut transcript, &proof.tipp, proof.com_ab, proof.z_ab) {
+        Some(challenges) => challenges,
+        None => return false,
+    };
+    let mipp_challenges = match verify_mipp(&mut transcript, &proof.mipp, proof.com_c, proof.z_c, &r) {
+        Some(challenges) => challenges,
+        None => return false,
+    };
+    let point = transcript.challenge();
+
+    // the folded keys are the polynomials of the challenges at the secrets
+    let tipp_inverses = inverses(&tipp_challenges);
+    let mipp_inverses = inverses(&mipp_challenges);
+    check_g2_opening(
+        srs_vk, &proof.tipp.v, &proof.openings.tipp_v, &point,
+        &key_eval(&tipp_inverses, &r.inverse().unwrap(), &point, 0))
+    && check_g1_opening(
+        srs_vk, &proof.tipp.w, &proof.openings.tipp_w, &point,
+        &key_eval(&tipp_challenges, &Fr::one(), &point, count))
+    && check_g2_opening(
+        srs_vk, &proof.mipp.v, &proof.openings.mipp_v, &point,
+        &key_eval(&mipp_inverses, &Fr::one(), &point, 0))
+}
+
+fn prove_tipp(
+    transcript: &mut Transcript,
+    mut a: Vec::<G1Affine>,
+    mut b: Vec::<G2Affine>,
+    mut v: KeyPair<Vec::<G2Affine>>,
+    mut w: KeyPair<Vec::<G1Affine>>,
+) -> (TippProof, Vec::<Fr>) {
+    let mut rounds = Vec::new();
+    let mut challenges = Vec::new();
+    while a.len() > 1 {
+        let half = a.len() / 2;
+        let (a_left, a_right) = a.split_at(half);
+        let (b_left, b_right) = b.split_at(half);
+        let round = TippRound {
+            com_left: Commitment {
+                alpha: commit(a_left, &v.alpha[half..], &w.alpha[..half], b_right),
+                beta: commit(a_left, &v.beta[half..], &w.beta[..half], b_right),
+            },
+            com_right: Commitment {
+                alpha: commit(a_right, &v.alpha[..half], &w.alpha[half..], b_left),
+                beta: commit(a_right, &v.beta[..half], &w.beta[half..], b_left),
+            },
+            z_left: pairing_product(a_left, b_right),
+            z_right: pairing_product(a_right, b_left),
+        };
+        transcript.append_tipp_round(&round);
+        let x = transcript.challenge();
+        let x_inv = x.inverse().unwrap();
+
+        a = fold(&a, &x);
+        b = fold(&b, &x_inv);
+        v = KeyPair { alpha: fold(&v.alpha, &x_inv), beta: fold(&v.beta, &x_inv) };
+        w = KeyPair { alpha: fold(&w.alpha, &x), beta: fold(&w.beta, &x) };
+        rounds.push(round);
+        challenges.push(x);
+    }
+
+    let proof = TippProof {
+        rounds,
+        a: a[0],
+        b: b[0],
+        v: KeyPair { alpha: v.alpha[0], beta: v.beta[0] },
+        w: KeyPair { alpha: w.alpha[0], beta: w.beta[0] },
+    };
+    transcript.append_tipp_final(&proof);
+    (proof, challenges)
+}
+
+fn verify_tipp(
+    transcript: &mut Transcript,
+    proof: &TippProof,
+    mut com: Commitment,
+    mut z: Fq12,
+) -> Option<Vec::<Fr>> {
+    let mut challenges = Vec::new();
+    for round in proof.rounds.iter() {
+        transcript.append_tipp_round(round);
+        let x = transcript.challenge();
+        let x_inv = x.inverse().unwrap();
+        com = com.fold(&round.com_left, &round.com_right, &x, &x_inv);
+        z = fold_gt(&z, &round.z_left, &round.z_right, &x, &x_inv);
+        challenges.push(x);
+    }
+
+    let a = [proof.a];
+    let b = [proof.b];
+    let expected = Commitment {
+        alpha: commit(&a, &[proof.v.alpha], &[proof.w.alpha], &b),
+        beta: commit(&a, &[proof.v.beta], &[proof.w.beta], &b),
+    };
+    if com != expected || z != pairing_product(&a, &b) {
+        return None;
+    }
+    transcript.append_tipp_final(proof);
+    Some(challenges)
+}
+
+fn prove_mipp(
+    transcript: &mut Transcript,
+    mut c: Vec::<G1Affine>,
+    mut scalars: Vec::<Fr>,
+    mut v: KeyPair<Vec::<G2Affine>>,
+) -> (MippProof, Vec::<Fr>) {
+    let mut rounds = Vec::new();
+    let mut challenges = Vec::new();
+    while c.len() > 1 {
+        let half = c.len() / 2;
+        let (c_left, c_right) = c.split_at(half);
+        let (scalars_left, scalars_right) = scalars.split_at(half);
+        let round = MippRound {
+            com_left: Commitment {
+                alpha: commit(c_left, &v.alpha[half..], &[], &[]),
+                beta: commit(c_left, &v.beta[half..], &[], &[]),
+            },
+            com_right: Commitment {
+                alpha: commit(c_right, &v.alpha[..half], &[], &[]),
+                beta: commit(c_right, &v.beta[..half], &[], &[]),
+            },
+            z_left: multiexp(c_left, scalars_right).into_affine(),
+            z_right: multiexp(c_right, scalars_left).into_affine(),
+        };
+        transcript.append_mipp_round(&round);
+        let x = transcript.challenge();
+        let x_inv = x.inverse().unwrap();
+
+        c = fold(&c, &x);
+        scalars = scalars_left.iter().zip(scalars_right.iter()).map(|(left, right)| {
+            let mut folded = *right;
+            folded.mul_assign(&x_inv);
+            folded.add_assign(left);
+            folded
+        }).collect();
+        v = KeyPair { alpha: fold(&v.alpha, &x_inv), beta: fold(&v.beta, &x_inv) };
+        rounds.push(round);
+        challenges.push(x);
+    }
+
+    let proof = MippProof {
+        rounds,
+        c: c[0],
+        v: KeyPair { alpha: v.alpha[0], beta: v.beta[0] },
+    };
+    transcript.append_mipp_final(&proof);
+    (proof, challenges)
+}
+
+// the r^i fold to the key polynomial of the challenges at r, the verifier
+// needs no vector of them
+fn verify_mipp(
+    transcript: &mut Transcript,
+    proof: &MippProof,
+    mut com: Commitment,
+    z: G1Affine,
+    r: &Fr,
+) -> Option<Vec::<Fr>> {
+    let mut challenges = Vec::new();
+    let mut z = z.into_projective();
+    for round in proof.rounds.iter() {
+        transcript.append_mipp_round(round);
+        let x = transcript.challenge();
+        let x_inv = x.inverse().unwrap();
+        com = com.fold(&round.com_left, &round.com_right, &x, &x_inv);
+        z.add_assign(&round.z_left.mul(x_inv));
+        z.add_assign(&round.z_right.mul(x));
+        challenges.push(x);
+    }
+
+    let c = [proof.c];
+    let expected = Commitment {
+        alpha: commit(&c, &[proof.v.alpha], &[], &[]),
+        beta: commit(&c, &[proof.v.beta], &[], &[]),
+    };
+    let scalar = key_eval(&inverses(&challenges), &Fr::one(), r, 0);
+    if com != expected || z.into_affine() != proof.c.mul(scalar).into_affine() {
+        return None;
+    }
+    transcript.append_mipp_final(proof);
+    Some(challenges)
+}
+
+// e(g^a - z g, opening) = e(g, key - f(z) h), for the alpha and the beta key
+fn check_g2_opening(
+    srs_vk: &AggregationVerifierKey,
+    key: &KeyPair<G2Affine>,
+    opening: &KeyPair<G2Affine>,
+    point: &Fr,
+    value: &Fr,
+) -> bool {
+    let mut neg_g = srs_vk.g;
+    neg_g.negate();
+    let check = |secret: &G1Affine, key: &G2Affine, opening: &G2Affine| {
+        let mut shifted = srs_vk.g.mul(*point);
+        shifted.negate();
+        shifted.add_assign_mixed(secret);
+        let mut evaluated = srs_vk.h.mul(*value);
+        evaluated.negate();
+        evaluated.add_assign_mixed(key);
+        pairing_product(
+            &[shifted.into_affine(), neg_g],
+            &[*opening, evaluated.into_affine()],
+        ) == Fq12::one()
+    };
+    check(&srs_vk.g_secrets.alpha, &key.alpha, &opening.alpha)
+        && check(&srs_vk.g_secrets.beta, &key.beta, &opening.beta)
+}
+
+// e(opening, h^a - z h) = e(key - f(z) g, h), for the alpha and the beta key
+fn check_g1_opening(
+    srs_vk: &AggregationVerifierKey,
+    key: &KeyPair<G1Affine>,
+    opening: &KeyPair<G1Affine>,
+    point: &Fr,
+    value: &Fr,
+) -> bool {
+    let mut neg_h = srs_vk.h;
+    neg_h.negate();
+    let check = |secret: &G2Affine, key: &G1Affine, opening: &G1Affine| {
+        let mut shifted = srs_vk.h.mul(*point);
+        shifted.negate();
+        shifted.add_assign_mixed(secret);
+        let mut evaluated = srs_vk.g.mul(*value);
+        evaluated.negate();
+        evaluated.add_assign_mixed(key);
+        pairing_product(
+            &[*opening, evaluated.into_affine()],
+            &[shifted.into_affine(), neg_h],
+        ) == Fq12::one()
+    };
+    check(&srs_vk.h_secrets.alpha, &key.alpha, &opening.alpha)
+        && check(&srs_vk.h_secrets.beta, &key.beta, &opening.beta)
+}
+
+// keccak fiat shamir over the public inputs and everything the prover sent
+// before each challenge; a challenge is appended itself, and redrawn in the
+// negligible case it is zero and has no inverse
+struct Transcript {
+    bytes: Vec::<u8>,
+}
+
+impl Transcript {
+    fn new(public_inputs: &[PublicInputs<Bn256>]) -> Self {
+        let mut bytes = b"openplasma aggregation".to_vec();
+        for inputs in public_inputs.iter() {
+            bytes.extend_from_slice(&public_inputs_to_eth_bytes(inputs));
+        }
+        Transcript { bytes }
+    }
+
+    fn challenge(&mut self) -> Fr {
+        loop {
+            let challenge = keccak_challenge(&self.bytes);
+            self.append_fr(&challenge);
+            if !challenge.is_zero() {
+                return challenge;
+            }
+        }
+    }
+
+    fn append_fr(&mut self, value: &Fr) {
+        value.into_repr().write_be(&mut self.bytes).unwrap();
+    }
+
+    fn append_fq(&mut self, value: &Fq) {
+        value.into_repr().write_be(&mut self.bytes).unwrap();
+    }
+
+    fn append_fq12(&mut self, value: &Fq12) {
+        for fq6 in [value.c0, value.c1].iter() {
+            for fq2 in [fq6.c0, fq6.c1, fq6.c2].iter() {
+                self.append_fq(&fq2.c0);
+                self.append_fq(&fq2.c1);
+            }
+        }
+    }
+
+    // the uncompressed encoding, all zero for the point at infinity
+    fn append_g1(&mut self, point: &G1Affine) {
+        if point.is_zero() {
+            self.bytes.extend_from_slice(&[0u8; 64]);
+        } else {
+            self.bytes.extend_from_slice(point.into_uncompressed().as_ref());
+        }
+    }
+
+    fn append_g2(&mut self, point: &G2Affine) {
+        if point.is_zero() {
+            self.bytes.extend_from_slice(&[0u8; 128]);
+        } else {
+            self.bytes.extend_from_slice(point.into_uncompressed().as_ref());
+        }
+    }
+
+    fn append_commitment(&mut self, com: &Commitment) {
+        self.append_fq12(&com.alpha);
+        self.append_fq12(&com.beta);
+    }
+
+    fn append_tipp_round(&mut self, round: &TippRound) {
+        self.append_commitment(&round.com_left);
+        self.append_commitment(&round.com_right);
+        self.append_fq12(&round.z_left);
+        self.append_fq12(&round.z_right);
+    }
+
+    fn append_tipp_final(&mut self, proof: &TippProof) {
+        self.append_g1(&proof.a);
+        self.append_g2(&proof.b);
+        self.append_g2(&proof.v.alpha);
+        self.append_g2(&proof.v.beta);
+        self.append_g1(&proof.w.alpha);
+        self.append_g1(&proof.w.beta);
+    }
+
+    fn append_mipp_round(&mut self, round: &MippRound) {
+        self.append_commitment(&round.com_left);
+        self.append_commitment(&round.com_right);
+        self.append_g1(&round.z_left);
+        self.append_g1(&round.z_right);
+    }
+
+    fn append_mipp_final(&mut self, proof: &MippProof) {
+        self.append_g1(&proof.c);
+        self.append_g2(&proof.v.alpha);
+        self.append_g2(&proof.v.beta);
+    }
+}
+
+// prod e(x_i, v_i) prod e(w_i, y_i)
+fn commit(x: &[G1Affine], v: &[G2Affine], w: &[G1Affine], y: &[G2Affine]) -> Fq12 {
+    let g1: Vec<_> = x.iter().chain(w.iter()).cloned().collect();
+    let g2: Vec<_> = v.iter().chain(y.iter()).cloned().collect();
+    pairing_product(&g1, &g2)
+}
+
+// prod e(g1_i, g2_i) with one final exponentiation
+fn pairing_product(g1: &[G1Affine], g2: &[G2Affine]) -> Fq12 {
+    let prepared: Vec<_> = g1.iter().zip(g2.iter()).map(|(p, q)| (p.prepare(), q.prepare())).collect();
+    let refs: Vec<_> = prepared.iter().map(|(p, q)| (p, q)).collect();
+    // the miller loop of points of the curves is never zero
+    Bn256::final_exponentiation(&Bn256::miller_loop(refs.iter())).unwrap()
+}
+
+// value left^(x^-1) right^x
+fn fold_gt(value: &Fq12, left: &Fq12, right: &Fq12, x: &Fr, x_inv: &Fr) -> Fq12 {
+    let mut folded = *value;
+    folded.mul_assign(&left.pow(x_inv.into_repr()));
+    folded.mul_assign(&right.pow(x.into_repr()));
+    folded
+}
+
+// left_i + x right_i of the halves
+fn fold<G: CurveAffine<Scalar = Fr>>(points: &[G], x: &Fr) -> Vec::<G> {
+    let (left, right) = points.split_at(points.len() / 2);
+    left.iter().zip(right.iter()).map(|(left, right)| {
+        let mut folded = right.mul(*x);
+        folded.add_assign_mixed(left);
+        folded.into_affine()
+    }).collect()
+}
+
+fn scale<G: CurveAffine<Scalar = Fr>>(points: &[G], scalars: &[Fr]) -> Vec::<G> {
+    points.iter().zip(scalars.iter()).map(|(point, scalar)| point.mul(*scalar).into_affine()).collect()
+}
+
+fn multiexp<G: CurveAffine<Scalar = Fr>>(points: &[G], scalars: &[Fr]) -> G::Projective {
+    let mut sum = G::Projective::zero();
+    for (point, scalar) in points.iter().zip(scalars.iter()) {
+        sum.add_assign(&point.mul(*scalar));
+    }
+    sum
+}
+
+fn powers(base: Fr, count: usize) -> Vec::<Fr> {
+    let mut power = Fr::one();
+    (0..count).map(|_| {
+        let current = power;
+        power.mul_assign(&base);
+        current
+    }).collect()
+}
+
+fn inverses(values: &[Fr]) -> Vec::<Fr> {
+    values.iter().map(|value| value.inverse().unwrap()).collect()
+}
+
+// the folded key of round factors y_j is sum_i c_i s^i K_(shift + i) with
+// c_i the product of the y_j of the bits of i, round 0 on the top bit, so
+// X^shift prod_j (1 + y_j (s X)^(2^(rounds - 1 - j))) at the secret
+fn key_coefficients(factors: &[Fr], scale: Fr, shift: usize) -> Vec::<Fr> {
+    let mut coefficients = vec![Fr::one()];
+    for factor in factors.iter().rev() {
+        let high: Vec<_> = coefficients.iter().map(|coefficient| {
+            let mut high = *coefficient;
+            high.mul_assign(factor);
+            high
+        }).collect();
+        coefficients.extend(high);
+    }
+    for (coefficient, power) in coefficients.iter_mut().zip(powers(scale, 1 << factors.len())) {
+        coefficient.mul_assign(&power);
+    }
+    let mut shifted = vec![Fr::zero(); shift];
+    shifted.extend(coefficients);
+    shifted
+}
+
+fn key_eval(factors: &[Fr], scale: &Fr, point: &Fr, shift: usize) -> Fr {
+    let mut value = point.pow([shift as u64]);
+    let mut power = *point;
+    power.mul_assign(scale);
+    for factor in factors.iter().rev() {
+        let mut term = *factor;
+        term.mul_assign(&power);
+        term.add_assign(&Fr::one());
+        value.mul_assign(&term);
+        power.square();
+    }
+    value
+}
+
+// (f - f(z)) / (X - z) by synthetic division
+fn quotient(coefficients: &[Fr], point: &Fr) -> Vec::<Fr> {
+    let mut quotient = vec![Fr::zero(); coefficients.len() - 1];
+    let mut carry = Fr::zero();
+    for i in (1..coefficients.len()).rev() {
+        carry.mul_assign(point);
+        carry.add_assign(&coefficients[i]);
+        quotient[i - 1] = carry;
+    }
+    quotient
+}
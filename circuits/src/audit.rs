@@ -0,0 +1,187 @@
+use std::{
+    fmt,
+    error::Error,
+    collections::BTreeSet,
+};
+
+use sapling_crypto_ce::{
+    alt_babyjubjub::AltJubjubBn256,
+    eddsa::PublicKey,
+    jubjub::edwards::Point,
+};
+
+use pairing_ce::bn256::{ self, Bn256 };
+
+use super::{
+    block::{ Pubdata, PubdataOp },
+    error::OpenPlasmaError,
+    data_structs::offchain_deposit::OffchainDeposit,
+    deposit_circuit::DepositAccumulator,
+    params::poseidon_params,
+    data_structs::offchain_withdrawal::credit_fee_and_record_state,
+    public_inputs::PublicInputs,
+    tree::account::{ AccountsTree, TreeError },
+    types::{ AccountId, Balance },
+    utils::utils::fr_to_hex,
+};
+
+#[derive(Debug)]
+pub enum AuditError {
+    // the state the audit starts from is not the one the block claims
+    OldRootMismatch { expected: bn256::Fr, actual: bn256::Fr },
+    // operation index of the pubdata doesn't decode
+    Malformed { index: usize, error: OpenPlasmaError },
+    // operation index doesn't apply to root, where the operations before it
+    // left the tree
+    Inapplicable { index: usize, root: bn256::Fr, error: OpenPlasmaError },
+    // the root after the block, its fee credit included, is not the new root
+    NewRootMismatch { expected: bn256::Fr, actual: bn256::Fr },
+    // the pubdata commitment is not the claimed accum hash
+    AccumMismatch { expected: bn256::Fr, actual: bn256::Fr },
+    // operation index is not a deposit, the poseidon and sponge accumulators
+    // absorb deposits only
+    NotADeposit { index: usize },
+}
+
+impl Error for AuditError {}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            AuditError::OldRootMismatch { expected, actual } => write!(
+                f, "Block starts at root {}, the state is at {}", fr_to_hex(expected), fr_to_hex(actual)),
+            AuditError::Malformed { index, error } => write!(
+                f, "Operation {} is malformed: {}", index, error),
+            AuditError::Inapplicable { index, root, error } => write!(
+                f, "Operation {} doesn't apply at root {}: {}", index, fr_to_hex(root), error),
+            AuditError::NewRootMismatch { expected, actual } => write!(
+                f, "Block ends at root {}, expected {}", fr_to_hex(actual), fr_to_hex(expected)),
+            AuditError::AccumMismatch { expected, actual } => write!(
+                f, "Pubdata commits to accum hash {}, expected {}", fr_to_hex(actual), fr_to_hex(expected)),
+            AuditError::NotADeposit { index } => write!(
+                f, "Operation {} is not a deposit, the accumulator takes deposits only", index),
+        }
+    }
+}
+
+// the noop deposit a batch is padded with, as the accumulators absorb it
+fn noop_deposit() -> OffchainDeposit {
+    OffchainDeposit {
+        account_id: AccountId(0),
+        pubkey: PublicKey::<Bn256>(Point::zero()),
+        token_id: 0,
+        amount: Balance(0),
+    }
+}
+
+// the accum hash the block's circuit chains: sha256 is Pubdata::commitment and
+// skips the noops, poseidon and the sponge absorb the deposits and refunds
+// and then the noops the batch was padded with, which are not in the pubdata
+fn accumulate(
+    pubdata: &Pubdata,
+    ops: &[PubdataOp],
+    accumulator: DepositAccumulator,
+    noops: usize,
+    old_accum_hash: bn256::Fr,
+) -> bn256::Fr {
+    if accumulator == DepositAccumulator::Sha256 {
+        return pubdata.commitment(old_accum_hash);
+    }
+
+    let hash_params = poseidon_params();
+    let noop = noop_deposit();
+    let deposits = ops.iter().filter_map(|op| match op {
+        PubdataOp::Deposit(deposit) | PubdataOp::Refund(deposit) => Some(deposit),
+        _ => None,
+    });
+    deposits.chain((0..noops).map(|_| &noop)).fold(old_accum_hash, |hash, deposit| {
+        match accumulator {
+            DepositAccumulator::Sponge => deposit.sponge_hash(hash, hash_params),
+            _ => deposit.hash(hash, hash_params),
+        }
+    })
+}
+
+// the second check of a block besides its proof: the proof shows the public
+// inputs follow from some witness, this shows they follow from the published
+// pubdata. the operations are decoded one by one and replayed, as
+// replay_pubdata does, on a throwaway tree of the accounts they touch taken
+// from state, which has to be at the old root; the root after them and the
+// fee credit has to be the new root. the accum hash is chained from the old
+// one by the accumulator of the block's circuit, with the noops it was padded
+// with. state is not changed
+#[allow(clippy::too_many_arguments)]
+pub fn verify_block_against_pubdata(
+    state: &AccountsTree,
+    pubdata: &Pubdata,
+    public_inputs: &PublicInputs<Bn256>,
+    accumulator: DepositAccumulator,
+    noops: usize,
+    fee_account_id: AccountId,
+    fee_token_id: usize,
+    sign_params: &AltJubjubBn256,
+) -> Result<(), AuditError> {
+    let actual = state.get_root();
+    if actual != public_inputs.old_account_root {
+        return Err(AuditError::OldRootMismatch { expected: public_inputs.old_account_root, actual });
+    }
+
+    let ops = pubdata.ops().enumerate().map(|(index, bytes)| {
+        PubdataOp::decode(bytes, sign_params).map_err(|error| AuditError::Malformed { index, error })
+    }).collect::<Result<Vec::<_>, _>>()?;
+
+    let mut touched = BTreeSet::new();
+    touched.insert(fee_account_id.index());
+    for (index, op) in ops.iter().enumerate() {
+        match op {
+            PubdataOp::Deposit(deposit) => {
+                touched.insert(deposit.account_id.index());
+            },
+            PubdataOp::Refund(_) => {},
+            _ if accumulator != DepositAccumulator::Sha256 => return Err(AuditError::NotADeposit { index }),
+            PubdataOp::Withdrawal(withdrawal) => {
+                touched.insert(withdrawal.account_id.index());
+            },
+            PubdataOp::Transfer(transfer) => {
+                touched.insert(transfer.from_account_id.index());
+                touched.insert(transfer.to_account_id.index());
+            },
+        }
+    }
+
+    // the fee credit fails as the operation after the last one, like the
+    // fee slot of a withdrawal batch
+    let mut tree = state.with_accounts(&touched);
+    let mut total_fee = Balance(0);
+    let mut charges_fee = false;
+    for (index, op) in ops.iter().enumerate() {
+        let root = tree.get_root();
+        let inapplicable = |error| AuditError::Inapplicable { index, root, error };
+
+        if let Some(fee) = op.apply(&mut tree).map_err(inapplicable)? {
+            charges_fee = true;
+            total_fee = total_fee.checked_add(fee).ok_or(TreeError::BalanceOverflow {
+                account_id: fee_account_id.index(),
+                token_id: fee_token_id,
+            }.into()).map_err(inapplicable)?;
+        }
+    }
+    if charges_fee {
+        let root = tree.get_root();
+        credit_fee_and_record_state(&mut tree, fee_account_id, fee_token_id, total_fee).map_err(
+            |error| AuditError::Inapplicable { index: ops.len(), root, error }
+        )?;
+    }
+
+    let actual = tree.get_root();
+    if actual != public_inputs.new_account_root {
+        return Err(AuditError::NewRootMismatch { expected: public_inputs.new_account_root, actual });
+    }
+
+    let actual = accumulate(pubdata, &ops, accumulator, noops, public_inputs.old_accum_hash);
+    if actual != public_inputs.new_accum_hash {
+        return Err(AuditError::AccumMismatch { expected: public_inputs.new_accum_hash, actual });
+    }
+
+    Ok(())
+}
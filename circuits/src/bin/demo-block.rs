@@ -0,0 +1,334 @@
+use std::{
+    env,
+    fs,
+    path::{ Path, PathBuf },
+    process,
+    sync::Arc,
+};
+
+use bellman_ce::groth16::{
+    create_random_proof,
+    generate_random_parameters,
+    prepare_verifying_key,
+    verify_proof,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+use rand::thread_rng;
+
+use openplasma_circuits::{
+    block::{ BlockBuilder, Pubdata },
+    data_structs::offchain_deposit::OffchainDeposit,
+    data_structs::offchain_withdrawal::{ OffchainWithdrawal, credit_fee_and_record_state },
+    family::{ BatchConfig, CircuitFamily },
+    keys::SecretKey,
+    offchain_withdrawal_circuit::{ OffchainWithdrawalCircuit, OffchainWithdrawalBatchCircuit },
+    params::{ Params, shared_params, poseidon_params, jubjub_params },
+    prover::{
+        generate_parameters,
+        load_parameters,
+        load_layout,
+        prove_deposit_block,
+        verify_block,
+        proof_to_eth_bytes,
+        public_inputs_to_eth_bytes,
+    },
+    public_inputs::PublicInputs,
+    testing::check_circuit,
+    tree::account::AccountsTree,
+    types::{ AccountId, Balance },
+    utils::domain::{ SigningDomain, ADDRESS_BYTES, address_to_fr },
+    utils::utils::usize_to_fr,
+};
+
+const USAGE: &str = "usage: demo-block [--batch <deposits per block>] [--deposits <n>] [--withdrawals <m>]
+    [--depth <account depth>] [--out <dir>] [--keys <deposit key file>] [--no-prove]";
+
+const TOKEN_DEPTH: usize = 2;
+const FEE_ACCOUNT: AccountId = AccountId(0);
+const FUNDING: Balance = Balance(1_000_000);
+const WITHDRAWAL_AMOUNT: Balance = Balance(1000);
+const WITHDRAWAL_FEE: Balance = Balance(10);
+const TIMESTAMP: usize = 1;
+
+// a deposit block built with the BlockBuilder and a withdrawal block built
+// the way the operator does, both proven and verified, or only checked with
+// a TestConstraintSystem with --no-prove. the proof, the public inputs and
+// the pubdata of each block are written to the out dir as the contract takes
+// them:
+// cargo run --release --bin demo-block -- --batch 4 --depth 8
+// cargo run --bin demo-block -- --batch 4 --depth 8 --no-prove
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        },
+    };
+
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+struct Options {
+    batch: usize,
+    deposits: usize,
+    withdrawals: usize,
+    depth: usize,
+    out: PathBuf,
+    // the deposit proving key, generated there if there is no file yet
+    keys: Option<PathBuf>,
+    prove: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            batch: 4,
+            deposits: 0,
+            withdrawals: 2,
+            depth: 8,
+            out: PathBuf::from("demo-block"),
+            keys: None,
+            prove: true,
+        };
+        let mut deposits = None;
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "--no-prove" {
+                options.prove = false;
+                continue;
+            }
+
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            let number = |value: &String| value.parse::<usize>().map_err(|_| format!("{} is not a number", arg));
+            match arg.as_str() {
+                "--batch" => options.batch = number(value()?)?,
+                "--deposits" => deposits = Some(number(value()?)?),
+                "--withdrawals" => options.withdrawals = number(value()?)?,
+                "--depth" => options.depth = number(value()?)?,
+                "--out" => options.out = PathBuf::from(value()?),
+                "--keys" => options.keys = Some(PathBuf::from(value()?)),
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        // a full deposit batch unless asked otherwise, the rest are noops
+        options.deposits = deposits.unwrap_or(options.batch);
+
+        if options.batch == 0 || options.withdrawals == 0 {
+            return Err("--batch and --withdrawals have to be at least 1".to_string());
+        }
+        if options.deposits > options.batch {
+            return Err(format!("{} deposits don't fit a batch of {}", options.deposits, options.batch));
+        }
+        // the fee account, the funded accounts and an account per deposit
+        let accounts = 1 + options.withdrawals + options.deposits;
+        if options.depth >= usize::BITS as usize || accounts > 1 << options.depth {
+            return Err(format!("{} accounts don't fit a tree of depth {}", accounts, options.depth));
+        }
+
+        Ok(options)
+    }
+}
+
+fn demo_key(account_id: AccountId) -> SecretKey {
+    SecretKey::from_seed(format!("demo account {}", account_id.0).as_bytes())
+}
+
+// the account id in the low bytes, every account withdraws to its own address
+fn demo_address(account_id: AccountId) -> [u8; ADDRESS_BYTES] {
+    let mut address = [0u8; ADDRESS_BYTES];
+    address[ADDRESS_BYTES - 4..].copy_from_slice(&account_id.0.to_be_bytes());
+    address
+}
+
+fn write(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    fs::write(path, bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let params = shared_params();
+    let mut tree = AccountsTree::new(options.depth, TOKEN_DEPTH, poseidon_params(), jubjub_params());
+    fs::create_dir_all(&options.out).map_err(|e| format!("{}: {}", options.out.display(), e))?;
+
+    // the operator and the accounts that withdraw are funded before the blocks
+    for account_id in 0..=options.withdrawals {
+        let account_id = AccountId(account_id as u32);
+        OffchainDeposit {
+            account_id,
+            pubkey: demo_key(account_id).public_key(jubjub_params()),
+            token_id: 0,
+            amount: FUNDING,
+        }.update_tree_and_record_state(&mut tree).map_err(|e| e.to_string())?;
+    }
+    println!("{} accounts funded, root {}", options.withdrawals + 1, tree.get_root());
+
+    deposit_block(options, &mut tree, &params)?;
+    withdrawal_block(options, &mut tree)?;
+
+    Ok(())
+}
+
+fn deposit_block(
+    options: &Options,
+    tree: &mut AccountsTree,
+    params: &Arc<Params<Bn256>>,
+) -> Result<(), String> {
+    let config = BatchConfig {
+        deposit_batch: options.batch,
+        account_depth: options.depth,
+        token_depth: TOKEN_DEPTH,
+        leaf_version: tree.leaf_version(),
+    };
+
+    let mut block = BlockBuilder::new(tree, config, params, bn256::Fr::zero()).map_err(|e| e.to_string())?;
+    for i in 0..options.deposits {
+        let account_id = AccountId((options.withdrawals + 1 + i) as u32);
+        block.push_deposit(OffchainDeposit {
+            account_id,
+            pubkey: demo_key(account_id).public_key(jubjub_params()),
+            token_id: i % (1 << TOKEN_DEPTH),
+            amount: Balance(100 * (i as u128 + 1)),
+        }).map_err(|e| e.to_string())?;
+    }
+    let (circuit, public_inputs, pubdata) = block.seal().map_err(|e| e.to_string())?;
+    println!("deposit block: {} deposits in a batch of {}, root {}", pubdata.len(), options.batch, public_inputs.new_account_root);
+
+    if options.prove {
+        let key_path = options.keys.clone()
+            .unwrap_or_else(|| options.out.join(format!("deposit-{}-{}.key", options.batch, options.depth)));
+        let key = if key_path.exists() {
+            let (key_config, key) = load_parameters(&key_path).map_err(|e| format!("{}: {}", key_path.display(), e))?;
+            if key_config != config {
+                return Err(format!("{}: the key is of {:?}", key_path.display(), key_config));
+            }
+            key
+        } else {
+            println!("generating the deposit key, {}", key_path.display());
+            generate_parameters(config, &key_path).map_err(|e| format!("{}: {}", key_path.display(), e))?
+        };
+
+        // a key passed with --keys may come without its layout file
+        let layout = load_layout(&key_path)
+            .or_else(|_| CircuitFamily::new(shared_params()).layout(config))
+            .map_err(|e| e.to_string())?;
+
+        let proof = prove_deposit_block(&key, circuit).map_err(|e| e.to_string())?;
+        if !verify_block(&prepare_verifying_key(&key.vk), &proof, &layout, &public_inputs) {
+            return Err("the deposit block proof doesn't verify".to_string());
+        }
+        write(&options.out.join("deposit.proof"), &proof_to_eth_bytes(&proof))?;
+        println!("deposit block proof verified");
+    } else {
+        let report = check_circuit(circuit).map_err(|e| format!("deposit block: {}", e))?;
+        println!("deposit block witness satisfies {} constraints", report.num_constraints);
+    }
+
+    write(&options.out.join("deposit.inputs"), &public_inputs_to_eth_bytes(&public_inputs))?;
+    write(&options.out.join("deposit.pubdata"), pubdata.as_bytes())
+}
+
+fn withdrawal_block(options: &Options, tree: &mut AccountsTree) -> Result<(), String> {
+    let domain = SigningDomain::default();
+    let old_hash = bn256::Fr::zero();
+    let old_root = tree.get_root();
+    let mut accum_hash = old_hash;
+    let mut queue = Vec::with_capacity(options.withdrawals);
+    let mut pubdata = Pubdata::default();
+    let mut total_fee = Balance(0);
+
+    for account_id in 1..=options.withdrawals {
+        let account_id = AccountId(account_id as u32);
+        let nonce = tree.nonce(account_id).map_err(|e| e.to_string())?.next().ok_or("nonce overflow")?;
+        let mut withdrawal = OffchainWithdrawal {
+            account_id,
+            token_id: 0,
+            amount: WITHDRAWAL_AMOUNT,
+            fee: WITHDRAWAL_FEE,
+            nonce,
+            valid_until: 0,
+            eth_address: demo_address(account_id),
+            sign: None,
+        };
+        demo_key(account_id).sign_withdrawal(&mut withdrawal, &domain, poseidon_params(), jubjub_params());
+
+        let account_state = withdrawal.update_tree_and_record_state(tree).map_err(|e| e.to_string())?;
+        accum_hash = withdrawal.record_hash(accum_hash, poseidon_params());
+        pubdata.push_withdrawal(&withdrawal).map_err(|e| e.to_string())?;
+        total_fee = total_fee.checked_add(withdrawal.fee).ok_or("fee overflow")?;
+
+        queue.push(OffchainWithdrawalCircuit::<Bn256> {
+            account_state,
+            account_id: Some(withdrawal.account_id.to_fr()),
+            token_id: Some(usize_to_fr(withdrawal.token_id)),
+            amount: Some(withdrawal.amount.to_fr()),
+            fee: Some(withdrawal.fee.to_fr()),
+            nonce: Some(withdrawal.nonce.to_fr()),
+            valid_until: Some(usize_to_fr(withdrawal.valid_until)),
+            eth_address: Some(address_to_fr(&withdrawal.eth_address)),
+            sign: withdrawal.sign.clone(),
+            pubkey: Some(tree.get_pubkey(account_id.index()).map_err(|e| e.to_string())?.0),
+            is_noop: Some(false),
+        });
+    }
+
+    let fee_account_state = credit_fee_and_record_state(tree, FEE_ACCOUNT, 0, total_fee).map_err(|e| e.to_string())?;
+    let public_inputs = PublicInputs::<Bn256>::new(old_hash, accum_hash, old_root, tree.get_root())
+        .with_total_fee(total_fee.to_fr());
+
+    let circuit = OffchainWithdrawalBatchCircuit {
+        batch_size: options.withdrawals,
+        account_depth: options.depth,
+        token_depth: TOKEN_DEPTH,
+        hash_params: poseidon_params(),
+        sign_params: jubjub_params(),
+        signing_domain: domain,
+        reject_zero_amount: false,
+        queue,
+        fee_account_state,
+        fee_account_id: Some(FEE_ACCOUNT.to_fr()),
+        fee_token_id: Some(usize_to_fr(0)),
+        total_fee: Some(total_fee.to_fr()),
+        timestamp: Some(usize_to_fr(TIMESTAMP)),
+        old_accum_hash: Some(public_inputs.old_accum_hash),
+        new_accum_hash: Some(public_inputs.new_accum_hash),
+        old_account_root: Some(public_inputs.old_account_root),
+        new_account_root: Some(public_inputs.new_account_root),
+    };
+    println!("withdrawal block: {} withdrawals, root {}", pubdata.len(), public_inputs.new_account_root);
+
+    // the timestamp is the input after the block public inputs and the total fee
+    let mut inputs = public_inputs.to_vec();
+    inputs.push(usize_to_fr(TIMESTAMP));
+    let mut input_bytes = public_inputs_to_eth_bytes(&public_inputs);
+    usize_to_fr(TIMESTAMP).into_repr().write_be(&mut input_bytes).map_err(|e| e.to_string())?;
+
+    if options.prove {
+        // there are no key files for withdrawal batches, the setup is done every run
+        let mut rng = thread_rng();
+        let key = generate_random_parameters(circuit.clone(), &mut rng).map_err(|e| e.to_string())?;
+        let proof = create_random_proof(circuit, &key, &mut rng).map_err(|e| e.to_string())?;
+        if !verify_proof(&prepare_verifying_key(&key.vk), &proof, &inputs).unwrap_or(false) {
+            return Err("the withdrawal block proof doesn't verify".to_string());
+        }
+        write(&options.out.join("withdrawal.proof"), &proof_to_eth_bytes(&proof))?;
+        println!("withdrawal block proof verified");
+    } else {
+        let report = check_circuit(circuit).map_err(|e| format!("withdrawal block: {}", e))?;
+        println!("withdrawal block witness satisfies {} constraints", report.num_constraints);
+    }
+
+    write(&options.out.join("withdrawal.inputs"), &input_bytes)?;
+    write(&options.out.join("withdrawal.pubdata"), pubdata.as_bytes())
+}
@@ -0,0 +1,209 @@
+use std::{
+    env,
+    fs,
+    io::{ self, Read },
+    process,
+    collections::HashMap,
+};
+
+use rand::{ Rng, thread_rng };
+
+use serde_json::{ json, Value };
+
+use sapling_crypto_ce::eddsa::PublicKey;
+
+use pairing_ce::bn256::Bn256;
+
+use openplasma_circuits::{
+    keys::{ SecretKey, sign_withdrawal_json },
+    data_structs::offchain_withdrawal::OffchainWithdrawal,
+    tree::account::{ AccountsTree, TREE_FILE_MAGIC },
+    tree::snapshot::StateSnapshot,
+    params::{ poseidon_params, jubjub_params },
+    utils::domain::SigningDomain,
+    utils::serde_address,
+    utils::point::{ pack_point, unpack_point },
+    utils::utils::{ fs_to_fr, fr_to_hex, fr_from_hex },
+};
+
+const SEED_BYTES: usize = 32;
+
+const USAGE: &str = "usage: plasma-cli [--pretty] <command> [options]
+  keygen [--seed <hex>]
+  sign-withdrawal --account-id <id> --amount <amount> --nonce <nonce> --eth-address <hex> --seed <hex>
+      [--token-id <id>] [--fee <fee>] [--chain-id <id>] [--rollup-address <hex>]
+  verify-withdrawal --pubkey <packed pubkey> [--file <json>]
+      [--chain-id <id>] [--rollup-address <hex>]
+  inspect-snapshot <json snapshot or tree file>";
+
+// every command prints one json value, errors go to stderr as {"error": ...}:
+// cargo run --bin plasma-cli -- --pretty keygen
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let pretty = match args.iter().position(|arg| arg == "--pretty") {
+        Some(i) => {
+            args.remove(i);
+            true
+        },
+        None => false,
+    };
+
+    if args.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let command = args.remove(0);
+
+    let result = parse_options(&args).and_then(|(options, positional)| match command.as_str() {
+        "keygen" => keygen(&options),
+        "sign-withdrawal" => sign_withdrawal(&options),
+        "verify-withdrawal" => verify_withdrawal(&options),
+        "inspect-snapshot" => inspect_snapshot(&positional),
+        _ => Err(format!("unknown command {}", command)),
+    });
+
+    match result {
+        Ok((output, success)) => {
+            println!("{}", to_json(&output, pretty));
+            if !success {
+                process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", to_json(&json!({ "error": e }), pretty));
+            process::exit(1);
+        },
+    }
+}
+
+fn to_json(value: &Value, pretty: bool) -> String {
+    let json = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    };
+    json.expect("a json value serializes")
+}
+
+type Options = HashMap<String, String>;
+
+// --name value pairs and the arguments that aren't options
+fn parse_options(args: &[String]) -> Result<(Options, Vec<String>), String> {
+    let mut options = HashMap::new();
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(name) => {
+                let value = args.next().ok_or(format!("--{} needs a value", name))?;
+                options.insert(name.to_string(), value.clone());
+            },
+            None => positional.push(arg.clone()),
+        }
+    }
+
+    Ok((options, positional))
+}
+
+fn required<'a>(options: &'a Options, name: &str) -> Result<&'a str, String> {
+    options.get(name).map(String::as_str).ok_or(format!("--{} is required", name))
+}
+
+fn parsed<T: std::str::FromStr>(options: &Options, name: &str, default: Option<T>) -> Result<T, String> {
+    match (options.get(name), default) {
+        (Some(value), _) => value.parse().map_err(|_| format!("--{} is not a number", name)),
+        (None, Some(default)) => Ok(default),
+        (None, None) => Err(format!("--{} is required", name)),
+    }
+}
+
+fn parse_hex(value: &str, name: &str) -> Result<Vec<u8>, String> {
+    hex::decode(value.strip_prefix("0x").unwrap_or(value)).map_err(|_| format!("--{} is not hex", name))
+}
+
+fn domain(options: &Options) -> Result<SigningDomain, String> {
+    let chain_id = parsed(options, "chain-id", Some(0))?;
+    match options.get("rollup-address") {
+        Some(address) => SigningDomain::from_address(chain_id, &parse_hex(address, "rollup-address")?)
+            .map_err(|e| e.to_string()),
+        None => Ok(SigningDomain { chain_id, ..SigningDomain::default() }),
+    }
+}
+
+fn keygen(options: &Options) -> Result<(Value, bool), String> {
+    let seed = match options.get("seed") {
+        Some(seed) => parse_hex(seed, "seed")?,
+        None => thread_rng().gen::<[u8; SEED_BYTES]>().to_vec(),
+    };
+
+    let seckey = SecretKey::from_seed(&seed);
+    let pubkey = seckey.public_key(jubjub_params());
+
+    Ok((json!({
+        "seed": format!("0x{}", hex::encode(&seed)),
+        "private_key": fr_to_hex(&fs_to_fr::<Bn256>(seckey.expose_private_key().0)),
+        "pubkey": fr_to_hex(&pack_point(&pubkey.0)),
+    }), true))
+}
+
+fn sign_withdrawal(options: &Options) -> Result<(Value, bool), String> {
+    let seed = parse_hex(required(options, "seed")?, "seed")?;
+
+    let signed = sign_withdrawal_json(
+        &seed,
+        parsed(options, "account-id", None)?,
+        parsed(options, "token-id", Some(0))?,
+        required(options, "amount")?,
+        options.get("fee").map_or("0", String::as_str),
+        parsed(options, "nonce", None)?,
+        &serde_address::from_hex(required(options, "eth-address")?).map_err(|e| format!("--eth-address: {}", e))?,
+        &domain(options)?,
+    ).map_err(|e| format!("amount or fee: {}", e))?;
+
+    Ok((serde_json::from_str(&signed).map_err(|e| e.to_string())?, true))
+}
+
+fn verify_withdrawal(options: &Options) -> Result<(Value, bool), String> {
+    let packed = fr_from_hex(required(options, "pubkey")?).map_err(|e| format!("--pubkey: {}", e))?;
+    let pubkey = unpack_point(packed, jubjub_params()).ok_or("--pubkey packs no point")?;
+
+    let json = match options.get("file") {
+        Some(path) => fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?,
+        None => {
+            let mut json = String::new();
+            io::stdin().read_to_string(&mut json).map_err(|e| e.to_string())?;
+            json
+        },
+    };
+    let withdrawal: OffchainWithdrawal = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+
+    Ok(match withdrawal.verify_signature(&PublicKey::<Bn256>(pubkey), &domain(options)?, None, None) {
+        Ok(()) => (json!({ "valid": true }), true),
+        Err(e) => (json!({ "valid": false, "error": e.to_string() }), false),
+    })
+}
+
+fn inspect_snapshot(positional: &[String]) -> Result<(Value, bool), String> {
+    let path = positional.first().ok_or("the snapshot path is required")?;
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    // a tree file or a json snapshot, either way the root is recomputed from the accounts
+    let snapshot = if bytes.starts_with(TREE_FILE_MAGIC) {
+        AccountsTree::read(&bytes[..], poseidon_params(), jubjub_params())
+            .map_err(|e| format!("{}: {}", path, e))?
+            .export_snapshot()
+    } else {
+        let snapshot: StateSnapshot = serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+        AccountsTree::from_snapshot(&snapshot, poseidon_params(), jubjub_params())
+            .map_err(|e| format!("{}: {}", path, e))?;
+        snapshot
+    };
+
+    Ok((json!({
+        "root": fr_to_hex(&snapshot.root),
+        "account_depth": snapshot.account_depth,
+        "token_depth": snapshot.token_depth,
+        "accounts": snapshot.accounts,
+    }), true))
+}
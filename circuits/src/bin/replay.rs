@@ -0,0 +1,45 @@
+use std::{ env, fs::File, io::BufReader, process };
+
+use openplasma_circuits::{
+    replay::replay_journal,
+    params::{ poseidon_params, jubjub_params },
+};
+
+// rebuilds the tree from a journal and checks every block of it:
+// cargo run --release --bin replay -- journal.bin [tree file to save]
+fn main() {
+    let mut args = env::args().skip(1);
+    let journal_path = match args.next() {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: replay <journal> [tree file]");
+            process::exit(2);
+        },
+    };
+    let tree_path = args.next();
+
+    let journal = match File::open(&journal_path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("{}: {}", journal_path, e);
+            process::exit(1);
+        },
+    };
+
+    let (tree, blocks) = match replay_journal(journal, poseidon_params(), jubjub_params()) {
+        Ok(replayed) => replayed,
+        Err(e) => {
+            eprintln!("{}: {}", journal_path, e);
+            process::exit(1);
+        },
+    };
+    println!("{} blocks replayed, root {}", blocks, tree.get_root());
+
+    if let Some(tree_path) = tree_path {
+        if let Err(e) = tree.save(&tree_path, false) {
+            eprintln!("{}: {}", tree_path, e);
+            process::exit(1);
+        }
+        println!("tree saved to {}", tree_path);
+    }
+}
@@ -0,0 +1,579 @@
+use std::fmt;
+use std::error::Error;
+use std::mem;
+use std::sync::Arc;
+
+use sapling_crypto_ce::{
+    alt_babyjubjub::AltJubjubBn256,
+    eddsa::PublicKey,
+    jubjub::edwards::Point,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use super::{
+    account::WitnessError,
+    data_structs::offchain_deposit::{ OffchainDeposit, OFFCHAIN_DEPOSIT_BYTES },
+    data_structs::offchain_withdrawal::{ OffchainWithdrawal, OFFCHAIN_WITHDRAWAL_BYTES, credit_fee_and_record_state },
+    data_structs::offchain_transfer::{ OffchainTransfer, OFFCHAIN_TRANSFER_BYTES },
+    data_structs::encoding::{ EncodingError, HEADER_BYTES },
+    deposit_circuit::{ DepositCircuit, DepositBatchCircuit },
+    family::BatchConfig,
+    l1::{ PriorityOp, QueuedOp, DecodeError },
+    params::Params,
+    public_inputs::PublicInputs,
+    pubdata::accumulate_pubdata,
+    tree::account::{ AccountsTree, TreeError },
+    tree::merkle_tree::PathCache,
+    types::{ AccountId, Balance },
+    utils::op_type::{ DEPOSIT_OP, OFFCHAIN_WITHDRAWAL_OP, OFFCHAIN_TRANSFER_OP, REFUND_OP },
+    utils::utils::fr_to_hex,
+    instrument::timed_span,
+    metrics::metrics,
+    limits::{ Limits, LimitViolation, add_value },
+};
+use crate::error::OpenPlasmaError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    BlockFull,
+    ConfigMismatch,
+    InvalidPriorityOp(DecodeError),
+    // the sealed witness of an operation would not satisfy the circuit
+    InvalidWitness(WitnessError),
+    // the block doesn't chain onto the accum hash the contract or journal has
+    AccumHashMismatch { expected: bn256::Fr, actual: bn256::Fr },
+    // the operation would break a limit, nothing of it was applied and it
+    // can be pushed again into a later block
+    LimitExceeded(LimitViolation),
+    // a priority op is not the next one of the contract's queue
+    PriorityOpOutOfOrder { expected: u64, actual: u64 },
+    // the priority op would break a limit of this block, which takes nothing
+    // more: it is sealed and the op goes first into the next block
+    LimitEndsBlock(LimitViolation),
+    // a mempool that would promise its requests no block at all
+    NoInclusionBlocks,
+}
+
+impl Error for BlockError {}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            BlockError::BlockFull => write!(f, "Block already holds a full batch"),
+            BlockError::ConfigMismatch => write!(f, "Tree depths or leaf version are not the ones of the batch config"),
+            BlockError::InvalidPriorityOp(e) => write!(f, "Invalid priority op: {}", e),
+            BlockError::InvalidWitness(e) => write!(f, "Invalid witness: {}", e),
+            BlockError::AccumHashMismatch { expected, actual } => write!(
+                f, "Block starts at accum hash {}, expected {}", fr_to_hex(actual), fr_to_hex(expected)),
+            BlockError::LimitExceeded(e) => write!(f, "Limit exceeded: {}", e),
+            BlockError::PriorityOpOutOfOrder { expected, actual } => write!(
+                f, "Priority op {} is not the next one, expected {}", actual, expected),
+            BlockError::LimitEndsBlock(e) => write!(f, "Priority op ends the block: {}", e),
+            BlockError::NoInclusionBlocks => write!(f, "Receipts need at least one inclusion block"),
+        }
+    }
+}
+
+impl From<DecodeError> for BlockError {
+    fn from(err: DecodeError) -> Self {
+        BlockError::InvalidPriorityOp(err)
+    }
+}
+
+impl From<WitnessError> for BlockError {
+    fn from(err: WitnessError) -> Self {
+        BlockError::InvalidWitness(err)
+    }
+}
+
+impl From<LimitViolation> for BlockError {
+    fn from(err: LimitViolation) -> Self {
+        BlockError::LimitExceeded(err)
+    }
+}
+
+// a deposit block applied to the tree as it is built: every push updates the
+// tree and records the witness, seal pads the batch with noops and is the
+// only place the public inputs come from. a deposit batch circuit holds
+// deposits only, withdrawal batches also need the fee account, the total fee
+// and the timestamp and are built by the operator. deposits charge no fee, so
+// the sealed public inputs have no total fee
+pub struct BlockBuilder<'t, 'a> {
+    tree: &'t mut AccountsTree<'a>,
+    config: BatchConfig,
+    params: Arc<Params<Bn256>>,
+    limits: Limits,
+
+    deposits: Vec::<DepositCircuit<Bn256>>,
+    pubdata: Pubdata,
+    old_accum_hash: bn256::Fr,
+    accum_hash: bn256::Fr,
+    old_account_root: bn256::Fr,
+    // sum of the amounts pushed
+    value: Balance,
+    // a priority op over a limit ended the block
+    ended: bool,
+    // the accounts tree is rehashed once on seal, a deposit reads the path
+    // of its account from the cache, an account deposited to again costs
+    // only the level its path shares with the deposits in between
+    path_cache: PathCache<bn256::Fr>,
+}
+
+impl<'t, 'a> BlockBuilder<'t, 'a> {
+    // old_accum_hash is the new accum hash of the previous block, zero for the first
+    pub fn new(
+        tree: &'t mut AccountsTree<'a>,
+        config: BatchConfig,
+        params: &Arc<Params<Bn256>>,
+        old_accum_hash: bn256::Fr,
+    ) -> Result<Self, OpenPlasmaError> {
+        if tree.accounts_tree.depth() != config.account_depth
+            || tree.accounts[0].balances_tree.depth() != config.token_depth
+            || tree.leaf_version() != config.leaf_version
+        {
+            return Err(BlockError::ConfigMismatch.into());
+        }
+
+        let old_account_root = tree.get_root();
+
+        Ok(BlockBuilder {
+            tree,
+            config,
+            params: Arc::clone(params),
+            limits: Limits::default(),
+            deposits: Vec::with_capacity(config.deposit_batch),
+            pubdata: Pubdata::default(),
+            old_accum_hash,
+            accum_hash: old_accum_hash,
+            old_account_root,
+            value: Balance(0),
+            ended: false,
+            path_cache: PathCache::default(),
+        })
+    }
+
+    // no limits unless set, max_withdrawal is for the mempool
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.deposits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deposits.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        self.ended || self.deposits.len() == self.config.deposit_batch
+    }
+
+    // the block value with the deposit, checked with the balance it leaves
+    fn check_limits(&self, deposit: &OffchainDeposit) -> Result<Balance, LimitViolation> {
+        let balance = self.tree.balance(deposit.account_id, deposit.token_id).unwrap_or_default();
+        self.limits.check_balance(deposit.account_id.index(), deposit.token_id, add_value(balance, deposit.amount))?;
+        let value = add_value(self.value, deposit.amount);
+        self.limits.check_block_value(value)?;
+
+        Ok(value)
+    }
+
+    // an operator-side deposit. a refused deposit leaves the tree and the
+    // block as they were. the limits are checked against the balance and the
+    // block value after the deposit, one over them is deferred:
+    // BlockError::LimitExceeded and the caller keeps it for a later block
+    pub fn push_deposit(&mut self, deposit: OffchainDeposit) -> Result<(), OpenPlasmaError> {
+        if self.is_full() {
+            return Err(BlockError::BlockFull.into());
+        }
+
+        let value = self.check_limits(&deposit).map_err(BlockError::from)?;
+        self.apply_deposit(deposit, value)
+    }
+
+    fn apply_deposit(&mut self, deposit: OffchainDeposit, value: Balance) -> Result<(), OpenPlasmaError> {
+        let _span = timed_span!(
+            "witness.deposit",
+            account_id = deposit.account_id.index(),
+            depth = self.config.account_depth,
+        );
+
+        let encoded = deposit.encode()?;
+        let account_state = deposit.update_tree_cached(self.tree, &mut self.path_cache)?;
+        let deposit = deposit.into_circuit(account_state);
+
+        self.accum_hash = deposit.absorb(self.accum_hash, &self.params.hash_params);
+        self.pubdata.push(encoded);
+        self.deposits.push(deposit);
+        self.value = value;
+        metrics().add_ops_processed(1);
+
+        Ok(())
+    }
+
+    // a deposit of the contract's queue, marked processed in the tree so it
+    // is refused if the queue is ingested again, e.g. after a restart. the
+    // ops go in serial id order and can't be deferred past each other: one
+    // over a limit ends the block with BlockError::LimitEndsBlock. an empty
+    // block doesn't take it over the limit, it returns
+    // BlockError::LimitExceeded and the op stays next until the limits let it
+    // in or it is refunded with refund_priority_op. an op that can't be
+    // applied at all, a pubkey that doesn't decompress, one the account
+    // doesn't have or a balance it would overflow, is refunded
+    pub fn push_priority_op(&mut self, op: &PriorityOp) -> Result<(), OpenPlasmaError> {
+        self.check_priority_op(op)?;
+
+        let deposit = match op.into_deposit(&self.params.sign_params) {
+            Ok(deposit) => deposit,
+            Err(_) => return self.refund(op),
+        };
+        let value = match self.check_limits(&deposit) {
+            Ok(value) => value,
+            Err(violation) if !self.deposits.is_empty() => {
+                self.ended = true;
+                return Err(BlockError::LimitEndsBlock(violation).into());
+            },
+            Err(violation) => return Err(BlockError::LimitExceeded(violation).into()),
+        };
+        match self.apply_deposit(deposit, value) {
+            Err(OpenPlasmaError::Tree(TreeError::PubkeyMismatch(_)))
+            | Err(OpenPlasmaError::Tree(TreeError::BalanceOverflow { .. })) => return self.refund(op),
+            result => result?,
+        }
+        self.tree.mark_processed(op.serial_id)?;
+
+        Ok(())
+    }
+
+    // an op as the queue hands it out, a dust op it rejected is refunded
+    pub fn push_queued_op(&mut self, op: &QueuedOp) -> Result<(), OpenPlasmaError> {
+        match op {
+            QueuedOp::Deposit(op) => self.push_priority_op(op),
+            QueuedOp::Refund(op) => self.refund_priority_op(op),
+        }
+    }
+
+    // the op takes a slot of the block and is marked processed, but only its
+    // record is absorbed into the accum hash, see DepositCircuit::refund, and
+    // the pubdata has it under REFUND_OP for the contract to pay it back
+    pub fn refund_priority_op(&mut self, op: &PriorityOp) -> Result<(), OpenPlasmaError> {
+        self.check_priority_op(op)?;
+        self.refund(op)
+    }
+
+    fn check_priority_op(&self, op: &PriorityOp) -> Result<(), OpenPlasmaError> {
+        if self.tree.processed_ops().contains(op.serial_id) {
+            return Err(TreeError::AlreadyProcessed(op.serial_id).into());
+        }
+        let expected = self.tree.processed_ops().next_serial_id();
+        if op.serial_id != expected {
+            return Err(BlockError::PriorityOpOutOfOrder { expected, actual: op.serial_id }.into());
+        }
+        if self.is_full() {
+            return Err(BlockError::BlockFull.into());
+        }
+
+        Ok(())
+    }
+
+    // ids outside the tree have no path for the slot, such an op is refused
+    fn refund(&mut self, op: &PriorityOp) -> Result<(), OpenPlasmaError> {
+        self.tree.check_token(op.account_id.index(), op.token_id)?;
+
+        let pubkey = op.into_deposit(&self.params.sign_params)
+            .map_or_else(|_| PublicKey(Point::zero()), |deposit| deposit.pubkey);
+        let refund = OffchainDeposit {
+            account_id: op.account_id,
+            pubkey,
+            token_id: op.token_id,
+            amount: op.amount,
+        };
+        let encoded = refund.encode_refund()?;
+        let refund = DepositCircuit::refund(
+            self.config.account_depth,
+            self.config.token_depth,
+            refund.pubkey.0,
+            op.account_id.index(),
+            op.token_id,
+            op.amount.to_fr(),
+        );
+
+        self.accum_hash = refund.absorb(self.accum_hash, &self.params.hash_params);
+        self.pubdata.push(encoded);
+        self.deposits.push(refund);
+        self.tree.mark_processed(op.serial_id)?;
+        metrics().add_ops_processed(1);
+
+        Ok(())
+    }
+
+    // seal, refused unless the block chains onto the accum hash the contract
+    // or the journal expects. the tree keeps the pushed deposits either way,
+    // the caller restores it from its snapshot
+    pub fn seal_checked(
+        self,
+        expected_old_accum_hash: bn256::Fr,
+    ) -> Result<(DepositBatchCircuit<Bn256>, PublicInputs<Bn256>, Pubdata), OpenPlasmaError> {
+        if self.old_accum_hash != expected_old_accum_hash {
+            return Err(BlockError::AccumHashMismatch {
+                expected: expected_old_accum_hash,
+                actual: self.old_accum_hash,
+            }.into());
+        }
+
+        self.seal()
+    }
+
+    // the circuit, its public inputs and the pubdata of the deposits; noops
+    // are absorbed into the accum hash but are not in the pubdata. every
+    // witness is validated, a tree changed behind the builder's back makes
+    // the slot it was read for fail instead of the proof
+    pub fn seal(mut self) -> Result<(DepositBatchCircuit<Bn256>, PublicInputs<Bn256>, Pubdata), OpenPlasmaError> {
+        let _span = timed_span!(
+            "block_builder.seal",
+            batch = self.config.deposit_batch,
+            deposits = self.deposits.len(),
+            depth = self.config.account_depth,
+        );
+
+        while self.deposits.len() < self.config.deposit_batch {
+            let noop = DepositCircuit::noop(self.config.account_depth, self.config.token_depth);
+            self.accum_hash = noop.absorb(self.accum_hash, &self.params.hash_params);
+            self.deposits.push(noop);
+        }
+
+        self.tree.refresh_accounts_tree();
+        let public_inputs = PublicInputs::new(
+            self.old_accum_hash,
+            self.accum_hash,
+            self.old_account_root,
+            self.tree.get_root(),
+        );
+
+        let circuit = DepositBatchCircuit {
+            deposit_batch: self.config.deposit_batch,
+            account_depth: self.config.account_depth,
+            token_depth: self.config.token_depth,
+            params: Arc::clone(&self.params),
+            reject_zero_amount: false,
+            leaf_version: self.config.leaf_version,
+            deposit_queue: mem::take(&mut self.deposits).into(),
+            old_accum_hash: Some(public_inputs.old_accum_hash),
+            new_accum_hash: Some(public_inputs.new_accum_hash),
+            old_account_root: Some(public_inputs.old_account_root),
+            new_account_root: Some(public_inputs.new_account_root),
+        };
+        circuit.validate_witness().map_err(BlockError::from)?;
+
+        Ok((circuit, public_inputs, mem::take(&mut self.pubdata)))
+    }
+}
+
+// a builder dropped without seal leaves the pushed deposits in the tree,
+// rehashed like on seal
+impl<'t, 'a> Drop for BlockBuilder<'t, 'a> {
+    fn drop(&mut self) {
+        self.tree.refresh_accounts_tree();
+    }
+}
+
+#[derive(Clone)]
+pub enum PubdataOp {
+    Deposit(OffchainDeposit),
+    Withdrawal(OffchainWithdrawal),
+    Transfer(OffchainTransfer),
+    // a deposit of the contract's queue refunded instead of applied
+    Refund(OffchainDeposit),
+}
+
+impl PubdataOp {
+    // one operation of the pubdata, the op type byte picks the decoder
+    pub fn decode(bytes: &[u8], sign_params: &AltJubjubBn256) -> Result<Self, OpenPlasmaError> {
+        if bytes.len() < HEADER_BYTES {
+            return Err(EncodingError::Truncated { expected: HEADER_BYTES, actual: bytes.len() }.into());
+        }
+
+        Ok(match usize::from(bytes[1]) {
+            DEPOSIT_OP => PubdataOp::Deposit(OffchainDeposit::decode(bytes, sign_params)?),
+            OFFCHAIN_WITHDRAWAL_OP => PubdataOp::Withdrawal(OffchainWithdrawal::decode(bytes, Some(sign_params))?),
+            OFFCHAIN_TRANSFER_OP => PubdataOp::Transfer(OffchainTransfer::decode(bytes, sign_params)?),
+            REFUND_OP => PubdataOp::Refund(OffchainDeposit::decode_refund(bytes, sign_params)?),
+            _ => return Err(EncodingError::UnexpectedOpType(bytes[1]).into()),
+        })
+    }
+
+    // applies the operation alone, the fee it charges is credited by the caller
+    pub fn apply(&self, tree: &mut AccountsTree) -> Result<Option::<Balance>, OpenPlasmaError> {
+        match self {
+            PubdataOp::Deposit(deposit) => {
+                deposit.update_tree_and_record_state(tree)?;
+                Ok(None)
+            },
+            PubdataOp::Withdrawal(withdrawal) => {
+                withdrawal.update_tree_and_record_state(tree)?;
+                Ok(Some(withdrawal.fee))
+            },
+            PubdataOp::Transfer(transfer) => {
+                transfer.update_tree_and_record_state(tree)?;
+                Ok(Some(transfer.fee))
+            },
+            PubdataOp::Refund(_) => Ok(None),
+        }
+    }
+}
+
+// the calldata of a block: the operations in the order they were applied,
+// each in its data_structs::encoding form, so its length follows from the op
+// type byte. noops are not in it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pubdata {
+    bytes: Vec::<u8>,
+    // end of every operation in bytes
+    ends: Vec::<usize>,
+}
+
+impl Pubdata {
+    fn push(&mut self, encoded: Vec::<u8>) {
+        self.bytes.extend(encoded);
+        self.ends.push(self.bytes.len());
+    }
+
+    pub fn push_deposit(&mut self, deposit: &OffchainDeposit) -> Result<(), OpenPlasmaError> {
+        self.push(deposit.encode()?);
+        Ok(())
+    }
+
+    // withdrawals and transfers are encoded with their signature
+    pub fn push_withdrawal(&mut self, withdrawal: &OffchainWithdrawal) -> Result<(), OpenPlasmaError> {
+        self.push(withdrawal.encode()?);
+        Ok(())
+    }
+
+    pub fn push_transfer(&mut self, transfer: &OffchainTransfer) -> Result<(), OpenPlasmaError> {
+        self.push(transfer.encode()?);
+        Ok(())
+    }
+
+    pub fn push_refund(&mut self, refund: &OffchainDeposit) -> Result<(), OpenPlasmaError> {
+        self.push(refund.encode_refund()?);
+        Ok(())
+    }
+
+    pub fn push_op(&mut self, op: &PubdataOp) -> Result<(), OpenPlasmaError> {
+        match op {
+            PubdataOp::Deposit(deposit) => self.push_deposit(deposit),
+            PubdataOp::Withdrawal(withdrawal) => self.push_withdrawal(withdrawal),
+            PubdataOp::Transfer(transfer) => self.push_transfer(transfer),
+            PubdataOp::Refund(refund) => self.push_refund(refund),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // the encoded operations in order
+    pub fn ops(&self) -> impl Iterator<Item = &[u8]> {
+        let mut start = 0;
+        self.ends.iter().map(move |end| {
+            let op = &self.bytes[start..*end];
+            start = *end;
+            op
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    // every operation chained with pubdata::accumulate_pubdata, for a deposit
+    // block the accum hash of Sha256DepositBatchCircuit
+    pub fn commitment(&self, prev_hash: bn256::Fr) -> bn256::Fr {
+        self.ops().fold(prev_hash, accumulate_pubdata)
+    }
+
+    // calldata split into its operations by their op type byte, not decoded
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, OpenPlasmaError> {
+        let mut pubdata = Pubdata::default();
+        let mut rest = bytes;
+
+        while !rest.is_empty() {
+            if rest.len() < HEADER_BYTES {
+                return Err(EncodingError::Truncated { expected: HEADER_BYTES, actual: rest.len() }.into());
+            }
+
+            let len = match usize::from(rest[1]) {
+                DEPOSIT_OP | REFUND_OP => OFFCHAIN_DEPOSIT_BYTES,
+                OFFCHAIN_WITHDRAWAL_OP => OFFCHAIN_WITHDRAWAL_BYTES,
+                OFFCHAIN_TRANSFER_OP => OFFCHAIN_TRANSFER_BYTES,
+                _ => return Err(EncodingError::UnexpectedOpType(rest[1]).into()),
+            };
+            if rest.len() < len {
+                return Err(EncodingError::Truncated { expected: len, actual: rest.len() }.into());
+            }
+
+            let (op, tail) = rest.split_at(len);
+            pubdata.push(op.to_vec());
+            rest = tail;
+        }
+
+        Ok(pubdata)
+    }
+
+    // the inverse of the pushes, for watchers that only see calldata
+    pub fn parse(bytes: &[u8], sign_params: &AltJubjubBn256) -> Result<Vec::<PubdataOp>, OpenPlasmaError> {
+        Pubdata::from_bytes(bytes)?.ops().map(|op| PubdataOp::decode(op, sign_params)).collect()
+    }
+}
+
+// applies the operations of a block as the operator did, the withdrawal and
+// transfer fees are credited to the fee account of the deployment after them.
+// signatures are not checked again, the proof of the block covers them. the
+// tree is left untouched unless every operation applies
+pub fn replay_pubdata(
+    tree: &mut AccountsTree,
+    ops: &[PubdataOp],
+    fee_account_id: AccountId,
+    fee_token_id: usize,
+) -> Result<(), OpenPlasmaError> {
+    let checkpoint = tree.checkpoint();
+
+    let result = (|| {
+        let mut total_fee = Balance(0);
+        let mut charges_fee = false;
+
+        for op in ops.iter() {
+            if let Some(fee) = op.apply(tree)? {
+                charges_fee = true;
+                total_fee = total_fee.checked_add(fee).ok_or(TreeError::BalanceOverflow {
+                    account_id: fee_account_id.index(),
+                    token_id: fee_token_id,
+                })?;
+            }
+        }
+
+        if charges_fee {
+            credit_fee_and_record_state(tree, fee_account_id, fee_token_id, total_fee)?;
+        }
+
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => tree.commit(checkpoint),
+        Err(err) => {
+            tree.rollback(checkpoint)?;
+            Err(err)
+        },
+    }
+}
@@ -0,0 +1,721 @@
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        Unknown,
+        JubjubEngine,
+        edwards::Point,
+    },
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        poseidon_hash::poseidon_hash,
+        num::AllocatedNum,
+        boolean::{ Boolean, AllocatedBit },
+    },
+    eddsa::Signature,
+};
+
+use ff_ce::{ Field, PrimeField };
+
+use crate::types::{ BALANCE_BITS, NONCE_BITS };
+use crate::utils::sign::{ verify_signature, check_pubkey };
+
+use super::account::{ AccountState, AccountCircuit, LEAF_PUBKEY, LEAF_NONCE };
+use super::utils::point::pack_point_gadget;
+use super::public_inputs::alloc_public_inputs;
+use super::utils::calc::{ check_decomposition_le, is_zero };
+use super::utils::op_type::{ alloc_op_type, OFFCHAIN_WITHDRAWAL_OP, TRANSFER_OP, BLOCK_OP };
+use super::utils::domain::{ SigningDomain, alloc_signing_domain, ADDRESS_BITS };
+
+// operation tag is two bits, it is absorbed into the accum hash to decode pubdata
+pub const NOOP_TAG: u8 = 0;
+pub const DEPOSIT_TAG: u8 = 1;
+pub const WITHDRAWAL_TAG: u8 = 2;
+pub const TRANSFER_TAG: u8 = 3;
+
+// every block slot updates two leaves: "a" is the depositor, withdrawer or sender,
+// "b" is the receiver of a transfer or the withdrawal fee, otherwise it is
+// an unchanged leaf of account 0 and the same token
+#[derive(Clone)]
+pub enum Operation<E: JubjubEngine> {
+    Noop,
+    Deposit {
+        pubkey: Option::<Point<E, Unknown>>,
+        account_id: Option::<E::Fr>,
+        token_id: Option::<E::Fr>,
+        amount: Option::<E::Fr>,
+    },
+    Withdrawal {
+        account_id: Option::<E::Fr>,
+        fee_account_id: Option::<E::Fr>,
+        token_id: Option::<E::Fr>,
+        amount: Option::<E::Fr>,
+        fee: Option::<E::Fr>,
+        nonce: Option::<E::Fr>,
+        // L1 address the amount is paid out to, see domain::address_to_fr
+        eth_address: Option::<E::Fr>,
+    },
+    Transfer {
+        account_id_from: Option::<E::Fr>,
+        account_id_to: Option::<E::Fr>,
+        token_id: Option::<E::Fr>,
+        amount: Option::<E::Fr>,
+        nonce: Option::<E::Fr>,
+    },
+}
+
+// the same set of values for every operation type
+struct SlotValues<E: JubjubEngine> {
+    account_id_a: Option::<E::Fr>,
+    account_id_b: Option::<E::Fr>,
+    token_id: Option::<E::Fr>,
+    amount: Option::<E::Fr>,
+    fee: Option::<E::Fr>,
+    nonce: Option::<E::Fr>,
+    eth_address: Option::<E::Fr>,
+    pubkey_x: Option::<E::Fr>,
+    pubkey_y: Option::<E::Fr>,
+}
+
+impl<E: JubjubEngine> Operation<E> {
+    pub fn tag(&self) -> u8 {
+        match self {
+            Operation::Noop => NOOP_TAG,
+            Operation::Deposit { .. } => DEPOSIT_TAG,
+            Operation::Withdrawal { .. } => WITHDRAWAL_TAG,
+            Operation::Transfer { .. } => TRANSFER_TAG,
+        }
+    }
+
+    fn values(&self) -> SlotValues<E> {
+        let zero = Some(E::Fr::zero());
+
+        match self {
+            Operation::Noop => SlotValues {
+                account_id_a: zero,
+                account_id_b: zero,
+                token_id: zero,
+                amount: zero,
+                fee: zero,
+                nonce: zero,
+                eth_address: zero,
+                pubkey_x: zero,
+                pubkey_y: zero,
+            },
+            Operation::Deposit { pubkey, account_id, token_id, amount } => {
+                let (pubkey_x, pubkey_y) = match pubkey {
+                    Some(point) => {
+                        let (x, y) = point.into_xy();
+                        (Some(x), Some(y))
+                    },
+                    None => (None, None),
+                };
+
+                SlotValues {
+                    account_id_a: *account_id,
+                    account_id_b: zero,
+                    token_id: *token_id,
+                    amount: *amount,
+                    fee: zero,
+                    nonce: zero,
+                    eth_address: zero,
+                    pubkey_x,
+                    pubkey_y,
+                }
+            },
+            Operation::Withdrawal { account_id, fee_account_id, token_id, amount, fee, nonce, eth_address } => SlotValues {
+                account_id_a: *account_id,
+                account_id_b: *fee_account_id,
+                token_id: *token_id,
+                amount: *amount,
+                fee: *fee,
+                nonce: *nonce,
+                eth_address: *eth_address,
+                pubkey_x: zero,
+                pubkey_y: zero,
+            },
+            Operation::Transfer { account_id_from, account_id_to, token_id, amount, nonce } => SlotValues {
+                account_id_a: *account_id_from,
+                account_id_b: *account_id_to,
+                token_id: *token_id,
+                amount: *amount,
+                fee: zero,
+                nonce: *nonce,
+                eth_address: zero,
+                pubkey_x: zero,
+                pubkey_y: zero,
+            },
+        }
+    }
+}
+
+// signature is verified in every slot to keep the shape constant, for deposits
+// and noops it may be made by any key over the withdrawal-style message
+#[derive(Clone)]
+pub struct BlockOperationCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub operation: Operation<E>,
+    pub account_state_a: AccountState<E>,
+    pub account_state_b: AccountState<E>,
+    pub sign: Option::<Signature<E>>,
+    pub pubkey: Option::<Point<E, Unknown>>,
+}
+
+impl<E> BlockOperationCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_operation<'a, CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &'a <E as PoseidonEngine>::Params,
+        sign_params: &'a <E as JubjubEngine>::Params,
+        signing_domain: &[AllocatedNum<E>; 2],
+        old_hash: &AllocatedNum<E>,
+        old_root: &AllocatedNum<E>,
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
+
+        // allocate avariables ----------------------------------------------------------
+
+        let account_circuit_a = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit a"),
+            account_depth,
+            token_depth,
+            hash_params,
+            &self.account_state_a,
+        )?;
+
+        let account_circuit_b = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit b"),
+            account_depth,
+            token_depth,
+            hash_params,
+            &self.account_state_b,
+        )?;
+
+        let values = self.operation.values();
+        let tag = self.operation.tag();
+
+        let alloc_value = |cs: &mut CS, name: &str, value: Option<E::Fr>| {
+            AllocatedNum::alloc(
+                cs.namespace(|| format!("allocate {}", name)),
+                || value.ok_or(SynthesisError::AssignmentMissing),
+            )
+        };
+
+        let account_id_a = alloc_value(&mut cs, "account id a", values.account_id_a)?;
+        let account_id_b = alloc_value(&mut cs, "account id b", values.account_id_b)?;
+        let token_id = alloc_value(&mut cs, "token id", values.token_id)?;
+        let amount = alloc_value(&mut cs, "amount", values.amount)?;
+        let fee = alloc_value(&mut cs, "fee", values.fee)?;
+        let nonce = alloc_value(&mut cs, "nonce", values.nonce)?;
+        let eth_address = alloc_value(&mut cs, "eth address", values.eth_address)?;
+        let pubkey_x = alloc_value(&mut cs, "pubkey x", values.pubkey_x)?;
+        let pubkey_y = alloc_value(&mut cs, "pubkey y", values.pubkey_y)?;
+
+        // operation type flags ---------------------------------------------------------
+
+        let tag_bit_0 = AllocatedBit::alloc(
+            cs.namespace(|| "allocate tag bit 0"),
+            Some(tag & 1 == 1),
+        )?;
+
+        let tag_bit_1 = AllocatedBit::alloc(
+            cs.namespace(|| "allocate tag bit 1"),
+            Some(tag & 2 == 2),
+        )?;
+
+        let tag_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate tag"),
+            || Ok(E::Fr::from_str(&tag.to_string()).unwrap()),
+        )?;
+
+        cs.enforce(
+            || "enforce tag bits",
+            |lc| lc + tag_bit_0.get_variable() + tag_bit_1.get_variable()
+                + tag_bit_1.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + tag_alloc.get_variable(),
+        );
+
+        // transfer = b0 * b1, deposit = b0 - transfer, withdrawal = b1 - transfer,
+        // noop = 1 - b0 - b1 + transfer, so every flag is a linear combination
+
+        let is_transfer = AllocatedBit::and(
+            cs.namespace(|| "is transfer"),
+            &tag_bit_0,
+            &tag_bit_1,
+        )?;
+
+        let b0 = tag_bit_0.get_variable();
+        let b1 = tag_bit_1.get_variable();
+        let t = is_transfer.get_variable();
+
+        // check unused fields are zero -------------------------------------------------
+
+        for (name, num) in [("account id a", &account_id_a), ("token id", &token_id), ("amount", &amount)].iter() {
+            cs.enforce(
+                || format!("check noop {} is zero", name),
+                |lc| lc + CS::one() - b0 - b1 + t,
+                |lc| lc + num.get_variable(),
+                |lc| lc,
+            );
+        }
+
+        cs.enforce(
+            || "check account id b is zero if not signed",
+            |lc| lc + CS::one() - b1,
+            |lc| lc + account_id_b.get_variable(),
+            |lc| lc,
+        );
+
+        for (name, num) in [("fee", &fee), ("eth address", &eth_address)].iter() {
+            cs.enforce(
+                || format!("check {} is zero if not withdrawal", name),
+                |lc| lc + CS::one() - b1 + t,
+                |lc| lc + num.get_variable(),
+                |lc| lc,
+            );
+        }
+
+        for (name, num) in [("pubkey x", &pubkey_x), ("pubkey y", &pubkey_y)].iter() {
+            cs.enforce(
+                || format!("check {} is zero if not deposit", name),
+                |lc| lc + CS::one() - b0 + t,
+                |lc| lc + num.get_variable(),
+                |lc| lc,
+            );
+        }
+
+        // check account id, token id consistency ---------------------------------------
+
+        check_decomposition_le(
+            cs.namespace(|| "account id a consistence"),
+            &account_id_a,
+            &account_circuit_a.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "account id b consistence"),
+            &account_id_b,
+            &account_circuit_b.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id a consistence"),
+            &token_id,
+            &account_circuit_a.balances_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id b consistence"),
+            &token_id,
+            &account_circuit_b.balances_tree.indices_alloc,
+        )?;
+
+        let old_leaf_a = &account_circuit_a.accounts_tree.old_leaf_alloc;
+        let new_leaf_a = &account_circuit_a.accounts_tree.new_leaf_alloc;
+        let old_balance_a = &account_circuit_a.balances_tree.old_leaf_alloc[0];
+        let new_balance_a = &account_circuit_a.balances_tree.new_leaf_alloc[0];
+        let old_balance_b = &account_circuit_b.balances_tree.old_leaf_alloc[0];
+        let new_balance_b = &account_circuit_b.balances_tree.new_leaf_alloc[0];
+
+        // check leaf a -----------------------------------------------------------------
+
+        // deposit credits amount, withdrawal debits amount and fee, transfer debits amount
+
+        cs.enforce(
+            || "check balance a",
+            |lc| lc + amount.get_variable(),
+            |lc| lc + b0 - b1 - t,
+            |lc| lc + new_balance_a.get_variable() - old_balance_a.get_variable()
+                + fee.get_variable(),
+        );
+
+        // signed operations bump the nonce
+
+        cs.enforce(
+            || "check nonce a",
+            |lc| lc + new_leaf_a[LEAF_NONCE].get_variable() - old_leaf_a[LEAF_NONCE].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + b1,
+        );
+
+        cs.enforce(
+            || "check signed nonce",
+            |lc| lc + b1,
+            |lc| lc + nonce.get_variable() - old_leaf_a[LEAF_NONCE].get_variable() - CS::one(),
+            |lc| lc,
+        );
+
+        nonce.limit_number_of_bits(
+            cs.namespace(|| "check nonce overflow"),
+            NONCE_BITS,
+        )?;
+
+        eth_address.limit_number_of_bits(
+            cs.namespace(|| "check eth address overflow"),
+            ADDRESS_BITS,
+        )?;
+
+        // deposit sets the pubkey, other operations keep it. the leaf holds it
+        // packed, the zero pubkey of other slots packs to zero
+
+        let packed_pubkey = pack_point_gadget(
+            cs.namespace(|| "pack pubkey"),
+            &pubkey_x,
+            &pubkey_y,
+        )?;
+
+        cs.enforce(
+            || "check pubkey a",
+            |lc| lc + b0 - t,
+            |lc| lc + packed_pubkey.get_variable() - old_leaf_a[LEAF_PUBKEY].get_variable(),
+            |lc| lc + new_leaf_a[LEAF_PUBKEY].get_variable() - old_leaf_a[LEAF_PUBKEY].get_variable(),
+        );
+
+        // deposit pubkey is a point of the prime order subgroup, other slots check
+        // the neutral element (0, 1) instead of the zero pubkey
+
+        let checked_pubkey_y = AllocatedNum::alloc(
+            cs.namespace(|| "allocate checked pubkey y"),
+            || {
+                let mut value = values.pubkey_y.ok_or(SynthesisError::AssignmentMissing)?;
+                if tag != DEPOSIT_TAG {
+                    value.add_assign(&E::Fr::one());
+                }
+                Ok(value)
+            },
+        )?;
+
+        cs.enforce(
+            || "enforce checked pubkey y",
+            |lc| lc + pubkey_y.get_variable() + CS::one() - b0 + t,
+            |lc| lc + CS::one(),
+            |lc| lc + checked_pubkey_y.get_variable(),
+        );
+
+        check_pubkey(
+            cs.namespace(|| "check pubkey"),
+            &pubkey_x,
+            &checked_pubkey_y,
+            sign_params,
+        )?;
+
+        // deposit may only set the pubkey of the empty leaf or keep the same one
+
+        let is_same_pubkey = AllocatedNum::equals(
+            cs.namespace(|| "is same pubkey"),
+            &packed_pubkey,
+            &old_leaf_a[LEAF_PUBKEY],
+        )?;
+
+        // y = 0 packs to zero
+        let is_empty_leaf = {
+            let is_zero_y = is_zero(
+                cs.namespace(|| "is old pubkey y zero"),
+                &old_leaf_a[LEAF_PUBKEY],
+            )?;
+
+            let is_zero_nonce = is_zero(
+                cs.namespace(|| "is old nonce zero"),
+                &old_leaf_a[LEAF_NONCE],
+            )?;
+
+            Boolean::and(
+                cs.namespace(|| "is empty leaf"),
+                &is_zero_y,
+                &is_zero_nonce,
+            )?
+        };
+
+        // not (is_same_pubkey or is_empty_leaf)
+        let is_overwrite = Boolean::and(
+            cs.namespace(|| "is pubkey overwrite"),
+            &is_same_pubkey.not(),
+            &is_empty_leaf.not(),
+        )?;
+
+        cs.enforce(
+            || "check deposit doesn't overwrite pubkey",
+            |lc| lc + b0 - t,
+            |_| is_overwrite.lc(CS::one(), E::Fr::one()),
+            |lc| lc,
+        );
+
+        // check leaf b -----------------------------------------------------------------
+
+        for (field, i) in [("pubkey", LEAF_PUBKEY), ("nonce", LEAF_NONCE)].iter() {
+            cs.enforce(
+                || format!("check {} b the same", field),
+                |lc| lc + account_circuit_b.accounts_tree.old_leaf_alloc[*i].get_variable(),
+                |lc| lc + CS::one(),
+                |lc| lc + account_circuit_b.accounts_tree.new_leaf_alloc[*i].get_variable(),
+            );
+        }
+
+        // transfer credits amount, withdrawal credits fee
+
+        cs.enforce(
+            || "check balance b",
+            |lc| lc + amount.get_variable(),
+            |lc| lc + t,
+            |lc| lc + new_balance_b.get_variable() - old_balance_b.get_variable()
+                - fee.get_variable(),
+        );
+
+        // transfer to self: (a - b) * inv = is_transfer has no solution for a = b
+
+        let account_ids_diff_inv = match (values.account_id_a, values.account_id_b) {
+            (Some(a), Some(b)) => {
+                let mut diff = a;
+                diff.sub_assign(&b);
+                match (tag == TRANSFER_TAG, diff.inverse()) {
+                    (true, Some(inv)) => Some(inv),
+                    _ => Some(E::Fr::zero()),
+                }
+            },
+            _ => None,
+        };
+
+        let account_ids_diff_inv = alloc_value(&mut cs, "account ids diff inverse", account_ids_diff_inv)?;
+
+        cs.enforce(
+            || "check transfer accounts are different",
+            |lc| lc + account_id_a.get_variable() - account_id_b.get_variable(),
+            |lc| lc + account_ids_diff_inv.get_variable(),
+            |lc| lc + t,
+        );
+
+        // check amount, fee and balances for overflow
+
+        for (name, num) in [
+            ("amount", &amount),
+            ("fee", &fee),
+            ("balance a", new_balance_a),
+            ("balance b", new_balance_b),
+        ].iter() {
+            num.limit_number_of_bits(
+                cs.namespace(|| format!("check {} overflow", name)),
+                BALANCE_BITS,
+            )?;
+        }
+
+        // check signature --------------------------------------------------------------
+
+        // the same messages as offchain withdrawal and transfer requests sign
+
+        let withdrawal_op_type = alloc_op_type(
+            cs.namespace(|| "allocate withdrawal op type"),
+            OFFCHAIN_WITHDRAWAL_OP,
+        )?;
+
+        let transfer_op_type = alloc_op_type(
+            cs.namespace(|| "allocate transfer op type"),
+            TRANSFER_OP,
+        )?;
+
+        // a block has no timestamp, so only withdrawals signed without expiry
+        // (valid until 0) are accepted
+
+        let no_expiry = AllocatedNum::alloc(
+            cs.namespace(|| "allocate withdrawal valid until"),
+            || Ok(E::Fr::zero()),
+        )?;
+        no_expiry.assert_zero(cs.namespace(|| "check withdrawal valid until"))?;
+
+        let withdrawal_hash = poseidon_hash(
+            cs.namespace(|| "calculate withdrawal message hash"),
+            &[
+                withdrawal_op_type,
+                account_id_a.clone(),
+                token_id.clone(),
+                amount.clone(),
+                fee.clone(),
+                nonce.clone(),
+                no_expiry,
+                eth_address.clone(),
+                signing_domain[0].clone(),
+                signing_domain[1].clone(),
+            ],
+            hash_params,
+        )?[0].clone();
+
+        let transfer_hash = poseidon_hash(
+            cs.namespace(|| "calculate transfer message hash"),
+            &[
+                transfer_op_type,
+                account_id_a.clone(),
+                account_id_b.clone(),
+                token_id.clone(),
+                amount.clone(),
+                nonce,
+            ],
+            hash_params,
+        )?[0].clone();
+
+        let message_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select message hash"),
+            &transfer_hash,
+            &withdrawal_hash,
+            &Boolean::from(is_transfer),
+        )?;
+
+        let sign_alloc = verify_signature(
+            cs.namespace(|| "verify signature"),
+            self.sign.clone(),
+            self.pubkey.clone(),
+            &message_hash,
+            sign_params,
+        )?;
+
+        // signed operations must be signed by the owner of leaf a
+
+        let signer_pubkey = pack_point_gadget(
+            cs.namespace(|| "pack signer pubkey"),
+            sign_alloc.pk.get_x(),
+            sign_alloc.pk.get_y(),
+        )?;
+
+        cs.enforce(
+            || "check signer pubkey",
+            |lc| lc + b1,
+            |lc| lc + signer_pubkey.get_variable() - old_leaf_a[LEAF_PUBKEY].get_variable(),
+            |lc| lc,
+        );
+
+        // calculate new hash -----------------------------------------------------------
+
+        let block_op_type = alloc_op_type(
+            cs.namespace(|| "allocate block op type"),
+            BLOCK_OP,
+        )?;
+
+        let new_hash = poseidon_hash(
+            cs.namespace(|| "calculate new accum hash"),
+            &[
+                block_op_type,
+                old_hash.clone(),
+                tag_alloc,
+                account_id_a,
+                account_id_b,
+                token_id,
+                amount,
+                fee,
+                eth_address,
+                pubkey_x,
+                pubkey_y,
+            ],
+            hash_params,
+        )?[0].clone();
+
+        // verify old root & calculate new root -----------------------------------------
+
+        account_circuit_a.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify a old root"),
+            old_root,
+        )?;
+
+        let intermediate_root = account_circuit_a.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate a new root"),
+        )?;
+
+        account_circuit_b.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify b old root"),
+            &intermediate_root,
+        )?;
+
+        let new_root = account_circuit_b.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate b new root"),
+        )?;
+
+        Ok((new_hash, new_root))
+    }
+}
+
+#[derive(Clone)]
+pub struct BlockCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub block_size: usize,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub signing_domain: SigningDomain,
+
+    pub operations: Vec::<BlockOperationCircuit<E>>,
+    pub old_accum_hash: Option::<E::Fr>,
+    pub new_accum_hash: Option::<E::Fr>,
+    pub old_account_root: Option::<E::Fr>,
+    pub new_account_root: Option::<E::Fr>,
+}
+
+impl<'a, E> Circuit<E> for BlockCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        if self.block_size != self.operations.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        )?;
+
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
+
+        let signing_domain = alloc_signing_domain(
+            cs.namespace(|| "allocate signing domain"),
+            &self.signing_domain,
+        )?;
+
+        for (i, operation) in self.operations.iter().enumerate() {
+            let (hash, root) = operation.process_operation(
+                cs.namespace(|| format!("verify operation {}", i)),
+                self.account_depth,
+                self.token_depth,
+                self.hash_params,
+                self.sign_params,
+                &signing_domain,
+                &prev_hash,
+                &prev_root,
+            )?;
+
+            prev_hash = hash;
+            prev_root = root;
+        }
+
+        cs.enforce(
+            || "enforce new accum hash equivalence",
+            |lc| lc + prev_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_hash.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new root equivalence",
+            |lc| lc + prev_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_root.get_variable(),
+        );
+
+        Ok(())
+    }
+}
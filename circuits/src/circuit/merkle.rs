@@ -0,0 +1,236 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::JubjubEngine,
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        num::AllocatedNum,
+        boolean::Boolean,
+    },
+};
+
+use crate::tree::merkle_tree::BINARY_ARITY;
+use crate::hasher::{ TreeHasher, Poseidon };
+use crate::utils::alloc::{ alloc_nums, alloc_bits };
+use crate::utils::tree::{
+    check_witness_length,
+    path_length,
+    indices_length,
+    calc_root_with_hasher,
+    verify_with_hasher,
+};
+
+// the witness of a leaf update: the leaf before and after, and the path of
+// siblings they share. the leaf width is up to the caller, the path comes
+// from PoseidonMerkleTree::get_leaf_path
+#[derive(Clone)]
+pub struct MerkleUpdateState<E: JubjubEngine> {
+    pub old_leaf: Vec::<Option<E::Fr>>,
+    pub new_leaf: Vec::<Option<E::Fr>>,
+    pub path: Vec::<Option<E::Fr>>,
+    pub indices: Vec::<Option<bool>>,
+}
+
+// one leaf of a tree replaced by another at the same position. the leaves,
+// path and indices are allocated once and shared by both roots, so the old
+// root proves the leaf position and the new root follows from it.
+// the caller constrains the leaf fields, e.g. AccountCircuit ties the
+// balances root field to the balances tree, and either verifies the old root
+// against a known one or calculates it to chain another update
+pub struct MerkleUpdateGadget<'a, E: JubjubEngine + PoseidonEngine, H: TreeHasher<E> = Poseidon> {
+    pub params: &'a H::Params,
+    pub arity: usize,
+    pub old_leaf_alloc: Vec::<AllocatedNum<E>>,
+    pub new_leaf_alloc: Vec::<AllocatedNum<E>>,
+    pub path_alloc: Vec::<AllocatedNum<E>>,
+    pub indices_alloc: Vec::<Boolean>,
+    // hashed before either leaf, see with_leaf_tag
+    pub leaf_tag: Option::<AllocatedNum<E>>,
+}
+
+// by hand, derive would require the params to be Clone
+impl<'a, E, H> Clone for MerkleUpdateGadget<'a, E, H>
+    where E: JubjubEngine + PoseidonEngine,
+          H: TreeHasher<E>,
+{
+    fn clone(&self) -> Self {
+        MerkleUpdateGadget {
+            params: self.params,
+            arity: self.arity,
+            old_leaf_alloc: self.old_leaf_alloc.clone(),
+            new_leaf_alloc: self.new_leaf_alloc.clone(),
+            path_alloc: self.path_alloc.clone(),
+            indices_alloc: self.indices_alloc.clone(),
+            leaf_tag: self.leaf_tag.clone(),
+        }
+    }
+}
+
+impl<'a, E> MerkleUpdateGadget<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    pub fn new<CS: ConstraintSystem<E>> (
+        cs: CS,
+        leaf_width: usize,
+        tree_depth: usize,
+        params: &'a <E as PoseidonEngine>::Params,
+        state: &MerkleUpdateState<E>,
+    ) -> Result<Self, SynthesisError> {
+        Self::new_with_arity(cs, leaf_width, tree_depth, BINARY_ARITY, params, state)
+    }
+
+    pub fn new_with_arity<CS: ConstraintSystem<E>> (
+        cs: CS,
+        leaf_width: usize,
+        tree_depth: usize,
+        arity: usize,
+        params: &'a <E as PoseidonEngine>::Params,
+        state: &MerkleUpdateState<E>,
+    ) -> Result<Self, SynthesisError> {
+        Self::new_with_hasher(cs, leaf_width, tree_depth, arity, params, state)
+    }
+}
+
+impl<'a, E, H> MerkleUpdateGadget<'a, E, H>
+    where E: JubjubEngine + PoseidonEngine,
+          H: TreeHasher<E>,
+{
+    // allocates the witness, no constraints but the booleanity of the
+    // indices. the witness lengths have to be the ones of the leaf width and
+    // the depth, a shorter path would make a smaller circuit
+    pub fn new_with_hasher<CS: ConstraintSystem<E>> (
+        mut cs: CS,
+        leaf_width: usize,
+        tree_depth: usize,
+        arity: usize,
+        params: &'a H::Params,
+        state: &MerkleUpdateState<E>,
+    ) -> Result<Self, SynthesisError> {
+        if arity < 2 || !arity.is_power_of_two() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        check_witness_length("old leaf", leaf_width, state.old_leaf.len())?;
+        check_witness_length("new leaf", leaf_width, state.new_leaf.len())?;
+        check_witness_length("leaf indices", indices_length(tree_depth, arity), state.indices.len())?;
+        check_witness_length("leaf path", path_length(tree_depth, arity), state.path.len())?;
+
+        let old_leaf_alloc = alloc_nums(
+            cs.namespace(|| "allocate old leaf"),
+            &state.old_leaf,
+        )?;
+
+        let new_leaf_alloc = alloc_nums(
+            cs.namespace(|| "allocate new leaf"),
+            &state.new_leaf,
+        )?;
+
+        let path_alloc = alloc_nums(
+            cs.namespace(|| "allocate leaf path"),
+            &state.path,
+        )?;
+
+        let indices_alloc = alloc_bits(
+            cs.namespace(|| "allocate leaf path indices"),
+            &state.indices,
+        )?;
+
+        let gadget = MerkleUpdateGadget {
+            params,
+            arity,
+            old_leaf_alloc,
+            new_leaf_alloc,
+            path_alloc,
+            indices_alloc,
+            leaf_tag: None,
+        };
+
+        Ok(gadget)
+    }
+
+    // both leaves are hashed after the constant, e.g. the tag of a
+    // tree::leaf::LeafVersion. the leaf fields keep their indices, None
+    // hashes the leaves alone
+    pub fn with_leaf_tag<CS: ConstraintSystem<E>>(
+        mut self,
+        mut cs: CS,
+        tag: Option<E::Fr>,
+    ) -> Result<Self, SynthesisError> {
+        self.leaf_tag = match tag {
+            Some(tag) => {
+                let tag_alloc = AllocatedNum::alloc(
+                    cs.namespace(|| "allocate leaf tag"),
+                    || Ok(tag),
+                )?;
+
+                // the tag is a constant, not a free witness
+                cs.enforce(
+                    || "enforce leaf tag",
+                    |lc| lc + tag_alloc.get_variable(),
+                    |lc| lc + CS::one(),
+                    |lc| lc + (tag, CS::one()),
+                );
+
+                Some(tag_alloc)
+            },
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    fn hashed_leaf(&self, leaf: &[AllocatedNum<E>]) -> Vec::<AllocatedNum<E>> {
+        self.leaf_tag.iter().chain(leaf).cloned().collect()
+    }
+
+    pub fn calc_old_root<CS: ConstraintSystem<E>>(
+        &self,
+        mut cs: CS,
+    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        calc_root_with_hasher::<E, H, _>(
+            cs.namespace(|| "calculate old root"),
+            self.params,
+            self.arity,
+            &self.hashed_leaf(&self.old_leaf_alloc),
+            &self.path_alloc,
+            &self.indices_alloc,
+        )
+    }
+
+    pub fn calc_new_root<CS: ConstraintSystem<E>>(
+        &self,
+        mut cs: CS,
+    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        calc_root_with_hasher::<E, H, _>(
+            cs.namespace(|| "calculate new root"),
+            self.params,
+            self.arity,
+            &self.hashed_leaf(&self.new_leaf_alloc),
+            &self.path_alloc,
+            &self.indices_alloc,
+        )
+    }
+
+    // enforces that the old leaf is in the tree of old_root
+    pub fn verify_old_root<CS: ConstraintSystem<E>>(
+        &self,
+        mut cs: CS,
+        old_root: &AllocatedNum<E>,
+    ) -> Result<(), SynthesisError> {
+        verify_with_hasher::<E, H, _>(
+            cs.namespace(|| "verify old root"),
+            self.params,
+            self.arity,
+            &self.hashed_leaf(&self.old_leaf_alloc),
+            &self.path_alloc,
+            &self.indices_alloc,
+            old_root,
+        )
+    }
+}
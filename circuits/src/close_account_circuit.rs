@@ -0,0 +1,265 @@
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        Unknown,
+        JubjubEngine,
+        edwards::Point,
+    },
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        poseidon_hash::poseidon_hash,
+        num::AllocatedNum,
+    },
+    eddsa::Signature,
+};
+
+use crate::utils::sign::verify_signature;
+use crate::tree::empty::empty_account_leaf;
+
+use super::account::{ AccountState, AccountCircuit, LEAF_PUBKEY, LEAF_NONCE, LEAF_BALANCES_ROOT };
+use super::utils::point::unpack_point_gadget;
+use super::public_inputs::alloc_public_inputs;
+use super::utils::op_type::{ alloc_op_type, CLOSE_ACCOUNT_OP };
+use super::utils::calc::{ check_decomposition_le, enforce_bit_length };
+use crate::types::NONCE_BITS;
+
+#[derive(Clone)]
+pub struct CloseAccountCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub account_state: AccountState<E>,
+    pub account_id: Option::<E::Fr>,
+    pub nonce: Option::<E::Fr>,
+    pub sign: Option::<Signature<E>>,
+    pub pubkey: Option::<Point<E, Unknown>>,
+}
+
+impl<E> CloseAccountCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_close_account<'a, CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &'a <E as PoseidonEngine>::Params,
+        sign_params: &'a <E as JubjubEngine>::Params,
+        empty_leaf: &[E::Fr],
+        old_hash: &AllocatedNum<E>,
+        old_root: &AllocatedNum<E>,
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
+
+        // allocate avariables ----------------------------------------------------------
+
+        let account_circuit = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit"),
+            account_depth,
+            token_depth,
+            hash_params,
+            &self.account_state,
+        )?;
+
+        let account_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate account id"),
+            || self.account_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let nonce_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate nonce"),
+            || self.nonce.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate op type"),
+            CLOSE_ACCOUNT_OP,
+        )?;
+
+        // check signature of the owner -------------------------------------------------
+
+        let close_account_hash = {
+            let hash_vec = poseidon_hash(
+                cs.namespace(|| "calculate message hash"),
+                &[
+                    op_type_alloc.clone(),
+                    account_id_alloc.clone(),
+                    nonce_alloc.clone(),
+                ],
+                hash_params,
+            )?;
+            hash_vec[0].clone()
+        };
+
+        let sign_alloc = verify_signature(
+            cs.namespace(|| "verify signature"),
+            self.sign.clone(),
+            self.pubkey.clone(),
+            &close_account_hash,
+            sign_params,
+        )?;
+
+        // check changes validity -------------------------------------------------------
+
+        // check pubkey consistency
+
+        unpack_point_gadget(
+            cs.namespace(|| "enforce pubkey and old leaf equivalence"),
+            &account_circuit.accounts_tree.old_leaf_alloc[LEAF_PUBKEY],
+            sign_alloc.pk.get_x(),
+            sign_alloc.pk.get_y(),
+        )?;
+
+        // check account id consistency
+
+        check_decomposition_le(
+            cs.namespace(|| "account id consistence"),
+            &account_id_alloc,
+            &account_circuit.accounts_tree.indices_alloc,
+        )?;
+
+        // check nonce, the signature is for the next one
+
+        cs.enforce(
+            || "nonce consistence",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one(),
+            |lc| lc + CS::one(),
+            |lc| lc + nonce_alloc.get_variable(),
+        );
+
+        enforce_bit_length(
+            cs.namespace(|| "check nonce overflow"),
+            &nonce_alloc,
+            NONCE_BITS,
+        )?;
+
+        // nothing left to withdraw: the balances root is the one of zero balances
+
+        cs.enforce(
+            || "check balances are zero",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_BALANCES_ROOT].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + (empty_leaf[LEAF_BALANCES_ROOT], CS::one()),
+        );
+
+        // new leaf is the empty one
+
+        for (i, value) in empty_leaf.iter().enumerate() {
+            cs.enforce(
+                || format!("enforce new leaf {} is empty", i),
+                |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[i].get_variable(),
+                |lc| lc + CS::one(),
+                |lc| lc + (*value, CS::one()),
+            );
+        }
+
+        // calculate new hash -----------------------------------------------------------
+
+        let new_hash = {
+            let hashes_vec = poseidon_hash(
+                cs.namespace(|| "calculate new accum hash"),
+                &[
+                    op_type_alloc.clone(),
+                    old_hash.clone(),
+                    account_id_alloc,
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
+
+        // verify old root & calculate new root -----------------------------------------
+
+        account_circuit.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify old root"),
+            old_root,
+        )?;
+
+        let new_root = account_circuit.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate new root"),
+        )?;
+
+        Ok((new_hash, new_root))
+    }
+}
+
+#[derive(Clone)]
+pub struct CloseAccountBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub batch_size: usize,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+
+    pub queue: Vec::<CloseAccountCircuit<E>>,
+    pub old_accum_hash: Option::<E::Fr>,
+    pub new_accum_hash: Option::<E::Fr>,
+    pub old_account_root: Option::<E::Fr>,
+    pub new_account_root: Option::<E::Fr>,
+}
+
+impl<'a, E> Circuit<E> for CloseAccountBatchCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        if self.batch_size != self.queue.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        // a constant of the layout, computed once for the whole batch
+        let empty_leaf = empty_account_leaf::<E>(self.token_depth, self.hash_params, self.sign_params);
+
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        )?;
+
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
+
+        for (i, close_account) in self.queue.iter().enumerate() {
+            let (hash, root) = close_account.process_close_account(
+                cs.namespace(|| format!("verify close account {}", i)),
+                self.account_depth,
+                self.token_depth,
+                self.hash_params,
+                self.sign_params,
+                &empty_leaf,
+                &prev_hash,
+                &prev_root,
+            )?;
+
+            prev_hash = hash;
+            prev_root = root;
+        }
+
+        cs.enforce(
+            || "enforce new accum hash equivalence",
+            |lc| lc + prev_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_hash.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new root equivalence",
+            |lc| lc + prev_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_root.get_variable(),
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,497 @@
+use std::mem;
+
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        Unknown,
+        JubjubEngine,
+        edwards::Point,
+    },
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        poseidon_hash::poseidon_hash,
+        num::AllocatedNum,
+        boolean::AllocatedBit,
+    },
+    eddsa::Signature,
+};
+
+use ff_ce::Field;
+
+use crate::types::{ BALANCE_BITS, NONCE_BITS };
+use crate::utils::sign::verify_signature;
+
+use super::account::{ AccountState, AccountCircuit, LEAF_PUBKEY, LEAF_NONCE };
+use super::transfer_circuit::TransferCircuit;
+use super::public_inputs::alloc_public_inputs;
+use super::utils::op_type::{ alloc_op_type, CONDITIONAL_TRANSFER_OP };
+use super::utils::calc::{ check_decomposition_le, sub, enforce_bit_length };
+
+const BITS_IN_BYTE: usize = 8;
+
+// claim: poseidon_hash([preimage]) == hash_lock and timestamp <= valid_until,
+// the amount is transferred. Refund: timestamp > valid_until, the request is
+// consumed with zero amount. Both paths have the same constraints
+#[derive(Clone)]
+pub struct ConditionalTransferCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub account_state_from: AccountState<E>,
+    pub account_state_to: AccountState<E>,
+    pub account_id_from: Option::<E::Fr>,
+    pub account_id_to: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
+    pub amount: Option::<E::Fr>,
+    pub nonce: Option::<E::Fr>,
+    pub hash_lock: Option::<E::Fr>,
+    pub valid_until: Option::<E::Fr>,
+    pub preimage: Option::<E::Fr>,
+    pub is_refund: Option::<bool>,
+    pub sign: Option::<Signature<E>>,
+    pub pubkey: Option::<Point<E, Unknown>>,
+}
+
+impl<E> ConditionalTransferCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_conditional_transfer<'a, CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &'a <E as PoseidonEngine>::Params,
+        sign_params: &'a <E as JubjubEngine>::Params,
+        timestamp: &AllocatedNum<E>,
+        old_hash: &AllocatedNum<E>,
+        old_root: &AllocatedNum<E>,
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
+
+        // allocate avariables ----------------------------------------------------------
+
+        let account_circuit_from = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit from"),
+            account_depth,
+            token_depth,
+            hash_params,
+            &self.account_state_from,
+        )?;
+
+        let account_circuit_to = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit to"),
+            account_depth,
+            token_depth,
+            hash_params,
+            &self.account_state_to,
+        )?;
+
+        let account_id_alloc_from = AllocatedNum::alloc(
+            cs.namespace(|| "allocate account id from"),
+            || self.account_id_from.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let account_id_alloc_to = AllocatedNum::alloc(
+            cs.namespace(|| "allocate account id to"),
+            || self.account_id_to.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let amount_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate amount"),
+            || self.amount.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let nonce_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate nonce"),
+            || self.nonce.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let hash_lock_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate hash lock"),
+            || self.hash_lock.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let valid_until_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate valid until"),
+            || self.valid_until.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let preimage_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate preimage"),
+            || self.preimage.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let is_refund = AllocatedBit::alloc(
+            cs.namespace(|| "allocate is refund"),
+            self.is_refund,
+        )?;
+
+        let op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate op type"),
+            CONDITIONAL_TRANSFER_OP,
+        )?;
+
+        // check signature --------------------------------------------------------------
+
+        let transfer_hash = {
+            let hash_vec = poseidon_hash(
+                cs.namespace(|| "calculate message hash"),
+                &[
+                    op_type_alloc.clone(),
+                    account_id_alloc_from.clone(),
+                    account_id_alloc_to.clone(),
+                    token_id_alloc.clone(),
+                    amount_alloc.clone(),
+                    nonce_alloc.clone(),
+                    hash_lock_alloc.clone(),
+                    valid_until_alloc.clone(),
+                ],
+                hash_params,
+            )?;
+            hash_vec[0].clone()
+        };
+
+        let sign_alloc = verify_signature(
+            cs.namespace(|| "verify signature"),
+            self.sign.clone(),
+            self.pubkey.clone(),
+            &transfer_hash,
+            sign_params,
+        )?;
+
+        // check changes validity -------------------------------------------------------
+
+        // check pubkey consistency
+
+        TransferCircuit::check_pubkey(
+            cs.namespace(|| "public key consistence"),
+            &sign_alloc.pk,
+            &account_circuit_from,
+        )?;
+
+        // check account id, token id consistency
+
+        check_decomposition_le(
+            cs.namespace(|| "account id from consistence"),
+            &account_id_alloc_from,
+            &account_circuit_from.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "account id to consistence"),
+            &account_id_alloc_to,
+            &account_circuit_to.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id from consistence"),
+            &token_id_alloc,
+            &account_circuit_from.balances_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id to consistence"),
+            &token_id_alloc,
+            &account_circuit_to.balances_tree.indices_alloc,
+        )?;
+
+        // check self transfer
+
+        let account_id_diff = sub(
+            cs.namespace(|| "account ids difference"),
+            &account_id_alloc_from,
+            &account_id_alloc_to,
+        )?;
+
+        account_id_diff.assert_nonzero(
+            cs.namespace(|| "check account ids are different"),
+        )?;
+
+        // check preimage, it is free on the refund path
+
+        let lock = poseidon_hash(
+            cs.namespace(|| "calculate preimage hash"),
+            &[preimage_alloc],
+            hash_params,
+        )?[0].clone();
+
+        cs.enforce(
+            || "check preimage if not refund",
+            |lc| lc + lock.get_variable() - hash_lock_alloc.get_variable(),
+            |lc| lc + CS::one() - is_refund.get_variable(),
+            |lc| lc,
+        );
+
+        // check timeout: diff is valid_until - timestamp for a claim and
+        // timestamp - valid_until - 1 for a refund, both must be non negative
+
+        enforce_bit_length(
+            cs.namespace(|| "check valid until overflow"),
+            &valid_until_alloc,
+            mem::size_of::<usize>() * BITS_IN_BYTE,
+        )?;
+
+        let diff = AllocatedNum::alloc(
+            cs.namespace(|| "allocate timeout diff"),
+            || {
+                let timestamp = timestamp.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+                let valid_until = valid_until_alloc.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+                let is_refund = is_refund.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+
+                if is_refund {
+                    let mut tmp = timestamp;
+                    tmp.sub_assign(&valid_until);
+                    tmp.sub_assign(&E::Fr::one());
+                    Ok(tmp)
+                } else {
+                    let mut tmp = valid_until;
+                    tmp.sub_assign(&timestamp);
+                    Ok(tmp)
+                }
+            },
+        )?;
+
+        cs.enforce(
+            || "enforce timeout diff",
+            |lc| lc + is_refund.get_variable(),
+            |lc| lc + timestamp.get_variable() + timestamp.get_variable()
+                - valid_until_alloc.get_variable() - valid_until_alloc.get_variable()
+                - CS::one(),
+            |lc| lc + diff.get_variable() - valid_until_alloc.get_variable() + timestamp.get_variable(),
+        );
+
+        enforce_bit_length(
+            cs.namespace(|| "check timeout"),
+            &diff,
+            mem::size_of::<usize>() * BITS_IN_BYTE,
+        )?;
+
+        // transferred amount is zero on the refund path
+
+        let transferred = AllocatedNum::alloc(
+            cs.namespace(|| "allocate transferred amount"),
+            || {
+                let is_refund = is_refund.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+                if is_refund {
+                    Ok(E::Fr::zero())
+                } else {
+                    amount_alloc.get_value().ok_or(SynthesisError::AssignmentMissing)
+                }
+            },
+        )?;
+
+        cs.enforce(
+            || "enforce transferred amount",
+            |lc| lc + amount_alloc.get_variable(),
+            |lc| lc + CS::one() - is_refund.get_variable(),
+            |lc| lc + transferred.get_variable(),
+        );
+
+        // check amount
+
+        cs.enforce(
+            || "check amount transfer from",
+            |lc| lc + account_circuit_from.balances_tree.old_leaf_alloc[0].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit_from.balances_tree.new_leaf_alloc[0].get_variable()
+                + transferred.get_variable(),
+        );
+
+        cs.enforce(
+            || "check amount transfer to",
+            |lc| lc + account_circuit_to.balances_tree.old_leaf_alloc[0].get_variable()
+                + transferred.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit_to.balances_tree.new_leaf_alloc[0].get_variable(),
+        );
+
+        // check amount and balances for overflow, so amount can't exceed sender balance
+
+        enforce_bit_length(
+            cs.namespace(|| "check amount overflow"),
+            &amount_alloc,
+            BALANCE_BITS,
+        )?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check from balance overflow"),
+            &account_circuit_from.balances_tree.new_leaf_alloc[0],
+            BALANCE_BITS,
+        )?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check to balance overflow"),
+            &account_circuit_to.balances_tree.new_leaf_alloc[0],
+            BALANCE_BITS,
+        )?;
+
+        // check nonce, it is consumed on both paths
+
+        cs.enforce(
+            || "nonce consistence",
+            |lc| lc + account_circuit_from.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one(),
+            |lc| lc + CS::one(),
+            |lc| lc + nonce_alloc.get_variable(),
+        );
+
+        enforce_bit_length(
+            cs.namespace(|| "check nonce overflow"),
+            &nonce_alloc,
+            NONCE_BITS,
+        )?;
+
+        cs.enforce(
+            || "check nonce + 1",
+            |lc| lc + account_circuit_from.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit_from.accounts_tree.new_leaf_alloc[LEAF_NONCE].get_variable(),
+        );
+
+        // check receiver pubkey and nonce the same
+
+        for (field, i) in [("pubkey", LEAF_PUBKEY), ("nonce", LEAF_NONCE)].iter() {
+            cs.enforce(
+                || format!("check receiver {} the same", field),
+                |lc| lc + account_circuit_to.accounts_tree.old_leaf_alloc[*i].get_variable(),
+                |lc| lc + CS::one(),
+                |lc| lc + account_circuit_to.accounts_tree.new_leaf_alloc[*i].get_variable(),
+            );
+        }
+
+        // calculate new hash -----------------------------------------------------------
+
+        let new_hash = {
+            let hashes_vec = poseidon_hash(
+                cs.namespace(|| "calculate new accum hash"),
+                &[
+                    op_type_alloc.clone(),
+                    old_hash.clone(),
+                    account_id_alloc_from,
+                    account_id_alloc_to,
+                    token_id_alloc,
+                    transferred,
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
+
+        // verify old root & calculate new root -----------------------------------------
+
+        account_circuit_from.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify from old root"),
+            old_root,
+        )?;
+
+        let root = account_circuit_from.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate from new root"),
+        )?;
+
+        account_circuit_to.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify to old root"),
+            &root,
+        )?;
+
+        let new_root = account_circuit_to.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate to new root"),
+        )?;
+
+        Ok((new_hash, new_root))
+    }
+}
+
+#[derive(Clone)]
+pub struct ConditionalTransferBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub batch_size: usize,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub queue: Vec::<ConditionalTransferCircuit<E>>,
+    // block timestamp, public input following the block public inputs
+    pub timestamp: Option::<E::Fr>,
+    pub old_accum_hash: Option::<E::Fr>,
+    pub new_accum_hash: Option::<E::Fr>,
+    pub old_account_root: Option::<E::Fr>,
+    pub new_account_root: Option::<E::Fr>,
+}
+
+impl<'a, E> Circuit<E> for ConditionalTransferBatchCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        if self.batch_size != self.queue.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        )?;
+
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
+
+        let timestamp = AllocatedNum::alloc(
+            cs.namespace(|| "allocate timestamp"),
+            || self.timestamp.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        timestamp.inputize(cs.namespace(|| "input timestamp"))?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check timestamp overflow"),
+            &timestamp,
+            mem::size_of::<usize>() * BITS_IN_BYTE,
+        )?;
+
+        for (i, transfer) in self.queue.iter().enumerate() {
+            let (hash, root) = transfer.process_conditional_transfer(
+                cs.namespace(|| format!("verify conditional transfer {}", i)),
+                self.account_depth,
+                self.token_depth,
+                self.hash_params,
+                self.sign_params,
+                &timestamp,
+                &prev_hash,
+                &prev_root,
+            )?;
+
+            prev_hash = hash;
+            prev_root = root;
+        }
+
+        cs.enforce(
+            || "enforce new accum hash equivalence",
+            |lc| lc + prev_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_hash.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new root equivalence",
+            |lc| lc + prev_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_root.get_variable(),
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use sapling_crypto_ce::{
+    eddsa::PublicKey,
+    poseidon::bn256::Bn256PoseidonParams,
+    jubjub::{
+        FixedGenerators,
+        JubjubParams,
+        ToUniform,
+        Unknown,
+        edwards::Point,
+    },
+    alt_babyjubjub::{ AltJubjubBn256, fs::{ Fs, FsRepr } },
+};
+
+use ff_ce::{ Field, PrimeField };
+
+use pairing_ce::bn256::Bn256;
+
+use rand::Rng;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use super::offchain_withdrawal::{ OffchainWithdrawal, SignatureError, is_canonical_point };
+
+use crate::utils::utils::fr_to_sign_message;
+use crate::utils::domain::SigningDomain;
+use crate::error::OpenPlasmaError;
+
+pub type SignedRequest = (OffchainWithdrawal, PublicKey::<Bn256>);
+
+// one result per request in the request order, so only the failed ones are
+// dropped from the batch; spread over threads with the parallel feature
+pub fn verify_signatures_batch(
+    requests: &[SignedRequest],
+    domain: &SigningDomain,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> Vec::<Result<(), OpenPlasmaError>> {
+    #[cfg(feature = "parallel")]
+    let requests = requests.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let requests = requests.iter();
+
+    requests.map(
+        |(withdrawal, pubkey)| withdrawal.verify_signature(pubkey, domain, Some(hash_params), Some(sign_params))
+    ).collect()
+}
+
+// the terms of 0 = -S . P_G + R + c . vk, see PublicKey::verify_for_raw_message,
+// each multiplied by the random z of the request
+struct Weighted {
+    point: Point<Bn256, Unknown>,
+    s: Fs,
+}
+
+fn weighted_terms(
+    request: &SignedRequest,
+    z: Fs,
+    domain: &SigningDomain,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> Result<Weighted, OpenPlasmaError> {
+    let (withdrawal, pubkey) = request;
+    let sign = withdrawal.sign.as_ref().ok_or(SignatureError::MissingSignature)?;
+    if !is_canonical_point(&sign.r, sign_params) || !is_canonical_point(&pubkey.0, sign_params) {
+        return Err(SignatureError::InvalidPoint.into());
+    }
+
+    let mut msg = fr_to_sign_message(withdrawal.hash(domain, Some(hash_params))).to_vec();
+    msg.resize(32, 0u8);
+    let mut zc = Fs::to_uniform_32(msg.as_ref());
+    zc.mul_assign(&z);
+
+    let mut s = sign.s;
+    s.mul_assign(&z);
+
+    Ok(Weighted {
+        point: pubkey.0.mul(zc, sign_params).add(&sign.r.mul(z, sign_params), sign_params),
+        s,
+    })
+}
+
+// random linear combination of the verification equations: one fixed base
+// multiplication for the whole batch and 128 bit weights for the R points.
+// when the combined check fails every request is verified on its own, so the
+// results are exactly those of verify_signatures_batch
+pub fn verify_signatures_combined<R: Rng>(
+    requests: &[SignedRequest],
+    domain: &SigningDomain,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+    rng: &mut R,
+) -> Vec::<Result<(), OpenPlasmaError>> {
+    let weights: Vec<_> = requests.iter().map(
+        |_| Fs::from_repr(FsRepr([rng.gen(), rng.gen(), 0, 0])).unwrap()
+    ).collect();
+
+    #[cfg(feature = "parallel")]
+    let weighted = requests.par_iter().zip(weights.into_par_iter());
+    #[cfg(not(feature = "parallel"))]
+    let weighted = requests.iter().zip(weights);
+
+    let terms: Vec<_> = weighted.map(
+        |(request, z)| weighted_terms(request, z, domain, hash_params, sign_params)
+    ).collect();
+
+    let mut sum = Point::<Bn256, Unknown>::zero();
+    let mut sum_s = Fs::zero();
+    for term in terms.iter().flatten() {
+        sum = sum.add(&term.point, sign_params);
+        sum_s.add_assign(&term.s);
+    }
+    let generator = sign_params.generator(FixedGenerators::SpendingKeyGenerator);
+    let sum = sum.add(&generator.mul(sum_s, sign_params).negate().into(), sign_params);
+
+    if sum.eq(&Point::zero()) {
+        return terms.into_iter().map(|term| term.map(|_| ())).collect();
+    }
+
+    let mut results = verify_signatures_batch(requests, domain, hash_params, sign_params);
+    for (result, term) in results.iter_mut().zip(terms) {
+        // refused before the combination, the same error either way
+        if let Err(err) = term {
+            *result = Err(err);
+        }
+    }
+    results
+}
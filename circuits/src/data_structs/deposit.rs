@@ -1,65 +1,92 @@
-use sapling_crypto_ce::eddsa::PublicKey;
+use sapling_crypto_ce::{
+    eddsa::PublicKey,
+    alt_babyjubjub::AltJubjubBn256,
+};
 use pairing_ce::bn256::Bn256;
 
-use crate::account::AccountState;
+use crate::account::{ AccountState, AccountLeaf };
 
 use super::super::{
-    tree::account::AccountsTree,
+    tree::account::{ AccountsTree, LeafUpdate, TreeError },
 };
 
 use crate::utils::utils::{
-    optionalize,
-    fr_to_usize,
-    usize_to_fr,
+    u128_to_fr,
+    fr_to_u128_checked,
 };
+use crate::error::OpenPlasmaError;
 
 #[derive(Clone)]
 pub struct Deposit {
     pub pubkey: Option::<PublicKey::<Bn256>>,
     pub account_id: usize,
-    pub amount: usize,
+    pub token_id: usize,
+    pub amount: u128,
 }
 
 impl Deposit {
+    // the circuit accepts only points of the prime order subgroup
+    pub fn is_valid_pubkey(
+        &self,
+        sign_params: &AltJubjubBn256,
+    ) -> bool {
+        match &self.pubkey {
+            Some(pubkey) => pubkey.0.as_prime_order(sign_params).is_some(),
+            None => false,
+        }
+    }
+
+    // the change update_tree_and_record_state makes, for AccountsTree::apply_batch
+    pub fn leaf_update(&self) -> LeafUpdate {
+        LeafUpdate {
+            account_id: self.account_id,
+            token_id: self.token_id,
+            pubkey: self.pubkey.clone(),
+            credit: self.amount,
+            debit: 0,
+            increment_nonce: false,
+        }
+    }
+
     pub fn update_tree_and_record_state(
         &self,
         tree: &mut AccountsTree,
-    ) -> AccountState::<Bn256> {
-        assert!(self.account_id < tree.accounts.len());
-
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
         // count balances
-        let old_balance = tree.accounts[self.account_id].balance;
-        let new_balance = usize_to_fr(fr_to_usize(old_balance) + self.amount);
+        let old_balance = tree.get_balance(self.account_id, self.token_id)?;
+        let new_balance = fr_to_u128_checked(&old_balance)?.checked_add(self.amount).ok_or(
+            TreeError::BalanceOverflow { account_id: self.account_id, token_id: self.token_id }
+        )?;
+        let new_balance = u128_to_fr(new_balance);
 
         // prepare paths, indices, pubkeys, nonces
         let old_pubkey = tree.accounts[self.account_id].pubkey.clone();
         let nonce = tree.accounts[self.account_id].nonce;
-        let new_pubkey = self.pubkey.clone().unwrap();
+        let new_pubkey = self.pubkey.clone().ok_or(TreeError::EmptyAccount(self.account_id))?;
         let account_path = tree.accounts_tree.get_leaf_path(self.account_id);
         let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id);
+        let token_path = tree.get_token_path(self.account_id, self.token_id)?;
+        let token_indices = tree.get_token_indices(self.account_id, self.token_id)?;
 
         // update balance & account
         tree.update_balance(
             self.account_id,
+            self.token_id,
             new_balance,
-        );
+        )?;
 
         tree.update_account(
             self.account_id,
             new_pubkey.clone(),
             nonce,
-        );
+        )?;
 
         // record account state
-        AccountState::<Bn256> {
-            old_balance: Some(old_balance),
-            new_balance: Some(new_balance),
-            old_pubkey: Some(old_pubkey.0),
-            new_pubkey: Some(new_pubkey.0),
-            old_nonce: Some(nonce),
-            new_nonce: Some(nonce),
-            account_path: optionalize(account_path),
-            account_indices: optionalize(account_indices),
-        }
+        Ok(AccountState::from_transition(
+            AccountLeaf { pubkey: old_pubkey.0, nonce, balance: old_balance },
+            AccountLeaf { pubkey: new_pubkey.0, nonce, balance: new_balance },
+            (&account_path, &token_path),
+            (&account_indices, &token_indices),
+        ))
     }
 }
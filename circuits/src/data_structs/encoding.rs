@@ -0,0 +1,202 @@
+use std::{
+    fmt,
+    error::Error,
+    convert::{ TryFrom, TryInto },
+};
+
+use sapling_crypto_ce::{
+    eddsa::{ PublicKey, Signature },
+    jubjub::edwards::Point,
+    alt_babyjubjub::{ AltJubjubBn256, fs::Fs },
+};
+
+use ff_ce::{ PrimeField, PrimeFieldRepr };
+
+use pairing_ce::bn256::{ self, Bn256 };
+
+use crate::types::{ Balance, Nonce, AccountId };
+use crate::error::OpenPlasmaError;
+use crate::utils::domain::ADDRESS_BYTES;
+use super::offchain_withdrawal::is_canonical_point;
+
+// every encoded operation starts with the version and the op type byte,
+// numbers are fixed width little endian, points are compressed to 32 bytes
+// and a signature is r then s, 64 bytes. L1 addresses are the 20 raw bytes.
+// version 2 added the eth address of withdrawals
+pub const ENCODING_VERSION: u8 = 2;
+pub const HEADER_BYTES: usize = 2;
+pub const POINT_BYTES: usize = 32;
+pub const SIGNATURE_BYTES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodingError {
+    Truncated { expected: usize, actual: usize },
+    Overlong { expected: usize, actual: usize },
+    UnsupportedVersion(u8),
+    UnexpectedOpType(u8),
+    Unsigned,
+    ValueOutOfRange(&'static str),
+    InvalidPoint,
+    NonCanonicalScalar,
+}
+
+impl Error for EncodingError {}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            EncodingError::Truncated { expected, actual } => write!(
+                f, "Operation is {} bytes, expected {}", actual, expected),
+            EncodingError::Overlong { expected, actual } => write!(
+                f, "Operation is {} bytes, expected only {}", actual, expected),
+            EncodingError::UnsupportedVersion(version) => write!(f, "Encoding version {} is not supported", version),
+            EncodingError::UnexpectedOpType(op) => write!(f, "Op type {} is not the decoded operation", op),
+            EncodingError::Unsigned => write!(f, "Operation is not signed"),
+            EncodingError::ValueOutOfRange(field) => write!(f, "{} doesn't fit its encoding", field),
+            EncodingError::InvalidPoint => write!(f, "Point is not on the curve or not of prime order"),
+            EncodingError::NonCanonicalScalar => write!(f, "Signature s is not canonical"),
+        }
+    }
+}
+
+pub struct Encoder {
+    bytes: Vec::<u8>,
+}
+
+impl Encoder {
+    pub fn new(op_type: usize, len: usize) -> Self {
+        let mut bytes = Vec::with_capacity(len);
+        bytes.push(ENCODING_VERSION);
+        bytes.push(op_type as u8);
+        Encoder { bytes }
+    }
+
+    pub fn account_id(&mut self, account_id: AccountId) {
+        self.bytes.extend_from_slice(&account_id.0.to_le_bytes());
+    }
+
+    pub fn balance(&mut self, balance: Balance) {
+        self.bytes.extend_from_slice(&balance.0.to_le_bytes());
+    }
+
+    pub fn nonce(&mut self, nonce: Nonce) {
+        self.bytes.extend_from_slice(&nonce.0.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: usize, field: &'static str) -> Result<(), OpenPlasmaError> {
+        let value = u32::try_from(value).map_err(|_| EncodingError::ValueOutOfRange(field))?;
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn u64(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    pub fn address(&mut self, address: &[u8; ADDRESS_BYTES]) {
+        self.bytes.extend_from_slice(address);
+    }
+
+    pub fn fr(&mut self, fr: &bn256::Fr) {
+        fr.into_repr().write_le(&mut self.bytes).expect("writing to a vec never fails");
+    }
+
+    pub fn pubkey(&mut self, pubkey: &PublicKey::<Bn256>) {
+        pubkey.write(&mut self.bytes).expect("writing to a vec never fails");
+    }
+
+    pub fn sign(&mut self, sign: &Option<Signature::<Bn256>>) -> Result<(), OpenPlasmaError> {
+        let sign = sign.as_ref().ok_or(EncodingError::Unsigned)?;
+        sign.r.write(&mut self.bytes).expect("writing to a vec never fails");
+        sign.s.into_repr().write_le(&mut self.bytes).expect("writing to a vec never fails");
+        Ok(())
+    }
+
+    pub fn finish(self) -> Vec::<u8> {
+        self.bytes
+    }
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    // checks the whole layout up front, the reads below can't run out of bytes
+    pub fn new(bytes: &'a [u8], op_type: usize, len: usize) -> Result<Self, OpenPlasmaError> {
+        if bytes.len() < HEADER_BYTES {
+            return Err(EncodingError::Truncated { expected: len, actual: bytes.len() }.into());
+        }
+        if bytes[0] != ENCODING_VERSION {
+            return Err(EncodingError::UnsupportedVersion(bytes[0]).into());
+        }
+        if usize::from(bytes[1]) != op_type {
+            return Err(EncodingError::UnexpectedOpType(bytes[1]).into());
+        }
+        if bytes.len() < len {
+            return Err(EncodingError::Truncated { expected: len, actual: bytes.len() }.into());
+        }
+        if bytes.len() > len {
+            return Err(EncodingError::Overlong { expected: len, actual: bytes.len() }.into());
+        }
+
+        Ok(Decoder { bytes: &bytes[HEADER_BYTES..] })
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        head.try_into().unwrap()
+    }
+
+    pub fn account_id(&mut self) -> AccountId {
+        AccountId(u32::from_le_bytes(self.take()))
+    }
+
+    pub fn balance(&mut self) -> Balance {
+        Balance(u128::from_le_bytes(self.take()))
+    }
+
+    pub fn nonce(&mut self) -> Nonce {
+        Nonce(u32::from_le_bytes(self.take()))
+    }
+
+    pub fn u32(&mut self) -> usize {
+        u32::from_le_bytes(self.take()) as usize
+    }
+
+    pub fn u64(&mut self, field: &'static str) -> Result<usize, OpenPlasmaError> {
+        Ok(usize::try_from(u64::from_le_bytes(self.take())).map_err(|_| EncodingError::ValueOutOfRange(field))?)
+    }
+
+    pub fn address(&mut self) -> [u8; ADDRESS_BYTES] {
+        self.take()
+    }
+
+    pub fn fr(&mut self, field: &'static str) -> Result<bn256::Fr, OpenPlasmaError> {
+        let bytes = self.take::<32>();
+        let mut repr = <bn256::Fr as PrimeField>::Repr::default();
+        repr.read_le(&bytes[..]).expect("the repr is 32 bytes");
+        Ok(bn256::Fr::from_repr(repr).map_err(|_| EncodingError::ValueOutOfRange(field))?)
+    }
+
+    pub fn pubkey(&mut self, sign_params: &AltJubjubBn256) -> Result<PublicKey::<Bn256>, OpenPlasmaError> {
+        let bytes = self.take::<POINT_BYTES>();
+        Ok(PublicKey::read(&bytes[..], sign_params).map_err(|_| EncodingError::InvalidPoint)?)
+    }
+
+    pub fn sign(&mut self, sign_params: &AltJubjubBn256) -> Result<Option<Signature::<Bn256>>, OpenPlasmaError> {
+        let r = self.take::<POINT_BYTES>();
+        let r = Point::read(&r[..], sign_params).map_err(|_| EncodingError::InvalidPoint)?;
+        if !is_canonical_point(&r, sign_params) {
+            return Err(EncodingError::InvalidPoint.into());
+        }
+
+        let s = self.take::<32>();
+        let mut s_repr = <Fs as PrimeField>::Repr::default();
+        s_repr.read_le(&s[..]).expect("the repr is 32 bytes");
+        let s = Fs::from_repr(s_repr).map_err(|_| EncodingError::NonCanonicalScalar)?;
+
+        Ok(Some(Signature { r, s }))
+    }
+}
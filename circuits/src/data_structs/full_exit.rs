@@ -0,0 +1,66 @@
+use crate::account::AccountState;
+
+use super::super::{
+    tree::account::AccountsTree,
+};
+
+use crate::utils::utils::{
+    optionalize,
+    usize_to_fr,
+    fr_to_u128_checked,
+};
+use crate::error::OpenPlasmaError;
+
+use pairing_ce::bn256::Bn256;
+
+// requested on L1, so it is not signed
+#[derive(Clone)]
+pub struct FullExit {
+    pub account_id: usize,
+    pub token_id: usize,
+}
+
+impl FullExit {
+
+    // returns account state and withdrawn amount of the token
+    pub fn update_tree_and_record_state(
+        &self,
+        tree: &mut AccountsTree,
+    ) -> Result<(AccountState::<Bn256>, u128), OpenPlasmaError> {
+        // whole token balance is withdrawn
+        let old_balance = tree.get_balance(self.account_id, self.token_id)?;
+        let amount = fr_to_u128_checked(&old_balance)?;
+        let new_balance = usize_to_fr(0);
+
+        // prepare paths, indices, pubkeys, nonces
+        let pubkey = tree.accounts[self.account_id].pubkey.clone();
+        let nonce = tree.accounts[self.account_id].nonce;
+        let account_path = tree.accounts_tree.get_leaf_path(self.account_id);
+        let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id);
+        let token_path = tree.get_token_path(self.account_id, self.token_id)?;
+        let token_indices = tree.get_token_indices(self.account_id, self.token_id)?;
+
+        // update balance
+        tree.update_balance(
+            self.account_id,
+            self.token_id,
+            new_balance,
+        )?;
+
+        // record account state
+        let state = AccountState::<Bn256> {
+            old_balance: Some(old_balance),
+            new_balance: Some(new_balance),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0),
+            old_nonce: Some(nonce),
+            new_nonce: Some(nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        };
+
+        Ok((state, amount))
+    }
+}
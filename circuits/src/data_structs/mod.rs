@@ -2,3 +2,12 @@ pub mod transfer;
 pub mod deposit;
 pub mod onchain_withdrawal;
 pub mod offchain_withdrawal;
+pub mod offchain_change_pubkey;
+pub mod full_exit;
+pub mod offchain_conditional_transfer;
+pub mod swap;
+pub mod close_account;
+pub mod offchain_transfer;
+pub mod offchain_deposit;
+pub mod encoding;
+pub mod batch_verification;
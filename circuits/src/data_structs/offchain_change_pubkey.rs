@@ -0,0 +1,150 @@
+use crate::account::AccountState;
+use sapling_crypto_ce::eddsa::Signature;
+
+use super::super::{
+    tree::account::AccountsTree,
+};
+
+use crate::utils::op_type::CHANGE_PUBKEY_OP;
+use crate::types::Nonce;
+
+use crate::utils::utils::{
+    optionalize,
+    usize_to_fr,
+    fr_to_sign_message,
+};
+use crate::error::OpenPlasmaError;
+use super::offchain_withdrawal::SignatureError;
+
+use sapling_crypto_ce::{
+    eddsa::{
+        PrivateKey,
+        PublicKey,
+    },
+    poseidon::{
+        poseidon_hash,
+        bn256::Bn256PoseidonParams,
+    },
+    jubjub::FixedGenerators,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use rand::thread_rng;
+
+pub use crate::utils::signature::NUM_BYTES_TO_SIGN;
+
+#[derive(Clone)]
+pub struct OffchainChangePubKey {
+    pub account_id: usize,
+    pub new_pubkey: PublicKey::<Bn256>,
+    pub nonce: Nonce,
+    pub sign: Option<Signature::<Bn256>>,
+}
+
+impl OffchainChangePubKey {
+
+    pub fn hash(
+        & self,
+        hash_params: &Bn256PoseidonParams
+    ) -> bn256::Fr {
+        let (new_pubkey_x, new_pubkey_y) = self.new_pubkey.0.into_xy();
+        let request = vec![
+            usize_to_fr(CHANGE_PUBKEY_OP),
+            usize_to_fr(self.account_id),
+            new_pubkey_x,
+            new_pubkey_y,
+            self.nonce.to_fr(),
+        ];
+
+        let hash_vec = poseidon_hash::<Bn256>(hash_params, &request);
+        hash_vec[0]
+    }
+
+    // signed with the old key, stored in the account leaf
+    pub fn sign(
+        &mut self,
+        seckey: &PrivateKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) {
+        let hash = self.hash(hash_params);
+        let hash_bytes = fr_to_sign_message(hash);
+        let mut rng = thread_rng();
+
+        let sign = seckey.sign_raw_message(
+            &hash_bytes,
+            &mut rng,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        );
+
+        self.sign = Some(sign);
+    }
+
+    pub fn verify_signature(
+        & self,
+        pubkey: &PublicKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<(), OpenPlasmaError> {
+        let sign = self.sign.as_ref().ok_or(SignatureError::MissingSignature)?;
+        let hash = self.hash(hash_params);
+        let hash_bytes = fr_to_sign_message(hash);
+
+        if !pubkey.verify_for_raw_message(
+            &hash_bytes,
+            sign,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        ) {
+            return Err(SignatureError::VerificationFailed.into());
+        }
+
+        Ok(())
+    }
+
+    pub fn update_tree_and_record_state(
+        &self,
+        tree: &mut AccountsTree,
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+        // balances are not changed, any token path proves the same balances root
+        let balance = tree.get_balance(self.account_id, 0)?;
+
+        // prepare paths, indices, pubkeys, nonces
+        let old_pubkey = tree.accounts[self.account_id].pubkey.clone();
+        let old_nonce = tree.check_next_nonce(self.account_id, self.nonce)?.to_fr();
+        let new_nonce = self.nonce.to_fr();
+        let account_path = tree.accounts_tree.get_leaf_path(self.account_id);
+        let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id);
+        let token_path = tree.get_token_path(self.account_id, 0)?;
+        let token_indices = tree.get_token_indices(self.account_id, 0)?;
+
+        // update account
+        tree.update_account(
+            self.account_id,
+            self.new_pubkey.clone(),
+            new_nonce,
+        )?;
+
+        // record account state
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(balance),
+            new_balance: Some(balance),
+            old_pubkey: Some(old_pubkey.0),
+            new_pubkey: Some(self.new_pubkey.0.clone()),
+            old_nonce: Some(old_nonce),
+            new_nonce: Some(new_nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        })
+    }
+}
@@ -0,0 +1,237 @@
+use crate::account::AccountState;
+use sapling_crypto_ce::eddsa::Signature;
+
+use super::super::{
+    tree::account::{ AccountsTree, TreeError },
+};
+
+use crate::utils::op_type::CONDITIONAL_TRANSFER_OP;
+use crate::types::{ Nonce, Balance };
+
+use crate::utils::utils::{
+    optionalize,
+    usize_to_fr,
+    fr_to_sign_message,
+    u128_to_fr,
+};
+use crate::error::OpenPlasmaError;
+use super::offchain_withdrawal::SignatureError;
+
+use sapling_crypto_ce::{
+    eddsa::{
+        PrivateKey,
+        PublicKey,
+    },
+    poseidon::{
+        poseidon_hash,
+        bn256::Bn256PoseidonParams,
+    },
+    jubjub::FixedGenerators,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use rand::thread_rng;
+
+pub use crate::utils::signature::NUM_BYTES_TO_SIGN;
+
+// hashlocked transfer: until valid_until it is executed only with a preimage
+// of hash_lock, after it the request is refunded, i.e. consumed without moving
+// the amount, so it can't be claimed later
+#[derive(Clone)]
+pub struct OffchainConditionalTransfer {
+    pub account_id_from: usize,
+    pub account_id_to: usize,
+    pub token_id: usize,
+    pub amount: u128,
+    pub nonce: Nonce,
+    pub hash_lock: bn256::Fr,
+    pub valid_until: usize,
+    pub sign: Option<Signature::<Bn256>>,
+}
+
+impl OffchainConditionalTransfer {
+
+    pub fn hash(
+        & self,
+        hash_params: &Bn256PoseidonParams
+    ) -> bn256::Fr {
+        let request = vec![
+            usize_to_fr(CONDITIONAL_TRANSFER_OP),
+            usize_to_fr(self.account_id_from),
+            usize_to_fr(self.account_id_to),
+            usize_to_fr(self.token_id),
+            u128_to_fr(self.amount),
+            self.nonce.to_fr(),
+            self.hash_lock,
+            usize_to_fr(self.valid_until),
+        ];
+
+        let hash_vec = poseidon_hash::<Bn256>(hash_params, &request);
+        hash_vec[0]
+    }
+
+    pub fn hash_lock(
+        preimage: bn256::Fr,
+        hash_params: &Bn256PoseidonParams,
+    ) -> bn256::Fr {
+        poseidon_hash::<Bn256>(hash_params, &[preimage])[0]
+    }
+
+    pub fn is_refund(&self, timestamp: usize) -> bool {
+        timestamp > self.valid_until
+    }
+
+    pub fn sign(
+        &mut self,
+        seckey: &PrivateKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) {
+        let hash = self.hash(hash_params);
+        let hash_bytes = fr_to_sign_message(hash);
+        let mut rng = thread_rng();
+
+        let sign = seckey.sign_raw_message(
+            &hash_bytes,
+            &mut rng,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        );
+
+        self.sign = Some(sign);
+    }
+
+    pub fn verify_signature(
+        & self,
+        pubkey: &PublicKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<(), OpenPlasmaError> {
+        let sign = self.sign.as_ref().ok_or(SignatureError::MissingSignature)?;
+        let hash = self.hash(hash_params);
+        let hash_bytes = fr_to_sign_message(hash);
+
+        if !pubkey.verify_for_raw_message(
+            &hash_bytes,
+            sign,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        ) {
+            return Err(SignatureError::VerificationFailed.into());
+        }
+
+        Ok(())
+    }
+
+    // both paths update both leaves, a refund moves zero amount
+    pub fn update_tree_and_record_state(
+        & self,
+        tree: &mut AccountsTree,
+        timestamp: usize,
+    ) -> Result<(AccountState::<Bn256>, AccountState::<Bn256>), OpenPlasmaError> {
+        if self.account_id_from == self.account_id_to {
+            return Err(TreeError::SelfTransfer(self.account_id_from).into());
+        }
+        tree.check_token(self.account_id_from, self.token_id)?;
+        tree.check_token(self.account_id_to, self.token_id)?;
+
+        let amount = if self.is_refund(timestamp) { 0 } else { self.amount };
+
+        // count balances, all checks are before the first write
+        let old_balance_from = tree.get_balance(self.account_id_from, self.token_id)?;
+        let new_balance_from = Balance::try_from_fr(&old_balance_from)?.0.checked_sub(amount)
+            .ok_or(TreeError::InsufficientBalance {
+                account_id: self.account_id_from,
+                token_id: self.token_id,
+            })?;
+
+        let old_balance_to = tree.get_balance(self.account_id_to, self.token_id)?;
+        let new_balance_to = Balance::try_from_fr(&old_balance_to)?.0.checked_add(amount)
+            .ok_or(TreeError::BalanceOverflow {
+                account_id: self.account_id_to,
+                token_id: self.token_id,
+            })?;
+
+        let old_nonce = tree.check_next_nonce(self.account_id_from, self.nonce)?.to_fr();
+
+        // account from ------------------------------------------------------------
+
+        let new_balance = u128_to_fr(new_balance_from);
+
+        // prepare paths, indices, pubkeys, nonces
+        let pubkey = tree.accounts[self.account_id_from].pubkey.clone();
+        let new_nonce = self.nonce.to_fr();
+        let account_path = tree.get_leaf_path(self.account_id_from)?;
+        let account_indices = tree.get_leaf_indices(self.account_id_from)?;
+        let token_path = tree.get_token_path(self.account_id_from, self.token_id)?;
+        let token_indices = tree.get_token_indices(self.account_id_from, self.token_id)?;
+
+        // update balance
+        tree.update_balance(
+            self.account_id_from,
+            self.token_id,
+            new_balance,
+        )?;
+
+        tree.update_nonce(
+            self.account_id_from,
+            new_nonce,
+        )?;
+
+        // record account state
+        let account_state_from = AccountState::<Bn256> {
+            old_balance: Some(old_balance_from),
+            new_balance: Some(new_balance),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0),
+            old_nonce: Some(old_nonce),
+            new_nonce: Some(new_nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        };
+
+        // account to --------------------------------------------------------------
+
+        let new_balance = u128_to_fr(new_balance_to);
+
+        // prepare paths, indices, pubkeys, nonces
+        let pubkey = tree.accounts[self.account_id_to].pubkey.clone();
+        let nonce = tree.accounts[self.account_id_to].nonce;
+        let account_path = tree.get_leaf_path(self.account_id_to)?;
+        let account_indices = tree.get_leaf_indices(self.account_id_to)?;
+        let token_path = tree.get_token_path(self.account_id_to, self.token_id)?;
+        let token_indices = tree.get_token_indices(self.account_id_to, self.token_id)?;
+
+        // update balance
+        tree.update_balance(
+            self.account_id_to,
+            self.token_id,
+            new_balance,
+        )?;
+
+        // record account state
+        let account_state_to = AccountState::<Bn256> {
+            old_balance: Some(old_balance_to),
+            new_balance: Some(new_balance),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0),
+            old_nonce: Some(nonce),
+            new_nonce: Some(nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        };
+
+        Ok((account_state_from, account_state_to))
+    }
+}
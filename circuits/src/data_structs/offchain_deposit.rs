@@ -0,0 +1,231 @@
+use sapling_crypto_ce::{
+    eddsa::PublicKey,
+    alt_babyjubjub::AltJubjubBn256,
+    poseidon::{
+        poseidon_hash,
+        bn256::Bn256PoseidonParams,
+    },
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use ff_ce::Field;
+
+use crate::account::AccountState;
+use crate::deposit_circuit::DepositCircuit;
+
+use super::super::{
+    tree::account::{ AccountsTree, LeafUpdate, TreeError },
+    tree::merkle_tree::PathCache,
+};
+
+use crate::utils::op_type::{ DEPOSIT_OP, REFUND_OP };
+use crate::pubdata::accumulate_pubdata;
+use crate::sponge::PoseidonSponge;
+use crate::types::{ Balance, AccountId };
+
+use super::encoding::{ Encoder, Decoder, HEADER_BYTES, POINT_BYTES };
+
+use crate::utils::utils::{
+    optionalize,
+    usize_to_fr,
+};
+use crate::error::OpenPlasmaError;
+
+// account id, pubkey, token id and amount
+pub const OFFCHAIN_DEPOSIT_BYTES: usize = HEADER_BYTES + 4 + POINT_BYTES + 4 + 16;
+
+// a deposit from plain values, everything a DepositCircuit witness needs
+// comes from the tree it is applied to
+#[derive(Clone)]
+pub struct OffchainDeposit {
+    pub account_id: AccountId,
+    pub pubkey: PublicKey::<Bn256>,
+    pub token_id: usize,
+    pub amount: Balance,
+}
+
+impl OffchainDeposit {
+
+    fn record(&self, prev_hash: bn256::Fr) -> Vec::<bn256::Fr> {
+        let (pubkey_x, pubkey_y) = self.pubkey.0.into_xy();
+        vec![
+            usize_to_fr(DEPOSIT_OP),
+            prev_hash,
+            pubkey_x,
+            pubkey_y,
+            self.account_id.to_fr(),
+            usize_to_fr(self.token_id),
+            self.amount.to_fr(),
+        ]
+    }
+
+    // one step of the deposit accum hash, the same record process_deposit absorbs
+    pub fn hash(
+        &self,
+        prev_hash: bn256::Fr,
+        hash_params: &Bn256PoseidonParams,
+    ) -> bn256::Fr {
+        let hash_vec = poseidon_hash::<Bn256>(hash_params, &self.record(prev_hash));
+        hash_vec[0]
+    }
+
+    // one step of the sponge accum hash, the same record through a
+    // PoseidonSponge; see deposit_circuit::DepositAccumulator
+    pub fn sponge_hash(
+        &self,
+        prev_hash: bn256::Fr,
+        hash_params: &Bn256PoseidonParams,
+    ) -> bn256::Fr {
+        PoseidonSponge::<Bn256>::hash(hash_params, &self.record(prev_hash))
+    }
+
+    // one step of the sha256 accum hash, over the bytes of encode; see
+    // deposit_circuit::DepositAccumulator
+    pub fn pubdata_hash(&self, prev_hash: bn256::Fr) -> Result<bn256::Fr, OpenPlasmaError> {
+        Ok(accumulate_pubdata(prev_hash, &self.encode()?))
+    }
+
+    // see data_structs::encoding
+    pub fn encode(&self) -> Result<Vec::<u8>, OpenPlasmaError> {
+        self.encode_as(DEPOSIT_OP)
+    }
+
+    pub fn decode(bytes: &[u8], sign_params: &AltJubjubBn256) -> Result<Self, OpenPlasmaError> {
+        Self::decode_as(bytes, DEPOSIT_OP, sign_params)
+    }
+
+    // a deposit of the contract's queue that is refunded instead of applied,
+    // the bytes of the deposit under REFUND_OP
+    pub fn encode_refund(&self) -> Result<Vec::<u8>, OpenPlasmaError> {
+        self.encode_as(REFUND_OP)
+    }
+
+    pub fn decode_refund(bytes: &[u8], sign_params: &AltJubjubBn256) -> Result<Self, OpenPlasmaError> {
+        Self::decode_as(bytes, REFUND_OP, sign_params)
+    }
+
+    fn encode_as(&self, op_type: usize) -> Result<Vec::<u8>, OpenPlasmaError> {
+        let mut encoder = Encoder::new(op_type, OFFCHAIN_DEPOSIT_BYTES);
+        encoder.account_id(self.account_id);
+        encoder.pubkey(&self.pubkey);
+        encoder.u32(self.token_id, "token id")?;
+        encoder.balance(self.amount);
+        Ok(encoder.finish())
+    }
+
+    fn decode_as(bytes: &[u8], op_type: usize, sign_params: &AltJubjubBn256) -> Result<Self, OpenPlasmaError> {
+        let mut decoder = Decoder::new(bytes, op_type, OFFCHAIN_DEPOSIT_BYTES)?;
+        Ok(OffchainDeposit {
+            account_id: decoder.account_id(),
+            pubkey: decoder.pubkey(sign_params)?,
+            token_id: decoder.u32(),
+            amount: decoder.balance(),
+        })
+    }
+
+    // as in the circuit the leaf has to be empty or already hold this pubkey
+    fn check_pubkey(&self, tree: &AccountsTree) -> Result<(), OpenPlasmaError> {
+        let account_id = self.account_id.index();
+        tree.check_token(account_id, self.token_id)?;
+
+        let old_pubkey = tree.get_pubkey(account_id)?;
+        let nonce = tree.get_nonce(account_id)?;
+        let is_empty_leaf = old_pubkey.0.into_xy().1.is_zero() && nonce.is_zero();
+        if !is_empty_leaf && old_pubkey.0.into_xy() != self.pubkey.0.into_xy() {
+            return Err(TreeError::PubkeyMismatch(account_id).into());
+        }
+
+        Ok(())
+    }
+
+    // the change update_tree_and_record_state makes, for AccountsTree::apply_batch
+    pub fn leaf_update(&self) -> LeafUpdate {
+        LeafUpdate {
+            account_id: self.account_id.index(),
+            token_id: self.token_id,
+            pubkey: Some(self.pubkey.clone()),
+            credit: self.amount.0,
+            debit: 0,
+            increment_nonce: false,
+        }
+    }
+
+    // the same account state as update_tree_and_record_state, for a block
+    // built with AccountsTree::apply_cached
+    pub fn update_tree_cached(
+        &self,
+        tree: &mut AccountsTree,
+        path_cache: &mut PathCache<bn256::Fr>,
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+        self.check_pubkey(tree)?;
+        tree.apply_cached(&self.leaf_update(), path_cache)
+    }
+
+    // credits the balance and sets the pubkey, the nonce is kept
+    pub fn update_tree_and_record_state(
+        &self,
+        tree: &mut AccountsTree,
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+        self.check_pubkey(tree)?;
+
+        let account_id = self.account_id.index();
+        let old_pubkey = tree.get_pubkey(account_id)?;
+        let nonce = tree.get_nonce(account_id)?;
+
+        // count balances
+        let old_balance = tree.balance(self.account_id, self.token_id)?;
+        let new_balance = old_balance.checked_add(self.amount).ok_or(TreeError::BalanceOverflow {
+            account_id,
+            token_id: self.token_id,
+        })?;
+
+        // prepare paths, indices
+        let account_path = tree.get_leaf_path(account_id)?;
+        let account_indices = tree.get_leaf_indices(account_id)?;
+        let token_path = tree.get_token_path(account_id, self.token_id)?;
+        let token_indices = tree.get_token_indices(account_id, self.token_id)?;
+
+        // update balance & account
+        tree.update_balance(
+            account_id,
+            self.token_id,
+            new_balance.to_fr(),
+        )?;
+
+        tree.update_account(
+            account_id,
+            self.pubkey.clone(),
+            nonce,
+        )?;
+
+        // record account state
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(old_balance.to_fr()),
+            new_balance: Some(new_balance.to_fr()),
+            old_pubkey: Some(old_pubkey.0),
+            new_pubkey: Some(self.pubkey.0.clone()),
+            old_nonce: Some(nonce),
+            new_nonce: Some(nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        })
+    }
+
+    pub fn into_circuit(self, account_state: AccountState::<Bn256>) -> DepositCircuit::<Bn256> {
+        DepositCircuit {
+            account_state,
+            pubkey: Some(self.pubkey.0),
+            account_id: Some(self.account_id.to_fr()),
+            token_id: Some(usize_to_fr(self.token_id)),
+            amount: Some(self.amount.to_fr()),
+            is_noop: Some(false),
+            is_refund: Some(false),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use crate::account::AccountState;
+use sapling_crypto_ce::eddsa::Signature;
+
+use super::super::{
+    tree::account::{ AccountsTree, TreeError },
+};
+
+use crate::utils::op_type::OFFCHAIN_TRANSFER_OP;
+use crate::types::{ Balance, Nonce, AccountId };
+
+use super::encoding::{ Encoder, Decoder, HEADER_BYTES, SIGNATURE_BYTES };
+
+use crate::utils::utils::{
+    optionalize,
+    usize_to_fr,
+    fr_to_sign_message,
+};
+use crate::error::OpenPlasmaError;
+use super::offchain_withdrawal::SignatureError;
+
+use sapling_crypto_ce::{
+    eddsa::{
+        PrivateKey,
+        PublicKey,
+    },
+    poseidon::{
+        poseidon_hash,
+        bn256::Bn256PoseidonParams,
+    },
+    jubjub::FixedGenerators,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use rand::thread_rng;
+
+pub use crate::utils::signature::NUM_BYTES_TO_SIGN;
+// from and to account ids, token id, amount, fee, nonce and the signature
+pub const OFFCHAIN_TRANSFER_BYTES: usize = HEADER_BYTES + 4 + 4 + 4 + 16 + 16 + 4 + SIGNATURE_BYTES;
+
+// amount moves to another registered account, fee is debited from the sender
+// together with it and credited to the operator like withdrawal fees
+#[derive(Clone)]
+pub struct OffchainTransfer {
+    pub from_account_id: AccountId,
+    pub to_account_id: AccountId,
+    pub token_id: usize,
+    pub amount: Balance,
+    pub fee: Balance,
+    pub nonce: Nonce,
+    pub sign: Option<Signature::<Bn256>>,
+}
+
+impl OffchainTransfer {
+
+    pub fn hash(
+        & self,
+        hash_params: &Bn256PoseidonParams
+    ) -> bn256::Fr {
+        let request = vec![
+            usize_to_fr(OFFCHAIN_TRANSFER_OP),
+            self.from_account_id.to_fr(),
+            self.to_account_id.to_fr(),
+            usize_to_fr(self.token_id),
+            self.amount.to_fr(),
+            self.fee.to_fr(),
+            self.nonce.to_fr(),
+        ];
+
+        let hash_vec = poseidon_hash::<Bn256>(hash_params, &request);
+        hash_vec[0]
+    }
+
+    // see data_structs::encoding, only signed transfers are encoded
+    pub fn encode(&self) -> Result<Vec::<u8>, OpenPlasmaError> {
+        let mut encoder = Encoder::new(OFFCHAIN_TRANSFER_OP, OFFCHAIN_TRANSFER_BYTES);
+        encoder.account_id(self.from_account_id);
+        encoder.account_id(self.to_account_id);
+        encoder.u32(self.token_id, "token id")?;
+        encoder.balance(self.amount);
+        encoder.balance(self.fee);
+        encoder.nonce(self.nonce);
+        encoder.sign(&self.sign)?;
+        Ok(encoder.finish())
+    }
+
+    pub fn decode(bytes: &[u8], sign_params: &AltJubjubBn256) -> Result<Self, OpenPlasmaError> {
+        let mut decoder = Decoder::new(bytes, OFFCHAIN_TRANSFER_OP, OFFCHAIN_TRANSFER_BYTES)?;
+        Ok(OffchainTransfer {
+            from_account_id: decoder.account_id(),
+            to_account_id: decoder.account_id(),
+            token_id: decoder.u32(),
+            amount: decoder.balance(),
+            fee: decoder.balance(),
+            nonce: decoder.nonce(),
+            sign: decoder.sign(sign_params)?,
+        })
+    }
+
+    pub fn sign(
+        &mut self,
+        seckey: &PrivateKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) {
+        let hash = self.hash(hash_params);
+        let hash_bytes = fr_to_sign_message(hash);
+        let mut rng = thread_rng();
+
+        let sign = seckey.sign_raw_message(
+            &hash_bytes,
+            &mut rng,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        );
+
+        self.sign = Some(sign);
+    }
+
+    pub fn verify_signature(
+        & self,
+        pubkey: &PublicKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<(), OpenPlasmaError> {
+        let sign = self.sign.as_ref().ok_or(SignatureError::MissingSignature)?;
+        let hash = self.hash(hash_params);
+        let hash_bytes = fr_to_sign_message(hash);
+
+        if !pubkey.verify_for_raw_message(
+            &hash_bytes,
+            sign,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        ) {
+            return Err(SignatureError::VerificationFailed.into());
+        }
+
+        Ok(())
+    }
+
+    // sender state first, the receiver state is recorded against the root the
+    // sender update left; the tree is left untouched unless both updates apply
+    pub fn update_tree_and_record_state(
+        &self,
+        tree: &mut AccountsTree,
+    ) -> Result<(AccountState::<Bn256>, AccountState::<Bn256>), OpenPlasmaError> {
+        let from_id = self.from_account_id.index();
+        let to_id = self.to_account_id.index();
+        tree.check_registered(from_id)?;
+        tree.check_token(from_id, self.token_id)?;
+        tree.check_registered(to_id)?;
+
+        // count balances, fee is debited together with amount
+        let old_balance_from = tree.balance(self.from_account_id, self.token_id)?;
+        let new_balance_from = self.amount.checked_add(self.fee)
+            .and_then(|debit| old_balance_from.checked_sub(debit))
+            .ok_or(TreeError::InsufficientBalance {
+                account_id: from_id,
+                token_id: self.token_id,
+            })?;
+
+        let old_balance_to = if from_id == to_id {
+            new_balance_from
+        } else {
+            tree.balance(self.to_account_id, self.token_id)?
+        };
+        let new_balance_to = old_balance_to.checked_add(self.amount)
+            .ok_or(TreeError::BalanceOverflow {
+                account_id: to_id,
+                token_id: self.token_id,
+            })?;
+
+        let old_nonce = tree.check_next_nonce(from_id, self.nonce)?;
+
+        // account from ------------------------------------------------------------
+
+        // prepare paths, indices, pubkeys
+        let pubkey = tree.get_pubkey(from_id)?;
+        let account_path = tree.get_leaf_path(from_id)?;
+        let account_indices = tree.get_leaf_indices(from_id)?;
+        let token_path = tree.get_token_path(from_id, self.token_id)?;
+        let token_indices = tree.get_token_indices(from_id, self.token_id)?;
+
+        // update balance
+        tree.update_balance(
+            from_id,
+            self.token_id,
+            new_balance_from.to_fr(),
+        )?;
+
+        tree.update_nonce(
+            from_id,
+            self.nonce.to_fr(),
+        )?;
+
+        // record account state
+        let account_state_from = AccountState::<Bn256> {
+            old_balance: Some(old_balance_from.to_fr()),
+            new_balance: Some(new_balance_from.to_fr()),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0),
+            old_nonce: Some(old_nonce.to_fr()),
+            new_nonce: Some(self.nonce.to_fr()),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        };
+
+        // account to --------------------------------------------------------------
+
+        // prepare paths, indices, pubkeys, nonces
+        let pubkey = tree.get_pubkey(to_id)?;
+        let nonce = tree.get_nonce(to_id)?;
+        let account_path = tree.get_leaf_path(to_id)?;
+        let account_indices = tree.get_leaf_indices(to_id)?;
+        let token_path = tree.get_token_path(to_id, self.token_id)?;
+        let token_indices = tree.get_token_indices(to_id, self.token_id)?;
+
+        // update balance
+        tree.update_balance(
+            to_id,
+            self.token_id,
+            new_balance_to.to_fr(),
+        )?;
+
+        // record account state
+        let account_state_to = AccountState::<Bn256> {
+            old_balance: Some(old_balance_to.to_fr()),
+            new_balance: Some(new_balance_to.to_fr()),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0),
+            old_nonce: Some(nonce),
+            new_nonce: Some(nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        };
+
+        Ok((account_state_from, account_state_to))
+    }
+}
@@ -1,16 +1,36 @@
+use std::{
+    fmt,
+    error::Error,
+    io::{ self, Read, Write },
+};
+
+use serde::{ Serialize, Deserialize };
+
 use crate::account::AccountState;
 use sapling_crypto_ce::eddsa::Signature;
 
 use super::super::{
-    tree::account::AccountsTree,
+    tree::account::{ AccountsTree, TreeError },
 };
 
+use crate::utils::op_type::{ OFFCHAIN_WITHDRAWAL_OP, WITHDRAWAL_PERMIT_OP };
+use crate::types::{ Balance, Nonce, AccountId };
+use crate::utils::{ serde_sign, serde_address };
+use crate::utils::domain::{ SigningDomain, ADDRESS_BYTES, address_to_fr };
+use crate::keys::SecretKey;
+use crate::params::{ poseidon_params, jubjub_params };
+use crate::hasher::{ TreeHasher, Poseidon };
+
+use super::encoding::{ Encoder, Decoder, HEADER_BYTES, SIGNATURE_BYTES };
+
 use crate::utils::utils::{
     optionalize,
-    fr_to_usize,
     usize_to_fr,
-    fr_to_bytes_le,
+    fr_to_sign_message,
+    deterministic_rng,
+    u128_to_fr,
 };
+use crate::error::OpenPlasmaError;
 
 use sapling_crypto_ce::{
     eddsa::{
@@ -21,43 +41,371 @@ use sapling_crypto_ce::{
         poseidon_hash,
         bn256::Bn256PoseidonParams,
     },
-    jubjub::FixedGenerators,
-    alt_babyjubjub::AltJubjubBn256,
+    jubjub::{
+        FixedGenerators,
+        Unknown,
+        edwards::Point,
+    },
+    alt_babyjubjub::{ AltJubjubBn256, fs::Fs },
 };
 
+use ff_ce::{ PrimeField, PrimeFieldRepr };
+
 use pairing_ce::{
     bn256,
     bn256::Bn256,
 };
 
-use rand::thread_rng;
+use rand::{ Rng, thread_rng };
 
-pub const NUM_BYTES_TO_SIGN: usize = 31;
+pub use crate::utils::signature::NUM_BYTES_TO_SIGN;
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    MissingSignature,
+    InvalidPoint,
+    VerificationFailed,
+}
+
+impl Error for SignatureError {}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            SignatureError::MissingSignature => write!(f, "Request is not signed"),
+            SignatureError::InvalidPoint => write!(f, "Signature or public key is not in the prime order subgroup"),
+            SignatureError::VerificationFailed => write!(f, "Signature doesn't match the request"),
+        }
+    }
+}
+
+// r and the pubkey have to be in the prime order subgroup and not the
+// identity: a small order component added to r leaves the signature valid,
+// so the same request would have several signatures. s is reduced off
+// circuit, the decoders refuse s at or above the order and verify_eddsa
+// range checks it in the circuit
+pub fn is_canonical_point(point: &Point::<Bn256, Unknown>, sign_params: &AltJubjubBn256) -> bool {
+    point.as_prime_order(sign_params).is_some() && !point.eq(&Point::zero())
+}
+// account id, token id, amount, fee, nonce, valid until, eth address and the signature
+pub const OFFCHAIN_WITHDRAWAL_BYTES: usize = HEADER_BYTES + 4 + 4 + 16 + 16 + 4 + 8 + ADDRESS_BYTES + SIGNATURE_BYTES;
+
+// travels from wallets to the operator as json, see serde_sign for the signature
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OffchainWithdrawal {
-    pub account_id: usize,
-    pub amount: usize,
-    pub nonce: usize,
+    pub account_id: AccountId,
+    pub token_id: usize,
+    pub amount: Balance,
+    pub fee: Balance,
+    pub nonce: Nonce,
+    // last block timestamp the withdrawal can be executed at, 0 never expires
+    pub valid_until: usize,
+    // L1 address the contract pays the amount out to, signed so the operator
+    // can't redirect it. json carries it as checksummed hex
+    #[serde(with = "serde_address")]
+    pub eth_address: [u8; ADDRESS_BYTES],
+    #[serde(with = "serde_sign::option")]
     pub sign: Option<Signature::<Bn256>>,
 }
 
 impl OffchainWithdrawal {
 
+    // the domain is not a part of the request, wallets and the operator
+    // agree on it out of band. params left as None are the shared ones, see
+    // params::shared_params
     pub fn hash(
         & self, 
+        domain: &SigningDomain,
+        hash_params: Option<&Bn256PoseidonParams>,
+    ) -> bn256::Fr {
+        self.hash_with_hasher::<Poseidon>(domain, hash_params.unwrap_or_else(|| poseidon_params()))
+    }
+
+    // the same request hashed with another tree hasher, a signature of one
+    // hash doesn't verify for the other. the eth address is a single element,
+    // see domain::address_to_fr
+    pub fn hash_with_hasher<H: TreeHasher<Bn256>>(
+        &self,
+        domain: &SigningDomain,
+        hash_params: &H::Params,
+    ) -> bn256::Fr {
+        let [chain_id, rollup_address] = domain.to_fr();
+        let request = vec![
+            usize_to_fr(OFFCHAIN_WITHDRAWAL_OP),
+            self.account_id.to_fr(),
+            usize_to_fr(self.token_id),
+            self.amount.to_fr(),
+            self.fee.to_fr(),
+            self.nonce.to_fr(),
+            usize_to_fr(self.valid_until),
+            address_to_fr(&self.eth_address),
+            chain_id,
+            rollup_address,
+        ];
+
+        H::hash(hash_params, &request)
+    }
+
+    pub fn is_expired(&self, timestamp: usize) -> bool {
+        self.valid_until != 0 && timestamp > self.valid_until
+    }
+
+    // the accum hash of a withdrawal batch after this withdrawal, the contract
+    // replays it from the pubdata to pay every amount out
+    pub fn record_hash(
+        &self,
+        prev_hash: bn256::Fr,
+        hash_params: &Bn256PoseidonParams,
+    ) -> bn256::Fr {
+        poseidon_hash::<Bn256>(hash_params, &[
+            usize_to_fr(OFFCHAIN_WITHDRAWAL_OP),
+            prev_hash,
+            self.account_id.to_fr(),
+            usize_to_fr(self.token_id),
+            self.amount.to_fr(),
+            address_to_fr(&self.eth_address),
+        ])[0]
+    }
+
+    // see data_structs::encoding, only signed withdrawals are encoded
+    pub fn encode(&self) -> Result<Vec::<u8>, OpenPlasmaError> {
+        let mut encoder = Encoder::new(OFFCHAIN_WITHDRAWAL_OP, OFFCHAIN_WITHDRAWAL_BYTES);
+        encoder.account_id(self.account_id);
+        encoder.u32(self.token_id, "token id")?;
+        encoder.balance(self.amount);
+        encoder.balance(self.fee);
+        encoder.nonce(self.nonce);
+        encoder.u64(self.valid_until);
+        encoder.address(&self.eth_address);
+        encoder.sign(&self.sign)?;
+        Ok(encoder.finish())
+    }
+
+    pub fn decode(bytes: &[u8], sign_params: Option<&AltJubjubBn256>) -> Result<Self, OpenPlasmaError> {
+        let sign_params = sign_params.unwrap_or_else(|| jubjub_params());
+        let mut decoder = Decoder::new(bytes, OFFCHAIN_WITHDRAWAL_OP, OFFCHAIN_WITHDRAWAL_BYTES)?;
+        Ok(OffchainWithdrawal {
+            account_id: decoder.account_id(),
+            token_id: decoder.u32(),
+            amount: decoder.balance(),
+            fee: decoder.balance(),
+            nonce: decoder.nonce(),
+            valid_until: decoder.u64("valid until")?,
+            eth_address: decoder.address(),
+            sign: decoder.sign(sign_params)?,
+        })
+    }
+
+    pub fn sign<R: Rng>(
+        &mut self,
+        seckey: &SecretKey,
+        domain: &SigningDomain,
+        hash_params: Option<&Bn256PoseidonParams>,
+        sign_params: Option<&AltJubjubBn256>,
+        rng: &mut R,
+    ) {
+        let hash_params = hash_params.unwrap_or_else(|| poseidon_params());
+        self.sign_with_hasher::<Poseidon, R>(seckey, domain, hash_params, sign_params, rng);
+    }
+
+    pub fn sign_with_hasher<H: TreeHasher<Bn256>, R: Rng>(
+        &mut self,
+        seckey: &SecretKey,
+        domain: &SigningDomain,
+        hash_params: &H::Params,
+        sign_params: Option<&AltJubjubBn256>,
+        rng: &mut R,
+    ) {
+        let sign_params = sign_params.unwrap_or_else(|| jubjub_params());
+        let hash = self.hash_with_hasher::<H>(domain, hash_params);
+        let hash_bytes = fr_to_sign_message(hash);
+
+        let sign = seckey.expose_private_key().sign_raw_message(
+            &hash_bytes,
+            rng,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        );
+
+        self.sign = Some(sign);
+    }
+
+    pub fn sign_with_thread_rng(
+        &mut self,
+        seckey: &SecretKey,
+        domain: &SigningDomain,
+        hash_params: Option<&Bn256PoseidonParams>,
+        sign_params: Option<&AltJubjubBn256>,
+    ) {
+        self.sign(seckey, domain, hash_params, sign_params, &mut thread_rng());
+    }
+
+    // the same request always gets the same signature, see deterministic_rng
+    pub fn sign_deterministic(
+        &mut self,
+        seckey: &SecretKey,
+        domain: &SigningDomain,
+        hash_params: Option<&Bn256PoseidonParams>,
+        sign_params: Option<&AltJubjubBn256>,
+    ) {
+        let hash_bytes = fr_to_sign_message(self.hash(domain, hash_params));
+        self.sign(seckey, domain, hash_params, sign_params, &mut deterministic_rng(seckey.expose_private_key(), &hash_bytes));
+    }
+
+    // requests come from the network, an unsigned one is an error, not a panic
+    pub fn verify_signature(
+        & self,
+        pubkey: &PublicKey::<Bn256>,
+        domain: &SigningDomain,
+        hash_params: Option<&Bn256PoseidonParams>,
+        sign_params: Option<&AltJubjubBn256>,
+    ) -> Result<(), OpenPlasmaError> {
+        let hash_params = hash_params.unwrap_or_else(|| poseidon_params());
+        self.verify_signature_with_hasher::<Poseidon>(pubkey, domain, hash_params, sign_params)
+    }
+
+    pub fn verify_signature_with_hasher<H: TreeHasher<Bn256>>(
+        &self,
+        pubkey: &PublicKey::<Bn256>,
+        domain: &SigningDomain,
+        hash_params: &H::Params,
+        sign_params: Option<&AltJubjubBn256>,
+    ) -> Result<(), OpenPlasmaError> {
+        let sign = self.sign.as_ref().ok_or(SignatureError::MissingSignature)?;
+        let sign_params = sign_params.unwrap_or_else(|| jubjub_params());
+
+        // the circuit accepts only points of the prime order subgroup
+        if !is_canonical_point(&sign.r, sign_params) || !is_canonical_point(&pubkey.0, sign_params) {
+            return Err(SignatureError::InvalidPoint.into());
+        }
+
+        let hash = self.hash_with_hasher::<H>(domain, hash_params);
+        let hash_bytes = fr_to_sign_message(hash);
+
+        if !pubkey.verify_for_raw_message(
+            &hash_bytes,
+            sign,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        ) {
+            return Err(SignatureError::VerificationFailed.into());
+        }
+
+        Ok(())
+    }
+
+    // the tree is left untouched unless the withdrawal is executable
+    pub fn update_tree_and_record_state(
+        &self,
+        tree: &mut AccountsTree,
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+        let account_id = self.account_id.index();
+        tree.check_registered(account_id)?;
+        tree.check_token(account_id, self.token_id)?;
+
+        // count balances, fee is debited together with amount
+        let old_balance = tree.balance(self.account_id, self.token_id)?;
+        let new_balance = self.amount.checked_add(self.fee)
+            .and_then(|debit| old_balance.checked_sub(debit))
+            .ok_or(TreeError::InsufficientBalance {
+                account_id,
+                token_id: self.token_id,
+            })?;
+
+        // prepare paths, indices, pubkeys, nonces
+        let pubkey = tree.accounts[account_id].pubkey.clone();
+        let old_nonce = tree.check_next_nonce(account_id, self.nonce)?;
+        let account_path = tree.get_leaf_path(account_id)?;
+        let account_indices = tree.get_leaf_indices(account_id)?;
+        let token_path = tree.get_token_path(account_id, self.token_id)?;
+        let token_indices = tree.get_token_indices(account_id, self.token_id)?;
+
+        // update balance
+        tree.update_balance(
+            account_id,
+            self.token_id,
+            new_balance.to_fr(),
+        )?;
+        
+        tree.update_nonce(
+            account_id,
+            self.nonce.to_fr(),
+        )?;
+
+        // record account state
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(old_balance.to_fr()),
+            new_balance: Some(new_balance.to_fr()),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0),
+            old_nonce: Some(old_nonce.to_fr()),
+            new_nonce: Some(self.nonce.to_fr()),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        })
+    }
+}
+
+// the owner of account_id lets spender sign one withdrawal of at most max_amount,
+// nonce is the account nonce the withdrawal consumes, so the permit is used once
+#[derive(Clone)]
+pub struct WithdrawalPermit {
+    pub account_id: usize,
+    pub spender_pubkey: PublicKey::<Bn256>,
+    pub max_amount: u128,
+    // 0 never expires, the same as for OffchainWithdrawal
+    pub valid_until: usize,
+    pub nonce: usize,
+    pub sign: Option<Signature::<Bn256>>,
+}
+
+impl WithdrawalPermit {
+
+    pub fn new(
+        account_id: usize,
+        spender_pubkey: PublicKey::<Bn256>,
+        max_amount: u128,
+        valid_until: usize,
+        nonce: usize,
+    ) -> Self {
+        WithdrawalPermit {
+            account_id,
+            spender_pubkey,
+            max_amount,
+            valid_until,
+            nonce,
+            sign: None,
+        }
+    }
+
+    pub fn hash(
+        & self,
         hash_params: &Bn256PoseidonParams
     ) -> bn256::Fr {
+        let (spender_x, spender_y) = self.spender_pubkey.0.into_xy();
         let request = vec![
+            usize_to_fr(WITHDRAWAL_PERMIT_OP),
             usize_to_fr(self.account_id),
-            usize_to_fr(self.amount),
+            spender_x,
+            spender_y,
+            u128_to_fr(self.max_amount),
+            usize_to_fr(self.valid_until),
             usize_to_fr(self.nonce),
         ];
-    
+
         let hash_vec = poseidon_hash::<Bn256>(hash_params, &request);
         hash_vec[0]
     }
 
+    pub fn is_expired(&self, timestamp: usize) -> bool {
+        self.valid_until != 0 && timestamp > self.valid_until
+    }
+
+    // signed by the account owner
     pub fn sign(
         &mut self,
         seckey: &PrivateKey::<Bn256>,
@@ -65,7 +413,7 @@ impl OffchainWithdrawal {
         sign_params: &AltJubjubBn256,
     ) {
         let hash = self.hash(hash_params);
-        let hash_bytes: Vec<_> = fr_to_bytes_le(hash, NUM_BYTES_TO_SIGN);
+        let hash_bytes = fr_to_sign_message(hash);
         let mut rng = thread_rng();
 
         let sign = seckey.sign_raw_message(
@@ -84,63 +432,122 @@ impl OffchainWithdrawal {
         pubkey: &PublicKey::<Bn256>,
         hash_params: &Bn256PoseidonParams,
         sign_params: &AltJubjubBn256,
-    ) -> bool {
+    ) -> Result<(), OpenPlasmaError> {
+        let sign = self.sign.as_ref().ok_or(SignatureError::MissingSignature)?;
         let hash = self.hash(hash_params);
-        let hash_bytes: Vec<_> = fr_to_bytes_le(hash, NUM_BYTES_TO_SIGN);
+        let hash_bytes = fr_to_sign_message(hash);
 
-        pubkey.verify_for_raw_message(
+        if !pubkey.verify_for_raw_message(
             &hash_bytes,
-            &self.sign.clone().unwrap(),
+            sign,
             FixedGenerators::SpendingKeyGenerator,
             sign_params,
             NUM_BYTES_TO_SIGN,
-        )
+        ) {
+            return Err(SignatureError::VerificationFailed.into());
+        }
+
+        Ok(())
     }
 
-    pub fn update_tree_and_record_state(
-        &self,
-        tree: &mut AccountsTree,
-    ) -> AccountState::<Bn256> {
-        assert!(self.account_id < tree.accounts.len());
-
-        // count balances
-        let old_balance = tree.accounts[self.account_id].balance;
-        let new_balance = {
-            let old_balance = fr_to_usize(old_balance);
-            assert!(old_balance >= self.amount);
-            usize_to_fr(old_balance - self.amount)
+    // withdrawal of the spender this permit authorizes
+    pub fn allows(&self, withdrawal: &OffchainWithdrawal, timestamp: usize) -> bool {
+        withdrawal.account_id.index() == self.account_id
+            && withdrawal.nonce.0 as usize == self.nonce
+            && withdrawal.fee.is_zero()
+            && withdrawal.amount.0 <= self.max_amount
+            && !self.is_expired(timestamp)
+    }
+
+    // numbers are 8 bytes big endian and max amount 16, the signature is r then s little endian,
+    // an unsigned permit can't be written
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let sign = self.sign.as_ref().ok_or_else(
+            || io::Error::new(io::ErrorKind::InvalidInput, "permit is not signed")
+        )?;
+
+        writer.write_all(&(self.account_id as u64).to_be_bytes())?;
+        self.spender_pubkey.write(&mut writer)?;
+        writer.write_all(&self.max_amount.to_be_bytes())?;
+        writer.write_all(&(self.valid_until as u64).to_be_bytes())?;
+        writer.write_all(&(self.nonce as u64).to_be_bytes())?;
+        sign.r.write(&mut writer)?;
+        sign.s.into_repr().write_le(&mut writer)
+    }
+
+    pub fn read<R: Read>(mut reader: R, sign_params: &AltJubjubBn256) -> io::Result<Self> {
+        let read_usize = |reader: &mut R| -> io::Result<usize> {
+            let mut bytes = [0u8; 8];
+            reader.read_exact(&mut bytes)?;
+            Ok(u64::from_be_bytes(bytes) as usize)
         };
 
-        // prepare paths, indices, pubkeys, nonces
-        let pubkey = tree.accounts[self.account_id].pubkey.clone();
-        let old_nonce = tree.accounts[self.account_id].nonce;
-        assert!(fr_to_usize(old_nonce) == self.nonce - 1);
-        let new_nonce = usize_to_fr(self.nonce);
-        let account_path = tree.accounts_tree.get_leaf_path(self.account_id);
-        let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id);
+        let account_id = read_usize(&mut reader)?;
+        let spender_pubkey = PublicKey::read(&mut reader, sign_params)?;
+        let mut max_amount = [0u8; 16];
+        reader.read_exact(&mut max_amount)?;
+        let max_amount = u128::from_be_bytes(max_amount);
+        let valid_until = read_usize(&mut reader)?;
+        let nonce = read_usize(&mut reader)?;
 
-        // update balance
-        tree.update_balance(
-            self.account_id,
-            new_balance,
-        );
-        
-        tree.update_account(
-            self.account_id,
-            tree.accounts[self.account_id].pubkey.clone(),
-            new_nonce,
-        );
+        let r = Point::read(&mut reader, sign_params)?;
+        let mut s_repr = <Fs as PrimeField>::Repr::default();
+        s_repr.read_le(&mut reader)?;
+        let s = Fs::from_repr(s_repr).map_err(
+            |_| io::Error::new(io::ErrorKind::InvalidData, "signature s is not canonical")
+        )?;
 
-        // record account state
-        AccountState::<Bn256> {
-            old_balance: Some(old_balance),
-            new_balance: Some(new_balance),
-            old_pubkey: Some(pubkey.0.clone()),
-            new_pubkey: Some(pubkey.0),
-            old_nonce: Some(old_nonce),
-            new_nonce: Some(new_nonce),
-            account_path: optionalize(account_path),
-            account_indices: optionalize(account_indices),
-        }
+        Ok(WithdrawalPermit {
+            account_id,
+            spender_pubkey,
+            max_amount,
+            valid_until,
+            nonce,
+            sign: Some(Signature { r, s }),
+        })
     }
-}
\ No newline at end of file
+}
+
+// fees of the whole batch are credited to the operator account with a single update
+pub fn credit_fee_and_record_state(
+    tree: &mut AccountsTree,
+    account_id: AccountId,
+    token_id: usize,
+    fee: Balance,
+) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+    // count balances
+    let old_balance = tree.balance(account_id, token_id)?;
+    let new_balance = old_balance.checked_add(fee).ok_or(TreeError::BalanceOverflow {
+        account_id: account_id.index(),
+        token_id,
+    })?;
+
+    // prepare paths, indices, pubkeys, nonces
+    let pubkey = tree.get_pubkey(account_id.index())?;
+    let nonce = tree.get_nonce(account_id.index())?;
+    let account_path = tree.get_leaf_path(account_id.index())?;
+    let account_indices = tree.get_leaf_indices(account_id.index())?;
+    let token_path = tree.get_token_path(account_id.index(), token_id)?;
+    let token_indices = tree.get_token_indices(account_id.index(), token_id)?;
+
+    // update balance
+    tree.update_balance(
+        account_id.index(),
+        token_id,
+        new_balance.to_fr(),
+    )?;
+
+    // record account state
+    Ok(AccountState::<Bn256> {
+        old_balance: Some(old_balance.to_fr()),
+        new_balance: Some(new_balance.to_fr()),
+        old_pubkey: Some(pubkey.0.clone()),
+        new_pubkey: Some(pubkey.0),
+        old_nonce: Some(nonce),
+        new_nonce: Some(nonce),
+        account_path: optionalize(account_path),
+        account_indices: optionalize(account_indices),
+        token_path: optionalize(token_path),
+        token_indices: optionalize(token_indices),
+    })
+}
@@ -10,23 +10,23 @@ use crate::utils::utils::{
     optionalize,
     usize_to_fr,
 };
+use crate::error::OpenPlasmaError;
 
 #[derive(Clone)]
 pub struct OnchainWithdrawal {
     pub account_id: usize,
-    pub amount: Option<usize>,
+    pub token_id: usize,
+    pub amount: Option<u128>,
 }
 
 impl OnchainWithdrawal {
     pub fn update_tree_and_record_state(
         &self,
         tree: &mut AccountsTree,
-    ) -> AccountState::<Bn256> {
-        assert!(self.account_id < tree.accounts.len());
-
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
         // count balances
-        let old_balance = tree.accounts[self.account_id].balance;
-        // onchain withdrawal takes all asset's value
+        let old_balance = tree.get_balance(self.account_id, self.token_id)?;
+        // onchain withdrawal takes all token's value
         let new_balance = usize_to_fr(0);
 
         // prepare paths, indices, pubkeys, nonces
@@ -34,15 +34,18 @@ impl OnchainWithdrawal {
         let nonce = tree.accounts[self.account_id].nonce;
         let account_path = tree.accounts_tree.get_leaf_path(self.account_id);
         let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id);
+        let token_path = tree.get_token_path(self.account_id, self.token_id)?;
+        let token_indices = tree.get_token_indices(self.account_id, self.token_id)?;
 
         // update balance
         tree.update_balance(
             self.account_id,
+            self.token_id,
             new_balance,
-        );
+        )?;
 
         // record account state
-        AccountState::<Bn256> {
+        Ok(AccountState::<Bn256> {
             old_balance: Some(old_balance),
             new_balance: Some(new_balance),
             old_pubkey: Some(pubkey.0.clone()),
@@ -51,6 +54,8 @@ impl OnchainWithdrawal {
             new_nonce: Some(nonce),
             account_path: optionalize(account_path),
             account_indices: optionalize(account_indices),
-        }
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        })
     }
 }
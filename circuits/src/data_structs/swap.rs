@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use crate::account::AccountState;
+use sapling_crypto_ce::eddsa::Signature;
+
+use super::super::{
+    tree::account::{ AccountsTree, TreeError },
+};
+
+use crate::utils::op_type::SWAP_OP;
+use crate::types::{ Nonce, Balance };
+
+use crate::utils::utils::{
+    optionalize,
+    usize_to_fr,
+    fr_to_sign_message,
+    u128_to_fr,
+};
+use crate::error::OpenPlasmaError;
+use super::offchain_withdrawal::SignatureError;
+
+use sapling_crypto_ce::{
+    eddsa::{
+        PrivateKey,
+        PublicKey,
+    },
+    poseidon::{
+        poseidon_hash,
+        bn256::Bn256PoseidonParams,
+    },
+    jubjub::FixedGenerators,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use rand::thread_rng;
+
+pub use crate::utils::signature::NUM_BYTES_TO_SIGN;
+
+// one side of the swap: the account sells amount of token_id
+#[derive(Clone)]
+pub struct SwapHalf {
+    pub account_id: usize,
+    pub token_id: usize,
+    pub amount: u128,
+    pub nonce: Nonce,
+    pub sign: Option<Signature::<Bn256>>,
+}
+
+// all or nothing exchange, each party signs the full terms with its own nonce
+#[derive(Clone)]
+pub struct Swap {
+    pub a: SwapHalf,
+    pub b: SwapHalf,
+}
+
+impl Swap {
+
+    pub fn hash(
+        & self,
+        hash_params: &Bn256PoseidonParams,
+        nonce: Nonce,
+    ) -> bn256::Fr {
+        let request = vec![
+            usize_to_fr(SWAP_OP),
+            usize_to_fr(self.a.account_id),
+            usize_to_fr(self.a.token_id),
+            u128_to_fr(self.a.amount),
+            usize_to_fr(self.b.account_id),
+            usize_to_fr(self.b.token_id),
+            u128_to_fr(self.b.amount),
+            nonce.to_fr(),
+        ];
+
+        let hash_vec = poseidon_hash::<Bn256>(hash_params, &request);
+        hash_vec[0]
+    }
+
+    // the same account on both sides would update its leaf against itself
+    pub fn is_valid(&self) -> bool {
+        self.a.account_id != self.b.account_id
+    }
+
+    fn sign_half(
+        &self,
+        nonce: Nonce,
+        seckey: &PrivateKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Signature::<Bn256> {
+        let hash = self.hash(hash_params, nonce);
+        let hash_bytes = fr_to_sign_message(hash);
+        let mut rng = thread_rng();
+
+        seckey.sign_raw_message(
+            &hash_bytes,
+            &mut rng,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        )
+    }
+
+    pub fn sign_a(
+        &mut self,
+        seckey: &PrivateKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) {
+        self.a.sign = Some(self.sign_half(self.a.nonce, seckey, hash_params, sign_params));
+    }
+
+    pub fn sign_b(
+        &mut self,
+        seckey: &PrivateKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) {
+        self.b.sign = Some(self.sign_half(self.b.nonce, seckey, hash_params, sign_params));
+    }
+
+    pub fn verify_signatures(
+        & self,
+        pubkey_a: &PublicKey::<Bn256>,
+        pubkey_b: &PublicKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<(), OpenPlasmaError> {
+        for (half, pubkey) in [(&self.a, pubkey_a), (&self.b, pubkey_b)] {
+            let sign = half.sign.as_ref().ok_or(SignatureError::MissingSignature)?;
+            let hash = self.hash(hash_params, half.nonce);
+            let hash_bytes = fr_to_sign_message(hash);
+
+            if !pubkey.verify_for_raw_message(
+                &hash_bytes,
+                sign,
+                FixedGenerators::SpendingKeyGenerator,
+                sign_params,
+                NUM_BYTES_TO_SIGN,
+            ) {
+                return Err(SignatureError::VerificationFailed.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // leaves are updated in the order of the circuit: a pays token a, b receives
+    // token a, b pays token b, a receives token b. Nonces are bumped on payment
+    pub fn update_tree_and_record_state(
+        & self,
+        tree: &mut AccountsTree,
+    ) -> Result<[AccountState::<Bn256>; 4], OpenPlasmaError> {
+        if !self.is_valid() {
+            return Err(TreeError::SelfTransfer(self.a.account_id).into());
+        }
+        for &account_id in &[self.a.account_id, self.b.account_id] {
+            tree.check_token(account_id, self.a.token_id)?;
+            tree.check_token(account_id, self.b.token_id)?;
+        }
+        tree.check_next_nonce(self.a.account_id, self.a.nonce)?;
+        tree.check_next_nonce(self.b.account_id, self.b.nonce)?;
+        let new_balances = self.new_balances(tree)?;
+
+        Ok([
+            Self::debit(tree, &self.a, new_balances[0])?,
+            Self::credit(tree, self.b.account_id, self.a.token_id, new_balances[1])?,
+            Self::debit(tree, &self.b, new_balances[2])?,
+            Self::credit(tree, self.a.account_id, self.b.token_id, new_balances[3])?,
+        ])
+    }
+
+    // the balances after each of the four updates, counted before the first
+    // write. with one token on both sides a leaf is updated twice
+    fn new_balances(&self, tree: &AccountsTree) -> Result<[u128; 4], OpenPlasmaError> {
+        let steps = [
+            (self.a.account_id, self.a.token_id, self.a.amount, false),
+            (self.b.account_id, self.a.token_id, self.a.amount, true),
+            (self.b.account_id, self.b.token_id, self.b.amount, false),
+            (self.a.account_id, self.b.token_id, self.b.amount, true),
+        ];
+
+        let mut balances = HashMap::new();
+        let mut new_balances = [0; 4];
+        for (new_balance, &(account_id, token_id, amount, is_credit)) in new_balances.iter_mut().zip(steps.iter()) {
+            let old_balance = match balances.get(&(account_id, token_id)) {
+                Some(&balance) => balance,
+                None => Balance::try_from_fr(&tree.get_balance(account_id, token_id)?)?.0,
+            };
+            *new_balance = if is_credit {
+                old_balance.checked_add(amount).ok_or(TreeError::BalanceOverflow { account_id, token_id })?
+            } else {
+                old_balance.checked_sub(amount).ok_or(TreeError::InsufficientBalance { account_id, token_id })?
+            };
+            balances.insert((account_id, token_id), *new_balance);
+        }
+
+        Ok(new_balances)
+    }
+
+    fn debit(
+        tree: &mut AccountsTree,
+        half: &SwapHalf,
+        new_balance: u128,
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+        let old_balance = tree.get_balance(half.account_id, half.token_id)?;
+        let new_balance = u128_to_fr(new_balance);
+
+        // prepare paths, indices, pubkeys, nonces
+        let pubkey = tree.accounts[half.account_id].pubkey.clone();
+        let old_nonce = tree.check_next_nonce(half.account_id, half.nonce)?.to_fr();
+        let new_nonce = half.nonce.to_fr();
+        let account_path = tree.get_leaf_path(half.account_id)?;
+        let account_indices = tree.get_leaf_indices(half.account_id)?;
+        let token_path = tree.get_token_path(half.account_id, half.token_id)?;
+        let token_indices = tree.get_token_indices(half.account_id, half.token_id)?;
+
+        // update balance
+        tree.update_balance(
+            half.account_id,
+            half.token_id,
+            new_balance,
+        )?;
+
+        tree.update_nonce(
+            half.account_id,
+            new_nonce,
+        )?;
+
+        // record account state
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(old_balance),
+            new_balance: Some(new_balance),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0),
+            old_nonce: Some(old_nonce),
+            new_nonce: Some(new_nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        })
+    }
+
+    fn credit(
+        tree: &mut AccountsTree,
+        account_id: usize,
+        token_id: usize,
+        new_balance: u128,
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+        let old_balance = tree.get_balance(account_id, token_id)?;
+        let new_balance = u128_to_fr(new_balance);
+
+        // prepare paths, indices, pubkeys, nonces
+        let pubkey = tree.accounts[account_id].pubkey.clone();
+        let nonce = tree.accounts[account_id].nonce;
+        let account_path = tree.get_leaf_path(account_id)?;
+        let account_indices = tree.get_leaf_indices(account_id)?;
+        let token_path = tree.get_token_path(account_id, token_id)?;
+        let token_indices = tree.get_token_indices(account_id, token_id)?;
+
+        // update balance
+        tree.update_balance(
+            account_id,
+            token_id,
+            new_balance,
+        )?;
+
+        // record account state
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(old_balance),
+            new_balance: Some(new_balance),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0),
+            old_nonce: Some(nonce),
+            new_nonce: Some(nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        })
+    }
+}
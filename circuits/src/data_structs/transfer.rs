@@ -2,15 +2,20 @@ use crate::account::AccountState;
 use sapling_crypto_ce::eddsa::Signature;
 
 use super::super::{
-    tree::account::AccountsTree,
+    tree::account::{ AccountsTree, TreeError },
 };
 
+use crate::utils::op_type::TRANSFER_OP;
+use crate::types::{ Nonce, Balance };
+
 use crate::utils::utils::{
     optionalize,
-    fr_to_usize,
     usize_to_fr,
-    fr_to_bytes_le,
+    fr_to_sign_message,
+    u128_to_fr,
 };
+use crate::error::OpenPlasmaError;
+use super::offchain_withdrawal::SignatureError;
 
 use sapling_crypto_ce::{
     eddsa::{
@@ -37,12 +42,13 @@ use rand::thread_rng;
 pub struct Transfer {
     pub account_id_from: usize,
     pub account_id_to: usize,
-    pub amount: usize,
-    pub nonce: usize,
+    pub token_id: usize,
+    pub amount: u128,
+    pub nonce: Nonce,
     pub sign: Option<Signature::<Bn256>>,
 }
 
-pub const NUM_BYTES_TO_SIGN: usize = 31;
+pub use crate::utils::signature::NUM_BYTES_TO_SIGN;
 
 impl Transfer {
 
@@ -51,10 +57,12 @@ impl Transfer {
         hash_params: &Bn256PoseidonParams
     ) -> bn256::Fr {
         let request = vec![
+            usize_to_fr(TRANSFER_OP),
             usize_to_fr(self.account_id_from),
             usize_to_fr(self.account_id_to),
-            usize_to_fr(self.amount),
-            usize_to_fr(self.nonce),
+            usize_to_fr(self.token_id),
+            u128_to_fr(self.amount),
+            self.nonce.to_fr(),
         ];
     
         let hash_vec = poseidon_hash::<Bn256>(hash_params, &request);
@@ -68,7 +76,7 @@ impl Transfer {
         sign_params: &AltJubjubBn256,
     ) {
         let hash = self.hash(hash_params);
-        let hash_bytes: Vec<_> = fr_to_bytes_le(hash, NUM_BYTES_TO_SIGN);
+        let hash_bytes = fr_to_sign_message(hash);
         let mut rng = thread_rng();
 
         let sign = seckey.sign_raw_message(
@@ -87,59 +95,79 @@ impl Transfer {
         pubkey: &PublicKey::<Bn256>,
         hash_params: &Bn256PoseidonParams,
         sign_params: &AltJubjubBn256,
-    ) -> bool {
+    ) -> Result<(), OpenPlasmaError> {
+        let sign = self.sign.as_ref().ok_or(SignatureError::MissingSignature)?;
         let hash = self.hash(hash_params);
-        let hash_bytes: Vec<_> = fr_to_bytes_le(hash, NUM_BYTES_TO_SIGN);
+        let hash_bytes = fr_to_sign_message(hash);
 
-        pubkey.verify_for_raw_message(
+        if !pubkey.verify_for_raw_message(
             &hash_bytes,
-            &self.sign.clone().unwrap(),
+            sign,
             FixedGenerators::SpendingKeyGenerator,
             sign_params,
             NUM_BYTES_TO_SIGN,
-        )
+        ) {
+            return Err(SignatureError::VerificationFailed.into());
+        }
+
+        Ok(())
     }
 
     pub fn update_tree_and_record_state(
         & self,
         tree: &mut AccountsTree,
-    ) -> (AccountState::<Bn256>, AccountState::<Bn256>) {
-        
-        assert!(self.account_id_from < tree.accounts.len());
-        assert!(self.account_id_to < tree.accounts.len());
+    ) -> Result<(AccountState::<Bn256>, AccountState::<Bn256>), OpenPlasmaError> {
+        // both states are of one leaf otherwise, the second on a stale path
+        if self.account_id_from == self.account_id_to {
+            return Err(TreeError::SelfTransfer(self.account_id_from).into());
+        }
+        tree.check_token(self.account_id_from, self.token_id)?;
+        tree.check_token(self.account_id_to, self.token_id)?;
+
+        // count balances, all checks are before the first write
+        let old_balance_from = tree.get_balance(self.account_id_from, self.token_id)?;
+        let new_balance_from = Balance::try_from_fr(&old_balance_from)?.0.checked_sub(self.amount)
+            .ok_or(TreeError::InsufficientBalance {
+                account_id: self.account_id_from,
+                token_id: self.token_id,
+            })?;
+
+        let old_balance_to = tree.get_balance(self.account_id_to, self.token_id)?;
+        let new_balance_to = Balance::try_from_fr(&old_balance_to)?.0.checked_add(self.amount)
+            .ok_or(TreeError::BalanceOverflow {
+                account_id: self.account_id_to,
+                token_id: self.token_id,
+            })?;
+
+        let old_nonce = tree.check_next_nonce(self.account_id_from, self.nonce)?.to_fr();
 
         // account from ------------------------------------------------------------
 
-        // count balances
-        let old_balance = tree.accounts[self.account_id_from].balance;
-        let new_balance = {
-            let old_balance = fr_to_usize(old_balance);
-            assert!(old_balance >= self.amount);
-            usize_to_fr(old_balance - self.amount)
-        };
+        let new_balance = u128_to_fr(new_balance_from);
 
         // prepare paths, indices, pubkeys, nonces
         let pubkey = tree.accounts[self.account_id_from].pubkey.clone();
-        let old_nonce = tree.accounts[self.account_id_from].nonce;
-        assert!(fr_to_usize(old_nonce) == self.nonce - 1);
-        let new_nonce = usize_to_fr(self.nonce);
-        let account_path = tree.accounts_tree.get_leaf_path(self.account_id_from);
-        let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id_from);
+        let new_nonce = self.nonce.to_fr();
+        let account_path = tree.get_leaf_path(self.account_id_from)?;
+        let account_indices = tree.get_leaf_indices(self.account_id_from)?;
+        let token_path = tree.get_token_path(self.account_id_from, self.token_id)?;
+        let token_indices = tree.get_token_indices(self.account_id_from, self.token_id)?;
 
         // update balance
         tree.update_balance(
             self.account_id_from,
+            self.token_id,
             new_balance,
-        );
+        )?;
 
         tree.update_nonce(
             self.account_id_from,
             new_nonce,
-        );
+        )?;
 
         // record account state
         let account_state_from = AccountState::<Bn256> {
-            old_balance: Some(old_balance),
+            old_balance: Some(old_balance_from),
             new_balance: Some(new_balance),
             old_pubkey: Some(pubkey.0.clone()),
             new_pubkey: Some(pubkey.0),
@@ -147,29 +175,32 @@ impl Transfer {
             new_nonce: Some(new_nonce),
             account_path: optionalize(account_path),
             account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
         };
 
         // account to --------------------------------------------------------------
 
-        // count balances
-        let old_balance = tree.accounts[self.account_id_to].balance;
-        let new_balance = usize_to_fr(fr_to_usize(old_balance) + self.amount);
+        let new_balance = u128_to_fr(new_balance_to);
 
         // prepare paths, indices, pubkeys, nonces
         let pubkey = tree.accounts[self.account_id_to].pubkey.clone();
         let nonce = tree.accounts[self.account_id_to].nonce;
-        let account_path = tree.accounts_tree.get_leaf_path(self.account_id_to);
-        let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id_to);
+        let account_path = tree.get_leaf_path(self.account_id_to)?;
+        let account_indices = tree.get_leaf_indices(self.account_id_to)?;
+        let token_path = tree.get_token_path(self.account_id_to, self.token_id)?;
+        let token_indices = tree.get_token_indices(self.account_id_to, self.token_id)?;
 
         // update balance
         tree.update_balance(
             self.account_id_to,
+            self.token_id,
             new_balance,
-        );
+        )?;
 
         // record account state
         let account_state_to = AccountState::<Bn256> {
-            old_balance: Some(old_balance),
+            old_balance: Some(old_balance_to),
             new_balance: Some(new_balance),
             old_pubkey: Some(pubkey.0.clone()),
             new_pubkey: Some(pubkey.0),
@@ -177,8 +208,10 @@ impl Transfer {
             new_nonce: Some(nonce),
             account_path: optionalize(account_path),
             account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
         };
 
-        (account_state_from, account_state_to)
+        Ok((account_state_from, account_state_to))
     }
 }
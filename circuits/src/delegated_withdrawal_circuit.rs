@@ -0,0 +1,422 @@
+use std::mem;
+
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        Unknown,
+        JubjubEngine,
+        edwards::Point,
+    },
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        poseidon_hash::poseidon_hash,
+        num::AllocatedNum,
+    },
+    eddsa::Signature,
+};
+
+use ff_ce::Field;
+
+use crate::types::{ BALANCE_BITS, NONCE_BITS };
+use crate::utils::sign::verify_signature;
+
+use super::account::{ AccountState, AccountCircuit, LEAF_NONCE };
+use super::offchain_withdrawal_circuit::{ OffchainWithdrawalCircuit, enforce_not_expired };
+use super::public_inputs::alloc_public_inputs;
+use super::utils::op_type::{ alloc_op_type, OFFCHAIN_WITHDRAWAL_OP, WITHDRAWAL_PERMIT_OP };
+use super::utils::domain::{ SigningDomain, alloc_signing_domain, ADDRESS_BITS };
+use super::utils::calc::{ check_decomposition_le, enforce_bit_length, enforce_less_or_equal };
+
+const BITS_IN_BYTE: usize = 8;
+
+// withdrawal signed by the spender of a WithdrawalPermit, the permit is signed
+// by the owner of the leaf. Both have the nonce the withdrawal consumes, fees
+// are not charged, so the withdrawal is signed with zero fee
+#[derive(Clone)]
+pub struct DelegatedWithdrawalCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub account_state: AccountState<E>,
+    pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
+    pub amount: Option::<E::Fr>,
+    pub nonce: Option::<E::Fr>,
+    pub valid_until: Option::<E::Fr>,
+    pub eth_address: Option::<E::Fr>,
+    pub sign: Option::<Signature<E>>,
+    pub spender_pubkey: Option::<Point<E, Unknown>>,
+
+    pub permit_max_amount: Option::<E::Fr>,
+    pub permit_valid_until: Option::<E::Fr>,
+    pub permit_sign: Option::<Signature<E>>,
+    pub pubkey: Option::<Point<E, Unknown>>,
+}
+
+impl<E> DelegatedWithdrawalCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn process<'a, CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &'a <E as PoseidonEngine>::Params,
+        sign_params: &'a <E as JubjubEngine>::Params,
+        signing_domain: &[AllocatedNum<E>; 2],
+        timestamp: &AllocatedNum<E>,
+        old_hash: &AllocatedNum<E>,
+        old_root: &AllocatedNum<E>,
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
+
+        // allocate avariables ----------------------------------------------------------
+
+        let account_circuit = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit"),
+            account_depth,
+            token_depth,
+            hash_params,
+            &self.account_state,
+        )?;
+
+        let account_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate account id"),
+            || self.account_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let amount_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate amount"),
+            || self.amount.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let nonce_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate nonce"),
+            || self.nonce.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let valid_until_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate valid until"),
+            || self.valid_until.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let eth_address_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate eth address"),
+            || self.eth_address.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let max_amount_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate permit max amount"),
+            || self.permit_max_amount.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let permit_valid_until_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate permit valid until"),
+            || self.permit_valid_until.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let zero_fee = AllocatedNum::alloc(
+            cs.namespace(|| "allocate zero fee"),
+            || Ok(E::Fr::zero()),
+        )?;
+        zero_fee.assert_zero(cs.namespace(|| "check zero fee"))?;
+
+        let op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate op type"),
+            OFFCHAIN_WITHDRAWAL_OP,
+        )?;
+
+        let permit_op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate permit op type"),
+            WITHDRAWAL_PERMIT_OP,
+        )?;
+
+        // check spender signature, the same message as OffchainWithdrawal::hash --------
+
+        let withdrawal_hash = poseidon_hash(
+            cs.namespace(|| "calculate message hash"),
+            &[
+                op_type_alloc.clone(),
+                account_id_alloc.clone(),
+                token_id_alloc.clone(),
+                amount_alloc.clone(),
+                zero_fee,
+                nonce_alloc.clone(),
+                valid_until_alloc.clone(),
+                eth_address_alloc.clone(),
+                signing_domain[0].clone(),
+                signing_domain[1].clone(),
+            ],
+            hash_params,
+        )?[0].clone();
+
+        let spender_sign_alloc = verify_signature(
+            cs.namespace(|| "verify spender signature"),
+            self.sign.clone(),
+            self.spender_pubkey.clone(),
+            &withdrawal_hash,
+            sign_params,
+        )?;
+
+        // check owner permit for this spender, see WithdrawalPermit::hash --------------
+
+        let permit_hash = poseidon_hash(
+            cs.namespace(|| "calculate permit hash"),
+            &[
+                permit_op_type_alloc,
+                account_id_alloc.clone(),
+                spender_sign_alloc.pk.get_x().clone(),
+                spender_sign_alloc.pk.get_y().clone(),
+                max_amount_alloc.clone(),
+                permit_valid_until_alloc.clone(),
+                nonce_alloc.clone(),
+            ],
+            hash_params,
+        )?[0].clone();
+
+        let owner_sign_alloc = verify_signature(
+            cs.namespace(|| "verify permit signature"),
+            self.permit_sign.clone(),
+            self.pubkey.clone(),
+            &permit_hash,
+            sign_params,
+        )?;
+
+        // check changes validity -------------------------------------------------------
+
+        // the permit is signed by the owner of the leaf
+
+        OffchainWithdrawalCircuit::check_pubkey(
+            cs.namespace(|| "public key consistence"),
+            &owner_sign_alloc.pk,
+            &account_circuit,
+        )?;
+
+        // check account id, token id consistency
+
+        check_decomposition_le(
+            cs.namespace(|| "account id consistence"),
+            &account_id_alloc,
+            &account_circuit.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
+        // check amount within the permit
+
+        cs.enforce(
+            || "check amount withdrawal",
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable()
+                + amount_alloc.get_variable(),
+        );
+
+        enforce_bit_length(
+            cs.namespace(|| "check amount overflow"),
+            &amount_alloc,
+            BALANCE_BITS,
+        )?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check max amount overflow"),
+            &max_amount_alloc,
+            BALANCE_BITS,
+        )?;
+
+        enforce_less_or_equal(
+            cs.namespace(|| "check amount within permit"),
+            &amount_alloc,
+            &max_amount_alloc,
+            BALANCE_BITS,
+        )?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check balance overflow"),
+            &account_circuit.balances_tree.new_leaf_alloc[0],
+            BALANCE_BITS,
+        )?;
+
+        // check expiry of both the withdrawal and the permit
+
+        enforce_not_expired(
+            cs.namespace(|| "check withdrawal not expired"),
+            timestamp,
+            &valid_until_alloc,
+        )?;
+
+        enforce_not_expired(
+            cs.namespace(|| "check permit not expired"),
+            timestamp,
+            &permit_valid_until_alloc,
+        )?;
+
+        // check nonce, the permit and the withdrawal consume the same one
+
+        cs.enforce(
+            || "nonce consistence",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one(),
+            |lc| lc + CS::one(),
+            |lc| lc + nonce_alloc.get_variable(),
+        );
+
+        enforce_bit_length(
+            cs.namespace(|| "check nonce overflow"),
+            &nonce_alloc,
+            NONCE_BITS,
+        )?;
+
+        // the contract reads it as uint160
+
+        enforce_bit_length(
+            cs.namespace(|| "check eth address overflow"),
+            &eth_address_alloc,
+            ADDRESS_BITS,
+        )?;
+
+        cs.enforce(
+            || "check nonce + 1",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[LEAF_NONCE].get_variable(),
+        );
+
+        // calculate new hash, the same record as of an offchain withdrawal -------------
+
+        let new_hash = {
+            let hashes_vec = poseidon_hash(
+                cs.namespace(|| "calculate new accum hash"),
+                &[
+                    op_type_alloc,
+                    old_hash.clone(),
+                    account_id_alloc,
+                    token_id_alloc,
+                    amount_alloc,
+                    eth_address_alloc,
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
+
+        // verify old root & calculate new root -----------------------------------------
+
+        account_circuit.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify old root"),
+            old_root,
+        )?;
+
+        let new_root = account_circuit.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate new root"),
+        )?;
+
+        Ok((new_hash, new_root))
+    }
+}
+
+#[derive(Clone)]
+pub struct DelegatedWithdrawalBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub batch_size: usize,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub signing_domain: SigningDomain,
+    pub queue: Vec::<DelegatedWithdrawalCircuit<E>>,
+    // block timestamp, public input following the block public inputs
+    pub timestamp: Option::<E::Fr>,
+    pub old_accum_hash: Option::<E::Fr>,
+    pub new_accum_hash: Option::<E::Fr>,
+    pub old_account_root: Option::<E::Fr>,
+    pub new_account_root: Option::<E::Fr>,
+}
+
+impl<'a, E> Circuit<E> for DelegatedWithdrawalBatchCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        if self.batch_size != self.queue.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        )?;
+
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
+
+        let timestamp = AllocatedNum::alloc(
+            cs.namespace(|| "allocate timestamp"),
+            || self.timestamp.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        timestamp.inputize(cs.namespace(|| "input timestamp"))?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check timestamp overflow"),
+            &timestamp,
+            mem::size_of::<usize>() * BITS_IN_BYTE,
+        )?;
+
+        let signing_domain = alloc_signing_domain(
+            cs.namespace(|| "allocate signing domain"),
+            &self.signing_domain,
+        )?;
+
+        for (i, withdrawal) in self.queue.iter().enumerate() {
+            let (hash, root) = withdrawal.process(
+                cs.namespace(|| format!("verify delegated withdrawal {}", i)),
+                self.account_depth,
+                self.token_depth,
+                self.hash_params,
+                self.sign_params,
+                &signing_domain,
+                &timestamp,
+                &prev_hash,
+                &prev_root,
+            )?;
+
+            prev_hash = hash;
+            prev_root = root;
+        }
+
+        cs.enforce(
+            || "enforce new accum hash equivalence",
+            |lc| lc + prev_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_hash.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new root equivalence",
+            |lc| lc + prev_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_root.get_variable(),
+        );
+
+        Ok(())
+    }
+}
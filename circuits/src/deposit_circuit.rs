@@ -1,3 +1,9 @@
+use std::{
+    fmt,
+    error::Error,
+    sync::{ Arc, Mutex },
+};
+
 use bellman_ce::{
     Circuit,
     ConstraintSystem,
@@ -8,6 +14,8 @@ use sapling_crypto_ce::{
     poseidon::{
         PoseidonEngine,
         QuinticSBox,
+        bn256::Bn256PoseidonParams,
+        poseidon_hash,
     },
     jubjub::{
         JubjubEngine,
@@ -16,38 +24,179 @@ use sapling_crypto_ce::{
     },
     circuit::{
         num::AllocatedNum,
-        poseidon_hash::poseidon_hash,
+        boolean::{ Boolean, AllocatedBit },
     },
 };
 
-use super::account::{ AccountState, AccountCircuit };
-use super::utils::calc::check_decomposition_le;
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use ff_ce::Field;
+
+use super::types::BALANCE_BITS;
+use super::account::{
+    AccountState,
+    AccountCircuit,
+    WitnessError,
+    indices_to_fr,
+    LEAF_PUBKEY,
+    LEAF_NONCE,
+};
+use super::tree::merkle_tree::BINARY_ARITY;
+use super::tree::empty::empty_account_leaf_with_hasher;
+use super::tree::leaf::LeafVersion;
+use super::public_inputs::{
+    PublicInputs,
+    AllocatedPublicInputs,
+    alloc_public_inputs,
+    alloc_committed_public_inputs,
+};
+use super::utils::calc::{
+    alloc_bits_le,
+    check_decomposition_le,
+    enforce_bit_length,
+    is_zero,
+    enforce_nonzero_unless,
+    is_equal_to_constants,
+};
+use super::utils::sign::check_pubkey;
+use super::utils::point::{ pack_point, pack_point_gadget };
+use super::utils::utils::usize_to_fr;
+use super::utils::op_type::{ alloc_op_type, DEPOSIT_OP, REFUND_OP };
+use super::data_structs::encoding::{ ENCODING_VERSION, POINT_BYTES };
+use super::pubdata::{
+    pubdata_commitment,
+    le_bytes_bits,
+    be_word_bits,
+    constant_byte_bits,
+};
+use super::stats::measure;
+use super::params::Params;
+use super::family::BatchConfig;
+use super::hasher::{ TreeHasher, Poseidon };
+use super::sponge::SpongeGadget;
+
+const BITS_IN_BYTE: usize = 8;
+
+// how deposits are absorbed into the accum hash. poseidon of the record, or
+// whatever tree hasher process_deposit is given, is cheap in the circuit,
+// sha256 of the OffchainDeposit::encode bytes is what a contract can recompute
+// from calldata, see pubdata::accumulate_pubdata. Sponge absorbs the same
+// record as Poseidon into a sponge::PoseidonSponge and squeezes once; it is a
+// different accum hash, so Poseidon stays the default for existing keys
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepositAccumulator {
+    Poseidon,
+    Sha256,
+    Sponge,
+}
 
 #[derive(Clone)]
 pub struct DepositCircuit<E: JubjubEngine + PoseidonEngine> {
     pub account_state: AccountState<E>,
     pub pubkey: Option::<Point<E, Unknown>>,
     pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
     pub amount: Option::<E::Fr>,
+    pub is_noop: Option::<bool>,
+    // an op of the contract's queue that can't be applied, it is absorbed
+    // like a deposit but leaves the root unchanged and is refunded on L1
+    pub is_refund: Option::<bool>,
+}
+
+impl<E: JubjubEngine + PoseidonEngine> DepositCircuit<E> {
+    pub fn missing_field(&self) -> Option<&'static str> {
+        if self.pubkey.is_none() {
+            Some("pubkey")
+        } else if self.account_id.is_none() {
+            Some("account_id")
+        } else if self.token_id.is_none() {
+            Some("token_id")
+        } else if self.amount.is_none() {
+            Some("amount")
+        } else if self.is_noop.is_none() {
+            Some("is_noop")
+        } else if self.is_refund.is_none() {
+            Some("is_refund")
+        } else {
+            self.account_state.missing_field()
+        }
+    }
 }
 
 impl<E> DepositCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
-    pub fn process_deposit<'a, CS: ConstraintSystem<E>> (
+    // deposit without witness for parameter generation, the paths have the
+    // lengths of the depths like in any filled deposit
+    pub fn empty(account_depth: usize, token_depth: usize) -> Self {
+        DepositCircuit {
+            account_state: AccountState::empty(account_depth, token_depth),
+            pubkey: None,
+            account_id: None,
+            token_id: None,
+            amount: None,
+            is_noop: None,
+            is_refund: None,
+        }
+    }
+
+    // padding deposit: zero point pubkey, zero account id, token id and amount,
+    // keeps the root unchanged but is still absorbed into the accum hash
+    pub fn noop(account_depth: usize, token_depth: usize) -> Self {
+        let zero_pubkey = Point::<E, Unknown>::zero();
+
+        let account_state = AccountState::<E> {
+            old_balance: Some(E::Fr::zero()),
+            new_balance: Some(E::Fr::zero()),
+            old_pubkey: Some(zero_pubkey.clone()),
+            new_pubkey: Some(zero_pubkey.clone()),
+            old_nonce: Some(E::Fr::zero()),
+            new_nonce: Some(E::Fr::zero()),
+            account_path: vec![Some(E::Fr::zero()); account_depth],
+            account_indices: vec![Some(false); account_depth],
+            token_path: vec![Some(E::Fr::zero()); token_depth],
+            token_indices: vec![Some(false); token_depth],
+        };
+
+        DepositCircuit {
+            account_state,
+            pubkey: Some(zero_pubkey),
+            account_id: Some(E::Fr::zero()),
+            token_id: Some(E::Fr::zero()),
+            amount: Some(E::Fr::zero()),
+            is_noop: Some(true),
+            is_refund: Some(false),
+        }
+    }
+
+    // the trees and the record are hashed with H, Poseidon for a deposit batch
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_deposit<CS: ConstraintSystem<E>, H: TreeHasher<E>> (
         &self,
         mut cs: CS,
         account_depth: usize,
-        hash_params: &'a <E as PoseidonEngine>::Params,
+        token_depth: usize,
+        hash_params: &H::Params,
+        sign_params: &<E as JubjubEngine>::Params,
+        sponge_params: &<E as PoseidonEngine>::Params,
         old_hash: &AllocatedNum<E>,
         old_root: &AllocatedNum<E>,
+        accumulator: DepositAccumulator,
+        reject_zero_amount: bool,
+        leaf_version: LeafVersion,
     ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
         // allocate circuit
-        let account_circuit = AccountCircuit::new(
+        let account_circuit = AccountCircuit::<E, H>::new_with_version(
             cs.namespace(|| "allocate account circuit"),
             account_depth,
+            token_depth,
+            BINARY_ARITY,
             hash_params,
             &self.account_state,
+            leaf_version,
         )?;
 
         let (pubkey_x, pubkey_y) = match &self.pubkey {
@@ -73,28 +222,146 @@ impl<E> DepositCircuit<E>
             || self.account_id.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
         let amount_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate amount"),
             || self.amount.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
-        // check pubkey consistence
+        let is_noop_alloc = AllocatedBit::alloc(
+            cs.namespace(|| "allocate is noop"),
+            self.is_noop,
+        )?;
+
+        let is_refund_alloc = AllocatedBit::alloc(
+            cs.namespace(|| "allocate is refund"),
+            self.is_refund,
+        )?;
 
         cs.enforce(
-            || "check pubkey x consistence",
+            || "check noop is not a refund",
+            |lc| lc + is_noop_alloc.get_variable(),
+            |lc| lc + is_refund_alloc.get_variable(),
+            |lc| lc,
+        );
+
+        // check noop record is zero: zero point pubkey, account id, token id and amount
+
+        cs.enforce(
+            || "check noop pubkey x",
             |lc| lc + pubkey_x_alloc.get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[0].get_variable(),
+            |lc| lc + is_noop_alloc.get_variable(),
+            |lc| lc,
         );
 
         cs.enforce(
-            || "check pubkey y consistence",
-            |lc| lc + pubkey_y_alloc.get_variable(),
+            || "check noop pubkey y",
+            |lc| lc + pubkey_y_alloc.get_variable() - CS::one(),
+            |lc| lc + is_noop_alloc.get_variable(),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check noop account id",
+            |lc| lc + account_id_alloc.get_variable(),
+            |lc| lc + is_noop_alloc.get_variable(),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check noop token id",
+            |lc| lc + token_id_alloc.get_variable(),
+            |lc| lc + is_noop_alloc.get_variable(),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check noop amount",
+            |lc| lc + amount_alloc.get_variable(),
+            |lc| lc + is_noop_alloc.get_variable(),
+            |lc| lc,
+        );
+
+        // a deposit that is not a noop has a nonzero amount, if the batch asks for it
+
+        if reject_zero_amount {
+            enforce_nonzero_unless(
+                cs.namespace(|| "check amount nonzero"),
+                &amount_alloc,
+                &Boolean::from(is_noop_alloc.clone()),
+            )?;
+        }
+
+        // check pubkey is a point of the prime order subgroup, noop zero point is as well
+
+        check_pubkey(
+            cs.namespace(|| "check pubkey"),
+            &pubkey_x_alloc,
+            &pubkey_y_alloc,
+            sign_params,
+        )?;
+
+        // check deposit doesn't overwrite pubkey: either the same pubkey or the empty leaf,
+        // empty account is the y = 0 point with zero nonce, it never belongs to a real key
+
+        let old_leaf = &account_circuit.accounts_tree.old_leaf_alloc;
+
+        // the leaf holds the packed pubkey, both the deposit and the old one are in
+        // the prime order subgroup, where packing is one to one
+        let packed_pubkey = pack_point_gadget(
+            cs.namespace(|| "pack pubkey"),
+            &pubkey_x_alloc,
+            &pubkey_y_alloc,
+        )?;
+
+        let is_same_pubkey = AllocatedNum::equals(
+            cs.namespace(|| "is same pubkey"),
+            &packed_pubkey,
+            &old_leaf[LEAF_PUBKEY],
+        )?;
+
+        // y = 0 packs to zero
+        let is_empty_leaf = {
+            let is_zero_y = is_zero(
+                cs.namespace(|| "is old pubkey y zero"),
+                &old_leaf[LEAF_PUBKEY],
+            )?;
+
+            let is_zero_nonce = is_zero(
+                cs.namespace(|| "is old nonce zero"),
+                &old_leaf[LEAF_NONCE],
+            )?;
+
+            Boolean::and(
+                cs.namespace(|| "is empty leaf"),
+                &is_zero_y,
+                &is_zero_nonce,
+            )?
+        };
+
+        // is_same_pubkey or is_empty_leaf
+
+        cs.enforce(
+            || "check pubkey not overwritten",
+            |_| is_same_pubkey.not().lc(CS::one(), E::Fr::one()),
+            |_| is_empty_leaf.not().lc(CS::one(), E::Fr::one()),
+            |lc| lc,
+        );
+
+        // check pubkey consistence
+
+        cs.enforce(
+            || "check pubkey consistence",
+            |lc| lc + packed_pubkey.get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[1].get_variable(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[LEAF_PUBKEY].get_variable(),
         );
 
-        // check account id, asset id consistency
+        // check account id, token id consistency
 
         check_decomposition_le(
             cs.namespace(|| "account id consistence"),
@@ -102,110 +369,405 @@ impl<E> DepositCircuit<E>
             &account_circuit.accounts_tree.indices_alloc,
         )?;
 
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
         // check amount deposit
 
         cs.enforce(
             || "check amount deposit",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[3].get_variable()
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable()
                     + amount_alloc.get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable(),
         );
 
+        // check amount and balance for overflow, otherwise the sum may wrap around the modulus
+
+        let amount_bits = alloc_bits_le(
+            cs.namespace(|| "check amount overflow"),
+            &amount_alloc,
+            BALANCE_BITS,
+        )?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check balance overflow"),
+            &account_circuit.balances_tree.new_leaf_alloc[0],
+            BALANCE_BITS,
+        )?;
+
         // check nonce the same
 
         cs.enforce(
             || "check nonce the same",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[2].get_variable(),
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[2].get_variable(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[LEAF_NONCE].get_variable(),
+        );
+
+        // a first deposit starts from the empty leaf of tree::empty, then the new
+        // leaf has nonce 0 and a balance of exactly the amount, not whatever the
+        // old values of the witness are
+
+        let empty_leaf = empty_account_leaf_with_hasher::<E, H>(token_depth, BINARY_ARITY, hash_params, sign_params);
+        let is_first_deposit = is_equal_to_constants(
+            cs.namespace(|| "is first deposit"),
+            old_leaf,
+            &empty_leaf,
+        )?;
+
+        cs.enforce(
+            || "check first deposit nonce",
+            |_| is_first_deposit.lc(CS::one(), E::Fr::one()),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[LEAF_NONCE].get_variable(),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check first deposit balance",
+            |_| is_first_deposit.lc(CS::one(), E::Fr::one()),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable()
+                    - amount_alloc.get_variable(),
+            |lc| lc,
         );
 
         // calculate new hash
 
-        let new_hash = {
-            let hashes_vec = poseidon_hash(
-                cs.namespace(|| "calculate new accum hash"),
-                &[
+        let is_noop = Boolean::from(is_noop_alloc);
+        let is_refund = Boolean::from(is_refund_alloc);
+
+        let new_hash = match accumulator {
+            DepositAccumulator::Poseidon | DepositAccumulator::Sponge => {
+                let op_type_alloc = alloc_op_type(
+                    cs.namespace(|| "allocate op type"),
+                    DEPOSIT_OP,
+                )?;
+
+                let record = [
+                    op_type_alloc,
                     old_hash.clone(),
                     pubkey_x_alloc,
                     pubkey_y_alloc,
                     account_id_alloc,
+                    token_id_alloc,
                     amount_alloc,
-                ],
-                hash_params,
-            )?;
-            hashes_vec[0].clone()
+                ];
+
+                if accumulator == DepositAccumulator::Sponge {
+                    SpongeGadget::hash(
+                        cs.namespace(|| "calculate new accum hash"),
+                        sponge_params,
+                        &record,
+                    )?
+                } else {
+                    H::hash_circuit(
+                        cs.namespace(|| "calculate new accum hash"),
+                        hash_params,
+                        &record,
+                    )?
+                }
+            },
+            DepositAccumulator::Sha256 => {
+                // the previous hash as a word, then the encoded deposit: version, op type,
+                // account id u32, compressed pubkey, token id u32 and amount u128.
+                // the ids are their checked index bits, the amount its overflow check bits
+                let mut bits = be_word_bits(&old_hash.into_bits_le_strict(
+                    cs.namespace(|| "old accum hash bits"),
+                )?);
+                bits.extend(constant_byte_bits(ENCODING_VERSION));
+                // a refund is in the pubdata under REFUND_OP, the op type bits
+                // where the two differ follow is_refund
+                bits.extend((0..8).rev().map(|i| match ((DEPOSIT_OP >> i) & 1 == 1, (REFUND_OP >> i) & 1 == 1) {
+                    (deposit, refund) if deposit == refund => Boolean::constant(deposit),
+                    (false, _) => is_refund.clone(),
+                    _ => is_refund.not(),
+                }));
+                bits.extend(le_bytes_bits(&account_circuit.accounts_tree.indices_alloc, 4));
+
+                // y with the parity of x in the top bit, as Point::write
+                let mut pubkey_bits = pubkey_y_alloc.into_bits_le_strict(
+                    cs.namespace(|| "pubkey y bits"),
+                )?;
+                let pubkey_x_bits = pubkey_x_alloc.into_bits_le_strict(
+                    cs.namespace(|| "pubkey x bits"),
+                )?;
+                pubkey_bits.resize(POINT_BYTES * BITS_IN_BYTE - 1, Boolean::constant(false));
+                pubkey_bits.push(pubkey_x_bits[0].clone());
+                bits.extend(le_bytes_bits(&pubkey_bits, POINT_BYTES));
+
+                bits.extend(le_bytes_bits(&account_circuit.balances_tree.indices_alloc, 4));
+                bits.extend(le_bytes_bits(&amount_bits, 16));
+
+                let commitment = pubdata_commitment(
+                    cs.namespace(|| "calculate new accum hash"),
+                    &bits,
+                )?;
+
+                // noop deposits are not in the calldata, they keep the hash
+                AllocatedNum::conditionally_select(
+                    cs.namespace(|| "select new accum hash"),
+                    old_hash,
+                    &commitment,
+                    &is_noop,
+                )?
+            },
         };
 
-        // verify old root & calculate new root
+        // verify old root & calculate new root, noop and refund leave the root unchanged
 
-        account_circuit.accounts_tree.verify_old_root(
-            cs.namespace(|| "verify old root"),
-            old_root,
+        let is_applied = Boolean::and(
+            cs.namespace(|| "is neither noop nor refund"),
+            &is_noop.not(),
+            &is_refund.not(),
         )?;
 
-        let new_root = account_circuit.accounts_tree.calc_new_root(
+        let calculated_old_root = account_circuit.accounts_tree.calc_old_root(
+            cs.namespace(|| "calculate old root"),
+        )?;
+
+        cs.enforce(
+            || "verify old root if not noop or refund",
+            |lc| lc + calculated_old_root.get_variable() - old_root.get_variable(),
+            |_| is_applied.lc(CS::one(), E::Fr::one()),
+            |lc| lc,
+        );
+
+        let calculated_new_root = account_circuit.accounts_tree.calc_new_root(
             cs.namespace(|| "calculate new root"),
         )?;
 
+        let new_root = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select new root"),
+            &calculated_new_root,
+            old_root,
+            &is_applied,
+        )?;
+
         Ok((new_hash, new_root))
     }
 }
 
+impl DepositCircuit<Bn256> {
+    // refunded op: its record is absorbed into the accum hash, the leaf is
+    // the one a first deposit of it would write but its path is not checked
+    // and the root stays unchanged. a pubkey that doesn't decompress is
+    // absorbed as the zero point
+    pub fn refund(
+        account_depth: usize,
+        token_depth: usize,
+        pubkey: Point<Bn256, Unknown>,
+        account_id: usize,
+        token_id: usize,
+        amount: bn256::Fr,
+    ) -> Self {
+        let bits = |id: usize, depth: usize| (0..depth).map(|i| Some((id >> i) & 1 == 1)).collect();
+
+        let account_state = AccountState::<Bn256> {
+            old_balance: Some(bn256::Fr::zero()),
+            new_balance: Some(amount),
+            old_pubkey: Some(pubkey.clone()),
+            new_pubkey: Some(pubkey.clone()),
+            old_nonce: Some(bn256::Fr::zero()),
+            new_nonce: Some(bn256::Fr::zero()),
+            account_path: vec![Some(bn256::Fr::zero()); account_depth],
+            account_indices: bits(account_id, account_depth),
+            token_path: vec![Some(bn256::Fr::zero()); token_depth],
+            token_indices: bits(token_id, token_depth),
+        };
+
+        DepositCircuit {
+            account_state,
+            pubkey: Some(pubkey),
+            account_id: Some(usize_to_fr(account_id)),
+            token_id: Some(usize_to_fr(token_id)),
+            amount: Some(amount),
+            is_noop: Some(false),
+            is_refund: Some(true),
+        }
+    }
+
+    // the record process_deposit absorbs with poseidon, for deposits and noops alike
+    pub fn absorb(&self, prev_hash: bn256::Fr, hash_params: &Bn256PoseidonParams) -> bn256::Fr {
+        let (pubkey_x, pubkey_y) = self.pubkey.as_ref().unwrap().into_xy();
+        poseidon_hash::<Bn256>(
+            hash_params,
+            &[
+                usize_to_fr(DEPOSIT_OP),
+                prev_hash,
+                pubkey_x,
+                pubkey_y,
+                self.account_id.unwrap(),
+                self.token_id.unwrap(),
+                self.amount.unwrap(),
+            ],
+        )[0]
+    }
+
+    // the witness checked off-circuit, so a stale path is found before
+    // proving: the old leaf has to be at old_root and the new one has to
+    // follow from the deposit. returns the accum hash and the root after the
+    // deposit, where the next slot starts
+    #[allow(clippy::too_many_arguments)]
+    pub fn validate_witness(
+        &self,
+        slot: usize,
+        account_depth: usize,
+        token_depth: usize,
+        leaf_version: LeafVersion,
+        old_hash: bn256::Fr,
+        old_root: bn256::Fr,
+        hash_params: &Bn256PoseidonParams,
+    ) -> Result<(bn256::Fr, bn256::Fr), WitnessError> {
+        if let Some(field) = self.missing_field() {
+            return Err(WitnessError::MissingField { slot: Some(slot), field });
+        }
+        let (calculated_old_root, calculated_new_root) = self.account_state.calc_versioned_roots(
+            slot, account_depth, token_depth, leaf_version, hash_params,
+        )?;
+
+        let state = &self.account_state;
+        let mut balance = state.old_balance.unwrap();
+        balance.add_assign(&self.amount.unwrap());
+
+        let reason = if indices_to_fr(&state.account_indices) != self.account_id {
+            Some("account id is not the leaf index")
+        } else if indices_to_fr(&state.token_indices) != self.token_id {
+            Some("token id is not the balance index")
+        } else if state.new_balance != Some(balance) {
+            Some("new balance is not the old one plus the amount")
+        } else if state.new_nonce != state.old_nonce {
+            Some("nonce changed")
+        } else if state.new_pubkey.as_ref().map(pack_point) != self.pubkey.as_ref().map(pack_point) {
+            Some("new pubkey is not the deposited one")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(WitnessError::InconsistentLeaf { slot, reason });
+        }
+
+        let new_hash = self.absorb(old_hash, hash_params);
+
+        // a noop or refund leaves the root unchanged, its path is not checked
+        if self.is_noop == Some(true) || self.is_refund == Some(true) {
+            return Ok((new_hash, old_root));
+        }
+        if calculated_old_root != old_root {
+            return Err(WitnessError::OldRootMismatch { slot });
+        }
+
+        Ok((new_hash, calculated_new_root))
+    }
+}
+
+type DepositSource<E> = dyn FnMut(usize) -> DepositCircuit<E> + Send;
+
+// the deposits of a batch, either held or produced one at a time while the
+// batch is synthesized, so the witnesses can be streamed from disk or built
+// on demand instead of all being in memory next to the prover's assignment.
+// the source is called with 0..deposit_batch in order on every synthesis,
+// clones of the batch share it
 #[derive(Clone)]
-pub struct DepositBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+pub enum DepositQueue<E: JubjubEngine + PoseidonEngine> {
+    Witnesses(Vec::<DepositCircuit<E>>),
+    Lazy(Arc<Mutex<DepositSource<E>>>),
+}
+
+impl<E: JubjubEngine + PoseidonEngine> DepositQueue<E> {
+    pub fn lazy<F>(source: F) -> Self
+        where F: FnMut(usize) -> DepositCircuit<E> + Send + 'static,
+    {
+        DepositQueue::Lazy(Arc::new(Mutex::new(source)))
+    }
+
+    // the held witnesses, none for a lazy queue
+    pub fn witnesses(&self) -> Option<&[DepositCircuit<E>]> {
+        match self {
+            DepositQueue::Witnesses(deposits) => Some(deposits),
+            DepositQueue::Lazy(_) => None,
+        }
+    }
+
+    pub fn witnesses_mut(&mut self) -> Option<&mut Vec::<DepositCircuit<E>>> {
+        match self {
+            DepositQueue::Witnesses(deposits) => Some(deposits),
+            DepositQueue::Lazy(_) => None,
+        }
+    }
+}
+
+impl<E: JubjubEngine + PoseidonEngine> From<Vec::<DepositCircuit<E>>> for DepositQueue<E> {
+    fn from(deposits: Vec::<DepositCircuit<E>>) -> Self {
+        DepositQueue::Witnesses(deposits)
+    }
+}
+
+#[derive(Clone)]
+pub struct DepositBatchCircuit<E: JubjubEngine + PoseidonEngine> {
     pub deposit_batch: usize,
     pub account_depth: usize,
-    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub token_depth: usize,
+    // owned, so the circuit can be moved to a proving thread
+    pub params: Arc<Params<E>>,
+    // amount != 0 for every deposit that is not a noop, off in the layout
+    // existing keys were generated for
+    pub reject_zero_amount: bool,
+    // the account leaf layout, a tree of another version never matches the roots
+    pub leaf_version: LeafVersion,
 
-    pub deposit_queue: Vec::<DepositCircuit<E>>,
+    pub deposit_queue: DepositQueue<E>,
     pub old_accum_hash: Option::<E::Fr>,
     pub new_accum_hash: Option::<E::Fr>,
     pub old_account_root: Option::<E::Fr>,
     pub new_account_root: Option::<E::Fr>,
 }
 
-impl<'a, E> Circuit<E> for DepositBatchCircuit<'a, E>
+impl<E> DepositBatchCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
-    fn synthesize<CS: ConstraintSystem<E>> (
-        self,
+    fn process_batch<CS: ConstraintSystem<E>> (
+        &self,
         cs: &mut CS,
+        public_inputs: AllocatedPublicInputs<E>,
+        accumulator: DepositAccumulator,
     ) -> Result<(), SynthesisError> {
-        assert_eq!(self.deposit_batch, self.deposit_queue.len());
-
-        let mut prev_hash = AllocatedNum::alloc(
-            cs.namespace(|| "allocate old accum hash"),
-            || self.old_accum_hash.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-        prev_hash.inputize(cs.namespace(|| "input old accum hash"))?;
-
-        let new_hash = AllocatedNum::alloc(
-            cs.namespace(|| "allocate new accum hash"),
-            || self.new_accum_hash.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-        new_hash.inputize(cs.namespace(|| "input new accum hash"))?;
+        if let DepositQueue::Witnesses(deposits) = &self.deposit_queue {
+            if self.deposit_batch != deposits.len() {
+                return Err(SynthesisError::Unsatisfiable);
+            }
+        }
 
-        let mut prev_root = AllocatedNum::alloc(
-            cs.namespace(|| "allocate old root"),
-            || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-        prev_root.inputize(cs.namespace(|| "input old root"))?;
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
 
-        let new_root = AllocatedNum::alloc(
-            cs.namespace(|| "allocate new root"),
-            || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-        new_root.inputize(cs.namespace(|| "input new root"))?;
+        for i in 0..self.deposit_batch {
+            // a produced witness is dropped once its constraints are in
+            let produced;
+            let deposit = match &self.deposit_queue {
+                DepositQueue::Witnesses(deposits) => &deposits[i],
+                DepositQueue::Lazy(source) => {
+                    produced = (source.lock().unwrap())(i);
+                    &produced
+                },
+            };
 
-        for (i, deposit) in self.deposit_queue.iter().enumerate() {
-            let (hash, root) = deposit.process_deposit(
+            let (hash, root) = deposit.process_deposit::<_, Poseidon>(
                 cs.namespace(|| format!("verify deposit {}", i)),
                 self.account_depth,
-                self.hash_params,
+                self.token_depth,
+                &self.params.hash_params,
+                &self.params.sign_params,
+                &self.params.hash_params,
                 &prev_hash,
                 &prev_root,
+                accumulator,
+                self.reject_zero_amount,
+                self.leaf_version,
             )?;
 
             prev_hash = hash;
@@ -229,3 +791,366 @@ impl<'a, E> Circuit<E> for DepositBatchCircuit<'a, E>
         Ok(())
     }
 }
+
+impl<E> Circuit<E> for DepositBatchCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        )?;
+
+        self.process_batch(cs, public_inputs, DepositAccumulator::Poseidon)
+    }
+}
+
+// the same batch with the accum hashes of DepositAccumulator::Sha256, deposits
+// are chained with pubdata::accumulate_pubdata and noops are skipped
+#[derive(Clone)]
+pub struct Sha256DepositBatchCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub batch: DepositBatchCircuit<E>,
+}
+
+impl<E> Circuit<E> for Sha256DepositBatchCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.batch.old_accum_hash,
+            self.batch.new_accum_hash,
+            self.batch.old_account_root,
+            self.batch.new_account_root,
+        )?;
+
+        self.batch.process_batch(cs, public_inputs, DepositAccumulator::Sha256)
+    }
+}
+
+// the same batch with the accum hashes of DepositAccumulator::Sponge, deposits
+// are chained with OffchainDeposit::sponge_hash
+#[derive(Clone)]
+pub struct SpongeDepositBatchCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub batch: DepositBatchCircuit<E>,
+}
+
+impl<E> Circuit<E> for SpongeDepositBatchCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.batch.old_accum_hash,
+            self.batch.new_accum_hash,
+            self.batch.old_account_root,
+            self.batch.new_account_root,
+        )?;
+
+        self.batch.process_batch(cs, public_inputs, DepositAccumulator::Sponge)
+    }
+}
+
+// the same batch with a single public input, the commitment of the four values
+#[derive(Clone)]
+pub struct CommittedDepositBatchCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub batch: DepositBatchCircuit<E>,
+}
+
+impl<E> Circuit<E> for CommittedDepositBatchCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        let public_inputs = alloc_committed_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            &self.batch.params.hash_params,
+            self.batch.old_accum_hash,
+            self.batch.new_accum_hash,
+            self.batch.old_account_root,
+            self.batch.new_account_root,
+        )?;
+
+        self.batch.process_batch(cs, public_inputs, DepositAccumulator::Poseidon)
+    }
+}
+
+impl DepositBatchCircuit<Bn256> {
+    // every deposit validated in order from the old root and accum hash, then
+    // the last one has to end at the new ones. a lazy queue produces its
+    // witnesses only while the batch is synthesized and is not validated
+    pub fn validate_witness(&self) -> Result<(), WitnessError> {
+        let deposits = match &self.deposit_queue {
+            DepositQueue::Witnesses(deposits) => deposits,
+            DepositQueue::Lazy(_) => return Ok(()),
+        };
+        if deposits.len() != self.deposit_batch {
+            return Err(WitnessError::WrongLength {
+                slot: None, field: "deposit queue", expected: self.deposit_batch, actual: deposits.len(),
+            });
+        }
+
+        let batch_values = [
+            ("old_accum_hash", self.old_accum_hash),
+            ("new_accum_hash", self.new_accum_hash),
+            ("old_account_root", self.old_account_root),
+            ("new_account_root", self.new_account_root),
+        ];
+        if let Some((field, _)) = batch_values.iter().find(|(_, value)| value.is_none()) {
+            return Err(WitnessError::MissingField { slot: None, field });
+        }
+
+        let mut hash = self.old_accum_hash.unwrap();
+        let mut root = self.old_account_root.unwrap();
+        for (slot, deposit) in deposits.iter().enumerate() {
+            let (new_hash, new_root) = deposit.validate_witness(
+                slot,
+                self.account_depth,
+                self.token_depth,
+                self.leaf_version,
+                hash,
+                root,
+                &self.params.hash_params,
+            )?;
+            hash = new_hash;
+            root = new_root;
+        }
+
+        if Some(root) != self.new_account_root {
+            return Err(WitnessError::NewRootMismatch);
+        }
+        if Some(hash) != self.new_accum_hash {
+            return Err(WitnessError::AccumHashMismatch);
+        }
+
+        Ok(())
+    }
+
+    pub fn builder(config: BatchConfig, params: &Arc<Params<Bn256>>) -> DepositBatchBuilder {
+        DepositBatchBuilder {
+            config,
+            params: Arc::clone(params),
+            deposits: Vec::with_capacity(config.deposit_batch),
+            old_accum_hash: None,
+            new_accum_hash: None,
+            old_account_root: None,
+            new_account_root: None,
+            reject_zero_amount: false,
+        }
+    }
+
+    // the circuit parameters are generated from, every length follows from
+    // the config so the key fits any batch filled for it
+    pub fn for_setup(config: BatchConfig, params: &Arc<Params<Bn256>>) -> Self {
+        DepositBatchCircuit {
+            leaf_version: config.leaf_version,
+            ..Self::empty(config.deposit_batch, config.account_depth, config.token_depth, params)
+        }
+    }
+
+    // batch without witness, the shape is the same as of any filled batch
+    pub fn empty(
+        deposit_batch: usize,
+        account_depth: usize,
+        token_depth: usize,
+        params: &Arc<Params<Bn256>>,
+    ) -> Self {
+        let deposit = DepositCircuit::<Bn256>::empty(account_depth, token_depth);
+
+        DepositBatchCircuit {
+            deposit_batch,
+            account_depth,
+            token_depth,
+            params: Arc::clone(params),
+            reject_zero_amount: false,
+            leaf_version: LeafVersion::V0,
+            deposit_queue: vec![deposit; deposit_batch].into(),
+            old_accum_hash: None,
+            new_accum_hash: None,
+            old_account_root: None,
+            new_account_root: None,
+        }
+    }
+
+    // every deposit adds the same number of constraints on top of the fixed batch
+    // part, so measuring batches of one and two deposits is enough for any batch
+    pub fn estimated_constraints(
+        deposit_batch: usize,
+        account_depth: usize,
+        token_depth: usize,
+        params: &Arc<Params<Bn256>>,
+    ) -> Result<usize, SynthesisError> {
+        let single = measure(
+            Self::empty(1, account_depth, token_depth, params)
+        )?.constraints;
+
+        let double = measure(
+            Self::empty(2, account_depth, token_depth, params)
+        )?.constraints;
+
+        let per_deposit = double - single;
+        let fixed = single - per_deposit;
+
+        Ok(fixed + per_deposit * deposit_batch)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    // deposit is the index in the batch for a field of a deposit witness
+    MissingField { deposit: Option<usize>, field: &'static str },
+    WrongLength { deposit: Option<usize>, field: &'static str, expected: usize, actual: usize },
+}
+
+impl Error for BuildError {}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            BuildError::MissingField { deposit: None, field } => write!(f, "{} is not set", field),
+            BuildError::MissingField { deposit: Some(i), field } => write!(
+                f, "{} of deposit {} is not set", field, i),
+            BuildError::WrongLength { deposit: None, field, expected, actual } => write!(
+                f, "{} length is {}, expected {}", field, actual, expected),
+            BuildError::WrongLength { deposit: Some(i), field, expected, actual } => write!(
+                f, "{} length of deposit {} is {}, expected {}", field, i, actual, expected),
+        }
+    }
+}
+
+// a filled batch for a config, build() checks every value synthesis would
+// otherwise only miss with AssignmentMissing somewhere inside a deposit
+pub struct DepositBatchBuilder {
+    config: BatchConfig,
+    params: Arc<Params<Bn256>>,
+    deposits: Vec::<DepositCircuit<Bn256>>,
+    old_accum_hash: Option::<bn256::Fr>,
+    new_accum_hash: Option::<bn256::Fr>,
+    old_account_root: Option::<bn256::Fr>,
+    new_account_root: Option::<bn256::Fr>,
+    reject_zero_amount: bool,
+}
+
+impl DepositBatchBuilder {
+    pub fn old_accum_hash(mut self, hash: bn256::Fr) -> Self {
+        self.old_accum_hash = Some(hash);
+        self
+    }
+
+    pub fn new_accum_hash(mut self, hash: bn256::Fr) -> Self {
+        self.new_accum_hash = Some(hash);
+        self
+    }
+
+    pub fn old_account_root(mut self, root: bn256::Fr) -> Self {
+        self.old_account_root = Some(root);
+        self
+    }
+
+    pub fn new_account_root(mut self, root: bn256::Fr) -> Self {
+        self.new_account_root = Some(root);
+        self
+    }
+
+    pub fn public_inputs(self, public_inputs: &PublicInputs<Bn256>) -> Self {
+        self.old_accum_hash(public_inputs.old_accum_hash)
+            .new_accum_hash(public_inputs.new_accum_hash)
+            .old_account_root(public_inputs.old_account_root)
+            .new_account_root(public_inputs.new_account_root)
+    }
+
+    // a different layout than without, see DepositBatchCircuit
+    pub fn reject_zero_amount(mut self, reject: bool) -> Self {
+        self.reject_zero_amount = reject;
+        self
+    }
+
+    pub fn push(mut self, deposit: DepositCircuit<Bn256>) -> Self {
+        self.deposits.push(deposit);
+        self
+    }
+
+    // fills the rest of the batch with noop deposits
+    pub fn pad_with_noops(mut self) -> Self {
+        let missing = self.config.deposit_batch.saturating_sub(self.deposits.len());
+        for _ in 0..missing {
+            self.deposits.push(DepositCircuit::noop(self.config.account_depth, self.config.token_depth));
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<DepositBatchCircuit<Bn256>, BuildError> {
+        let config = self.config;
+
+        let public_inputs = [
+            ("old_accum_hash", self.old_accum_hash),
+            ("new_accum_hash", self.new_accum_hash),
+            ("old_account_root", self.old_account_root),
+            ("new_account_root", self.new_account_root),
+        ];
+        if let Some((field, _)) = public_inputs.iter().find(|(_, value)| value.is_none()) {
+            return Err(BuildError::MissingField { deposit: None, field });
+        }
+
+        if self.deposits.len() != config.deposit_batch {
+            return Err(BuildError::WrongLength {
+                deposit: None,
+                field: "deposit_queue",
+                expected: config.deposit_batch,
+                actual: self.deposits.len(),
+            });
+        }
+
+        for (i, deposit) in self.deposits.iter().enumerate() {
+            let state = &deposit.account_state;
+            let lengths = [
+                ("account_path", config.account_depth, state.account_path.len()),
+                ("account_indices", config.account_depth, state.account_indices.len()),
+                ("token_path", config.token_depth, state.token_path.len()),
+                ("token_indices", config.token_depth, state.token_indices.len()),
+            ];
+            for (field, expected, actual) in lengths.iter() {
+                if expected != actual {
+                    return Err(BuildError::WrongLength {
+                        deposit: Some(i),
+                        field,
+                        expected: *expected,
+                        actual: *actual,
+                    });
+                }
+            }
+
+            if let Some(field) = deposit.missing_field() {
+                return Err(BuildError::MissingField { deposit: Some(i), field });
+            }
+        }
+
+        Ok(DepositBatchCircuit {
+            deposit_batch: config.deposit_batch,
+            account_depth: config.account_depth,
+            token_depth: config.token_depth,
+            params: self.params,
+            reject_zero_amount: self.reject_zero_amount,
+            leaf_version: config.leaf_version,
+            deposit_queue: self.deposits.into(),
+            old_accum_hash: self.old_accum_hash,
+            new_accum_hash: self.new_accum_hash,
+            old_account_root: self.old_account_root,
+            new_account_root: self.new_account_root,
+        })
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    fmt,
+    io,
+    error::Error,
+};
+
+use bellman_ce::SynthesisError;
+
+use crate::tree::account::TreeError;
+use crate::tree::leaf::LeafError;
+use crate::data_structs::offchain_withdrawal::SignatureError;
+use crate::data_structs::encoding::EncodingError;
+use crate::utils::utils::ConversionError;
+use crate::types::RangeError;
+use crate::block::BlockError;
+
+// the error of the public api of data_structs, tree, utils, prover and block.
+// the modules keep their own enums for the ways they fail, this wraps them so
+// a service handles one type; panics are left for broken invariants only
+#[derive(Debug)]
+pub enum OpenPlasmaError {
+    // the state refused the operation or a snapshot doesn't hold
+    Tree(TreeError),
+    Signature(SignatureError),
+    // a value that doesn't fit the type it is read as
+    Conversion(ConversionError),
+    // the bytes of an operation don't decode
+    Encoding(EncodingError),
+    // the block builder refused the operation
+    Block(BlockError),
+    // synthesis, proving or verification
+    Circuit(SynthesisError),
+    // files, and keys or proofs that don't parse
+    Io(io::Error),
+}
+
+impl Error for OpenPlasmaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OpenPlasmaError::Tree(err) => Some(err),
+            OpenPlasmaError::Signature(err) => Some(err),
+            OpenPlasmaError::Conversion(err) => Some(err),
+            OpenPlasmaError::Encoding(err) => Some(err),
+            OpenPlasmaError::Block(err) => Some(err),
+            OpenPlasmaError::Circuit(err) => Some(err),
+            OpenPlasmaError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl fmt::Display for OpenPlasmaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            OpenPlasmaError::Tree(err) => write!(f, "Tree error: {}", err),
+            OpenPlasmaError::Signature(err) => write!(f, "Signature error: {}", err),
+            OpenPlasmaError::Conversion(err) => write!(f, "Conversion error: {}", err),
+            OpenPlasmaError::Encoding(err) => write!(f, "Encoding error: {}", err),
+            OpenPlasmaError::Block(err) => write!(f, "Block error: {}", err),
+            OpenPlasmaError::Circuit(err) => write!(f, "Circuit error: {}", err),
+            OpenPlasmaError::Io(err) => write!(f, "Io error: {}", err),
+        }
+    }
+}
+
+// neither io::Error nor SynthesisError compare, they do by kind and by
+// message, which is what a caller can tell apart anyway
+impl PartialEq for OpenPlasmaError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (OpenPlasmaError::Tree(a), OpenPlasmaError::Tree(b)) => a == b,
+            (OpenPlasmaError::Signature(a), OpenPlasmaError::Signature(b)) => a == b,
+            (OpenPlasmaError::Conversion(a), OpenPlasmaError::Conversion(b)) => a == b,
+            (OpenPlasmaError::Encoding(a), OpenPlasmaError::Encoding(b)) => a == b,
+            (OpenPlasmaError::Block(a), OpenPlasmaError::Block(b)) => a == b,
+            (OpenPlasmaError::Circuit(a), OpenPlasmaError::Circuit(b)) => a.to_string() == b.to_string(),
+            (OpenPlasmaError::Io(a), OpenPlasmaError::Io(b)) => a.kind() == b.kind(),
+            _ => false,
+        }
+    }
+}
+
+impl From<TreeError> for OpenPlasmaError {
+    fn from(err: TreeError) -> Self {
+        OpenPlasmaError::Tree(err)
+    }
+}
+
+impl From<LeafError> for OpenPlasmaError {
+    fn from(err: LeafError) -> Self {
+        OpenPlasmaError::Tree(TreeError::InvalidLeaf(err))
+    }
+}
+
+impl From<RangeError> for OpenPlasmaError {
+    fn from(err: RangeError) -> Self {
+        OpenPlasmaError::Tree(TreeError::OutOfRange(err))
+    }
+}
+
+impl From<SignatureError> for OpenPlasmaError {
+    fn from(err: SignatureError) -> Self {
+        OpenPlasmaError::Signature(err)
+    }
+}
+
+impl From<ConversionError> for OpenPlasmaError {
+    fn from(err: ConversionError) -> Self {
+        OpenPlasmaError::Conversion(err)
+    }
+}
+
+impl From<EncodingError> for OpenPlasmaError {
+    fn from(err: EncodingError) -> Self {
+        OpenPlasmaError::Encoding(err)
+    }
+}
+
+impl From<BlockError> for OpenPlasmaError {
+    fn from(err: BlockError) -> Self {
+        OpenPlasmaError::Block(err)
+    }
+}
+
+// an io error of bellman, reading parameters, is an io error here too
+impl From<SynthesisError> for OpenPlasmaError {
+    fn from(err: SynthesisError) -> Self {
+        match err {
+            SynthesisError::IoError(err) => OpenPlasmaError::Io(err),
+            err => OpenPlasmaError::Circuit(err),
+        }
+    }
+}
+
+impl From<io::Error> for OpenPlasmaError {
+    fn from(err: io::Error) -> Self {
+        OpenPlasmaError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for OpenPlasmaError {
+    fn from(err: bincode::Error) -> Self {
+        OpenPlasmaError::Io(io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    error::Error,
+};
+
+use bellman_ce::{
+    SynthesisError,
+    groth16::{ Parameters, create_random_proof },
+};
+
+use sapling_crypto_ce::{
+    poseidon::bn256::Bn256PoseidonParams,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use ff_ce::{ PrimeField, PrimeFieldRepr };
+
+use rand::thread_rng;
+
+use super::{
+    exit_circuit::ExitCircuit,
+    prover::{ proof_to_eth_bytes, PROOF_ETH_BYTES },
+    tree::account::{ AccountsTree, TreeError },
+    tree::proof::BalanceProof,
+    tree::snapshot::{ AccountSnapshot, StateSnapshot },
+    types::AccountId,
+    error::OpenPlasmaError,
+    metrics::metrics,
+};
+
+#[derive(Debug)]
+pub enum ExitError {
+    RootMismatch { expected: bn256::Fr, actual: bn256::Fr },
+    EmptyAccount(usize),
+    // the tree or the prover failed underneath
+    PlasmaError(OpenPlasmaError),
+}
+
+impl Error for ExitError {}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ExitError::RootMismatch { expected, actual } => write!(
+                f, "Snapshot root is {}, expected {}", actual, expected),
+            ExitError::EmptyAccount(id) => write!(f, "Account {} is empty, there is nothing to exit", id),
+            ExitError::PlasmaError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<OpenPlasmaError> for ExitError {
+    fn from(err: OpenPlasmaError) -> Self {
+        ExitError::PlasmaError(err)
+    }
+}
+
+impl From<TreeError> for ExitError {
+    fn from(err: TreeError) -> Self {
+        ExitError::PlasmaError(err.into())
+    }
+}
+
+impl From<SynthesisError> for ExitError {
+    fn from(err: SynthesisError) -> Self {
+        ExitError::PlasmaError(err.into())
+    }
+}
+
+// everything a user submits to exit once the operator is gone: the account
+// leaf, the proof of the balance under the root, the witness of the exit
+// circuit and, when proving keys were given, the Groth16 proof as
+// verifyProof takes it
+pub struct ExitPackage<'a> {
+    pub account: AccountSnapshot,
+    pub proof: BalanceProof,
+    pub circuit: ExitCircuit<'a, Bn256>,
+    // 32 bytes big endian per input, in the order of ExitCircuit::public_inputs
+    pub public_inputs: Vec::<u8>,
+    pub groth16_proof: Option<[u8; PROOF_ETH_BYTES]>,
+}
+
+// expected_root is the root the contract froze, checked before the snapshot
+// is rebuilt. proving_params are the parameters of the exit circuit at the
+// depths of the snapshot
+pub fn generate_exit<'a>(
+    snapshot: &StateSnapshot,
+    expected_root: bn256::Fr,
+    account_id: AccountId,
+    token_id: usize,
+    proving_params: Option<&Parameters<Bn256>>,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> Result<ExitPackage<'a>, ExitError> {
+    if snapshot.root != expected_root {
+        return Err(ExitError::RootMismatch { expected: expected_root, actual: snapshot.root });
+    }
+
+    let account_id = account_id.index();
+    if account_id >= 1 << snapshot.account_depth {
+        return Err(TreeError::AccountOutOfRange(account_id).into());
+    }
+    if token_id >= 1 << snapshot.token_depth {
+        return Err(TreeError::TokenOutOfRange(token_id).into());
+    }
+    let account = snapshot.accounts.iter()
+        .find(|account| account.account_id == account_id)
+        .ok_or(ExitError::EmptyAccount(account_id))?
+        .clone();
+
+    // the root is recomputed from the accounts, so the witness is under it
+    let tree = AccountsTree::from_snapshot(snapshot, hash_params, sign_params)?;
+    let circuit = tree.exit_witness(account_id, token_id)?;
+    let proof = tree.prove_balance(account_id, token_id)?;
+
+    let mut public_inputs = Vec::new();
+    for input in circuit.public_inputs().unwrap() {
+        input.into_repr().write_be(&mut public_inputs).unwrap();
+    }
+
+    let groth16_proof = match proving_params {
+        Some(params) => {
+            let proof = create_random_proof(circuit.clone(), params, &mut thread_rng())?;
+            metrics().proof_generated();
+            Some(proof_to_eth_bytes(&proof))
+        },
+        None => None,
+    };
+
+    Ok(ExitPackage {
+        account,
+        proof,
+        circuit,
+        public_inputs,
+        groth16_proof,
+    })
+}
@@ -0,0 +1,183 @@
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::JubjubEngine,
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        num::AllocatedNum,
+        ecc::EdwardsPoint,
+    },
+};
+
+use super::utils::alloc::{ alloc_nums, alloc_bits };
+use super::utils::tree::{ calc_root, verify, check_witness_length };
+use super::utils::calc::check_decomposition_le;
+use super::utils::point::pack_point_gadget;
+
+// exodus mode: proves the account owned the balance under a committed root,
+// nothing is updated. Public inputs are root, account id, token id, pubkey x,
+// pubkey y and balance, the token id is needed to know what to pay out
+#[derive(Clone)]
+pub struct ExitCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+
+    pub root: Option::<E::Fr>,
+    pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
+    pub pubkey_x: Option::<E::Fr>,
+    pub pubkey_y: Option::<E::Fr>,
+    pub balance: Option::<E::Fr>,
+
+    pub nonce: Option::<E::Fr>,
+    pub account_path: Vec::<Option<E::Fr>>,
+    pub account_indices: Vec::<Option<bool>>,
+    pub token_path: Vec::<Option<E::Fr>>,
+    pub token_indices: Vec::<Option<bool>>,
+}
+
+impl<'a, E: JubjubEngine + PoseidonEngine> ExitCircuit<'a, E> {
+    // in the order of inputize in synthesize
+    pub fn public_inputs(&self) -> Option<Vec::<E::Fr>> {
+        Some(vec![
+            self.root?,
+            self.account_id?,
+            self.token_id?,
+            self.pubkey_x?,
+            self.pubkey_y?,
+            self.balance?,
+        ])
+    }
+}
+
+impl<'a, E> Circuit<E> for ExitCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        check_witness_length("account path", self.account_depth, self.account_path.len())?;
+        check_witness_length("account indices", self.account_depth, self.account_indices.len())?;
+        check_witness_length("token path", self.token_depth, self.token_path.len())?;
+        check_witness_length("token indices", self.token_depth, self.token_indices.len())?;
+
+        // allocate public inputs -------------------------------------------------------
+
+        let inputs = alloc_nums(
+            cs.namespace(|| "allocate public inputs"),
+            &[
+                self.root,
+                self.account_id,
+                self.token_id,
+                self.pubkey_x,
+                self.pubkey_y,
+                self.balance,
+            ],
+        )?;
+
+        for (i, input) in inputs.iter().enumerate() {
+            input.inputize(cs.namespace(|| format!("input {}", i)))?;
+        }
+
+        let root = &inputs[0];
+        let account_id = &inputs[1];
+        let token_id = &inputs[2];
+        let pubkey_x = &inputs[3];
+        let pubkey_y = &inputs[4];
+        let balance = &inputs[5];
+
+        // allocate witness -------------------------------------------------------------
+
+        let nonce = AllocatedNum::alloc(
+            cs.namespace(|| "allocate nonce"),
+            || self.nonce.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let account_path = alloc_nums(
+            cs.namespace(|| "allocate account path"),
+            &self.account_path,
+        )?;
+
+        let account_indices = alloc_bits(
+            cs.namespace(|| "allocate account indices"),
+            &self.account_indices,
+        )?;
+
+        let token_path = alloc_nums(
+            cs.namespace(|| "allocate token path"),
+            &self.token_path,
+        )?;
+
+        let token_indices = alloc_bits(
+            cs.namespace(|| "allocate token indices"),
+            &self.token_indices,
+        )?;
+
+        // check ids against the path indices -------------------------------------------
+
+        check_decomposition_le(
+            cs.namespace(|| "account id consistence"),
+            account_id,
+            &account_indices,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            token_id,
+            &token_indices,
+        )?;
+
+        // verify membership, no new root is calculated ---------------------------------
+
+        let balance_leaf = [balance.clone()];
+
+        let balances_root = calc_root(
+            cs.namespace(|| "calculate balances root"),
+            self.hash_params,
+            &balance_leaf,
+            &token_path,
+            &token_indices,
+        )?;
+
+        // the leaf holds the pubkey packed, the public one has to be a curve point
+        // for the packed value to tell which
+
+        let pubkey = EdwardsPoint::interpret(
+            cs.namespace(|| "interpret pubkey"),
+            pubkey_x,
+            pubkey_y,
+            self.sign_params,
+        )?;
+
+        let packed_pubkey = pack_point_gadget(
+            cs.namespace(|| "pack pubkey"),
+            pubkey.get_x(),
+            pubkey.get_y(),
+        )?;
+
+        let account_leaf = [
+            packed_pubkey,
+            nonce,
+            balances_root,
+        ];
+
+        verify(
+            cs.namespace(|| "verify account root"),
+            self.hash_params,
+            &account_leaf,
+            &account_path,
+            &account_indices,
+            root,
+        )
+    }
+}
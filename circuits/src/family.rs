@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    io::{ self, Read, Write },
+    sync::Arc,
+};
+
+use bellman_ce::{
+    SynthesisError,
+    groth16::{
+        Parameters,
+        generate_random_parameters,
+    },
+};
+
+use pairing_ce::bn256::Bn256;
+
+use rand::Rng;
+
+use super::deposit_circuit::{ DepositCircuit, DepositBatchCircuit };
+use super::public_inputs::PublicInputs;
+use super::stats::{ CircuitShape, shape };
+use super::layout::CircuitLayout;
+use super::utils::tree::check_witness_length;
+use super::params::Params;
+use super::tree::leaf::LeafVersion;
+
+// everything that changes the constraint layout of a deposit batch, circuits
+// with the same config share groth16 parameters
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BatchConfig {
+    pub deposit_batch: usize,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    // the account leaf layout of the tree the batch proves against
+    pub leaf_version: LeafVersion,
+}
+
+// the only place that instantiates deposit batch circuits for a config, so
+// parameter generation and proving always see the same layout
+pub struct CircuitFamily {
+    params: Arc<Params<Bn256>>,
+    shapes: HashMap<BatchConfig, CircuitShape>,
+}
+
+impl CircuitFamily {
+    pub fn new(params: Arc<Params<Bn256>>) -> Self {
+        CircuitFamily {
+            params,
+            shapes: HashMap::new(),
+        }
+    }
+
+    // synthesizes the empty circuit once per config and remembers its shape
+    pub fn register(&mut self, config: BatchConfig) -> Result<CircuitShape, SynthesisError> {
+        if let Some(shape) = self.shapes.get(&config) {
+            return Ok(*shape);
+        }
+
+        let circuit_shape = shape(self.empty_circuit(config))?;
+        self.shapes.insert(config, circuit_shape);
+
+        Ok(circuit_shape)
+    }
+
+    pub fn shape(&self, config: &BatchConfig) -> Option<CircuitShape> {
+        self.shapes.get(config).cloned()
+    }
+
+    pub fn empty_circuit(&self, config: BatchConfig) -> DepositBatchCircuit<Bn256> {
+        DepositBatchCircuit::for_setup(config, &self.params)
+    }
+
+    // rejects a witness that does not fit the config instead of synthesizing
+    // a circuit of another shape
+    pub fn circuit(
+        &self,
+        config: BatchConfig,
+        deposit_queue: Vec::<DepositCircuit<Bn256>>,
+        public_inputs: &PublicInputs<Bn256>,
+    ) -> Result<DepositBatchCircuit<Bn256>, SynthesisError> {
+        check_witness_length("deposit queue", config.deposit_batch, deposit_queue.len())?;
+
+        for deposit in deposit_queue.iter() {
+            let state = &deposit.account_state;
+            check_witness_length("account path", config.account_depth, state.account_path.len())?;
+            check_witness_length("account indices", config.account_depth, state.account_indices.len())?;
+            check_witness_length("token path", config.token_depth, state.token_path.len())?;
+            check_witness_length("token indices", config.token_depth, state.token_indices.len())?;
+        }
+
+        Ok(DepositBatchCircuit {
+            deposit_batch: config.deposit_batch,
+            account_depth: config.account_depth,
+            token_depth: config.token_depth,
+            params: Arc::clone(&self.params),
+            reject_zero_amount: false,
+            leaf_version: config.leaf_version,
+            deposit_queue: deposit_queue.into(),
+            old_accum_hash: Some(public_inputs.old_accum_hash),
+            new_accum_hash: Some(public_inputs.new_accum_hash),
+            old_account_root: Some(public_inputs.old_account_root),
+            new_account_root: Some(public_inputs.new_account_root),
+        })
+    }
+
+    // the public inputs and constraint ranges of the config's circuit
+    pub fn layout(&self, config: BatchConfig) -> Result<CircuitLayout, SynthesisError> {
+        CircuitLayout::of(self.empty_circuit(config))
+    }
+
+    pub fn generate_parameters<R: Rng>(
+        &mut self,
+        config: BatchConfig,
+        rng: &mut R,
+    ) -> Result<Parameters<Bn256>, SynthesisError> {
+        self.register(config)?;
+        generate_random_parameters(self.empty_circuit(config), rng)
+    }
+
+    // groth16 parameters do not keep the constraints themselves, but their
+    // sizes follow from the shape: one ic point per input, one l point per aux
+    // variable and a power of two domain over constraints and inputs
+    pub fn check_parameters(
+        &mut self,
+        config: BatchConfig,
+        params: &Parameters<Bn256>,
+    ) -> Result<(), SynthesisError> {
+        let stats = self.register(config)?.stats;
+        let domain = (stats.constraints + stats.input_variables).next_power_of_two();
+
+        if params.vk.ic.len() != stats.input_variables
+            || params.l.len() != stats.aux_variables
+            || params.h.len() != domain - 1
+        {
+            return Err(SynthesisError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("parameters do not match {:?}: {}", config, stats),
+            )));
+        }
+
+        Ok(())
+    }
+
+    // parameters prefixed with the shape hash, big endian
+    pub fn write_parameters<W: Write>(
+        &mut self,
+        config: BatchConfig,
+        params: &Parameters<Bn256>,
+        mut writer: W,
+    ) -> Result<(), SynthesisError> {
+        self.check_parameters(config, params)?;
+
+        let hash = self.register(config)?.hash;
+        writer.write_all(&hash.to_be_bytes())?;
+        params.write(writer)?;
+
+        Ok(())
+    }
+
+    pub fn read_parameters<R: Read>(
+        &mut self,
+        config: BatchConfig,
+        mut reader: R,
+        checked: bool,
+    ) -> Result<Parameters<Bn256>, SynthesisError> {
+        let expected = self.register(config)?.hash;
+
+        let mut hash = [0u8; 8];
+        reader.read_exact(&mut hash)?;
+        let hash = u64::from_be_bytes(hash);
+
+        if hash != expected {
+            return Err(SynthesisError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("shape hash is {:016x}, expected {:016x} for {:?}", hash, expected, config),
+            )));
+        }
+
+        let params = Parameters::read(reader, checked)?;
+        self.check_parameters(config, &params)?;
+
+        Ok(params)
+    }
+}
@@ -0,0 +1,213 @@
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::JubjubEngine,
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        poseidon_hash::poseidon_hash,
+        num::AllocatedNum,
+    },
+};
+
+use super::account::{ AccountState, AccountCircuit, LEAF_PUBKEY, LEAF_NONCE };
+use super::public_inputs::alloc_public_inputs;
+use super::utils::op_type::{ alloc_op_type, FULL_EXIT_OP };
+use super::utils::calc::check_decomposition_le;
+
+// exit is authorized on L1, so there is no signature: the whole token balance
+// of the account is withdrawn, the amount is taken from the old balance leaf
+#[derive(Clone)]
+pub struct FullExitCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub account_state: AccountState<E>,
+    pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
+}
+
+impl<E> FullExitCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    pub fn process_full_exit<CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &<E as PoseidonEngine>::Params,
+        old_hash: &AllocatedNum<E>,
+        old_root: &AllocatedNum<E>,
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
+
+        // allocate avariables ----------------------------------------------------------
+
+        let account_circuit = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit"),
+            account_depth,
+            token_depth,
+            hash_params,
+            &self.account_state,
+        )?;
+
+        let account_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate account id"),
+            || self.account_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let old_leaf = &account_circuit.accounts_tree.old_leaf_alloc;
+        let new_leaf = &account_circuit.accounts_tree.new_leaf_alloc;
+        let old_balance = &account_circuit.balances_tree.old_leaf_alloc[0];
+        let new_balance = &account_circuit.balances_tree.new_leaf_alloc[0];
+
+        // check changes validity -------------------------------------------------------
+
+        // check account id, token id consistency
+
+        check_decomposition_le(
+            cs.namespace(|| "account id consistence"),
+            &account_id_alloc,
+            &account_circuit.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
+        // pubkey and nonce are not changed
+
+        for (name, i) in [("pubkey", LEAF_PUBKEY), ("nonce", LEAF_NONCE)].iter() {
+            cs.enforce(
+                || format!("enforce {} is not changed", name),
+                |lc| lc + old_leaf[*i].get_variable(),
+                |lc| lc + CS::one(),
+                |lc| lc + new_leaf[*i].get_variable(),
+            );
+        }
+
+        // whole balance is withdrawn, for an empty account it is just 0
+
+        cs.enforce(
+            || "enforce new balance is zero",
+            |lc| lc + new_balance.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc,
+        );
+
+        let op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate op type"),
+            FULL_EXIT_OP,
+        )?;
+
+        // calculate new hash -----------------------------------------------------------
+
+        let new_hash = {
+            let hashes_vec = poseidon_hash(
+                cs.namespace(|| "calculate new accum hash"),
+                &[
+                    op_type_alloc.clone(),
+                    old_hash.clone(),
+                    account_id_alloc,
+                    token_id_alloc,
+                    old_leaf[LEAF_PUBKEY].clone(),
+                    old_balance.clone(),
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
+
+        // verify old root & calculate new root -----------------------------------------
+
+        account_circuit.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify old root"),
+            old_root,
+        )?;
+
+        let new_root = account_circuit.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate new root"),
+        )?;
+
+        Ok((new_hash, new_root))
+    }
+}
+
+#[derive(Clone)]
+pub struct FullExitBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub batch_size: usize,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+
+    pub queue: Vec::<FullExitCircuit<E>>,
+    pub old_accum_hash: Option::<E::Fr>,
+    pub new_accum_hash: Option::<E::Fr>,
+    pub old_account_root: Option::<E::Fr>,
+    pub new_account_root: Option::<E::Fr>,
+}
+
+impl<'a, E> Circuit<E> for FullExitBatchCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        if self.batch_size != self.queue.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        )?;
+
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
+
+        for (i, exit) in self.queue.iter().enumerate() {
+            let (hash, root) = exit.process_full_exit(
+                cs.namespace(|| format!("verify full exit {}", i)),
+                self.account_depth,
+                self.token_depth,
+                self.hash_params,
+                &prev_hash,
+                &prev_root,
+            )?;
+
+            prev_hash = hash;
+            prev_root = root;
+        }
+
+        cs.enforce(
+            || "enforce new accum hash equivalence",
+            |lc| lc + prev_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_hash.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new root equivalence",
+            |lc| lc + prev_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_root.get_variable(),
+        );
+
+        Ok(())
+    }
+}
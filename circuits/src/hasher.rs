@@ -0,0 +1,77 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+        poseidon_hash,
+    },
+    circuit::{
+        num::AllocatedNum,
+        poseidon_hash::poseidon_hash as poseidon_hash_gadget,
+    },
+};
+
+use pairing_ce::{ Engine, bn256::{ self, Bn256 } };
+
+use super::rescue::{ Bn256RescueParams, rescue_hash, rescue_hash_gadget };
+
+// the hash of tree nodes and leaves and of the records the circuits absorb,
+// the same value in and out of the circuit. Poseidon is the deployed one, so
+// every tree and circuit generic over a hasher defaults to it. hashers are
+// markers, the state is in the params
+pub trait TreeHasher<E: Engine>: Copy {
+    type Params;
+
+    fn hash(params: &Self::Params, input: &[E::Fr]) -> E::Fr;
+
+    fn hash_circuit<CS: ConstraintSystem<E>>(
+        cs: CS,
+        params: &Self::Params,
+        input: &[AllocatedNum<E>],
+    ) -> Result<AllocatedNum<E>, SynthesisError>;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Poseidon;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rescue;
+
+impl<E> TreeHasher<E> for Poseidon
+    where E: PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    type Params = <E as PoseidonEngine>::Params;
+
+    fn hash(params: &Self::Params, input: &[E::Fr]) -> E::Fr {
+        poseidon_hash::<E>(params, input)[0]
+    }
+
+    fn hash_circuit<CS: ConstraintSystem<E>>(
+        cs: CS,
+        params: &Self::Params,
+        input: &[AllocatedNum<E>],
+    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        Ok(poseidon_hash_gadget(cs, input, params)?[0].clone())
+    }
+}
+
+// see rescue, only over bn256
+impl TreeHasher<Bn256> for Rescue {
+    type Params = Bn256RescueParams;
+
+    fn hash(params: &Self::Params, input: &[bn256::Fr]) -> bn256::Fr {
+        rescue_hash(params, input)
+    }
+
+    fn hash_circuit<CS: ConstraintSystem<Bn256>>(
+        cs: CS,
+        params: &Self::Params,
+        input: &[AllocatedNum<Bn256>],
+    ) -> Result<AllocatedNum<Bn256>, SynthesisError> {
+        rescue_hash_gadget(cs, params, input)
+    }
+}
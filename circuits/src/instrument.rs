@@ -0,0 +1,57 @@
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+// spans around the slow parts, with the tracing feature: block_builder.seal,
+// witness.deposit and witness.withdrawal for every operation, tree.apply_batch,
+// prover.prove and prover.verify. a span records the sizes it is opened with
+// and duration_us when it is dropped, any tracing subscriber picks them up.
+// without the feature timed_span! is a unit value and the sizes are not kept
+#[cfg(feature = "tracing")]
+pub struct TimedSpan {
+    span: tracing::span::EnteredSpan,
+    start: Instant,
+}
+
+#[cfg(feature = "tracing")]
+impl TimedSpan {
+    // the span needs a duration_us field, timed_span! declares it
+    pub fn enter(span: tracing::Span) -> Self {
+        TimedSpan {
+            span: span.entered(),
+            start: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for TimedSpan {
+    fn drop(&mut self) {
+        self.span.record("duration_us", self.start.elapsed().as_micros() as u64);
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub struct TimedSpan;
+
+// timed_span!("tree.apply_batch", updates = updates.len()), the guard has to
+// be bound to a name to stay open: let _span = timed_span!(..)
+#[cfg(feature = "tracing")]
+macro_rules! timed_span {
+    ($name:expr $(, $field:ident = $value:expr)* $(,)?) => {
+        $crate::instrument::TimedSpan::enter(tracing::info_span!(
+            $name,
+            $( $field = $value as u64, )*
+            duration_us = tracing::field::Empty
+        ))
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! timed_span {
+    ($name:expr $(, $field:ident = $value:expr)* $(,)?) => {{
+        $( let _ = &$value; )*
+        $crate::instrument::TimedSpan
+    }};
+}
+
+pub(crate) use timed_span;
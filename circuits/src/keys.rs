@@ -0,0 +1,156 @@
+use std::{
+    num::ParseIntError,
+    ptr,
+    sync::atomic::{ self, Ordering },
+};
+
+use zeroize::{ Zeroize, ZeroizeOnDrop };
+
+use sapling_crypto_ce::{
+    eddsa::{ PrivateKey, PublicKey },
+    poseidon::bn256::Bn256PoseidonParams,
+    jubjub::{ FixedGenerators, JubjubEngine },
+    alt_babyjubjub::{ AltJubjubBn256, fs::Fs },
+    util::hash_to_scalar,
+};
+
+use ff_ce::Field;
+
+use pairing_ce::bn256::Bn256;
+
+use crate::data_structs::offchain_withdrawal::OffchainWithdrawal;
+use crate::params::{ poseidon_params, jubjub_params };
+use crate::types::{ AccountId, Balance, Nonce };
+use crate::utils::domain::{ SigningDomain, ADDRESS_BYTES };
+
+pub const ETH_SIGNATURE_BYTES: usize = 65;
+
+const KEY_DERIVATION_PERSONALIZATION: &[u8; 16] = b"OpenPlasmaKeyGen";
+const SEED_PERSONALIZATION: &[u8; 16] = b"OpenPlasmaSeedSk";
+const PADDING_SEED: &[u8] = b"OpenPlasma padding";
+
+// owns the signing key and wipes it on drop. there is no Debug and no Clone,
+// a copy has to be asked for with expose_private_key. Fs is Copy, so the
+// scalar arithmetic inside sapling still leaves copies this can't reach
+pub struct SecretKey(PrivateKey::<Bn256>);
+
+impl SecretKey {
+    // BLAKE2b-512 of the seed personalized with "OpenPlasmaSeedSk", reduced
+    // mod the subgroup order like derive_private_key
+    pub fn from_seed(seed: &[u8]) -> Self {
+        SecretKey(PrivateKey(hash_to_scalar::<Bn256>(SEED_PERSONALIZATION, seed, &[])))
+    }
+
+    pub fn public_key(&self, sign_params: &AltJubjubBn256) -> PublicKey::<Bn256> {
+        PublicKey::from_private(&self.0, FixedGenerators::SpendingKeyGenerator, sign_params)
+    }
+
+    // deterministic, see OffchainWithdrawal::sign_deterministic
+    pub fn sign_withdrawal(
+        &self,
+        withdrawal: &mut OffchainWithdrawal,
+        domain: &SigningDomain,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) {
+        withdrawal.sign_deterministic(self, domain, Some(hash_params), Some(sign_params));
+    }
+
+    // for the operations that don't take a SecretKey yet
+    pub fn expose_private_key(&self) -> &PrivateKey::<Bn256> {
+        &self.0
+    }
+}
+
+impl From<PrivateKey::<Bn256>> for SecretKey {
+    fn from(seckey: PrivateKey::<Bn256>) -> Self {
+        SecretKey(seckey)
+    }
+}
+
+impl Zeroize for SecretKey {
+    fn zeroize(&mut self) {
+        // SAFETY: the pointer comes from a unique reference to an initialized Fs,
+        // the write is volatile so it isn't dropped as a dead store
+        unsafe { ptr::write_volatile(&mut (self.0).0, Fs::zero()) };
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SecretKey {}
+
+// the key is BLAKE2b-512 of the signature r || s || v, personalized with
+// "OpenPlasmaKeyGen", read little endian and reduced mod the jubjub scalar
+// field order. 512 bits reduce without a noticeable bias. wallets report v as
+// 27/28 or 0/1, it is normalized to 27/28 first so both give the same key.
+// the vectors in tests/key_derivation_vectors.json pin the derivation
+pub fn derive_private_key(eth_signature_bytes: &[u8; ETH_SIGNATURE_BYTES]) -> SecretKey {
+    // the signature is as secret as the key it derives
+    let mut signature = *eth_signature_bytes;
+    if signature[ETH_SIGNATURE_BYTES - 1] < 27 {
+        signature[ETH_SIGNATURE_BYTES - 1] += 27;
+    }
+
+    let seckey = SecretKey(PrivateKey(hash_to_scalar::<Bn256>(KEY_DERIVATION_PERSONALIZATION, &signature, &[])));
+    signature.zeroize();
+    seckey
+}
+
+// the key noop withdrawals are signed with, see OffchainWithdrawalCircuit::noop.
+// everyone can derive it: a noop is bound to its pubkey and changes no
+// account, a signature of it authorizes nothing
+pub fn padding_secret_key() -> SecretKey {
+    SecretKey::from_seed(PADDING_SEED)
+}
+
+// the pubkey of padding_secret_key for any engine, the circuits compare the
+// signer of a noop with it as a constant
+pub fn padding_pubkey<E: JubjubEngine>(sign_params: &E::Params) -> PublicKey::<E> {
+    let seckey = PrivateKey::<E>(hash_to_scalar::<E>(SEED_PERSONALIZATION, PADDING_SEED, &[]));
+    PublicKey::from_private(&seckey, FixedGenerators::SpendingKeyGenerator, sign_params)
+}
+
+pub fn derive_keypair(
+    eth_signature_bytes: &[u8; ETH_SIGNATURE_BYTES],
+    sign_params: &AltJubjubBn256,
+) -> (SecretKey, PublicKey::<Bn256>) {
+    let seckey = derive_private_key(eth_signature_bytes);
+    let pubkey = seckey.public_key(sign_params);
+    (seckey, pubkey)
+}
+
+// what a browser wallet needs to sign, without rng or borrowed params:
+// amounts are decimal strings as in the json, the result is the signed
+// request the operator accepts. it is not a wasm export: the crate doesn't
+// build for wasm32 and has no wasm-bindgen wrapper
+#[allow(clippy::too_many_arguments)]
+pub fn sign_withdrawal_json(
+    seed: &[u8],
+    account_id: u32,
+    token_id: usize,
+    amount: &str,
+    fee: &str,
+    nonce: u32,
+    eth_address: &[u8; ADDRESS_BYTES],
+    domain: &SigningDomain,
+) -> Result<String, ParseIntError> {
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(account_id),
+        token_id,
+        amount: Balance(amount.parse()?),
+        fee: Balance(fee.parse()?),
+        nonce: Nonce(nonce),
+        valid_until: 0,
+        eth_address: *eth_address,
+        sign: None,
+    };
+    SecretKey::from_seed(seed).sign_withdrawal(&mut withdrawal, domain, poseidon_params(), jubjub_params());
+
+    Ok(serde_json::to_string(&withdrawal).expect("the request serializes to json"))
+}
@@ -0,0 +1,289 @@
+use std::{
+    fmt,
+    error::Error,
+    collections::{ VecDeque, BTreeSet },
+};
+
+use serde::{ Serialize, Deserialize };
+
+use sapling_crypto_ce::{
+    eddsa::PublicKey,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::bn256::Bn256;
+
+use super::{
+    data_structs::offchain_deposit::OffchainDeposit,
+    data_structs::offchain_withdrawal::is_canonical_point,
+    data_structs::encoding::POINT_BYTES,
+    types::{ AccountId, Balance, MIN_AMOUNT },
+};
+
+const WORD_BYTES: usize = 32;
+
+// the data of the deposit event, six abi words: uint64 serialId, uint32
+// accountId, uint32 tokenId, bytes32 pubkey, uint128 amount, uint64 ethBlock.
+// the pubkey is compressed as PublicKey::write, y little endian with the sign
+// of x in the top bit. Plasma.sol doesn't emit it yet, this is the layout the
+// operator reads once it does
+pub const DEPOSIT_EVENT_BYTES: usize = 6 * WORD_BYTES;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    WrongLength { expected: usize, actual: usize },
+    ValueOutOfRange(&'static str),
+    InvalidPubkey,
+}
+
+impl Error for DecodeError {}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            DecodeError::WrongLength { expected, actual } => write!(
+                f, "Event data is {} bytes, expected {}", actual, expected),
+            DecodeError::ValueOutOfRange(field) => write!(f, "{} doesn't fit its type", field),
+            DecodeError::InvalidPubkey => write!(f, "Pubkey is not a point of the prime order subgroup"),
+        }
+    }
+}
+
+// a deposit the contract recorded, the pubkey is kept as the event has it so
+// a malformed one is still an op of the queue, rejected by into_deposit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriorityOp {
+    pub serial_id: u64,
+    pub account_id: AccountId,
+    pub token_id: usize,
+    pub pubkey_bytes: [u8; POINT_BYTES],
+    pub amount: Balance,
+    pub eth_block: u64,
+}
+
+// the low bytes of a big endian word, the rest has to be zero
+fn word_uint(word: &[u8], bytes: usize, field: &'static str) -> Result<u128, DecodeError> {
+    let (high, low) = word.split_at(WORD_BYTES - bytes);
+    if high.iter().any(|byte| *byte != 0) {
+        return Err(DecodeError::ValueOutOfRange(field));
+    }
+    Ok(low.iter().fold(0, |value, byte| (value << 8) | u128::from(*byte)))
+}
+
+pub fn decode_deposit_event(log_data: &[u8]) -> Result<PriorityOp, DecodeError> {
+    if log_data.len() != DEPOSIT_EVENT_BYTES {
+        return Err(DecodeError::WrongLength { expected: DEPOSIT_EVENT_BYTES, actual: log_data.len() });
+    }
+
+    let words: Vec<_> = log_data.chunks(WORD_BYTES).collect();
+    let mut pubkey_bytes = [0u8; POINT_BYTES];
+    pubkey_bytes.copy_from_slice(words[3]);
+
+    Ok(PriorityOp {
+        serial_id: word_uint(words[0], 8, "serial id")? as u64,
+        account_id: AccountId(word_uint(words[1], 4, "account id")? as u32),
+        token_id: word_uint(words[2], 4, "token id")? as usize,
+        pubkey_bytes,
+        amount: Balance(word_uint(words[4], 16, "amount")?),
+        eth_block: word_uint(words[5], 8, "eth block")? as u64,
+    })
+}
+
+impl PriorityOp {
+    // the event data decode_deposit_event reads
+    pub fn encode_event(&self) -> Vec::<u8> {
+        let word = |value: u128| {
+            let mut word = [0u8; WORD_BYTES];
+            word[WORD_BYTES - 16..].copy_from_slice(&value.to_be_bytes());
+            word
+        };
+
+        let mut data = Vec::with_capacity(DEPOSIT_EVENT_BYTES);
+        data.extend_from_slice(&word(u128::from(self.serial_id)));
+        data.extend_from_slice(&word(u128::from(self.account_id.0)));
+        data.extend_from_slice(&word(self.token_id as u128));
+        data.extend_from_slice(&self.pubkey_bytes);
+        data.extend_from_slice(&word(self.amount.0));
+        data.extend_from_slice(&word(u128::from(self.eth_block)));
+        data
+    }
+
+    // the pubkey has to decompress to a point of the prime order subgroup, as
+    // for any deposit the operator accepts
+    pub fn into_deposit(&self, sign_params: &AltJubjubBn256) -> Result<OffchainDeposit, DecodeError> {
+        let pubkey = PublicKey::<Bn256>::read(&self.pubkey_bytes[..], sign_params)
+            .map_err(|_| DecodeError::InvalidPubkey)?;
+        if !is_canonical_point(&pubkey.0, sign_params) {
+            return Err(DecodeError::InvalidPubkey);
+        }
+
+        Ok(OffchainDeposit {
+            account_id: self.account_id,
+            pubkey,
+            token_id: self.token_id,
+            amount: self.amount,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PriorityQueueError {
+    // already queued or executed, e.g. an event seen twice
+    Duplicate(u64),
+    Gap { expected: u64, actual: u64 },
+    NotEnoughOps { requested: usize, queued: usize },
+    // the op is still queued, as a refund
+    BelowMinimum { serial_id: u64, amount: Balance, minimum: Balance },
+}
+
+impl Error for PriorityQueueError {}
+
+impl fmt::Display for PriorityQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            PriorityQueueError::Duplicate(serial_id) => write!(f, "Priority op {} is already queued", serial_id),
+            PriorityQueueError::Gap { expected, actual } => write!(
+                f, "Priority op {} arrived before op {}", actual, expected),
+            PriorityQueueError::NotEnoughOps { requested, queued } => write!(
+                f, "{} priority ops requested, {} queued", requested, queued),
+            PriorityQueueError::BelowMinimum { serial_id, amount, minimum } => write!(
+                f, "Priority op {} has amount {} below the minimum {}, it is refunded", serial_id, amount, minimum),
+        }
+    }
+}
+
+// serial ids of the priority ops applied to the tree, kept in its snapshot
+// so an operator restarting mid-block doesn't apply a re-ingested deposit twice
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProcessedOps {
+    serial_ids: BTreeSet::<u64>,
+}
+
+impl ProcessedOps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.serial_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.serial_ids.is_empty()
+    }
+
+    pub fn contains(&self, serial_id: u64) -> bool {
+        self.serial_ids.contains(&serial_id)
+    }
+
+    // false if the op was already processed
+    pub fn insert(&mut self, serial_id: u64) -> bool {
+        self.serial_ids.insert(serial_id)
+    }
+
+    pub fn remove(&mut self, serial_id: u64) -> bool {
+        self.serial_ids.remove(&serial_id)
+    }
+
+    // ascending serial ids
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.serial_ids.iter().copied()
+    }
+
+    // the op after the last processed one, 0 if there is none
+    pub fn next_serial_id(&self) -> u64 {
+        self.serial_ids.iter().next_back().map_or(0, |last| last + 1)
+    }
+}
+
+// an op of the queue, to apply or to refund, see BlockBuilder::push_queued_op
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueuedOp {
+    Deposit(PriorityOp),
+    Refund(PriorityOp),
+}
+
+impl QueuedOp {
+    pub fn op(&self) -> &PriorityOp {
+        match self {
+            QueuedOp::Deposit(op) | QueuedOp::Refund(op) => op,
+        }
+    }
+}
+
+// the ops of the contract in serial id order, with no op missing: the accum
+// hash of a block has to cover them exactly as the contract chained them.
+// a dust op is rejected as a deposit but keeps its place as a refund
+pub struct PriorityQueue {
+    next_serial_id: u64,
+    min_amount: Balance,
+    ops: VecDeque::<QueuedOp>,
+}
+
+impl PriorityQueue {
+    // next_serial_id is the first op not executed yet, 0 on a new contract
+    pub fn new(next_serial_id: u64) -> Self {
+        PriorityQueue {
+            next_serial_id,
+            min_amount: MIN_AMOUNT,
+            ops: VecDeque::new(),
+        }
+    }
+
+    // types::MIN_AMOUNT unless set, the contract is expected to refuse
+    // deposits below the same minimum
+    pub fn with_min_amount(mut self, min_amount: Balance) -> Self {
+        self.min_amount = min_amount;
+        self
+    }
+
+    // after a restart, the contract's events are ingested again from the
+    // first op the tree didn't process
+    pub fn resume(processed: &ProcessedOps) -> Self {
+        Self::new(processed.next_serial_id())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn push(&mut self, op: PriorityOp) -> Result<(), PriorityQueueError> {
+        if op.serial_id < self.next_serial_id {
+            return Err(PriorityQueueError::Duplicate(op.serial_id));
+        }
+        if op.serial_id > self.next_serial_id {
+            return Err(PriorityQueueError::Gap { expected: self.next_serial_id, actual: op.serial_id });
+        }
+
+        // a dust op the contract recorded anyway is in its chain, it is
+        // reported and queued to be refunded so the ops after it still
+        // arrive in order and the block's accum hash covers it
+        self.next_serial_id += 1;
+        if op.amount < self.min_amount {
+            let err = PriorityQueueError::BelowMinimum {
+                serial_id: op.serial_id,
+                amount: op.amount,
+                minimum: self.min_amount,
+            };
+            self.ops.push_back(QueuedOp::Refund(op));
+            return Err(err);
+        }
+        self.ops.push_back(QueuedOp::Deposit(op));
+
+        Ok(())
+    }
+
+    // the first n ops or none of them
+    pub fn drain(&mut self, n: usize) -> Result<Vec::<QueuedOp>, PriorityQueueError> {
+        if n > self.ops.len() {
+            return Err(PriorityQueueError::NotEnoughOps { requested: n, queued: self.ops.len() });
+        }
+
+        Ok(self.ops.drain(..n).collect())
+    }
+}
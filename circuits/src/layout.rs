@@ -0,0 +1,175 @@
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+    Index,
+    LinearCombination,
+    Variable,
+};
+
+use pairing_ce::bn256::{ self, Bn256 };
+
+use serde::{ Serialize, Deserialize };
+
+use crate::public_inputs::{ PublicInputs, InputSemantic };
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicInputLayout {
+    // position in the input vector of verify_proof, the constant one not counted
+    pub index: usize,
+    // namespaces of the inputize call joined with /
+    pub name: String,
+    pub semantic: InputSemantic,
+}
+
+// constraints [start, end) of a top level namespace, or of a single
+// constraint enforced outside of any
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceRange {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+// which public input is which and where the constraints come from, recorded
+// by synthesizing the circuit without witness. the ranges are in synthesis
+// order, namespaces without constraints are left out
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitLayout {
+    pub inputs: Vec::<PublicInputLayout>,
+    pub namespaces: Vec::<NamespaceRange>,
+    pub constraints: usize,
+}
+
+impl CircuitLayout {
+    pub fn of<C: Circuit<Bn256>>(circuit: C) -> Result<Self, SynthesisError> {
+        let mut cs = LayoutConstraintSystem::new();
+        circuit.synthesize(&mut cs)?;
+
+        Ok(cs.layout)
+    }
+
+    pub fn index_of(&self, semantic: InputSemantic) -> Option<usize> {
+        self.inputs.iter().find(|input| input.semantic == semantic).map(|input| input.index)
+    }
+
+    // the input vector in this layout's order, picked from the public inputs
+    // by meaning. None if the layout has an input they don't hold
+    pub fn input_vector(&self, public_inputs: &PublicInputs<Bn256>) -> Option<Vec::<bn256::Fr>> {
+        self.inputs.iter().map(|input| public_inputs.get(input.semantic)).collect()
+    }
+}
+
+// keeps the namespace stack and never calls the assignment closures, like
+// stats::CountingConstraintSystem
+struct LayoutConstraintSystem {
+    layout: CircuitLayout,
+    aux_variables: usize,
+    namespaces: Vec::<String>,
+    // the constraint count when the current top level namespace was entered
+    namespace_start: usize,
+}
+
+impl LayoutConstraintSystem {
+    fn new() -> Self {
+        LayoutConstraintSystem {
+            layout: CircuitLayout {
+                inputs: Vec::new(),
+                namespaces: Vec::new(),
+                constraints: 0,
+            },
+            aux_variables: 0,
+            namespaces: Vec::new(),
+            namespace_start: 0,
+        }
+    }
+
+    fn push_range(&mut self, name: String, start: usize) {
+        if self.layout.constraints > start {
+            self.layout.namespaces.push(NamespaceRange {
+                name,
+                start,
+                end: self.layout.constraints,
+            });
+        }
+    }
+}
+
+impl ConstraintSystem<Bn256> for LayoutConstraintSystem {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(
+        &mut self,
+        _: A,
+        _: F,
+    ) -> Result<Variable, SynthesisError>
+        where F: FnOnce() -> Result<bn256::Fr, SynthesisError>,
+              A: FnOnce() -> AR, AR: Into<String>,
+    {
+        let index = self.aux_variables;
+        self.aux_variables += 1;
+
+        Ok(Variable::new_unchecked(Index::Aux(index)))
+    }
+
+    fn alloc_input<F, A, AR>(
+        &mut self,
+        _: A,
+        _: F,
+    ) -> Result<Variable, SynthesisError>
+        where F: FnOnce() -> Result<bn256::Fr, SynthesisError>,
+              A: FnOnce() -> AR, AR: Into<String>,
+    {
+        let index = self.layout.inputs.len();
+        let semantic = InputSemantic::from_input_name(self.namespaces.last().map_or("", String::as_str));
+
+        self.layout.inputs.push(PublicInputLayout {
+            index,
+            name: self.namespaces.join("/"),
+            semantic,
+        });
+
+        // the constant one is input 0
+        Ok(Variable::new_unchecked(Index::Input(index + 1)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(
+        &mut self,
+        annotation: A,
+        _: LA,
+        _: LB,
+        _: LC,
+    )
+        where A: FnOnce() -> AR, AR: Into<String>,
+              LA: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+              LB: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+              LC: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+    {
+        let start = self.layout.constraints;
+        self.layout.constraints += 1;
+
+        if self.namespaces.is_empty() {
+            self.push_range(annotation().into(), start);
+        }
+    }
+
+    fn push_namespace<NR, N>(&mut self, name_fn: N)
+        where NR: Into<String>, N: FnOnce() -> NR,
+    {
+        if self.namespaces.is_empty() {
+            self.namespace_start = self.layout.constraints;
+        }
+        self.namespaces.push(name_fn().into());
+    }
+
+    fn pop_namespace(&mut self) {
+        let name = self.namespaces.pop().expect("a namespace to pop");
+        if self.namespaces.is_empty() {
+            self.push_range(name, self.namespace_start);
+        }
+    }
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
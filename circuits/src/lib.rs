@@ -1,9 +1,50 @@
 pub mod deposit_circuit;
 pub mod onchain_withdrawal_circuit;
 pub mod offchain_withdrawal_circuit;
+pub mod delegated_withdrawal_circuit;
 pub mod utils;
+pub mod circuit;
 pub mod account;
 pub mod operator;
 pub mod data_structs;
 pub mod tree;
 pub mod transfer_circuit;
+pub mod conditional_transfer_circuit;
+pub mod swap_circuit;
+pub mod change_pubkey_circuit;
+pub mod full_exit_circuit;
+pub mod exit_circuit;
+pub mod block_circuit;
+pub mod stats;
+pub mod layout;
+pub mod family;
+pub mod public_inputs;
+pub mod close_account_circuit;
+pub mod types;
+pub mod error;
+pub mod keys;
+pub mod musig;
+pub mod params;
+pub mod prover;
+pub mod proof_batch;
+pub mod aggregation;
+#[cfg(feature = "plonk")]
+pub mod plonk;
+pub mod pubdata;
+pub mod rescue;
+pub mod sponge;
+pub mod hasher;
+pub mod block;
+pub mod mempool;
+pub mod limits;
+pub mod receipt;
+pub mod l1;
+pub mod replay;
+pub mod audit;
+pub mod exit;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(test, feature = "testing"))]
+pub mod scenario;
+pub mod instrument;
+pub mod metrics;
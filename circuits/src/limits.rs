@@ -0,0 +1,89 @@
+use std::{
+    fmt,
+    error::Error,
+};
+
+use serde::{ Serialize, Deserialize };
+
+use super::types::Balance;
+
+// risk limits of an operator, checked off-circuit by the mempool and the
+// block builder against the state after the operation. None is no limit,
+// the default has none
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    // amount of a single withdrawal
+    pub max_withdrawal: Option::<Balance>,
+    // balance of an account in any token after a deposit
+    pub max_balance: Option::<Balance>,
+    // sum of the amounts of the operations of one block
+    pub max_block_value: Option::<Balance>,
+}
+
+// the limit an operation would break, with the value it would have reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitViolation {
+    MaxWithdrawal { account_id: usize, amount: Balance, limit: Balance },
+    MaxBalance { account_id: usize, token_id: usize, balance: Balance, limit: Balance },
+    MaxBlockValue { value: Balance, limit: Balance },
+}
+
+impl Error for LimitViolation {}
+
+impl fmt::Display for LimitViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            LimitViolation::MaxWithdrawal { account_id, amount, limit } => write!(
+                f, "Withdrawal of {} from account {} is above the limit {}", amount, account_id, limit),
+            LimitViolation::MaxBalance { account_id, token_id, balance, limit } => write!(
+                f, "Balance {} of account {} in token {} would be above the limit {}", balance, account_id, token_id, limit),
+            LimitViolation::MaxBlockValue { value, limit } => write!(
+                f, "Block value {} would be above the limit {}", value, limit),
+        }
+    }
+}
+
+impl Limits {
+    pub fn with_max_withdrawal(mut self, max_withdrawal: Balance) -> Self {
+        self.max_withdrawal = Some(max_withdrawal);
+        self
+    }
+
+    pub fn with_max_balance(mut self, max_balance: Balance) -> Self {
+        self.max_balance = Some(max_balance);
+        self
+    }
+
+    pub fn with_max_block_value(mut self, max_block_value: Balance) -> Self {
+        self.max_block_value = Some(max_block_value);
+        self
+    }
+
+    pub fn check_withdrawal(&self, account_id: usize, amount: Balance) -> Result<(), LimitViolation> {
+        match self.max_withdrawal {
+            Some(limit) if amount > limit => Err(LimitViolation::MaxWithdrawal { account_id, amount, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    // the balance after the operation
+    pub fn check_balance(&self, account_id: usize, token_id: usize, balance: Balance) -> Result<(), LimitViolation> {
+        match self.max_balance {
+            Some(limit) if balance > limit => Err(LimitViolation::MaxBalance { account_id, token_id, balance, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    // the value of the block with the operation
+    pub fn check_block_value(&self, value: Balance) -> Result<(), LimitViolation> {
+        match self.max_block_value {
+            Some(limit) if value > limit => Err(LimitViolation::MaxBlockValue { value, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+// sums of amounts saturate, a saturated sum is over any limit below u128::MAX
+pub fn add_value(value: Balance, amount: Balance) -> Balance {
+    Balance(value.0.saturating_add(amount.0))
+}
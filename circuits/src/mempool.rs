@@ -0,0 +1,310 @@
+use std::{
+    fmt,
+    error::Error,
+    sync::Mutex,
+    mem,
+    collections::{ BTreeMap, BTreeSet, HashMap },
+};
+
+use sapling_crypto_ce::eddsa::PublicKey;
+
+use pairing_ce::bn256::Bn256;
+
+use super::{
+    data_structs::offchain_withdrawal::OffchainWithdrawal,
+    error::OpenPlasmaError,
+    block::BlockError,
+    keys::SecretKey,
+    receipt::Receipt,
+    tree::account::AccountsTree,
+    types::{ AccountId, Balance, Nonce, MIN_AMOUNT },
+    utils::domain::SigningDomain,
+    metrics::metrics,
+    limits::{ Limits, LimitViolation, add_value },
+};
+
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    InvalidSignature(OpenPlasmaError),
+    StaleNonce { account_id: usize, nonce: u32 },
+    Duplicate { account_id: usize, nonce: u32 },
+    BelowMinimum { account_id: usize, amount: Balance, minimum: Balance },
+    // the request alone breaks a limit, no block could take it
+    LimitExceeded(LimitViolation),
+    // a batch without room for every request promised the block
+    BatchTooSmall { requested: usize, promised: usize },
+}
+
+impl Error for MempoolError {}
+
+impl From<LimitViolation> for MempoolError {
+    fn from(err: LimitViolation) -> Self {
+        MempoolError::LimitExceeded(err)
+    }
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            MempoolError::InvalidSignature(e) => write!(f, "{}", e),
+            MempoolError::StaleNonce { account_id, nonce } => write!(
+                f, "Nonce {} of account {} is already used", nonce, account_id),
+            MempoolError::Duplicate { account_id, nonce } => write!(
+                f, "Account {} already has a pending request with nonce {}", account_id, nonce),
+            MempoolError::BelowMinimum { account_id, amount, minimum } => write!(
+                f, "Amount {} of account {} is below the minimum {}", amount, account_id, minimum),
+            MempoolError::LimitExceeded(e) => write!(f, "Limit exceeded: {}", e),
+            MempoolError::BatchTooSmall { requested, promised } => write!(
+                f, "Batch of {} requests has no room for the {} promised ones", requested, promised),
+        }
+    }
+}
+
+// a receipt promises the request by the next block unless set
+pub const DEFAULT_INCLUSION_BLOCKS: usize = 1;
+
+#[derive(Default)]
+struct Pending {
+    // queued requests of every account by nonce, gaps wait for the missing nonce
+    accounts: BTreeMap::<usize, BTreeMap::<Nonce, OffchainWithdrawal>>,
+    // the last nonce of every account handed out by the last take_batch, not
+    // in the tree until its block is applied
+    taken: HashMap::<usize, Nonce>,
+    // the balances by account and token the last take_batch left
+    taken_balances: HashMap::<(usize, usize), Balance>,
+    // the requests promised a block since the last take_batch, and the sum
+    // of their amounts
+    promised: BTreeSet::<(usize, Nonce)>,
+    promised_value: Balance,
+    // the number of the block the next take_batch is for
+    next_block: usize,
+}
+
+// signed withdrawals waiting for a block, shared between the threads that
+// receive them and the one building blocks. requests are taken in nonce
+// order per account, one account after another. every accepted request
+// gets a Receipt signed with the operator's key, with a block only if the
+// next batch can execute it
+pub struct Mempool {
+    signing_domain: SigningDomain,
+    operator_key: SecretKey,
+    min_amount: Balance,
+    limits: Limits,
+    inclusion_blocks: usize,
+    pending: Mutex<Pending>,
+}
+
+impl Mempool {
+    pub fn new(signing_domain: SigningDomain, operator_key: SecretKey) -> Self {
+        Mempool {
+            signing_domain,
+            operator_key,
+            min_amount: MIN_AMOUNT,
+            limits: Limits::default(),
+            inclusion_blocks: DEFAULT_INCLUSION_BLOCKS,
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    // types::MIN_AMOUNT unless set, zero accepts every amount
+    pub fn with_min_amount(mut self, min_amount: Balance) -> Self {
+        self.min_amount = min_amount;
+        self
+    }
+
+    // no limits unless set, max_balance is for deposits and not checked here
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // the blocks a receipt gives the request, counted from the next one
+    pub fn with_inclusion_blocks(mut self, inclusion_blocks: usize) -> Result<Self, OpenPlasmaError> {
+        if inclusion_blocks == 0 {
+            return Err(BlockError::NoInclusionBlocks.into());
+        }
+        self.inclusion_blocks = inclusion_blocks;
+        Ok(self)
+    }
+
+    // the block the next take_batch is for, the receipts of the requests
+    // inserted after it promise a block counted from it. zero unless set,
+    // every take_batch moves it to the next block
+    pub fn set_next_block(&self, block_number: usize) {
+        self.pending.lock().unwrap().next_block = block_number;
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().accounts.values().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // pubkey, account_nonce and account_balance, in the token of the request,
+    // are the account's in the tree the caller reads. the nonce has to be
+    // above it and above what is already taken. the amount is checked first,
+    // a dust request or one above the limits costs no signature check. the
+    // receipt is signed once the request is queued. it promises a block only
+    // to the next nonce of the account, covered by its balance after the
+    // requests taken already, and within max_block_value together with the
+    // requests promised before it; any other request is accepted without a
+    // block
+    pub fn insert(
+        &self,
+        withdrawal: OffchainWithdrawal,
+        pubkey: &PublicKey::<Bn256>,
+        account_nonce: Nonce,
+        account_balance: Balance,
+    ) -> Result<Receipt, MempoolError> {
+        let account_id = withdrawal.account_id.index();
+        if withdrawal.amount < self.min_amount {
+            return Err(MempoolError::BelowMinimum {
+                account_id,
+                amount: withdrawal.amount,
+                minimum: self.min_amount,
+            });
+        }
+        self.limits.check_withdrawal(account_id, withdrawal.amount)?;
+        self.limits.check_block_value(withdrawal.amount)?;
+
+        withdrawal.verify_signature(pubkey, &self.signing_domain, None, None).map_err(|e| {
+            metrics().signature_rejected();
+            MempoolError::InvalidSignature(e)
+        })?;
+
+        let nonce = withdrawal.nonce;
+        let account = withdrawal.account_id;
+        let request_hash = withdrawal.hash(&self.signing_domain, None);
+        let debit = withdrawal.amount.checked_add(withdrawal.fee);
+        let amount = withdrawal.amount;
+        let token_id = withdrawal.token_id;
+
+        let mut pending = self.pending.lock().unwrap();
+        let used = pending.taken.get(&account_id).map_or(account_nonce, |taken| account_nonce.max(*taken));
+        if nonce <= used {
+            return Err(MempoolError::StaleNonce { account_id, nonce: nonce.0 });
+        }
+
+        let queue = pending.accounts.entry(account_id).or_default();
+        if queue.contains_key(&nonce) {
+            return Err(MempoolError::Duplicate { account_id, nonce: nonce.0 });
+        }
+        queue.insert(nonce, withdrawal);
+
+        // the tree doesn't have the debits of the taken requests yet
+        let balance = match pending.taken_balances.get(&(account_id, token_id)) {
+            Some(balance) if used > account_nonce => *balance,
+            _ => account_balance,
+        };
+        let value = add_value(pending.promised_value, amount);
+        let executable = used.next() == Some(nonce)
+            && debit.is_some_and(|debit| debit <= balance)
+            && self.limits.check_block_value(value).is_ok();
+        let promised_block = if executable {
+            pending.promised.insert((account_id, nonce));
+            pending.promised_value = value;
+            Some(pending.next_block + self.inclusion_blocks - 1)
+        } else {
+            None
+        };
+        drop(pending);
+
+        Ok(Receipt::sign(request_hash, account, nonce, promised_block, &self.operator_key))
+    }
+
+    // at most n requests executable on the tree one after another, the
+    // batch of the next block. requests the tree already executed and
+    // expired ones are dropped, a request the balance doesn't cover or that
+    // would take the batch over max_block_value stays queued and holds back
+    // the later nonces of its account. promised requests are taken first, an
+    // n below their number would break their receipts and is refused with
+    // MempoolError::BatchTooSmall, nothing is taken and the block stays next
+    pub fn take_batch(
+        &self,
+        n: usize,
+        tree: &AccountsTree,
+        timestamp: usize,
+    ) -> Result<Vec::<OffchainWithdrawal>, MempoolError> {
+        let mut pending = self.pending.lock().unwrap();
+        if n < pending.promised.len() {
+            return Err(MempoolError::BatchTooSmall { requested: n, promised: pending.promised.len() });
+        }
+        pending.taken.clear();
+        pending.next_block += 1;
+        let promised = mem::take(&mut pending.promised);
+        pending.promised_value = Balance(0);
+
+        // the next nonce of every account with requests, accounts the tree
+        // doesn't have are dropped
+        let mut cursors = Vec::new();
+        pending.accounts.retain(|account_id, queue| {
+            let tree_nonce = match tree.nonce(AccountId(*account_id as u32)) {
+                Ok(nonce) => nonce,
+                Err(_) => return false,
+            };
+            queue.retain(|nonce, withdrawal| *nonce > tree_nonce && !withdrawal.is_expired(timestamp));
+            if let Some(next) = tree_nonce.next() {
+                cursors.push((*account_id, next));
+            }
+            !queue.is_empty()
+        });
+        cursors.sort_by_key(|cursor| !promised.contains(cursor));
+
+        // a round takes the next request of every account that has one executable
+        let mut balances = HashMap::<(usize, usize), Balance>::new();
+        let mut value = Balance(0);
+        let mut batch = Vec::new();
+        while batch.len() < n && !cursors.is_empty() {
+            let mut next_cursors = Vec::with_capacity(cursors.len());
+
+            for (account_id, nonce) in cursors {
+                if batch.len() == n {
+                    break;
+                }
+
+                let queue = match pending.accounts.get_mut(&account_id) {
+                    Some(queue) => queue,
+                    None => continue,
+                };
+                let withdrawal = match queue.get(&nonce) {
+                    Some(withdrawal) => withdrawal,
+                    None => continue,
+                };
+                let next_value = add_value(value, withdrawal.amount);
+                if self.limits.check_block_value(next_value).is_err() {
+                    continue;
+                }
+
+                let key = (account_id, withdrawal.token_id);
+                let balance = *balances.entry(key).or_insert_with(
+                    || tree.balance(withdrawal.account_id, withdrawal.token_id).unwrap_or_default()
+                );
+                let rest = match withdrawal.amount.checked_add(withdrawal.fee)
+                    .and_then(|debit| balance.checked_sub(debit))
+                {
+                    Some(rest) => rest,
+                    None => continue,
+                };
+
+                balances.insert(key, rest);
+                value = next_value;
+                batch.push(queue.remove(&nonce).unwrap());
+                if queue.is_empty() {
+                    pending.accounts.remove(&account_id);
+                }
+                pending.taken.insert(account_id, nonce);
+
+                if let Some(next) = nonce.next() {
+                    next_cursors.push((account_id, next));
+                }
+            }
+
+            cursors = next_cursors;
+        }
+        pending.taken_balances = balances;
+
+        Ok(batch)
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use serde::{ Serialize, Deserialize };
+
+// counters of the whole process for an operator to export, e.g. as
+// prometheus counters under the names of MetricsSnapshot::counters. always
+// on, an increment is one relaxed atomic add
+pub struct Metrics {
+    ops_processed: AtomicU64,
+    signatures_rejected: AtomicU64,
+    proofs_generated: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    // operations applied to the tree for a block, noops not counted
+    pub ops_processed: u64,
+    // signed requests refused by the mempool or the operator for their signature
+    pub signatures_rejected: u64,
+    pub proofs_generated: u64,
+}
+
+static METRICS: Metrics = Metrics::new();
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            ops_processed: AtomicU64::new(0),
+            signatures_rejected: AtomicU64::new(0),
+            proofs_generated: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add_ops_processed(&self, ops: usize) {
+        self.ops_processed.fetch_add(ops as u64, Ordering::Relaxed);
+    }
+
+    pub fn signature_rejected(&self) {
+        self.signatures_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn proof_generated(&self) {
+        self.proofs_generated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            ops_processed: self.ops_processed.load(Ordering::Relaxed),
+            signatures_rejected: self.signatures_rejected.load(Ordering::Relaxed),
+            proofs_generated: self.proofs_generated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    // prometheus style names, totals since the process started
+    pub fn counters(&self) -> [(&'static str, u64); 3] {
+        [
+            ("openplasma_ops_processed_total", self.ops_processed),
+            ("openplasma_signatures_rejected_total", self.signatures_rejected),
+            ("openplasma_proofs_generated_total", self.proofs_generated),
+        ]
+    }
+}
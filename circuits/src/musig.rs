@@ -0,0 +1,275 @@
+use std::{
+    fmt,
+    error::Error,
+};
+
+use sapling_crypto_ce::{
+    eddsa::{ PrivateKey, PublicKey, Signature },
+    jubjub::{
+        FixedGenerators,
+        JubjubParams,
+        ToUniform,
+        Unknown,
+        edwards::Point,
+    },
+    alt_babyjubjub::{ AltJubjubBn256, fs::Fs },
+    util::hash_to_scalar,
+};
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+use pairing_ce::bn256::Bn256;
+
+use rand::Rng;
+
+use zeroize::Zeroize;
+
+use crate::keys::SecretKey;
+use crate::data_structs::offchain_withdrawal::is_canonical_point;
+
+// the messages signed here are raw 31 byte messages as for verify_signature,
+// see OffchainWithdrawal::sign
+pub const MAX_MESSAGE_BYTES: usize = 31;
+
+const COEFFICIENT_PERSONALIZATION: &[u8; 16] = b"OpenPlasma_MuSig";
+const COMMITMENT_PERSONALIZATION: &[u8; 16] = b"OpenPlasmaMuSigR";
+const NONCE_PERSONALIZATION: &[u8; 16] = b"OpenPlasmaMuSigN";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MusigError {
+    InvalidPoint,
+    NotASigner,
+    MessageTooLong(usize),
+    WrongSignerCount { expected: usize, actual: usize },
+    CommitmentsMissing,
+    CommitmentMismatch(usize),
+    InvalidPartialSignature(usize),
+}
+
+impl Error for MusigError {}
+
+impl fmt::Display for MusigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            MusigError::InvalidPoint => write!(f, "Key or nonce is not in the prime order subgroup"),
+            MusigError::NotASigner => write!(f, "Key is not a part of the aggregate key"),
+            MusigError::MessageTooLong(len) => write!(
+                f, "Message is {} bytes, at most {} are signed", len, MAX_MESSAGE_BYTES),
+            MusigError::WrongSignerCount { expected, actual } => write!(
+                f, "Got {} values for {} signers", actual, expected),
+            MusigError::CommitmentsMissing => write!(f, "Nonce commitments are not exchanged yet"),
+            MusigError::CommitmentMismatch(index) => write!(f, "Nonce of signer {} doesn't match its commitment", index),
+            MusigError::InvalidPartialSignature(index) => write!(f, "Partial signature of signer {} is invalid", index),
+        }
+    }
+}
+
+fn point_bytes(point: &Point::<Bn256, Unknown>) -> Vec::<u8> {
+    let mut bytes = Vec::new();
+    point.write(&mut bytes).expect("writing to a vec never fails");
+    bytes
+}
+
+// c = M as in PublicKey::verify_for_raw_message
+fn challenge(message: &[u8]) -> Fs {
+    let mut padded = message.to_vec();
+    padded.resize(32, 0u8);
+    Fs::to_uniform_32(&padded)
+}
+
+fn generator_mul(scalar: Fs, sign_params: &AltJubjubBn256) -> Point::<Bn256, Unknown> {
+    sign_params.generator(FixedGenerators::SpendingKeyGenerator).mul(scalar, sign_params).into()
+}
+
+// n-of-n key of the leaf, PK = sum a_i . PK_i with a_i = H(PK_1 .. PK_n, PK_i),
+// the coefficients keep a signer from choosing its key to cancel the others
+pub struct AggregateKey {
+    pubkeys: Vec::<PublicKey::<Bn256>>,
+    coefficients: Vec::<Fs>,
+    pubkey: PublicKey::<Bn256>,
+}
+
+impl AggregateKey {
+    pub fn new(pubkeys: Vec::<PublicKey::<Bn256>>, sign_params: &AltJubjubBn256) -> Result<Self, MusigError> {
+        if pubkeys.is_empty() {
+            return Err(MusigError::WrongSignerCount { expected: 1, actual: 0 });
+        }
+        if pubkeys.iter().any(|pubkey| !is_canonical_point(&pubkey.0, sign_params)) {
+            return Err(MusigError::InvalidPoint);
+        }
+
+        let all_bytes: Vec<_> = pubkeys.iter().flat_map(|pubkey| point_bytes(&pubkey.0)).collect();
+        let coefficients: Vec<_> = pubkeys.iter().map(
+            |pubkey| hash_to_scalar::<Bn256>(COEFFICIENT_PERSONALIZATION, &all_bytes, &point_bytes(&pubkey.0))
+        ).collect();
+
+        let mut aggregate = Point::<Bn256, Unknown>::zero();
+        for (pubkey, coefficient) in pubkeys.iter().zip(coefficients.iter()) {
+            aggregate = aggregate.add(&pubkey.0.mul(*coefficient, sign_params), sign_params);
+        }
+        if !is_canonical_point(&aggregate, sign_params) {
+            return Err(MusigError::InvalidPoint);
+        }
+
+        Ok(AggregateKey {
+            pubkeys,
+            coefficients,
+            pubkey: PublicKey(aggregate),
+        })
+    }
+
+    // the key the leaf holds and verify_signature is given
+    pub fn pubkey(&self) -> &PublicKey::<Bn256> {
+        &self.pubkey
+    }
+
+    pub fn pubkeys(&self) -> &[PublicKey::<Bn256>] {
+        &self.pubkeys
+    }
+
+    fn check_count(&self, actual: usize) -> Result<(), MusigError> {
+        if actual != self.pubkeys.len() {
+            return Err(MusigError::WrongSignerCount { expected: self.pubkeys.len(), actual });
+        }
+        Ok(())
+    }
+}
+
+// round one sends the commitment, round two the nonce once every commitment
+// is in, so no signer picks its nonce after seeing the others
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NonceCommitment(pub Fs);
+
+#[derive(Clone)]
+pub struct PublicNonce(pub Point::<Bn256, Unknown>);
+
+impl PublicNonce {
+    pub fn commitment(&self) -> NonceCommitment {
+        NonceCommitment(hash_to_scalar::<Bn256>(COMMITMENT_PERSONALIZATION, &point_bytes(&self.0), &[]))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialSignature(pub Fs);
+
+// one signer of one message, the nonce is used by partial_sign which consumes
+// the session, so it can't sign twice with the same nonce
+pub struct SigningSession<'a> {
+    seckey: &'a SecretKey,
+    aggregate: &'a AggregateKey,
+    index: usize,
+    message: Vec::<u8>,
+    nonce: SecretKey,
+    public_nonce: PublicNonce,
+    commitments: Option<Vec::<NonceCommitment>>,
+}
+
+impl<'a> SigningSession<'a> {
+    pub fn new<R: Rng>(
+        seckey: &'a SecretKey,
+        aggregate: &'a AggregateKey,
+        message: &[u8],
+        sign_params: &AltJubjubBn256,
+        rng: &mut R,
+    ) -> Result<Self, MusigError> {
+        if message.len() > MAX_MESSAGE_BYTES {
+            return Err(MusigError::MessageTooLong(message.len()));
+        }
+        let pubkey = seckey.public_key(sign_params);
+        let index = aggregate.pubkeys.iter().position(
+            |signer| signer.0.eq(&pubkey.0)
+        ).ok_or(MusigError::NotASigner)?;
+
+        // the nonce depends on the key as well, a weak rng alone doesn't give it away
+        let mut entropy = Vec::new();
+        seckey.expose_private_key().0.into_repr().write_le(&mut entropy).expect("writing to a vec never fails");
+        let mut random = [0u8; 32];
+        rng.fill_bytes(&mut random);
+        entropy.extend_from_slice(&random);
+        let nonce = SecretKey::from(PrivateKey(hash_to_scalar::<Bn256>(NONCE_PERSONALIZATION, &entropy, message)));
+        entropy.zeroize();
+
+        let public_nonce = PublicNonce(generator_mul(nonce.expose_private_key().0, sign_params));
+
+        Ok(SigningSession {
+            seckey,
+            aggregate,
+            index,
+            message: message.to_vec(),
+            nonce,
+            public_nonce,
+            commitments: None,
+        })
+    }
+
+    pub fn commitment(&self) -> NonceCommitment {
+        self.public_nonce.commitment()
+    }
+
+    // all commitments in the signer order of the aggregate key, this one included
+    pub fn reveal_nonce(&mut self, commitments: Vec::<NonceCommitment>) -> Result<PublicNonce, MusigError> {
+        self.aggregate.check_count(commitments.len())?;
+        if commitments[self.index] != self.commitment() {
+            return Err(MusigError::CommitmentMismatch(self.index));
+        }
+        self.commitments = Some(commitments);
+        Ok(self.public_nonce.clone())
+    }
+
+    // s_i = r_i + c . a_i . sk_i
+    pub fn partial_sign(self, nonces: &[PublicNonce], sign_params: &AltJubjubBn256) -> Result<PartialSignature, MusigError> {
+        let commitments = self.commitments.as_ref().ok_or(MusigError::CommitmentsMissing)?;
+        self.aggregate.check_count(nonces.len())?;
+        for (index, (nonce, commitment)) in nonces.iter().zip(commitments.iter()).enumerate() {
+            if !is_canonical_point(&nonce.0, sign_params) {
+                return Err(MusigError::InvalidPoint);
+            }
+            if nonce.commitment() != *commitment {
+                return Err(MusigError::CommitmentMismatch(index));
+            }
+        }
+
+        let mut s = challenge(&self.message);
+        s.mul_assign(&self.aggregate.coefficients[self.index]);
+        s.mul_assign(&self.seckey.expose_private_key().0);
+        s.add_assign(&self.nonce.expose_private_key().0);
+
+        Ok(PartialSignature(s))
+    }
+}
+
+// R = sum R_i, S = sum s_i, checking s_i . G = R_i + c . a_i . PK_i for every
+// signer first, so a bad partial signature names its signer
+pub fn aggregate_signatures(
+    aggregate: &AggregateKey,
+    message: &[u8],
+    nonces: &[PublicNonce],
+    partials: &[PartialSignature],
+    sign_params: &AltJubjubBn256,
+) -> Result<Signature::<Bn256>, MusigError> {
+    if message.len() > MAX_MESSAGE_BYTES {
+        return Err(MusigError::MessageTooLong(message.len()));
+    }
+    aggregate.check_count(nonces.len())?;
+    aggregate.check_count(partials.len())?;
+    let c = challenge(message);
+
+    let mut r = Point::<Bn256, Unknown>::zero();
+    let mut s = Fs::zero();
+    for (index, (nonce, partial)) in nonces.iter().zip(partials.iter()).enumerate() {
+        let mut weight = c;
+        weight.mul_assign(&aggregate.coefficients[index]);
+        let expected = nonce.0.add(&aggregate.pubkeys[index].0.mul(weight, sign_params), sign_params);
+        if !generator_mul(partial.0, sign_params).eq(&expected) {
+            return Err(MusigError::InvalidPartialSignature(index));
+        }
+
+        r = r.add(&nonce.0, sign_params);
+        s.add_assign(&partial.0);
+    }
+    if !is_canonical_point(&r, sign_params) {
+        return Err(MusigError::InvalidPoint);
+    }
+
+    Ok(Signature { r, s })
+}
@@ -15,10 +15,12 @@ use sapling_crypto_ce::{
     poseidon::{
         PoseidonEngine,
         QuinticSBox,
+        bn256::Bn256PoseidonParams,
+        poseidon_hash,
     },
+    alt_babyjubjub::AltJubjubBn256,
     circuit::{
-        baby_eddsa::EddsaSignature,
-        poseidon_hash::poseidon_hash,
+        poseidon_hash::poseidon_hash as poseidon_hash_gadget,
         num::AllocatedNum,
         boolean::{ Boolean, AllocatedBit },
         ecc::EdwardsPoint,
@@ -26,44 +28,81 @@ use sapling_crypto_ce::{
     eddsa::Signature,
 };
 
-use crate::utils::{
-    sign::verify_signature,
-    calc::boolean_to_allocated_num,
-};
+use pairing_ce::bn256::{ self, Bn256 };
+
+use ff_ce::Field;
 
-use super::account::{ AccountState, AccountCircuit };
-use super::utils::calc::check_decomposition_le;
+use crate::types::{ AccountId, Balance, Nonce, BALANCE_BITS, NONCE_BITS };
+use crate::utils::sign::verify_signature;
+use crate::data_structs::offchain_withdrawal::OffchainWithdrawal;
+use crate::keys::{ padding_secret_key, padding_pubkey };
+use crate::params::jubjub_params;
+
+use super::account::{
+    AccountState,
+    AccountCircuit,
+    WitnessError,
+    indices_to_fr,
+    LEAF_PUBKEY,
+    LEAF_NONCE,
+};
+use super::utils::point::{ pack_point, unpack_point_gadget };
+use super::utils::utils::usize_to_fr;
+use super::public_inputs::{ alloc_public_inputs, alloc_total_fee_input };
+use super::utils::op_type::{ alloc_op_type, OFFCHAIN_WITHDRAWAL_OP };
+use super::utils::domain::{ SigningDomain, alloc_signing_domain, ADDRESS_BITS, ADDRESS_BYTES };
+use super::utils::calc::{
+    check_decomposition_le,
+    add,
+    enforce_bit_length,
+    enforce_less_or_equal,
+    is_zero,
+    enforce_nonzero_unless,
+};
 
 const BITS_IN_BYTE: usize = 8;
-const NUM_BYTES_TO_SIGN: usize = 31;
 
 #[derive(Clone)]
 pub struct OffchainWithdrawalCircuit<E: JubjubEngine + PoseidonEngine> {
     pub account_state: AccountState<E>,
     pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
     pub amount: Option::<E::Fr>,
+    pub fee: Option::<E::Fr>,
     pub nonce: Option::<E::Fr>,
+    pub valid_until: Option::<E::Fr>,
+    pub eth_address: Option::<E::Fr>,
     pub sign: Option::<Signature<E>>,
     pub pubkey: Option::<Point<E, Unknown>>,
+    // a padding slot, see noop
+    pub is_noop: Option::<bool>,
 }
 
 impl<E> OffchainWithdrawalCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
+    #[allow(clippy::too_many_arguments, clippy::type_complexity)]
     pub fn process<'a, CS: ConstraintSystem<E>> (
         &self,
         mut cs: CS,
         account_depth: usize,
+        token_depth: usize,
         hash_params: &'a <E as PoseidonEngine>::Params,
         sign_params: &'a <E as JubjubEngine>::Params,
+        signing_domain: &[AllocatedNum<E>; 2],
+        fee_token_id: &AllocatedNum<E>,
+        timestamp: &AllocatedNum<E>,
+        old_hash: &AllocatedNum<E>,
         old_root: &AllocatedNum<E>,
-    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        reject_zero_amount: bool,
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
         
         // allocate avariables ----------------------------------------------------------
         
         let account_circuit = AccountCircuit::new(
             cs.namespace(|| "allocate account circuit"),
             account_depth,
+            token_depth,
             hash_params,
             &self.account_state,
         )?;
@@ -72,28 +111,82 @@ impl<E> OffchainWithdrawalCircuit<E>
             cs.namespace(|| "allocate account id"),
             || self.account_id.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        account_id_alloc.inputize(cs.namespace(|| "input account id"))?;
+
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
 
         let amount_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate amount"),
             || self.amount.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        amount_alloc.inputize(cs.namespace(|| "input amount"))?;
+
+        let fee_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate fee"),
+            || self.fee.ok_or(SynthesisError::AssignmentMissing),
+        )?;
 
         let nonce_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate nonce"),
             || self.nonce.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
+        let valid_until_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate valid until"),
+            || self.valid_until.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let eth_address_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate eth address"),
+            || self.eth_address.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate op type"),
+            OFFCHAIN_WITHDRAWAL_OP,
+        )?;
+
+        let is_noop_alloc = AllocatedBit::alloc(
+            cs.namespace(|| "allocate is noop"),
+            self.is_noop,
+        )?;
+
+        // check noop record is zero, so a noop pays nothing out and no fee
+
+        for (field, value) in [
+            ("account id", &account_id_alloc),
+            ("token id", &token_id_alloc),
+            ("amount", &amount_alloc),
+            ("fee", &fee_alloc),
+            ("nonce", &nonce_alloc),
+            ("valid until", &valid_until_alloc),
+            ("eth address", &eth_address_alloc),
+        ].iter() {
+            cs.enforce(
+                || format!("check noop {}", field),
+                |lc| lc + value.get_variable(),
+                |lc| lc + is_noop_alloc.get_variable(),
+                |lc| lc,
+            );
+        }
+
         // check signature --------------------------------------------------------------
 
         let withdrawal_hash = {
-            let hash_vec = poseidon_hash(
+            let hash_vec = poseidon_hash_gadget(
                 cs.namespace(|| "calculate message hash"),
                 &[
+                    op_type_alloc.clone(),
                     account_id_alloc.clone(),
+                    token_id_alloc.clone(),
                     amount_alloc.clone(),
+                    fee_alloc.clone(),
                     nonce_alloc.clone(),
+                    valid_until_alloc.clone(),
+                    eth_address_alloc.clone(),
+                    signing_domain[0].clone(),
+                    signing_domain[1].clone(),
                 ],
                 hash_params,
             )?;
@@ -105,10 +198,28 @@ impl<E> OffchainWithdrawalCircuit<E>
             self.sign.clone(),
             self.pubkey.clone(),
             &withdrawal_hash,
-            NUM_BYTES_TO_SIGN,
             sign_params,
         )?;
 
+        // a noop is signed with the padding key, its signature can't stand in
+        // for one of a real account
+
+        let (padding_x, padding_y) = padding_pubkey::<E>(sign_params).0.into_xy();
+
+        cs.enforce(
+            || "check noop pubkey x",
+            |lc| lc + sign_alloc.pk.get_x().get_variable() - (padding_x, CS::one()),
+            |lc| lc + is_noop_alloc.get_variable(),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check noop pubkey y",
+            |lc| lc + sign_alloc.pk.get_y().get_variable() - (padding_y, CS::one()),
+            |lc| lc + is_noop_alloc.get_variable(),
+            |lc| lc,
+        );
+
         // check changes validity -------------------------------------------------------
 
         // check pubkey consistency
@@ -117,9 +228,9 @@ impl<E> OffchainWithdrawalCircuit<E>
             cs.namespace(|| "public key consistence"),
             &sign_alloc.pk,
             &account_circuit,
-        );
+        )?;
         
-        // check account id, asset id consistency
+        // check account id, token id consistency
 
         check_decomposition_le(
             cs.namespace(|| "account id consistence"),
@@ -127,100 +238,481 @@ impl<E> OffchainWithdrawalCircuit<E>
             &account_circuit.accounts_tree.indices_alloc,
         )?;
 
-        // check amount
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
+        // check amount and fee, both are debited from the withdrawn token
 
         cs.enforce(
             || "check amount withdrawal",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[3].get_variable()
-                + amount_alloc.get_variable(),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable()
+                + amount_alloc.get_variable()
+                + fee_alloc.get_variable(),
         );
 
-        // check balance for overflow
+        // fee is credited to the single operator leaf, so nonzero fee is
+        // only allowed in the fee token
 
-        account_circuit.accounts_tree.new_leaf_alloc[3].limit_number_of_bits(
+        cs.enforce(
+            || "check fee token",
+            |lc| lc + fee_alloc.get_variable(),
+            |lc| lc + token_id_alloc.get_variable() - fee_token_id.get_variable(),
+            |lc| lc,
+        );
+
+        // a withdrawal that is not a noop has a nonzero amount, if the batch asks for it
+
+        let is_noop = Boolean::from(is_noop_alloc);
+
+        if reject_zero_amount {
+            enforce_nonzero_unless(
+                cs.namespace(|| "check amount nonzero"),
+                &amount_alloc,
+                &is_noop,
+            )?;
+        }
+
+        // check amount, fee and balance for overflow
+
+        enforce_bit_length(
+            cs.namespace(|| "check amount overflow"),
+            &amount_alloc,
+            BALANCE_BITS,
+        )?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check fee overflow"),
+            &fee_alloc,
+            BALANCE_BITS,
+        )?;
+
+        enforce_bit_length(
             cs.namespace(|| "check buy balance overflow"),
-            mem::size_of::<usize>() * BITS_IN_BYTE,
+            &account_circuit.balances_tree.new_leaf_alloc[0],
+            BALANCE_BITS,
+        )?;
+
+        // check expiry
+
+        enforce_not_expired(
+            cs.namespace(|| "check not expired"),
+            timestamp,
+            &valid_until_alloc,
         )?;
 
-        // check nonce
+        // check nonce, the zero nonce of a noop follows no leaf
 
         cs.enforce(
             || "nonce consistence",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[2].get_variable() + CS::one(),
-            |lc| lc + CS::one(),
-            |lc| lc + nonce_alloc.get_variable(),
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one()
+                - nonce_alloc.get_variable(),
+            |_| is_noop.not().lc(CS::one(), E::Fr::one()),
+            |lc| lc,
         );
 
+        enforce_bit_length(
+            cs.namespace(|| "check nonce overflow"),
+            &nonce_alloc,
+            NONCE_BITS,
+        )?;
+
+        // the contract reads it as uint160
+
+        enforce_bit_length(
+            cs.namespace(|| "check eth address overflow"),
+            &eth_address_alloc,
+            ADDRESS_BITS,
+        )?;
+
         cs.enforce(
             || "check nonce + 1",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[2].get_variable() + CS::one(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[2].get_variable(),
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one()
+                - account_circuit.accounts_tree.new_leaf_alloc[LEAF_NONCE].get_variable(),
+            |_| is_noop.not().lc(CS::one(), E::Fr::one()),
+            |lc| lc,
         );
 
-        // verify old root & calculate new root -----------------------------------------
+        // calculate new hash, a noop is not paid out and keeps it ---------------------
 
-        account_circuit.accounts_tree.verify_old_root(
-            cs.namespace(|| "verify old root"),
-            old_root,
+        let calculated_hash = {
+            let hashes_vec = poseidon_hash_gadget(
+                cs.namespace(|| "calculate new accum hash"),
+                &[
+                    op_type_alloc.clone(),
+                    old_hash.clone(),
+                    account_id_alloc,
+                    token_id_alloc,
+                    amount_alloc,
+                    eth_address_alloc,
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
+
+        let new_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select new accum hash"),
+            old_hash,
+            &calculated_hash,
+            &is_noop,
         )?;
 
-        let new_root = account_circuit.accounts_tree.calc_new_root(
+        // verify old root & calculate new root, noop leaves the root unchanged ---------
+
+        let calculated_old_root = account_circuit.accounts_tree.calc_old_root(
+            cs.namespace(|| "calculate old root"),
+        )?;
+
+        cs.enforce(
+            || "verify old root if not noop",
+            |lc| lc + calculated_old_root.get_variable() - old_root.get_variable(),
+            |_| is_noop.not().lc(CS::one(), E::Fr::one()),
+            |lc| lc,
+        );
+
+        let calculated_new_root = account_circuit.accounts_tree.calc_new_root(
             cs.namespace(|| "calculate new root"),
         )?;
 
-        Ok(new_root)
+        let new_root = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select new root"),
+            old_root,
+            &calculated_new_root,
+            &is_noop,
+        )?;
+
+        Ok((new_hash, new_root, fee_alloc))
     }
 
+    // the pubkey packed in the old leaf, the withdrawal keeps it
     pub fn check_pubkey<CS: ConstraintSystem<E>> (
         mut cs: CS,
         pubkey: &EdwardsPoint<E>,
         account_circuit: &AccountCircuit<E>,
-    ) {
-        cs.enforce(
-            || "enforce pubkey x and old leaf equivalence",
-            |lc| lc + pubkey.get_x().get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[0].get_variable(),
-        );
+    ) -> Result<(), SynthesisError> {
+        unpack_point_gadget(
+            cs.namespace(|| "enforce pubkey and old leaf equivalence"),
+            &account_circuit.accounts_tree.old_leaf_alloc[LEAF_PUBKEY],
+            pubkey.get_x(),
+            pubkey.get_y(),
+        )?;
 
         cs.enforce(
-            || "enforce pubkey x and new leaf equivalence",
-            |lc| lc + pubkey.get_x().get_variable(),
+            || "enforce old and new leaf pubkey equivalence",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_PUBKEY].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[0].get_variable(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[LEAF_PUBKEY].get_variable(),
         );
 
-        cs.enforce(
-            || "enforce pubkey y and old leaf equivalence",
-            |lc| lc + pubkey.get_y().get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[1].get_variable(),
-        );
+        Ok(())
+    }
+}
 
-        cs.enforce(
-            || "enforce pubkey y and new leaf equivalence",
-            |lc| lc + pubkey.get_y().get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[1].get_variable(),
-        );
+impl OffchainWithdrawalCircuit<Bn256> {
+    pub fn missing_field(&self) -> Option<&'static str> {
+        let values = [
+            ("account_id", self.account_id),
+            ("token_id", self.token_id),
+            ("amount", self.amount),
+            ("fee", self.fee),
+            ("nonce", self.nonce),
+            ("valid_until", self.valid_until),
+            ("eth_address", self.eth_address),
+        ];
+
+        if let Some((field, _)) = values.iter().find(|(_, value)| value.is_none()) {
+            Some(field)
+        } else if self.sign.is_none() {
+            Some("sign")
+        } else if self.pubkey.is_none() {
+            Some("pubkey")
+        } else if self.is_noop.is_none() {
+            Some("is_noop")
+        } else {
+            self.account_state.missing_field()
+        }
+    }
+
+    // padding withdrawal: the zero record signed with the padding key of
+    // keys::padding_secret_key, on a leaf of the padding pubkey at no real
+    // path. keeps the root and the accum hash, so a partial batch has the
+    // constraints of a full one
+    pub fn noop(
+        account_depth: usize,
+        token_depth: usize,
+        signing_domain: &SigningDomain,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Self {
+        let seckey = padding_secret_key();
+        let pubkey = seckey.public_key(sign_params).0;
+        let mut record = OffchainWithdrawal {
+            account_id: AccountId(0),
+            token_id: 0,
+            amount: Balance(0),
+            fee: Balance(0),
+            nonce: Nonce(0),
+            valid_until: 0,
+            eth_address: [0; ADDRESS_BYTES],
+            sign: None,
+        };
+        seckey.sign_withdrawal(&mut record, signing_domain, hash_params, sign_params);
+
+        let zero = Some(bn256::Fr::zero());
+        let account_state = AccountState::<Bn256> {
+            old_balance: zero,
+            new_balance: zero,
+            old_pubkey: Some(pubkey.clone()),
+            new_pubkey: Some(pubkey.clone()),
+            old_nonce: zero,
+            new_nonce: zero,
+            account_path: vec![zero; account_depth],
+            account_indices: vec![Some(false); account_depth],
+            token_path: vec![zero; token_depth],
+            token_indices: vec![Some(false); token_depth],
+        };
+
+        OffchainWithdrawalCircuit {
+            account_state,
+            account_id: zero,
+            token_id: zero,
+            amount: zero,
+            fee: zero,
+            nonce: zero,
+            valid_until: zero,
+            eth_address: zero,
+            sign: record.sign,
+            pubkey: Some(pubkey),
+            is_noop: Some(true),
+        }
+    }
+
+    // the witness checked off-circuit like DepositCircuit::validate_witness,
+    // the signature is left to the circuit. returns the accum hash and the
+    // root after the withdrawal, a noop returns them unchanged
+    #[allow(clippy::too_many_arguments)]
+    pub fn validate_witness(
+        &self,
+        slot: usize,
+        account_depth: usize,
+        token_depth: usize,
+        old_hash: bn256::Fr,
+        old_root: bn256::Fr,
+        hash_params: &Bn256PoseidonParams,
+    ) -> Result<(bn256::Fr, bn256::Fr), WitnessError> {
+        if let Some(field) = self.missing_field() {
+            return Err(WitnessError::MissingField { slot: Some(slot), field });
+        }
+        let (calculated_old_root, calculated_new_root) = self.account_state.calc_roots(
+            slot, account_depth, token_depth, hash_params,
+        )?;
+
+        let state = &self.account_state;
+        let mut balance = state.new_balance.unwrap();
+        balance.add_assign(&self.amount.unwrap());
+        balance.add_assign(&self.fee.unwrap());
+        let mut nonce = state.old_nonce.unwrap();
+        nonce.add_assign(&bn256::Fr::one());
+        let old_pubkey = state.old_pubkey.as_ref().map(pack_point);
+        let is_noop = self.is_noop == Some(true);
+        let record = [
+            self.account_id,
+            self.token_id,
+            self.amount,
+            self.fee,
+            self.nonce,
+            self.valid_until,
+            self.eth_address,
+        ];
+
+        let reason = if is_noop && record.iter().any(|value| *value != Some(bn256::Fr::zero())) {
+            Some("noop record is not zero")
+        } else if is_noop && self.pubkey.as_ref().map(pack_point) != Some(pack_point(&padding_pubkey::<Bn256>(jubjub_params()).0)) {
+            Some("noop is not signed with the padding key")
+        } else if indices_to_fr(&state.account_indices) != self.account_id {
+            Some("account id is not the leaf index")
+        } else if indices_to_fr(&state.token_indices) != self.token_id {
+            Some("token id is not the balance index")
+        } else if state.old_balance != Some(balance) {
+            Some("old balance is not the new one plus the amount and the fee")
+        } else if !is_noop && (self.nonce != Some(nonce) || state.new_nonce != Some(nonce)) {
+            Some("nonce is not the old one plus one")
+        } else if self.pubkey.as_ref().map(pack_point) != old_pubkey || state.new_pubkey.as_ref().map(pack_point) != old_pubkey {
+            Some("pubkey is not the one of the account")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(WitnessError::InconsistentLeaf { slot, reason });
+        }
+
+        if is_noop {
+            return Ok((old_hash, old_root));
+        }
+        if calculated_old_root != old_root {
+            return Err(WitnessError::OldRootMismatch { slot });
+        }
+
+        let new_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[
+                usize_to_fr(OFFCHAIN_WITHDRAWAL_OP),
+                old_hash,
+                self.account_id.unwrap(),
+                self.token_id.unwrap(),
+                self.amount.unwrap(),
+                self.eth_address.unwrap(),
+            ],
+        )[0];
+
+        Ok((new_hash, calculated_new_root))
     }
 }
 
+// valid until 0 is replaced with the timestamp itself, so the constraints are
+// the same for expiring and not expiring requests, timestamp is range checked
+pub fn enforce_not_expired<E, CS>(
+    mut cs: CS,
+    timestamp: &AllocatedNum<E>,
+    valid_until: &AllocatedNum<E>,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    enforce_bit_length(
+        cs.namespace(|| "check valid until overflow"),
+        valid_until,
+        mem::size_of::<usize>() * BITS_IN_BYTE,
+    )?;
+
+    let no_expiry = is_zero(
+        cs.namespace(|| "check no expiry"),
+        valid_until,
+    )?;
+
+    let deadline = AllocatedNum::conditionally_select(
+        cs.namespace(|| "select deadline"),
+        timestamp,
+        valid_until,
+        &no_expiry,
+    )?;
+
+    enforce_less_or_equal(
+        cs.namespace(|| "check timestamp"),
+        timestamp,
+        &deadline,
+        mem::size_of::<usize>() * BITS_IN_BYTE,
+    )
+}
+
 #[derive(Clone)]
 pub struct OffchainWithdrawalBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
     pub batch_size: usize,
     pub account_depth: usize,
+    pub token_depth: usize,
     pub hash_params: &'a <E as PoseidonEngine>::Params,
     pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub signing_domain: SigningDomain,
+    // amount != 0 for every withdrawal, off in the layout existing keys were
+    // generated for
+    pub reject_zero_amount: bool,
 
     pub queue: Vec::<OffchainWithdrawalCircuit<E>>,
+    pub fee_account_state: AccountState<E>,
+    pub fee_account_id: Option::<E::Fr>,
+    pub fee_token_id: Option::<E::Fr>,
+    // sum of the fees of the queue, public input following the block public inputs
+    pub total_fee: Option::<E::Fr>,
+    // block timestamp, public input following the total fee
+    pub timestamp: Option::<E::Fr>,
+    pub old_accum_hash: Option::<E::Fr>,
+    pub new_accum_hash: Option::<E::Fr>,
     pub old_account_root: Option::<E::Fr>,
     pub new_account_root: Option::<E::Fr>,
 }
 
+impl<'a, E> OffchainWithdrawalBatchCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn credit_fee<CS: ConstraintSystem<E>> (
+        mut cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &<E as PoseidonEngine>::Params,
+        account_state: &AccountState<E>,
+        account_id: &AllocatedNum<E>,
+        token_id: &AllocatedNum<E>,
+        fee: &AllocatedNum<E>,
+        old_root: &AllocatedNum<E>,
+    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        let account_circuit = AccountCircuit::new(
+            cs.namespace(|| "allocate fee account circuit"),
+            account_depth,
+            token_depth,
+            hash_params,
+            account_state,
+        )?;
+
+        // check account id, token id consistency
+
+        check_decomposition_le(
+            cs.namespace(|| "fee account id consistence"),
+            account_id,
+            &account_circuit.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "fee token id consistence"),
+            token_id,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
+        // check pubkey and nonce the same
+
+        for (field, i) in [("pubkey", LEAF_PUBKEY), ("nonce", LEAF_NONCE)].iter() {
+            cs.enforce(
+                || format!("check fee account {} the same", field),
+                |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[*i].get_variable(),
+                |lc| lc + CS::one(),
+                |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[*i].get_variable(),
+            );
+        }
+
+        // check fee credit
+
+        cs.enforce(
+            || "check fee credit",
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable()
+                + fee.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable(),
+        );
+
+        enforce_bit_length(
+            cs.namespace(|| "check fee account balance overflow"),
+            &account_circuit.balances_tree.new_leaf_alloc[0],
+            BALANCE_BITS,
+        )?;
+
+        // verify old root & calculate new root
+
+        account_circuit.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify old root"),
+            old_root,
+        )?;
+
+        account_circuit.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate new root"),
+        )
+    }
+}
+
 impl<'a, E> Circuit<E> for OffchainWithdrawalBatchCircuit<'a, E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
@@ -228,32 +720,120 @@ impl<'a, E> Circuit<E> for OffchainWithdrawalBatchCircuit<'a, E>
         self,
         cs: &mut CS,
     ) -> Result<(), SynthesisError> {
-        assert_eq!(self.batch_size, self.queue.len());
+        if self.batch_size != self.queue.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        )?;
+
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
+
+        let claimed_fee = alloc_total_fee_input(
+            cs.namespace(|| "allocate total fee input"),
+            self.total_fee,
+        )?;
+
+        let timestamp = AllocatedNum::alloc(
+            cs.namespace(|| "allocate timestamp"),
+            || self.timestamp.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        timestamp.inputize(cs.namespace(|| "input timestamp"))?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check timestamp overflow"),
+            &timestamp,
+            mem::size_of::<usize>() * BITS_IN_BYTE,
+        )?;
+
+        let signing_domain = alloc_signing_domain(
+            cs.namespace(|| "allocate signing domain"),
+            &self.signing_domain,
+        )?;
 
-        let mut prev_root = AllocatedNum::alloc(
-            cs.namespace(|| "allocate old root"),
-            || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
+        let fee_account_id = AllocatedNum::alloc(
+            cs.namespace(|| "allocate fee account id"),
+            || self.fee_account_id.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        prev_root.inputize(cs.namespace(|| "input old root"))?;
 
-        let new_root = AllocatedNum::alloc(
-            cs.namespace(|| "allocate new root"),
-            || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
+        let fee_token_id = AllocatedNum::alloc(
+            cs.namespace(|| "allocate fee token id"),
+            || self.fee_token_id.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        new_root.inputize(cs.namespace(|| "input new root"))?;
+
+        let mut total_fee = AllocatedNum::alloc(
+            cs.namespace(|| "allocate zero fee"),
+            || Ok(E::Fr::zero()),
+        )?;
+        total_fee.assert_zero(cs.namespace(|| "check zero fee"))?;
 
         for (i, withdrawal) in self.queue.iter().enumerate() {
-            let root = withdrawal.process(
+            let (hash, root, fee) = withdrawal.process(
                 cs.namespace(|| format!("verify withdrawal {}", i)),
                 self.account_depth,
+                self.token_depth,
                 self.hash_params,
                 self.sign_params,
+                &signing_domain,
+                &fee_token_id,
+                &timestamp,
+                &prev_hash,
                 &prev_root,
+                self.reject_zero_amount,
+            )?;
+
+            total_fee = add(
+                cs.namespace(|| format!("accumulate fee {}", i)),
+                &total_fee,
+                &fee,
             )?;
 
+            enforce_bit_length(
+                cs.namespace(|| format!("check fee accumulator overflow {}", i)),
+                &total_fee,
+                BALANCE_BITS,
+            )?;
+
+            prev_hash = hash;
             prev_root = root;
         }
 
+        // credit accumulated fees to the operator account with a single update
+
+        prev_root = Self::credit_fee(
+            cs.namespace(|| "credit fee"),
+            self.account_depth,
+            self.token_depth,
+            self.hash_params,
+            &self.fee_account_state,
+            &fee_account_id,
+            &fee_token_id,
+            &total_fee,
+            &prev_root,
+        )?;
+
+        cs.enforce(
+            || "enforce total fee equivalence",
+            |lc| lc + total_fee.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + claimed_fee.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new accum hash equivalence",
+            |lc| lc + prev_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_hash.get_variable(),
+        );
+
         cs.enforce(
             || "enforce new root equivalence",
             |lc| lc + prev_root.get_variable(),
@@ -264,3 +844,81 @@ impl<'a, E> Circuit<E> for OffchainWithdrawalBatchCircuit<'a, E>
         Ok(())
     }
 }
+
+impl<'a> OffchainWithdrawalBatchCircuit<'a, Bn256> {
+    // every withdrawal validated in order, then the fee credit as the slot
+    // after the last withdrawal, which has to end at the new root
+    pub fn validate_witness(&self) -> Result<(), WitnessError> {
+        if self.queue.len() != self.batch_size {
+            return Err(WitnessError::WrongLength {
+                slot: None, field: "queue", expected: self.batch_size, actual: self.queue.len(),
+            });
+        }
+
+        let batch_values = [
+            ("old_accum_hash", self.old_accum_hash),
+            ("new_accum_hash", self.new_accum_hash),
+            ("old_account_root", self.old_account_root),
+            ("new_account_root", self.new_account_root),
+            ("fee_account_id", self.fee_account_id),
+            ("fee_token_id", self.fee_token_id),
+        ];
+        if let Some((field, _)) = batch_values.iter().find(|(_, value)| value.is_none()) {
+            return Err(WitnessError::MissingField { slot: None, field });
+        }
+
+        let mut hash = self.old_accum_hash.unwrap();
+        let mut root = self.old_account_root.unwrap();
+        let mut total_fee = bn256::Fr::zero();
+        for (slot, withdrawal) in self.queue.iter().enumerate() {
+            let (new_hash, new_root) = withdrawal.validate_witness(
+                slot,
+                self.account_depth,
+                self.token_depth,
+                hash,
+                root,
+                self.hash_params,
+            )?;
+            hash = new_hash;
+            root = new_root;
+            total_fee.add_assign(&withdrawal.fee.unwrap());
+        }
+
+        let slot = self.queue.len();
+        let state = &self.fee_account_state;
+        let (fee_old_root, fee_new_root) = state.calc_roots(
+            slot, self.account_depth, self.token_depth, self.hash_params,
+        )?;
+
+        let mut balance = state.old_balance.unwrap();
+        balance.add_assign(&total_fee);
+        let reason = if indices_to_fr(&state.account_indices) != self.fee_account_id {
+            Some("fee account id is not the leaf index")
+        } else if indices_to_fr(&state.token_indices) != self.fee_token_id {
+            Some("fee token id is not the balance index")
+        } else if state.new_balance != Some(balance) {
+            Some("fee account is not credited the total fee")
+        } else if state.new_nonce != state.old_nonce
+            || state.new_pubkey.as_ref().map(pack_point) != state.old_pubkey.as_ref().map(pack_point)
+        {
+            Some("fee account nonce or pubkey changed")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(WitnessError::InconsistentLeaf { slot, reason });
+        }
+        if fee_old_root != root {
+            return Err(WitnessError::OldRootMismatch { slot });
+        }
+
+        if Some(fee_new_root) != self.new_account_root {
+            return Err(WitnessError::NewRootMismatch);
+        }
+        if Some(hash) != self.new_accum_hash {
+            return Err(WitnessError::AccumHashMismatch);
+        }
+
+        Ok(())
+    }
+}
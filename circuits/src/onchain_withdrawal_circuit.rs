@@ -1,5 +1,3 @@
-use std::mem;
-
 use bellman_ce::{
     Circuit,
     ConstraintSystem,
@@ -20,26 +18,29 @@ use sapling_crypto_ce::{
     },
 };
 
-use super::account::{ AccountState, AccountCircuit };
-use super::utils::calc::check_decomposition_le;
-
-const BITS_IN_BYTE: usize = 8;
+use super::types::BALANCE_BITS;
+use super::account::{ AccountState, AccountCircuit, LEAF_NONCE };
+use super::public_inputs::alloc_public_inputs;
+use super::utils::op_type::{ alloc_op_type, ONCHAIN_WITHDRAWAL_OP };
+use super::utils::calc::{ check_decomposition_le, enforce_bit_length };
 
 #[derive(Clone)]
 pub struct OnchainWithdrawalCircuit<E: JubjubEngine + PoseidonEngine> {
     pub account_state: AccountState<E>,
     pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
     pub amount: Option::<E::Fr>,
 }
 
 impl<E> OnchainWithdrawalCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
-    pub fn process<'a, CS: ConstraintSystem<E>> (
+    pub fn process<CS: ConstraintSystem<E>> (
         &self,
         mut cs: CS,
         account_depth: usize,
-        hash_params: &'a <E as PoseidonEngine>::Params,
+        token_depth: usize,
+        hash_params: &<E as PoseidonEngine>::Params,
         old_hash: &AllocatedNum<E>,
         old_root: &AllocatedNum<E>,
     ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
@@ -49,6 +50,7 @@ impl<E> OnchainWithdrawalCircuit<E>
         let account_circuit = AccountCircuit::new(
             cs.namespace(|| "allocate account circuit"),
             account_depth,
+            token_depth,
             hash_params,
             &self.account_state,
         )?;
@@ -59,6 +61,12 @@ impl<E> OnchainWithdrawalCircuit<E>
         )?;
         account_id_alloc.inputize(cs.namespace(|| "input account id"))?;
 
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        token_id_alloc.inputize(cs.namespace(|| "input token id"))?;
+
         let amount_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate amount"),
             || self.amount.ok_or(SynthesisError::AssignmentMissing),
@@ -67,7 +75,7 @@ impl<E> OnchainWithdrawalCircuit<E>
 
         // check changes validity -----------------------------------
         
-        // check account id, asset id consistency
+        // check account id, token id consistency
 
         check_decomposition_le(
             cs.namespace(|| "account id consistence"),
@@ -75,40 +83,54 @@ impl<E> OnchainWithdrawalCircuit<E>
             &account_circuit.accounts_tree.indices_alloc,
         )?;
 
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
         // check amount
 
         cs.enforce(
             || "check amount withdrawal",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[3].get_variable()
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable()
                 + amount_alloc.get_variable(),
         );
 
         // check balance for overflow
 
-        account_circuit.accounts_tree.new_leaf_alloc[3].limit_number_of_bits(
+        enforce_bit_length(
             cs.namespace(|| "check buy balance overflow"),
-            mem::size_of::<usize>() * BITS_IN_BYTE,
+            &account_circuit.balances_tree.new_leaf_alloc[0],
+            BALANCE_BITS,
         )?;
 
         // check nonce the same TODO ???
 
         cs.enforce(
             || "check nonce the same",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[2].get_variable(),
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[2].get_variable(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[LEAF_NONCE].get_variable(),
         );
 
+        let op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate op type"),
+            ONCHAIN_WITHDRAWAL_OP,
+        )?;
+
         // calculate new hash ---------------------------------------
 
         let new_hash = {
             let hashes_vec = poseidon_hash(
                 cs.namespace(|| "calculate new accum hash"),
                 &[
+                    op_type_alloc.clone(),
                     old_hash.clone(),
                     account_id_alloc,
+                    token_id_alloc,
                 ],
                 hash_params,
             )?;
@@ -134,6 +156,7 @@ impl<E> OnchainWithdrawalCircuit<E>
 pub struct OnchainWithdrawalBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
     pub batch_size: usize,
     pub account_depth: usize,
+    pub token_depth: usize,
     pub hash_params: &'a <E as PoseidonEngine>::Params,
 
     pub queue: Vec::<OnchainWithdrawalCircuit<E>>,
@@ -150,36 +173,28 @@ impl<'a, E> Circuit<E> for OnchainWithdrawalBatchCircuit<'a, E>
         self,
         cs: &mut CS,
     ) -> Result<(), SynthesisError> {
-        assert_eq!(self.batch_size, self.queue.len());
-
-        let mut prev_hash = AllocatedNum::alloc(
-            cs.namespace(|| "allocate old accum hash"),
-            || self.old_accum_hash.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-        prev_hash.inputize(cs.namespace(|| "input old accum hash"))?;
-
-        let new_hash = AllocatedNum::alloc(
-            cs.namespace(|| "allocate new accum hash"),
-            || self.new_accum_hash.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-        new_hash.inputize(cs.namespace(|| "input new accum hash"))?;
+        if self.batch_size != self.queue.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
 
-        let mut prev_root = AllocatedNum::alloc(
-            cs.namespace(|| "allocate old root"),
-            || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
         )?;
-        prev_root.inputize(cs.namespace(|| "input old root"))?;
 
-        let new_root = AllocatedNum::alloc(
-            cs.namespace(|| "allocate new root"),
-            || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-        new_root.inputize(cs.namespace(|| "input new root"))?;
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
 
         for (i, withdrawal) in self.queue.iter().enumerate() {
             let (hash, root) = withdrawal.process(
                 cs.namespace(|| format!("verify withdrawal {}", i)),
                 self.account_depth,
+                self.token_depth,
                 self.hash_params,
                 &prev_hash,
                 &prev_root,
@@ -1,6 +1,7 @@
 use std::fmt;
 use std::io;
 use std::error::Error;
+use std::sync::Arc;
 
 #[allow(unused_imports)]
 use sapling_crypto_ce::{
@@ -35,16 +36,32 @@ use super::{
     data_structs::transfer::Transfer,
     data_structs::deposit::Deposit,
     data_structs::onchain_withdrawal::OnchainWithdrawal,
-    data_structs::offchain_withdrawal::OffchainWithdrawal,
-    tree::account::AccountsTree,
+    data_structs::offchain_withdrawal::{ OffchainWithdrawal, credit_fee_and_record_state },
+    tree::account::{ AccountsTree, TreeError },
 };
 
+use crate::utils::op_type::{
+    DEPOSIT_OP,
+    ONCHAIN_WITHDRAWAL_OP,
+    TRANSFER_OP,
+};
+
+use crate::utils::domain::{ SigningDomain, address_to_fr };
+
 use crate::utils::utils::{
     usize_to_fr,
-    fr_to_usize,
+    u128_to_fr,
+    fr_to_u128_checked,
 };
 
+use crate::error::OpenPlasmaError;
+use crate::metrics::metrics;
+use crate::instrument::timed_span;
+
 use crate::{
+    types::{ Balance, AccountId },
+    public_inputs::PublicInputs,
+    params::Params,
     deposit_circuit::{ DepositCircuit, DepositBatchCircuit },
     onchain_withdrawal_circuit:: { OnchainWithdrawalCircuit, OnchainWithdrawalBatchCircuit },
     offchain_withdrawal_circuit:: { OffchainWithdrawalCircuit, OffchainWithdrawalBatchCircuit },
@@ -57,42 +74,54 @@ pub enum OperatorError {
     Unknown,
     NotEnoughObjects,
     InvalidSignature,
-    CircuitError(SynthesisError),
-    IoError(std::io::Error),
+    InvalidTransfer,
+    InvalidWithdrawal,
+    InvalidPubkey,
+    DuplicatePubkey,
+    // the tree, a circuit or io failed underneath
+    PlasmaError(OpenPlasmaError),
 }
 
-impl Error for OperatorError {
-    fn description(&self) -> &str {
-        match *self {
+impl Error for OperatorError {}
+
+impl fmt::Display for OperatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let description = match self {
             OperatorError::Unknown => "Unknown error",
             OperatorError::NotEnoughObjects => "Not enough objects for batch",
             OperatorError::InvalidSignature => "Invalid order signature",
-            OperatorError::CircuitError(_) => "Encountered a circuit error",
-            OperatorError::IoError(_) => "Encountered an I/O error",
-        }
+            OperatorError::InvalidTransfer => "Invalid transfer request",
+            OperatorError::InvalidWithdrawal => "Invalid withdrawal request",
+            OperatorError::InvalidPubkey => "Deposit pubkey is not a point of the prime order subgroup",
+            OperatorError::DuplicatePubkey => "Deposit pubkey belongs to another account",
+            OperatorError::PlasmaError(e) => return write!(f, "{}", e),
+        };
+
+        write!(f, "{}", description)
     }
 }
 
-impl fmt::Display for OperatorError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        if let &OperatorError::IoError(ref e) = self {
-            write!(f, "I/O error: ")?;
-            e.fmt(f)
-        } else {
-            write!(f, "{}", self.description())
-        }
+impl From<OpenPlasmaError> for OperatorError {
+    fn from(err: OpenPlasmaError) -> Self {
+        OperatorError::PlasmaError(err)
     }
 }
 
 impl From<io::Error> for OperatorError {
     fn from(err: io::Error) -> Self {
-        OperatorError::IoError(err)
+        OperatorError::PlasmaError(err.into())
+    }
+}
+
+impl From<TreeError> for OperatorError {
+    fn from(err: TreeError) -> Self {
+        OperatorError::PlasmaError(err.into())
     }
 }
 
 impl From<SynthesisError> for OperatorError {
     fn from(err: SynthesisError) -> Self {
-        OperatorError::CircuitError(err)
+        OperatorError::PlasmaError(err.into())
     }
 }
 
@@ -111,8 +140,22 @@ pub struct Operator<'a> {
     pub tree: AccountsTree<'a>,
     pub deposit_accum_hash: bn256::Fr,
     pub withdrawal_accum_hash: bn256::Fr,
+    pub offchain_withdrawal_accum_hash: bn256::Fr,
+    pub transfer_accum_hash: bn256::Fr,
+    // the last block finalized in the tree history, block 0 is the empty tree
+    pub block_number: usize,
+
+    // offchain withdrawal fees are credited here
+    pub fee_account_id: AccountId,
+    pub fee_token_id: usize,
+    // requests are signed for this deployment, see SigningDomain
+    pub signing_domain: SigningDomain,
 
     pub account_depth: usize,
+    pub token_depth: usize,
+    // hash_params and sign_params borrow from params, the deposit circuit
+    // owns a copy of the Arc
+    pub params: Arc<Params<Bn256>>,
     pub hash_params: &'a Bn256PoseidonParams,
     pub sign_params: &'a AltJubjubBn256,
     pub deposit_circuit_params: &'a Parameters::<Bn256>,
@@ -123,19 +166,30 @@ pub struct Operator<'a> {
 
 #[allow(dead_code)]
 impl<'a> Operator<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         account_depth: usize,
+        token_depth: usize,
         deposit_batch: usize,
         transfer_batch: usize,
         offchain_withdrawal_batch: usize,
         onchain_withdrawal_batch: usize,
-        hash_params: &'a Bn256PoseidonParams,
-        sign_params: &'a AltJubjubBn256,
+        params: &'a Arc<Params<Bn256>>,
         deposit_circuit_params: &'a Parameters::<Bn256>,
         transfer_circuit_params: &'a Parameters::<Bn256>,
         offchain_withdrawal_circuit_params: &'a Parameters::<Bn256>,
         onchain_withdrawal_circuit_params: &'a Parameters::<Bn256>,
     ) -> Self {
+        let hash_params = &params.hash_params;
+        let sign_params = &params.sign_params;
+        let mut tree = AccountsTree::new(
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+        );
+        tree.finalize_block(0).expect("new tree has no history");
+
         Operator {
             transfer_batch,
             transfer_queue: Vec::new(),
@@ -145,14 +199,18 @@ impl<'a> Operator<'a> {
             onchain_withdrawal_queue: Vec::new(),
             offchain_withdrawal_batch,
             offchain_withdrawal_queue: Vec::new(),
-            tree: AccountsTree::new(
-                account_depth,
-                hash_params,
-                sign_params,
-            ),
+            tree,
             deposit_accum_hash: bn256::Fr::zero(),
             withdrawal_accum_hash: bn256::Fr::zero(),
+            offchain_withdrawal_accum_hash: bn256::Fr::zero(),
+            transfer_accum_hash: bn256::Fr::zero(),
+            block_number: 0,
+            fee_account_id: AccountId(0),
+            fee_token_id: 0,
+            signing_domain: SigningDomain::default(),
             account_depth,
+            token_depth,
+            params: Arc::clone(params),
             hash_params,
             sign_params,
             deposit_circuit_params,
@@ -167,6 +225,20 @@ impl<'a> Operator<'a> {
         deposit: Deposit,
     ) -> Result<(), OperatorError> {
         // TODO check deposit correctnes
+        if !deposit.is_valid_pubkey(self.sign_params) {
+            return Err(OperatorError::InvalidPubkey);
+        }
+
+        // one account per key, neither in the tree nor among queued deposits
+        let pubkey = deposit.pubkey.as_ref().unwrap();
+        let registered = self.tree.account_id_by_pubkey(pubkey);
+        let queued = self.deposit_queue.iter().find(
+            |queued| queued.pubkey.as_ref().unwrap().0 == pubkey.0
+        ).map(|queued| queued.account_id);
+        if registered.into_iter().chain(queued).any(|account_id| account_id != deposit.account_id) {
+            return Err(OperatorError::DuplicatePubkey);
+        }
+
         self.deposit_queue.push(deposit);
 
         Ok(())
@@ -187,6 +259,10 @@ impl<'a> Operator<'a> {
         withdrawal: OffchainWithdrawal,
     ) -> Result<(), OperatorError> {
         // TODO check withdrawal correctnes
+        if !withdrawal.fee.is_zero() && withdrawal.token_id != self.fee_token_id {
+            return Err(OperatorError::InvalidWithdrawal);
+        }
+
         self.offchain_withdrawal_queue.push(withdrawal);
 
         Ok(())
@@ -197,15 +273,60 @@ impl<'a> Operator<'a> {
         transfer: Transfer,
     ) -> Result<(), OperatorError> {
         // TODO assert correctness - recheck matcher: orders not cancelled, enough balances, prices correspond, price integer
+        if transfer.account_id_from == transfer.account_id_to {
+            return Err(OperatorError::InvalidTransfer);
+        }
+
         self.transfer_queue.push(transfer);
 
         Ok(())
     }
 
+    // a failed batch, e.g. a bad signature in the middle of it or a proving
+    // error, leaves the tree and the accum hashes as they were; the requests
+    // already taken from the queue are dropped
+    fn revert_on_error<F>(
+        &mut self,
+        batch: F,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError>
+        where F: FnOnce(&mut Self) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError>,
+    {
+        let checkpoint = self.tree.checkpoint();
+        let accum_hashes = (
+            self.deposit_accum_hash,
+            self.withdrawal_accum_hash,
+            self.offchain_withdrawal_accum_hash,
+            self.transfer_accum_hash,
+        );
+
+        match batch(self) {
+            Ok(result) => {
+                self.tree.commit(checkpoint)?;
+                self.tree.finalize_block(self.block_number + 1)?;
+                self.block_number += 1;
+                Ok(result)
+            },
+            Err(err) => {
+                self.tree.rollback(checkpoint)?;
+                self.deposit_accum_hash = accum_hashes.0;
+                self.withdrawal_accum_hash = accum_hashes.1;
+                self.offchain_withdrawal_accum_hash = accum_hashes.2;
+                self.transfer_accum_hash = accum_hashes.3;
+                Err(err)
+            },
+        }
+    }
+
     pub fn execute_deposit_batch(
         &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.revert_on_error(|operator| operator.process_deposit_batch())
+    }
+
+    fn process_deposit_batch(
+        &mut self,
     ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> { 
-        if self.deposit_queue.len() < self.deposit_batch {
+        if self.deposit_queue.is_empty() {
             return Err(OperatorError::NotEnoughObjects);
         }
 
@@ -215,38 +336,49 @@ impl<'a> Operator<'a> {
         let old_root = self.tree.get_root();
         let mut executed_deposits = Vec::new();
 
-        for _ in 0..self.deposit_batch {
-            let deposit = self.deposit_queue.remove(0);
-            // update account
+        // update accounts in one pass, the queue keeps the deposits if it fails
+        let num_deposits = self.deposit_batch.min(self.deposit_queue.len());
+        let updates: Vec<_> = self.deposit_queue[..num_deposits].iter().map(
+            |deposit| deposit.leaf_update()
+        ).collect();
+        let account_states = self.tree.apply_batch(&updates)?;
+        let mut deposits = self.deposit_queue.drain(..num_deposits).zip(account_states);
 
-            let pubkey = deposit.pubkey.clone().unwrap();
-            let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+        for _ in 0..self.deposit_batch {
+            // partially filled batch is padded with noop deposits
+            let executed_deposit = match deposits.next() {
+                Some((deposit, account_state)) => DepositCircuit {
+                    account_state,
+                    pubkey: Some(deposit.pubkey.unwrap().0),
+                    account_id: Some(usize_to_fr(deposit.account_id)),
+                    token_id: Some(usize_to_fr(deposit.token_id)),
+                    amount: Some(u128_to_fr(deposit.amount)),
+                    is_noop: Some(false),
+                    is_refund: Some(false),
+                },
+                None => DepositCircuit::noop(self.account_depth, self.token_depth),
+            };
 
             // update accumulate hash
 
+            let (pubkey_x, pubkey_y) = executed_deposit.pubkey.as_ref().unwrap().into_xy();
+
             self.deposit_accum_hash = {
                 let hashes_vec = poseidon_hash::<Bn256>(
                     self.hash_params,
                     &[
+                        usize_to_fr(DEPOSIT_OP),
                         self.deposit_accum_hash,
                         pubkey_x,
                         pubkey_y,
-                        usize_to_fr(deposit.account_id),
-                        usize_to_fr(deposit.amount),
+                        executed_deposit.account_id.unwrap(),
+                        executed_deposit.token_id.unwrap(),
+                        executed_deposit.amount.unwrap(),
                     ],
                 );
                 hashes_vec[0]
             };
 
-            let account_state = deposit.update_tree_and_record_state(&mut self.tree);
-
-            let executed_deposit = DepositCircuit {
-                account_state,
-                pubkey: Some(pubkey.0),
-                account_id: Some(usize_to_fr(deposit.account_id)),
-                amount: Some(usize_to_fr(deposit.amount)),
-            };
-
             executed_deposits.push(executed_deposit);
         }
 
@@ -258,9 +390,12 @@ impl<'a> Operator<'a> {
         let circuit = DepositBatchCircuit {
             deposit_batch: self.deposit_batch,
             account_depth: self.account_depth,
-            hash_params: self.hash_params,
+            token_depth: self.token_depth,
+            params: Arc::clone(&self.params),
+            reject_zero_amount: false,
+            leaf_version: self.tree.leaf_version(),
 
-            deposit_queue: executed_deposits,
+            deposit_queue: executed_deposits.into(),
             old_accum_hash: Some(old_hash),
             new_accum_hash: Some(new_hash),
             old_account_root: Some(old_root),
@@ -287,7 +422,9 @@ impl<'a> Operator<'a> {
 
         let mut rng = thread_rng();
         let proof = create_random_proof(circuit, self.deposit_circuit_params, &mut rng)?;
-        let public_inputs = vec![old_hash, new_hash, old_root, new_root];
+        metrics().proof_generated();
+        metrics().add_ops_processed(num_deposits);
+        let public_inputs = PublicInputs::<Bn256>::from_deposit_block(old_hash, new_hash, old_root, new_root);
 
         // TODO send new state to smart contract
 
@@ -297,6 +434,12 @@ impl<'a> Operator<'a> {
     pub fn execute_onchain_withdrawal_batch(
         &mut self,
     ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.revert_on_error(|operator| operator.process_onchain_withdrawal_batch())
+    }
+
+    fn process_onchain_withdrawal_batch(
+        &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
         
         if self.onchain_withdrawal_queue.len() < self.onchain_withdrawal_batch {
             return Err(OperatorError::NotEnoughObjects);
@@ -316,24 +459,27 @@ impl<'a> Operator<'a> {
                 let hashes_vec = poseidon_hash::<Bn256>(
                     self.hash_params,
                     &[
+                        usize_to_fr(ONCHAIN_WITHDRAWAL_OP),
                         self.withdrawal_accum_hash,
                         usize_to_fr(withdrawal.account_id),
+                        usize_to_fr(withdrawal.token_id),
                     ],
                 );
                 hashes_vec[0]
             };
 
             // calculate withdrawal amount (onchain withdrawal takes all value)
-            withdrawal.amount = Some(fr_to_usize(
-                self.tree.get_balance(withdrawal.account_id)
-            ));
+            withdrawal.amount = Some(fr_to_u128_checked(
+                &self.tree.get_balance(withdrawal.account_id, withdrawal.token_id)?
+            )?);
 
-            let account_state = withdrawal.update_tree_and_record_state(&mut self.tree);
+            let account_state = withdrawal.update_tree_and_record_state(&mut self.tree)?;
 
             let executed_withdrawal = OnchainWithdrawalCircuit {
                 account_state,
                 account_id: Some(usize_to_fr(withdrawal.account_id)),
-                amount: Some(usize_to_fr(withdrawal.amount.unwrap())),
+                token_id: Some(usize_to_fr(withdrawal.token_id)),
+                amount: Some(u128_to_fr(withdrawal.amount.unwrap())),
             };
 
             executed.push(executed_withdrawal);
@@ -347,6 +493,7 @@ impl<'a> Operator<'a> {
         let circuit = OnchainWithdrawalBatchCircuit {
             batch_size: self.onchain_withdrawal_batch,
             account_depth: self.account_depth,
+            token_depth: self.token_depth,
             hash_params: self.hash_params,
             queue: executed.clone(),
             old_accum_hash: Some(old_hash),
@@ -375,12 +522,15 @@ impl<'a> Operator<'a> {
 
         let mut rng = thread_rng();
         let proof = create_random_proof(circuit, self.onchain_withdrawal_circuit_params, &mut rng)?;
+        metrics().proof_generated();
+        metrics().add_ops_processed(self.onchain_withdrawal_batch);
         
-        let mut public_inputs = vec![old_hash, new_hash, old_root, new_root];
-        for i in 0..self.onchain_withdrawal_batch {
+        let mut public_inputs = PublicInputs::<Bn256>::new(old_hash, new_hash, old_root, new_root).to_vec();
+        for withdrawal in executed.iter() {
             let mut inputs = vec![
-                executed[i].account_id.unwrap(), 
-                executed[i].amount.unwrap(),
+                withdrawal.account_id.unwrap(),
+                withdrawal.token_id.unwrap(),
+                withdrawal.amount.unwrap(),
             ];
             public_inputs.append(&mut inputs);
         }
@@ -392,6 +542,14 @@ impl<'a> Operator<'a> {
 
     pub fn execute_offchain_withdrawal_batch(
         &mut self,
+        timestamp: usize,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.revert_on_error(|operator| operator.process_offchain_withdrawal_batch(timestamp))
+    }
+
+    fn process_offchain_withdrawal_batch(
+        &mut self,
+        timestamp: usize,
     ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
         
         if self.offchain_withdrawal_queue.len() < self.offchain_withdrawal_batch {
@@ -400,30 +558,77 @@ impl<'a> Operator<'a> {
 
         // update local tree ----------------------------------------
 
+        let old_hash = self.offchain_withdrawal_accum_hash;
         let old_root = self.tree.get_root();
         let mut executed = Vec::new();
+        let mut total_fee = Balance(0);
 
-        for _ in 0..self.offchain_withdrawal_batch {
-            let withdrawal = self.offchain_withdrawal_queue.remove(0);
+        let mut accepted = Vec::new();
 
-            self.check_offchain_withdrawal_signature(&withdrawal)?;
+        while executed.len() < self.offchain_withdrawal_batch {
+            if self.offchain_withdrawal_queue.is_empty() {
+                // accepted withdrawals wait for the next batch, the tree is
+                // rolled back by the caller
+                self.offchain_withdrawal_queue.splice(0..0, accepted);
+                return Err(OperatorError::NotEnoughObjects);
+            }
+            let withdrawal = self.offchain_withdrawal_queue.remove(0);
+            let _span = timed_span!(
+                "witness.withdrawal",
+                account_id = withdrawal.account_id.index(),
+                depth = self.account_depth,
+            );
+
+            // a bad request is dropped and the next one takes its place,
+            // nothing of it reaches the tree or the accum hash
+            if withdrawal.is_expired(timestamp)
+                || self.check_offchain_withdrawal_signature(&withdrawal).is_err()
+            {
+                continue;
+            }
+            let account_state = match withdrawal.update_tree_and_record_state(&mut self.tree) {
+                Ok(account_state) => account_state,
+                Err(_) => continue,
+            };
 
-            let account_state = withdrawal.update_tree_and_record_state(&mut self.tree);
+            // update accumulate hash
+            self.offchain_withdrawal_accum_hash = withdrawal.record_hash(
+                self.offchain_withdrawal_accum_hash,
+                self.hash_params,
+            );
 
-            let pubkey = self.tree.get_pubkey(withdrawal.account_id);
+            let pubkey = self.tree.get_pubkey(withdrawal.account_id.index())?;
 
             let executed_withdrawal = OffchainWithdrawalCircuit {
                 account_state,
-                account_id: Some(usize_to_fr(withdrawal.account_id)),
-                amount: Some(usize_to_fr(withdrawal.amount)),
-                nonce: Some(usize_to_fr(withdrawal.nonce)),
-                sign: Some(withdrawal.sign.unwrap()),
+                account_id: Some(withdrawal.account_id.to_fr()),
+                token_id: Some(usize_to_fr(withdrawal.token_id)),
+                amount: Some(withdrawal.amount.to_fr()),
+                fee: Some(withdrawal.fee.to_fr()),
+                nonce: Some(withdrawal.nonce.to_fr()),
+                valid_until: Some(usize_to_fr(withdrawal.valid_until)),
+                eth_address: Some(address_to_fr(&withdrawal.eth_address)),
+                sign: withdrawal.sign.clone(),
                 pubkey: Some(pubkey.0),
+                is_noop: Some(false),
             };
 
+            total_fee = total_fee.checked_add(withdrawal.fee).ok_or(TreeError::BalanceOverflow {
+                account_id: self.fee_account_id.index(),
+                token_id: self.fee_token_id,
+            })?;
             executed.push(executed_withdrawal);
+            accepted.push(withdrawal);
         }
 
+        let fee_account_state = credit_fee_and_record_state(
+            &mut self.tree,
+            self.fee_account_id,
+            self.fee_token_id,
+            total_fee,
+        )?;
+
+        let new_hash = self.offchain_withdrawal_accum_hash;
         let new_root = self.tree.get_root();
 
         // prepare snark input
@@ -431,10 +636,20 @@ impl<'a> Operator<'a> {
         let circuit = OffchainWithdrawalBatchCircuit {
             batch_size: self.offchain_withdrawal_batch,
             account_depth: self.account_depth,
+            token_depth: self.token_depth,
             hash_params: self.hash_params,
             sign_params: self.sign_params,
-
-            queue: executed.clone(),
+            signing_domain: self.signing_domain,
+            reject_zero_amount: false,
+
+            queue: executed,
+            fee_account_state,
+            fee_account_id: Some(self.fee_account_id.to_fr()),
+            fee_token_id: Some(usize_to_fr(self.fee_token_id)),
+            total_fee: Some(total_fee.to_fr()),
+            timestamp: Some(usize_to_fr(timestamp)),
+            old_accum_hash: Some(old_hash),
+            new_accum_hash: Some(new_hash),
             old_account_root: Some(old_root),
             new_account_root: Some(new_root),
         };
@@ -443,15 +658,12 @@ impl<'a> Operator<'a> {
 
         let mut rng = thread_rng();
         let proof = create_random_proof(circuit, self.offchain_withdrawal_circuit_params, &mut rng)?;
-        
-        let mut public_inputs = vec![old_root, new_root];
-        for i in 0..self.offchain_withdrawal_batch {
-            let mut inputs = vec![
-                executed[i].account_id.unwrap(),
-                executed[i].amount.unwrap(),
-            ];
-            public_inputs.append(&mut inputs);
-        }
+        metrics().proof_generated();
+        metrics().add_ops_processed(self.offchain_withdrawal_batch);
+        let mut public_inputs = PublicInputs::<Bn256>::new(old_hash, new_hash, old_root, new_root)
+            .with_total_fee(total_fee.to_fr())
+            .to_vec();
+        public_inputs.push(usize_to_fr(timestamp));
 
         // TODO send new state to smart contract --------------------
 
@@ -462,39 +674,46 @@ impl<'a> Operator<'a> {
         &self,
         transfer: &Transfer
     ) -> Result<(), OperatorError> {
-        let pubkey = &self.tree.get_pubkey(transfer.account_id_from);
+        let pubkey = &self.tree.get_pubkey(transfer.account_id_from)?;
 
-        if !transfer.verify_signature(
+        transfer.verify_signature(
             pubkey,
             self.hash_params,
             self.sign_params,
-        ) {
-            return Err(OperatorError::InvalidSignature);
-        }
-
-        Ok(())
+        ).map_err(|_| {
+            metrics().signature_rejected();
+            OperatorError::InvalidSignature
+        })
     }
 
     fn check_offchain_withdrawal_signature(
         &self,
         withdrawal: &OffchainWithdrawal
     ) -> Result<(), OperatorError> {
-        let pubkey = &self.tree.get_pubkey(withdrawal.account_id);
+        self.tree.check_registered(withdrawal.account_id.index())?;
 
-        if !withdrawal.verify_signature(
-            pubkey,
-            self.hash_params,
-            self.sign_params,
-        ) {
-            return Err(OperatorError::InvalidSignature);
-        }
+        let pubkey = &self.tree.get_pubkey(withdrawal.account_id.index())?;
 
-        Ok(())
+        withdrawal.verify_signature(
+            pubkey,
+            &self.signing_domain,
+            Some(self.hash_params),
+            Some(self.sign_params),
+        ).map_err(|_| {
+            metrics().signature_rejected();
+            OperatorError::InvalidSignature
+        })
     }
 
     pub fn execute_transfer_batch(
         &mut self,
     ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.revert_on_error(|operator| operator.process_transfer_batch())
+    }
+
+    fn process_transfer_batch(
+        &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
         
         if self.transfer_queue.len() < self.transfer_batch {
             return Err(OperatorError::NotEnoughObjects);
@@ -502,6 +721,7 @@ impl<'a> Operator<'a> {
 
         // update local tree ----------------------------------------
 
+        let old_hash = self.transfer_accum_hash;
         let old_root = self.tree.get_root();
         let mut executed = Vec::new();
 
@@ -510,17 +730,34 @@ impl<'a> Operator<'a> {
 
             self.check_transfer_signature(&transfer)?;
 
-            let (account_state_from, account_state_to) = transfer.update_tree_and_record_state(&mut self.tree);
+            // update accumulate hash
+            self.transfer_accum_hash = {
+                let hashes_vec = poseidon_hash::<Bn256>(
+                    self.hash_params,
+                    &[
+                        usize_to_fr(TRANSFER_OP),
+                        self.transfer_accum_hash,
+                        usize_to_fr(transfer.account_id_from),
+                        usize_to_fr(transfer.account_id_to),
+                        usize_to_fr(transfer.token_id),
+                        u128_to_fr(transfer.amount),
+                    ],
+                );
+                hashes_vec[0]
+            };
+
+            let (account_state_from, account_state_to) = transfer.update_tree_and_record_state(&mut self.tree)?;
 
-            let pubkey = self.tree.get_pubkey(transfer.account_id_from);
+            let pubkey = self.tree.get_pubkey(transfer.account_id_from)?;
 
             let executed_transfer = TransferCircuit {
                 account_state_from,
                 account_state_to,
                 account_id_from: Some(usize_to_fr(transfer.account_id_from)),
                 account_id_to: Some(usize_to_fr(transfer.account_id_to)),
-                amount: Some(usize_to_fr(transfer.amount)),
-                nonce: Some(usize_to_fr(transfer.nonce)),
+                token_id: Some(usize_to_fr(transfer.token_id)),
+                amount: Some(u128_to_fr(transfer.amount)),
+                nonce: Some(transfer.nonce.to_fr()),
                 sign: Some(transfer.sign.unwrap()),
                 pubkey: Some(pubkey.0),
             };
@@ -528,6 +765,7 @@ impl<'a> Operator<'a> {
             executed.push(executed_transfer);
         }
 
+        let new_hash = self.transfer_accum_hash;
         let new_root = self.tree.get_root();
 
         // prepare snark input
@@ -535,9 +773,12 @@ impl<'a> Operator<'a> {
         let circuit = TransferBatchCircuit {
             batch_size: self.transfer_batch,
             account_depth: self.account_depth,
+            token_depth: self.token_depth,
             hash_params: self.hash_params,
             sign_params: self.sign_params,
             queue: executed.clone(),
+            old_accum_hash: Some(old_hash),
+            new_accum_hash: Some(new_hash),
             old_account_root: Some(old_root),
             new_account_root: Some(new_root),
         };
@@ -546,7 +787,9 @@ impl<'a> Operator<'a> {
 
         let mut rng = thread_rng();
         let proof = create_random_proof(circuit, self.transfer_circuit_params, &mut rng)?;
-        let public_inputs = vec![old_root, new_root];
+        metrics().proof_generated();
+        metrics().add_ops_processed(self.transfer_batch);
+        let public_inputs = PublicInputs::<Bn256>::new(old_hash, new_hash, old_root, new_root).to_vec();
 
         // TODO send new state to smart contract --------------------
 
@@ -0,0 +1,63 @@
+use std::sync::{ Arc, OnceLock };
+
+use sapling_crypto_ce::{
+    poseidon::{
+        PoseidonEngine,
+        bn256::Bn256PoseidonParams,
+    },
+    jubjub::JubjubEngine,
+    alt_babyjubjub::AltJubjubBn256,
+    group_hash::BlakeHasher,
+};
+
+use pairing_ce::bn256::Bn256;
+
+use crate::rescue::Bn256RescueParams;
+
+// the poseidon and jubjub parameters a circuit is built with, shared behind an
+// Arc so circuits own them and can be moved to proving threads
+pub struct Params<E: JubjubEngine + PoseidonEngine> {
+    pub hash_params: <E as PoseidonEngine>::Params,
+    pub sign_params: <E as JubjubEngine>::Params,
+}
+
+impl Params<Bn256> {
+    // the parameters of the deployment, building them takes a while, prefer
+    // shared_params
+    pub fn new() -> Self {
+        Params {
+            hash_params: Bn256PoseidonParams::new_for_params::<BlakeHasher>(5, 6, 52, 126),
+            sign_params: AltJubjubBn256::new(),
+        }
+    }
+}
+
+impl Default for Params<Bn256> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn cached() -> &'static Arc<Params<Bn256>> {
+    static PARAMS: OnceLock<Arc<Params<Bn256>>> = OnceLock::new();
+    PARAMS.get_or_init(|| Arc::new(Params::new()))
+}
+
+// built once on first use, the same instance everywhere after that
+pub fn shared_params() -> Arc<Params<Bn256>> {
+    Arc::clone(cached())
+}
+
+pub fn poseidon_params() -> &'static Bn256PoseidonParams {
+    &cached().hash_params
+}
+
+pub fn jubjub_params() -> &'static AltJubjubBn256 {
+    &cached().sign_params
+}
+
+// the hasher for comparing against poseidon, not part of the deployment
+pub fn rescue_params() -> &'static Bn256RescueParams {
+    static PARAMS: OnceLock<Bn256RescueParams> = OnceLock::new();
+    PARAMS.get_or_init(Bn256RescueParams::new::<BlakeHasher>)
+}
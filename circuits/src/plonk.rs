@@ -0,0 +1,1285 @@
+use std::io::{ Read, Write };
+
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    Index,
+    LinearCombination,
+    SynthesisError,
+    Variable,
+};
+
+use pairing_ce::{
+    CurveAffine,
+    CurveProjective,
+    Engine,
+    bn256::{ Bn256, Fq12, Fr, FrRepr, G1, G1Affine, G2Affine },
+};
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+use rand::{ Rng, thread_rng };
+
+use tiny_keccak::{ Hasher, Keccak };
+
+use crate::deposit_circuit::DepositBatchCircuit;
+use crate::family::{ BatchConfig, CircuitFamily };
+use crate::layout::CircuitLayout;
+use crate::params::shared_params;
+use crate::proof_batch::keccak_challenge;
+use crate::prover::{ G1_BYTES, G2_BYTES, invalid_data, g1_to_bytes, g2_to_bytes, g1_from_bytes, g2_from_bytes };
+use crate::public_inputs::PublicInputs;
+use crate::tree::leaf::LeafVersion;
+use crate::error::OpenPlasmaError;
+use crate::instrument::timed_span;
+use crate::metrics::metrics;
+
+// the deposit batch proved with PLONK (Gabizon, Williamson, Ciobotaru 2019)
+// over KZG commitments. the setup is universal: one PlonkSrs serves every
+// BatchConfig up to its size, and plonk_setup only commits to the circuit.
+// the circuit is the same DepositBatchCircuit: its R1CS is recorded and each
+// constraint (A)(B) = (C) becomes one gate
+//   q_m a b + q_l a + q_r b + q_o c + q_c + PI = 0
+// after linear combinations of more than one variable are summed into a new
+// variable by addition gates. a variable used on several wires is held equal
+// by the permutation argument, so the gates accept exactly the witnesses the
+// constraints do, with the same public inputs in the same order
+
+// the blinding of the wires and of z raises the degrees above the domain
+// size n, the quotient splits into parts of up to n + 6 coefficients
+const BLINDED_DEGREE: usize = 6;
+
+const VK_FILE_MAGIC: &[u8; 4] = b"OPPV";
+const PK_FILE_MAGIC: &[u8; 4] = b"OPPP";
+
+// the powers of a secret x in G1 and x in G2, a universal trusted setup: a
+// single one serves circuits of any config up to max_gates rows
+#[derive(Clone)]
+pub struct PlonkSrs {
+    pub g1_powers: Vec::<G1Affine>,
+    pub g2: G2Affine,
+    pub g2_x: G2Affine,
+}
+
+impl PlonkSrs {
+    // with a fresh secret that is dropped here, as prover::generate_parameters
+    // does for the groth16 keys
+    pub fn generate<R: Rng>(max_gates: usize, rng: &mut R) -> Self {
+        let x: Fr = rng.gen();
+        let g1 = G1Affine::one();
+        let mut power = Fr::one();
+        let g1_powers = (0..max_gates.next_power_of_two() + BLINDED_DEGREE).map(|_| {
+            let point = g1.mul(power).into_affine();
+            power.mul_assign(&x);
+            point
+        }).collect();
+
+        PlonkSrs {
+            g1_powers,
+            g2: G2Affine::one(),
+            g2_x: G2Affine::one().mul(x).into_affine(),
+        }
+    }
+
+    pub fn max_gates(&self) -> usize {
+        self.g1_powers.len() - BLINDED_DEGREE
+    }
+}
+
+// the commitments to the selectors q_m, q_l, q_r, q_o, q_c and to the
+// permutation polynomials of the a, b and c wires. the layout is the one of
+// the groth16 key of the config, the inputs come in its order
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlonkVerifyingKey {
+    pub config: BatchConfig,
+    pub domain_size: usize,
+    pub layout: CircuitLayout,
+    pub selectors: [G1Affine; 5],
+    pub sigmas: [G1Affine; 3],
+    pub g2: G2Affine,
+    pub g2_x: G2Affine,
+}
+
+// the srs powers the prover commits with and a hash of the gates, a circuit
+// of another shape is refused before proving
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlonkProvingKey {
+    pub vk: PlonkVerifyingKey,
+    pub shape_hash: [u8; 32],
+    pub g1_powers: Vec::<G1Affine>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlonkProof {
+    pub a: G1Affine,
+    pub b: G1Affine,
+    pub c: G1Affine,
+    pub z: G1Affine,
+    pub t_lo: G1Affine,
+    pub t_mid: G1Affine,
+    pub t_hi: G1Affine,
+    pub w_zeta: G1Affine,
+    pub w_zeta_omega: G1Affine,
+    pub a_eval: Fr,
+    pub b_eval: Fr,
+    pub c_eval: Fr,
+    pub sigma1_eval: Fr,
+    pub sigma2_eval: Fr,
+    pub z_omega_eval: Fr,
+}
+
+// commits to the gates of the config's empty circuit, no secret is involved
+pub fn plonk_setup(
+    config: BatchConfig,
+    srs: &PlonkSrs,
+) -> Result<(PlonkProvingKey, PlonkVerifyingKey), OpenPlasmaError> {
+    let family = CircuitFamily::new(shared_params());
+    let gates = Gates::synthesize(family.empty_circuit(config), false)?;
+    let n = gates.domain_size();
+    if n + BLINDED_DEGREE > srs.g1_powers.len() {
+        return Err(SynthesisError::PolynomialDegreeTooLarge.into());
+    }
+
+    let domain = Domain::new(n)?;
+    let preprocessed = Preprocessed::new(&gates, &domain);
+    let g1_powers = srs.g1_powers[..n + BLINDED_DEGREE].to_vec();
+    let vk = PlonkVerifyingKey {
+        config,
+        domain_size: n,
+        layout: family.layout(config)?,
+        selectors: [
+            commit(&g1_powers, &preprocessed.selectors[0]),
+            commit(&g1_powers, &preprocessed.selectors[1]),
+            commit(&g1_powers, &preprocessed.selectors[2]),
+            commit(&g1_powers, &preprocessed.selectors[3]),
+            commit(&g1_powers, &preprocessed.selectors[4]),
+        ],
+        sigmas: [
+            commit(&g1_powers, &preprocessed.sigmas[0]),
+            commit(&g1_powers, &preprocessed.sigmas[1]),
+            commit(&g1_powers, &preprocessed.sigmas[2]),
+        ],
+        g2: srs.g2,
+        g2_x: srs.g2_x,
+    };
+    let pk = PlonkProvingKey {
+        vk: vk.clone(),
+        shape_hash: gates.shape_hash(),
+        g1_powers,
+    };
+
+    Ok((pk, vk))
+}
+
+// the circuit has to be of the key's config and satisfied, an unsatisfied
+// one is SynthesisError::Unsatisfiable instead of a proof that never verifies
+pub fn plonk_prove(
+    pk: &PlonkProvingKey,
+    circuit: DepositBatchCircuit<Bn256>,
+) -> Result<PlonkProof, OpenPlasmaError> {
+    let config = BatchConfig {
+        deposit_batch: circuit.deposit_batch,
+        account_depth: circuit.account_depth,
+        token_depth: circuit.token_depth,
+        leaf_version: circuit.leaf_version,
+    };
+    if config != pk.vk.config {
+        return Err(invalid_data(format!("circuit of {:?} for a key of {:?}", config, pk.vk.config)));
+    }
+
+    let gates = Gates::synthesize(circuit, true)?;
+    if gates.shape_hash() != pk.shape_hash || gates.domain_size() != pk.vk.domain_size {
+        return Err(invalid_data(format!("gates do not match the key of {:?}", config)));
+    }
+    gates.check_satisfied()?;
+
+    let _span = timed_span!(
+        "plonk.prove",
+        batch = config.deposit_batch,
+        depth = config.account_depth,
+        gates = gates.domain_size(),
+    );
+    let proof = prove(pk, &gates, &mut thread_rng())?;
+    metrics().proof_generated();
+
+    Ok(proof)
+}
+
+// the inputs are put in the layout's order by meaning, as prover::verify_block does
+pub fn plonk_verify(
+    vk: &PlonkVerifyingKey,
+    proof: &PlonkProof,
+    public_inputs: &PublicInputs<Bn256>,
+) -> bool {
+    match vk.layout.input_vector(public_inputs) {
+        Some(inputs) => verify(vk, proof, &inputs),
+        None => false,
+    }
+}
+
+impl PlonkVerifyingKey {
+    // magic, the config as in the groth16 key file header, the domain size,
+    // the commitments and the G2 points in the encoding of the precompiles.
+    // the layout is not written, it follows from the config
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), OpenPlasmaError> {
+        writer.write_all(VK_FILE_MAGIC)?;
+        write_config(&self.config, &mut writer)?;
+        writer.write_all(&(self.domain_size as u64).to_be_bytes())?;
+        for point in self.selectors.iter().chain(self.sigmas.iter()) {
+            writer.write_all(&g1_to_bytes(point))?;
+        }
+        writer.write_all(&g2_to_bytes(&self.g2))?;
+        writer.write_all(&g2_to_bytes(&self.g2_x))?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, OpenPlasmaError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != VK_FILE_MAGIC {
+            return Err(invalid_data("not a PLONK verifying key".to_string()));
+        }
+        let config = read_config(&mut reader)?;
+        let domain_size = read_u64(&mut reader)? as usize;
+        if !domain_size.is_power_of_two() {
+            return Err(invalid_data(format!("domain size {} is not a power of two", domain_size)));
+        }
+
+        let mut points = [G1Affine::zero(); 8];
+        for point in points.iter_mut() {
+            *point = read_g1(&mut reader)?;
+        }
+        let g2 = read_g2(&mut reader)?;
+        let g2_x = read_g2(&mut reader)?;
+
+        Ok(PlonkVerifyingKey {
+            config,
+            domain_size,
+            layout: CircuitFamily::new(shared_params()).layout(config)?,
+            selectors: [points[0], points[1], points[2], points[3], points[4]],
+            sigmas: [points[5], points[6], points[7]],
+            g2,
+            g2_x,
+        })
+    }
+}
+
+impl PlonkProvingKey {
+    // magic, the verifying key, the shape hash and the srs powers
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), OpenPlasmaError> {
+        writer.write_all(PK_FILE_MAGIC)?;
+        self.vk.write(&mut writer)?;
+        writer.write_all(&self.shape_hash)?;
+        writer.write_all(&(self.g1_powers.len() as u64).to_be_bytes())?;
+        for point in self.g1_powers.iter() {
+            writer.write_all(&g1_to_bytes(point))?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, OpenPlasmaError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != PK_FILE_MAGIC {
+            return Err(invalid_data("not a PLONK proving key".to_string()));
+        }
+        let vk = PlonkVerifyingKey::read(&mut reader)?;
+        let mut shape_hash = [0u8; 32];
+        reader.read_exact(&mut shape_hash)?;
+
+        let count = read_u64(&mut reader)? as usize;
+        if count != vk.domain_size + BLINDED_DEGREE {
+            return Err(invalid_data(format!("{} srs powers for a domain of {}", count, vk.domain_size)));
+        }
+        let g1_powers = (0..count).map(|_| read_g1(&mut reader)).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(PlonkProvingKey { vk, shape_hash, g1_powers })
+    }
+}
+
+fn write_config<W: Write>(config: &BatchConfig, writer: &mut W) -> Result<(), OpenPlasmaError> {
+    if config.deposit_batch > u32::MAX as usize || config.account_depth > u8::MAX as usize
+        || config.token_depth > u8::MAX as usize
+    {
+        return Err(invalid_data(format!("{:?} doesn't fit the key header", config)));
+    }
+    writer.write_all(&(config.deposit_batch as u32).to_be_bytes())?;
+    writer.write_all(&[config.account_depth as u8, config.token_depth as u8, config.leaf_version.number()])?;
+    Ok(())
+}
+
+fn read_config<R: Read>(reader: &mut R) -> Result<BatchConfig, OpenPlasmaError> {
+    let mut header = [0u8; 7];
+    reader.read_exact(&mut header)?;
+    Ok(BatchConfig {
+        deposit_batch: u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize,
+        account_depth: header[4] as usize,
+        token_depth: header[5] as usize,
+        leaf_version: LeafVersion::from_number(header[6]).ok_or(
+            invalid_data(format!("unknown leaf version {}", header[6]))
+        )?,
+    })
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, OpenPlasmaError> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_g1<R: Read>(reader: &mut R) -> Result<G1Affine, OpenPlasmaError> {
+    let mut bytes = [0u8; G1_BYTES];
+    reader.read_exact(&mut bytes)?;
+    g1_from_bytes(&bytes)
+}
+
+fn read_g2<R: Read>(reader: &mut R) -> Result<G2Affine, OpenPlasmaError> {
+    let mut bytes = [0u8; G2_BYTES];
+    reader.read_exact(&mut bytes)?;
+    g2_from_bytes(&bytes)
+}
+
+// records the R1CS of a synthesis. the assignments are kept when proving;
+// for setup they are missing and the closures' errors are ignored, as the
+// groth16 key generation does
+struct RecordingConstraintSystem {
+    with_witness: bool,
+    // input 0 is the constant one
+    inputs: Vec::<Fr>,
+    aux: Vec::<Fr>,
+    constraints: Vec::<[Vec::<(Index, Fr)>; 3]>,
+}
+
+impl RecordingConstraintSystem {
+    fn assign<F>(&self, f: F) -> Result<Fr, SynthesisError>
+        where F: FnOnce() -> Result<Fr, SynthesisError>,
+    {
+        if self.with_witness {
+            f()
+        } else {
+            Ok(Fr::zero())
+        }
+    }
+}
+
+fn terms(lc: LinearCombination<Bn256>) -> Vec::<(Index, Fr)> {
+    lc.as_ref().iter().map(|(variable, coefficient)| (variable.get_unchecked(), *coefficient)).collect()
+}
+
+impl ConstraintSystem<Bn256> for RecordingConstraintSystem {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(
+        &mut self,
+        _: A,
+        f: F,
+    ) -> Result<Variable, SynthesisError>
+        where F: FnOnce() -> Result<Fr, SynthesisError>,
+              A: FnOnce() -> AR, AR: Into<String>,
+    {
+        let value = self.assign(f)?;
+        self.aux.push(value);
+        Ok(Variable::new_unchecked(Index::Aux(self.aux.len() - 1)))
+    }
+
+    fn alloc_input<F, A, AR>(
+        &mut self,
+        _: A,
+        f: F,
+    ) -> Result<Variable, SynthesisError>
+        where F: FnOnce() -> Result<Fr, SynthesisError>,
+              A: FnOnce() -> AR, AR: Into<String>,
+    {
+        let value = self.assign(f)?;
+        self.inputs.push(value);
+        Ok(Variable::new_unchecked(Index::Input(self.inputs.len() - 1)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(
+        &mut self,
+        _: A,
+        a: LA,
+        b: LB,
+        c: LC,
+    )
+        where A: FnOnce() -> AR, AR: Into<String>,
+              LA: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+              LB: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+              LC: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+    {
+        self.constraints.push([
+            terms(a(LinearCombination::zero())),
+            terms(b(LinearCombination::zero())),
+            terms(c(LinearCombination::zero())),
+        ]);
+    }
+
+    fn push_namespace<NR, N>(&mut self, _: N)
+        where NR: Into<String>, N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+// the rows of the circuit. variable 0 is zero and sits on the unused wires,
+// variables 1 to num_inputs are the public inputs, on the first rows, then
+// come the aux variables of the R1CS and the sums of linear combinations
+struct Gates {
+    // q_m, q_l, q_r, q_o and q_c of every row
+    selectors: [Vec::<Fr>; 5],
+    // the variable on the a, b and c wire of every row
+    wires: [Vec::<usize>; 3],
+    // all zero without witness
+    values: Vec::<Fr>,
+    num_inputs: usize,
+}
+
+impl Gates {
+    fn synthesize<C: Circuit<Bn256>>(circuit: C, with_witness: bool) -> Result<Self, SynthesisError> {
+        let mut cs = RecordingConstraintSystem {
+            with_witness,
+            inputs: vec![Fr::one()],
+            aux: Vec::new(),
+            constraints: Vec::new(),
+        };
+        circuit.synthesize(&mut cs)?;
+
+        let num_inputs = cs.inputs.len() - 1;
+        let mut values = vec![Fr::zero()];
+        values.extend_from_slice(&cs.inputs[1..]);
+        values.extend_from_slice(&cs.aux);
+        let mut gates = Gates {
+            selectors: Default::default(),
+            wires: Default::default(),
+            values,
+            num_inputs,
+        };
+
+        // q_l a + PI = 0 with PI = -input on the row of every input
+        for input in 1..=num_inputs {
+            gates.push([input, 0, 0], [Fr::zero(), Fr::one(), Fr::zero(), Fr::zero(), Fr::zero()]);
+        }
+
+        // (alpha u + kappa)(beta v + lambda) = gamma w + mu
+        let variable = |index: &Index| match *index {
+            Index::Input(0) => None,
+            Index::Input(i) => Some(i),
+            Index::Aux(i) => Some(1 + num_inputs + i),
+        };
+        for [a, b, c] in cs.constraints.iter() {
+            let (alpha, u, kappa) = gates.reduce(a, &variable);
+            let (beta, v, lambda) = gates.reduce(b, &variable);
+            let (gamma, w, mu) = gates.reduce(c, &variable);
+
+            let mut q_m = alpha;
+            q_m.mul_assign(&beta);
+            let mut q_l = alpha;
+            q_l.mul_assign(&lambda);
+            let mut q_r = kappa;
+            q_r.mul_assign(&beta);
+            let mut q_o = gamma;
+            q_o.negate();
+            let mut q_c = kappa;
+            q_c.mul_assign(&lambda);
+            q_c.sub_assign(&mu);
+            gates.push([u, v, w], [q_m, q_l, q_r, q_o, q_c]);
+        }
+
+        let n = gates.domain_size();
+        for selector in gates.selectors.iter_mut() {
+            selector.resize(n, Fr::zero());
+        }
+        for wire in gates.wires.iter_mut() {
+            wire.resize(n, 0);
+        }
+
+        Ok(gates)
+    }
+
+    fn push(&mut self, wires: [usize; 3], selectors: [Fr; 5]) {
+        for (column, variable) in self.wires.iter_mut().zip(wires.iter()) {
+            column.push(*variable);
+        }
+        for (column, value) in self.selectors.iter_mut().zip(selectors.iter()) {
+            column.push(*value);
+        }
+    }
+
+    fn new_variable(&mut self, value: Fr) -> usize {
+        self.values.push(value);
+        self.values.len() - 1
+    }
+
+    // a linear combination as coefficient * variable + constant, summing
+    // more than one variable into a new one with addition gates
+    fn reduce<V>(&mut self, lc: &[(Index, Fr)], variable: &V) -> (Fr, usize, Fr)
+        where V: Fn(&Index) -> Option<usize>,
+    {
+        let mut constant = Fr::zero();
+        let mut terms: Vec::<(usize, Fr)> = Vec::new();
+        for (index, coefficient) in lc.iter() {
+            match variable(index) {
+                None => constant.add_assign(coefficient),
+                Some(v) => match terms.iter_mut().find(|(term, _)| *term == v) {
+                    Some((_, sum)) => sum.add_assign(coefficient),
+                    None => terms.push((v, *coefficient)),
+                },
+            }
+        }
+        // some gadgets order the terms by the witness, the gates must not
+        terms.retain(|(_, coefficient)| !coefficient.is_zero());
+        terms.sort_by_key(|(variable, _)| *variable);
+
+        match terms.len() {
+            0 => (Fr::zero(), 0, constant),
+            1 => (terms[0].1, terms[0].0, constant),
+            _ => {
+                let mut minus_one = Fr::one();
+                minus_one.negate();
+
+                // c1 x1 + c2 x2 + constant - sum = 0
+                let (x1, c1) = terms[0];
+                let (x2, c2) = terms[1];
+                let mut value = scaled(&self.values[x1], &c1);
+                value.add_assign(&scaled(&self.values[x2], &c2));
+                value.add_assign(&constant);
+                let mut sum = self.new_variable(value);
+                self.push([x1, x2, sum], [Fr::zero(), c1, c2, minus_one, constant]);
+
+                // sum + c x - next = 0
+                for (x, c) in terms[2..].iter() {
+                    let mut value = self.values[sum];
+                    value.add_assign(&scaled(&self.values[*x], c));
+                    let next = self.new_variable(value);
+                    self.push([sum, *x, next], [Fr::zero(), Fr::one(), *c, minus_one, Fr::zero()]);
+                    sum = next;
+                }
+                (Fr::one(), sum, Fr::zero())
+            },
+        }
+    }
+
+    fn domain_size(&self) -> usize {
+        self.wires[0].len().next_power_of_two()
+    }
+
+    fn inputs(&self) -> &[Fr] {
+        &self.values[1..=self.num_inputs]
+    }
+
+    fn wire_values(&self, column: usize) -> Vec::<Fr> {
+        self.wires[column].iter().map(|variable| self.values[*variable]).collect()
+    }
+
+    // keccak256 of the selectors and wires, rows in order
+    fn shape_hash(&self) -> [u8; 32] {
+        let mut hasher = Keccak::v256();
+        hasher.update(&(self.num_inputs as u64).to_be_bytes());
+        for selector in self.selectors.iter() {
+            for value in selector.iter() {
+                let mut bytes = Vec::new();
+                value.into_repr().write_be(&mut bytes).unwrap();
+                hasher.update(&bytes);
+            }
+        }
+        for wire in self.wires.iter() {
+            for variable in wire.iter() {
+                hasher.update(&(*variable as u64).to_be_bytes());
+            }
+        }
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+        hash
+    }
+
+    fn check_satisfied(&self) -> Result<(), SynthesisError> {
+        let [a, b, c] = [self.wire_values(0), self.wire_values(1), self.wire_values(2)];
+        for row in 0..a.len() {
+            let mut value = gate_value(
+                [&self.selectors[0][row], &self.selectors[1][row], &self.selectors[2][row],
+                 &self.selectors[3][row], &self.selectors[4][row]],
+                &a[row], &b[row], &c[row],
+            );
+            if row < self.num_inputs {
+                value.sub_assign(&self.inputs()[row]);
+            }
+            if !value.is_zero() {
+                return Err(SynthesisError::Unsatisfiable);
+            }
+        }
+        Ok(())
+    }
+}
+
+// q_m a b + q_l a + q_r b + q_o c + q_c
+fn gate_value(selectors: [&Fr; 5], a: &Fr, b: &Fr, c: &Fr) -> Fr {
+    let mut value = *a;
+    value.mul_assign(b);
+    value.mul_assign(selectors[0]);
+    value.add_assign(&scaled(a, selectors[1]));
+    value.add_assign(&scaled(b, selectors[2]));
+    value.add_assign(&scaled(c, selectors[3]));
+    value.add_assign(selectors[4]);
+    value
+}
+
+// the selector and permutation polynomials in coefficients, and the
+// permutation as the labels sigma*(i) of the wire each wire is sent to
+struct Preprocessed {
+    selectors: [Vec::<Fr>; 5],
+    sigmas: [Vec::<Fr>; 3],
+    sigma_labels: [Vec::<Fr>; 3],
+}
+
+impl Preprocessed {
+    fn new(gates: &Gates, domain: &Domain) -> Self {
+        let n = domain.size;
+        let mut cycles = vec![Vec::new(); gates.values.len()];
+        for (column, wire) in gates.wires.iter().enumerate() {
+            for (row, variable) in wire.iter().enumerate() {
+                cycles[*variable].push(column * n + row);
+            }
+        }
+
+        // the label of wire column * n + row is k_column omega^row
+        let omega_powers = powers(&domain.omega, n);
+        let shifts = coset_shifts();
+        let mut sigma_labels: [Vec::<Fr>; 3] = Default::default();
+        for labels in sigma_labels.iter_mut() {
+            labels.resize(n, Fr::zero());
+        }
+        for cycle in cycles.iter() {
+            for (i, position) in cycle.iter().enumerate() {
+                let next = cycle[(i + 1) % cycle.len()];
+                sigma_labels[position / n][position % n] = scaled(&omega_powers[next % n], &shifts[next / n]);
+            }
+        }
+
+        Preprocessed {
+            selectors: [
+                domain.ifft(gates.selectors[0].clone()),
+                domain.ifft(gates.selectors[1].clone()),
+                domain.ifft(gates.selectors[2].clone()),
+                domain.ifft(gates.selectors[3].clone()),
+                domain.ifft(gates.selectors[4].clone()),
+            ],
+            sigmas: [
+                domain.ifft(sigma_labels[0].clone()),
+                domain.ifft(sigma_labels[1].clone()),
+                domain.ifft(sigma_labels[2].clone()),
+            ],
+            sigma_labels,
+        }
+    }
+}
+
+// 1, k1 and k2 for the wire columns: the multiplicative generator g and g^2
+// are in no coset of the domain but their own, as the field's two-adicity
+// is far below the order of g
+fn coset_shifts() -> [Fr; 3] {
+    let g = Fr::multiplicative_generator();
+    let mut g_squared = g;
+    g_squared.square();
+    [Fr::one(), g, g_squared]
+}
+
+fn prove<R: Rng>(
+    pk: &PlonkProvingKey,
+    gates: &Gates,
+    rng: &mut R,
+) -> Result<PlonkProof, SynthesisError> {
+    let vk = &pk.vk;
+    let n = vk.domain_size;
+    let domain = Domain::new(n)?;
+    let preprocessed = Preprocessed::new(gates, &domain);
+    let shifts = coset_shifts();
+    let mut transcript = Transcript::new(vk, gates.inputs());
+
+    // round 1: the wires, blinded with (b0 + b1 X) Z_H
+    let wire_values = [gates.wire_values(0), gates.wire_values(1), gates.wire_values(2)];
+    let wires: Vec<_> = wire_values.iter().map(|values| {
+        blind(domain.ifft(values.clone()), &[rng.gen(), rng.gen()], n)
+    }).collect();
+    let wire_commitments: Vec<_> = wires.iter().map(|wire| commit(&pk.g1_powers, wire)).collect();
+    for commitment in wire_commitments.iter() {
+        transcript.append_g1(commitment);
+    }
+    let beta = transcript.challenge();
+    let gamma = transcript.challenge();
+
+    // round 2: the grand product of the permutation, z(1) = 1 and
+    // z(omega^(i + 1)) = z(omega^i) prod (w + beta id + gamma) / (w + beta sigma + gamma)
+    let omega_powers = powers(&domain.omega, n);
+    let mut z_values = Vec::with_capacity(n);
+    let mut product = Fr::one();
+    for row in 0..n {
+        z_values.push(product);
+        let mut numerator = Fr::one();
+        let mut denominator = Fr::one();
+        for column in 0..3 {
+            let id = scaled(&omega_powers[row], &shifts[column]);
+            numerator.mul_assign(&permutation_factor(&wire_values[column][row], &id, &beta, &gamma));
+            denominator.mul_assign(&permutation_factor(
+                &wire_values[column][row], &preprocessed.sigma_labels[column][row], &beta, &gamma));
+        }
+        product.mul_assign(&numerator);
+        product.mul_assign(&denominator.inverse().ok_or(SynthesisError::Unsatisfiable)?);
+    }
+    if product != Fr::one() {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+    let z = blind(domain.ifft(z_values), &[rng.gen(), rng.gen(), rng.gen()], n);
+    let z_commitment = commit(&pk.g1_powers, &z);
+    transcript.append_g1(&z_commitment);
+    let alpha = transcript.challenge();
+
+    // round 3: the quotient, evaluated on a coset of 8 n where every term fits
+    let big_domain = Domain::new(8 * n)?;
+    let on_coset = |coefficients: &[Fr]| big_domain.coset_fft(coefficients.to_vec());
+    let wire_evals: Vec<_> = wires.iter().map(|wire| on_coset(wire)).collect();
+    let z_evals = on_coset(&z);
+    let z_omega_evals = on_coset(&scale_powers(&z, &domain.omega));
+    let selector_evals: Vec<_> = preprocessed.selectors.iter().map(|selector| on_coset(selector)).collect();
+    let sigma_evals: Vec<_> = preprocessed.sigmas.iter().map(|sigma| on_coset(sigma)).collect();
+    let mut public_values = vec![Fr::zero(); n];
+    for (value, input) in public_values.iter_mut().zip(gates.inputs().iter()) {
+        *value = *input;
+        value.negate();
+    }
+    let public_evals = on_coset(&domain.ifft(public_values));
+    // L_1 = (X^n - 1) / (n (X - 1)) = (1 + X + .. + X^(n - 1)) / n
+    let l1_evals = on_coset(&vec![domain.size_inv; n]);
+
+    let mut x = Fr::multiplicative_generator();
+    let mut quotient_evals = Vec::with_capacity(8 * n);
+    let mut alpha_squared = alpha;
+    alpha_squared.square();
+    for j in 0..8 * n {
+        let at = |evals: &Vec::<Fr>| evals[j];
+        let mut value = gate_value(
+            [&selector_evals[0][j], &selector_evals[1][j], &selector_evals[2][j],
+             &selector_evals[3][j], &selector_evals[4][j]],
+            &wire_evals[0][j], &wire_evals[1][j], &wire_evals[2][j],
+        );
+        value.add_assign(&public_evals[j]);
+
+        let mut permutation = at(&z_evals);
+        let mut shifted = at(&z_omega_evals);
+        for column in 0..3 {
+            permutation.mul_assign(&permutation_factor(&wire_evals[column][j], &scaled(&x, &shifts[column]), &beta, &gamma));
+            shifted.mul_assign(&permutation_factor(&wire_evals[column][j], &sigma_evals[column][j], &beta, &gamma));
+        }
+        permutation.sub_assign(&shifted);
+        value.add_assign(&scaled(&permutation, &alpha));
+
+        let mut first = at(&z_evals);
+        first.sub_assign(&Fr::one());
+        first.mul_assign(&l1_evals[j]);
+        value.add_assign(&scaled(&first, &alpha_squared));
+
+        let mut vanishing = x.pow([n as u64]);
+        vanishing.sub_assign(&Fr::one());
+        value.mul_assign(&vanishing.inverse().ok_or(SynthesisError::DivisionByZero)?);
+        quotient_evals.push(value);
+
+        x.mul_assign(&big_domain.omega);
+    }
+    let mut quotient = big_domain.coset_ifft(quotient_evals);
+    if quotient[3 * n + BLINDED_DEGREE..].iter().any(|coefficient| !coefficient.is_zero()) {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+    quotient.truncate(3 * n + BLINDED_DEGREE);
+    let t_hi = quotient.split_off(2 * n);
+    let t_mid = quotient.split_off(n);
+    let t_lo = quotient;
+    let t_commitments = [commit(&pk.g1_powers, &t_lo), commit(&pk.g1_powers, &t_mid), commit(&pk.g1_powers, &t_hi)];
+    for commitment in t_commitments.iter() {
+        transcript.append_g1(commitment);
+    }
+    let zeta = transcript.challenge();
+
+    // round 4: the evaluations at zeta and zeta omega
+    let mut zeta_omega = zeta;
+    zeta_omega.mul_assign(&domain.omega);
+    let a_eval = evaluate(&wires[0], &zeta);
+    let b_eval = evaluate(&wires[1], &zeta);
+    let c_eval = evaluate(&wires[2], &zeta);
+    let sigma1_eval = evaluate(&preprocessed.sigmas[0], &zeta);
+    let sigma2_eval = evaluate(&preprocessed.sigmas[1], &zeta);
+    let z_omega_eval = evaluate(&z, &zeta_omega);
+    for eval in [a_eval, b_eval, c_eval, sigma1_eval, sigma2_eval, z_omega_eval].iter() {
+        transcript.append_fr(eval);
+    }
+    let v = transcript.challenge();
+
+    // round 5: the linearization r, zero at zeta, and the openings
+    let evals = Evaluations { a: a_eval, b: b_eval, c: c_eval, sigma1: sigma1_eval, sigma2: sigma2_eval, z_omega: z_omega_eval };
+    let challenges = Challenges { beta, gamma, alpha, zeta };
+    let scalars = linearization(&domain, gates.inputs(), &evals, &challenges);
+    let mut opening = vec![Fr::zero(); n + BLINDED_DEGREE];
+    add_scaled(&mut opening, &preprocessed.selectors[0], &scalars.q_m);
+    add_scaled(&mut opening, &preprocessed.selectors[1], &a_eval);
+    add_scaled(&mut opening, &preprocessed.selectors[2], &b_eval);
+    add_scaled(&mut opening, &preprocessed.selectors[3], &c_eval);
+    add_scaled(&mut opening, &preprocessed.selectors[4], &Fr::one());
+    add_scaled(&mut opening, &z, &scalars.z);
+    add_scaled(&mut opening, &preprocessed.sigmas[2], &scalars.sigma3);
+    add_scaled(&mut opening, &t_lo, &scalars.t_lo);
+    add_scaled(&mut opening, &t_mid, &scalars.t_mid);
+    add_scaled(&mut opening, &t_hi, &scalars.t_hi);
+    opening[0].add_assign(&scalars.constant);
+    if !evaluate(&opening, &zeta).is_zero() {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    let mut power = v;
+    for (polynomial, eval) in [&wires[0], &wires[1], &wires[2], &preprocessed.sigmas[0], &preprocessed.sigmas[1]].iter()
+        .zip([a_eval, b_eval, c_eval, sigma1_eval, sigma2_eval].iter())
+    {
+        add_scaled(&mut opening, polynomial, &power);
+        opening[0].sub_assign(&scaled(eval, &power));
+        power.mul_assign(&v);
+    }
+    let w_zeta = divide(&opening, &zeta);
+    let mut shifted_z = z.clone();
+    shifted_z[0].sub_assign(&z_omega_eval);
+    let w_zeta_omega = divide(&shifted_z, &zeta_omega);
+
+    Ok(PlonkProof {
+        a: wire_commitments[0],
+        b: wire_commitments[1],
+        c: wire_commitments[2],
+        z: z_commitment,
+        t_lo: t_commitments[0],
+        t_mid: t_commitments[1],
+        t_hi: t_commitments[2],
+        w_zeta: commit(&pk.g1_powers, &w_zeta),
+        w_zeta_omega: commit(&pk.g1_powers, &w_zeta_omega),
+        a_eval,
+        b_eval,
+        c_eval,
+        sigma1_eval,
+        sigma2_eval,
+        z_omega_eval,
+    })
+}
+
+fn verify(vk: &PlonkVerifyingKey, proof: &PlonkProof, inputs: &[Fr]) -> bool {
+    let domain = match Domain::new(vk.domain_size) {
+        Ok(domain) => domain,
+        Err(_) => return false,
+    };
+    if inputs.len() != vk.layout.inputs.len() || inputs.len() >= domain.size {
+        return false;
+    }
+
+    let mut transcript = Transcript::new(vk, inputs);
+    for commitment in [proof.a, proof.b, proof.c].iter() {
+        transcript.append_g1(commitment);
+    }
+    let beta = transcript.challenge();
+    let gamma = transcript.challenge();
+    transcript.append_g1(&proof.z);
+    let alpha = transcript.challenge();
+    for commitment in [proof.t_lo, proof.t_mid, proof.t_hi].iter() {
+        transcript.append_g1(commitment);
+    }
+    let zeta = transcript.challenge();
+    let evals = Evaluations {
+        a: proof.a_eval,
+        b: proof.b_eval,
+        c: proof.c_eval,
+        sigma1: proof.sigma1_eval,
+        sigma2: proof.sigma2_eval,
+        z_omega: proof.z_omega_eval,
+    };
+    for eval in [evals.a, evals.b, evals.c, evals.sigma1, evals.sigma2, evals.z_omega].iter() {
+        transcript.append_fr(eval);
+    }
+    let v = transcript.challenge();
+    transcript.append_g1(&proof.w_zeta);
+    transcript.append_g1(&proof.w_zeta_omega);
+    let u = transcript.challenge();
+
+    // F = [r] - r0 + sum v^i ([p_i] - p_i(zeta)) + u ([z] - z(zeta omega)),
+    // the points and scalars of the commitments and of the generator
+    let challenges = Challenges { beta, gamma, alpha, zeta };
+    let scalars = linearization(&domain, inputs, &evals, &challenges);
+    let mut z_scalar = scalars.z;
+    z_scalar.add_assign(&u);
+    let mut points = vec![
+        (vk.selectors[0], scalars.q_m),
+        (vk.selectors[1], evals.a),
+        (vk.selectors[2], evals.b),
+        (vk.selectors[3], evals.c),
+        (vk.selectors[4], Fr::one()),
+        (proof.z, z_scalar),
+        (vk.sigmas[2], scalars.sigma3),
+        (proof.t_lo, scalars.t_lo),
+        (proof.t_mid, scalars.t_mid),
+        (proof.t_hi, scalars.t_hi),
+    ];
+    let mut generator_scalar = scalars.constant;
+    let mut power = v;
+    for (point, eval) in [proof.a, proof.b, proof.c, vk.sigmas[0], vk.sigmas[1]].iter()
+        .zip([evals.a, evals.b, evals.c, evals.sigma1, evals.sigma2].iter())
+    {
+        points.push((*point, power));
+        generator_scalar.sub_assign(&scaled(eval, &power));
+        power.mul_assign(&v);
+    }
+    generator_scalar.sub_assign(&scaled(&evals.z_omega, &u));
+
+    // e(W_zeta + u W_zeta_omega, x) = e(zeta W_zeta + u zeta omega W_zeta_omega + F, 1)
+    let mut zeta_omega = zeta;
+    zeta_omega.mul_assign(&domain.omega);
+    points.push((proof.w_zeta, zeta));
+    points.push((proof.w_zeta_omega, scaled(&u, &zeta_omega)));
+    points.push((G1Affine::one(), generator_scalar));
+    let mut right = G1::zero();
+    for (point, scalar) in points.iter() {
+        right.add_assign(&point.mul(*scalar));
+    }
+    right.negate();
+    let mut left = proof.w_zeta_omega.mul(u);
+    left.add_assign_mixed(&proof.w_zeta);
+
+    let pairs = [
+        (left.into_affine().prepare(), vk.g2_x.prepare()),
+        (right.into_affine().prepare(), vk.g2.prepare()),
+    ];
+    let refs: Vec<_> = pairs.iter().map(|(g1, g2)| (g1, g2)).collect();
+    match Bn256::final_exponentiation(&Bn256::miller_loop(refs.iter())) {
+        Some(result) => result == Fq12::one(),
+        None => false,
+    }
+}
+
+struct Evaluations {
+    a: Fr,
+    b: Fr,
+    c: Fr,
+    sigma1: Fr,
+    sigma2: Fr,
+    z_omega: Fr,
+}
+
+struct Challenges {
+    beta: Fr,
+    gamma: Fr,
+    alpha: Fr,
+    zeta: Fr,
+}
+
+// r = a b q_m + a q_l + b q_r + c q_o + q_c + PI(zeta)
+//   + alpha (a + beta zeta + gamma)(b + beta k1 zeta + gamma)(c + beta k2 zeta + gamma) z
+//   - alpha (a + beta s1 + gamma)(b + beta s2 + gamma)(c + beta S3 + gamma) z(zeta omega)
+//   + alpha^2 L_1(zeta) (z - 1)
+//   - Z_H(zeta) (t_lo + zeta^n t_mid + zeta^2n t_hi)
+// with the evaluations for a, b, c, s1, s2 and z(zeta omega), as the
+// scalars of q_m, z, S3 and the t parts and a constant
+struct Linearization {
+    q_m: Fr,
+    z: Fr,
+    sigma3: Fr,
+    t_lo: Fr,
+    t_mid: Fr,
+    t_hi: Fr,
+    constant: Fr,
+}
+
+fn linearization(
+    domain: &Domain,
+    inputs: &[Fr],
+    evals: &Evaluations,
+    challenges: &Challenges,
+) -> Linearization {
+    let Challenges { beta, gamma, alpha, zeta } = challenges;
+    let shifts = coset_shifts();
+    let mut zeta_n = zeta.pow([domain.size as u64]);
+    let mut vanishing = zeta_n;
+    vanishing.sub_assign(&Fr::one());
+
+    // L_i(zeta) = omega^i Z_H(zeta) / (n (zeta - omega^i)), PI(zeta) = -sum x_i L_i(zeta)
+    let lagrange = |omega_i: &Fr| {
+        let mut denominator = *zeta;
+        denominator.sub_assign(omega_i);
+        denominator.mul_assign(&Fr::from_str(&domain.size.to_string()).unwrap());
+        let mut value = vanishing;
+        value.mul_assign(omega_i);
+        // zeta on the domain is negligible, the check then fails
+        value.mul_assign(&denominator.inverse().unwrap_or_else(Fr::zero));
+        value
+    };
+    let mut public_eval = Fr::zero();
+    let mut omega_i = Fr::one();
+    for input in inputs.iter() {
+        public_eval.sub_assign(&scaled(input, &lagrange(&omega_i)));
+        omega_i.mul_assign(&domain.omega);
+    }
+    let l1_eval = lagrange(&Fr::one());
+
+    let mut alpha_squared = *alpha;
+    alpha_squared.square();
+
+    let mut z = *alpha;
+    for (eval, shift) in [evals.a, evals.b, evals.c].iter().zip(shifts.iter()) {
+        z.mul_assign(&permutation_factor(eval, &scaled(zeta, shift), beta, gamma));
+    }
+    z.add_assign(&scaled(&l1_eval, &alpha_squared));
+
+    let mut sigma_product = *alpha;
+    sigma_product.mul_assign(&permutation_factor(&evals.a, &evals.sigma1, beta, gamma));
+    sigma_product.mul_assign(&permutation_factor(&evals.b, &evals.sigma2, beta, gamma));
+    sigma_product.mul_assign(&evals.z_omega);
+    let mut sigma3 = sigma_product;
+    sigma3.mul_assign(beta);
+    sigma3.negate();
+
+    let mut constant = public_eval;
+    let mut c_gamma = evals.c;
+    c_gamma.add_assign(gamma);
+    constant.sub_assign(&scaled(&sigma_product, &c_gamma));
+    constant.sub_assign(&scaled(&l1_eval, &alpha_squared));
+
+    let mut q_m = evals.a;
+    q_m.mul_assign(&evals.b);
+    let mut t_lo = vanishing;
+    t_lo.negate();
+    let t_mid = scaled(&t_lo, &zeta_n);
+    zeta_n.square();
+    let t_hi = scaled(&t_lo, &zeta_n);
+
+    Linearization { q_m, z, sigma3, t_lo, t_mid, t_hi, constant }
+}
+
+// w + beta label + gamma
+fn permutation_factor(wire: &Fr, label: &Fr, beta: &Fr, gamma: &Fr) -> Fr {
+    let mut factor = scaled(label, beta);
+    factor.add_assign(wire);
+    factor.add_assign(gamma);
+    factor
+}
+
+// keccak fiat shamir over the key, the inputs and the prover's messages,
+// each challenge appended itself
+struct Transcript {
+    bytes: Vec::<u8>,
+}
+
+impl Transcript {
+    fn new(vk: &PlonkVerifyingKey, inputs: &[Fr]) -> Self {
+        let mut transcript = Transcript { bytes: b"openplasma plonk".to_vec() };
+        transcript.bytes.extend_from_slice(&(vk.domain_size as u64).to_be_bytes());
+        for point in vk.selectors.iter().chain(vk.sigmas.iter()) {
+            transcript.append_g1(point);
+        }
+        for input in inputs.iter() {
+            transcript.append_fr(input);
+        }
+        transcript
+    }
+
+    fn challenge(&mut self) -> Fr {
+        let challenge = keccak_challenge(&self.bytes);
+        self.append_fr(&challenge);
+        challenge
+    }
+
+    fn append_fr(&mut self, value: &Fr) {
+        value.into_repr().write_be(&mut self.bytes).unwrap();
+    }
+
+    fn append_g1(&mut self, point: &G1Affine) {
+        self.bytes.extend_from_slice(&g1_to_bytes(point));
+    }
+}
+
+// a radix 2 domain of the scalar field, omega a primitive size-th root of unity
+struct Domain {
+    size: usize,
+    omega: Fr,
+    omega_inv: Fr,
+    size_inv: Fr,
+}
+
+impl Domain {
+    fn new(size: usize) -> Result<Self, SynthesisError> {
+        let log_size = size.trailing_zeros();
+        if !size.is_power_of_two() || log_size > Fr::S {
+            return Err(SynthesisError::PolynomialDegreeTooLarge);
+        }
+        let mut omega = Fr::root_of_unity();
+        for _ in log_size..Fr::S {
+            omega.square();
+        }
+        Ok(Domain {
+            size,
+            omega,
+            omega_inv: omega.inverse().unwrap(),
+            size_inv: Fr::from_str(&size.to_string()).unwrap().inverse().unwrap(),
+        })
+    }
+
+    fn fft(&self, mut coefficients: Vec::<Fr>) -> Vec::<Fr> {
+        coefficients.resize(self.size, Fr::zero());
+        fft(&mut coefficients, &self.omega);
+        coefficients
+    }
+
+    fn ifft(&self, mut values: Vec::<Fr>) -> Vec::<Fr> {
+        values.resize(self.size, Fr::zero());
+        fft(&mut values, &self.omega_inv);
+        for value in values.iter_mut() {
+            value.mul_assign(&self.size_inv);
+        }
+        values
+    }
+
+    // the values at g omega^j for the multiplicative generator g
+    fn coset_fft(&self, coefficients: Vec::<Fr>) -> Vec::<Fr> {
+        self.fft(scale_powers(&coefficients, &Fr::multiplicative_generator()))
+    }
+
+    fn coset_ifft(&self, values: Vec::<Fr>) -> Vec::<Fr> {
+        let coefficients = self.ifft(values);
+        scale_powers(&coefficients, &Fr::multiplicative_generator().inverse().unwrap())
+    }
+}
+
+// in place, the values at omega^j of the coefficients
+fn fft(values: &mut [Fr], omega: &Fr) {
+    let n = values.len();
+    let log_n = n.trailing_zeros();
+    for k in 0..n {
+        let reversed = k.reverse_bits() >> (usize::BITS - log_n);
+        if n > 1 && k < reversed {
+            values.swap(k, reversed);
+        }
+    }
+
+    let mut half = 1;
+    while half < n {
+        let step = omega.pow([(n / (2 * half)) as u64]);
+        for start in (0..n).step_by(2 * half) {
+            let mut twiddle = Fr::one();
+            for j in start..start + half {
+                let mut odd = values[j + half];
+                odd.mul_assign(&twiddle);
+                let mut even = values[j];
+                even.sub_assign(&odd);
+                values[j + half] = even;
+                values[j].add_assign(&odd);
+                twiddle.mul_assign(&step);
+            }
+        }
+        half *= 2;
+    }
+}
+
+// (p - p(z)) / (X - z) by synthetic division, for p(z) = 0 the exact quotient
+fn divide(coefficients: &[Fr], point: &Fr) -> Vec::<Fr> {
+    let mut quotient = vec![Fr::zero(); coefficients.len() - 1];
+    let mut carry = Fr::zero();
+    for i in (1..coefficients.len()).rev() {
+        carry.mul_assign(point);
+        carry.add_assign(&coefficients[i]);
+        quotient[i - 1] = carry;
+    }
+    quotient
+}
+
+// p + (b_0 + b_1 X + ..) (X^n - 1), the same values on the domain
+fn blind(mut coefficients: Vec::<Fr>, blinding: &[Fr], n: usize) -> Vec::<Fr> {
+    coefficients.resize(n + blinding.len(), Fr::zero());
+    for (i, factor) in blinding.iter().enumerate() {
+        coefficients[i].sub_assign(factor);
+        coefficients[n + i].add_assign(factor);
+    }
+    coefficients
+}
+
+fn evaluate(coefficients: &[Fr], point: &Fr) -> Fr {
+    let mut value = Fr::zero();
+    for coefficient in coefficients.iter().rev() {
+        value.mul_assign(point);
+        value.add_assign(coefficient);
+    }
+    value
+}
+
+fn add_scaled(sum: &mut Vec::<Fr>, coefficients: &[Fr], scalar: &Fr) {
+    if sum.len() < coefficients.len() {
+        sum.resize(coefficients.len(), Fr::zero());
+    }
+    for (total, coefficient) in sum.iter_mut().zip(coefficients.iter()) {
+        total.add_assign(&scaled(coefficient, scalar));
+    }
+}
+
+// the coefficients of p(s X)
+fn scale_powers(coefficients: &[Fr], base: &Fr) -> Vec::<Fr> {
+    coefficients.iter().zip(powers(base, coefficients.len())).map(|(coefficient, power)| scaled(coefficient, &power)).collect()
+}
+
+fn powers(base: &Fr, count: usize) -> Vec::<Fr> {
+    let mut power = Fr::one();
+    (0..count).map(|_| {
+        let current = power;
+        power.mul_assign(base);
+        current
+    }).collect()
+}
+
+fn scaled(value: &Fr, scalar: &Fr) -> Fr {
+    let mut product = *value;
+    product.mul_assign(scalar);
+    product
+}
+
+// sum c_i [x^i], bucketed by windows of the scalars (Pippenger)
+fn commit(g1_powers: &[G1Affine], coefficients: &[Fr]) -> G1Affine {
+    assert!(coefficients.len() <= g1_powers.len(), "polynomial above the srs degree");
+    let scalars: Vec::<FrRepr> = coefficients.iter().map(|coefficient| coefficient.into_repr()).collect();
+    let window = if scalars.len() < 32 { 3 } else { (scalars.len() as f64).ln().ceil() as usize };
+
+    let mut sum = G1::zero();
+    let windows = (Fr::NUM_BITS as usize).div_ceil(window);
+    for w in (0..windows).rev() {
+        for _ in 0..window {
+            sum.double();
+        }
+        let mut buckets = vec![G1::zero(); (1 << window) - 1];
+        for (base, scalar) in g1_powers.iter().zip(scalars.iter()) {
+            let digit = window_digit(scalar, w * window, window);
+            if digit != 0 {
+                buckets[digit - 1].add_assign_mixed(base);
+            }
+        }
+        let mut running = G1::zero();
+        for bucket in buckets.iter().rev() {
+            running.add_assign(bucket);
+            sum.add_assign(&running);
+        }
+    }
+    sum.into_affine()
+}
+
+fn window_digit(scalar: &FrRepr, start: usize, width: usize) -> usize {
+    let limbs = scalar.as_ref();
+    let limb = start / 64;
+    let shift = start % 64;
+    if limb >= limbs.len() {
+        return 0;
+    }
+    let mut bits = limbs[limb] >> shift;
+    if shift + width > 64 && limb + 1 < limbs.len() {
+        bits |= limbs[limb + 1] << (64 - shift);
+    }
+    (bits & ((1 << width) - 1)) as usize
+}
@@ -0,0 +1,122 @@
+use bellman_ce::groth16::{ Proof, VerifyingKey };
+
+use pairing_ce::{
+    CurveAffine,
+    CurveProjective,
+    Engine,
+    bn256::{ Bn256, Fq12, Fr, G1 },
+};
+
+use ff_ce::{ Field, PrimeField };
+
+use tiny_keccak::{ Hasher, Keccak };
+
+use crate::prover::{ proof_to_eth_bytes, public_inputs_to_eth_bytes };
+use crate::public_inputs::PublicInputs;
+
+// block proofs checked with one multi pairing, the folding step of SnarkPack
+// without its inner product arguments: with the powers of a challenge r
+//   prod e(r^i A_i, B_i) = e(alpha, beta)^(sum r^i) e(sum r^i L_i, gamma) e(sum r^i C_i, delta)
+// with L_i the input combination of proof i, so K proofs take K + 3 pairings
+// instead of 4 K. this is batch verification, not aggregation: the batch is
+// the proofs themselves and grows with K
+#[derive(Clone)]
+pub struct ProofBatch {
+    pub proofs: Vec::<(Proof<Bn256>, PublicInputs<Bn256>)>,
+}
+
+impl ProofBatch {
+    pub fn new(proofs: &[(Proof<Bn256>, PublicInputs<Bn256>)]) -> Self {
+        ProofBatch { proofs: proofs.to_vec() }
+    }
+
+    // every proof as proof_to_eth_bytes followed by its inputs as
+    // public_inputs_to_eth_bytes, in block order
+    pub fn to_eth_bytes(&self) -> Vec::<u8> {
+        let mut bytes = Vec::new();
+        for (proof, public_inputs) in self.proofs.iter() {
+            bytes.extend_from_slice(&proof_to_eth_bytes(proof));
+            bytes.extend_from_slice(&public_inputs_to_eth_bytes(public_inputs));
+        }
+        bytes
+    }
+
+    // keccak256 of to_eth_bytes reduced mod the scalar field, the way a
+    // contract gets it: uint256(keccak256(data)) % r. the proofs are fixed
+    // before r is known, so none can be made to cancel another
+    pub fn challenge(&self) -> Fr {
+        keccak_challenge(&self.to_eth_bytes())
+    }
+}
+
+// uint256(keccak256(bytes)) % r
+pub(crate) fn keccak_challenge(bytes: &[u8]) -> Fr {
+    let mut hasher = Keccak::v256();
+    hasher.update(bytes);
+    let mut digest = [0u8; 32];
+    hasher.finalize(&mut digest);
+
+    let base = Fr::from_str("256").unwrap();
+    let mut challenge = Fr::zero();
+    for byte in digest.iter() {
+        challenge.mul_assign(&base);
+        challenge.add_assign(&Fr::from_str(&byte.to_string()).unwrap());
+    }
+    challenge
+}
+
+// false for no proofs and for a key of another number of inputs
+pub fn batch_verify(vk: &VerifyingKey<Bn256>, batch: &ProofBatch) -> bool {
+    if batch.proofs.is_empty() {
+        return false;
+    }
+
+    let challenge = batch.challenge();
+    let mut power = Fr::one();
+    let mut power_sum = Fr::zero();
+    let mut ic_coefficients = vec![Fr::zero(); vk.ic.len()];
+    let mut folded_c = G1::zero();
+    let mut folded_a = Vec::new();
+
+    for (proof, public_inputs) in batch.proofs.iter() {
+        let inputs = public_inputs.to_vec();
+        if inputs.len() + 1 != vk.ic.len() {
+            return false;
+        }
+
+        folded_a.push((proof.a.mul(power).into_affine().prepare(), proof.b.prepare()));
+        folded_c.add_assign(&proof.c.mul(power));
+
+        // sum r^i L_i = sum_j IC_j (sum_i r^i x_ij), one multiplication per IC point
+        ic_coefficients[0].add_assign(&power);
+        for (coefficient, input) in ic_coefficients[1..].iter_mut().zip(inputs.iter()) {
+            let mut term = *input;
+            term.mul_assign(&power);
+            coefficient.add_assign(&term);
+        }
+
+        power_sum.add_assign(&power);
+        power.mul_assign(&challenge);
+    }
+
+    let mut folded_inputs = G1::zero();
+    for (ic, coefficient) in vk.ic.iter().zip(ic_coefficients.iter()) {
+        folded_inputs.add_assign(&ic.mul(*coefficient));
+    }
+
+    let mut neg_alpha = vk.alpha_g1.mul(power_sum);
+    neg_alpha.negate();
+    folded_inputs.negate();
+    folded_c.negate();
+
+    let mut pairs = folded_a;
+    pairs.push((neg_alpha.into_affine().prepare(), vk.beta_g2.prepare()));
+    pairs.push((folded_inputs.into_affine().prepare(), vk.gamma_g2.prepare()));
+    pairs.push((folded_c.into_affine().prepare(), vk.delta_g2.prepare()));
+
+    let refs: Vec<_> = pairs.iter().map(|(g1, g2)| (g1, g2)).collect();
+    match Bn256::final_exponentiation(&Bn256::miller_loop(refs.iter())) {
+        Some(result) => result == Fq12::one(),
+        None => false,
+    }
+}
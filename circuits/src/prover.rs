@@ -0,0 +1,710 @@
+use std::{
+    any::Any,
+    collections::{ BTreeMap, HashMap },
+    error::Error,
+    fmt::{ self, Write as _ },
+    fs::{ self, File },
+    io::{ self, BufReader, BufWriter, Read, Write },
+    panic::{ self, AssertUnwindSafe },
+    path::{ Path, PathBuf },
+    sync::{ Arc, Mutex, atomic::{ AtomicBool, Ordering } },
+    thread::{ self, JoinHandle },
+};
+
+use crossbeam_channel::{ Receiver, Sender, unbounded };
+
+use bellman_ce::{
+    groth16::{
+        Parameters,
+        PreparedVerifyingKey,
+        Proof,
+        VerifyingKey,
+        create_random_proof,
+        verify_proof,
+    },
+    SynthesisError,
+};
+
+use pairing_ce::{
+    CurveAffine,
+    CurveProjective,
+    EncodedPoint,
+    bn256::{ Bn256, Fr, G1Affine, G2Affine, G1Uncompressed, G2Uncompressed },
+};
+
+use ff_ce::PrimeField;
+
+use serde::{ Serialize, Deserialize };
+
+use rand::thread_rng;
+
+use crate::deposit_circuit::DepositBatchCircuit;
+use crate::family::{ BatchConfig, CircuitFamily };
+use crate::params::shared_params;
+use crate::layout::CircuitLayout;
+use crate::public_inputs::PublicInputs;
+use crate::tree::leaf::LeafVersion;
+use crate::error::OpenPlasmaError;
+use crate::instrument::timed_span;
+use crate::metrics::metrics;
+
+const KEY_FILE_MAGIC: &[u8; 4] = b"OPDK";
+// 2 since the header ends with the leaf version, 1 is read as LeafVersion::V0
+const KEY_FILE_VERSION: u8 = 2;
+
+pub(crate) fn invalid_data(msg: String) -> OpenPlasmaError {
+    OpenPlasmaError::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// magic, version, the batch size as u32 and both depths as u8, big endian,
+// then the shape hash and the parameters as CircuitFamily::write_parameters
+// puts them
+fn write_key_file<W: Write>(
+    family: &mut CircuitFamily,
+    config: BatchConfig,
+    params: &Parameters<Bn256>,
+    mut writer: W,
+) -> Result<(), OpenPlasmaError> {
+    if config.deposit_batch > u32::MAX as usize || config.account_depth > u8::MAX as usize
+        || config.token_depth > u8::MAX as usize
+    {
+        return Err(invalid_data(format!("{:?} doesn't fit the key file header", config)));
+    }
+
+    writer.write_all(KEY_FILE_MAGIC)?;
+    writer.write_all(&[KEY_FILE_VERSION])?;
+    writer.write_all(&(config.deposit_batch as u32).to_be_bytes())?;
+    writer.write_all(&[config.account_depth as u8, config.token_depth as u8, config.leaf_version.number()])?;
+
+    family.write_parameters(config, params, writer)?;
+    Ok(())
+}
+
+fn read_key_file<R: Read>(
+    family: &mut CircuitFamily,
+    mut reader: R,
+) -> Result<(BatchConfig, Parameters<Bn256>), OpenPlasmaError> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != KEY_FILE_MAGIC {
+        return Err(invalid_data("not a deposit proving key file".to_string()));
+    }
+
+    let mut header = [0u8; 7];
+    reader.read_exact(&mut header)?;
+    let leaf_version = match header[0] {
+        1 => LeafVersion::V0,
+        KEY_FILE_VERSION => {
+            let mut version = [0u8; 1];
+            reader.read_exact(&mut version)?;
+            LeafVersion::from_number(version[0]).ok_or(
+                invalid_data(format!("unknown leaf version {}", version[0]))
+            )?
+        },
+        _ => return Err(invalid_data(format!("unsupported key file version {}", header[0]))),
+    };
+    let config = BatchConfig {
+        deposit_batch: u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize,
+        account_depth: header[5] as usize,
+        token_depth: header[6] as usize,
+        leaf_version,
+    };
+
+    // the shape hash and the parameter sizes are checked against the circuit
+    // this build synthesizes for the config
+    let params = family.read_parameters(config, reader, true)?;
+
+    Ok((config, params))
+}
+
+// the json CircuitLayout generate_parameters writes with the key file
+pub fn layout_path<P: AsRef<Path>>(key_path: P) -> PathBuf {
+    key_path.as_ref().with_extension("layout.json")
+}
+
+// trusted setup for the deposit batch of the config over the shared params,
+// the key file is written next to the path and renamed like AccountsTree::save,
+// the circuit layout goes to layout_path the same way. the randomness is
+// thread_rng and is not kept, this is a single party setup
+pub fn generate_parameters<P: AsRef<Path>>(
+    config: BatchConfig,
+    path: P,
+) -> Result<Parameters<Bn256>, OpenPlasmaError> {
+    let path = path.as_ref();
+    let temp_path = path.with_extension("tmp");
+
+    let mut family = CircuitFamily::new(shared_params());
+    let params = family.generate_parameters(config, &mut thread_rng())?;
+
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    write_key_file(&mut family, config, &params, &mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    let layout = serde_json::to_string_pretty(&family.layout(config)?)
+        .map_err(|err| invalid_data(err.to_string()))?;
+    let layout_path = layout_path(path);
+    let temp_layout_path = layout_path.with_extension("tmp");
+    fs::write(&temp_layout_path, layout)?;
+
+    fs::rename(&temp_path, path)?;
+    fs::rename(&temp_layout_path, layout_path)?;
+
+    Ok(params)
+}
+
+pub fn load_layout<P: AsRef<Path>>(key_path: P) -> Result<CircuitLayout, OpenPlasmaError> {
+    let json = fs::read_to_string(layout_path(key_path))?;
+    serde_json::from_str(&json).map_err(|err| invalid_data(err.to_string()))
+}
+
+// keys of another layout, e.g. written before a circuit change, are an error
+pub fn load_parameters<P: AsRef<Path>>(
+    path: P,
+) -> Result<(BatchConfig, Parameters<Bn256>), OpenPlasmaError> {
+    let mut family = CircuitFamily::new(shared_params());
+    read_key_file(&mut family, BufReader::new(File::open(path)?))
+}
+
+// the parameters have to be of the circuit's config, a key of another shape
+// is refused instead of producing a proof that never verifies
+pub fn prove_deposit_block(
+    params: &Parameters<Bn256>,
+    circuit: DepositBatchCircuit<Bn256>,
+) -> Result<Proof<Bn256>, OpenPlasmaError> {
+    let config = BatchConfig {
+        deposit_batch: circuit.deposit_batch,
+        account_depth: circuit.account_depth,
+        token_depth: circuit.token_depth,
+        leaf_version: circuit.leaf_version,
+    };
+    let mut family = CircuitFamily::new(Arc::clone(&circuit.params));
+    family.check_parameters(config, params)?;
+
+    let _span = timed_span!(
+        "prover.prove",
+        batch = config.deposit_batch,
+        depth = config.account_depth,
+        constraints = family.shape(&config).map_or(0, |shape| shape.stats.constraints),
+    );
+    let proof = create_random_proof(circuit, params, &mut thread_rng())?;
+    metrics().proof_generated();
+
+    Ok(proof)
+}
+
+// the inputs are put in the layout's order by meaning, so a key of another
+// input order is no problem. a layout with an input the public inputs don't
+// hold and a malformed proof are not valid either
+pub fn verify_block(
+    verifying_key: &PreparedVerifyingKey<Bn256>,
+    proof: &Proof<Bn256>,
+    layout: &CircuitLayout,
+    public_inputs: &PublicInputs<Bn256>,
+) -> bool {
+    let _span = timed_span!(
+        "prover.verify",
+        inputs = layout.inputs.len(),
+        constraints = layout.constraints,
+    );
+
+    match layout.input_vector(public_inputs) {
+        Some(inputs) => verify_proof(verifying_key, proof, &inputs).unwrap_or(false),
+        None => false,
+    }
+}
+
+const WORD_BYTES: usize = 32;
+
+// 0x prefixed, 64 hex digits
+fn to_words(bytes: &[u8]) -> Vec::<String> {
+    bytes.chunks(WORD_BYTES).map(|word| format!("0x{}", hex::encode(word))).collect()
+}
+
+fn from_words(words: &[String], bytes: &mut [u8]) -> Result<(), OpenPlasmaError> {
+    for (word, chunk) in words.iter().zip(bytes.chunks_mut(WORD_BYTES)) {
+        let digits = word.strip_prefix("0x").unwrap_or(word);
+        let decoded = hex::decode(digits).map_err(|err| invalid_data(format!("{} is not hex: {}", word, err)))?;
+        if decoded.len() != WORD_BYTES {
+            return Err(invalid_data(format!("{} is not a 32 byte word", word)));
+        }
+        chunk.copy_from_slice(&decoded);
+    }
+    Ok(())
+}
+
+pub(crate) const G1_BYTES: usize = 2 * WORD_BYTES;
+pub(crate) const G2_BYTES: usize = 4 * WORD_BYTES;
+pub const PROOF_ETH_BYTES: usize = 2 * G1_BYTES + G2_BYTES;
+
+// the precompiles take points as big endian words, G1 as x, y and G2 as
+// x_im, x_re, y_im, y_re, which is the order of the uncompressed encoding.
+// the point at infinity is all zero words there instead of a flag bit
+pub(crate) fn g1_to_bytes(point: &G1Affine) -> [u8; G1_BYTES] {
+    let mut bytes = [0u8; G1_BYTES];
+    if !point.is_zero() {
+        bytes.copy_from_slice(point.into_uncompressed().as_ref());
+    }
+    bytes
+}
+
+pub(crate) fn g2_to_bytes(point: &G2Affine) -> [u8; G2_BYTES] {
+    let mut bytes = [0u8; G2_BYTES];
+    if !point.is_zero() {
+        bytes.copy_from_slice(point.into_uncompressed().as_ref());
+    }
+    bytes
+}
+
+// coordinates are below 2^254, the flag bits of the uncompressed encoding
+// are never set in a word the precompiles accept
+fn check_flag_bits(bytes: &[u8]) -> Result<(), OpenPlasmaError> {
+    if bytes[0] & 0xc0 != 0 {
+        return Err(invalid_data("coordinate is not a field element".to_string()));
+    }
+    Ok(())
+}
+
+pub(crate) fn g1_from_bytes(bytes: &[u8]) -> Result<G1Affine, OpenPlasmaError> {
+    if bytes.iter().all(|byte| *byte == 0) {
+        return Ok(G1Affine::zero());
+    }
+    check_flag_bits(bytes)?;
+
+    let mut encoded = G1Uncompressed::empty();
+    encoded.as_mut().copy_from_slice(bytes);
+    encoded.into_affine().map_err(|err| invalid_data(format!("invalid G1 point: {}", err)))
+}
+
+pub(crate) fn g2_from_bytes(bytes: &[u8]) -> Result<G2Affine, OpenPlasmaError> {
+    if bytes.iter().all(|byte| *byte == 0) {
+        return Ok(G2Affine::zero());
+    }
+    check_flag_bits(bytes)?;
+
+    let mut encoded = G2Uncompressed::empty();
+    encoded.as_mut().copy_from_slice(bytes);
+    let point = encoded.into_affine().map_err(|err| invalid_data(format!("invalid G2 point: {}", err)))?;
+
+    // the G2 cofactor is not one, a point on the curve can be out of the subgroup
+    if !point.mul(Fr::char()).is_zero() {
+        return Err(invalid_data("G2 point is not in the prime order subgroup".to_string()));
+    }
+    Ok(point)
+}
+
+fn g1_words(point: &G1Affine) -> [String; 2] {
+    let words = to_words(&g1_to_bytes(point));
+    [words[0].clone(), words[1].clone()]
+}
+
+fn g2_words(point: &G2Affine) -> [[String; 2]; 2] {
+    let words = to_words(&g2_to_bytes(point));
+    [[words[0].clone(), words[1].clone()], [words[2].clone(), words[3].clone()]]
+}
+
+fn g1_from_words(words: &[String; 2]) -> Result<G1Affine, OpenPlasmaError> {
+    let mut bytes = [0u8; G1_BYTES];
+    from_words(words, &mut bytes)?;
+    g1_from_bytes(&bytes)
+}
+
+fn g2_from_words(words: &[[String; 2]; 2]) -> Result<G2Affine, OpenPlasmaError> {
+    let mut bytes = [0u8; G2_BYTES];
+    let flat: Vec<_> = words.iter().flat_map(|pair| pair.iter().cloned()).collect();
+    from_words(&flat, &mut bytes)?;
+    g2_from_bytes(&bytes)
+}
+
+// a, b and c the way verifyProof takes them, which is also the abi encoding
+// of its three static arrays
+pub fn proof_to_eth_bytes(proof: &Proof<Bn256>) -> [u8; PROOF_ETH_BYTES] {
+    let mut bytes = [0u8; PROOF_ETH_BYTES];
+    bytes[..G1_BYTES].copy_from_slice(&g1_to_bytes(&proof.a));
+    bytes[G1_BYTES..G1_BYTES + G2_BYTES].copy_from_slice(&g2_to_bytes(&proof.b));
+    bytes[G1_BYTES + G2_BYTES..].copy_from_slice(&g1_to_bytes(&proof.c));
+    bytes
+}
+
+// the points are checked to be on the curve and in the subgroup, so a bad
+// proof fails here and not inside the pairing
+pub fn proof_from_eth_bytes(bytes: &[u8]) -> Result<Proof<Bn256>, OpenPlasmaError> {
+    if bytes.len() != PROOF_ETH_BYTES {
+        return Err(invalid_data(format!("proof is {} bytes, expected {}", bytes.len(), PROOF_ETH_BYTES)));
+    }
+
+    Ok(Proof {
+        a: g1_from_bytes(&bytes[..G1_BYTES])?,
+        b: g2_from_bytes(&bytes[G1_BYTES..G1_BYTES + G2_BYTES])?,
+        c: g1_from_bytes(&bytes[G1_BYTES + G2_BYTES..])?,
+    })
+}
+
+// the input argument of verifyProof, see PublicInputs::to_be_bytes
+pub fn public_inputs_to_eth_bytes(public_inputs: &PublicInputs<Bn256>) -> Vec::<u8> {
+    public_inputs.to_be_bytes()
+}
+
+// every point of the key in precompile order, beta_g1 and delta_g1 are only
+// needed to prove but are kept so the key reads back whole
+#[derive(Serialize, Deserialize)]
+struct VerifyingKeyJson {
+    n_inputs: usize,
+    alpha_g1: [String; 2],
+    beta_g1: [String; 2],
+    beta_g2: [[String; 2]; 2],
+    gamma_g2: [[String; 2]; 2],
+    delta_g1: [String; 2],
+    delta_g2: [[String; 2]; 2],
+    ic: Vec::<[String; 2]>,
+}
+
+pub fn export_vk_json(vk: &VerifyingKey<Bn256>) -> String {
+    let json = VerifyingKeyJson {
+        n_inputs: vk.ic.len() - 1,
+        alpha_g1: g1_words(&vk.alpha_g1),
+        beta_g1: g1_words(&vk.beta_g1),
+        beta_g2: g2_words(&vk.beta_g2),
+        gamma_g2: g2_words(&vk.gamma_g2),
+        delta_g1: g1_words(&vk.delta_g1),
+        delta_g2: g2_words(&vk.delta_g2),
+        ic: vk.ic.iter().map(g1_words).collect(),
+    };
+    serde_json::to_string_pretty(&json).expect("the key serializes to json")
+}
+
+// points are checked as for proof_from_eth_bytes
+pub fn import_vk_json(json: &str) -> Result<VerifyingKey<Bn256>, OpenPlasmaError> {
+    let json: VerifyingKeyJson = serde_json::from_str(json).map_err(|err| invalid_data(err.to_string()))?;
+    if json.ic.len() != json.n_inputs + 1 {
+        return Err(invalid_data(format!("{} ic points for {} inputs", json.ic.len(), json.n_inputs)));
+    }
+
+    Ok(VerifyingKey {
+        alpha_g1: g1_from_words(&json.alpha_g1)?,
+        beta_g1: g1_from_words(&json.beta_g1)?,
+        beta_g2: g2_from_words(&json.beta_g2)?,
+        gamma_g2: g2_from_words(&json.gamma_g2)?,
+        delta_g1: g1_from_words(&json.delta_g1)?,
+        delta_g2: g2_from_words(&json.delta_g2)?,
+        ic: json.ic.iter().map(g1_from_words).collect::<Result<_, _>>()?,
+    })
+}
+
+fn g1_constants(out: &mut String, name: &str, point: &G1Affine) {
+    let [x, y] = g1_words(point);
+    writeln!(out, "    uint256 constant {}_X = {};", name, x).unwrap();
+    writeln!(out, "    uint256 constant {}_Y = {};", name, y).unwrap();
+}
+
+fn g2_constants(out: &mut String, name: &str, point: &G2Affine) {
+    let [[x_im, x_re], [y_im, y_re]] = g2_words(point);
+    writeln!(out, "    uint256 constant {}_X_IM = {};", name, x_im).unwrap();
+    writeln!(out, "    uint256 constant {}_X_RE = {};", name, x_re).unwrap();
+    writeln!(out, "    uint256 constant {}_Y_IM = {};", name, y_im).unwrap();
+    writeln!(out, "    uint256 constant {}_Y_RE = {};", name, y_re).unwrap();
+}
+
+// Verifier.sol checking e(-A, B) e(alpha, beta) e(vk_x, gamma) e(C, delta) == 1
+// with the bn256 precompiles, vk_x = ic[0] + sum input[i] ic[i + 1]. b is
+// passed as [[x_im, x_re], [y_im, y_re]], inputs in PublicInputs::to_vec order.
+// n_inputs doesn't count the constant one, for the deposit batch it is 4; a
+// key with another number of ic points is malformed for it
+pub fn export_solidity_verifier(vk: &VerifyingKey<Bn256>, n_inputs: usize) -> Result<String, OpenPlasmaError> {
+    if vk.ic.len() != n_inputs + 1 {
+        return Err(SynthesisError::MalformedVerifyingKey.into());
+    }
+
+    let mut out = String::new();
+    out.push_str(SOLIDITY_HEADER);
+
+    g1_constants(&mut out, "ALPHA", &vk.alpha_g1);
+    g2_constants(&mut out, "BETA", &vk.beta_g2);
+    g2_constants(&mut out, "GAMMA", &vk.gamma_g2);
+    g2_constants(&mut out, "DELTA", &vk.delta_g2);
+    for (i, point) in vk.ic.iter().enumerate() {
+        g1_constants(&mut out, &format!("IC{}", i), point);
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "    function verifyProof(").unwrap();
+    writeln!(out, "        uint256[2] calldata a,").unwrap();
+    writeln!(out, "        uint256[2][2] calldata b,").unwrap();
+    writeln!(out, "        uint256[2] calldata c,").unwrap();
+    writeln!(out, "        uint256[{}] calldata input", n_inputs).unwrap();
+    writeln!(out, "    ) external view returns (bool) {{").unwrap();
+    writeln!(out, "        for (uint256 i = 0; i < input.length; i++) {{").unwrap();
+    writeln!(out, "            if (input[i] >= SNARK_SCALAR_FIELD) return false;").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "        uint256[2] memory vkX = [IC0_X, IC0_Y];").unwrap();
+    for i in 1..=n_inputs {
+        writeln!(out, "        vkX = ecAdd(vkX, ecMul([IC{}_X, IC{}_Y], input[{}]));", i, i, i - 1).unwrap();
+    }
+    out.push_str(SOLIDITY_PAIRING);
+
+    Ok(out)
+}
+
+const SOLIDITY_HEADER: &str = "// SPDX-License-Identifier: MIT
+// generated by openplasma_circuits::prover::export_solidity_verifier
+pragma solidity ^0.8.0;
+
+contract Verifier {
+    uint256 constant PRIME_Q = 21888242871839275222246405745257275088696311157297823662689037894645226208583;
+    uint256 constant SNARK_SCALAR_FIELD = 21888242871839275222246405745257275088548364400416034343698204186575808495617;
+
+";
+
+const SOLIDITY_PAIRING: &str = "
+        uint256[24] memory p;
+        p[0] = a[0];
+        p[1] = a[1] % PRIME_Q == 0 ? 0 : PRIME_Q - (a[1] % PRIME_Q);
+        p[2] = b[0][0];
+        p[3] = b[0][1];
+        p[4] = b[1][0];
+        p[5] = b[1][1];
+        p[6] = ALPHA_X;
+        p[7] = ALPHA_Y;
+        p[8] = BETA_X_IM;
+        p[9] = BETA_X_RE;
+        p[10] = BETA_Y_IM;
+        p[11] = BETA_Y_RE;
+        p[12] = vkX[0];
+        p[13] = vkX[1];
+        p[14] = GAMMA_X_IM;
+        p[15] = GAMMA_X_RE;
+        p[16] = GAMMA_Y_IM;
+        p[17] = GAMMA_Y_RE;
+        p[18] = c[0];
+        p[19] = c[1];
+        p[20] = DELTA_X_IM;
+        p[21] = DELTA_X_RE;
+        p[22] = DELTA_Y_IM;
+        p[23] = DELTA_Y_RE;
+
+        uint256[1] memory result;
+        bool success;
+        assembly {
+            success := staticcall(gas(), 8, p, 768, result, 32)
+        }
+        return success && result[0] == 1;
+    }
+
+    function ecAdd(uint256[2] memory p1, uint256[2] memory p2) internal view returns (uint256[2] memory r) {
+        uint256[4] memory input = [p1[0], p1[1], p2[0], p2[1]];
+        bool success;
+        assembly {
+            success := staticcall(gas(), 6, input, 128, r, 64)
+        }
+        require(success, \"ecAdd failed\");
+    }
+
+    function ecMul(uint256[2] memory p, uint256 s) internal view returns (uint256[2] memory r) {
+        uint256[3] memory input = [p[0], p[1], s];
+        bool success;
+        assembly {
+            success := staticcall(gas(), 7, input, 96, r, 64)
+        }
+        require(success, \"ecMul failed\");
+    }
+}
+";
+
+// a deposit block for the pool, the public inputs come back with its proof
+pub struct ProofJob {
+    pub block_number: u64,
+    pub circuit: DepositBatchCircuit<Bn256>,
+    pub public_inputs: PublicInputs<Bn256>,
+}
+
+#[derive(Debug)]
+pub enum ProofError {
+    Proving(OpenPlasmaError),
+    Cancelled,
+    Panicked(String),
+}
+
+impl Error for ProofError {}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ProofError::Proving(err) => write!(f, "Proving failed: {}", err),
+            ProofError::Cancelled => write!(f, "Job was cancelled"),
+            ProofError::Panicked(msg) => write!(f, "Prover panicked: {}", msg),
+        }
+    }
+}
+
+impl From<OpenPlasmaError> for ProofError {
+    fn from(err: OpenPlasmaError) -> Self {
+        ProofError::Proving(err)
+    }
+}
+
+pub struct ProofResult {
+    pub block_number: u64,
+    pub public_inputs: PublicInputs<Bn256>,
+    pub proof: Result<Proof<Bn256>, ProofError>,
+}
+
+type Prover = dyn Fn(DepositBatchCircuit<Bn256>) -> Result<Proof<Bn256>, OpenPlasmaError> + Send + Sync;
+
+struct Task {
+    seq: u64,
+    job: ProofJob,
+    cancelled: Arc<AtomicBool>,
+}
+
+// jobs not delivered yet by submission number, with their block and cancel flag
+#[derive(Default)]
+struct Pending {
+    next_seq: u64,
+    jobs: HashMap<u64, (u64, Arc<AtomicBool>)>,
+}
+
+// proves deposit blocks on its own threads so block production doesn't wait
+// for a proof. results come out of results() in submission order, which is
+// block order as long as blocks are submitted in order. a cancelled job is
+// delivered as ProofError::Cancelled so the order stays gap free, a panic
+// while proving is caught and delivered as ProofError::Panicked
+pub struct Pool {
+    jobs: Option<Sender<Task>>,
+    results: Receiver<ProofResult>,
+    pending: Arc<Mutex<Pending>>,
+    workers: Vec<JoinHandle<()>>,
+    collector: Option<JoinHandle<()>>,
+}
+
+impl Pool {
+    pub fn new(workers: usize, params: Arc<Parameters<Bn256>>) -> Self {
+        Self::with_prover(workers, move |circuit| prove_deposit_block(&params, circuit))
+    }
+
+    // the proving function is called on the worker threads, e.g. to prove
+    // on a remote machine
+    pub fn with_prover<F>(workers: usize, prove: F) -> Self
+        where F: Fn(DepositBatchCircuit<Bn256>) -> Result<Proof<Bn256>, OpenPlasmaError> + Send + Sync + 'static,
+    {
+        assert!(workers > 0, "a pool needs at least one worker");
+        let prove: Arc<Prover> = Arc::new(prove);
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (jobs, tasks) = unbounded::<Task>();
+        let (done, finished) = unbounded::<(u64, ProofResult)>();
+        let (delivered, results) = unbounded();
+
+        let workers = (0..workers).map(|_| {
+            let tasks = tasks.clone();
+            let done = done.clone();
+            let prove = Arc::clone(&prove);
+            thread::spawn(move || {
+                for Task { seq, job, cancelled } in tasks.iter() {
+                    let ProofJob { block_number, circuit, public_inputs } = job;
+                    let proof = if cancelled.load(Ordering::SeqCst) {
+                        Err(ProofError::Cancelled)
+                    } else {
+                        match panic::catch_unwind(AssertUnwindSafe(|| prove(circuit))) {
+                            Ok(proof) => proof.map_err(ProofError::from),
+                            Err(payload) => Err(ProofError::Panicked(panic_message(payload.as_ref()))),
+                        }
+                    };
+                    if done.send((seq, ProofResult { block_number, public_inputs, proof })).is_err() {
+                        break;
+                    }
+                }
+            })
+        }).collect();
+        drop(done);
+
+        let collector = {
+            let pending = Arc::clone(&pending);
+            thread::spawn(move || {
+                let mut next = 0;
+                let mut out_of_order = BTreeMap::new();
+                for (seq, result) in finished.iter() {
+                    out_of_order.insert(seq, result);
+                    while let Some(mut result) = out_of_order.remove(&next) {
+                        let (_, cancelled) = pending.lock().unwrap().jobs.remove(&next)
+                            .expect("every submitted job is pending until delivered");
+                        // cancelled while it was being proven
+                        if cancelled.load(Ordering::SeqCst) {
+                            result.proof = Err(ProofError::Cancelled);
+                        }
+                        next += 1;
+                        // the receiver is gone only when the pool is dropped
+                        let _ = delivered.send(result);
+                    }
+                }
+            })
+        };
+
+        Pool {
+            jobs: Some(jobs),
+            results,
+            pending,
+            workers,
+            collector: Some(collector),
+        }
+    }
+
+    pub fn submit(&self, job: ProofJob) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let seq = {
+            let mut pending = self.pending.lock().unwrap();
+            let seq = pending.next_seq;
+            pending.next_seq += 1;
+            pending.jobs.insert(seq, (job.block_number, Arc::clone(&cancelled)));
+            seq
+        };
+
+        self.jobs.as_ref().expect("jobs are open until the pool is dropped")
+            .send(Task { seq, job, cancelled })
+            .expect("workers run until the pool is dropped");
+    }
+
+    // every job of the block that isn't delivered yet comes back cancelled,
+    // e.g. when a reorg drops the block. a job already proving still runs to
+    // the end, its proof is discarded. returns the number of jobs cancelled
+    pub fn cancel(&self, block_number: u64) -> usize {
+        let pending = self.pending.lock().unwrap();
+        let mut count = 0;
+        for (block, cancelled) in pending.jobs.values() {
+            if *block == block_number && !cancelled.swap(true, Ordering::SeqCst) {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn results(&self) -> &Receiver<ProofResult> {
+        &self.results
+    }
+}
+
+impl Drop for Pool {
+    // the queued jobs are cancelled so the workers don't prove them first
+    fn drop(&mut self) {
+        for (_, cancelled) in self.pending.lock().unwrap().jobs.values() {
+            cancelled.store(true, Ordering::SeqCst);
+        }
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        if let Some(collector) = self.collector.take() {
+            let _ = collector.join();
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
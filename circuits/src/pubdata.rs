@@ -0,0 +1,106 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::circuit::{
+    num::AllocatedNum,
+    boolean::Boolean,
+    sha256::sha256,
+};
+
+use pairing_ce::{
+    Engine,
+    bn256::{ Fr, FrRepr },
+};
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+use sha2::{ Digest, Sha256 };
+
+// the digest is cut to its low 253 bits, the contract takes
+// uint256(sha256(data)) & ((1 << 253) - 1), which is always below the modulus
+pub const COMMITMENT_BITS: usize = 253;
+pub const WORD_BYTES: usize = 32;
+
+pub fn compute_pubdata_commitment(bytes: &[u8]) -> Fr {
+    let mut digest = [0u8; WORD_BYTES];
+    digest.copy_from_slice(&Sha256::digest(bytes));
+    digest[0] &= 0xff >> (8 * WORD_BYTES - COMMITMENT_BITS);
+
+    let mut repr = FrRepr::default();
+    repr.read_be(&digest[..]).expect("32 bytes always read");
+    Fr::from_repr(repr).expect("253 bits are below the modulus")
+}
+
+// one step of a sha256 accumulator, the commitment of the previous value as a
+// big endian word followed by the pubdata: sha256(abi.encodePacked(uint256 prev, bytes pubdata))
+pub fn accumulate_pubdata(prev: Fr, pubdata: &[u8]) -> Fr {
+    let mut bytes = Vec::with_capacity(WORD_BYTES + pubdata.len());
+    prev.into_repr().write_be(&mut bytes).expect("writing to a vec never fails");
+    bytes.extend_from_slice(pubdata);
+    compute_pubdata_commitment(&bytes)
+}
+
+// sha256 takes the bits of every byte most significant first, values here are
+// given as little endian bits as the decompositions produce them
+
+// a little endian number of `bytes` bytes, the bits past the given ones are zero
+pub fn le_bytes_bits(bits_le: &[Boolean], bytes: usize) -> Vec::<Boolean> {
+    assert!(bits_le.len() <= bytes * 8);
+    let bit = |i: usize| bits_le.get(i).cloned().unwrap_or(Boolean::constant(false));
+    (0..bytes).flat_map(|byte| (0..8).rev().map(move |i| byte * 8 + i)).map(bit).collect()
+}
+
+// a big endian word, as for the previous value of accumulate_pubdata
+pub fn be_word_bits(bits_le: &[Boolean]) -> Vec::<Boolean> {
+    assert!(bits_le.len() <= WORD_BYTES * 8);
+    let bit = |i: usize| bits_le.get(i).cloned().unwrap_or(Boolean::constant(false));
+    (0..WORD_BYTES * 8).rev().map(bit).collect()
+}
+
+pub fn constant_byte_bits(byte: u8) -> Vec::<Boolean> {
+    (0..8).rev().map(|i| Boolean::constant((byte >> i) & 1 == 1)).collect()
+}
+
+// compute_pubdata_commitment of the bits, packed into one number
+pub fn pubdata_commitment<E, CS>(
+    mut cs: CS,
+    bits: &[Boolean],
+) -> Result<AllocatedNum<E>, SynthesisError>
+    where E: Engine,
+          CS: ConstraintSystem<E>,
+{
+    let digest = sha256(cs.namespace(|| "sha256 of pubdata"), bits)?;
+    let kept = &digest[digest.len() - COMMITMENT_BITS..];
+
+    let value = kept.iter().try_fold(E::Fr::zero(), |mut acc, bit| {
+        acc.double();
+        if bit.get_value()? {
+            acc.add_assign(&E::Fr::one());
+        }
+        Some(acc)
+    });
+
+    let commitment = AllocatedNum::alloc(
+        cs.namespace(|| "allocate commitment"),
+        || value.ok_or(SynthesisError::AssignmentMissing),
+    )?;
+
+    cs.enforce(
+        || "pack commitment",
+        |lc| {
+            let mut lc = lc;
+            let mut coeff = E::Fr::one();
+            for bit in kept.iter().rev() {
+                lc = lc + &bit.lc(CS::one(), coeff);
+                coeff.double();
+            }
+            lc
+        },
+        |lc| lc + CS::one(),
+        |lc| lc + commitment.get_variable(),
+    );
+
+    Ok(commitment)
+}
@@ -0,0 +1,274 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+    groth16::{
+        Proof,
+        PreparedVerifyingKey,
+        verify_proof,
+    },
+};
+
+use sapling_crypto_ce::{
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+        poseidon_hash,
+    },
+    circuit::{
+        num::AllocatedNum,
+        poseidon_hash::poseidon_hash as poseidon_hash_gadget,
+    },
+};
+
+use pairing_ce::Engine;
+
+use ff_ce::{ PrimeField, PrimeFieldRepr };
+
+use serde::{ Serialize, Deserialize };
+
+// what a public input means, known from the name it is inputized under,
+// see layout::CircuitLayout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputSemantic {
+    OldAccumHash,
+    NewAccumHash,
+    OldRoot,
+    NewRoot,
+    TotalFee,
+    Timestamp,
+    BlockCommitment,
+    // an input of a circuit other than a block, e.g. an exit
+    Other,
+}
+
+impl InputSemantic {
+    pub fn from_input_name(name: &str) -> Self {
+        match name {
+            "input old accum hash" => InputSemantic::OldAccumHash,
+            "input new accum hash" => InputSemantic::NewAccumHash,
+            "input old root" => InputSemantic::OldRoot,
+            "input new root" => InputSemantic::NewRoot,
+            "input total fee" => InputSemantic::TotalFee,
+            "input timestamp" => InputSemantic::Timestamp,
+            "input block commitment" => InputSemantic::BlockCommitment,
+            _ => InputSemantic::Other,
+        }
+    }
+}
+
+// block public inputs, the only place that knows their order: batch circuits
+// inputize them with alloc_public_inputs, verifiers get them from to_vec
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicInputs<E: Engine> {
+    pub old_accum_hash: E::Fr,
+    pub new_accum_hash: E::Fr,
+    pub old_account_root: E::Fr,
+    pub new_account_root: E::Fr,
+    // fees the block credits to the operator, the input after the four above
+    // in blocks that charge fees, see alloc_total_fee_input
+    pub total_fee: Option::<E::Fr>,
+}
+
+pub struct AllocatedPublicInputs<E: Engine> {
+    pub old_accum_hash: AllocatedNum<E>,
+    pub new_accum_hash: AllocatedNum<E>,
+    pub old_account_root: AllocatedNum<E>,
+    pub new_account_root: AllocatedNum<E>,
+}
+
+impl<E: Engine> PublicInputs<E> {
+    pub fn new(
+        old_accum_hash: E::Fr,
+        new_accum_hash: E::Fr,
+        old_account_root: E::Fr,
+        new_account_root: E::Fr,
+    ) -> Self {
+        PublicInputs {
+            old_accum_hash,
+            new_accum_hash,
+            old_account_root,
+            new_account_root,
+            total_fee: None,
+        }
+    }
+
+    pub fn with_total_fee(mut self, total_fee: E::Fr) -> Self {
+        self.total_fee = Some(total_fee);
+        self
+    }
+
+    // None for what PublicInputs doesn't hold, the timestamp and the commitment
+    // come from elsewhere
+    pub fn get(&self, semantic: InputSemantic) -> Option<E::Fr> {
+        match semantic {
+            InputSemantic::OldAccumHash => Some(self.old_accum_hash),
+            InputSemantic::NewAccumHash => Some(self.new_accum_hash),
+            InputSemantic::OldRoot => Some(self.old_account_root),
+            InputSemantic::NewRoot => Some(self.new_account_root),
+            InputSemantic::TotalFee => self.total_fee,
+            InputSemantic::Timestamp | InputSemantic::BlockCommitment | InputSemantic::Other => None,
+        }
+    }
+
+    pub fn from_deposit_block(
+        old_accum_hash: E::Fr,
+        new_accum_hash: E::Fr,
+        old_account_root: E::Fr,
+        new_account_root: E::Fr,
+    ) -> Vec::<E::Fr> {
+        Self::new(old_accum_hash, new_accum_hash, old_account_root, new_account_root).to_vec()
+    }
+
+    pub fn to_vec(&self) -> Vec::<E::Fr> {
+        let mut inputs = vec![
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        ];
+        inputs.extend(self.total_fee);
+        inputs
+    }
+
+    // 32 bytes big endian per input, the layout of the contract calldata
+    pub fn to_be_bytes(&self) -> Vec::<u8> {
+        let mut bytes = Vec::new();
+        for input in self.to_vec() {
+            input.into_repr().write_be(&mut bytes).unwrap();
+        }
+        bytes
+    }
+}
+
+fn alloc_inputs<E, CS>(
+    mut cs: CS,
+    old_accum_hash: Option::<E::Fr>,
+    new_accum_hash: Option::<E::Fr>,
+    old_account_root: Option::<E::Fr>,
+    new_account_root: Option::<E::Fr>,
+    inputize: bool,
+) -> Result<AllocatedPublicInputs<E>, SynthesisError>
+    where E: Engine,
+          CS: ConstraintSystem<E>,
+{
+    let old_accum_hash = AllocatedNum::alloc(
+        cs.namespace(|| "allocate old accum hash"),
+        || old_accum_hash.ok_or(SynthesisError::AssignmentMissing),
+    )?;
+
+    let new_accum_hash = AllocatedNum::alloc(
+        cs.namespace(|| "allocate new accum hash"),
+        || new_accum_hash.ok_or(SynthesisError::AssignmentMissing),
+    )?;
+
+    let old_account_root = AllocatedNum::alloc(
+        cs.namespace(|| "allocate old root"),
+        || old_account_root.ok_or(SynthesisError::AssignmentMissing),
+    )?;
+
+    let new_account_root = AllocatedNum::alloc(
+        cs.namespace(|| "allocate new root"),
+        || new_account_root.ok_or(SynthesisError::AssignmentMissing),
+    )?;
+
+    if inputize {
+        old_accum_hash.inputize(cs.namespace(|| "input old accum hash"))?;
+        new_accum_hash.inputize(cs.namespace(|| "input new accum hash"))?;
+        old_account_root.inputize(cs.namespace(|| "input old root"))?;
+        new_account_root.inputize(cs.namespace(|| "input new root"))?;
+    }
+
+    Ok(AllocatedPublicInputs {
+        old_accum_hash,
+        new_accum_hash,
+        old_account_root,
+        new_account_root,
+    })
+}
+
+// allocates and inputizes in the order of PublicInputs::to_vec
+pub fn alloc_public_inputs<E, CS>(
+    cs: CS,
+    old_accum_hash: Option::<E::Fr>,
+    new_accum_hash: Option::<E::Fr>,
+    old_account_root: Option::<E::Fr>,
+    new_account_root: Option::<E::Fr>,
+) -> Result<AllocatedPublicInputs<E>, SynthesisError>
+    where E: Engine,
+          CS: ConstraintSystem<E>,
+{
+    alloc_inputs(cs, old_accum_hash, new_accum_hash, old_account_root, new_account_root, true)
+}
+
+// the fee total the block claims, inputized right after alloc_public_inputs.
+// the circuit enforces it equals the sum of the fees it credits
+pub fn alloc_total_fee_input<E, CS>(
+    mut cs: CS,
+    total_fee: Option::<E::Fr>,
+) -> Result<AllocatedNum<E>, SynthesisError>
+    where E: Engine,
+          CS: ConstraintSystem<E>,
+{
+    let total_fee = AllocatedNum::alloc(
+        cs.namespace(|| "allocate total fee"),
+        || total_fee.ok_or(SynthesisError::AssignmentMissing),
+    )?;
+    total_fee.inputize(cs.namespace(|| "input total fee"))?;
+
+    Ok(total_fee)
+}
+
+// allocates the same values as witnesses and inputizes only their commitment,
+// see compute_block_commitment
+pub fn alloc_committed_public_inputs<E, CS>(
+    mut cs: CS,
+    hash_params: &<E as PoseidonEngine>::Params,
+    old_accum_hash: Option::<E::Fr>,
+    new_accum_hash: Option::<E::Fr>,
+    old_account_root: Option::<E::Fr>,
+    new_account_root: Option::<E::Fr>,
+) -> Result<AllocatedPublicInputs<E>, SynthesisError>
+    where E: PoseidonEngine<SBox = QuinticSBox<E>>,
+          CS: ConstraintSystem<E>,
+{
+    let inputs = alloc_inputs(
+        cs.namespace(|| "allocate committed values"),
+        old_accum_hash,
+        new_accum_hash,
+        old_account_root,
+        new_account_root,
+        false,
+    )?;
+
+    let commitment = poseidon_hash_gadget(
+        cs.namespace(|| "calculate block commitment"),
+        &[
+            inputs.old_accum_hash.clone(),
+            inputs.new_accum_hash.clone(),
+            inputs.old_account_root.clone(),
+            inputs.new_account_root.clone(),
+        ],
+        hash_params,
+    )?[0].clone();
+
+    commitment.inputize(cs.namespace(|| "input block commitment"))?;
+
+    Ok(inputs)
+}
+
+// the only public input of committed batch circuits, hash of PublicInputs::to_vec
+pub fn compute_block_commitment<E: PoseidonEngine>(
+    hash_params: &<E as PoseidonEngine>::Params,
+    public_inputs: &PublicInputs<E>,
+) -> E::Fr {
+    poseidon_hash::<E>(hash_params, &public_inputs.to_vec())[0]
+}
+
+pub fn verify_block_proof<E: Engine>(
+    verifying_key: &PreparedVerifyingKey<E>,
+    proof: &Proof<E>,
+    public_inputs: &PublicInputs<E>,
+) -> Result<bool, SynthesisError> {
+    verify_proof(verifying_key, proof, &public_inputs.to_vec())
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+
+use serde::{ Serialize, Deserialize };
+
+use sapling_crypto_ce::{
+    eddsa::{ PublicKey, Signature },
+    jubjub::FixedGenerators,
+    alt_babyjubjub::AltJubjubBn256,
+    poseidon::poseidon_hash,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use super::{
+    data_structs::offchain_withdrawal::{ OffchainWithdrawal, SignatureError, is_canonical_point },
+    data_structs::encoding::{ Encoder, Decoder, EncodingError, HEADER_BYTES, SIGNATURE_BYTES },
+    keys::SecretKey,
+    params::{ poseidon_params, jubjub_params },
+    types::{ AccountId, Nonce },
+    utils::domain::SigningDomain,
+    utils::op_type::RECEIPT_OP,
+    utils::{ serde_fr, serde_sign },
+    utils::utils::{ usize_to_fr, fr_to_hex, fr_to_sign_message, deterministic_rng },
+    utils::signature::NUM_BYTES_TO_SIGN,
+};
+use crate::error::OpenPlasmaError;
+
+// request hash, account id, nonce, promised block and the signature. the
+// block is 0 for none and block + 1 for a promise, in the hash as well
+pub const RECEIPT_BYTES: usize = HEADER_BYTES + 32 + 4 + 4 + 8 + SIGNATURE_BYTES;
+
+// the operator's signed statement that the request with request_hash, the
+// hash its owner signed, is accepted, and with a promised_block that it is
+// in a block up to that one. the client keeps it with the request: a
+// receipt whose block passed without the request is evidence against the
+// operator. a request the next batch can't execute yet is accepted without
+// a block. signed with the operator's own key, not an account key, hashed
+// with the shared params
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Receipt {
+    #[serde(with = "serde_fr")]
+    pub request_hash: bn256::Fr,
+    pub account_id: AccountId,
+    pub nonce: Nonce,
+    pub promised_block: Option::<usize>,
+    #[serde(with = "serde_sign")]
+    pub operator_sig: Signature::<Bn256>,
+}
+
+// by hand, Signature has neither
+impl PartialEq for Receipt {
+    fn eq(&self, other: &Self) -> bool {
+        self.request_hash == other.request_hash
+            && self.account_id == other.account_id
+            && self.nonce == other.nonce
+            && self.promised_block == other.promised_block
+            && self.operator_sig.r == other.operator_sig.r
+            && self.operator_sig.s == other.operator_sig.s
+    }
+}
+
+impl fmt::Debug for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Receipt")
+            .field("request_hash", &fr_to_hex(&self.request_hash))
+            .field("account_id", &self.account_id)
+            .field("nonce", &self.nonce)
+            .field("promised_block", &self.promised_block)
+            .finish()
+    }
+}
+
+fn block_word(promised_block: Option::<usize>) -> usize {
+    promised_block.map_or(0, |block| block + 1)
+}
+
+impl Receipt {
+    fn hash(
+        request_hash: bn256::Fr,
+        account_id: AccountId,
+        nonce: Nonce,
+        promised_block: Option::<usize>,
+    ) -> bn256::Fr {
+        poseidon_hash::<Bn256>(poseidon_params(), &[
+            usize_to_fr(RECEIPT_OP),
+            request_hash,
+            account_id.to_fr(),
+            nonce.to_fr(),
+            usize_to_fr(block_word(promised_block)),
+        ])[0]
+    }
+
+    // deterministic, the same promise always gets the same signature
+    pub fn sign(
+        request_hash: bn256::Fr,
+        account_id: AccountId,
+        nonce: Nonce,
+        promised_block: Option::<usize>,
+        operator_key: &SecretKey,
+    ) -> Self {
+        let hash_bytes = fr_to_sign_message(Self::hash(request_hash, account_id, nonce, promised_block));
+        let seckey = operator_key.expose_private_key();
+
+        let operator_sig = seckey.sign_raw_message(
+            &hash_bytes,
+            &mut deterministic_rng(seckey, &hash_bytes),
+            FixedGenerators::SpendingKeyGenerator,
+            jubjub_params(),
+            NUM_BYTES_TO_SIGN,
+        );
+
+        Receipt {
+            request_hash,
+            account_id,
+            nonce,
+            promised_block,
+            operator_sig,
+        }
+    }
+
+    // the receipt of a withdrawal, its hash in the domain it was signed for
+    pub fn for_withdrawal(
+        withdrawal: &OffchainWithdrawal,
+        domain: &SigningDomain,
+        promised_block: Option::<usize>,
+        operator_key: &SecretKey,
+    ) -> Self {
+        Self::sign(withdrawal.hash(domain, None), withdrawal.account_id, withdrawal.nonce, promised_block, operator_key)
+    }
+
+    // the operator's pubkey is published out of band, as the signing domain
+    pub fn verify(&self, operator_pubkey: &PublicKey::<Bn256>) -> Result<(), OpenPlasmaError> {
+        let sign_params = jubjub_params();
+        if !is_canonical_point(&self.operator_sig.r, sign_params) || !is_canonical_point(&operator_pubkey.0, sign_params) {
+            return Err(SignatureError::InvalidPoint.into());
+        }
+
+        let hash = Self::hash(self.request_hash, self.account_id, self.nonce, self.promised_block);
+        if !operator_pubkey.verify_for_raw_message(
+            &fr_to_sign_message(hash),
+            &self.operator_sig,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        ) {
+            return Err(SignatureError::VerificationFailed.into());
+        }
+
+        Ok(())
+    }
+
+    // the receipt is for this request
+    pub fn covers(&self, withdrawal: &OffchainWithdrawal, domain: &SigningDomain) -> bool {
+        self.request_hash == withdrawal.hash(domain, None)
+            && self.account_id == withdrawal.account_id
+            && self.nonce == withdrawal.nonce
+    }
+
+    // see data_structs::encoding, the request hash is 32 bytes little endian
+    pub fn encode(&self) -> Vec::<u8> {
+        let mut encoder = Encoder::new(RECEIPT_OP, RECEIPT_BYTES);
+        encoder.fr(&self.request_hash);
+        encoder.account_id(self.account_id);
+        encoder.nonce(self.nonce);
+        encoder.u64(block_word(self.promised_block));
+        encoder.sign(&Some(self.operator_sig.clone())).expect("a receipt is always signed");
+        encoder.finish()
+    }
+
+    pub fn decode(bytes: &[u8], sign_params: Option<&AltJubjubBn256>) -> Result<Self, OpenPlasmaError> {
+        let sign_params = sign_params.unwrap_or_else(|| jubjub_params());
+        let mut decoder = Decoder::new(bytes, RECEIPT_OP, RECEIPT_BYTES)?;
+        Ok(Receipt {
+            request_hash: decoder.fr("request hash")?,
+            account_id: decoder.account_id(),
+            nonce: decoder.nonce(),
+            promised_block: match decoder.u64("promised block")? {
+                0 => None,
+                word => Some(word - 1),
+            },
+            operator_sig: decoder.sign(sign_params)?.ok_or(EncodingError::Unsigned)?,
+        })
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    fmt,
+    error::Error,
+    io::{ self, BufRead, Read, Write },
+};
+
+use sapling_crypto_ce::{
+    poseidon::bn256::Bn256PoseidonParams,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::bn256;
+
+use ff_ce::Field;
+
+use super::{
+    block::{ Pubdata, PubdataOp, replay_pubdata },
+    error::OpenPlasmaError,
+    tree::account::{ AccountsTree, MAX_TREE_DEPTH, invalid_data, read_fr, write_fr },
+    types::AccountId,
+    utils::checksum::Fnv64,
+};
+
+const JOURNAL_MAGIC: &[u8; 4] = b"OPAJ";
+const JOURNAL_VERSION: u8 = 1;
+
+// what a replica needs besides the blocks: the tree shape and where the
+// operator credits fees
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JournalHeader {
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub fee_account_id: AccountId,
+    pub fee_token_id: usize,
+}
+
+// accum_hash is the pubdata commitment chained from the previous block's,
+// zero before block 1, see Pubdata::commitment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalBlock {
+    pub block_number: u64,
+    pub old_root: bn256::Fr,
+    pub new_root: bn256::Fr,
+    pub accum_hash: bn256::Fr,
+    pub pubdata: Pubdata,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    IoError(io::Error),
+    // the checksum of the block doesn't match, e.g. an append cut short
+    Corrupt { block_number: u64 },
+    BlockOutOfOrder { expected: u64, actual: u64 },
+    EncodingError { block_number: u64, error: OpenPlasmaError },
+    TreeError { block_number: u64, error: OpenPlasmaError },
+    OldRootMismatch { block_number: u64, journal: bn256::Fr, replayed: bn256::Fr },
+    NewRootMismatch { block_number: u64, journal: bn256::Fr, replayed: bn256::Fr },
+    AccumMismatch { block_number: u64, journal: bn256::Fr, replayed: bn256::Fr },
+}
+
+impl Error for ReplayError {}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ReplayError::IoError(e) => write!(f, "I/O error: {}", e),
+            ReplayError::Corrupt { block_number } => write!(f, "Block {} is corrupt", block_number),
+            ReplayError::BlockOutOfOrder { expected, actual } => write!(
+                f, "Block {} follows in place of block {}", actual, expected),
+            ReplayError::EncodingError { block_number, error } => write!(
+                f, "Block {} has malformed pubdata: {}", block_number, error),
+            ReplayError::TreeError { block_number, error } => write!(
+                f, "Block {} doesn't apply: {}", block_number, error),
+            ReplayError::OldRootMismatch { block_number, journal, replayed } => write!(
+                f, "Block {} starts at root {}, replay is at {}", block_number, journal, replayed),
+            ReplayError::NewRootMismatch { block_number, journal, replayed } => write!(
+                f, "Block {} ends at root {}, replay gives {}", block_number, journal, replayed),
+            ReplayError::AccumMismatch { block_number, journal, replayed } => write!(
+                f, "Block {} has accum hash {}, its pubdata gives {}", block_number, journal, replayed),
+        }
+    }
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        ReplayError::IoError(err)
+    }
+}
+
+// magic, version, account and token depths, fee account id and fee token id
+// as u32, then the blocks as they are appended: block number u64, old root,
+// new root and accum hash, the pubdata length u32 and the pubdata, then the
+// fnv-1a 64 of the block. numbers and field elements are big endian
+pub struct JournalWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JournalWriter<W> {
+    pub fn new(mut writer: W, header: &JournalHeader) -> io::Result<Self> {
+        writer.write_all(JOURNAL_MAGIC)?;
+        writer.write_all(&[JOURNAL_VERSION, header.account_depth as u8, header.token_depth as u8])?;
+        writer.write_all(&header.fee_account_id.0.to_be_bytes())?;
+        writer.write_all(&(header.fee_token_id as u32).to_be_bytes())?;
+        writer.flush()?;
+
+        Ok(JournalWriter { writer })
+    }
+
+    // a block is written at once and flushed, a crash leaves at most a torn last block
+    pub fn append(&mut self, block: &JournalBlock) -> io::Result<()> {
+        let pubdata = block.pubdata.as_bytes();
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&block.block_number.to_be_bytes());
+        write_fr(&mut record, &block.old_root)?;
+        write_fr(&mut record, &block.new_root)?;
+        write_fr(&mut record, &block.accum_hash)?;
+        record.extend_from_slice(&(pubdata.len() as u32).to_be_bytes());
+        record.extend_from_slice(pubdata);
+
+        let mut checksum = Fnv64::new();
+        checksum.update(&record);
+        record.extend_from_slice(&checksum.finish().to_be_bytes());
+
+        self.writer.write_all(&record)?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+pub fn read_journal_header<R: Read>(reader: &mut R) -> io::Result<JournalHeader> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != JOURNAL_MAGIC {
+        return Err(invalid_data("not a journal file"));
+    }
+
+    let mut header = [0u8; 3];
+    reader.read_exact(&mut header)?;
+    let [version, account_depth, token_depth] = header;
+    if version != JOURNAL_VERSION {
+        return Err(invalid_data("unsupported journal version"));
+    }
+    if account_depth as usize > MAX_TREE_DEPTH || token_depth as usize > MAX_TREE_DEPTH {
+        return Err(invalid_data("tree depth is too large"));
+    }
+
+    Ok(JournalHeader {
+        account_depth: account_depth as usize,
+        token_depth: token_depth as usize,
+        fee_account_id: AccountId(read_u32(reader)?),
+        fee_token_id: read_u32(reader)? as usize,
+    })
+}
+
+// None at the end of the journal, block_number is the one expected next
+fn read_block<R: BufRead>(
+    reader: &mut R,
+    block_number: u64,
+    sign_params: &AltJubjubBn256,
+) -> Result<Option<(JournalBlock, Vec::<PubdataOp>)>, ReplayError> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let corrupt = |err: io::Error| match err.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => ReplayError::Corrupt { block_number },
+        _ => ReplayError::IoError(err),
+    };
+
+    let mut record = vec![0u8; 8 + 3 * 32 + 4];
+    reader.read_exact(&mut record).map_err(corrupt)?;
+    let pubdata_len = read_u32(&mut &record[record.len() - 4..])? as usize;
+    let mut pubdata = vec![0u8; pubdata_len];
+    reader.read_exact(&mut pubdata).map_err(corrupt)?;
+    let expected_checksum = read_u64(reader).map_err(corrupt)?;
+
+    let mut checksum = Fnv64::new();
+    checksum.update(&record);
+    checksum.update(&pubdata);
+    if checksum.finish() != expected_checksum {
+        return Err(ReplayError::Corrupt { block_number });
+    }
+
+    let mut fields = &record[..];
+    let actual = read_u64(&mut fields)?;
+    if actual != block_number {
+        return Err(ReplayError::BlockOutOfOrder { expected: block_number, actual });
+    }
+    let old_root = read_fr(&mut fields).map_err(corrupt)?;
+    let new_root = read_fr(&mut fields).map_err(corrupt)?;
+    let accum_hash = read_fr(&mut fields).map_err(corrupt)?;
+
+    let encoding_error = |error| ReplayError::EncodingError { block_number, error };
+    let ops = Pubdata::parse(&pubdata, sign_params).map_err(encoding_error)?;
+    let mut parsed = Pubdata::default();
+    for op in ops.iter() {
+        parsed.push_op(op).map_err(encoding_error)?;
+    }
+
+    Ok(Some((JournalBlock { block_number, old_root, new_root, accum_hash, pubdata: parsed }, ops)))
+}
+
+// applies every block of the journal to an empty tree and checks its roots
+// and accum hash, the first block that doesn't agree is the error. the tree
+// history gets every block, as the operator finalizes them
+pub fn replay_journal<'a, R: BufRead>(
+    mut reader: R,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &'a AltJubjubBn256,
+) -> Result<(AccountsTree<'a>, u64), ReplayError> {
+    let header = read_journal_header(&mut reader)?;
+
+    let mut tree = AccountsTree::new(header.account_depth, header.token_depth, hash_params, sign_params);
+    let tree_error = |block_number, error| ReplayError::TreeError { block_number, error };
+    tree.finalize_block(0).map_err(|error| tree_error(0, error))?;
+
+    let mut accum_hash = bn256::Fr::zero();
+    let mut block_number = 1;
+    while let Some((block, ops)) = read_block(&mut reader, block_number, sign_params)? {
+        let replayed = tree.get_root();
+        if block.old_root != replayed {
+            return Err(ReplayError::OldRootMismatch { block_number, journal: block.old_root, replayed });
+        }
+
+        replay_pubdata(&mut tree, &ops, header.fee_account_id, header.fee_token_id)
+            .map_err(|error| tree_error(block_number, error))?;
+
+        let replayed = tree.get_root();
+        if block.new_root != replayed {
+            return Err(ReplayError::NewRootMismatch { block_number, journal: block.new_root, replayed });
+        }
+
+        let replayed = block.pubdata.commitment(accum_hash);
+        if block.accum_hash != replayed {
+            return Err(ReplayError::AccumMismatch { block_number, journal: block.accum_hash, replayed });
+        }
+
+        tree.finalize_block(block_number as usize).map_err(|error| tree_error(block_number, error))?;
+        accum_hash = block.accum_hash;
+        block_number += 1;
+    }
+
+    Ok((tree, block_number - 1))
+}
@@ -0,0 +1,283 @@
+use bellman_ce::{
+    ConstraintSystem,
+    LinearCombination,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    circuit::num::AllocatedNum,
+    group_hash::GroupHasher,
+    constants::GH_FIRST_BLOCK,
+};
+
+use pairing_ce::bn256::{ Bn256, Fr };
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+// rescue over the bn256 scalar field: a state of 3 with rate 2, every round
+// is x^5 and x^(1/5) on the whole state, each followed by the mds matrix and
+// round constants. 22 rounds as the 2 to 1 bn256 parameters of franklin-crypto,
+// the constants are derived here as sapling derives the poseidon ones, so the
+// hashes are not franklin's
+pub const RESCUE_WIDTH: usize = 3;
+pub const RESCUE_RATE: usize = 2;
+pub const RESCUE_ROUNDS: usize = 22;
+
+// 1/5 mod r - 1, little endian limbs, r - 1 is not a multiple of 5
+const ALPHA_INV: [u64; 4] = [
+    0xcfe7_f7a9_8ccc_cccd,
+    0x535c_b9d3_9494_5a0d,
+    0x9373_6af8_679a_ad17,
+    0x26b6_a528_b427_b354,
+];
+
+pub struct Bn256RescueParams {
+    // the initial key, then two per round
+    round_constants: Vec::<[Fr; RESCUE_WIDTH]>,
+    mds_matrix: [[Fr; RESCUE_WIDTH]; RESCUE_WIDTH],
+}
+
+impl Bn256RescueParams {
+    pub fn new<H: GroupHasher>() -> Self {
+        let mut constants = Vec::new();
+        let mut nonce = 0u32;
+        while constants.len() < (2 * RESCUE_ROUNDS + 1) * RESCUE_WIDTH {
+            let mut h = H::new(&b"Rescue_k"[..]);
+            h.update(GH_FIRST_BLOCK);
+            h.update(&nonce.to_be_bytes());
+            let h = h.finalize();
+
+            let mut repr = <Fr as PrimeField>::Repr::default();
+            repr.read_le(&h[..]).unwrap();
+            if let Ok(constant) = Fr::from_repr(repr) {
+                if !constant.is_zero() {
+                    constants.push(constant);
+                }
+            }
+            nonce += 1;
+        }
+
+        let round_constants = constants.chunks(RESCUE_WIDTH)
+            .map(|chunk| [chunk[0], chunk[1], chunk[2]])
+            .collect();
+
+        // the cauchy matrix 1 / (x_i + y_j) with x_i = i and y_j = width + j,
+        // the sums are distinct and never zero, so every square minor is invertible
+        let mut mds_matrix = [[Fr::zero(); RESCUE_WIDTH]; RESCUE_WIDTH];
+        for (i, row) in mds_matrix.iter_mut().enumerate() {
+            for (j, element) in row.iter_mut().enumerate() {
+                let sum = Fr::from_str(&(i + RESCUE_WIDTH + j).to_string()).unwrap();
+                *element = sum.inverse().unwrap();
+            }
+        }
+
+        Bn256RescueParams { round_constants, mds_matrix }
+    }
+
+    fn round_key(&self, index: usize) -> &[Fr; RESCUE_WIDTH] {
+        &self.round_constants[index]
+    }
+}
+
+fn mds(params: &Bn256RescueParams, state: &[Fr; RESCUE_WIDTH], key: &[Fr; RESCUE_WIDTH]) -> [Fr; RESCUE_WIDTH] {
+    let mut result = *key;
+    for (element, row) in result.iter_mut().zip(params.mds_matrix.iter()) {
+        for (coeff, value) in row.iter().zip(state.iter()) {
+            let mut term = *coeff;
+            term.mul_assign(value);
+            element.add_assign(&term);
+        }
+    }
+    result
+}
+
+fn quintic(value: &Fr) -> Fr {
+    let mut result = *value;
+    result.square();
+    result.square();
+    result.mul_assign(value);
+    result
+}
+
+pub fn rescue_permutation(params: &Bn256RescueParams, state: &mut [Fr; RESCUE_WIDTH]) {
+    for (element, key) in state.iter_mut().zip(params.round_key(0).iter()) {
+        element.add_assign(key);
+    }
+
+    for round in 0..RESCUE_ROUNDS {
+        for element in state.iter_mut() {
+            *element = quintic(element);
+        }
+        *state = mds(params, state, params.round_key(2 * round + 1));
+
+        for element in state.iter_mut() {
+            *element = element.pow(ALPHA_INV);
+        }
+        *state = mds(params, state, params.round_key(2 * round + 2));
+    }
+}
+
+// a sponge with the input length in the capacity element, so inputs that
+// differ only by trailing zeros don't collide
+pub fn rescue_hash(params: &Bn256RescueParams, input: &[Fr]) -> Fr {
+    let mut state = [Fr::zero(); RESCUE_WIDTH];
+    state[RESCUE_RATE] = Fr::from_str(&input.len().to_string()).unwrap();
+
+    if input.is_empty() {
+        rescue_permutation(params, &mut state);
+    }
+    for chunk in input.chunks(RESCUE_RATE) {
+        for (element, value) in state.iter_mut().zip(chunk.iter()) {
+            element.add_assign(value);
+        }
+        rescue_permutation(params, &mut state);
+    }
+
+    state[0]
+}
+
+// a state element of the gadget, the linear layers only grow the combinations
+#[derive(Clone)]
+struct Element {
+    lc: LinearCombination<Bn256>,
+    value: Option::<Fr>,
+}
+
+impl Element {
+    fn constant<CS: ConstraintSystem<Bn256>>(value: Fr) -> Self {
+        Element { lc: LinearCombination::zero() + (value, CS::one()), value: Some(value) }
+    }
+
+    fn num(num: &AllocatedNum<Bn256>) -> Self {
+        Element { lc: LinearCombination::zero() + num.get_variable(), value: num.get_value() }
+    }
+
+    fn add(&mut self, other: &Element) {
+        self.lc = self.lc.clone() + &other.lc;
+        self.value = self.value.and_then(|value| other.value.map(|other| {
+            let mut sum = value;
+            sum.add_assign(&other);
+            sum
+        }));
+    }
+}
+
+// x^5 in 3 constraints
+fn quintic_gadget<CS: ConstraintSystem<Bn256>>(
+    mut cs: CS,
+    x: &Element,
+) -> Result<Element, SynthesisError> {
+    let x2 = AllocatedNum::alloc(cs.namespace(|| "x^2"), || {
+        x.value.map(|mut value| {
+            value.square();
+            value
+        }).ok_or(SynthesisError::AssignmentMissing)
+    })?;
+    cs.enforce(|| "enforce x^2", |_| x.lc.clone(), |_| x.lc.clone(), |lc| lc + x2.get_variable());
+
+    let x4 = x2.square(cs.namespace(|| "x^4"))?;
+
+    let x5 = AllocatedNum::alloc(cs.namespace(|| "x^5"), || {
+        x.value.map(|value| quintic(&value)).ok_or(SynthesisError::AssignmentMissing)
+    })?;
+    cs.enforce(|| "enforce x^5", |lc| lc + x4.get_variable(), |_| x.lc.clone(), |lc| lc + x5.get_variable());
+
+    Ok(Element::num(&x5))
+}
+
+// y = x^(1/5) witnessed and checked as y^5 = x, also 3 constraints
+fn quintic_root_gadget<CS: ConstraintSystem<Bn256>>(
+    mut cs: CS,
+    x: &Element,
+) -> Result<Element, SynthesisError> {
+    let y = AllocatedNum::alloc(cs.namespace(|| "y"), || {
+        x.value.map(|value| value.pow(ALPHA_INV)).ok_or(SynthesisError::AssignmentMissing)
+    })?;
+    let y2 = y.square(cs.namespace(|| "y^2"))?;
+    let y4 = y2.square(cs.namespace(|| "y^4"))?;
+    cs.enforce(|| "y^5", |lc| lc + y4.get_variable(), |lc| lc + y.get_variable(), |_| x.lc.clone());
+
+    Ok(Element::num(&y))
+}
+
+fn mds_gadget<CS: ConstraintSystem<Bn256>>(
+    params: &Bn256RescueParams,
+    state: &[Element],
+    key: &[Fr; RESCUE_WIDTH],
+) -> Vec::<Element> {
+    params.mds_matrix.iter().zip(key.iter()).map(|(row, key)| {
+        let mut element = Element::constant::<CS>(*key);
+        for (coeff, input) in row.iter().zip(state.iter()) {
+            let scaled = Element {
+                lc: LinearCombination::zero() + (*coeff, &input.lc),
+                value: input.value.map(|mut value| {
+                    value.mul_assign(coeff);
+                    value
+                }),
+            };
+            element.add(&scaled);
+        }
+        element
+    }).collect()
+}
+
+fn rescue_permutation_gadget<CS: ConstraintSystem<Bn256>>(
+    mut cs: CS,
+    params: &Bn256RescueParams,
+    state: Vec::<Element>,
+) -> Result<Vec::<Element>, SynthesisError> {
+    let mut state: Vec<_> = state.into_iter().zip(params.round_key(0).iter()).map(|(mut element, key)| {
+        element.add(&Element::constant::<CS>(*key));
+        element
+    }).collect();
+
+    for round in 0..RESCUE_ROUNDS {
+        let powered = state.iter().enumerate().map(|(i, element)| quintic_gadget(
+            cs.namespace(|| format!("round {} x^5 {}", round, i)),
+            element,
+        )).collect::<Result<Vec<_>, _>>()?;
+        state = mds_gadget::<CS>(params, &powered, params.round_key(2 * round + 1));
+
+        let rooted = state.iter().enumerate().map(|(i, element)| quintic_root_gadget(
+            cs.namespace(|| format!("round {} root {}", round, i)),
+            element,
+        )).collect::<Result<Vec<_>, _>>()?;
+        state = mds_gadget::<CS>(params, &rooted, params.round_key(2 * round + 2));
+    }
+
+    Ok(state)
+}
+
+// rescue_hash in the circuit
+pub fn rescue_hash_gadget<CS: ConstraintSystem<Bn256>>(
+    mut cs: CS,
+    params: &Bn256RescueParams,
+    input: &[AllocatedNum<Bn256>],
+) -> Result<AllocatedNum<Bn256>, SynthesisError> {
+    let mut state = vec![Element::constant::<CS>(Fr::zero()); RESCUE_WIDTH];
+    state[RESCUE_RATE] = Element::constant::<CS>(Fr::from_str(&input.len().to_string()).unwrap());
+
+    if input.is_empty() {
+        state = rescue_permutation_gadget(cs.namespace(|| "permutation"), params, state)?;
+    }
+    for (i, chunk) in input.chunks(RESCUE_RATE).enumerate() {
+        for (element, num) in state.iter_mut().zip(chunk.iter()) {
+            element.add(&Element::num(num));
+        }
+        state = rescue_permutation_gadget(cs.namespace(|| format!("absorb {}", i)), params, state)?;
+    }
+
+    let output = &state[0];
+    let hash = AllocatedNum::alloc(
+        cs.namespace(|| "allocate hash"),
+        || output.value.ok_or(SynthesisError::AssignmentMissing),
+    )?;
+    cs.enforce(
+        || "enforce hash",
+        |_| output.lc.clone(),
+        |lc| lc + CS::one(),
+        |lc| lc + hash.get_variable(),
+    );
+
+    Ok(hash)
+}
@@ -0,0 +1,227 @@
+use std::{
+    fmt,
+    error::Error,
+    sync::Arc,
+};
+
+use bellman_ce::Circuit;
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use ff_ce::Field;
+
+use crate::deposit_circuit::DepositBatchCircuit;
+use crate::data_structs::offchain_deposit::OffchainDeposit;
+use crate::family::BatchConfig;
+use crate::tree::account::AccountsTree;
+use crate::error::OpenPlasmaError;
+use crate::testing::{ check_circuit, UnsatisfiedAt };
+use crate::types::{ Balance, AccountId };
+use crate::keys::SecretKey;
+use crate::params::Params;
+
+// a change of one value of the witness of a batch operation, the circuit has
+// to reject every one of them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mutation {
+    Amount { operation: usize },
+    // a sibling of the account path, level 0 is next to the leaf
+    PathSibling { operation: usize, level: usize },
+    Nonce { operation: usize },
+}
+
+impl Mutation {
+    pub fn operation(&self) -> usize {
+        match self {
+            Mutation::Amount { operation } => *operation,
+            Mutation::PathSibling { operation, .. } => *operation,
+            Mutation::Nonce { operation } => *operation,
+        }
+    }
+}
+
+// a sequence of operations over a tree: run applies them with the off-circuit
+// state machine and records the witness of the batch circuit that replays
+// them. a circuit plugs in with its own scenario type
+pub trait Scenario {
+    type Circuit: Circuit<Bn256> + Clone;
+
+    fn run(
+        &self,
+        tree: &mut AccountsTree,
+        params: &Arc<Params<Bn256>>,
+    ) -> Result<Self::Circuit, OpenPlasmaError>;
+
+    // the root the circuit is given as its new root public input
+    fn new_root(circuit: &Self::Circuit) -> Option<bn256::Fr>;
+
+    // false if there is no such value in the witness
+    fn mutate(circuit: &mut Self::Circuit, mutation: Mutation) -> bool;
+}
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    // the off-circuit state machine rejected the scenario
+    Tree(OpenPlasmaError),
+    Unsatisfied(UnsatisfiedAt),
+    RootMismatch,
+    NotApplicable(Mutation),
+    // the circuit took a changed witness
+    MutationAccepted(Mutation),
+}
+
+impl Error for ScenarioError {}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ScenarioError::Tree(err) => write!(f, "{}", err),
+            ScenarioError::Unsatisfied(err) => write!(f, "{}", err),
+            ScenarioError::RootMismatch => write!(f, "Circuit new root is not the tree root"),
+            ScenarioError::NotApplicable(mutation) => write!(f, "{:?} is not in the witness", mutation),
+            ScenarioError::MutationAccepted(mutation) => write!(f, "Circuit accepts {:?}", mutation),
+        }
+    }
+}
+
+impl From<OpenPlasmaError> for ScenarioError {
+    fn from(err: OpenPlasmaError) -> Self {
+        ScenarioError::Tree(err)
+    }
+}
+
+impl From<UnsatisfiedAt> for ScenarioError {
+    fn from(err: UnsatisfiedAt) -> Self {
+        ScenarioError::Unsatisfied(err)
+    }
+}
+
+// runs the scenario on the tree, the circuit has to be satisfied and end at
+// the root the tree ends at
+pub fn check_scenario<S: Scenario>(
+    scenario: &S,
+    tree: &mut AccountsTree,
+    params: &Arc<Params<Bn256>>,
+) -> Result<S::Circuit, ScenarioError> {
+    let circuit = scenario.run(tree, params)?;
+    check_circuit(circuit.clone())?;
+
+    if S::new_root(&circuit) != Some(tree.get_root()) {
+        return Err(ScenarioError::RootMismatch);
+    }
+
+    Ok(circuit)
+}
+
+// the mutated witness of a circuit check_scenario accepted has to be rejected
+pub fn check_mutation<S: Scenario>(
+    circuit: &S::Circuit,
+    mutation: Mutation,
+) -> Result<UnsatisfiedAt, ScenarioError> {
+    let mut mutated = circuit.clone();
+    if !S::mutate(&mut mutated, mutation) {
+        return Err(ScenarioError::NotApplicable(mutation));
+    }
+
+    match check_circuit(mutated) {
+        Ok(_) => Err(ScenarioError::MutationAccepted(mutation)),
+        Err(unsatisfied) => Ok(unsatisfied),
+    }
+}
+
+fn add_one(value: &mut Option<bn256::Fr>) {
+    if let Some(value) = value.as_mut() {
+        value.add_assign(&bn256::Fr::one());
+    }
+}
+
+// the same key for an account in every scenario, so deposits never try to
+// overwrite a pubkey
+pub fn scenario_key(account_id: AccountId) -> SecretKey {
+    SecretKey::from_seed(format!("scenario account {}", account_id.0).as_bytes())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DepositStep {
+    pub account_id: AccountId,
+    pub token_id: usize,
+    pub amount: Balance,
+}
+
+// deposits in a batch of their count, starting from a zero accum hash
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DepositScenario {
+    pub steps: Vec::<DepositStep>,
+}
+
+impl Scenario for DepositScenario {
+    type Circuit = DepositBatchCircuit<Bn256>;
+
+    fn run(
+        &self,
+        tree: &mut AccountsTree,
+        params: &Arc<Params<Bn256>>,
+    ) -> Result<Self::Circuit, OpenPlasmaError> {
+        let config = BatchConfig {
+            deposit_batch: self.steps.len(),
+            account_depth: tree.accounts_tree.depth(),
+            token_depth: tree.accounts[0].balances_tree.depth(),
+            leaf_version: tree.leaf_version(),
+        };
+
+        let old_hash = bn256::Fr::zero();
+        let old_root = tree.get_root();
+        let mut accum_hash = old_hash;
+        let mut builder = DepositBatchCircuit::builder(config, params);
+
+        for step in self.steps.iter() {
+            let deposit = OffchainDeposit {
+                account_id: step.account_id,
+                pubkey: scenario_key(step.account_id).public_key(&params.sign_params),
+                token_id: step.token_id,
+                amount: step.amount,
+            };
+
+            let account_state = deposit.update_tree_and_record_state(tree)?;
+            accum_hash = deposit.hash(accum_hash, &params.hash_params);
+            builder = builder.push(deposit.into_circuit(account_state));
+        }
+
+        // every value is set and of the tree's lengths
+        Ok(builder
+            .old_accum_hash(old_hash)
+            .new_accum_hash(accum_hash)
+            .old_account_root(old_root)
+            .new_account_root(tree.get_root())
+            .build()
+            .unwrap())
+    }
+
+    fn new_root(circuit: &Self::Circuit) -> Option<bn256::Fr> {
+        circuit.new_account_root
+    }
+
+    fn mutate(circuit: &mut Self::Circuit, mutation: Mutation) -> bool {
+        let deposit = match circuit.deposit_queue.witnesses_mut()
+            .and_then(|deposits| deposits.get_mut(mutation.operation()))
+        {
+            Some(deposit) => deposit,
+            None => return false,
+        };
+
+        // the nonce a deposit keeps
+        match mutation {
+            Mutation::Amount { .. } => add_one(&mut deposit.amount),
+            Mutation::PathSibling { level, .. } => match deposit.account_state.account_path.get_mut(level) {
+                Some(sibling) => add_one(sibling),
+                None => return false,
+            },
+            Mutation::Nonce { .. } => add_one(&mut deposit.account_state.new_nonce),
+        }
+
+        true
+    }
+}
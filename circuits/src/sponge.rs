@@ -0,0 +1,218 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    poseidon::{
+        PoseidonEngine,
+        PoseidonHashParams,
+        QuinticSBox,
+        poseidon_mimc,
+    },
+    circuit::{
+        num::{ AllocatedNum, Num },
+        boolean::Boolean,
+        poseidon_hash::poseidon_mimc as poseidon_mimc_gadget,
+    },
+};
+
+use ff_ce::Field;
+
+// a sponge over the poseidon permutation of the given params, for inputs that
+// arrive in pieces or outputs longer than one element. the state is the
+// permutation of all zeroes, as in poseidon_hash; its first rate words
+// (absorbtion_cycle_len, 4 of the 5 for the deployed params) take the input
+// and give the output, the rest is the capacity.
+//
+// padding: the first squeeze after absorbing appends a one and then zeroes up
+// to the end of the rate block, also when the input filled the last block, so
+// [x] and [x, 0] and the empty input are three different inputs. then the
+// rate words are squeezed in order and the state is permuted when they run
+// out. absorbing after a squeeze starts over with an empty block and is padded
+// again. poseidon_hash pads with zeroes only, a sponge hash of a record is not
+// its poseidon_hash
+#[derive(Clone)]
+pub struct PoseidonSponge<'a, E: PoseidonEngine> {
+    params: &'a E::Params,
+    state: Vec::<E::Fr>,
+    // absorbed but not yet added to the state, shorter than the rate
+    block: Vec::<E::Fr>,
+    // the next rate word to squeeze, None while absorbing
+    squeezed: Option::<usize>,
+}
+
+impl<'a, E: PoseidonEngine> PoseidonSponge<'a, E> {
+    pub fn new(params: &'a E::Params) -> Self {
+        PoseidonSponge {
+            params,
+            state: poseidon_mimc::<E>(params, &vec![E::Fr::zero(); params.t() as usize]),
+            block: Vec::new(),
+            squeezed: None,
+        }
+    }
+
+    // absorb then squeeze once
+    pub fn hash(params: &'a E::Params, input: &[E::Fr]) -> E::Fr {
+        let mut sponge = Self::new(params);
+        sponge.absorb(input);
+        sponge.squeeze()
+    }
+
+    fn rate(&self) -> usize {
+        self.params.absorbtion_cycle_len() as usize
+    }
+
+    fn absorb_block(&mut self) {
+        for (word, value) in self.state.iter_mut().zip(self.block.drain(..)) {
+            word.add_assign(&value);
+        }
+        self.state = poseidon_mimc::<E>(self.params, &self.state);
+    }
+
+    pub fn absorb(&mut self, input: &[E::Fr]) {
+        self.squeezed = None;
+        for value in input {
+            self.block.push(*value);
+            if self.block.len() == self.rate() {
+                self.absorb_block();
+            }
+        }
+    }
+
+    pub fn squeeze(&mut self) -> E::Fr {
+        let index = match self.squeezed {
+            None => {
+                self.block.push(E::Fr::one());
+                self.block.resize(self.rate(), E::Fr::zero());
+                self.absorb_block();
+                0
+            },
+            Some(index) if index == self.rate() => {
+                self.state = poseidon_mimc::<E>(self.params, &self.state);
+                0
+            },
+            Some(index) => index,
+        };
+        self.squeezed = Some(index + 1);
+
+        self.state[index]
+    }
+}
+
+// PoseidonSponge in the circuit, the same padding and squeeze order. the
+// state stays linear combinations between permutations, every permutation
+// allocates its t input words
+pub struct SpongeGadget<'a, E: PoseidonEngine> {
+    params: &'a E::Params,
+    state: Vec::<Num<E>>,
+    // the words of the last permutation, what is squeezed
+    output: Vec::<AllocatedNum<E>>,
+    block: Vec::<AllocatedNum<E>>,
+    squeezed: Option::<usize>,
+    permutations: usize,
+}
+
+impl<'a, E> SpongeGadget<'a, E>
+    where E: PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    pub fn new<CS: ConstraintSystem<E>>(params: &'a E::Params) -> Self {
+        let initial_state = poseidon_mimc::<E>(params, &vec![E::Fr::zero(); params.t() as usize]);
+        let state = initial_state.into_iter().map(|word| {
+            Num::zero().add_bool_with_coeff(CS::one(), &Boolean::constant(true), word)
+        }).collect();
+
+        SpongeGadget {
+            params,
+            state,
+            output: Vec::new(),
+            block: Vec::new(),
+            squeezed: None,
+            permutations: 0,
+        }
+    }
+
+    // PoseidonSponge::hash in the circuit
+    pub fn hash<CS: ConstraintSystem<E>>(
+        mut cs: CS,
+        params: &'a E::Params,
+        input: &[AllocatedNum<E>],
+    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        let mut sponge = Self::new::<CS>(params);
+        sponge.absorb(cs.namespace(|| "absorb"), input)?;
+        sponge.squeeze(cs.namespace(|| "squeeze"))
+    }
+
+    fn rate(&self) -> usize {
+        self.params.absorbtion_cycle_len() as usize
+    }
+
+    fn permute<CS: ConstraintSystem<E>>(&mut self, mut cs: CS) -> Result<(), SynthesisError> {
+        let mut words = Vec::with_capacity(self.state.len());
+        for (i, word) in self.state.iter().enumerate() {
+            let allocated = AllocatedNum::alloc(
+                cs.namespace(|| format!("allocate word {}", i)),
+                || word.get_value().ok_or(SynthesisError::AssignmentMissing),
+            )?;
+            cs.enforce(
+                || format!("enforce word {}", i),
+                |_| word.lc(E::Fr::one()),
+                |lc| lc + CS::one(),
+                |lc| lc + allocated.get_variable(),
+            );
+            words.push(allocated);
+        }
+
+        self.output = poseidon_mimc_gadget(cs.namespace(|| "permutation"), &words, self.params)?;
+        self.state = self.output.iter().cloned().map(Num::from).collect();
+        self.permutations += 1;
+
+        Ok(())
+    }
+
+    fn absorb_block<CS: ConstraintSystem<E>>(&mut self, cs: CS) -> Result<(), SynthesisError> {
+        for (word, value) in self.state.iter_mut().zip(self.block.drain(..)) {
+            word.mut_add_number_with_coeff(&value, E::Fr::one());
+        }
+        self.permute(cs)
+    }
+
+    pub fn absorb<CS: ConstraintSystem<E>>(
+        &mut self,
+        mut cs: CS,
+        input: &[AllocatedNum<E>],
+    ) -> Result<(), SynthesisError> {
+        self.squeezed = None;
+        for value in input {
+            self.block.push(value.clone());
+            if self.block.len() == self.rate() {
+                let i = self.permutations;
+                self.absorb_block(cs.namespace(|| format!("absorb block {}", i)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn squeeze<CS: ConstraintSystem<E>>(&mut self, mut cs: CS) -> Result<AllocatedNum<E>, SynthesisError> {
+        let i = self.permutations;
+        let index = match self.squeezed {
+            None => {
+                // the padding is constant, the one goes into the word after
+                // the block and the zeroes change nothing
+                let position = self.block.len();
+                self.state[position].mut_add_bool_with_coeff(CS::one(), &Boolean::constant(true), E::Fr::one());
+                self.absorb_block(cs.namespace(|| format!("absorb padded block {}", i)))?;
+                0
+            },
+            Some(index) if index == self.rate() => {
+                self.permute(cs.namespace(|| format!("squeeze permutation {}", i)))?;
+                0
+            },
+            Some(index) => index,
+        };
+        self.squeezed = Some(index + 1);
+
+        Ok(self.output[index].clone())
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    fmt,
+    collections::BTreeMap,
+};
+
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+    Index,
+    LinearCombination,
+    Variable,
+};
+
+use pairing_ce::bn256::{ self, Bn256 };
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+use crate::utils::checksum::Fnv64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitStats {
+    pub constraints: usize,
+    pub aux_variables: usize,
+    // including the constant one, the same as groth16 parameters count inputs
+    pub input_variables: usize,
+}
+
+impl fmt::Display for CircuitStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{} constraints, {} aux variables, {} inputs",
+            self.constraints,
+            self.aux_variables,
+            self.input_variables,
+        )
+    }
+}
+
+// stats and a hash of every constraint, two circuits with the same shape have
+// the same constraint layout, so they can share groth16 parameters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitShape {
+    pub stats: CircuitStats,
+    pub hash: u64,
+}
+
+// counts allocations and constraints, never calls the assignment closures,
+// so a circuit without witness can be measured as well
+struct CountingConstraintSystem {
+    stats: CircuitStats,
+    hash: Fnv64,
+}
+
+impl CountingConstraintSystem {
+    fn new() -> Self {
+        CountingConstraintSystem {
+            stats: CircuitStats {
+                constraints: 0,
+                aux_variables: 0,
+                input_variables: 1,
+            },
+            hash: Fnv64::new(),
+        }
+    }
+
+    fn hash_bytes(&mut self, bytes: &[u8]) {
+        self.hash.update(bytes);
+    }
+
+    fn hash_usize(&mut self, value: usize) {
+        self.hash_bytes(&(value as u64).to_le_bytes());
+    }
+
+    // gadgets may emit the terms in any order, poseidon merges them through a
+    // hash map, so terms are summed per variable and sorted before hashing
+    fn hash_lc(&mut self, lc: &LinearCombination<Bn256>) {
+        let mut terms = BTreeMap::<(u8, usize), bn256::Fr>::new();
+
+        for (variable, coeff) in lc.as_ref() {
+            let key = match variable.get_unchecked() {
+                Index::Input(index) => (0, index),
+                Index::Aux(index) => (1, index),
+            };
+
+            terms.entry(key).or_insert_with(bn256::Fr::zero).add_assign(coeff);
+        }
+
+        terms.retain(|_, coeff| !coeff.is_zero());
+        self.hash_usize(terms.len());
+
+        for ((kind, index), coeff) in terms {
+            self.hash_bytes(&[kind]);
+            self.hash_usize(index);
+
+            let mut repr = Vec::new();
+            coeff.into_repr().write_le(&mut repr).unwrap();
+            self.hash_bytes(&repr);
+        }
+    }
+}
+
+impl ConstraintSystem<Bn256> for CountingConstraintSystem {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(
+        &mut self,
+        _: A,
+        _: F,
+    ) -> Result<Variable, SynthesisError>
+        where F: FnOnce() -> Result<bn256::Fr, SynthesisError>,
+              A: FnOnce() -> AR, AR: Into<String>,
+    {
+        let index = self.stats.aux_variables;
+        self.stats.aux_variables += 1;
+
+        Ok(Variable::new_unchecked(Index::Aux(index)))
+    }
+
+    fn alloc_input<F, A, AR>(
+        &mut self,
+        _: A,
+        _: F,
+    ) -> Result<Variable, SynthesisError>
+        where F: FnOnce() -> Result<bn256::Fr, SynthesisError>,
+              A: FnOnce() -> AR, AR: Into<String>,
+    {
+        let index = self.stats.input_variables;
+        self.stats.input_variables += 1;
+
+        Ok(Variable::new_unchecked(Index::Input(index)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(
+        &mut self,
+        _: A,
+        a: LA,
+        b: LB,
+        c: LC,
+    )
+        where A: FnOnce() -> AR, AR: Into<String>,
+              LA: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+              LB: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+              LC: FnOnce(LinearCombination<Bn256>) -> LinearCombination<Bn256>,
+    {
+        self.stats.constraints += 1;
+
+        self.hash_lc(&a(LinearCombination::zero()));
+        self.hash_lc(&b(LinearCombination::zero()));
+        self.hash_lc(&c(LinearCombination::zero()));
+    }
+
+    fn push_namespace<NR, N>(&mut self, _: N)
+        where NR: Into<String>, N: FnOnce() -> NR,
+    {}
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+pub fn measure<C: Circuit<Bn256>>(circuit: C) -> Result<CircuitStats, SynthesisError> {
+    Ok(shape(circuit)?.stats)
+}
+
+pub fn shape<C: Circuit<Bn256>>(circuit: C) -> Result<CircuitShape, SynthesisError> {
+    let mut cs = CountingConstraintSystem::new();
+
+    circuit.synthesize(&mut cs)?;
+
+    // variables that take part in no constraint still change the parameters
+    let stats = cs.stats;
+    cs.hash_usize(stats.aux_variables);
+    cs.hash_usize(stats.input_variables);
+
+    Ok(CircuitShape {
+        stats,
+        hash: cs.hash.finish(),
+    })
+}
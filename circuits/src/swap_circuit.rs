@@ -0,0 +1,363 @@
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        Unknown,
+        JubjubEngine,
+        edwards::Point,
+    },
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        poseidon_hash::poseidon_hash,
+        num::AllocatedNum,
+    },
+    eddsa::Signature,
+};
+
+use crate::types::{ BALANCE_BITS, NONCE_BITS };
+use crate::utils::sign::verify_signature;
+
+use super::account::{ AccountState, AccountCircuit, LEAF_PUBKEY, LEAF_NONCE };
+use super::transfer_circuit::TransferCircuit;
+use super::public_inputs::alloc_public_inputs;
+use super::utils::op_type::{ alloc_op_type, SWAP_OP };
+use super::utils::calc::{ check_decomposition_le, sub, enforce_bit_length };
+
+// a sells amount_a of token_a to b for amount_b of token_b. Account states
+// are chained in the order: a pays token a, b receives token a, b pays
+// token b, a receives token b, see Swap::update_tree_and_record_state
+#[derive(Clone)]
+pub struct SwapCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub account_states: [AccountState<E>; 4],
+    pub account_id_a: Option::<E::Fr>,
+    pub token_id_a: Option::<E::Fr>,
+    pub amount_a: Option::<E::Fr>,
+    pub nonce_a: Option::<E::Fr>,
+    pub sign_a: Option::<Signature<E>>,
+    pub pubkey_a: Option::<Point<E, Unknown>>,
+    pub account_id_b: Option::<E::Fr>,
+    pub token_id_b: Option::<E::Fr>,
+    pub amount_b: Option::<E::Fr>,
+    pub nonce_b: Option::<E::Fr>,
+    pub sign_b: Option::<Signature<E>>,
+    pub pubkey_b: Option::<Point<E, Unknown>>,
+}
+
+impl<E> SwapCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_swap<'a, CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &'a <E as PoseidonEngine>::Params,
+        sign_params: &'a <E as JubjubEngine>::Params,
+        old_hash: &AllocatedNum<E>,
+        old_root: &AllocatedNum<E>,
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
+
+        // allocate avariables ----------------------------------------------------------
+
+        let mut account_circuits = Vec::with_capacity(self.account_states.len());
+        for (i, account_state) in self.account_states.iter().enumerate() {
+            account_circuits.push(AccountCircuit::new(
+                cs.namespace(|| format!("allocate account circuit {}", i)),
+                account_depth,
+                token_depth,
+                hash_params,
+                account_state,
+            )?);
+        }
+
+        let alloc = |cs: &mut CS, name: &str, value: Option::<E::Fr>| {
+            AllocatedNum::alloc(
+                cs.namespace(|| format!("allocate {}", name)),
+                || value.ok_or(SynthesisError::AssignmentMissing),
+            )
+        };
+
+        let account_id_a = alloc(&mut cs, "account id a", self.account_id_a)?;
+        let token_id_a = alloc(&mut cs, "token id a", self.token_id_a)?;
+        let amount_a = alloc(&mut cs, "amount a", self.amount_a)?;
+        let nonce_a = alloc(&mut cs, "nonce a", self.nonce_a)?;
+        let account_id_b = alloc(&mut cs, "account id b", self.account_id_b)?;
+        let token_id_b = alloc(&mut cs, "token id b", self.token_id_b)?;
+        let amount_b = alloc(&mut cs, "amount b", self.amount_b)?;
+        let nonce_b = alloc(&mut cs, "nonce b", self.nonce_b)?;
+
+        let op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate op type"),
+            SWAP_OP,
+        )?;
+
+        // check signatures, both over the full terms and the own nonce -----------------
+
+        let terms = [
+            op_type_alloc.clone(),
+            account_id_a.clone(),
+            token_id_a.clone(),
+            amount_a.clone(),
+            account_id_b.clone(),
+            token_id_b.clone(),
+            amount_b.clone(),
+        ];
+
+        for (side, nonce, sign, pubkey, payer) in [
+            ("a", &nonce_a, &self.sign_a, &self.pubkey_a, 0),
+            ("b", &nonce_b, &self.sign_b, &self.pubkey_b, 2),
+        ].iter() {
+            let mut message = terms.to_vec();
+            message.push((*nonce).clone());
+
+            let swap_hash = poseidon_hash(
+                cs.namespace(|| format!("calculate message hash {}", side)),
+                &message,
+                hash_params,
+            )?[0].clone();
+
+            let sign_alloc = verify_signature(
+                cs.namespace(|| format!("verify signature {}", side)),
+                (*sign).clone(),
+                (*pubkey).clone(),
+                &swap_hash,
+                sign_params,
+            )?;
+
+            // the signer owns the paying leaf
+
+            TransferCircuit::check_pubkey(
+                cs.namespace(|| format!("public key consistence {}", side)),
+                &sign_alloc.pk,
+                &account_circuits[*payer],
+            )?;
+        }
+
+        // check changes validity -------------------------------------------------------
+
+        // no self swap, the leaves of one account would be chained against each other
+
+        let account_id_diff = sub(
+            cs.namespace(|| "account ids difference"),
+            &account_id_a,
+            &account_id_b,
+        )?;
+
+        account_id_diff.assert_nonzero(
+            cs.namespace(|| "check account ids are different"),
+        )?;
+
+        // (account id, token id, amount, nonce if it is paid) of every update
+
+        let updates = [
+            (&account_id_a, &token_id_a, &amount_a, Some(&nonce_a)),
+            (&account_id_b, &token_id_a, &amount_a, None),
+            (&account_id_b, &token_id_b, &amount_b, Some(&nonce_b)),
+            (&account_id_a, &token_id_b, &amount_b, None),
+        ];
+
+        for (i, (account_circuit, (account_id, token_id, amount, nonce)))
+            in account_circuits.iter().zip(updates.iter()).enumerate()
+        {
+            let old_leaf = &account_circuit.accounts_tree.old_leaf_alloc;
+            let new_leaf = &account_circuit.accounts_tree.new_leaf_alloc;
+            let old_balance = &account_circuit.balances_tree.old_leaf_alloc[0];
+            let new_balance = &account_circuit.balances_tree.new_leaf_alloc[0];
+
+            check_decomposition_le(
+                cs.namespace(|| format!("account id consistence {}", i)),
+                account_id,
+                &account_circuit.accounts_tree.indices_alloc,
+            )?;
+
+            check_decomposition_le(
+                cs.namespace(|| format!("token id consistence {}", i)),
+                token_id,
+                &account_circuit.balances_tree.indices_alloc,
+            )?;
+
+            match nonce {
+                Some(nonce) => {
+                    cs.enforce(
+                        || format!("check amount debit {}", i),
+                        |lc| lc + old_balance.get_variable(),
+                        |lc| lc + CS::one(),
+                        |lc| lc + new_balance.get_variable() + amount.get_variable(),
+                    );
+
+                    cs.enforce(
+                        || format!("nonce consistence {}", i),
+                        |lc| lc + old_leaf[LEAF_NONCE].get_variable() + CS::one(),
+                        |lc| lc + CS::one(),
+                        |lc| lc + nonce.get_variable(),
+                    );
+
+                    cs.enforce(
+                        || format!("check nonce + 1 {}", i),
+                        |lc| lc + old_leaf[LEAF_NONCE].get_variable() + CS::one(),
+                        |lc| lc + CS::one(),
+                        |lc| lc + new_leaf[LEAF_NONCE].get_variable(),
+                    );
+
+                    enforce_bit_length(
+                        cs.namespace(|| format!("check nonce overflow {}", i)),
+                        nonce,
+                        NONCE_BITS,
+                    )?;
+                },
+                None => {
+                    cs.enforce(
+                        || format!("check amount credit {}", i),
+                        |lc| lc + old_balance.get_variable() + amount.get_variable(),
+                        |lc| lc + CS::one(),
+                        |lc| lc + new_balance.get_variable(),
+                    );
+
+                    for (field, j) in [("pubkey", LEAF_PUBKEY), ("nonce", LEAF_NONCE)].iter() {
+                        cs.enforce(
+                            || format!("check receiver {} the same {}", field, i),
+                            |lc| lc + old_leaf[*j].get_variable(),
+                            |lc| lc + CS::one(),
+                            |lc| lc + new_leaf[*j].get_variable(),
+                        );
+                    }
+                },
+            }
+
+            enforce_bit_length(
+                cs.namespace(|| format!("check balance overflow {}", i)),
+                new_balance,
+                BALANCE_BITS,
+            )?;
+        }
+
+        // check amounts for overflow, so they can't exceed payer balances
+
+        enforce_bit_length(
+            cs.namespace(|| "check amount a overflow"),
+            &amount_a,
+            BALANCE_BITS,
+        )?;
+
+        enforce_bit_length(
+            cs.namespace(|| "check amount b overflow"),
+            &amount_b,
+            BALANCE_BITS,
+        )?;
+
+        // calculate new hash -----------------------------------------------------------
+
+        let new_hash = {
+            let hashes_vec = poseidon_hash(
+                cs.namespace(|| "calculate new accum hash"),
+                &[
+                    op_type_alloc,
+                    old_hash.clone(),
+                    account_id_a,
+                    token_id_a,
+                    amount_a,
+                    account_id_b,
+                    token_id_b,
+                    amount_b,
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
+
+        // verify old root & calculate new root, updates are chained --------------------
+
+        let mut root = old_root.clone();
+        for (i, account_circuit) in account_circuits.iter().enumerate() {
+            account_circuit.accounts_tree.verify_old_root(
+                cs.namespace(|| format!("verify old root {}", i)),
+                &root,
+            )?;
+
+            root = account_circuit.accounts_tree.calc_new_root(
+                cs.namespace(|| format!("calculate new root {}", i)),
+            )?;
+        }
+
+        Ok((new_hash, root))
+    }
+}
+
+#[derive(Clone)]
+pub struct SwapBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub batch_size: usize,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub queue: Vec::<SwapCircuit<E>>,
+    pub old_accum_hash: Option::<E::Fr>,
+    pub new_accum_hash: Option::<E::Fr>,
+    pub old_account_root: Option::<E::Fr>,
+    pub new_account_root: Option::<E::Fr>,
+}
+
+impl<'a, E> Circuit<E> for SwapBatchCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        if self.batch_size != self.queue.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
+        )?;
+
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
+
+        for (i, swap) in self.queue.iter().enumerate() {
+            let (hash, root) = swap.process_swap(
+                cs.namespace(|| format!("verify swap {}", i)),
+                self.account_depth,
+                self.token_depth,
+                self.hash_params,
+                self.sign_params,
+                &prev_hash,
+                &prev_root,
+            )?;
+
+            prev_hash = hash;
+            prev_root = root;
+        }
+
+        cs.enforce(
+            || "enforce new accum hash equivalence",
+            |lc| lc + prev_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_hash.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new root equivalence",
+            |lc| lc + prev_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_root.get_variable(),
+        );
+
+        Ok(())
+    }
+}
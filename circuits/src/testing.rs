@@ -0,0 +1,67 @@
+use std::{
+    fmt,
+    error::Error,
+};
+
+use bellman_ce::Circuit;
+
+use sapling_crypto_ce::circuit::test::TestConstraintSystem;
+
+use pairing_ce::bn256::Bn256;
+
+// every batch circuit puts operation i under the "verify <operation> i" namespace
+const OPERATION_NAMESPACE: &str = "verify ";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitReport {
+    pub num_constraints: usize,
+    // including the constant one
+    pub num_inputs: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnsatisfiedAt {
+    // the namespaces and the name of the first unsatisfied constraint, joined with /
+    pub path: String,
+}
+
+impl UnsatisfiedAt {
+    pub fn operation(&self) -> Option<usize> {
+        which_operation(&self.path)
+    }
+}
+
+impl Error for UnsatisfiedAt {}
+
+impl fmt::Display for UnsatisfiedAt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.operation() {
+            Some(i) => write!(f, "Operation {} is unsatisfied at {}", i, self.path),
+            None => write!(f, "Unsatisfied at {}", self.path),
+        }
+    }
+}
+
+// the index of the batch operation a constraint path belongs to, none for the
+// constraints of the batch itself
+pub fn which_operation(path: &str) -> Option<usize> {
+    let namespace = path.split('/').next()?;
+    let operation = namespace.strip_prefix(OPERATION_NAMESPACE)?;
+    operation.rsplit(' ').next()?.parse().ok()
+}
+
+// synthesizes with the witness and checks every constraint, a witness that
+// can't be synthesized at all is a broken test and panics
+pub fn check_circuit<C: Circuit<Bn256>>(circuit: C) -> Result<CircuitReport, UnsatisfiedAt> {
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).expect("circuit must synthesize");
+
+    if let Some(path) = cs.which_is_unsatisfied() {
+        return Err(UnsatisfiedAt { path: path.to_string() });
+    }
+
+    Ok(CircuitReport {
+        num_constraints: cs.num_constraints(),
+        num_inputs: cs.num_inputs(),
+    })
+}
@@ -1,5 +1,3 @@
-use std::mem;
-
 use bellman_ce::{
     Circuit,
     ConstraintSystem,
@@ -17,25 +15,21 @@ use sapling_crypto_ce::{
         QuinticSBox,
     },
     circuit::{
-        baby_eddsa::EddsaSignature,
         poseidon_hash::poseidon_hash,
         num::AllocatedNum,
-        boolean::{ Boolean, AllocatedBit },
         ecc::EdwardsPoint,
     },  
     eddsa::Signature,
 };
 
-use crate::utils::{
-    sign::verify_signature,
-    calc::boolean_to_allocated_num,
-};
-
-use super::account::{ AccountState, AccountCircuit };
-use super::utils::calc::check_decomposition_le;
+use crate::types::{ BALANCE_BITS, NONCE_BITS };
+use crate::utils::sign::verify_signature;
 
-const BITS_IN_BYTE: usize = 8;
-const NUM_BYTES_TO_SIGN: usize = 31;
+use super::account::{ AccountState, AccountCircuit, LEAF_PUBKEY, LEAF_NONCE };
+use super::utils::point::unpack_point_gadget;
+use super::public_inputs::alloc_public_inputs;
+use super::utils::op_type::{ alloc_op_type, TRANSFER_OP };
+use super::utils::calc::{ check_decomposition_le, sub, enforce_bit_length };
 
 #[derive(Clone)]
 pub struct TransferCircuit<E: JubjubEngine + PoseidonEngine> {
@@ -43,6 +37,7 @@ pub struct TransferCircuit<E: JubjubEngine + PoseidonEngine> {
     pub account_state_to: AccountState<E>,
     pub account_id_from: Option::<E::Fr>,
     pub account_id_to: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
     pub amount: Option::<E::Fr>,
     pub nonce: Option::<E::Fr>,
     pub sign: Option::<Signature<E>>,
@@ -52,41 +47,51 @@ pub struct TransferCircuit<E: JubjubEngine + PoseidonEngine> {
 impl<E> TransferCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
-    pub fn process<'a, CS: ConstraintSystem<E>> (
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_transfer<'a, CS: ConstraintSystem<E>> (
         &self,
         mut cs: CS,
         account_depth: usize,
+        token_depth: usize,
         hash_params: &'a <E as PoseidonEngine>::Params,
         sign_params: &'a <E as JubjubEngine>::Params,
+        old_hash: &AllocatedNum<E>,
         old_root: &AllocatedNum<E>,
-    ) -> Result<AllocatedNum<E>, SynthesisError> {
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
         
         // allocate avariables ----------------------------------------------------------
         
         let account_circuit_from = AccountCircuit::new(
-            cs.namespace(|| "allocate account circuit"),
+            cs.namespace(|| "allocate account circuit from"),
             account_depth,
+            token_depth,
             hash_params,
             &self.account_state_from,
         )?;
 
         let account_circuit_to = AccountCircuit::new(
-            cs.namespace(|| "allocate account circuit"),
+            cs.namespace(|| "allocate account circuit to"),
             account_depth,
+            token_depth,
             hash_params,
             &self.account_state_to,
         )?;
 
         let account_id_alloc_from = AllocatedNum::alloc(
-            cs.namespace(|| "allocate account id"),
+            cs.namespace(|| "allocate account id from"),
             || self.account_id_from.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
         let account_id_alloc_to = AllocatedNum::alloc(
-            cs.namespace(|| "allocate account id"),
+            cs.namespace(|| "allocate account id to"),
             || self.account_id_to.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
         let amount_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate amount"),
             || self.amount.ok_or(SynthesisError::AssignmentMissing),
@@ -97,14 +102,21 @@ impl<E> TransferCircuit<E>
             || self.nonce.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
+        let op_type_alloc = alloc_op_type(
+            cs.namespace(|| "allocate op type"),
+            TRANSFER_OP,
+        )?;
+
         // check signature --------------------------------------------------------------
 
         let transfer_hash = {
             let hash_vec = poseidon_hash(
                 cs.namespace(|| "calculate message hash"),
                 &[
+                    op_type_alloc.clone(),
                     account_id_alloc_from.clone(),
                     account_id_alloc_to.clone(),
+                    token_id_alloc.clone(),
                     amount_alloc.clone(),
                     nonce_alloc.clone(),
                 ],
@@ -118,7 +130,6 @@ impl<E> TransferCircuit<E>
             self.sign.clone(),
             self.pubkey.clone(),
             &transfer_hash,
-            NUM_BYTES_TO_SIGN,
             sign_params,
         )?;
 
@@ -130,9 +141,9 @@ impl<E> TransferCircuit<E>
             cs.namespace(|| "public key consistence"),
             &sign_alloc.pk,
             &account_circuit_from,
-        );
+        )?;
         
-        // check account id consistency
+        // check account id, token id consistency
 
         check_decomposition_le(
             cs.namespace(|| "account id from consistence"),
@@ -146,53 +157,116 @@ impl<E> TransferCircuit<E>
             &account_circuit_to.accounts_tree.indices_alloc,
         )?;
 
+        check_decomposition_le(
+            cs.namespace(|| "token id from consistence"),
+            &token_id_alloc,
+            &account_circuit_from.balances_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id to consistence"),
+            &token_id_alloc,
+            &account_circuit_to.balances_tree.indices_alloc,
+        )?;
+
+        // check self transfer: sender and receiver leaves are updated one after
+        // another, so the same account on both sides is rejected
+
+        let account_id_diff = sub(
+            cs.namespace(|| "account ids difference"),
+            &account_id_alloc_from,
+            &account_id_alloc_to,
+        )?;
+
+        account_id_diff.assert_nonzero(
+            cs.namespace(|| "check account ids are different"),
+        )?;
+
         // check amount
 
         cs.enforce(
             || "check amount transfer from",
-            |lc| lc + account_circuit_from.accounts_tree.old_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit_from.balances_tree.old_leaf_alloc[0].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit_from.accounts_tree.new_leaf_alloc[3].get_variable()
+            |lc| lc + account_circuit_from.balances_tree.new_leaf_alloc[0].get_variable()
                 + amount_alloc.get_variable(),
         );
 
         cs.enforce(
             || "check amount transfer to",
-            |lc| lc + account_circuit_to.accounts_tree.old_leaf_alloc[3].get_variable()
+            |lc| lc + account_circuit_to.balances_tree.old_leaf_alloc[0].get_variable()
                 + amount_alloc.get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit_to.accounts_tree.new_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit_to.balances_tree.new_leaf_alloc[0].get_variable(),
         );
 
-        // check balance for overflow
+        // check amount and balances for overflow, so amount can't exceed sender balance
+
+        amount_alloc.limit_number_of_bits(
+            cs.namespace(|| "check amount overflow"),
+            BALANCE_BITS,
+        )?;
 
-        account_circuit_from.accounts_tree.new_leaf_alloc[3].limit_number_of_bits(
+        account_circuit_from.balances_tree.new_leaf_alloc[0].limit_number_of_bits(
             cs.namespace(|| "check from balance overflow"),
-            mem::size_of::<usize>() * BITS_IN_BYTE,
+            BALANCE_BITS,
         )?;
 
-        account_circuit_to.accounts_tree.new_leaf_alloc[3].limit_number_of_bits(
+        account_circuit_to.balances_tree.new_leaf_alloc[0].limit_number_of_bits(
             cs.namespace(|| "check to balance overflow"),
-            mem::size_of::<usize>() * BITS_IN_BYTE,
+            BALANCE_BITS,
         )?;
 
         // check nonce
 
         cs.enforce(
             || "nonce consistence",
-            |lc| lc + account_circuit_from.accounts_tree.old_leaf_alloc[2].get_variable() + CS::one(),
+            |lc| lc + account_circuit_from.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one(),
             |lc| lc + CS::one(),
             |lc| lc + nonce_alloc.get_variable(),
         );
 
+        enforce_bit_length(
+            cs.namespace(|| "check nonce overflow"),
+            &nonce_alloc,
+            NONCE_BITS,
+        )?;
+
         cs.enforce(
             || "check nonce + 1",
-            |lc| lc + account_circuit_from.accounts_tree.old_leaf_alloc[2].get_variable() + CS::one(),
+            |lc| lc + account_circuit_from.accounts_tree.old_leaf_alloc[LEAF_NONCE].get_variable() + CS::one(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit_from.accounts_tree.new_leaf_alloc[2].get_variable(),
+            |lc| lc + account_circuit_from.accounts_tree.new_leaf_alloc[LEAF_NONCE].get_variable(),
         );
 
-        // TODO check that to account hash the same
+        // check receiver pubkey and nonce the same
+
+        for (field, i) in [("pubkey", LEAF_PUBKEY), ("nonce", LEAF_NONCE)].iter() {
+            cs.enforce(
+                || format!("check receiver {} the same", field),
+                |lc| lc + account_circuit_to.accounts_tree.old_leaf_alloc[*i].get_variable(),
+                |lc| lc + CS::one(),
+                |lc| lc + account_circuit_to.accounts_tree.new_leaf_alloc[*i].get_variable(),
+            );
+        }
+
+        // calculate new hash -----------------------------------------------------------
+
+        let new_hash = {
+            let hashes_vec = poseidon_hash(
+                cs.namespace(|| "calculate new accum hash"),
+                &[
+                    op_type_alloc.clone(),
+                    old_hash.clone(),
+                    account_id_alloc_from,
+                    account_id_alloc_to,
+                    token_id_alloc,
+                    amount_alloc,
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
 
         // verify old root & calculate new root -----------------------------------------
 
@@ -214,41 +288,30 @@ impl<E> TransferCircuit<E>
             cs.namespace(|| "calculate to new root"),
         )?;
 
-        Ok(new_root)
+        Ok((new_hash, new_root))
     }
 
+    // the pubkey packed in the old leaf, the transfer keeps it
     pub fn check_pubkey<CS: ConstraintSystem<E>> (
         mut cs: CS,
         pubkey: &EdwardsPoint<E>,
         account_circuit: &AccountCircuit<E>,
-    ) {
-        cs.enforce(
-            || "enforce pubkey x and old leaf equivalence",
-            |lc| lc + pubkey.get_x().get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[0].get_variable(),
-        );
-
-        cs.enforce(
-            || "enforce pubkey x and new leaf equivalence",
-            |lc| lc + pubkey.get_x().get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[0].get_variable(),
-        );
+    ) -> Result<(), SynthesisError> {
+        unpack_point_gadget(
+            cs.namespace(|| "enforce pubkey and old leaf equivalence"),
+            &account_circuit.accounts_tree.old_leaf_alloc[LEAF_PUBKEY],
+            pubkey.get_x(),
+            pubkey.get_y(),
+        )?;
 
         cs.enforce(
-            || "enforce pubkey y and old leaf equivalence",
-            |lc| lc + pubkey.get_y().get_variable(),
+            || "enforce old and new leaf pubkey equivalence",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[LEAF_PUBKEY].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[1].get_variable(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[LEAF_PUBKEY].get_variable(),
         );
 
-        cs.enforce(
-            || "enforce pubkey y and new leaf equivalence",
-            |lc| lc + pubkey.get_y().get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[1].get_variable(),
-        );
+        Ok(())
     }
 }
 
@@ -256,9 +319,12 @@ impl<E> TransferCircuit<E>
 pub struct TransferBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
     pub batch_size: usize,
     pub account_depth: usize,
+    pub token_depth: usize,
     pub hash_params: &'a <E as PoseidonEngine>::Params,
     pub sign_params: &'a <E as JubjubEngine>::Params,
     pub queue: Vec::<TransferCircuit<E>>,
+    pub old_accum_hash: Option::<E::Fr>,
+    pub new_accum_hash: Option::<E::Fr>,
     pub old_account_root: Option::<E::Fr>,
     pub new_account_root: Option::<E::Fr>,
 }
@@ -270,32 +336,45 @@ impl<'a, E> Circuit<E> for TransferBatchCircuit<'a, E>
         self,
         cs: &mut CS,
     ) -> Result<(), SynthesisError> {
-        assert_eq!(self.batch_size, self.queue.len());
+        if self.batch_size != self.queue.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
 
-        let mut prev_root = AllocatedNum::alloc(
-            cs.namespace(|| "allocate old root"),
-            || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
+        let public_inputs = alloc_public_inputs(
+            cs.namespace(|| "allocate public inputs"),
+            self.old_accum_hash,
+            self.new_accum_hash,
+            self.old_account_root,
+            self.new_account_root,
         )?;
-        prev_root.inputize(cs.namespace(|| "input old root"))?;
 
-        let new_root = AllocatedNum::alloc(
-            cs.namespace(|| "allocate new root"),
-            || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-        new_root.inputize(cs.namespace(|| "input new root"))?;
+        let mut prev_hash = public_inputs.old_accum_hash;
+        let new_hash = public_inputs.new_accum_hash;
+        let mut prev_root = public_inputs.old_account_root;
+        let new_root = public_inputs.new_account_root;
 
-        for (i, withdrawal) in self.queue.iter().enumerate() {
-            let root = withdrawal.process(
-                cs.namespace(|| format!("verify withdrawal {}", i)),
+        for (i, transfer) in self.queue.iter().enumerate() {
+            let (hash, root) = transfer.process_transfer(
+                cs.namespace(|| format!("verify transfer {}", i)),
                 self.account_depth,
+                self.token_depth,
                 self.hash_params,
                 self.sign_params,
+                &prev_hash,
                 &prev_root,
             )?;
 
+            prev_hash = hash;
             prev_root = root;
         }
 
+        cs.enforce(
+            || "enforce new accum hash equivalence",
+            |lc| lc + prev_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_hash.get_variable(),
+        );
+
         cs.enforce(
             || "enforce new root equivalence",
             |lc| lc + prev_root.get_variable(),
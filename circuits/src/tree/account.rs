@@ -1,14 +1,21 @@
+use std::{
+    fmt,
+    fs::{ self, File },
+    io::{ self, Read, Write, BufReader, BufWriter },
+    path::Path,
+    error::Error,
+    collections::{ HashMap, BTreeMap, BTreeSet },
+    ops::{ Index, IndexMut },
+    convert::TryFrom,
+};
+
 use sapling_crypto_ce::{
     poseidon::bn256::Bn256PoseidonParams,
     eddsa::PublicKey,
     alt_babyjubjub::AltJubjubBn256,
-    jubjub::{
-        edwards::Point,
-        Unknown,
-    },
 };
 
-use ff_ce::Field;
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
 
 use pairing_ce::{
     bn256,
@@ -16,63 +23,1066 @@ use pairing_ce::{
 };
 
 use super::{
-    merkle_tree::PoseidonMerkleTree,
+    merkle_tree::{ PoseidonMerkleTree, PathCache },
+    proof::{ MerkleProof, BalanceProof },
+    snapshot::{ AccountSnapshot, StateSnapshot },
+    history::RootHistory,
+    empty::empty_pubkey,
+    leaf::{ LeafAccount, LeafVersion, LeafCodec, LeafError },
 };
 
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::account::AccountState;
+use crate::exit_circuit::ExitCircuit;
+use crate::hasher::{ TreeHasher, Poseidon };
+use crate::l1::ProcessedOps;
+use crate::instrument::timed_span;
+
+use crate::utils::point::{ pack_point, unpack_point };
+use crate::utils::utils::{ optionalize, usize_to_fr, u128_to_fr, fr_to_hex };
+use crate::utils::checksum::{ ChecksumReader, ChecksumWriter };
+use crate::types::{ Balance, Nonce, AccountId, RangeError };
+use crate::params::jubjub_params;
+use crate::error::OpenPlasmaError;
+
+pub const TREE_FILE_MAGIC: &[u8; 4] = b"OPAT";
+// 3 since only the stored accounts, balances and nodes are written
+const TREE_FILE_VERSION: u8 = 3;
+const TREE_FILE_WITH_NODES: u8 = 1;
+// the high nibble of the flags byte is the leaf version, 0 in older files
+const TREE_FILE_LEAF_VERSION_SHIFT: u8 = 4;
+// deeper trees don't fit in memory anyway, guards allocations against a broken header
+pub(crate) const MAX_TREE_DEPTH: usize = 32;
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn write_fr<W: Write>(writer: &mut W, fr: &bn256::Fr) -> io::Result<()> {
+    fr.into_repr().write_be(writer)
+}
+
+pub(crate) fn read_fr<R: Read>(reader: &mut R) -> io::Result<bn256::Fr> {
+    let mut repr = <bn256::Fr as PrimeField>::Repr::default();
+    repr.read_be(reader)?;
+    bn256::Fr::from_repr(repr).map_err(|_| invalid_data("field element is not canonical"))
+}
+
+fn write_u64<W: Write>(writer: &mut W, n: usize) -> io::Result<()> {
+    writer.write_all(&(n as u64).to_be_bytes())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<usize> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    usize::try_from(u64::from_be_bytes(bytes)).map_err(|_| invalid_data("number doesn't fit in usize"))
+}
+
+// the stored nodes of a tree: their number, then level, offset and node
+fn write_nodes<W: Write>(writer: &mut W, nodes: &[((usize, usize), bn256::Fr)]) -> io::Result<()> {
+    write_u64(writer, nodes.len())?;
+    for ((level, offset), node) in nodes.iter() {
+        writer.write_all(&[*level as u8])?;
+        write_u64(writer, *offset)?;
+        write_fr(writer, node)?;
+    }
+    Ok(())
+}
+
+fn read_nodes<R: Read>(reader: &mut R) -> io::Result<Vec::<((usize, usize), bn256::Fr)>> {
+    let len = read_u64(reader)?;
+    let mut nodes = Vec::new();
+    for _ in 0..len {
+        let mut level = [0u8; 1];
+        reader.read_exact(&mut level)?;
+        let offset = read_u64(reader)?;
+        nodes.push(((level[0] as usize, offset), read_fr(reader)?));
+    }
+    Ok(nodes)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeError {
+    AccountOutOfRange(usize),
+    TokenOutOfRange(usize),
+    InsufficientBalance { account_id: usize, token_id: usize },
+    NonceMismatch { account_id: usize, expected: Nonce, got: Nonce },
+    // the account signed nonce u32::MAX, nonces don't wrap around to 0
+    NonceOverflow(usize),
+    EmptyAccount(usize),
+    BalanceOverflow { account_id: usize, token_id: usize },
+    UnknownCheckpoint,
+    InvalidSnapshot(&'static str),
+    AccountNotEmpty(usize),
+    OpenCheckpoint,
+    BlockOutOfOrder(usize),
+    UnknownBlock(usize),
+    OutOfRange(RangeError),
+    PubkeyMismatch(usize),
+    // a transfer or swap of the account with itself
+    SelfTransfer(usize),
+    // the diff was made against another state than the snapshot holds
+    BaseRootMismatch { expected: bn256::Fr, actual: bn256::Fr },
+    // the priority op with this serial id is already applied
+    AlreadyProcessed(u64),
+    // a leaf that doesn't decode in the tree's leaf version
+    InvalidLeaf(LeafError),
+    // a genesis listing the account twice, or with a pubkey that is off the
+    // curve, out of the prime order subgroup or the identity
+    DuplicateAccount(usize),
+    InvalidPubkey(usize),
+    DepthTooLarge(usize),
+}
+
+impl Error for TreeError {}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            TreeError::AccountOutOfRange(id) => write!(f, "Account {} is out of the tree", id),
+            TreeError::TokenOutOfRange(id) => write!(f, "Token {} is out of the balances tree", id),
+            TreeError::InsufficientBalance { account_id, token_id } => write!(
+                f, "Account {} has not enough of token {}", account_id, token_id),
+            TreeError::NonceMismatch { account_id, expected, got } => write!(
+                f, "Account {} expects nonce {}, got {}", account_id, expected, got),
+            TreeError::NonceOverflow(id) => write!(f, "Account {} has used up its nonces", id),
+            TreeError::EmptyAccount(id) => write!(f, "Account {} has no public key", id),
+            TreeError::BalanceOverflow { account_id, token_id } => write!(
+                f, "Account {} balance of token {} overflows", account_id, token_id),
+            TreeError::UnknownCheckpoint => write!(f, "Checkpoint was already rolled back or committed"),
+            TreeError::InvalidSnapshot(reason) => write!(f, "Invalid state snapshot: {}", reason),
+            TreeError::AccountNotEmpty(id) => write!(f, "Account {} still holds a balance", id),
+            TreeError::OpenCheckpoint => write!(f, "Block can't be finalized with an open checkpoint"),
+            TreeError::BlockOutOfOrder(block) => write!(f, "Block {} is not after the last finalized one", block),
+            TreeError::UnknownBlock(block) => write!(f, "Block {} was never finalized", block),
+            TreeError::AlreadyProcessed(serial_id) => write!(f, "Priority op {} is already applied", serial_id),
+            TreeError::OutOfRange(err) => write!(f, "{}", err),
+            TreeError::PubkeyMismatch(id) => write!(f, "Account {} belongs to another public key", id),
+            TreeError::SelfTransfer(id) => write!(f, "Account {} can't transfer to itself", id),
+            TreeError::BaseRootMismatch { expected, actual } => write!(
+                f, "Diff applies to root {}, the snapshot is at {}", fr_to_hex(expected), fr_to_hex(actual)),
+            TreeError::InvalidLeaf(err) => write!(f, "{}", err),
+            TreeError::DuplicateAccount(id) => write!(f, "Account {} is listed twice", id),
+            TreeError::InvalidPubkey(id) => write!(f, "Account {} has no valid public key", id),
+            TreeError::DepthTooLarge(depth) => write!(f, "Tree depth {} is too large", depth),
+        }
+    }
+}
+
+impl From<RangeError> for TreeError {
+    fn from(err: RangeError) -> Self {
+        TreeError::OutOfRange(err)
+    }
+}
+
+// one account leaf change of a batch, relative to the state left by the
+// previous updates: the balance moves by credit - debit, pubkey None keeps it
+#[derive(Clone)]
+pub struct LeafUpdate {
+    pub account_id: usize,
+    pub token_id: usize,
+    pub pubkey: Option::<PublicKey::<Bn256>>,
+    pub credit: u128,
+    pub debit: u128,
+    pub increment_nonce: bool,
+}
+
+// the balances of an account by token id. only the balances that were
+// written are stored, any other token reads as zero
+#[derive(Clone, Debug, PartialEq)]
+pub struct Balances {
+    balances: BTreeMap::<usize, bn256::Fr>,
+    zero: bn256::Fr,
+    len: usize,
+}
+
+impl Balances {
+    pub fn new(token_depth: usize) -> Self {
+        Balances {
+            balances: BTreeMap::new(),
+            zero: bn256::Fr::zero(),
+            len: 1 << token_depth,
+        }
+    }
+
+    // one balance per token id, as snapshots hold them
+    pub fn from_dense(balances: &[bn256::Fr]) -> Self {
+        Balances {
+            balances: balances.iter().cloned().enumerate().filter(|(_, balance)| !balance.is_zero()).collect(),
+            zero: bn256::Fr::zero(),
+            len: balances.len(),
+        }
+    }
+
+    // the number of token ids, stored or not
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // the stored balances in ascending token id order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &bn256::Fr)> + '_ {
+        self.balances.iter().map(|(token_id, balance)| (*token_id, balance))
+    }
+
+    pub fn num_stored(&self) -> usize {
+        self.balances.len()
+    }
+
+    // a zero balance is dropped, the token reads as zero again
+    pub fn set(&mut self, token_id: usize, balance: bn256::Fr) {
+        assert!(token_id < self.len);
+        if balance.is_zero() {
+            self.balances.remove(&token_id);
+        } else {
+            self.balances.insert(token_id, balance);
+        }
+    }
+
+    // one balance per token id, the inverse of from_dense
+    pub fn to_vec(&self) -> Vec::<bn256::Fr> {
+        (0..self.len).map(|token_id| self[token_id]).collect()
+    }
+}
+
+impl Index<usize> for Balances {
+    type Output = bn256::Fr;
+
+    fn index(&self, token_id: usize) -> &Self::Output {
+        assert!(token_id < self.len);
+        self.balances.get(&token_id).unwrap_or(&self.zero)
+    }
+}
+
+// stores a zero balance on the first write
+impl IndexMut<usize> for Balances {
+    fn index_mut(&mut self, token_id: usize) -> &mut Self::Output {
+        assert!(token_id < self.len);
+        self.balances.entry(token_id).or_insert_with(bn256::Fr::zero)
+    }
+}
+
 #[derive(Clone)]
-pub struct Account {
+pub struct Account<'a, H: TreeHasher<Bn256> = Poseidon> {
     pub pubkey: PublicKey::<Bn256>,
     pub nonce: bn256::Fr,
-    pub balance: bn256::Fr,
+    pub balances: Balances,
+    pub balances_tree: PoseidonMerkleTree::<'a, Bn256, H>,
 }
 
-impl Account {
+impl<'a, H> Account<'a, H>
+    where H: TreeHasher<Bn256>,
+          H::Params: Sync,
+{
     pub fn new(
+        token_depth: usize,
+        hash_params: &'a H::Params,
         sign_params: &AltJubjubBn256,
     ) -> Self {        
-        let pubkey = PublicKey::<Bn256>(
-            Point::<Bn256, Unknown>::get_for_y(bn256::Fr::zero(), true, sign_params).unwrap()
-        );
+        let pubkey = PublicKey::<Bn256>(empty_pubkey(sign_params));
+
+        let balances = Balances::new(token_depth);
+        let balances_tree = PoseidonMerkleTree::<'a, Bn256, H>::new_empty(token_depth, &[bn256::Fr::zero()], hash_params);
 
         Account {
             pubkey,
             nonce: bn256::Fr::zero(),
-            balance: bn256::Fr::zero(),
+            balances,
+            balances_tree,
         }
     }
 
+    pub fn leaf(&self) -> LeafAccount {
+        LeafAccount {
+            pubkey: pack_point(&self.pubkey.0),
+            nonce: self.nonce,
+            balances_root: self.balances_tree.root(),
+        }
+    }
+
+    // the untagged LeafVersion::V0 leaf
     pub fn compress_to_leaf(&self) -> Vec::<bn256::Fr> {
-        let (pubkey_x, pubkey_y) = self.pubkey.0.into_xy();
-        vec![pubkey_x, pubkey_y, self.nonce, self.balance]
+        self.encode_leaf(LeafVersion::V0)
+    }
+
+    pub fn encode_leaf(&self, version: LeafVersion) -> Vec::<bn256::Fr> {
+        version.encode(&self.leaf())
     }
 }
 
+// the accounts by id. only the accounts that were written are stored, any
+// other id reads as the empty account, so a deep tree costs what it holds
 #[derive(Clone)]
-pub struct AccountsTree<'a> {
-    pub accounts: Vec::<Account>,
-    pub accounts_tree: PoseidonMerkleTree::<'a, Bn256>,
+pub struct Accounts<'a, H: TreeHasher<Bn256> = Poseidon> {
+    accounts: BTreeMap::<usize, Account<'a, H>>,
+    empty: Account<'a, H>,
+    len: usize,
+}
+
+impl<'a, H: TreeHasher<Bn256>> Accounts<'a, H> {
+    pub fn new(account_depth: usize, empty: Account<'a, H>) -> Self {
+        Accounts {
+            accounts: BTreeMap::new(),
+            empty,
+            len: 1 << account_depth,
+        }
+    }
+
+    // the number of account ids, stored or not
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // the stored accounts in ascending id order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Account<'a, H>)> + '_ {
+        self.accounts.iter().map(|(account_id, account)| (*account_id, account))
+    }
+
+    pub fn num_stored(&self) -> usize {
+        self.accounts.len()
+    }
+
+    // the id reads as the empty account again
+    pub fn reset(&mut self, account_id: usize) {
+        self.accounts.remove(&account_id);
+    }
+}
+
+impl<'a, H: TreeHasher<Bn256>> Index<usize> for Accounts<'a, H> {
+    type Output = Account<'a, H>;
+
+    fn index(&self, account_id: usize) -> &Self::Output {
+        assert!(account_id < self.len);
+        self.accounts.get(&account_id).unwrap_or(&self.empty)
+    }
+}
+
+// stores a copy of the empty account on the first write
+impl<'a, H: TreeHasher<Bn256>> IndexMut<usize> for Accounts<'a, H> {
+    fn index_mut(&mut self, account_id: usize) -> &mut Self::Output {
+        assert!(account_id < self.len);
+        let empty = &self.empty;
+        self.accounts.entry(account_id).or_insert_with(|| empty.clone())
+    }
+}
+
+// valid until it is rolled back or committed, together with every checkpoint
+// taken after it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckpointId(usize);
+
+// an account as it was before an update, journaled while a checkpoint is open
+#[derive(Clone)]
+struct JournalEntry<'a, H: TreeHasher<Bn256>> {
+    account_id: usize,
+    account: Account<'a, H>,
+}
+
+// the accounts and balances trees share the hasher, poseidon unless another
+// one is given to new_with_hasher. tree files and exit witnesses are poseidon
+#[derive(Clone)]
+pub struct AccountsTree<'a, H: TreeHasher<Bn256> = Poseidon> {
+    pub accounts: Accounts<'a, H>,
+    pub accounts_tree: PoseidonMerkleTree::<'a, Bn256, H>,
+    journal: Vec::<JournalEntry<'a, H>>,
+    // serial ids marked processed since the outermost open checkpoint
+    processed_journal: Vec::<u64>,
+    // journal lengths at every open checkpoint, outermost first
+    checkpoints: Vec::<(usize, usize)>,
+    // packed pubkey to the accounts holding it, accounts with the empty
+    // account pubkey are not registered
+    pubkey_index: HashMap::<[u8; PACKED_PUBKEY_SIZE], BTreeSet::<usize>>,
+    registered: BTreeSet::<usize>,
+    empty_pubkey: [u8; PACKED_PUBKEY_SIZE],
+    history: RootHistory,
+    // accounts changed since the last finalized block, as they were before
+    unfinalized_accounts: BTreeMap::<usize, AccountSnapshot>,
+    processed_ops: ProcessedOps,
+    leaf_version: LeafVersion,
+}
+
+const PACKED_PUBKEY_SIZE: usize = 32;
+
+// the pubkey affine conversion is an inversion per account, worth the threads
+// of the parallel feature on trees built from scratch. only the stored
+// accounts are hashed, the other leaves are the empty one
+fn accounts_tree<'a, H>(
+    accounts: &Accounts<'a, H>,
+    account_depth: usize,
+    version: LeafVersion,
+    hash_params: &'a H::Params,
+) -> PoseidonMerkleTree::<'a, Bn256, H>
+    where H: TreeHasher<Bn256>,
+          H::Params: Sync,
+{
+    let stored: Vec<_> = accounts.iter().collect();
+
+    #[cfg(feature = "parallel")]
+    let stored_iter = stored.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let stored_iter = stored.iter();
+
+    let leaves = stored_iter.map(
+        |(account_id, account)| (*account_id, account.encode_leaf(version))
+    ).collect();
+
+    let mut tree = PoseidonMerkleTree::new_empty(account_depth, &accounts.empty.encode_leaf(version), hash_params);
+    tree.set_leaves(leaves);
+    tree.refresh();
+    tree
+}
+
+// only the stored balances are hashed, the other leaves are zero
+fn balances_tree<'a, H>(
+    balances: &Balances,
+    token_depth: usize,
+    hash_params: &'a H::Params,
+) -> PoseidonMerkleTree::<'a, Bn256, H>
+    where H: TreeHasher<Bn256>,
+          H::Params: Sync,
+{
+    let leaves = balances.iter().map(|(token_id, balance)| (token_id, vec![*balance])).collect();
+
+    let mut tree = PoseidonMerkleTree::new_empty(token_depth, &[bn256::Fr::zero()], hash_params);
+    tree.set_leaves(leaves);
+    tree.refresh();
+    tree
+}
+
+// compressed point: y and the sign of x
+fn pack_pubkey(pubkey: &PublicKey::<Bn256>) -> [u8; PACKED_PUBKEY_SIZE] {
+    let mut packed = [0u8; PACKED_PUBKEY_SIZE];
+    pubkey.write(&mut packed[..]).unwrap();
+    packed
 }
 
-#[allow(dead_code)]
 impl<'a> AccountsTree<'a> {
     pub fn new(
         account_depth: usize,
+        token_depth: usize,
         hash_params: &'a Bn256PoseidonParams,
         sign_params: &AltJubjubBn256,
     ) -> Self {
-        let num_accounts = 1 << account_depth;
-        let mut accounts = Vec::with_capacity(num_accounts);
-        accounts.resize_with(
-            num_accounts,
-            || Account::new(sign_params),
+        Self::new_with_hasher(account_depth, token_depth, hash_params, sign_params)
+    }
+
+    pub fn from_snapshot(
+        snapshot: &StateSnapshot,
+        hash_params: &'a Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<Self, OpenPlasmaError> {
+        Self::from_snapshot_with_hasher(snapshot, hash_params, sign_params)
+    }
+
+    // exit witness against the root of a finalized block, not only the latest,
+    // sign_params left as None are the shared ones
+    pub fn exit_witness_at(
+        &self,
+        block_number: usize,
+        account_id: usize,
+        token_id: usize,
+        sign_params: Option<&AltJubjubBn256>,
+    ) -> Result<ExitCircuit<'a, Bn256>, OpenPlasmaError> {
+        let sign_params = sign_params.unwrap_or_else(|| jubjub_params());
+        self.state_at(block_number, sign_params)?.exit_witness(account_id, token_id)
+    }
+
+    // everything a wallet needs to prove the balance from a tree snapshot
+    pub fn exit_witness(&self, account_id: usize, token_id: usize) -> Result<ExitCircuit<'a, Bn256>, OpenPlasmaError> {
+        self.check_token(account_id, token_id)?;
+
+        let account = &self.accounts[account_id];
+        let (pubkey_x, pubkey_y) = account.pubkey.0.into_xy();
+
+        Ok(ExitCircuit {
+            account_depth: self.accounts_tree.depth(),
+            token_depth: account.balances_tree.depth(),
+            hash_params: self.accounts_tree.params(),
+            sign_params: jubjub_params(),
+            root: Some(self.get_root()),
+            account_id: Some(usize_to_fr(account_id)),
+            token_id: Some(usize_to_fr(token_id)),
+            pubkey_x: Some(pubkey_x),
+            pubkey_y: Some(pubkey_y),
+            balance: Some(account.balances[token_id]),
+            nonce: Some(account.nonce),
+            account_path: optionalize(self.accounts_tree.get_leaf_path(account_id)),
+            account_indices: optionalize(self.accounts_tree.get_leaf_indices(account_id)),
+            token_path: optionalize(account.balances_tree.get_leaf_path(token_id)),
+            token_indices: optionalize(account.balances_tree.get_leaf_indices(token_id)),
+        })
+    }
+
+    // cached nodes are trusted once the checksum matches, otherwise the trees
+    // are rehashed and the root has to match the saved one
+    pub fn read<R: Read>(
+        reader: R,
+        hash_params: &'a Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<Self, OpenPlasmaError> {
+        let mut reader = ChecksumReader::new(reader);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != TREE_FILE_MAGIC {
+            return Err(invalid_data("not an accounts tree file").into());
+        }
+
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let [version, account_depth, token_depth, flags] = header;
+        if version != TREE_FILE_VERSION {
+            return Err(invalid_data("unsupported accounts tree file version").into());
+        }
+
+        let account_depth = account_depth as usize;
+        let token_depth = token_depth as usize;
+        if account_depth > MAX_TREE_DEPTH || token_depth > MAX_TREE_DEPTH {
+            return Err(invalid_data("tree depth is too large").into());
+        }
+        let leaf_version = LeafVersion::from_number(flags >> TREE_FILE_LEAF_VERSION_SHIFT).ok_or(
+            invalid_data("unknown account leaf version")
+        )?;
+        let flags = flags & ((1 << TREE_FILE_LEAF_VERSION_SHIFT) - 1);
+        if flags > TREE_FILE_WITH_NODES {
+            return Err(invalid_data("unknown accounts tree file flags").into());
+        }
+        let with_nodes = flags == TREE_FILE_WITH_NODES;
+
+        let empty_account = Account::new(token_depth, hash_params, sign_params);
+        let mut accounts = Accounts::new(account_depth, empty_account.clone());
+        let num_accounts = read_u64(&mut reader)?;
+        let mut next_account_id = 0;
+        for _ in 0..num_accounts {
+            let account_id = read_u64(&mut reader)?;
+            if account_id < next_account_id || account_id >= accounts.len() {
+                return Err(invalid_data("account ids are not ascending or out of the tree").into());
+            }
+            next_account_id = account_id + 1;
+
+            let pubkey = PublicKey::read(&mut reader, sign_params)?;
+            let nonce = read_fr(&mut reader)?;
+
+            let mut balances = Balances::new(token_depth);
+            let num_balances = read_u64(&mut reader)?;
+            let mut next_token_id = 0;
+            for _ in 0..num_balances {
+                let token_id = read_u64(&mut reader)?;
+                if token_id < next_token_id || token_id >= balances.len() {
+                    return Err(invalid_data("token ids are not ascending or out of the balances tree").into());
+                }
+                next_token_id = token_id + 1;
+                balances.set(token_id, read_fr(&mut reader)?);
+            }
+
+            let balances_tree = if with_nodes {
+                PoseidonMerkleTree::from_nodes(token_depth, &[bn256::Fr::zero()], read_nodes(&mut reader)?, hash_params)
+                    .ok_or(invalid_data("balances tree node is out of the tree"))?
+            } else {
+                balances_tree(&balances, token_depth, hash_params)
+            };
+
+            let account = Account { pubkey, nonce, balances, balances_tree };
+            if account.compress_to_leaf() != empty_account.compress_to_leaf() {
+                accounts[account_id] = account;
+            }
+        }
+
+        let accounts_tree = if with_nodes {
+            let empty_leaf = empty_account.encode_leaf(leaf_version);
+            PoseidonMerkleTree::from_nodes(account_depth, &empty_leaf, read_nodes(&mut reader)?, hash_params)
+                .ok_or(invalid_data("accounts tree node is out of the tree"))?
+        } else {
+            accounts_tree(&accounts, account_depth, leaf_version, hash_params)
+        };
+
+        let root = read_fr(&mut reader)?;
+        let expected_checksum = reader.checksum();
+
+        let mut checksum = [0u8; 8];
+        reader.read_exact(&mut checksum)?;
+        if u64::from_be_bytes(checksum) != expected_checksum {
+            return Err(invalid_data("accounts tree file checksum mismatch").into());
+        }
+
+        let empty_pubkey = pack_pubkey(&empty_account.pubkey);
+        let mut tree = AccountsTree {
+            accounts,
+            accounts_tree,
+            journal: Vec::new(),
+            processed_journal: Vec::new(),
+            checkpoints: Vec::new(),
+            pubkey_index: HashMap::new(),
+            registered: BTreeSet::new(),
+            empty_pubkey,
+            history: RootHistory::new(),
+            unfinalized_accounts: BTreeMap::new(),
+            processed_ops: ProcessedOps::new(),
+            leaf_version,
+        };
+        if tree.get_root() != root {
+            return Err(invalid_data("accounts tree root mismatch").into());
+        }
+
+        let stored: Vec<_> = tree.accounts.iter().map(|(account_id, _)| account_id).collect();
+        for account_id in stored {
+            tree.index_account(account_id);
+        }
+
+        Ok(tree)
+    }
+
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        hash_params: &'a Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<Self, OpenPlasmaError> {
+        Self::read(BufReader::new(File::open(path)?), hash_params, sign_params)
+    }
+}
+
+#[allow(dead_code)]
+impl<'a, H> AccountsTree<'a, H>
+    where H: TreeHasher<Bn256>,
+          H::Params: Sync,
+{
+    pub fn new_with_hasher(
+        account_depth: usize,
+        token_depth: usize,
+        hash_params: &'a H::Params,
+        sign_params: &AltJubjubBn256,
+    ) -> Self {
+        let empty_account = Account::new(token_depth, hash_params, sign_params);
+        let empty_pubkey = pack_pubkey(&empty_account.pubkey);
+        let accounts = Accounts::new(account_depth, empty_account);
+
+        let accounts_tree = PoseidonMerkleTree::<'a, Bn256, H>::new_empty(
+            account_depth,
+            &accounts.empty.encode_leaf(LeafVersion::V0),
+            hash_params,
+        );
+
+        // every account is empty, nothing to index
+        AccountsTree {
+            accounts,
+            accounts_tree,
+            journal: Vec::new(),
+            processed_journal: Vec::new(),
+            checkpoints: Vec::new(),
+            pubkey_index: HashMap::new(),
+            registered: BTreeSet::new(),
+            empty_pubkey,
+            history: RootHistory::new(),
+            unfinalized_accounts: BTreeMap::new(),
+            processed_ops: ProcessedOps::new(),
+            leaf_version: LeafVersion::V0,
+        }
+    }
+
+    // every leaf rehashed in the given layout. meant for a tree being set
+    // up, the roots of finalized blocks stay the ones of the old leaves; a
+    // tree with history moves on with snapshot::migrate_leaves
+    pub fn with_leaf_version(mut self, version: LeafVersion) -> Self {
+        self.leaf_version = version;
+        self.accounts_tree = accounts_tree(
+            &self.accounts,
+            self.accounts_tree.depth(),
+            version,
+            self.accounts_tree.params(),
         );
+        self
+    }
+
+    pub fn leaf_version(&self) -> LeafVersion {
+        self.leaf_version
+    }
+
+    fn index_account(&mut self, account_id: usize) {
+        let packed = pack_pubkey(&self.accounts[account_id].pubkey);
+        if packed != self.empty_pubkey {
+            self.pubkey_index.entry(packed).or_default().insert(account_id);
+            self.registered.insert(account_id);
+        }
+    }
+
+    fn unindex_account(&mut self, account_id: usize) {
+        let packed = pack_pubkey(&self.accounts[account_id].pubkey);
+        if let Some(account_ids) = self.pubkey_index.get_mut(&packed) {
+            account_ids.remove(&account_id);
+            if account_ids.is_empty() {
+                self.pubkey_index.remove(&packed);
+            }
+        }
+        self.registered.remove(&account_id);
+    }
+
+    // an account differs from the empty one in any of the leaf fields,
+    // transfers can credit an account that never registered a key
+    fn is_empty_account(&self, account_id: usize) -> bool {
+        let account = &self.accounts[account_id];
+        !self.registered.contains(&account_id)
+            && account.nonce.is_zero()
+            && account.balances.iter().all(|(_, balance)| balance.is_zero())
+    }
+
+    // (account id, pubkey, nonce, balances by token id) of non-empty accounts
+    pub fn iter_accounts(
+        &self,
+    ) -> impl Iterator<Item = (usize, &PublicKey::<Bn256>, bn256::Fr, &Balances)> + '_ {
+        self.accounts.iter()
+            .filter(move |(account_id, _)| !self.is_empty_account(*account_id))
+            .map(move |(account_id, account)| {
+                (account_id, &account.pubkey, account.nonce, &account.balances)
+            })
+    }
+
+    fn account_snapshot(&self, account_id: usize) -> AccountSnapshot {
+        let account = &self.accounts[account_id];
+
+        AccountSnapshot {
+            account_id,
+            pubkey: pack_point(&account.pubkey.0),
+            nonce: account.nonce,
+            balances: account.balances.to_vec(),
+        }
+    }
+
+    pub fn export_snapshot(&self) -> StateSnapshot {
+        let accounts = self.iter_accounts().map(
+            |(account_id, _, _, _)| self.account_snapshot(account_id)
+        ).collect();
+
+        StateSnapshot {
+            root: self.get_root(),
+            account_depth: self.accounts_tree.depth(),
+            token_depth: self.accounts[0].balances_tree.depth(),
+            accounts,
+            history: self.history.clone(),
+            unfinalized_accounts: self.unfinalized_accounts.values().cloned().collect(),
+            processed_ops: self.processed_ops.clone(),
+            leaf_version: self.leaf_version,
+        }
+    }
+
+    // every leaf is hashed once, the result has to match the snapshot root
+    pub fn from_snapshot_with_hasher(
+        snapshot: &StateSnapshot,
+        hash_params: &'a H::Params,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<Self, OpenPlasmaError> {
+        if snapshot.account_depth > MAX_TREE_DEPTH || snapshot.token_depth > MAX_TREE_DEPTH {
+            return Err(TreeError::InvalidSnapshot("tree depth is too large").into());
+        }
+
+        let empty_account = Account::new(snapshot.token_depth, hash_params, sign_params);
+        let mut accounts = Accounts::new(snapshot.account_depth, empty_account.clone());
+        let mut next_account_id = 0;
+
+        for entry in snapshot.accounts.iter() {
+            if entry.account_id < next_account_id || entry.account_id >= accounts.len() {
+                return Err(TreeError::InvalidSnapshot("account ids are not ascending or out of the tree").into());
+            }
+            next_account_id = entry.account_id + 1;
+
+            if entry.balances.len() != empty_account.balances.len() {
+                return Err(TreeError::InvalidSnapshot("balances do not match the token depth").into());
+            }
+
+            let pubkey = unpack_point(entry.pubkey, sign_params).ok_or(
+                TreeError::InvalidSnapshot("pubkey packs no curve point")
+            )?;
+
+            let balances = Balances::from_dense(&entry.balances);
+            accounts[entry.account_id] = Account {
+                pubkey: PublicKey::<Bn256>(pubkey),
+                nonce: entry.nonce,
+                balances_tree: balances_tree(&balances, snapshot.token_depth, hash_params),
+                balances,
+            };
+        }
+
+        let accounts_tree = accounts_tree(&accounts, snapshot.account_depth, snapshot.leaf_version, hash_params);
+
+        let mut tree = AccountsTree {
+            accounts,
+            accounts_tree,
+            journal: Vec::new(),
+            processed_journal: Vec::new(),
+            checkpoints: Vec::new(),
+            pubkey_index: HashMap::new(),
+            registered: BTreeSet::new(),
+            empty_pubkey: pack_pubkey(&empty_account.pubkey),
+            history: snapshot.history.clone(),
+            unfinalized_accounts: snapshot.unfinalized_accounts.iter().map(
+                |account| (account.account_id, account.clone())
+            ).collect(),
+            processed_ops: snapshot.processed_ops.clone(),
+            leaf_version: snapshot.leaf_version,
+        };
+        if tree.get_root() != snapshot.root {
+            return Err(TreeError::InvalidSnapshot("root mismatch").into());
+        }
+
+        for entry in snapshot.accounts.iter() {
+            tree.index_account(entry.account_id);
+        }
+
+        Ok(tree)
+    }
+
+    // a throwaway copy holding only these accounts and the nodes of their
+    // paths, to replay operations on them without cloning the whole state.
+    // any other account reads as empty, the roots are the ones of self. no
+    // history, journal or unfinalized accounts; ids out of the tree are left
+    // to the operations to refuse
+    pub fn with_accounts(&self, account_ids: &BTreeSet::<usize>) -> Self {
+        let in_tree = || account_ids.iter().cloned().filter(|account_id| *account_id < self.accounts.len());
+
+        let mut accounts = Accounts {
+            accounts: BTreeMap::new(),
+            empty: self.accounts.empty.clone(),
+            len: self.accounts.len,
+        };
+        for account_id in in_tree() {
+            if let Some(account) = self.accounts.accounts.get(&account_id) {
+                accounts.accounts.insert(account_id, account.clone());
+            }
+        }
+
+        let mut tree = AccountsTree {
+            accounts,
+            accounts_tree: self.accounts_tree.with_paths(in_tree()),
+            journal: Vec::new(),
+            processed_journal: Vec::new(),
+            checkpoints: Vec::new(),
+            pubkey_index: HashMap::new(),
+            registered: BTreeSet::new(),
+            empty_pubkey: self.empty_pubkey,
+            history: RootHistory::new(),
+            unfinalized_accounts: BTreeMap::new(),
+            processed_ops: self.processed_ops.clone(),
+            leaf_version: self.leaf_version,
+        };
+        for account_id in in_tree() {
+            tree.index_account(account_id);
+        }
+
+        tree
+    }
+
+    // the lowest account id when the circuit let several accounts share a key
+    pub fn account_id_by_pubkey(&self, pubkey: &PublicKey::<Bn256>) -> Option<usize> {
+        self.pubkey_index.get(&pack_pubkey(pubkey)).and_then(
+            |account_ids| account_ids.iter().next().cloned()
+        )
+    }
+
+    // the first account without a registered pubkey
+    pub fn first_empty_leaf(&self) -> Option<usize> {
+        (0..self.accounts.len()).find(|account_id| !self.registered.contains(account_id))
+    }
+
+    fn journal_account(&mut self, account_id: usize) {
+        if !self.unfinalized_accounts.contains_key(&account_id) {
+            let previous = self.account_snapshot(account_id);
+            self.unfinalized_accounts.insert(account_id, previous);
+        }
+
+        if !self.checkpoints.is_empty() {
+            self.journal.push(JournalEntry {
+                account_id,
+                account: self.accounts[account_id].clone(),
+            });
+        }
+    }
+
+    pub fn history(&self) -> &RootHistory {
+        &self.history
+    }
+
+    pub fn processed_ops(&self) -> &ProcessedOps {
+        &self.processed_ops
+    }
+
+    // the priority op is applied to the tree, a rollback to an earlier
+    // checkpoint unmarks it
+    pub fn mark_processed(&mut self, serial_id: u64) -> Result<(), OpenPlasmaError> {
+        if !self.processed_ops.insert(serial_id) {
+            return Err(TreeError::AlreadyProcessed(serial_id).into());
+        }
+        if !self.checkpoints.is_empty() {
+            self.processed_journal.push(serial_id);
+        }
+
+        Ok(())
+    }
+
+    // records the current root for the block, a checkpoint still open could
+    // roll back what the block committed
+    pub fn finalize_block(&mut self, block_number: usize) -> Result<(), OpenPlasmaError> {
+        if !self.checkpoints.is_empty() {
+            return Err(TreeError::OpenCheckpoint.into());
+        }
+        if self.history.last_block().is_some_and(|last| block_number <= last) {
+            return Err(TreeError::BlockOutOfOrder(block_number).into());
+        }
+
+        let previous_accounts = std::mem::take(&mut self.unfinalized_accounts).into_values().collect();
+        self.history.record(block_number, self.get_root(), previous_accounts);
 
-        let leaves: Vec<_> = accounts.iter().map(
-            |account| account.compress_to_leaf()
+        Ok(())
+    }
+
+    // the tree as it was when the block was finalized: the current accounts
+    // with the changes of every later block undone
+    pub fn state_at(
+        &self,
+        block_number: usize,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<Self, OpenPlasmaError> {
+        let root = self.history.get_root_at(block_number).ok_or(
+            TreeError::UnknownBlock(block_number)
+        )?;
+
+        let mut accounts: BTreeMap<_, _> = self.iter_accounts().map(
+            |(account_id, _, _, _)| (account_id, self.account_snapshot(account_id))
         ).collect();
-        let accounts_tree = PoseidonMerkleTree::<'a, Bn256>::new(leaves, hash_params);
 
-        AccountsTree { accounts, accounts_tree }
+        let undone = self.unfinalized_accounts.values().chain(
+            self.history.entries_after(block_number).flat_map(|entry| entry.previous_accounts.iter())
+        );
+        for account in undone {
+            accounts.insert(account.account_id, account.clone());
+        }
+
+        let history = self.history.entries_after(block_number).count();
+        let mut entries: Vec<_> = self.history.clone().into();
+        entries.truncate(entries.len() - history);
+
+        let snapshot = StateSnapshot {
+            root,
+            account_depth: self.accounts_tree.depth(),
+            token_depth: self.accounts[0].balances_tree.depth(),
+            accounts: accounts.into_values().collect(),
+            history: entries.into(),
+            unfinalized_accounts: Vec::new(),
+            // ops are not recorded per block, this state has no processed ops
+            processed_ops: ProcessedOps::new(),
+            leaf_version: self.leaf_version,
+        };
+
+        Self::from_snapshot_with_hasher(&snapshot, self.accounts_tree.params(), sign_params)
+    }
+
+    // every update after it is journaled until the checkpoint is committed or
+    // rolled back, checkpoints nest
+    pub fn checkpoint(&mut self) -> CheckpointId {
+        self.checkpoints.push((self.journal.len(), self.processed_journal.len()));
+        CheckpointId(self.checkpoints.len() - 1)
+    }
+
+    // restores every account changed after the checkpoint, the root is
+    // rehashed once, inner checkpoints are dropped as well
+    pub fn rollback(&mut self, checkpoint: CheckpointId) -> Result<(), OpenPlasmaError> {
+        let CheckpointId(depth) = checkpoint;
+        if depth >= self.checkpoints.len() {
+            return Err(TreeError::UnknownCheckpoint.into());
+        }
+
+        let (journal_len, processed_len) = self.checkpoints[depth];
+        self.checkpoints.truncate(depth);
+
+        for serial_id in self.processed_journal.drain(processed_len..) {
+            self.processed_ops.remove(serial_id);
+        }
+
+        // newest first, so an account changed several times ends up the oldest
+        while self.journal.len() > journal_len {
+            let entry = self.journal.pop().unwrap();
+            let leaf = entry.account.encode_leaf(self.leaf_version);
+            self.unindex_account(entry.account_id);
+            self.accounts[entry.account_id] = entry.account;
+            self.index_account(entry.account_id);
+            self.accounts_tree.set_leaf(entry.account_id, leaf);
+        }
+        self.accounts_tree.refresh();
+
+        Ok(())
+    }
+
+    // keeps the updates, an outer checkpoint can still roll them back
+    pub fn commit(&mut self, checkpoint: CheckpointId) -> Result<(), OpenPlasmaError> {
+        let CheckpointId(depth) = checkpoint;
+        if depth >= self.checkpoints.len() {
+            return Err(TreeError::UnknownCheckpoint.into());
+        }
+
+        self.checkpoints.truncate(depth);
+        if self.checkpoints.is_empty() {
+            self.journal.clear();
+            self.processed_journal.clear();
+        }
+
+        Ok(())
+    }
+
+    pub fn check_account(&self, account_id: usize) -> Result<(), OpenPlasmaError> {
+        if account_id >= self.accounts.len() {
+            return Err(TreeError::AccountOutOfRange(account_id).into());
+        }
+        Ok(())
+    }
+
+    pub fn check_token(&self, account_id: usize, token_id: usize) -> Result<(), OpenPlasmaError> {
+        self.check_account(account_id)?;
+        if token_id >= self.accounts[account_id].balances.len() {
+            return Err(TreeError::TokenOutOfRange(token_id).into());
+        }
+        Ok(())
+    }
+
+    // requests signed by the account owner need a key to check against
+    pub fn check_registered(&self, account_id: usize) -> Result<(), OpenPlasmaError> {
+        self.check_account(account_id)?;
+        if !self.registered.contains(&account_id) {
+            return Err(TreeError::EmptyAccount(account_id).into());
+        }
+        Ok(())
+    }
+
+    // the same as empty_account_leaf for the tree depth and params, encoded
+    // for the leaf version of the tree
+    pub fn empty_leaf(&self) -> Vec::<bn256::Fr> {
+        self.accounts.empty.encode_leaf(self.leaf_version)
+    }
+
+    // resets the leaf to the empty one, so the slot can be registered again,
+    // only an account without balances can be closed
+    pub fn clear_account(&mut self, account_id: usize) -> Result<(), OpenPlasmaError> {
+        self.check_account(account_id)?;
+        if self.accounts[account_id].balances.iter().any(|(_, balance)| !balance.is_zero()) {
+            return Err(TreeError::AccountNotEmpty(account_id).into());
+        }
+        self.journal_account(account_id);
+
+        self.unindex_account(account_id);
+        self.accounts.reset(account_id);
+
+        self.accounts_tree.update_leaf(
+            account_id,
+            self.accounts[account_id].encode_leaf(self.leaf_version),
+        );
+
+        Ok(())
     }
 
     pub fn update_account(
@@ -80,64 +1090,352 @@ impl<'a> AccountsTree<'a> {
         account_id: usize,
         pubkey: PublicKey::<Bn256>,
         nonce: bn256::Fr,
-    ) {
-        assert!(account_id < self.accounts.len());
+    ) -> Result<(), OpenPlasmaError> {
+        self.check_account(account_id)?;
+        self.journal_account(account_id);
 
+        // a rotated key no longer points to the account
+        self.unindex_account(account_id);
         self.accounts[account_id].pubkey = pubkey;
         self.accounts[account_id].nonce = nonce;
+        self.index_account(account_id);
 
         self.accounts_tree.update_leaf(
             account_id,
-            self.accounts[account_id].compress_to_leaf(),
+            self.accounts[account_id].encode_leaf(self.leaf_version),
         );
+
+        Ok(())
     }
 
     pub fn update_nonce(
         &mut self,
         account_id: usize,
         nonce: bn256::Fr,
-    ) {
-        assert!(account_id < self.accounts.len());
+    ) -> Result<(), OpenPlasmaError> {
+        self.check_account(account_id)?;
+        self.journal_account(account_id);
 
         self.accounts[account_id].nonce = nonce;
 
         self.accounts_tree.update_leaf(
             account_id,
-            self.accounts[account_id].compress_to_leaf(),
+            self.accounts[account_id].encode_leaf(self.leaf_version),
         );
+
+        Ok(())
     }
     
-    pub fn get_pubkey(&self, account_id: usize) -> PublicKey::<Bn256> {
-        assert!(account_id < self.accounts.len());        
-        self.accounts[account_id].pubkey.clone()
+    pub fn get_pubkey(&self, account_id: usize) -> Result<PublicKey::<Bn256>, OpenPlasmaError> {
+        self.check_account(account_id)?;
+        Ok(self.accounts[account_id].pubkey.clone())
     }
 
-    pub fn get_nonce(&self, account_id: usize) -> bn256::Fr {
-        assert!(account_id < self.accounts.len());        
-        self.accounts[account_id].nonce
+    pub fn get_nonce(&self, account_id: usize) -> Result<bn256::Fr, OpenPlasmaError> {
+        self.check_account(account_id)?;
+        Ok(self.accounts[account_id].nonce)
     }
 
     pub fn update_balance(
         &mut self,
         account_id: usize,
+        token_id: usize,
         new_balance: bn256::Fr,
-    ) {
-        assert!(account_id < self.accounts.len());
-        self.accounts[account_id].balance = new_balance;
+    ) -> Result<(), OpenPlasmaError> {
+        self.check_token(account_id, token_id)?;
+        self.journal_account(account_id);
+
+        let account = &mut self.accounts[account_id];
+        account.balances.set(token_id, new_balance);
+        account.balances_tree.update_leaf(token_id, vec![new_balance]);
 
         self.accounts_tree.update_leaf(
             account_id,
-            self.accounts[account_id].compress_to_leaf(),
+            self.accounts[account_id].encode_leaf(self.leaf_version),
         );
+
+        Ok(())
+    }
+
+    pub fn get_balance(&self, account_id: usize, token_id: usize) -> Result<bn256::Fr, OpenPlasmaError> {
+        self.check_token(account_id, token_id)?;
+        Ok(self.accounts[account_id].balances[token_id])
     }
 
-    pub fn get_balance(&mut self, account_id: usize) -> bn256::Fr {
-        assert!(account_id < self.accounts.len());
-        self.accounts[account_id].balance 
+    // typed reads, a leaf value that doesn't fit is an error instead of a
+    // truncated number
+    pub fn balance(&self, account_id: AccountId, token_id: usize) -> Result<Balance, OpenPlasmaError> {
+        self.check_token(account_id.index(), token_id)?;
+        Ok(Balance::try_from_fr(&self.accounts[account_id.index()].balances[token_id])?)
+    }
+
+    pub fn nonce(&self, account_id: AccountId) -> Result<Nonce, OpenPlasmaError> {
+        self.check_account(account_id.index())?;
+        Ok(Nonce::try_from_fr(&self.accounts[account_id.index()].nonce)?)
+    }
+
+    // a signed request carries the nonce after the account one, returns the
+    // account nonce it replaces
+    pub fn check_next_nonce(&self, account_id: usize, nonce: Nonce) -> Result<Nonce, OpenPlasmaError> {
+        self.check_account(account_id)?;
+        let old_nonce = Nonce::try_from_fr(&self.accounts[account_id].nonce)?;
+        let expected = old_nonce.next().ok_or(TreeError::NonceOverflow(account_id))?;
+        if nonce != expected {
+            return Err(TreeError::NonceMismatch { account_id, expected, got: nonce }.into());
+        }
+        Ok(old_nonce)
+    }
+
+    pub fn get_leaf_path(&self, account_id: usize) -> Result<Vec::<bn256::Fr>, OpenPlasmaError> {
+        self.check_account(account_id)?;
+        Ok(self.accounts_tree.get_leaf_path(account_id))
+    }
+
+    pub fn get_leaf_indices(&self, account_id: usize) -> Result<Vec::<bool>, OpenPlasmaError> {
+        self.check_account(account_id)?;
+        Ok(self.accounts_tree.get_leaf_indices(account_id))
+    }
+
+    pub fn get_token_path(&self, account_id: usize, token_id: usize) -> Result<Vec::<bn256::Fr>, OpenPlasmaError> {
+        self.check_token(account_id, token_id)?;
+        Ok(self.accounts[account_id].balances_tree.get_leaf_path(token_id))
+    }
+
+    pub fn get_token_indices(&self, account_id: usize, token_id: usize) -> Result<Vec::<bool>, OpenPlasmaError> {
+        self.check_token(account_id, token_id)?;
+        Ok(self.accounts[account_id].balances_tree.get_leaf_indices(token_id))
     }
 
     pub fn get_root(&self) -> bn256::Fr {
         self.accounts_tree.root()
     }
-}
 
+    pub fn prove(&self, account_id: usize) -> Result<MerkleProof, OpenPlasmaError> {
+        self.check_account(account_id)?;
+
+        Ok(MerkleProof {
+            leaf: self.accounts[account_id].encode_leaf(self.leaf_version),
+            path: self.accounts_tree.get_leaf_path(account_id),
+            indices: self.accounts_tree.get_leaf_indices(account_id),
+            root: self.get_root(),
+        })
+    }
+
+    pub fn prove_balance(&self, account_id: usize, token_id: usize) -> Result<BalanceProof, OpenPlasmaError> {
+        self.check_token(account_id, token_id)?;
+
+        let account = &self.accounts[account_id];
+
+        Ok(BalanceProof {
+            account: self.prove(account_id)?,
+            balance: MerkleProof {
+                leaf: vec![account.balances[token_id]],
+                path: account.balances_tree.get_leaf_path(token_id),
+                indices: account.balances_tree.get_leaf_indices(token_id),
+                root: account.balances_tree.root(),
+            },
+        })
+    }
+
+    // the same account states as applying the updates one by one, but the
+    // accounts tree rehashes only the siblings each witness path needs and
+    // every other changed node once at the end
+    pub fn apply_batch(
+        &mut self,
+        updates: &[LeafUpdate],
+    ) -> Result<Vec::<AccountState::<Bn256>>, OpenPlasmaError> {
+        let _span = timed_span!(
+            "tree.apply_batch",
+            updates = updates.len(),
+            depth = self.accounts_tree.depth(),
+        );
+
+        self.check_updates(updates)?;
+
+        let mut path_cache = PathCache::default();
+        let states = updates.iter().map(
+            |update| self.apply_update(update, &mut path_cache)
+        ).collect();
+
+        self.accounts_tree.refresh();
+
+        states
+    }
+
+    // one update of a block whose updates share the path cache, see
+    // PathCache. the accounts tree is left stale, refresh_accounts_tree
+    // brings it up to date once the block is done
+    pub fn apply_cached(
+        &mut self,
+        update: &LeafUpdate,
+        path_cache: &mut PathCache<bn256::Fr>,
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+        self.check_updates(std::slice::from_ref(update))?;
+        self.apply_update(update, path_cache)
+    }
+
+    // rehashes the nodes left stale by apply_cached
+    pub fn refresh_accounts_tree(&mut self) {
+        self.accounts_tree.refresh();
+    }
+
+    // nothing is applied unless every update is valid
+    fn check_updates(&self, updates: &[LeafUpdate]) -> Result<(), OpenPlasmaError> {
+        let mut balances = HashMap::new();
+        let mut nonces = HashMap::new();
+        for update in updates.iter() {
+            self.check_token(update.account_id, update.token_id)?;
+
+            if update.increment_nonce {
+                let nonce = match nonces.get(&update.account_id) {
+                    Some(nonce) => *nonce,
+                    None => Nonce::try_from_fr(&self.accounts[update.account_id].nonce)?,
+                };
+                let next = nonce.next().ok_or(TreeError::NonceOverflow(update.account_id))?;
+                nonces.insert(update.account_id, next);
+            }
+
+            let key = (update.account_id, update.token_id);
+            let balance = match balances.get(&key) {
+                Some(balance) => *balance,
+                None => Balance::try_from_fr(&self.get_balance(update.account_id, update.token_id)?)?.0,
+            };
+            let credited = balance.checked_add(update.credit).ok_or(
+                TreeError::BalanceOverflow {
+                    account_id: update.account_id,
+                    token_id: update.token_id,
+                }
+            )?;
+            let new_balance = credited.checked_sub(update.debit).ok_or(
+                TreeError::InsufficientBalance {
+                    account_id: update.account_id,
+                    token_id: update.token_id,
+                }
+            )?;
+            balances.insert(key, new_balance);
+        }
+
+        Ok(())
+    }
+
+    // a checked update, the leaf is set but its ancestors are only marked stale.
+    // the balance is still converted with checks, a tree that check_updates
+    // passed doesn't fail here and nothing is changed if it does
+    fn apply_update(
+        &mut self,
+        update: &LeafUpdate,
+        path_cache: &mut PathCache<bn256::Fr>,
+    ) -> Result<AccountState::<Bn256>, OpenPlasmaError> {
+        let old_balance = self.accounts[update.account_id].balances[update.token_id];
+        let credited = Balance::try_from_fr(&old_balance)?.0.checked_add(update.credit).ok_or(
+            TreeError::BalanceOverflow { account_id: update.account_id, token_id: update.token_id }
+        )?;
+        let new_balance = u128_to_fr(credited.checked_sub(update.debit).ok_or(
+            TreeError::InsufficientBalance { account_id: update.account_id, token_id: update.token_id }
+        )?);
+
+        let account_path = self.accounts_tree.cached_leaf_path(path_cache, update.account_id);
+        let account_indices = self.accounts_tree.get_leaf_indices(update.account_id);
+        let balances_tree = &self.accounts[update.account_id].balances_tree;
+        let token_path = balances_tree.get_leaf_path(update.token_id);
+        let token_indices = balances_tree.get_leaf_indices(update.token_id);
+        self.journal_account(update.account_id);
+        if update.pubkey.is_some() {
+            self.unindex_account(update.account_id);
+        }
+
+        let account = &mut self.accounts[update.account_id];
+        let old_pubkey = account.pubkey.clone();
+        let old_nonce = account.nonce;
+
+        account.balances.set(update.token_id, new_balance);
+        account.balances_tree.update_leaf(update.token_id, vec![new_balance]);
+        if let Some(pubkey) = &update.pubkey {
+            account.pubkey = pubkey.clone();
+        }
+        if update.increment_nonce {
+            account.nonce.add_assign(&bn256::Fr::one());
+        }
+
+        let new_pubkey = account.pubkey.clone();
+        let new_nonce = account.nonce;
+        let leaf = account.encode_leaf(self.leaf_version);
+        self.accounts_tree.set_cached_leaf(path_cache, update.account_id, leaf);
+        if update.pubkey.is_some() {
+            self.index_account(update.account_id);
+        }
+
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(old_balance),
+            new_balance: Some(new_balance),
+            old_pubkey: Some(old_pubkey.0),
+            new_pubkey: Some(new_pubkey.0),
+            old_nonce: Some(old_nonce),
+            new_nonce: Some(new_nonce),
+            account_path: optionalize(account_path),
+            account_indices: optionalize(account_indices),
+            token_path: optionalize(token_path),
+            token_indices: optionalize(token_indices),
+        })
+    }
+
+    // magic, version, account and token depths and a flag byte with the leaf
+    // version in its high nibble, then the number of stored accounts and for
+    // each its id, pubkey, nonce, stored balances by token id and, with cached
+    // nodes, the stored nodes of its balances tree; then the stored nodes of
+    // the accounts tree, the root and a checksum of it all. nodes are keyed
+    // by level and offset, see write_nodes. field elements are 32 bytes big
+    // endian, numbers 8, levels 1, the checksum is fnv-1a 64
+    pub fn write<W: Write>(&self, writer: W, with_nodes: bool) -> Result<(), OpenPlasmaError> {
+        let mut writer = ChecksumWriter::new(writer);
+        let token_depth = self.accounts[0].balances_tree.depth();
+
+        writer.write_all(TREE_FILE_MAGIC)?;
+        writer.write_all(&[
+            TREE_FILE_VERSION,
+            self.accounts_tree.depth() as u8,
+            token_depth as u8,
+            (self.leaf_version.number() << TREE_FILE_LEAF_VERSION_SHIFT)
+                | if with_nodes { TREE_FILE_WITH_NODES } else { 0 },
+        ])?;
+
+        write_u64(&mut writer, self.accounts.num_stored())?;
+        for (account_id, account) in self.accounts.iter() {
+            write_u64(&mut writer, account_id)?;
+            account.pubkey.write(&mut writer)?;
+            write_fr(&mut writer, &account.nonce)?;
+            write_u64(&mut writer, account.balances.num_stored())?;
+            for (token_id, balance) in account.balances.iter() {
+                write_u64(&mut writer, token_id)?;
+                write_fr(&mut writer, balance)?;
+            }
+            if with_nodes {
+                write_nodes(&mut writer, &account.balances_tree.nodes())?;
+            }
+        }
+
+        if with_nodes {
+            write_nodes(&mut writer, &self.accounts_tree.nodes())?;
+        }
+
+        write_fr(&mut writer, &self.get_root())?;
+        let checksum = writer.checksum();
+        writer.write_all(&checksum.to_be_bytes())?;
+        Ok(())
+    }
+
+    // written next to the path and renamed, so a crash never leaves half a file
+    pub fn save<P: AsRef<Path>>(&self, path: P, with_nodes: bool) -> Result<(), OpenPlasmaError> {
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        self.write(&mut writer, with_nodes)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use sapling_crypto_ce::{
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    jubjub::{
+        JubjubEngine,
+        edwards::Point,
+        Unknown,
+    },
+};
+
+use ff_ce::Field;
+
+use pairing_ce::Engine;
+
+use crate::utils::point::pack_point;
+use crate::hasher::{ TreeHasher, Poseidon };
+use super::merkle_tree::BINARY_ARITY;
+
+// the leaf of an account that never registered or was closed: the empty
+// pubkey, nonce 0 and the root of zero balances. the tree and the circuits
+// both take it from here, so they agree on what empty means
+
+// the point with y = 0, nobody knows its discrete log
+pub fn empty_pubkey<E: JubjubEngine>(sign_params: &<E as JubjubEngine>::Params) -> Point<E, Unknown> {
+    Point::<E, Unknown>::get_for_y(E::Fr::zero(), true, sign_params).unwrap()
+}
+
+// every level of a zero tree is one node hashed with itself, so depth hashes
+// instead of building the tree
+pub fn empty_balances_root<E>(token_depth: usize, hash_params: &<E as PoseidonEngine>::Params) -> E::Fr
+    where E: PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    empty_balances_root_with_hasher::<E, Poseidon>(token_depth, BINARY_ARITY, hash_params)
+}
+
+// the same for the trees of another hasher or arity, level nodes hash arity
+// copies of the node below
+pub fn empty_balances_root_with_hasher<E: Engine, H: TreeHasher<E>>(
+    token_depth: usize,
+    arity: usize,
+    params: &H::Params,
+) -> E::Fr {
+    let mut node = H::hash(params, &[E::Fr::zero()]);
+    for _ in 0..token_depth {
+        node = H::hash(params, &vec![node; arity]);
+    }
+    node
+}
+
+pub fn empty_account_leaf<E>(
+    token_depth: usize,
+    hash_params: &<E as PoseidonEngine>::Params,
+    sign_params: &<E as JubjubEngine>::Params,
+) -> Vec::<E::Fr>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    empty_account_leaf_with_hasher::<E, Poseidon>(token_depth, BINARY_ARITY, hash_params, sign_params)
+}
+
+pub fn empty_account_leaf_with_hasher<E, H>(
+    token_depth: usize,
+    arity: usize,
+    params: &H::Params,
+    sign_params: &<E as JubjubEngine>::Params,
+) -> Vec::<E::Fr>
+    where E: JubjubEngine,
+          H: TreeHasher<E>,
+{
+    let pubkey = pack_point(&empty_pubkey::<E>(sign_params));
+    vec![pubkey, E::Fr::zero(), empty_balances_root_with_hasher::<E, H>(token_depth, arity, params)]
+}
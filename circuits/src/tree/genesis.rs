@@ -0,0 +1,138 @@
+use std::collections::{ BTreeMap, BTreeSet };
+
+use serde::{ Serialize, Deserialize };
+
+use sapling_crypto_ce::{
+    eddsa::PublicKey,
+    jubjub::edwards::Point,
+};
+
+use pairing_ce::bn256::{ self, Bn256 };
+
+use crate::utils::serde_fr;
+use crate::data_structs::offchain_withdrawal::is_canonical_point;
+use crate::params::{ poseidon_params, jubjub_params };
+use crate::types::{ AccountId, Balance };
+use crate::error::OpenPlasmaError;
+
+use super::account::{ AccountsTree, LeafUpdate, TreeError, MAX_TREE_DEPTH };
+use super::leaf::LeafVersion;
+
+// an account of the initial state, nonce 0. the pubkey is the point, not
+// packed, so a file with a point off the curve is refused rather than read
+// as some other key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisAccount {
+    pub account_id: AccountId,
+    #[serde(with = "serde_fr")]
+    pub pubkey_x: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    pub pubkey_y: bn256::Fr,
+    // by token id, tokens not listed are zero
+    #[serde(default)]
+    pub balances: BTreeMap::<usize, Balance>,
+}
+
+impl GenesisAccount {
+    pub fn new(account_id: AccountId, pubkey: &PublicKey::<Bn256>) -> Self {
+        let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+        GenesisAccount {
+            account_id,
+            pubkey_x,
+            pubkey_y,
+            balances: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_balance(mut self, token_id: usize, amount: Balance) -> Self {
+        self.balances.insert(token_id, amount);
+        self
+    }
+}
+
+// the initial state of a deployment, committed as json next to the contract
+// and built into the tree its root is deployed with. every account not
+// listed is empty
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Genesis {
+    // the account depth, account ids are below 2^depth
+    pub depth: usize,
+    pub token_depth: usize,
+    pub accounts: Vec::<GenesisAccount>,
+    #[serde(default)]
+    pub leaf_version: LeafVersion,
+}
+
+impl Genesis {
+    pub fn new(depth: usize, token_depth: usize) -> Self {
+        Genesis {
+            depth,
+            token_depth,
+            accounts: Vec::new(),
+            leaf_version: LeafVersion::default(),
+        }
+    }
+
+    pub fn with_account(mut self, account: GenesisAccount) -> Self {
+        self.accounts.push(account);
+        self
+    }
+
+    // the whole file is checked before anything is hashed
+    pub fn validate(&self) -> Result<(), OpenPlasmaError> {
+        if self.depth > MAX_TREE_DEPTH || self.token_depth > MAX_TREE_DEPTH {
+            return Err(TreeError::DepthTooLarge(self.depth.max(self.token_depth)).into());
+        }
+
+        let sign_params = jubjub_params();
+        let mut account_ids = BTreeSet::new();
+        for account in self.accounts.iter() {
+            let account_id = account.account_id.index();
+            if (account_id as u64) >> self.depth != 0 {
+                return Err(TreeError::AccountOutOfRange(account_id).into());
+            }
+            if !account_ids.insert(account_id) {
+                return Err(TreeError::DuplicateAccount(account_id).into());
+            }
+            if let Some(token_id) = account.balances.keys().find(|token_id| (**token_id as u64) >> self.token_depth != 0) {
+                return Err(TreeError::TokenOutOfRange(*token_id).into());
+            }
+            match Point::<Bn256, _>::from_xy(account.pubkey_x, account.pubkey_y, sign_params) {
+                Some(pubkey) if is_canonical_point(&pubkey, sign_params) => (),
+                _ => return Err(TreeError::InvalidPubkey(account_id).into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    // a tree like any other, with the shared params, and its root
+    pub fn build(&self) -> Result<(AccountsTree<'static>, bn256::Fr), OpenPlasmaError> {
+        self.validate()?;
+
+        let sign_params = jubjub_params();
+        let mut updates = Vec::new();
+        for account in self.accounts.iter() {
+            let pubkey = Point::from_xy(account.pubkey_x, account.pubkey_y, sign_params).expect("validated");
+            let update = LeafUpdate {
+                account_id: account.account_id.index(),
+                token_id: 0,
+                pubkey: Some(PublicKey::<Bn256>(pubkey)),
+                credit: 0,
+                debit: 0,
+                increment_nonce: false,
+            };
+            updates.push(update.clone());
+            updates.extend(account.balances.iter().map(
+                |(token_id, amount)| LeafUpdate { token_id: *token_id, pubkey: None, credit: amount.0, ..update.clone() }
+            ));
+        }
+
+        let mut tree = AccountsTree::new(self.depth, self.token_depth, poseidon_params(), sign_params)
+            .with_leaf_version(self.leaf_version);
+        tree.apply_batch(&updates)?;
+        let root = tree.get_root();
+
+        Ok((tree, root))
+    }
+}
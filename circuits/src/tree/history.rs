@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use serde::{ Serialize, Deserialize };
+
+use pairing_ce::bn256;
+
+use ff_ce::{ PrimeField, PrimeFieldRepr };
+
+use crate::utils::serde_fr;
+
+use super::snapshot::AccountSnapshot;
+
+// a finalized block: its root and every account it changed as it was before
+// the block, so older states are restored by undoing newer blocks
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub block_number: usize,
+    #[serde(with = "serde_fr")]
+    pub root: bn256::Fr,
+    pub previous_accounts: Vec::<AccountSnapshot>,
+}
+
+// roots of finalized blocks in ascending block order
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<HistoryEntry>", into = "Vec<HistoryEntry>")]
+pub struct RootHistory {
+    entries: Vec::<HistoryEntry>,
+    // root to the first block that committed it
+    blocks_by_root: HashMap::<[u8; 32], usize>,
+}
+
+fn root_key(root: &bn256::Fr) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    root.into_repr().write_be(&mut key[..]).unwrap();
+    key
+}
+
+impl RootHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // false if the block is not after the last recorded one
+    pub fn record(
+        &mut self,
+        block_number: usize,
+        root: bn256::Fr,
+        previous_accounts: Vec::<AccountSnapshot>,
+    ) -> bool {
+        if let Some(last) = self.entries.last() {
+            if block_number <= last.block_number {
+                return false;
+            }
+        }
+
+        self.blocks_by_root.entry(root_key(&root)).or_insert(block_number);
+        self.entries.push(HistoryEntry { block_number, root, previous_accounts });
+
+        true
+    }
+
+    pub fn get_root_at(&self, block_number: usize) -> Option<bn256::Fr> {
+        self.entries.binary_search_by_key(&block_number, |entry| entry.block_number)
+            .ok()
+            .map(|position| self.entries[position].root)
+    }
+
+    pub fn contains(&self, root: &bn256::Fr) -> bool {
+        self.blocks_by_root.contains_key(&root_key(root))
+    }
+
+    pub fn block_of(&self, root: &bn256::Fr) -> Option<usize> {
+        self.blocks_by_root.get(&root_key(root)).cloned()
+    }
+
+    pub fn last_block(&self) -> Option<usize> {
+        self.entries.last().map(|entry| entry.block_number)
+    }
+
+    // entries after the block, newest first
+    pub fn entries_after(&self, block_number: usize) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter().rev().take_while(move |entry| entry.block_number > block_number)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl From<Vec::<HistoryEntry>> for RootHistory {
+    fn from(entries: Vec::<HistoryEntry>) -> Self {
+        let mut history = RootHistory::new();
+        for entry in entries {
+            history.record(entry.block_number, entry.root, entry.previous_accounts);
+        }
+        history
+    }
+}
+
+impl From<RootHistory> for Vec::<HistoryEntry> {
+    fn from(history: RootHistory) -> Self {
+        history.entries
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    fmt,
+    error::Error,
+};
+
+use serde::{ Serialize, Deserialize };
+
+use ff_ce::PrimeField;
+
+use pairing_ce::bn256;
+
+use crate::utils::serde_fr;
+use crate::error::OpenPlasmaError;
+
+// the fields of an account leaf, whatever their order and tag. the pubkey is
+// packed, see utils::point
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeafAccount {
+    #[serde(with = "serde_fr")]
+    pub pubkey: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    pub nonce: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    pub balances_root: bn256::Fr,
+}
+
+// the layout of an account leaf. V0 is the untagged [pubkey, nonce, balances
+// root] every existing root is of. later versions put their number first, so
+// a leaf of one version never hashes like a leaf of another and a layout
+// change comes with a new version instead of a new meaning for old roots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LeafVersion {
+    #[default]
+    V0,
+    // [1, pubkey, nonce, balances root]
+    V1,
+}
+
+impl LeafVersion {
+    pub const LATEST: LeafVersion = LeafVersion::V1;
+
+    pub fn number(&self) -> u8 {
+        match self {
+            LeafVersion::V0 => 0,
+            LeafVersion::V1 => 1,
+        }
+    }
+
+    pub fn from_number(number: u8) -> Option<Self> {
+        match number {
+            0 => Some(LeafVersion::V0),
+            1 => Some(LeafVersion::V1),
+            _ => None,
+        }
+    }
+
+    // the version migrate_leaves rehashes to, None for the latest
+    pub fn next(&self) -> Option<Self> {
+        Self::from_number(self.number() + 1)
+    }
+
+    // the first leaf element, None for the untagged V0. generic, so the
+    // circuits of any engine prepend the same constant
+    pub fn tag<F: PrimeField>(&self) -> Option<F> {
+        match self {
+            LeafVersion::V0 => None,
+            _ => Some(F::from_repr(F::Repr::from(self.number() as u64)).unwrap()),
+        }
+    }
+
+    pub fn leaf_size(&self) -> usize {
+        match self {
+            LeafVersion::V0 => 3,
+            _ => 4,
+        }
+    }
+}
+
+impl fmt::Display for LeafVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "v{}", self.number())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeafError {
+    WrongLength { version: LeafVersion, expected: usize, actual: usize },
+    // the first element is not the tag of the version
+    WrongTag(LeafVersion),
+}
+
+impl Error for LeafError {}
+
+impl fmt::Display for LeafError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            LeafError::WrongLength { version, expected, actual } => write!(
+                f, "Leaf {} has {} elements, expected {}", version, actual, expected),
+            LeafError::WrongTag(version) => write!(f, "Leaf is not tagged {}", version),
+        }
+    }
+}
+
+// an account to the field elements of its leaf and back, for one version.
+// the accounts tree and the migration hash what encode returns
+pub trait LeafCodec {
+    fn version(&self) -> LeafVersion;
+
+    fn encode(&self, account: &LeafAccount) -> Vec::<bn256::Fr>;
+
+    fn decode(&self, leaf: &[bn256::Fr]) -> Result<LeafAccount, OpenPlasmaError>;
+}
+
+impl LeafCodec for LeafVersion {
+    fn version(&self) -> LeafVersion {
+        *self
+    }
+
+    fn encode(&self, account: &LeafAccount) -> Vec::<bn256::Fr> {
+        let mut leaf = Vec::with_capacity(self.leaf_size());
+        leaf.extend(self.tag::<bn256::Fr>());
+        leaf.extend([account.pubkey, account.nonce, account.balances_root]);
+        leaf
+    }
+
+    fn decode(&self, leaf: &[bn256::Fr]) -> Result<LeafAccount, OpenPlasmaError> {
+        if leaf.len() != self.leaf_size() {
+            return Err(LeafError::WrongLength {
+                version: *self,
+                expected: self.leaf_size(),
+                actual: leaf.len(),
+            }.into());
+        }
+
+        let fields = match self.tag::<bn256::Fr>() {
+            Some(tag) if leaf[0] != tag => return Err(LeafError::WrongTag(*self).into()),
+            Some(_) => &leaf[1..],
+            None => leaf,
+        };
+
+        Ok(LeafAccount {
+            pubkey: fields[0],
+            nonce: fields[1],
+            balances_root: fields[2],
+        })
+    }
+}
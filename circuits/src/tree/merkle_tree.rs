@@ -1,173 +1,512 @@
-use std::fmt;
+use std::{
+    fmt,
+    collections::{ HashMap, HashSet },
+};
+
+use sapling_crypto_ce::poseidon::PoseidonEngine;
+
+use crate::hasher::{ TreeHasher, Poseidon };
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+// the hash of every input in the input order, spread over threads with the
+// parallel feature, the hashes are the same either way
+fn hash_many<E, H, I>(params: &H::Params, inputs: &[I]) -> Vec::<E::Fr>
+    where E: PoseidonEngine,
+          H: TreeHasher<E>,
+          H::Params: Sync,
+          I: AsRef<[E::Fr]> + Sync,
+{
+    #[cfg(feature = "parallel")]
+    let inputs = inputs.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let inputs = inputs.iter();
+
+    inputs.map(
+        |input| H::hash(params, input.as_ref())
+    ).collect()
+}
+
+pub const BINARY_ARITY: usize = 2;
 
-use sapling_crypto_ce::{
-    poseidon::{
-        PoseidonEngine,
-        QuinticSBox,
-        poseidon_hash,
+// witness paths of leaves of one tree updated with set_cached_leaf, e.g. the
+// accounts of a block. a leaf update changes only its ancestors, so its own
+// path stays valid and the path of another leaf changes only at the level
+// where the two paths meet, the one level that is read again. every update of
+// the tree has to go through set_cached_leaf while the cache is used
+pub struct PathCache<F> {
+    paths: HashMap<usize, CachedPath<F>>,
+}
+
+struct CachedPath<F> {
+    path: Vec::<F>,
+    // levels whose siblings changed since the path was read
+    stale: Vec::<bool>,
+}
+
+impl<F> Default for PathCache<F> {
+    fn default() -> Self {
+        PathCache { paths: HashMap::new() }
+    }
+}
+
+impl<F> PathCache<F> {
+    // the level below which the paths of the leaves are apart
+    fn meeting_level(mut leaf_index: usize, mut other_index: usize, arity: usize) -> usize {
+        let mut level = 0;
+        while leaf_index / arity != other_index / arity {
+            leaf_index /= arity;
+            other_index /= arity;
+            level += 1;
+        }
+        level
     }
-};
 
-pub struct PoseidonMerkleTree<'a, E: PoseidonEngine> {
-    params: &'a E::Params,
-    tree: Vec::<E::Fr>,
+    fn invalidate(&mut self, leaf_index: usize, arity: usize) {
+        for (&other_index, cached) in self.paths.iter_mut() {
+            if other_index != leaf_index {
+                cached.stale[Self::meeting_level(leaf_index, other_index, arity)] = true;
+            }
+        }
+    }
+}
+
+// hashed with poseidon unless another TreeHasher is given. sparse: only
+// the nodes that differ from the root of an empty subtree of their level are
+// stored, so a deep tree costs the leaves set in it
+pub struct PoseidonMerkleTree<'a, E: PoseidonEngine, H: TreeHasher<E> = Poseidon> {
+    params: &'a H::Params,
+    // (level, offset in the level) to the node, leaves are level 0
+    nodes: HashMap::<(usize, usize), E::Fr>,
+    // the root of an empty subtree by level, from the empty leaf up
+    empty: Vec::<E::Fr>,
     depth: usize,
+    // children per node, a power of two, so a leaf index is still addressed
+    // by its binary digits, log2(arity) of them per level
+    arity: usize,
+    // internal nodes whose children changed after set_leaf, see refresh
+    stale: HashSet::<(usize, usize)>,
 }
 
 #[allow(dead_code)]
-impl<'a, E> PoseidonMerkleTree<'a, E>
-    where E: PoseidonEngine<SBox = QuinticSBox<E>>
+impl<'a, E, H> PoseidonMerkleTree<'a, E, H>
+    where E: PoseidonEngine,
+          H: TreeHasher<E>,
+          H::Params: Sync,
 {
     pub fn hash(&self, input: &[E::Fr]) -> E::Fr {
-        let hash = poseidon_hash::<E>(self.params, input);
-        hash[0].clone()
+        H::hash(self.params, input)
     }
 
     pub fn num_leaves(&self) -> usize {
-        1 << self.depth
+        1 << (self.depth * self.index_bits())
     }
 
     pub fn depth(&self) -> usize {
         self.depth
     }
 
-    pub fn new(leaves: Vec::<Vec::<E::Fr>>, params: &'a E::Params) -> Self {
-        let mut merkle_tree = PoseidonMerkleTree {
-            params: params,
-            tree: Vec::new(),
-            depth: 0,
-        };
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
 
-        merkle_tree.tree = leaves
-            .iter().map(
-                |leaf| merkle_tree.hash(leaf)
-            ).collect();
+    // index bits per level
+    fn index_bits(&self) -> usize {
+        self.arity.trailing_zeros() as usize
+    }
 
-        let mut start_index = 0;
-        let mut end_index = leaves.len();
+    pub fn params(&self) -> &'a H::Params {
+        self.params
+    }
 
-        while end_index - start_index > 1 {
-            for i in (start_index..end_index).step_by(2) {
-                merkle_tree.tree.push(
-                    merkle_tree.hash(
-                        &merkle_tree.tree[i..i + 2]
-                    )
-                );
-            }
+    pub fn new(leaves: Vec::<Vec::<E::Fr>>, params: &'a H::Params) -> Self {
+        Self::new_with_arity(leaves, BINARY_ARITY, params)
+    }
 
-            start_index = end_index;
-            end_index = merkle_tree.tree.len();
-            merkle_tree.depth += 1;
+    // the number of leaves must be a power of the arity. the first leaf is
+    // taken for the empty one, only the leaves that differ from it are stored
+    pub fn new_with_arity(leaves: Vec::<Vec::<E::Fr>>, arity: usize, params: &'a H::Params) -> Self {
+        assert!(arity >= 2 && arity.is_power_of_two());
+        assert!(!leaves.is_empty());
+
+        let mut depth = 0;
+        let mut level_nodes = leaves.len();
+        while level_nodes > 1 {
+            assert!(level_nodes.is_multiple_of(arity));
+            level_nodes /= arity;
+            depth += 1;
         }
 
+        let empty_leaf = leaves[0].clone();
+        let mut merkle_tree = Self::new_empty_with_arity(depth, arity, &empty_leaf, params);
+        let leaves: Vec<_> = leaves.into_iter().enumerate().filter(
+            |(_, leaf)| *leaf != empty_leaf
+        ).collect();
+        merkle_tree.set_leaves(leaves);
+        merkle_tree.refresh();
+
         merkle_tree
     }
 
+    pub fn new_empty(depth: usize, empty_leaf: &[E::Fr], params: &'a H::Params) -> Self {
+        Self::new_empty_with_arity(depth, BINARY_ARITY, empty_leaf, params)
+    }
+
+    // every leaf is empty_leaf, depth hashes whatever the depth
+    pub fn new_empty_with_arity(depth: usize, arity: usize, empty_leaf: &[E::Fr], params: &'a H::Params) -> Self {
+        assert!(arity >= 2 && arity.is_power_of_two());
+
+        let mut empty = vec![H::hash(params, empty_leaf)];
+        for level in 0..depth {
+            empty.push(H::hash(params, &vec![empty[level]; arity]));
+        }
+
+        PoseidonMerkleTree {
+            params,
+            nodes: HashMap::new(),
+            empty,
+            depth,
+            arity,
+            stale: HashSet::new(),
+        }
+    }
+
+    // restores a binary tree from its stored nodes, keyed by (level, offset),
+    // checking only that they are inside the tree. every other node is the
+    // root of an empty subtree over empty_leaf
+    pub fn from_nodes(
+        depth: usize,
+        empty_leaf: &[E::Fr],
+        nodes: Vec::<((usize, usize), E::Fr)>,
+        params: &'a H::Params,
+    ) -> Option<Self> {
+        let mut merkle_tree = Self::new_empty(depth, empty_leaf, params);
+        for ((level, offset), node) in nodes {
+            if level > depth || offset >> (depth - level) != 0 {
+                return None;
+            }
+            merkle_tree.set_node(level, offset, node);
+        }
+
+        Some(merkle_tree)
+    }
+
+    // the stored nodes keyed by (level, offset), leaves first, level by level
+    pub fn nodes(&self) -> Vec::<((usize, usize), E::Fr)> {
+        debug_assert!(!self.is_stale());
+
+        let mut nodes: Vec<_> = self.nodes.iter().map(|(key, node)| (*key, *node)).collect();
+        nodes.sort_by_key(|(key, _)| *key);
+        nodes
+    }
+
+    // the number of nodes stored, the others are roots of empty subtrees
+    pub fn num_stored_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // the root of an empty subtree of the level
+    pub fn empty_node(&self, level: usize) -> E::Fr {
+        self.empty[level]
+    }
+
+    fn node(&self, level: usize, offset: usize) -> E::Fr {
+        match self.nodes.get(&(level, offset)) {
+            Some(node) => *node,
+            None => self.empty[level],
+        }
+    }
+
+    // a node equal to the empty one of its level is dropped, so a leaf set
+    // back to empty frees its path
+    fn set_node(&mut self, level: usize, offset: usize, node: E::Fr) {
+        if node == self.empty[level] {
+            self.nodes.remove(&(level, offset));
+        } else {
+            self.nodes.insert((level, offset), node);
+        }
+    }
+
+    fn children(&self, level: usize, offset: usize) -> Vec::<E::Fr> {
+        (0..self.arity).map(
+            |child| self.node(level - 1, offset * self.arity + child)
+        ).collect()
+    }
+
+    // siblings of the node among the children of its parent: the other node
+    // of its pair, then the other pair, the other quadruple and so on, the
+    // order the circuit swaps them in by the index bits
+    fn sibling_offsets(&self, offset: usize) -> Vec::<usize> {
+        let first_child = offset - offset % self.arity;
+        let position = offset % self.arity;
+
+        let mut siblings = Vec::new();
+        for bit in 0..self.index_bits() {
+            let block_size = 1 << bit;
+            let block_start = (position >> (bit + 1) << (bit + 1)) + (((position >> bit) & 1) ^ 1) * block_size;
+            siblings.extend((0..block_size).map(|i| first_child + block_start + i));
+        }
+        siblings
+    }
+
+    // little endian bits of the leaf index, log2(arity) per level
     pub fn get_leaf_indices(&self, leaf_index: usize) -> Vec::<bool> {
         assert!(leaf_index < self.num_leaves());
 
-        let bin_str = format!("{:0w$b}", leaf_index, w=self.depth());
+        let bin_str = format!("{:0w$b}", leaf_index, w=self.depth() * self.index_bits());
         let mut bin_array: Vec<_> = bin_str.chars()
-            .into_iter().map(
+            .map(
                 |x| x == '1'
             ).collect();
         bin_array.reverse();
         bin_array
     }
 
+    // arity - 1 siblings per level, see sibling_offsets. siblings nobody set
+    // are the empty nodes of their level
     pub fn get_leaf_path(&self, leaf_index: usize) -> Vec::<E::Fr> {
         assert!(leaf_index < self.num_leaves());
+        debug_assert!(!self.is_stale());
 
         let mut path = Vec::new();
-        let mut level_start = 0;
-        let mut level_nodes = self.num_leaves();
-        let mut level_shift = leaf_index;
+        let mut offset = leaf_index;
 
-        while level_nodes > 1 {
-            let neighbor_index = {
-                let node_index = level_start + level_shift;
-                if node_index % 2 == 0 {
-                    node_index + 1
-                } else {
-                    node_index - 1
-                }
+        for level in 0..self.depth {
+            for sibling in self.sibling_offsets(offset) {
+                path.push(self.node(level, sibling));
+            }
+            offset /= self.arity;
+        }
+
+        path
+    }
+
+    pub fn update_leaf(&mut self, leaf_index: usize, new_leaf: Vec::<E::Fr>) {
+        assert!(leaf_index < self.num_leaves());
+        self.refresh();
+
+        let leaf = self.hash(&new_leaf);
+        self.set_node(0, leaf_index, leaf);
+
+        let mut offset = leaf_index;
+        for level in 1..=self.depth {
+            offset /= self.arity;
+            let parent = self.hash(&self.children(level, offset));
+            self.set_node(level, offset, parent);
+        }
+    }
+
+    // hashes the leaf but only marks its ancestors stale, so several updates
+    // of nearby leaves rehash their common nodes once in refresh
+    pub fn set_leaf(&mut self, leaf_index: usize, new_leaf: Vec::<E::Fr>) {
+        assert!(leaf_index < self.num_leaves());
+
+        let leaf = self.hash(&new_leaf);
+        self.set_node(0, leaf_index, leaf);
+        self.mark_stale(leaf_index);
+    }
+
+    // set_leaf of many leaves, hashed over threads with the parallel feature
+    pub fn set_leaves(&mut self, leaves: Vec::<(usize, Vec::<E::Fr>)>) {
+        let (indices, leaves): (Vec<_>, Vec<_>) = leaves.into_iter().unzip();
+        let hashes = hash_many::<E, H, _>(self.params, &leaves);
+
+        for (leaf_index, leaf) in indices.into_iter().zip(hashes) {
+            assert!(leaf_index < self.num_leaves());
+            self.set_node(0, leaf_index, leaf);
+            self.mark_stale(leaf_index);
+        }
+    }
+
+    fn mark_stale(&mut self, leaf_index: usize) {
+        let mut offset = leaf_index;
+        for level in 1..=self.depth {
+            offset /= self.arity;
+
+            // the rest of the path is stale already
+            if !self.stale.insert((level, offset)) {
+                break;
+            }
+        }
+    }
+
+    // stale nodes below and including the given one, by level
+    fn collect_stale(&self, level: usize, offset: usize, stale: &mut Vec::<Vec::<usize>>) {
+        if level == 0 || !self.stale.contains(&(level, offset)) {
+            return;
+        }
+
+        stale[level].push(offset);
+        for child in 0..self.arity {
+            self.collect_stale(level - 1, self.arity * offset + child, stale);
+        }
+    }
+
+    // level by level from the bottom, nodes of one level are independent
+    fn refresh_node(&mut self, level: usize, offset: usize) {
+        let mut stale = vec![Vec::new(); level + 1];
+        self.collect_stale(level, offset, &mut stale);
+
+        for (level, offsets) in stale.iter().enumerate().skip(1) {
+            let hashes = {
+                let children: Vec<_> = offsets.iter().map(
+                    |offset| self.children(level, *offset)
+                ).collect();
+                hash_many::<E, H, _>(self.params, &children)
             };
 
-            path.push(self.tree[neighbor_index].clone());
+            for (offset, hash) in offsets.iter().zip(hashes) {
+                self.set_node(level, *offset, hash);
+                self.stale.remove(&(level, *offset));
+            }
+        }
+    }
+
+    // rehashes every stale node once
+    pub fn refresh(&mut self) {
+        self.refresh_node(self.depth, 0);
+    }
+
+    // the same path as get_leaf_path after refresh, but rehashes only the
+    // siblings on the path, the nodes above the leaf may stay stale
+    pub fn refresh_leaf_path(&mut self, leaf_index: usize) -> Vec::<E::Fr> {
+        assert!(leaf_index < self.num_leaves());
 
-            level_shift /= 2;
-            level_start += level_nodes;
-            level_nodes /= 2;
+        let mut path = Vec::new();
+        let mut offset = leaf_index;
+
+        for level in 0..self.depth {
+            for sibling in self.sibling_offsets(offset) {
+                self.refresh_node(level, sibling);
+                path.push(self.node(level, sibling));
+            }
+            offset /= self.arity;
         }
 
         path
     }
 
-    pub fn update_leaf(&mut self, leaf_index: usize, new_leaf: Vec::<E::Fr>) {
+    // refresh_leaf_path with the levels of the path that no update changed
+    // taken from the cache
+    pub fn cached_leaf_path(&mut self, cache: &mut PathCache<E::Fr>, leaf_index: usize) -> Vec::<E::Fr> {
         assert!(leaf_index < self.num_leaves());
 
-        self.tree[leaf_index] = self.hash(
-            &new_leaf
-        );
+        let cached = match cache.paths.get_mut(&leaf_index) {
+            Some(cached) => cached,
+            None => {
+                let path = self.refresh_leaf_path(leaf_index);
+                cache.paths.insert(leaf_index, CachedPath {
+                    path: path.clone(),
+                    stale: vec![false; self.depth],
+                });
+                return path;
+            }
+        };
 
-        let mut level_start = 0;
-        let mut level_nodes = self.num_leaves();
-        let mut level_shift = leaf_index;
+        let siblings = self.arity - 1;
+        let mut offset = leaf_index;
+        for level in 0..self.depth {
+            if cached.stale[level] {
+                for (i, sibling) in self.sibling_offsets(offset).into_iter().enumerate() {
+                    self.refresh_node(level, sibling);
+                    cached.path[level * siblings + i] = self.node(level, sibling);
+                }
+                cached.stale[level] = false;
+            }
+            offset /= self.arity;
+        }
 
-        while level_nodes > 1 { 
-            let parent_index = level_start + level_nodes + level_shift / 2;
-            let even_node_index = {
-                let node_index = level_start + level_shift;
-                node_index - (node_index % 2)
-            };
+        cached.path.clone()
+    }
 
-            self.tree[parent_index] = self.hash(
-                &self.tree[even_node_index..even_node_index + 2]
-            );
+    // set_leaf, keeping the cached paths of the other leaves
+    pub fn set_cached_leaf(&mut self, cache: &mut PathCache<E::Fr>, leaf_index: usize, new_leaf: Vec::<E::Fr>) {
+        self.set_leaf(leaf_index, new_leaf);
+        cache.invalidate(leaf_index, self.arity);
+    }
 
-            level_shift /= 2;
-            level_start += level_nodes;
-            level_nodes /= 2;
+    // only the nodes the paths of these leaves need, the children of every
+    // node on them: the root, and the roots after updates of these leaves,
+    // are the ones of self, any other leaf reads as empty
+    pub fn with_paths<I: IntoIterator<Item = usize>>(&self, leaves: I) -> Self {
+        debug_assert!(!self.is_stale());
+
+        let mut nodes = HashMap::new();
+        let mut copy = |level: usize, offset: usize| {
+            if let Some(node) = self.nodes.get(&(level, offset)) {
+                nodes.insert((level, offset), *node);
+            }
+        };
+
+        copy(self.depth, 0);
+        for leaf_index in leaves {
+            assert!(leaf_index < self.num_leaves());
+
+            let mut offset = leaf_index;
+            for level in 0..self.depth {
+                let first_child = offset - offset % self.arity;
+                for child in first_child..first_child + self.arity {
+                    copy(level, child);
+                }
+                offset /= self.arity;
+            }
+        }
+
+        PoseidonMerkleTree {
+            params: self.params,
+            nodes,
+            empty: self.empty.clone(),
+            depth: self.depth,
+            arity: self.arity,
+            stale: HashSet::new(),
         }
     }
 
+    // a stale node implies a stale root
+    pub fn is_stale(&self) -> bool {
+        self.stale.contains(&(self.depth, 0))
+    }
+
     pub fn root(&self) -> E::Fr {
-        let root_index = self.tree.len() - 1;
-        self.tree[root_index].clone()
+        debug_assert!(!self.is_stale());
+        self.node(self.depth, 0)
     }
 }
 
-impl<'a, E> Clone for PoseidonMerkleTree<'a, E>
-    where E: PoseidonEngine<SBox = QuinticSBox<E>>
+impl<'a, E, H> Clone for PoseidonMerkleTree<'a, E, H>
+    where E: PoseidonEngine,
+          H: TreeHasher<E>,
 {
     fn clone(&self) -> Self {
         PoseidonMerkleTree {
             params: self.params,
-            tree: self.tree.clone(),
+            nodes: self.nodes.clone(),
+            empty: self.empty.clone(),
             depth: self.depth,
+            arity: self.arity,
+            stale: self.stale.clone(),
         }
     }
 }
 
-impl<'a, E> fmt::Debug for PoseidonMerkleTree<'a, E>
-    where E: PoseidonEngine<SBox = QuinticSBox<E>>
+impl<'a, E, H> fmt::Debug for PoseidonMerkleTree<'a, E, H>
+    where E: PoseidonEngine,
+          H: TreeHasher<E>,
+          H::Params: Sync,
 {
+    // the stored nodes by level, any other node is the empty one of its level
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut nodes: Vec<_> = self.nodes.iter().collect();
+        nodes.sort_by_key(|(key, _)| **key);
 
-        let mut level_end = self.num_leaves();
-        let mut level_size = self.num_leaves();
-
-        write!(f, "tree: [\n")?;
-        for (i, node) in self.tree.clone().into_iter().enumerate() {
-            if i == level_end {
-                write!(f, "\n")?;
-                level_size /= 2;
-                level_end += level_size;
+        writeln!(f, "tree: [")?;
+        for level in 0..=self.depth {
+            writeln!(f, "    level {}, empty {:?}:", level, self.empty[level])?;
+            for ((_, offset), node) in nodes.iter().filter(|((node_level, _), _)| *node_level == level) {
+                writeln!(f, "        {}: {:?},", offset, node)?;
             }
-            write!(f, "    {:?},\n", node)?;
         }
-        write!(f, "]\n")?;
-
-        Ok(())
+        writeln!(f, "]")
     }
 }
@@ -1,2 +1,8 @@
 pub mod account;
 pub mod merkle_tree;
+pub mod proof;
+pub mod snapshot;
+pub mod history;
+pub mod empty;
+pub mod leaf;
+pub mod genesis;
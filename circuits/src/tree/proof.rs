@@ -0,0 +1,67 @@
+use serde::{ Serialize, Deserialize };
+
+use sapling_crypto_ce::poseidon::{
+    bn256::Bn256PoseidonParams,
+    poseidon_hash,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use crate::utils::serde_fr;
+
+// inclusion of a leaf, checked without a circuit: the root is recomputed the
+// way calc_root does, a true index puts the node to the right of its neighbor
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    #[serde(with = "serde_fr::vec")]
+    pub leaf: Vec::<bn256::Fr>,
+    #[serde(with = "serde_fr::vec")]
+    pub path: Vec::<bn256::Fr>,
+    pub indices: Vec::<bool>,
+    #[serde(with = "serde_fr")]
+    pub root: bn256::Fr,
+}
+
+impl MerkleProof {
+    pub fn calc_root(&self, hash_params: &Bn256PoseidonParams) -> bn256::Fr {
+        let mut node = poseidon_hash::<Bn256>(hash_params, &self.leaf)[0];
+
+        for (neighbor, index) in self.path.iter().zip(self.indices.iter()) {
+            let children = if *index {
+                [*neighbor, node]
+            } else {
+                [node, *neighbor]
+            };
+            node = poseidon_hash::<Bn256>(hash_params, &children)[0];
+        }
+
+        node
+    }
+
+    pub fn verify(&self, hash_params: &Bn256PoseidonParams) -> bool {
+        self.path.len() == self.indices.len() && self.calc_root(hash_params) == self.root
+    }
+}
+
+// a token balance: its leaf in the balances tree, whose root is the last
+// element of the account leaf in the accounts tree
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceProof {
+    pub account: MerkleProof,
+    pub balance: MerkleProof,
+}
+
+impl BalanceProof {
+    pub fn balance(&self) -> Option<bn256::Fr> {
+        self.balance.leaf.first().cloned()
+    }
+
+    pub fn verify(&self, hash_params: &Bn256PoseidonParams) -> bool {
+        self.account.leaf.last() == Some(&self.balance.root)
+            && self.balance.verify(hash_params)
+            && self.account.verify(hash_params)
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{ BTreeMap, BTreeSet };
+
+use serde::{ Serialize, Deserialize };
+
+use sapling_crypto_ce::{
+    poseidon::bn256::Bn256PoseidonParams,
+    alt_babyjubjub::AltJubjubBn256,
+    jubjub::edwards::Point,
+};
+
+use pairing_ce::bn256::{ self, Bn256 };
+
+use ff_ce::Field;
+
+use crate::utils::serde_fr;
+use crate::utils::point::pack_point;
+use crate::params::{ poseidon_params, jubjub_params };
+use crate::l1::ProcessedOps;
+use crate::error::OpenPlasmaError;
+
+use super::history::RootHistory;
+use super::merkle_tree::PoseidonMerkleTree;
+use super::empty::{ empty_pubkey, empty_balances_root };
+use super::account::{ AccountsTree, TreeError, MAX_TREE_DEPTH };
+use super::leaf::{ LeafAccount, LeafVersion, LeafCodec };
+
+// account leaf fields, enough to hash the leaf without the operator. the
+// pubkey is packed as in the leaf, see utils::point
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountSnapshot {
+    pub account_id: usize,
+    #[serde(with = "serde_fr")]
+    pub pubkey: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    pub nonce: bn256::Fr,
+    #[serde(with = "serde_fr::vec")]
+    pub balances: Vec::<bn256::Fr>,
+}
+
+// the whole state: every account not listed is empty, accounts go in
+// ascending account id order. history and the accounts changed since the
+// last finalized block are optional, they only serve historical queries
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    #[serde(with = "serde_fr")]
+    pub root: bn256::Fr,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub accounts: Vec::<AccountSnapshot>,
+    #[serde(default)]
+    pub history: RootHistory,
+    #[serde(default)]
+    pub unfinalized_accounts: Vec::<AccountSnapshot>,
+    // serial ids of the priority ops already applied to the accounts
+    #[serde(default)]
+    pub processed_ops: ProcessedOps,
+    // the layout root is of, snapshots from before versioning are V0
+    #[serde(default)]
+    pub leaf_version: LeafVersion,
+}
+
+// the accounts that differ between two snapshots of a tree, a replica at
+// old_root gets to new_root without downloading the whole state
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDiff {
+    #[serde(with = "serde_fr")]
+    pub old_root: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    pub new_root: bn256::Fr,
+    // new and changed accounts as they are at new_root, ascending account ids
+    pub accounts: Vec::<AccountSnapshot>,
+    // accounts that became empty
+    pub removed: Vec::<usize>,
+    // serial ids of the priority ops applied since old_root, and of the ones
+    // a rollback unmarked, so a replica taking over resumes the queue there
+    #[serde(default)]
+    pub processed_ops: Vec::<u64>,
+    #[serde(default)]
+    pub unprocessed_ops: Vec::<u64>,
+}
+
+impl StateDiff {
+    // bincode, field elements take their 32 bytes, see serde_fr
+    pub fn to_bytes(&self) -> Vec::<u8> {
+        bincode::serialize(self).expect("a state diff serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, OpenPlasmaError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+impl StateSnapshot {
+    // both snapshots are of a tree of the same depths, history is not diffed
+    pub fn diff(old: &StateSnapshot, new: &StateSnapshot) -> StateDiff {
+        let old_accounts: BTreeMap<_, _> = old.accounts.iter().map(
+            |account| (account.account_id, account)
+        ).collect();
+        let new_ids: BTreeSet<_> = new.accounts.iter().map(|account| account.account_id).collect();
+
+        StateDiff {
+            old_root: old.root,
+            new_root: new.root,
+            accounts: new.accounts.iter().filter(
+                |account| old_accounts.get(&account.account_id) != Some(account)
+            ).cloned().collect(),
+            removed: old_accounts.keys().filter(
+                |account_id| !new_ids.contains(account_id)
+            ).cloned().collect(),
+            processed_ops: new.processed_ops.iter().filter(
+                |serial_id| !old.processed_ops.contains(*serial_id)
+            ).collect(),
+            unprocessed_ops: old.processed_ops.iter().filter(
+                |serial_id| !new.processed_ops.contains(*serial_id)
+            ).collect(),
+        }
+    }
+
+    // the accounts are rehashed and have to end at the diff's new root, the
+    // snapshot is left untouched unless they do. the processed ops follow the
+    // diff, an op processed twice is refused. history and unfinalized
+    // accounts are the replica's own and are kept
+    pub fn apply_diff(&mut self, diff: &StateDiff) -> Result<(), OpenPlasmaError> {
+        if diff.old_root != self.root {
+            return Err(TreeError::BaseRootMismatch { expected: diff.old_root, actual: self.root }.into());
+        }
+
+        let mut accounts: BTreeMap<_, _> = self.accounts.iter().map(
+            |account| (account.account_id, account.clone())
+        ).collect();
+        for account_id in diff.removed.iter() {
+            accounts.remove(account_id);
+        }
+        for account in diff.accounts.iter() {
+            accounts.insert(account.account_id, account.clone());
+        }
+
+        let mut processed_ops = self.processed_ops.clone();
+        for serial_id in diff.unprocessed_ops.iter() {
+            processed_ops.remove(*serial_id);
+        }
+        for serial_id in diff.processed_ops.iter() {
+            if !processed_ops.insert(*serial_id) {
+                return Err(TreeError::AlreadyProcessed(*serial_id).into());
+            }
+        }
+
+        let applied = StateSnapshot {
+            root: diff.new_root,
+            accounts: accounts.into_values().collect(),
+            processed_ops,
+            ..self.clone()
+        };
+        AccountsTree::from_snapshot(&applied, poseidon_params(), jubjub_params())?;
+
+        *self = applied;
+        Ok(())
+    }
+}
+
+// snapshots of the [pubkey x, pubkey y, nonce, balances root] leaf, only read
+// to migrate them. history is not kept, its roots are of the old leaf
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegacyAccountSnapshot {
+    pub account_id: usize,
+    #[serde(with = "serde_fr")]
+    pub pubkey_x: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    pub pubkey_y: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    pub nonce: bn256::Fr,
+    #[serde(with = "serde_fr::vec")]
+    pub balances: Vec::<bn256::Fr>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegacyStateSnapshot {
+    #[serde(with = "serde_fr")]
+    pub root: bn256::Fr,
+    pub account_depth: usize,
+    pub token_depth: usize,
+    pub accounts: Vec::<LegacyAccountSnapshot>,
+}
+
+// the same accounts with packed pubkeys under the root of the new leaves, the
+// legacy root is checked first. the result has no history
+pub fn migrate_snapshot(
+    legacy: &LegacyStateSnapshot,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> Result<StateSnapshot, OpenPlasmaError> {
+    if legacy.account_depth > MAX_TREE_DEPTH || legacy.token_depth > MAX_TREE_DEPTH {
+        return Err(TreeError::InvalidSnapshot("tree depth is too large").into());
+    }
+
+    let balances_root = empty_balances_root::<Bn256>(legacy.token_depth, hash_params);
+    let empty_pubkey = empty_pubkey::<Bn256>(sign_params);
+    let (empty_x, empty_y) = empty_pubkey.into_xy();
+    let mut legacy_leaves = vec![
+        vec![empty_x, empty_y, bn256::Fr::zero(), balances_root];
+        1 << legacy.account_depth
+    ];
+    let mut leaves = vec![
+        vec![pack_point(&empty_pubkey), bn256::Fr::zero(), balances_root];
+        1 << legacy.account_depth
+    ];
+
+    let mut accounts = Vec::with_capacity(legacy.accounts.len());
+    let mut next_account_id = 0;
+    for entry in legacy.accounts.iter() {
+        if entry.account_id < next_account_id || entry.account_id >= leaves.len() {
+            return Err(TreeError::InvalidSnapshot("account ids are not ascending or out of the tree").into());
+        }
+        next_account_id = entry.account_id + 1;
+
+        if entry.balances.len() != 1 << legacy.token_depth {
+            return Err(TreeError::InvalidSnapshot("balances do not match the token depth").into());
+        }
+
+        let pubkey = Point::<Bn256, _>::from_xy(entry.pubkey_x, entry.pubkey_y, sign_params).ok_or(
+            TreeError::InvalidSnapshot("pubkey is not a curve point")
+        )?;
+        let pubkey = pack_point(&pubkey);
+
+        let balances_tree = PoseidonMerkleTree::<Bn256>::new(
+            entry.balances.iter().map(|balance| vec![*balance]).collect(),
+            hash_params,
+        );
+        let balances_root = balances_tree.root();
+
+        legacy_leaves[entry.account_id] = vec![entry.pubkey_x, entry.pubkey_y, entry.nonce, balances_root];
+        leaves[entry.account_id] = vec![pubkey, entry.nonce, balances_root];
+
+        accounts.push(AccountSnapshot {
+            account_id: entry.account_id,
+            pubkey,
+            nonce: entry.nonce,
+            balances: entry.balances.clone(),
+        });
+    }
+
+    if PoseidonMerkleTree::<Bn256>::new(legacy_leaves, hash_params).root() != legacy.root {
+        return Err(TreeError::InvalidSnapshot("legacy root mismatch").into());
+    }
+
+    Ok(StateSnapshot {
+        root: PoseidonMerkleTree::<Bn256>::new(leaves, hash_params).root(),
+        account_depth: legacy.account_depth,
+        token_depth: legacy.token_depth,
+        accounts,
+        history: RootHistory::new(),
+        unfinalized_accounts: Vec::new(),
+        processed_ops: ProcessedOps::new(),
+        leaf_version: LeafVersion::V0,
+    })
+}
+
+// the fields of every account leaf a migration rehashed, enough to check
+// that the new root holds the same accounts as the old one without trusting
+// whoever migrated: both trees are rebuilt from the same fields, an account
+// not listed has the fields of empty
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationTranscript {
+    pub from: LeafVersion,
+    pub to: LeafVersion,
+    pub account_depth: usize,
+    #[serde(with = "serde_fr")]
+    pub old_root: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    pub new_root: bn256::Fr,
+    pub empty: LeafAccount,
+    // ascending account ids
+    pub accounts: Vec::<(usize, LeafAccount)>,
+}
+
+impl MigrationTranscript {
+    fn root(&self, version: LeafVersion, hash_params: &Bn256PoseidonParams) -> Result<bn256::Fr, OpenPlasmaError> {
+        let mut tree = PoseidonMerkleTree::<Bn256>::new_empty(self.account_depth, &version.encode(&self.empty), hash_params);
+        let mut leaves = Vec::new();
+        let mut next_account_id = 0;
+        for (account_id, account) in self.accounts.iter() {
+            if *account_id < next_account_id || *account_id >= tree.num_leaves() {
+                return Err(TreeError::InvalidSnapshot("account ids are not ascending or out of the tree").into());
+            }
+            next_account_id = account_id + 1;
+            leaves.push((*account_id, version.encode(account)));
+        }
+
+        tree.set_leaves(leaves);
+        tree.refresh();
+        Ok(tree.root())
+    }
+
+    // the listed fields hash to old_root in the old layout and to new_root
+    // in the new one
+    pub fn verify(&self, hash_params: &Bn256PoseidonParams) -> Result<(), OpenPlasmaError> {
+        if self.account_depth > MAX_TREE_DEPTH {
+            return Err(TreeError::InvalidSnapshot("tree depth is too large").into());
+        }
+        if self.from.next() != Some(self.to) {
+            return Err(TreeError::InvalidSnapshot("migration does not go to the next leaf version").into());
+        }
+        if self.root(self.from, hash_params)? != self.old_root {
+            return Err(TreeError::InvalidSnapshot("root mismatch").into());
+        }
+        if self.root(self.to, hash_params)? != self.new_root {
+            return Err(TreeError::InvalidSnapshot("migrated root mismatch").into());
+        }
+
+        Ok(())
+    }
+}
+
+// the same accounts with leaves of the next version under a new root, the
+// snapshot root is checked first. history and unfinalized accounts are left
+// out, their roots are of the old leaves; processed ops are kept
+pub fn migrate_leaves(
+    snapshot: &StateSnapshot,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> Result<(StateSnapshot, MigrationTranscript), OpenPlasmaError> {
+    let to = snapshot.leaf_version.next().ok_or(
+        TreeError::InvalidSnapshot("leaves are of the latest version")
+    )?;
+
+    let tree = AccountsTree::from_snapshot(snapshot, hash_params, sign_params)?.with_leaf_version(to);
+    let accounts = tree.iter_accounts().map(
+        |(account_id, _, _, _)| (account_id, tree.accounts[account_id].leaf())
+    ).collect();
+
+    let migrated = StateSnapshot {
+        history: RootHistory::new(),
+        unfinalized_accounts: Vec::new(),
+        ..tree.export_snapshot()
+    };
+
+    let transcript = MigrationTranscript {
+        from: snapshot.leaf_version,
+        to,
+        account_depth: snapshot.account_depth,
+        old_root: snapshot.root,
+        new_root: migrated.root,
+        empty: LeafAccount {
+            pubkey: pack_point(&empty_pubkey::<Bn256>(sign_params)),
+            nonce: bn256::Fr::zero(),
+            balances_root: empty_balances_root::<Bn256>(snapshot.token_depth, hash_params),
+        },
+        accounts,
+    };
+
+    Ok((migrated, transcript))
+}
@@ -0,0 +1,125 @@
+use std::{
+    fmt,
+    convert::TryFrom,
+    error::Error,
+};
+
+use serde::{
+    Serialize,
+    Deserialize,
+    Deserializer,
+    Serializer,
+    de::Error as DeError,
+};
+
+use pairing_ce::bn256;
+
+use crate::utils::utils::{ u128_to_fr, fr_to_u128_checked };
+
+// a field element that doesn't fit the type it was read as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeError(pub &'static str);
+
+impl Error for RangeError {}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Field element is out of the range of {}", self.0)
+    }
+}
+
+// fixed width values of the tree, the same on 32 and 64 bit platforms,
+// unlike usize
+macro_rules! fr_value {
+    ($name:ident, $inner:ty) => {
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub $inner);
+
+        impl $name {
+            pub fn to_fr(self) -> bn256::Fr {
+                u128_to_fr(u128::from(self.0))
+            }
+
+            pub fn try_from_fr(fr: &bn256::Fr) -> Result<Self, RangeError> {
+                fr_to_u128_checked(fr).ok()
+                    .and_then(|value| <$inner>::try_from(value).ok())
+                    .map($name)
+                    .ok_or(RangeError(stringify!($name)))
+            }
+
+            pub fn checked_add(self, other: Self) -> Option<Self> {
+                self.0.checked_add(other.0).map($name)
+            }
+
+            pub fn checked_sub(self, other: Self) -> Option<Self> {
+                self.0.checked_sub(other.0).map($name)
+            }
+
+            pub fn is_zero(self) -> bool {
+                self.0 == 0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+                write!(f, "{}", self.0)
+            }
+        }
+
+        // decimal strings, json numbers lose precision above 2^53
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(&self.0)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let decimal = String::deserialize(deserializer)?;
+                decimal.parse::<$inner>().map($name).map_err(
+                    |_| D::Error::custom(format!("{} is not a valid {}", decimal, stringify!($name)))
+                )
+            }
+        }
+    };
+}
+
+fr_value!(Balance, u128);
+
+// the range circuits check amounts and balances to, a sum of two stays far
+// below the modulus
+pub const BALANCE_BITS: usize = 128;
+
+// the smallest amount a deposit or withdrawal request is accepted with by
+// default, zero amounts only bloat blocks and cost signature checks. noops
+// are not requests and keep their zero amount
+pub const MIN_AMOUNT: Balance = Balance(1);
+
+// kept for code that still holds amounts as usize, deprecated: build
+// Balance(u128) directly, usize caps amounts at the pointer width
+impl From<usize> for Balance {
+    fn from(amount: usize) -> Self {
+        Balance(amount as u128)
+    }
+}
+
+fr_value!(Nonce, u32);
+fr_value!(AccountId, u32);
+
+// the range circuits check signed nonces to, so the one after u32::MAX is
+// rejected like TreeError::NonceOverflow instead of wrapping
+pub const NONCE_BITS: usize = 32;
+
+impl Nonce {
+    // the nonce a request has to sign after this one
+    pub fn next(self) -> Option<Self> {
+        self.checked_add(Nonce(1))
+    }
+}
+
+impl AccountId {
+    // leaf index in the accounts tree
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
@@ -6,12 +6,12 @@ use bellman_ce::{
 use sapling_crypto_ce::{
     jubjub::JubjubEngine,
     circuit::{
-        num::AllocatedNum,
-        boolean::Boolean,
+        num::{ AllocatedNum, Num },
+        boolean::{ Boolean, AllocatedBit },
     },  
 };
 
-use ff_ce::Field;
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
 
 pub fn add<E, CS> (
     mut cs: CS,
@@ -131,51 +131,378 @@ pub fn boolean_to_allocated_num<E, CS> (
 ) -> Result<AllocatedNum<E>, SynthesisError>
     where E: JubjubEngine,
           CS: ConstraintSystem<E>,
-{   let zero = AllocatedNum::alloc(
-        cs.namespace(|| "allocate zero"),
-        || Ok(E::Fr::zero()),
+{
+    // num = cond, one constraint instead of selecting between allocated 1 and 0
+    let num = AllocatedNum::alloc(
+        cs.namespace(|| "allocate num"),
+        || {
+            let cond = cond.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+            Ok(if cond { E::Fr::one() } else { E::Fr::zero() })
+        },
     )?;
 
-    let one = AllocatedNum::alloc(
-        cs.namespace(|| "allocate one"),
-        || Ok(E::Fr::one()),
+    cs.enforce(
+        || "enforce num",
+        |_| cond.lc(CS::one(), E::Fr::one()),
+        |lc| lc + CS::one(),
+        |lc| lc + num.get_variable(),
+    );
+
+    Ok(num)
+}
+
+// out = 1 - num * inv, num * out = 0, so out is boolean and set iff num is zero
+pub fn is_zero<E, CS> (
+    mut cs: CS,
+    num: &AllocatedNum<E>,
+) -> Result<Boolean, SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let value = num.get_value();
+
+    let inv = AllocatedNum::alloc(
+        cs.namespace(|| "allocate inverse"),
+        || {
+            let value = value.ok_or(SynthesisError::AssignmentMissing)?;
+            Ok(value.inverse().unwrap_or_else(E::Fr::zero))
+        },
     )?;
 
-    zero.assert_zero(cs.namespace(|| "check zero"))?;
+    let out = AllocatedBit::alloc(
+        cs.namespace(|| "allocate is zero"),
+        value.map(|value| value.is_zero()),
+    )?;
+
+    cs.enforce(
+        || "enforce is zero",
+        |lc| lc + num.get_variable(),
+        |lc| lc + inv.get_variable(),
+        |lc| lc + CS::one() - out.get_variable(),
+    );
+
+    cs.enforce(
+        || "enforce num is zero if out is set",
+        |lc| lc + num.get_variable(),
+        |lc| lc + out.get_variable(),
+        |lc| lc,
+    );
+
+    Ok(Boolean::from(out))
+}
+
+// num * inv = 1 - skip, so num has an inverse and is not zero unless skip is
+// set; a skipped num may be anything, inv is zero then
+pub fn enforce_nonzero_unless<E, CS> (
+    mut cs: CS,
+    num: &AllocatedNum<E>,
+    skip: &Boolean,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let inv = AllocatedNum::alloc(
+        cs.namespace(|| "allocate inverse"),
+        || {
+            let value = num.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+            let skip = skip.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+            Ok(if skip { E::Fr::zero() } else { value.inverse().unwrap_or_else(E::Fr::zero) })
+        },
+    )?;
+
+    cs.enforce(
+        || "enforce nonzero",
+        |lc| lc + num.get_variable(),
+        |lc| lc + inv.get_variable(),
+        |_| skip.not().lc(CS::one(), E::Fr::one()),
+    );
+
+    Ok(())
+}
+
+// num = sum(bits[i] * 2^i) exactly, so num < 2^bits.len() and there is
+// no other number with the same low bits, e.g. an account id aliasing a leaf
+pub fn check_decomposition_le<E, CS> (
+    mut cs: CS,
+    num: &AllocatedNum<E>,
+    bits: &[Boolean],
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    // the packed sum must not wrap around the modulus
+    if bits.len() >= E::Fr::CAPACITY as usize {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    let mut coeff = E::Fr::one();
+    let mut packed = Num::<E>::zero();
+
+    for (i, bit) in bits.iter().enumerate() {
+        cs.enforce(
+            || format!("check bit {} booleanity", i),
+            |_| bit.lc(CS::one(), E::Fr::one()),
+            |_| bit.not().lc(CS::one(), E::Fr::one()),
+            |lc| lc,
+        );
+
+        packed = packed.add_bool_with_coeff(CS::one(), bit, coeff);
+        coeff.double();
+    }
+
     cs.enforce(
-        || "check one",
+        || "check decomposition",
+        |_| packed.lc(E::Fr::one()),
         |lc| lc + CS::one(),
+        |lc| lc + num.get_variable(),
+    );
+
+    Ok(())
+}
+
+// num = sum(digits[i] * arity^i) with every digit given by its log2(arity)
+// little endian bits, for a power of two arity the digit bits concatenate
+// into the binary decomposition, e.g. the indices of a quad tree path
+pub fn check_digit_decomposition_le<E, CS> (
+    cs: CS,
+    num: &AllocatedNum<E>,
+    digit_bits: &[Boolean],
+    arity: usize,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    if arity < 2 || !arity.is_power_of_two()
+        || !digit_bits.len().is_multiple_of(arity.trailing_zeros() as usize)
+    {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    check_decomposition_le(cs, num, digit_bits)
+}
+
+// packs `bits` witnessed bits back into the number, so it is in [0, 2^bits),
+// costs bits + 1 constraints against the full decomposition of limit_number_of_bits
+pub fn enforce_bit_length<E, CS> (
+    cs: CS,
+    num: &AllocatedNum<E>,
+    bits: usize,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    alloc_bits_le(cs, num, bits).map(|_| ())
+}
+
+// the little endian bits of enforce_bit_length, for encoding the number
+pub fn alloc_bits_le<E, CS> (
+    mut cs: CS,
+    num: &AllocatedNum<E>,
+    bits: usize,
+) -> Result<Vec::<Boolean>, SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    // the packed sum must not wrap around the modulus
+    if bits >= E::Fr::CAPACITY as usize {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    let repr = num.get_value().map(|value| value.into_repr());
+
+    let mut packed = Vec::with_capacity(bits);
+    for i in 0..bits {
+        let bit_value = repr.as_ref().map(|repr| {
+            let mut tmp = *repr;
+            tmp.shr(i as u32);
+            tmp.is_odd()
+        });
+
+        let bit = AllocatedBit::alloc(
+            cs.namespace(|| format!("allocate bit {}", i)),
+            bit_value,
+        )?;
+
+        packed.push(bit);
+    }
+
+    cs.enforce(
+        || "enforce packing",
+        |lc| {
+            let mut lc = lc;
+            let mut coeff = E::Fr::one();
+            for bit in packed.iter() {
+                lc = lc + (coeff, bit.get_variable());
+                coeff.double();
+            }
+            lc
+        },
         |lc| lc + CS::one(),
-        |lc| lc + one.get_variable(),
+        |lc| lc + num.get_variable(),
     );
 
-    AllocatedNum::conditionally_select(
-        cs.namespace(|| "select zero or one"),
-        &one,
-        &zero,
-        cond,
+    Ok(packed.into_iter().map(Boolean::from).collect())
+}
+
+// a <= b for a and b already known to be in [0, 2^bits), nothing here range
+// checks them: then b - a is in [0, 2^bits) only if it doesn't wrap around
+// the modulus
+pub fn enforce_less_or_equal<E, CS> (
+    mut cs: CS,
+    a: &AllocatedNum<E>,
+    b: &AllocatedNum<E>,
+    bits: usize,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let diff = sub(
+        cs.namespace(|| "calculate difference"),
+        b,
+        a,
+    )?;
+
+    enforce_bit_length(
+        cs.namespace(|| "check difference is not negative"),
+        &diff,
+        bits,
     )
 }
 
-pub fn check_decomposition_le<E, CS> (
+// num < constant for a constant in [1, 2^bits]: num and constant - 1 - num
+// both in [0, 2^bits) leave no room to wrap around the modulus
+pub fn enforce_less_than_constant<E, CS> (
     mut cs: CS,
     num: &AllocatedNum<E>,
-    bits: &Vec::<Boolean>,
+    constant: E::Fr,
+    bits: usize,
 ) -> Result<(), SynthesisError>
     where E: JubjubEngine,
           CS: ConstraintSystem<E>,
 {
-    let decomp = num.into_bits_le(
-        cs.namespace(|| "decompose number")
+    enforce_bit_length(
+        cs.namespace(|| "check number bit length"),
+        num,
+        bits,
+    )?;
+
+    let mut max = constant;
+    max.sub_assign(&E::Fr::one());
+
+    let diff = AllocatedNum::alloc(
+        cs.namespace(|| "allocate difference to the constant"),
+        || {
+            let mut value = max;
+            value.sub_assign(&num.get_value().ok_or(SynthesisError::AssignmentMissing)?);
+            Ok(value)
+        },
     )?;
 
-    for i in 0..bits.len() {
-        Boolean::enforce_equal(
-            cs.namespace(|| format!("check bit {}", i)),
-            &decomp[i],
-            &bits[i],
+    cs.enforce(
+        || "enforce difference to the constant",
+        |lc| lc + (max, CS::one()) - num.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + diff.get_variable(),
+    );
+
+    enforce_bit_length(
+        cs.namespace(|| "check number is below the constant"),
+        &diff,
+        bits,
+    )
+}
+
+// a <= b as a boolean for conditional logic, under the same contract on a and
+// b: b - a + 2^bits is in [1, 2^(bits + 1)) and its bit `bits` is set iff a <= b
+pub fn is_less_or_equal<E, CS> (
+    mut cs: CS,
+    a: &AllocatedNum<E>,
+    b: &AllocatedNum<E>,
+    bits: usize,
+) -> Result<Boolean, SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    // the shifted difference has bits + 1 bits
+    if bits + 1 >= E::Fr::CAPACITY as usize {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    let mut shift = E::Fr::one();
+    for _ in 0..bits {
+        shift.double();
+    }
+
+    let shifted = AllocatedNum::alloc(
+        cs.namespace(|| "allocate shifted difference"),
+        || {
+            let mut value = b.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+            value.sub_assign(&a.get_value().ok_or(SynthesisError::AssignmentMissing)?);
+            value.add_assign(&shift);
+            Ok(value)
+        },
+    )?;
+
+    cs.enforce(
+        || "enforce shifted difference",
+        |lc| lc + b.get_variable() - a.get_variable() + (shift, CS::one()),
+        |lc| lc + CS::one(),
+        |lc| lc + shifted.get_variable(),
+    );
+
+    let shifted_bits = alloc_bits_le(
+        cs.namespace(|| "shifted difference into bits"),
+        &shifted,
+        bits + 1,
+    )?;
+
+    Ok(shifted_bits[bits].clone())
+}
+
+// every num equals its constant, e.g. a leaf is the empty leaf. the constants
+// are in the constraints, not allocated
+pub fn is_equal_to_constants<E, CS> (
+    mut cs: CS,
+    nums: &[AllocatedNum<E>],
+    constants: &[E::Fr],
+) -> Result<Boolean, SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    if nums.len() != constants.len() {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    let mut is_equal = Boolean::constant(true);
+
+    for (i, (num, constant)) in nums.iter().zip(constants.iter()).enumerate() {
+        let diff = AllocatedNum::alloc(
+            cs.namespace(|| format!("allocate difference {}", i)),
+            || {
+                let mut value = num.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+                value.sub_assign(constant);
+                Ok(value)
+            },
+        )?;
+
+        cs.enforce(
+            || format!("enforce difference {}", i),
+            |lc| lc + num.get_variable() - (*constant, CS::one()),
+            |lc| lc + CS::one(),
+            |lc| lc + diff.get_variable(),
+        );
+
+        let is_zero_diff = is_zero(
+            cs.namespace(|| format!("is difference {} zero", i)),
+            &diff,
+        )?;
+
+        is_equal = Boolean::and(
+            cs.namespace(|| format!("and equal {}", i)),
+            &is_equal,
+            &is_zero_diff,
         )?;
     }
 
-    Ok(())
+    Ok(is_equal)
 }
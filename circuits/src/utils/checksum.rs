@@ -0,0 +1,83 @@
+use std::io::{ self, Read, Write };
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// fnv-1a, stable across builds and platforms unlike the std hashers
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv64(u64);
+
+impl Fnv64 {
+    pub fn new() -> Self {
+        Fnv64(FNV_OFFSET_BASIS)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// checksum of everything written through
+pub struct ChecksumWriter<W: Write> {
+    inner: W,
+    checksum: Fnv64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChecksumWriter { inner, checksum: Fnv64::new() }
+    }
+
+    pub fn checksum(&self) -> u64 {
+        self.checksum.finish()
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// checksum of everything read through
+pub struct ChecksumReader<R: Read> {
+    inner: R,
+    checksum: Fnv64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChecksumReader { inner, checksum: Fnv64::new() }
+    }
+
+    pub fn checksum(&self) -> u64 {
+        self.checksum.finish()
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.checksum.update(&buf[..read]);
+        Ok(read)
+    }
+}
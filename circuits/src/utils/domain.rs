@@ -0,0 +1,94 @@
+use std::convert::TryInto;
+
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::JubjubEngine,
+    circuit::num::AllocatedNum,
+};
+
+use ff_ce::{ PrimeField, PrimeFieldRepr };
+
+use crate::utils::utils::ConversionError;
+use crate::error::OpenPlasmaError;
+
+pub const ADDRESS_BYTES: usize = 20;
+
+// the deployment a request is signed for, absorbed into the request hash so a
+// signature made for one chain or rollup contract never verifies on another
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SigningDomain {
+    pub chain_id: u64,
+    pub rollup_address: [u8; ADDRESS_BYTES],
+}
+
+impl SigningDomain {
+    // the address as the contract sees it, 20 big endian bytes
+    pub fn from_address(chain_id: u64, address: &[u8]) -> Result<Self, OpenPlasmaError> {
+        let rollup_address = address.try_into().map_err(
+            |_| ConversionError::WrongLength { expected: ADDRESS_BYTES, actual: address.len() }
+        )?;
+        Ok(SigningDomain { chain_id, rollup_address })
+    }
+
+    pub fn chain_id_fr<F: PrimeField>(&self) -> F {
+        F::from_repr(F::Repr::from(self.chain_id)).expect("u64 is below the field modulus")
+    }
+
+    pub fn rollup_address_fr<F: PrimeField>(&self) -> F {
+        address_to_fr(&self.rollup_address)
+    }
+
+    pub fn to_fr<F: PrimeField>(&self) -> [F; 2] {
+        [self.chain_id_fr(), self.rollup_address_fr()]
+    }
+}
+
+pub const ADDRESS_BITS: usize = ADDRESS_BYTES * 8;
+
+// an L1 address in one field element: the 20 bytes read as a big endian
+// integer, as the contract gets it from uint160(address). 160 bits always fit
+// the field, circuits range check it to ADDRESS_BITS
+pub fn address_to_fr<F: PrimeField>(address: &[u8; ADDRESS_BYTES]) -> F {
+    let mut repr = F::Repr::default();
+    let mut bytes = [0u8; 32];
+    bytes[32 - ADDRESS_BYTES..].copy_from_slice(address);
+    repr.read_be(&bytes[32 - repr.as_ref().len() * 8..]).expect("the repr is at least 20 bytes");
+    F::from_repr(repr).expect("160 bits are below the field modulus")
+}
+
+// the domain is a constant of the circuit like the op type, proving keys are
+// made for a single deployment
+pub fn alloc_signing_domain<E, CS>(
+    mut cs: CS,
+    domain: &SigningDomain,
+) -> Result<[AllocatedNum<E>; 2], SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let [chain_id, rollup_address] = domain.to_fr::<E::Fr>();
+
+    let mut alloc_constant = |name: &str, value: E::Fr| -> Result<AllocatedNum<E>, SynthesisError> {
+        let value_alloc = AllocatedNum::alloc(
+            cs.namespace(|| format!("allocate {}", name)),
+            || Ok(value),
+        )?;
+
+        cs.enforce(
+            || format!("enforce {}", name),
+            |lc| lc + value_alloc.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + (value, CS::one()),
+        );
+
+        Ok(value_alloc)
+    };
+
+    Ok([
+        alloc_constant("chain id", chain_id)?,
+        alloc_constant("rollup address", rollup_address)?,
+    ])
+}
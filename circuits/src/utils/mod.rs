@@ -1,6 +1,15 @@
 pub mod alloc;
 pub mod tree;
 pub mod sign;
+pub mod signature;
+pub mod point;
 pub mod calc;
+pub mod op_type;
+pub mod domain;
+pub mod checksum;
+pub mod serde_fr;
+pub mod serde_sign;
+pub mod serde_address;
+#[allow(clippy::module_inception)]
 pub mod utils;
 
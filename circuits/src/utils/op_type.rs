@@ -0,0 +1,55 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::JubjubEngine,
+    circuit::num::AllocatedNum,
+};
+
+use ff_ce::PrimeField;
+
+// operation type is absorbed first into every accumulator and request hash,
+// so a record of one operation never has the preimage of another one
+
+pub const DEPOSIT_OP: usize = 1;
+pub const ONCHAIN_WITHDRAWAL_OP: usize = 2;
+pub const OFFCHAIN_WITHDRAWAL_OP: usize = 3;
+pub const TRANSFER_OP: usize = 4;
+pub const CHANGE_PUBKEY_OP: usize = 5;
+pub const FULL_EXIT_OP: usize = 6;
+pub const BLOCK_OP: usize = 7;
+pub const CONDITIONAL_TRANSFER_OP: usize = 8;
+pub const SWAP_OP: usize = 9;
+pub const WITHDRAWAL_PERMIT_OP: usize = 10;
+pub const CLOSE_ACCOUNT_OP: usize = 11;
+pub const OFFCHAIN_TRANSFER_OP: usize = 12;
+pub const RECEIPT_OP: usize = 13;
+pub const REFUND_OP: usize = 14;
+
+pub fn alloc_op_type<E, CS>(
+    mut cs: CS,
+    op_type: usize,
+) -> Result<AllocatedNum<E>, SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let value = E::Fr::from_str(&op_type.to_string()).ok_or(SynthesisError::Unsatisfiable)?;
+
+    let op_type_alloc = AllocatedNum::alloc(
+        cs.namespace(|| "allocate op type"),
+        || Ok(value),
+    )?;
+
+    // op type is a constant, not a free witness
+
+    cs.enforce(
+        || "enforce op type",
+        |lc| lc + op_type_alloc.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + (value, CS::one()),
+    );
+
+    Ok(op_type_alloc)
+}
@@ -0,0 +1,109 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        JubjubEngine,
+        edwards::Point,
+        Unknown,
+    },
+    circuit::num::AllocatedNum,
+};
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+// a pubkey as one field element of the account leaf: y, negated when the
+// canonical x is odd. (x, y) and (-x, y) are both points, (x, -y) and (-x, -y)
+// are them plus the order 2 point (0, -1), so a packed value is the same for a
+// point P and P + (0, -1) unless x = 0, and for nothing else. in the prime
+// order subgroup, where every registered pubkey is, it is one to one
+pub fn pack_point<E: JubjubEngine>(point: &Point<E, Unknown>) -> E::Fr {
+    let (x, mut y) = point.into_xy();
+    if x.into_repr().is_odd() {
+        y.negate();
+    }
+    y
+}
+
+// the point of the prime order subgroup packed to the value, otherwise the
+// one with odd x, which is how empty_pubkey packed to zero comes back. None if
+// the value packs no point
+pub fn unpack_point<E: JubjubEngine>(
+    packed: E::Fr,
+    params: &<E as JubjubEngine>::Params,
+) -> Option<Point<E, Unknown>> {
+    let mut negated = packed;
+    negated.negate();
+
+    let even = Point::<E, Unknown>::get_for_y(packed, false, params);
+    let odd = Point::<E, Unknown>::get_for_y(negated, true, params);
+
+    match even {
+        Some(point) if point.as_prime_order(params).is_some() => Some(point),
+        _ => odd.or(even),
+    }
+}
+
+// pack_point of x and y in the circuit, x is decomposed strictly so the sign
+// is the parity of the canonical x
+pub fn pack_point_gadget<E, CS>(
+    mut cs: CS,
+    x: &AllocatedNum<E>,
+    y: &AllocatedNum<E>,
+) -> Result<AllocatedNum<E>, SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let x_bits = x.into_bits_le_strict(cs.namespace(|| "x into bits"))?;
+    let sign = &x_bits[0];
+
+    let packed = AllocatedNum::alloc(
+        cs.namespace(|| "allocate packed"),
+        || {
+            let mut value = y.get_value().ok_or(SynthesisError::AssignmentMissing)?;
+            if sign.get_value().ok_or(SynthesisError::AssignmentMissing)? {
+                value.negate();
+            }
+            Ok(value)
+        },
+    )?;
+
+    // packed = y (1 - 2 sign)
+    let mut minus_two = E::Fr::one();
+    minus_two.double();
+    minus_two.negate();
+
+    cs.enforce(
+        || "enforce packed",
+        |lc| lc + y.get_variable(),
+        |lc| lc + CS::one() + &sign.lc(CS::one(), minus_two),
+        |lc| lc + packed.get_variable(),
+    );
+
+    Ok(packed)
+}
+
+// the claimed x and y are the point packed to the leaf value. it is not an
+// on curve check, the callers have x and y of an EdwardsPoint
+pub fn unpack_point_gadget<E, CS>(
+    mut cs: CS,
+    packed: &AllocatedNum<E>,
+    x: &AllocatedNum<E>,
+    y: &AllocatedNum<E>,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let computed = pack_point_gadget(cs.namespace(|| "pack point"), x, y)?;
+
+    cs.enforce(
+        || "enforce packed point",
+        |lc| lc + computed.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + packed.get_variable(),
+    );
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use serde::{
+    Deserialize,
+    Deserializer,
+    Serializer,
+    de::Error,
+};
+
+use tiny_keccak::{ Hasher, Keccak };
+
+use crate::utils::domain::ADDRESS_BYTES;
+
+// L1 addresses as EIP-55 checksummed hex, for #[serde(with)]: a digit is
+// upper case when the nibble of keccak256 of the lower case hex at its
+// position is 8 or more
+pub fn to_checksum_hex(address: &[u8; ADDRESS_BYTES]) -> String {
+    let lower = hex::encode(address);
+
+    let mut hash = [0u8; 32];
+    let mut hasher = Keccak::v256();
+    hasher.update(lower.as_bytes());
+    hasher.finalize(&mut hash);
+
+    let digits: String = lower.chars().enumerate().map(|(i, digit)| {
+        let nibble = (hash[i / 2] >> (4 * (1 - i % 2))) & 0x0f;
+        if nibble >= 8 { digit.to_ascii_uppercase() } else { digit }
+    }).collect();
+
+    format!("0x{}", digits)
+}
+
+// all lower or all upper case hex carries no checksum and is taken as it is,
+// mixed case has to be the checksummed form
+pub fn from_hex(hex: &str) -> Result<[u8; ADDRESS_BYTES], String> {
+    let digits = hex.strip_prefix("0x").ok_or_else(|| format!("{} is not 0x prefixed", hex))?;
+    if digits.len() != 2 * ADDRESS_BYTES {
+        return Err(format!("{} has {} hex digits, expected {}", hex, digits.len(), 2 * ADDRESS_BYTES));
+    }
+
+    let mut address = [0u8; ADDRESS_BYTES];
+    address.copy_from_slice(&hex::decode(digits).map_err(|err| format!("{} is not hex: {}", hex, err))?);
+
+    let mixed_case = digits.chars().any(|c| c.is_ascii_lowercase())
+        && digits.chars().any(|c| c.is_ascii_uppercase());
+    if mixed_case && to_checksum_hex(&address) != hex {
+        return Err(format!("{} has a wrong checksum", hex));
+    }
+
+    Ok(address)
+}
+
+pub fn serialize<S: Serializer>(address: &[u8; ADDRESS_BYTES], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_checksum_hex(address))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; ADDRESS_BYTES], D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    from_hex(&hex).map_err(D::Error::custom)
+}
@@ -0,0 +1,58 @@
+use serde::{
+    Serialize,
+    Serializer,
+    Deserialize,
+    Deserializer,
+    de::Error,
+};
+
+use pairing_ce::bn256;
+
+use crate::utils::utils::{ fr_to_be_bytes, fr_from_be_bytes, FR_BYTES };
+
+// field elements as 0x prefixed big endian hex strings, for #[serde(with)]:
+// bn256::Fr has no serde impls of its own. binary formats like bincode get
+// the 32 big endian bytes instead
+pub fn serialize<S: Serializer>(fr: &bn256::Fr, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&format!("0x{}", fr.to_hex()))
+    } else {
+        fr_to_be_bytes(fr).serialize(serializer)
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bn256::Fr, D::Error> {
+    if deserializer.is_human_readable() {
+        let hex = String::deserialize(deserializer)?;
+        bn256::Fr::from_hex(&hex).map_err(D::Error::custom)
+    } else {
+        let bytes = <[u8; FR_BYTES]>::deserialize(deserializer)?;
+        fr_from_be_bytes(&bytes).map_err(D::Error::custom)
+    }
+}
+
+pub mod vec {
+    use super::*;
+
+    struct Element(bn256::Fr);
+
+    impl Serialize for Element {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(&self.0, serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Element {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::deserialize(deserializer).map(Element)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(frs: &[bn256::Fr], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(frs.iter().map(|fr| Element(*fr)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec::<bn256::Fr>, D::Error> {
+        Ok(Vec::<Element>::deserialize(deserializer)?.into_iter().map(|element| element.0).collect())
+    }
+}
@@ -0,0 +1,95 @@
+use serde::{
+    Serialize,
+    Deserialize,
+    Deserializer,
+    Serializer,
+    de::Error,
+};
+
+use sapling_crypto_ce::{
+    eddsa::Signature,
+    jubjub::edwards::Point,
+    alt_babyjubjub::fs::{ Fs, FsRepr },
+};
+
+use ff_ce::{ PrimeField, PrimeFieldRepr };
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use crate::utils::serde_fr;
+use crate::data_structs::offchain_withdrawal::is_canonical_point;
+use crate::params::jubjub_params;
+
+// signatures as { r_x, r_y, s }, 0x prefixed big endian hex, for #[serde(with)]:
+// a point off the curve or out of the prime order subgroup or a non canonical s
+// is a deserialization error, so verify_signature never sees them
+#[derive(Serialize, Deserialize)]
+struct SignatureFields {
+    #[serde(with = "serde_fr")]
+    r_x: bn256::Fr,
+    #[serde(with = "serde_fr")]
+    r_y: bn256::Fr,
+    s: String,
+}
+
+fn fs_from_hex(hex: &str) -> Result<Fs, String> {
+    let digits = hex.strip_prefix("0x").unwrap_or(hex);
+    if digits.len() != 64 {
+        return Err(format!("s has {} hex digits, expected 64", digits.len()));
+    }
+    let bytes = hex::decode(digits).map_err(|err| format!("s is not hex: {}", err))?;
+
+    let mut repr = FsRepr::default();
+    repr.read_be(&bytes[..]).map_err(|err| err.to_string())?;
+    Fs::from_repr(repr).map_err(|_| "s is not canonical".to_string())
+}
+
+pub fn serialize<S: Serializer>(sign: &Signature<Bn256>, serializer: S) -> Result<S::Ok, S::Error> {
+    let (r_x, r_y) = sign.r.into_xy();
+    let mut s = Vec::new();
+    sign.s.into_repr().write_be(&mut s).map_err(serde::ser::Error::custom)?;
+
+    SignatureFields {
+        r_x,
+        r_y,
+        s: format!("0x{}", hex::encode(s)),
+    }.serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Signature<Bn256>, D::Error> {
+    let fields = SignatureFields::deserialize(deserializer)?;
+
+    // the curve check needs the params, the shared ones are built on first use
+    let r = Point::from_xy(fields.r_x, fields.r_y, jubjub_params())
+        .ok_or_else(|| D::Error::custom("r is not a curve point"))?;
+    if !is_canonical_point(&r, jubjub_params()) {
+        return Err(D::Error::custom("r is not of prime order"));
+    }
+    let s = fs_from_hex(&fields.s).map_err(D::Error::custom)?;
+
+    Ok(Signature { r, s })
+}
+
+pub mod option {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Wrapper(
+        #[serde(with = "super")]
+        Signature<Bn256>,
+    );
+
+    pub fn serialize<S: Serializer>(sign: &Option<Signature<Bn256>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match sign {
+            Some(sign) => serializer.serialize_some(&Wrapper(sign.clone())),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Signature<Bn256>>, D::Error> {
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|wrapper| wrapper.0))
+    }
+}
@@ -1,13 +1,12 @@
-use super::utils::{
-    fs_to_fr,
+use super::{
+    utils::fs_to_fr,
+    signature::verify_eddsa,
 };
 
 use sapling_crypto_ce::{
     jubjub::{
         Unknown,
         JubjubEngine,
-        JubjubParams,
-        FixedGenerators,
         edwards::Point,
     },
    circuit::{
@@ -23,7 +22,7 @@ use bellman_ce::{
     SynthesisError,
 };
 
-const BITS_IN_BYTE: usize = 8;
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
 
 pub fn alloc_signature<E, CS>(
     mut cs: CS,
@@ -70,7 +69,6 @@ pub fn verify_signature<E, CS>(
     sign: Option::<Signature<E>>,
     pk: Option::<Point<E, Unknown>>,
     leaf_hash: &AllocatedNum<E>,
-    msg_bytes_len: usize,
     params: &E::Params,
 ) -> Result<EddsaSignature<E>, SynthesisError>
     where E: JubjubEngine,
@@ -83,27 +81,78 @@ pub fn verify_signature<E, CS>(
         params,
     )?;
 
-    let msg_alloc = leaf_hash.into_bits_le(
-        cs.namespace(|| "convert hash to bits")
-    )?;
-
-    let public_generator = params.generator(
-            FixedGenerators::SpendingKeyGenerator
-    ).clone();
-    let generator = EdwardsPoint::witness(
-        cs.namespace(|| "allocate public generator"),
-        Some(public_generator),
-        params
-    )?;
-
-    sign_alloc.verify_raw_message_signature(
+    verify_eddsa(
         cs.namespace(|| "verify signature"),
+        sign_alloc.pk.get_x(),
+        sign_alloc.pk.get_y(),
+        &sign_alloc.r,
+        &sign_alloc.s,
+        leaf_hash,
         params,
-        &msg_alloc[..(msg_bytes_len * BITS_IN_BYTE)],
-        generator,
-        msg_bytes_len,
     )?;
 
     Ok(sign_alloc)
 }
 
+// on curve by interpretation and [r]P = O for the prime subgroup order r, the scalar
+// is a constant, so double-and-add needs no selections
+pub fn check_pubkey<E, CS>(
+    mut cs: CS,
+    pk_x: &AllocatedNum<E>,
+    pk_y: &AllocatedNum<E>,
+    params: &E::Params,
+) -> Result<EdwardsPoint<E>, SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let pubkey = EdwardsPoint::interpret(
+        cs.namespace(|| "check pubkey on curve"),
+        pk_x,
+        pk_y,
+        params,
+    )?;
+
+    let order = E::Fs::char();
+    let num_bits = E::Fs::NUM_BITS as usize;
+
+    let mut base = pubkey.clone();
+    let mut result: Option<EdwardsPoint<E>> = None;
+
+    for i in 0..num_bits {
+        let mut tmp = order;
+        tmp.shr(i as u32);
+
+        if tmp.is_odd() {
+            result = Some(match result {
+                Some(result) => result.add(
+                    cs.namespace(|| format!("addition {}", i)),
+                    &base,
+                    params,
+                )?,
+                None => base.clone(),
+            });
+        }
+
+        if i + 1 < num_bits {
+            base = base.double(
+                cs.namespace(|| format!("doubling {}", i)),
+                params,
+            )?;
+        }
+    }
+
+    let result = result.ok_or(SynthesisError::Unsatisfiable)?;
+
+    // [r]P is the neutral element (0, 1)
+
+    result.get_x().assert_zero(cs.namespace(|| "check subgroup x"))?;
+
+    cs.enforce(
+        || "check subgroup y",
+        |lc| lc + result.get_y().get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + (E::Fr::one(), CS::one()),
+    );
+
+    Ok(pubkey)
+}
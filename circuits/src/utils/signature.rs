@@ -0,0 +1,114 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        JubjubEngine,
+        JubjubParams,
+        FixedGenerators,
+    },
+    circuit::{
+        num::AllocatedNum,
+        baby_eddsa::EddsaSignature,
+        ecc::EdwardsPoint,
+    },
+};
+
+use ff_ce::{ Field, PrimeField, BitIterator };
+
+use super::calc::enforce_less_than_constant;
+
+const BITS_IN_BYTE: usize = 8;
+
+// the same as for off-circuit signing: message is a field element truncated
+// to the first 31 bytes in little endian, see fr_to_sign_message
+pub const NUM_BYTES_TO_SIGN: usize = 31;
+
+pub fn verify_eddsa<E, CS>(
+    mut cs: CS,
+    pubkey_x: &AllocatedNum<E>,
+    pubkey_y: &AllocatedNum<E>,
+    r: &EdwardsPoint<E>,
+    s: &AllocatedNum<E>,
+    msg_hash: &AllocatedNum<E>,
+    params: &E::Params,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    let pubkey = EdwardsPoint::interpret(
+        cs.namespace(|| "interpret public key"),
+        pubkey_x,
+        pubkey_y,
+        params,
+    )?;
+
+    // strict decomposition, so msg bits are the canonical representation
+    // the same as fr_to_sign_message(hash) produces
+    let msg_bits = msg_hash.into_bits_le_strict(
+        cs.namespace(|| "convert message hash to bits")
+    )?;
+
+    let public_generator = params.generator(
+        FixedGenerators::SpendingKeyGenerator
+    ).clone();
+    let (generator_x, generator_y) = public_generator.into_xy();
+    let generator = EdwardsPoint::witness(
+        cs.namespace(|| "allocate public generator"),
+        Some(public_generator),
+        params,
+    )?;
+
+    // generator is a constant, not a free witness
+
+    cs.enforce(
+        || "enforce generator x",
+        |lc| lc + generator.get_x().get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + (generator_x, CS::one()),
+    );
+
+    cs.enforce(
+        || "enforce generator y",
+        |lc| lc + generator.get_y().get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + (generator_y, CS::one()),
+    );
+
+    // the gadget takes s as a plain number and only uses its bits, so s + l
+    // would verify as well: a second signature for the same request
+    enforce_less_than_constant(
+        cs.namespace(|| "check s is below the subgroup order"),
+        s,
+        subgroup_order::<E>(),
+        E::Fs::NUM_BITS as usize,
+    )?;
+
+    let sign = EddsaSignature {
+        r: r.clone(),
+        s: s.clone(),
+        pk: pubkey,
+    };
+
+    sign.verify_raw_message_signature(
+        cs.namespace(|| "verify raw message signature"),
+        params,
+        &msg_bits[..(NUM_BYTES_TO_SIGN * BITS_IN_BYTE)],
+        generator,
+        NUM_BYTES_TO_SIGN,
+    )
+}
+
+// l, the order of the prime order subgroup, as an Fr
+fn subgroup_order<E: JubjubEngine>() -> E::Fr {
+    let mut order = E::Fr::zero();
+    for bit in BitIterator::new(E::Fs::char()) {
+        order.double();
+        if bit {
+            order.add_assign(&E::Fr::one());
+        }
+    }
+    order
+}
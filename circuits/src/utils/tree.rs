@@ -1,3 +1,5 @@
+use std::io;
+
 use bellman_ce::{
     ConstraintSystem,
     SynthesisError,
@@ -12,122 +14,41 @@ use sapling_crypto_ce::{
     circuit::{
         num::AllocatedNum,
         boolean::Boolean,
-        poseidon_hash::poseidon_hash,
     },
 };
 
-use super::{
-    alloc::{ alloc_nums, alloc_bits },
-};
+use crate::tree::merkle_tree::BINARY_ARITY;
+use crate::hasher::{ TreeHasher, Poseidon };
+
+// SynthesisError carries a message only as an io error, so wrong witness
+// lengths are reported as invalid input instead of an index panic
+pub fn check_witness_length(
+    name: &str,
+    expected: usize,
+    actual: usize,
+) -> Result<(), SynthesisError> {
+    if expected != actual {
+        return Err(SynthesisError::IoError(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} length is {}, expected {}", name, actual, expected),
+        )));
+    }
 
-#[derive(Clone)]
-pub struct TreeState<E: JubjubEngine> {
-    pub old_leaf: Vec::<Option<E::Fr>>,
-    pub new_leaf: Vec::<Option<E::Fr>>,
-    pub path: Vec::<Option<E::Fr>>,
-    pub indices: Vec::<Option<bool>>,
+    Ok(())
 }
 
-#[derive(Clone)]
-pub struct TreeCircuit<'a, E: JubjubEngine + PoseidonEngine> {
-    pub params: &'a <E as PoseidonEngine>::Params,
-    pub old_leaf_alloc: Vec::<AllocatedNum<E>>,
-    pub new_leaf_alloc: Vec::<AllocatedNum<E>>,
-    pub path_alloc: Vec::<AllocatedNum<E>>,
-    pub indices_alloc: Vec::<Boolean>,
+// witness lengths of a path of the given depth, see PoseidonMerkleTree::get_leaf_path
+pub fn path_length(tree_depth: usize, arity: usize) -> usize {
+    tree_depth * (arity - 1)
 }
 
-impl<'a, E> TreeCircuit<'a, E> 
-    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
-{
-    pub fn new<CS: ConstraintSystem<E>> (
-        mut cs: CS,
-        leaf_size: usize,
-        tree_depth: usize,
-        params: &'a <E as PoseidonEngine>::Params,
-        tree_state: &TreeState<E>,
-    ) -> Result<Self, SynthesisError> {
-        assert_eq!(tree_state.old_leaf.len(), leaf_size);
-        assert_eq!(tree_state.new_leaf.len(), leaf_size);
-        assert_eq!(tree_state.indices.len(), tree_depth);
-        assert_eq!(tree_state.path.len(), tree_depth);
-
-        let old_leaf_alloc = alloc_nums(
-            cs.namespace(|| "allocate old leaf"),
-            &tree_state.old_leaf,
-        )?;
-
-        let new_leaf_alloc = alloc_nums(
-            cs.namespace(|| "allocate new leaf"),
-            &tree_state.new_leaf,
-        )?;
-
-        let path_alloc = alloc_nums(
-            cs.namespace(|| "allocate leaf path"),
-            &tree_state.path,
-        )?;
-
-        let indices_alloc = alloc_bits(
-            cs.namespace(|| "allocate leaf path indices"),
-            &tree_state.indices,
-        )?;
-
-        let tree = TreeCircuit {
-            params,
-            old_leaf_alloc,
-            new_leaf_alloc,
-            path_alloc,
-            indices_alloc,
-        };
-
-        Ok(tree)
-    }
-
-    pub fn calc_old_root<CS: ConstraintSystem<E>>(
-        &self,
-        mut cs: CS,
-    ) -> Result<AllocatedNum<E>, SynthesisError> {
-        calc_root(
-            cs.namespace(|| "calculate old root"),
-            self.params,
-            &self.old_leaf_alloc,
-            &self.path_alloc,
-            &self.indices_alloc,
-        )
-    }
-
-    pub fn calc_new_root<CS: ConstraintSystem<E>>(
-        &self,
-        mut cs: CS,
-    ) -> Result<AllocatedNum<E>, SynthesisError> {
-        calc_root(
-            cs.namespace(|| "calculate new root"),
-            self.params,
-            &self.new_leaf_alloc,
-            &self.path_alloc,
-            &self.indices_alloc,
-        )
-    }
-
-    pub fn verify_old_root<CS: ConstraintSystem<E>>(
-        &self,
-        mut cs: CS,
-        old_root: &AllocatedNum<E>,
-    ) -> Result<(), SynthesisError> {
-        verify(
-            cs.namespace(|| "verify old root"),
-            self.params,
-            &self.old_leaf_alloc,
-            &self.path_alloc,
-            &self.indices_alloc,
-            old_root,
-        )
-    }
+pub fn indices_length(tree_depth: usize, arity: usize) -> usize {
+    tree_depth * arity.trailing_zeros() as usize
 }
 
 // TODO the same logic in utils/tree ???
 pub fn calc_root<E, CS> (
-    mut cs: CS,
+    cs: CS,
     params: &<E as PoseidonEngine>::Params,
     leaf: &[AllocatedNum<E>],
     path: &[AllocatedNum<E>],
@@ -136,42 +57,108 @@ pub fn calc_root<E, CS> (
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
           CS: ConstraintSystem<E>,
 {
-    let mut prev_hash = {
-        let hashes_vec = poseidon_hash(
-            cs.namespace(|| "calculate leaf hash"),
-            leaf,
-            params,
-        )?;
-        hashes_vec[0].clone()
-    };
+    calc_root_with_arity(cs, params, BINARY_ARITY, leaf, path, indices)
+}
+
+pub fn calc_root_with_arity<E, CS> (
+    cs: CS,
+    params: &<E as PoseidonEngine>::Params,
+    arity: usize,
+    leaf: &[AllocatedNum<E>],
+    path: &[AllocatedNum<E>],
+    indices: &[Boolean],
+) -> Result<AllocatedNum<E>, SynthesisError>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+          CS: ConstraintSystem<E>,
+{
+    calc_root_with_hasher::<E, Poseidon, CS>(cs, params, arity, leaf, path, indices)
+}
+
+// every level takes log2(arity) index bits and arity - 1 siblings: bit j
+// puts the 2^j nodes built so far before or after the next 2^j siblings,
+// so the children are in order for the level hash
+pub fn calc_root_with_hasher<E, H, CS> (
+    mut cs: CS,
+    params: &H::Params,
+    arity: usize,
+    leaf: &[AllocatedNum<E>],
+    path: &[AllocatedNum<E>],
+    indices: &[Boolean],
+) -> Result<AllocatedNum<E>, SynthesisError>
+    where E: JubjubEngine,
+          H: TreeHasher<E>,
+          CS: ConstraintSystem<E>,
+{
+    let index_bits = arity.trailing_zeros() as usize;
+    if arity < 2 || !arity.is_power_of_two()
+        || !indices.len().is_multiple_of(index_bits)
+        || path.len() != path_length(indices.len() / index_bits, arity)
+    {
+        return Err(SynthesisError::Unsatisfiable);
+    }
 
-    for (i, (index, neighbor_hash)) in indices.iter()
-        .zip(path.iter())
+    let mut prev_hash = H::hash_circuit(
+        cs.namespace(|| "calculate leaf hash"),
+        params,
+        leaf,
+    )?;
+
+    for (i, (level_indices, level_path)) in indices.chunks(index_bits)
+        .zip(path.chunks(arity - 1))
         .enumerate()
     {
-        let (left, right) = AllocatedNum::conditionally_reverse(
-            cs.namespace(|| format!("conditionally reversing node children {}", i)),
-            &prev_hash,
-            neighbor_hash,
-            index,
+        let mut children = vec![prev_hash];
+        let mut siblings = level_path;
+
+        for (j, index) in level_indices.iter().enumerate() {
+            let (block, rest) = siblings.split_at(children.len());
+            let mut lower = Vec::new();
+            let mut upper = Vec::new();
+
+            for (k, (node, neighbor_hash)) in children.iter().zip(block.iter()).enumerate() {
+                let (left, right) = AllocatedNum::conditionally_reverse(
+                    cs.namespace(|| format!("conditionally reversing node children {} {} {}", i, j, k)),
+                    node,
+                    neighbor_hash,
+                    index,
+                )?;
+                lower.push(left);
+                upper.push(right);
+            }
+
+            lower.extend(upper);
+            children = lower;
+            siblings = rest;
+        }
+
+        prev_hash = H::hash_circuit(
+            cs.namespace(|| format!("calculate level hash {}", i)),
+            params,
+            &children,
         )?;
-
-        prev_hash = {
-            let hashes_vec = poseidon_hash(
-                cs.namespace(|| format!("calculate level hash {}", i)),
-                &[left, right],
-                params,
-            )?;
-            hashes_vec[0].clone()
-        };
     }
 
     Ok(prev_hash)
 }
 
 pub fn verify<E, CS> (
-    mut cs: CS,
+    cs: CS,
+    params: &<E as PoseidonEngine>::Params,
+    leaf: &[AllocatedNum<E>],
+    path: &[AllocatedNum<E>],
+    indices: &[Boolean],
+    root: &AllocatedNum<E>,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+          CS: ConstraintSystem<E>,
+{
+    verify_with_arity(cs, params, BINARY_ARITY, leaf, path, indices, root)
+}
+
+pub fn verify_with_arity<E, CS> (
+    cs: CS,
     params: &<E as PoseidonEngine>::Params,
+    arity: usize,
     leaf: &[AllocatedNum<E>],
     path: &[AllocatedNum<E>],
     indices: &[Boolean],
@@ -180,9 +167,26 @@ pub fn verify<E, CS> (
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
           CS: ConstraintSystem<E>,
 {
-    let last_hash = calc_root(
+    verify_with_hasher::<E, Poseidon, CS>(cs, params, arity, leaf, path, indices, root)
+}
+
+pub fn verify_with_hasher<E, H, CS> (
+    mut cs: CS,
+    params: &H::Params,
+    arity: usize,
+    leaf: &[AllocatedNum<E>],
+    path: &[AllocatedNum<E>],
+    indices: &[Boolean],
+    root: &AllocatedNum<E>,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          H: TreeHasher<E>,
+          CS: ConstraintSystem<E>,
+{
+    let last_hash = calc_root_with_hasher::<E, H, _>(
         cs.namespace(|| "calculate root"),
         params,
+        arity,
         leaf,
         path,
         indices,
@@ -197,4 +201,3 @@ pub fn verify<E, CS> (
 
     Ok(())
 }
-
@@ -1,28 +1,189 @@
 use std::fmt;
+use std::mem;
+use std::convert::TryFrom;
+use std::error::Error;
 
 use pairing_ce::bn256;
 
 use sapling_crypto_ce::{
-    jubjub::JubjubEngine,
+    jubjub::{ JubjubEngine, Unknown, edwards::Point },
+    alt_babyjubjub::AltJubjubBn256,
+    eddsa::PrivateKey,
+    util::hash_to_scalar,
 };
 
+use pairing_ce::bn256::Bn256;
+
 use ff_ce::{
     Field,
     PrimeField,
+    PrimeFieldRepr,
     BitIterator,
 };
 
-const BITS_IN_BYTE: usize = 8;
+use rand::{ SeedableRng, chacha::ChaChaRng };
+
+use super::signature::NUM_BYTES_TO_SIGN;
+use crate::error::OpenPlasmaError;
+
+use zeroize::Zeroize;
+
+pub const FR_BYTES: usize = 32;
+// the largest power of ten in a u64, decimal strings are printed 19 digits at a time
+const DECIMAL_CHUNK: u128 = 10_000_000_000_000_000_000;
+const DECIMAL_CHUNK_DIGITS: usize = 19;
+// blake2b personalization, 16 bytes
+const DETERMINISTIC_SIGN_PERSONALIZATION: &[u8; 16] = b"OpenPlasma_Nonce";
 
-pub fn fr_to_usize(fr_a: bn256::Fr) -> usize {
-    let a = fr_a.to_hex();
-    usize::from_str_radix(a.as_str(), 16).expect("Failed to parse hex")
+// errors if any bit above the usize width is set instead of keeping the low bits
+pub fn fr_to_usize(fr_a: bn256::Fr) -> Result<usize, OpenPlasmaError> {
+    let value = fr_to_u128_checked(&fr_a).ok().and_then(|a| usize::try_from(a).ok());
+    Ok(value.ok_or(ConversionError::DoesNotFit { bytes: mem::size_of::<usize>() })?)
 }
 
 pub fn usize_to_fr(a: usize) -> bn256::Fr {
     bn256::Fr::from_hex(&format!("{:#066x}", a)).expect("Failed to parse hex")
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    MissingHexPrefix,
+    InvalidHex,
+    InvalidDecimal,
+    WrongLength { expected: usize, actual: usize },
+    DoesNotFit { bytes: usize },
+    OutOfField,
+    NotOnCurve,
+}
+
+impl Error for ConversionError {}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ConversionError::MissingHexPrefix => write!(f, "Hex string has no 0x prefix"),
+            ConversionError::InvalidHex => write!(f, "Not a hex number"),
+            ConversionError::InvalidDecimal => write!(f, "Not a decimal number"),
+            ConversionError::WrongLength { expected, actual } => write!(
+                f, "Field element is {} bytes, expected {}", actual, expected),
+            ConversionError::DoesNotFit { bytes } => write!(f, "Value doesn't fit in {} bytes", bytes),
+            ConversionError::OutOfField => write!(f, "Value is not below the field modulus"),
+            ConversionError::NotOnCurve => write!(f, "Coordinates are not a point of the curve"),
+        }
+    }
+}
+
+// digits most significant first, every value up to 2^256 - 1 is read before
+// the modulus is checked
+fn fr_from_digits(digits: &str, radix: u32, malformed: ConversionError) -> Result<bn256::Fr, ConversionError> {
+    if digits.is_empty() {
+        return Err(malformed);
+    }
+
+    let mut limbs = [0u64; 4];
+    for c in digits.chars() {
+        let mut carry = u128::from(c.to_digit(radix).ok_or_else(|| malformed.clone())?);
+        for limb in limbs.iter_mut() {
+            let value = u128::from(*limb) * u128::from(radix) + carry;
+            *limb = value as u64;
+            carry = value >> 64;
+        }
+        if carry != 0 {
+            return Err(ConversionError::OutOfField);
+        }
+    }
+
+    bn256::Fr::from_repr(bn256::FrRepr(limbs)).map_err(|_| ConversionError::OutOfField)
+}
+
+// 32 bytes big endian, the layout of a uint256 in the evm
+pub fn fr_to_be_bytes(fr: &bn256::Fr) -> [u8; FR_BYTES] {
+    let mut bytes = [0u8; FR_BYTES];
+    fr.into_repr().write_be(&mut bytes[..]).unwrap();
+    bytes
+}
+
+pub fn fr_from_be_bytes(bytes: &[u8]) -> Result<bn256::Fr, OpenPlasmaError> {
+    if bytes.len() != FR_BYTES {
+        return Err(ConversionError::WrongLength { expected: FR_BYTES, actual: bytes.len() }.into());
+    }
+
+    let mut repr = bn256::FrRepr::default();
+    repr.read_be(bytes).unwrap();
+    Ok(bn256::Fr::from_repr(repr).map_err(|_| ConversionError::OutOfField)?)
+}
+
+// 0x and all 64 digits, as serde_fr writes it
+pub fn fr_to_hex(fr: &bn256::Fr) -> String {
+    format!("0x{}", fr.to_hex())
+}
+
+// any number of digits in either case after the 0x, unlike Fr::from_hex which
+// drops the bytes above the 32nd
+pub fn fr_from_hex(hex: &str) -> Result<bn256::Fr, OpenPlasmaError> {
+    let digits = hex.strip_prefix("0x").ok_or(ConversionError::MissingHexPrefix)?;
+    Ok(fr_from_digits(digits, 16, ConversionError::InvalidHex)?)
+}
+
+pub fn fr_to_dec_string(fr: &bn256::Fr) -> String {
+    let mut limbs = fr.into_repr().0;
+
+    // 19 digit chunks least significant first
+    let mut chunks = Vec::new();
+    loop {
+        let mut rest = 0u128;
+        for limb in limbs.iter_mut().rev() {
+            let value = (rest << 64) | u128::from(*limb);
+            *limb = (value / DECIMAL_CHUNK) as u64;
+            rest = value % DECIMAL_CHUNK;
+        }
+        chunks.push(rest as u64);
+        if limbs.iter().all(|limb| *limb == 0) {
+            break;
+        }
+    }
+
+    let mut decimal = chunks.pop().unwrap().to_string();
+    for chunk in chunks.iter().rev() {
+        decimal.push_str(&format!("{:0width$}", chunk, width = DECIMAL_CHUNK_DIGITS));
+    }
+    decimal
+}
+
+// digits only, no sign and no separators
+pub fn fr_from_dec_string(decimal: &str) -> Result<bn256::Fr, OpenPlasmaError> {
+    Ok(fr_from_digits(decimal, 10, ConversionError::InvalidDecimal)?)
+}
+
+pub fn point_to_hex_xy(point: &Point<Bn256, Unknown>) -> (String, String) {
+    let (x, y) = point.into_xy();
+    (fr_to_hex(&x), fr_to_hex(&y))
+}
+
+// the point has to be on the curve, whether it is in the prime order
+// subgroup is for the caller to check, see is_canonical_point
+pub fn point_from_hex_xy(
+    x: &str,
+    y: &str,
+    sign_params: &AltJubjubBn256,
+) -> Result<Point<Bn256, Unknown>, OpenPlasmaError> {
+    Ok(Point::from_xy(fr_from_hex(x)?, fr_from_hex(y)?, sign_params).ok_or(ConversionError::NotOnCurve)?)
+}
+
+// any u128 is below the modulus, amounts and balances go through these
+pub fn u128_to_fr(value: u128) -> bn256::Fr {
+    let repr = bn256::FrRepr([value as u64, (value >> 64) as u64, 0, 0]);
+    bn256::Fr::from_repr(repr).expect("u128 is below the field modulus")
+}
+
+pub fn fr_to_u128_checked(fr: &bn256::Fr) -> Result<u128, OpenPlasmaError> {
+    let repr = fr.into_repr();
+    if repr.0[2] != 0 || repr.0[3] != 0 {
+        return Err(ConversionError::DoesNotFit { bytes: 16 }.into());
+    }
+    Ok((u128::from(repr.0[1]) << 64) | u128::from(repr.0[0]))
+}
+
 pub fn bool_to_fr(cond: bool) -> bn256::Fr {
     if cond {
         bn256::Fr::one()
@@ -31,39 +192,62 @@ pub fn bool_to_fr(cond: bool) -> bn256::Fr {
     }
 }
 
-pub fn fr_to_bytes_le(value: bn256::Fr, bytes_len: usize) -> Vec::<u8> {
-    let mut field_char = BitIterator::new(bn256::Fr::char());
-    let mut value_bits = Vec::with_capacity(bn256::Fr::NUM_BITS as usize);
+// the low bytes_len bytes of the element, the value has to fit in them
+pub fn fr_to_bytes_le(value: bn256::Fr, bytes_len: usize) -> Result<Vec::<u8>, OpenPlasmaError> {
+    let mut bytes = Vec::with_capacity(FR_BYTES.max(bytes_len));
+    value.into_repr().write_le(&mut bytes).unwrap();
+    if bytes.iter().skip(bytes_len).any(|byte| *byte != 0) {
+        return Err(ConversionError::DoesNotFit { bytes: bytes_len }.into());
+    }
 
-    let mut found_one = false;
-    for b in BitIterator::new(value.into_repr()) {
-        found_one |= field_char.next().unwrap();
-        if !found_one {
-            continue;
-        }
+    bytes.resize(bytes_len, 0);
+    Ok(bytes)
+}
 
-        value_bits.push(b);
+// any length, the bytes above the 32nd have to be zero
+pub fn bytes_le_to_fr(bytes: &[u8]) -> Result<bn256::Fr, OpenPlasmaError> {
+    if bytes.iter().skip(FR_BYTES).any(|byte| *byte != 0) {
+        return Err(ConversionError::OutOfField.into());
     }
 
-    value_bits.reverse();
+    let mut le = [0u8; FR_BYTES];
+    let len = bytes.len().min(FR_BYTES);
+    le[..len].copy_from_slice(&bytes[..len]);
 
-    let mut value_bytes = Vec::with_capacity(bytes_len);
-    for (i, byte_chunk) in value_bits.chunks(BITS_IN_BYTE).enumerate() {
-        let mut byte = 0u8;
-        for (j, bit) in byte_chunk.into_iter().enumerate() {
-            if *bit {
-                byte += 1 << j;
-            }
-        }
+    let mut repr = bn256::FrRepr::default();
+    repr.read_le(&le[..]).unwrap();
+    Ok(bn256::Fr::from_repr(repr).map_err(|_| ConversionError::OutOfField)?)
+}
 
-        value_bytes.push(byte);
+// the message eddsa signs for a request hash: its low 31 bytes little
+// endian, the top 6 bits of the 254 are dropped. a raw message signature
+// covers at most 31 bytes, verify_eddsa takes the same bits in the circuit
+pub fn fr_to_sign_message(hash: bn256::Fr) -> [u8; NUM_BYTES_TO_SIGN] {
+    let mut bytes = Vec::with_capacity(FR_BYTES);
+    hash.into_repr().write_le(&mut bytes).unwrap();
 
-        if i == bytes_len - 1 {
-            break;
-        }
-    }
+    let mut message = [0u8; NUM_BYTES_TO_SIGN];
+    message.copy_from_slice(&bytes[..NUM_BYTES_TO_SIGN]);
+    message
+}
+
+// rfc6979 style signing randomness: a hash of the secret key and the message,
+// so signing the same message twice gives the same signature and the eddsa
+// nonce is as secret as the key
+pub fn deterministic_rng(seckey: &PrivateKey::<Bn256>, msg: &[u8]) -> ChaChaRng {
+    let mut key = Vec::new();
+    seckey.0.into_repr().write_le(&mut key).unwrap();
+    let seed = hash_to_scalar::<Bn256>(DETERMINISTIC_SIGN_PERSONALIZATION, &key, msg);
+    key.zeroize();
 
-    value_bytes
+    // the seed gives away the eddsa nonce and through it the key, the chacha
+    // state itself can't be wiped
+    let mut seed: Vec<u32> = seed.into_repr().as_ref().iter().flat_map(
+        |limb| vec![*limb as u32, (*limb >> 32) as u32]
+    ).collect();
+    let rng = ChaChaRng::from_seed(&seed[..]);
+    seed.zeroize();
+    rng
 }
 
 pub fn fs_to_fr<E: JubjubEngine> (num: E::Fs) -> E::Fr {
@@ -96,15 +280,15 @@ pub fn write_formatted_vector<T: fmt::Debug>(
     output: Vec::<Vec::<T>>,
     annotation: &str,
 ) -> fmt::Result {
-    write!(f, "{}: [\n", annotation)?;
+    writeln!(f, "{}: [", annotation)?;
     for subvector in output.iter() {
-        write!(f, "    [\n")?;
-        for elem in subvector.into_iter() {
-            write!(f, "        {:?},\n", elem)?;
+        writeln!(f, "    [")?;
+        for elem in subvector.iter() {
+            writeln!(f, "        {:?},", elem)?;
         }
-        write!(f, "    ],\n")?;
+        writeln!(f, "    ],")?;
     }
-    write!(f, "]\n")?;
+    writeln!(f, "]")?;
 
     Ok(())
 }
@@ -1,107 +1,238 @@
+use std::sync::{ Arc, Mutex };
+
 use openplasma_circuits::{
     data_structs::{
         transfer::Transfer,
         deposit::Deposit,
         onchain_withdrawal::OnchainWithdrawal,
-        offchain_withdrawal::OffchainWithdrawal,
+        offchain_withdrawal::{
+            OffchainWithdrawal,
+            WithdrawalPermit,
+            credit_fee_and_record_state,
+            SignatureError,
+            OFFCHAIN_WITHDRAWAL_BYTES,
+        },
+        offchain_change_pubkey::OffchainChangePubKey,
+        offchain_conditional_transfer::OffchainConditionalTransfer,
+        swap::{ Swap, SwapHalf },
+        full_exit::FullExit,
+        close_account::CloseAccount,
+        offchain_transfer::{ OffchainTransfer, OFFCHAIN_TRANSFER_BYTES },
+        offchain_deposit::{ OffchainDeposit, OFFCHAIN_DEPOSIT_BYTES },
+        encoding::{ EncodingError, ENCODING_VERSION, HEADER_BYTES },
+        batch_verification::{ verify_signatures_batch, verify_signatures_combined },
     },
     operator::Operator,
-    utils::utils::{fr_to_usize, usize_to_fr},
-    account::AccountState,
-    deposit_circuit::{ DepositCircuit, DepositBatchCircuit },
+    types::{ Balance, Nonce, AccountId, RangeError, MIN_AMOUNT },
+    error::OpenPlasmaError,
+    tree::account::{ Account, AccountsTree, LeafUpdate, TreeError },
+    tree::proof::{ MerkleProof, BalanceProof },
+    tree::snapshot::{ StateSnapshot, StateDiff, LegacyStateSnapshot, LegacyAccountSnapshot, migrate_snapshot, migrate_leaves, MigrationTranscript },
+    tree::merkle_tree::{ PoseidonMerkleTree, PathCache, BINARY_ARITY },
+    tree::leaf::{ LeafAccount, LeafVersion, LeafCodec, LeafError },
+    tree::empty::{ empty_account_leaf, empty_account_leaf_with_hasher, empty_balances_root_with_hasher, empty_pubkey },
+    tree::genesis::{ Genesis, GenesisAccount },
+    utils::utils::{
+        fr_to_usize,
+        usize_to_fr,
+        u128_to_fr,
+        fr_to_u128_checked,
+        optionalize,
+        fs_to_fr,
+        fr_to_bytes_le,
+        bytes_le_to_fr,
+        fr_to_sign_message,
+        fr_to_be_bytes,
+        fr_from_be_bytes,
+        fr_to_hex,
+        fr_from_hex,
+        fr_to_dec_string,
+        fr_from_dec_string,
+        point_to_hex_xy,
+        point_from_hex_xy,
+        ConversionError,
+    },
+    utils::signature::{ verify_eddsa, NUM_BYTES_TO_SIGN },
+    utils::point::{ pack_point, unpack_point, pack_point_gadget },
+    utils::sign::check_pubkey,
+    utils::domain::{ SigningDomain, ADDRESS_BYTES, address_to_fr },
+    utils::serde_address,
+    utils::op_type::{
+        DEPOSIT_OP,
+        OFFCHAIN_WITHDRAWAL_OP,
+        TRANSFER_OP,
+        CHANGE_PUBKEY_OP,
+        FULL_EXIT_OP,
+        BLOCK_OP,
+        CONDITIONAL_TRANSFER_OP,
+        SWAP_OP,
+        CLOSE_ACCOUNT_OP,
+    },
+    utils::calc::{
+        check_decomposition_le,
+        check_digit_decomposition_le,
+        enforce_less_or_equal,
+        is_less_or_equal,
+        is_zero,
+        boolean_to_allocated_num,
+    },
+    account::{ AccountState, AccountLeaf, AccountCircuit, WitnessError },
+    deposit_circuit::{
+        DepositCircuit,
+        DepositBatchCircuit,
+        CommittedDepositBatchCircuit,
+        Sha256DepositBatchCircuit,
+        SpongeDepositBatchCircuit,
+        DepositQueue,
+        DepositAccumulator,
+        BuildError,
+    },
     onchain_withdrawal_circuit::{ OnchainWithdrawalCircuit, OnchainWithdrawalBatchCircuit },
     offchain_withdrawal_circuit::{ OffchainWithdrawalCircuit, OffchainWithdrawalBatchCircuit },
+    delegated_withdrawal_circuit::{ DelegatedWithdrawalCircuit, DelegatedWithdrawalBatchCircuit },
     transfer_circuit::{ TransferCircuit, TransferBatchCircuit },
+    conditional_transfer_circuit::{ ConditionalTransferCircuit, ConditionalTransferBatchCircuit },
+    swap_circuit::{ SwapCircuit, SwapBatchCircuit },
+    change_pubkey_circuit::{ ChangePubKeyCircuit, ChangePubKeyBatchCircuit },
+    full_exit_circuit::{ FullExitCircuit, FullExitBatchCircuit },
+    exit_circuit::ExitCircuit,
+    close_account_circuit::{ CloseAccountCircuit, CloseAccountBatchCircuit },
+    block_circuit::{ Operation, BlockOperationCircuit, BlockCircuit },
+    keys::{ SecretKey, derive_private_key, derive_keypair, sign_withdrawal_json, padding_secret_key, padding_pubkey },
+    params::{ Params, shared_params, poseidon_params, jubjub_params, rescue_params },
+    musig::{ AggregateKey, SigningSession, MusigError, aggregate_signatures, MAX_MESSAGE_BYTES },
+    stats::{ measure, shape },
+    testing::{ check_circuit, which_operation },
+    scenario::{ Scenario, DepositScenario, DepositStep, Mutation, ScenarioError, check_scenario, check_mutation },
+    family::{ BatchConfig, CircuitFamily },
+    prover::{
+        generate_parameters,
+        load_parameters,
+        load_layout,
+        layout_path,
+        prove_deposit_block,
+        verify_block,
+        export_vk_json,
+        import_vk_json,
+        export_solidity_verifier,
+        proof_to_eth_bytes,
+        proof_from_eth_bytes,
+        public_inputs_to_eth_bytes,
+        PROOF_ETH_BYTES,
+        Pool,
+        ProofJob,
+        ProofError,
+    },
+    public_inputs::{ PublicInputs, InputSemantic, verify_block_proof, compute_block_commitment },
+    layout::CircuitLayout,
+    proof_batch::{ ProofBatch, batch_verify },
+    aggregation::{ AggregationSrs, AggregationError, aggregate, verify_aggregated },
+    pubdata::{ compute_pubdata_commitment, accumulate_pubdata },
+    hasher::{ TreeHasher, Poseidon, Rescue },
+    block::{ BlockBuilder, BlockError, Pubdata, PubdataOp, replay_pubdata },
+    mempool::{ Mempool, MempoolError },
+    receipt::{ Receipt, RECEIPT_BYTES },
+    limits::{ Limits, LimitViolation },
+    metrics::metrics,
+    exit::{ ExitError, generate_exit },
+    replay::{ JournalBlock, JournalHeader, JournalWriter, ReplayError, replay_journal },
+    audit::{ AuditError, verify_block_against_pubdata },
+    l1::{
+        PriorityOp,
+        PriorityQueue,
+        PriorityQueueError,
+        ProcessedOps,
+        DecodeError,
+        decode_deposit_event,
+        DEPOSIT_EVENT_BYTES,
+    },
+    rescue::rescue_hash,
+    sponge::{ PoseidonSponge, SpongeGadget },
+    utils::tree::calc_root_with_hasher,
+    circuit::merkle::{ MerkleUpdateGadget, MerkleUpdateState },
 };
 
 use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
     SynthesisError,
     groth16::{
         Parameters,
         generate_random_parameters,
         prepare_verifying_key,
         verify_proof,
+        create_random_proof,
+        VerifyingKey,
     },
 };
 
 use sapling_crypto_ce::{
-    poseidon::{bn256::Bn256PoseidonParams, poseidon_hash},
+    poseidon::{ bn256::Bn256PoseidonParams, poseidon_hash },
     group_hash::BlakeHasher,
-    jubjub::FixedGenerators,
-    alt_babyjubjub::AltJubjubBn256,
-    eddsa::{ PublicKey, PrivateKey },
+    circuit::{
+        test::TestConstraintSystem,
+        num::AllocatedNum,
+        boolean::{ Boolean, AllocatedBit },
+        ecc::EdwardsPoint,
+    },
+    jubjub::{ FixedGenerators, Unknown, edwards::Point },
+    alt_babyjubjub::{ AltJubjubBn256, fs::{ Fs, FsRepr } },
+    eddsa::{ PublicKey, PrivateKey, Signature },
 };
 
-use pairing_ce::bn256::Bn256;
+use pairing_ce::{
+    CurveAffine,
+    CurveProjective,
+    bn256::{ self, Bn256, G1Affine, G2Affine },
+};
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+use rand::{ Rng, SeedableRng, XorShiftRng, thread_rng };
+
+use proptest::prelude::*;
 
-use rand::{ Rng, thread_rng };
+// withdrawals are signed with a SecretKey, most tests share one raw key between
+// withdrawals and the other operations
+fn secret(seckey: &PrivateKey<Bn256>) -> SecretKey {
+    SecretKey::from(PrivateKey(seckey.0))
+}
+
+// the EIP-55 example address, test withdrawals pay out to it
+const ETH_ADDRESS: [u8; ADDRESS_BYTES] = [
+    0x5a, 0xae, 0xb6, 0x05, 0x3f, 0x3e, 0x94, 0xc9, 0xb9, 0xa0,
+    0x9f, 0x33, 0x66, 0x94, 0x35, 0xe7, 0xef, 0x1b, 0xea, 0xed,
+];
 
 // circuit params generation ------------------------------------------------------------
 // --------------------------------------------------------------------------------------
 
-fn setup_deposit_circuit<'a>(
+fn setup_deposit_circuit(
     deposit_batch: usize,
     account_depth: usize,
-    hash_params: &'a Bn256PoseidonParams,
+    token_depth: usize,
+    params: &Arc<Params<Bn256>>,
 ) -> Result<Parameters<Bn256>, SynthesisError> {
-    let account_state = AccountState::<Bn256> {
-        old_balance: None,
-        new_balance: None,
-        old_pubkey: None,
-        old_nonce: None,
-        new_pubkey: None,
-        new_nonce: None,
-        account_path: vec![None; account_depth],
-        account_indices: vec![None; account_depth],
-    };
-
-    let deposit_gen = || {
-        DepositCircuit::<Bn256> {
-            account_state: account_state.clone(),
-            pubkey: None,
-            account_id: None,
-            amount: None,
-        }
-    };
-
-    let mut deposit_queue = Vec::with_capacity(deposit_batch);
-    deposit_queue.resize_with(deposit_batch, deposit_gen);
-
-    let circuit = DepositBatchCircuit {
-        deposit_batch,
-        account_depth,
-        hash_params,
-        deposit_queue,
-        old_accum_hash: None,
-        new_accum_hash: None,
-        old_account_root: None,
-        new_account_root: None,
-    };
+    let config = BatchConfig { deposit_batch, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let circuit = DepositBatchCircuit::for_setup(config, params);
 
     let mut rng = thread_rng();
     generate_random_parameters(circuit, &mut rng)
 }
 
-fn setup_onchain_withdraw_circuit<'a>(
+fn setup_onchain_withdraw_circuit(
     batch_size: usize,
     account_depth: usize,
-    hash_params: &'a Bn256PoseidonParams,
+    token_depth: usize,
+    hash_params: &Bn256PoseidonParams,
 ) -> Result<Parameters<Bn256>, SynthesisError> {
-    let account_state = AccountState::<Bn256> {
-        old_balance: None,
-        new_balance: None,
-        old_pubkey: None,
-        old_nonce: None,
-        new_pubkey: None,
-        new_nonce: None,
-        account_path: vec![None; account_depth],
-        account_indices: vec![None; account_depth],
-    };
+    let account_state = AccountState::<Bn256>::empty(account_depth, token_depth);
 
     let withdrawal_gen = || {
         OnchainWithdrawalCircuit::<Bn256> {
             account_state: account_state.clone(),
             account_id: None,
+            token_id: None,
             amount: None,
         }
     };
@@ -112,6 +243,7 @@ fn setup_onchain_withdraw_circuit<'a>(
     let circuit = OnchainWithdrawalBatchCircuit {
         batch_size,
         account_depth,
+        token_depth,
         hash_params,
         queue,
         old_accum_hash: None,
@@ -127,28 +259,25 @@ fn setup_onchain_withdraw_circuit<'a>(
 fn setup_offchain_withdraw_circuit<'a>(
     batch_size: usize,
     account_depth: usize,
+    token_depth: usize,
     hash_params: &'a Bn256PoseidonParams,
     sign_params: &'a AltJubjubBn256,
 ) -> Result<Parameters<Bn256>, SynthesisError> {
-    let account_state = AccountState::<Bn256> {
-        old_balance: None,
-        new_balance: None,
-        old_pubkey: None,
-        old_nonce: None,
-        new_pubkey: None,
-        new_nonce: None,
-        account_path: vec![None; account_depth],
-        account_indices: vec![None; account_depth],
-    };
+    let account_state = AccountState::<Bn256>::empty(account_depth, token_depth);
 
     let withdrawal_gen = || {
         OffchainWithdrawalCircuit::<Bn256> {
             account_state: account_state.clone(),
             account_id: None,
+            token_id: None,
             amount: None,
+            fee: None,
             nonce: None,
+            valid_until: None,
+            eth_address: None,
             sign: None,
             pubkey: None,
+            is_noop: None,
         }
     };
 
@@ -158,9 +287,19 @@ fn setup_offchain_withdraw_circuit<'a>(
     let circuit = OffchainWithdrawalBatchCircuit {
         batch_size,
         account_depth,
+        token_depth,
         hash_params,
         sign_params,
+        signing_domain: SigningDomain::default(),
+        reject_zero_amount: false,
         queue,
+        fee_account_state: account_state,
+        fee_account_id: None,
+        fee_token_id: None,
+        total_fee: None,
+        timestamp: None,
+        old_accum_hash: None,
+        new_accum_hash: None,
         old_account_root: None,
         new_account_root: None,
     };
@@ -172,19 +311,11 @@ fn setup_offchain_withdraw_circuit<'a>(
 fn setup_transfer_circuit<'a>(
     batch_size: usize,
     account_depth: usize,
+    token_depth: usize,
     hash_params: &'a Bn256PoseidonParams,
     sign_params: &'a AltJubjubBn256,
 ) -> Result<Parameters<Bn256>, SynthesisError> {
-    let account_state = AccountState::<Bn256> {
-        old_balance: None,
-        new_balance: None,
-        old_pubkey: None,
-        old_nonce: None,
-        new_pubkey: None,
-        new_nonce: None,
-        account_path: vec![None; account_depth],
-        account_indices: vec![None; account_depth],
-    };
+    let account_state = AccountState::<Bn256>::empty(account_depth, token_depth);
 
     let transfer_gen = || {
         TransferCircuit::<Bn256> {
@@ -192,6 +323,7 @@ fn setup_transfer_circuit<'a>(
             account_state_to: account_state.clone(),
             account_id_from: None,
             account_id_to: None,
+            token_id: None,
             amount: None,
             nonce: None,
             sign: None,
@@ -205,9 +337,12 @@ fn setup_transfer_circuit<'a>(
     let circuit = TransferBatchCircuit {
         batch_size,
         account_depth,
+        token_depth,
         hash_params,
         sign_params,
         queue,
+        old_accum_hash: None,
+        new_accum_hash: None,
         old_account_root: None,
         new_account_root: None,
     };
@@ -221,15 +356,17 @@ fn setup_transfer_circuit<'a>(
 
 #[test]
 pub fn happy_path() {
-    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
-    let sign_params = AltJubjubBn256::new();
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
 
-    let dep_params = setup_deposit_circuit(2, 2, &hash_params).unwrap();
-    let transfer_params = setup_transfer_circuit(1, 2, &hash_params, &sign_params).unwrap();
-    let of_w_params = setup_offchain_withdraw_circuit(1, 2, &hash_params, &sign_params).unwrap();
-    let on_w_params = setup_onchain_withdraw_circuit(2, 2, &hash_params).unwrap();
+    let dep_params = setup_deposit_circuit(2, 2, 1, &shared_params()).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, hash_params, sign_params).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, hash_params, sign_params).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(2, 2, 1, hash_params).unwrap();
 
-    let mut oper = Operator::new(2, 2, 1, 1, 2, &hash_params, &sign_params, 
+    let params = shared_params();
+    let mut oper = Operator::new(2, 1, 2, 1, 1, 2, &params, 
         &dep_params, &transfer_params, &of_w_params, &on_w_params);
     
     let mut rng = thread_rng();
@@ -238,14 +375,14 @@ pub fn happy_path() {
     let pubkey_maker = PublicKey::from_private(
         &seckey_maker,
         FixedGenerators::SpendingKeyGenerator,
-        &sign_params,
+        sign_params,
     );
 
     let seckey_taker = PrivateKey::<Bn256>(rng.gen());
     let pubkey_taker = PublicKey::from_private(
         &seckey_taker,
         FixedGenerators::SpendingKeyGenerator,
-        &sign_params,
+        sign_params,
     );
 
     // check deposit execution ----------------------------------------------------------
@@ -253,6 +390,7 @@ pub fn happy_path() {
     let deposit_maker = Deposit {
         pubkey: Some(pubkey_maker.clone()),
         account_id: 0,
+        token_id: 0,
         amount: 100,
     };
     oper.add_deposit(deposit_maker.clone()).unwrap();
@@ -260,6 +398,7 @@ pub fn happy_path() {
     let deposit_taker = Deposit {
         pubkey: Some(pubkey_taker.clone()),
         account_id: 1,
+        token_id: 0,
         amount: 100,
     };
     oper.add_deposit(deposit_taker.clone()).unwrap();
@@ -292,20 +431,21 @@ pub fn happy_path() {
 
     // check after deposit execution
 
-    assert_eq!(fr_to_usize(oper.tree.get_balance(0)), 100);
-    assert_eq!(fr_to_usize(oper.tree.get_balance(1)), 100);
+    assert_eq!(fr_to_usize(oper.tree.get_balance(0, 0).unwrap()), Ok(100));
+    assert_eq!(fr_to_usize(oper.tree.get_balance(1, 0).unwrap()), Ok(100));
 
     // check transfer execution ------------------------------------------------------------
 
     let mut transfer = Transfer {
         account_id_from: 0,
         account_id_to: 1,
+        token_id: 0,
         amount: 1,
-        nonce: 1,
+        nonce: Nonce(1),
         sign: None,
     };
 
-    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    transfer.sign(&seckey_maker, hash_params, sign_params);
     oper.add_transfer(transfer.clone()).unwrap();
 
     println!("Transfer circuit ------------------------");
@@ -334,22 +474,26 @@ pub fn happy_path() {
 
     assert_eq!(oper.transfer_queue.len(), 0);
 
-    assert_eq!(fr_to_usize(oper.tree.get_balance(0)), 99);
-    assert_eq!(fr_to_usize(oper.tree.get_balance(1)), 101);
+    assert_eq!(fr_to_usize(oper.tree.get_balance(0, 0).unwrap()), Ok(99));
+    assert_eq!(fr_to_usize(oper.tree.get_balance(1, 0).unwrap()), Ok(101));
 
     // check offchain withdrawal execution ----------------------------------------------
 
     let mut withdrawal = OffchainWithdrawal {
-        account_id: 0,
-        amount: 10,
-        nonce: 2,
+        account_id: AccountId(0),
+        token_id: 0,
+        amount: Balance(10),
+        fee: Balance(0),
+        nonce: Nonce(2),
+        valid_until: 0,
+        eth_address: ETH_ADDRESS,
         sign: None,
     };
 
-    withdrawal.sign(&seckey_maker, &hash_params, &sign_params);
+    withdrawal.sign_with_thread_rng(&secret(&seckey_maker), &domain, None, None);
     oper.add_offchain_withdrawal(withdrawal).unwrap();
 
-    let (public_inputs, proof) = oper.execute_offchain_withdrawal_batch().unwrap();
+    let (public_inputs, proof) = oper.execute_offchain_withdrawal_batch(0).unwrap();
     
     println!("Offchain withdrawal circuit ------------------------");
     println!("public inputs: {:?}", public_inputs);
@@ -371,12 +515,13 @@ pub fn happy_path() {
 
     // check withdrawal execution
 
-    assert_eq!(fr_to_usize(oper.tree.get_balance(0)), 89);
+    assert_eq!(fr_to_usize(oper.tree.get_balance(0, 0).unwrap()), Ok(89));
 
     // check onchain withdrawal ---------------------------------------------------------
 
     let mut withdrawal = OnchainWithdrawal {
         account_id: 0,
+        token_id: 0,
         amount: None,
     };
     oper.add_onchain_withdrawal(withdrawal.clone()).unwrap();
@@ -408,6 +553,8048 @@ pub fn happy_path() {
 
     // check withdrawal execution
 
-    assert_eq!(fr_to_usize(oper.tree.get_balance(0)), 0);
-    assert_eq!(fr_to_usize(oper.tree.get_balance(1)), 0);
+    assert_eq!(fr_to_usize(oper.tree.get_balance(0, 0).unwrap()), Ok(0));
+    assert_eq!(fr_to_usize(oper.tree.get_balance(1, 0).unwrap()), Ok(0));
+}
+
+#[test]
+pub fn self_transfer_rejected() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 0,
+        token_id: 0,
+        amount: 10,
+        nonce: Nonce(1),
+        sign: None,
+    };
+    transfer.sign(&seckey, hash_params, sign_params);
+
+    // operator rejects self transfer before execution
+
+    let dummy_params = setup_transfer_circuit(1, account_depth, token_depth, hash_params, sign_params).unwrap();
+    let params = shared_params();
+    let mut oper = Operator::new(account_depth, token_depth, 1, 1, 1, 1, &params,
+        &dummy_params, &dummy_params, &dummy_params, &dummy_params);
+    assert!(oper.add_transfer(transfer.clone()).is_err());
+
+    // so does the tree, before touching the leaf
+
+    let root = tree.get_root();
+    assert_eq!(transfer.update_tree_and_record_state(&mut tree).err(), Some(TreeError::SelfTransfer(0).into()));
+    assert_eq!(tree.get_root(), root);
+
+    // circuit rejects witness which debits and credits the same leaf
+
+    let old_root = tree.get_root();
+
+    let account_state_from = AccountState::<Bn256> {
+        old_balance: Some(usize_to_fr(100)),
+        new_balance: Some(usize_to_fr(90)),
+        old_pubkey: Some(pubkey.0.clone()),
+        new_pubkey: Some(pubkey.0.clone()),
+        old_nonce: Some(usize_to_fr(0)),
+        new_nonce: Some(usize_to_fr(1)),
+        account_path: optionalize(tree.accounts_tree.get_leaf_path(0)),
+        account_indices: optionalize(tree.accounts_tree.get_leaf_indices(0)),
+        token_path: optionalize(tree.get_token_path(0, 0).unwrap()),
+        token_indices: optionalize(tree.get_token_indices(0, 0).unwrap()),
+    };
+    tree.update_balance(0, 0, usize_to_fr(90)).unwrap();
+    tree.update_nonce(0, usize_to_fr(1)).unwrap();
+
+    let account_state_to = AccountState::<Bn256> {
+        old_balance: Some(usize_to_fr(90)),
+        new_balance: Some(usize_to_fr(100)),
+        old_pubkey: Some(pubkey.0.clone()),
+        new_pubkey: Some(pubkey.0.clone()),
+        old_nonce: Some(usize_to_fr(1)),
+        new_nonce: Some(usize_to_fr(1)),
+        account_path: optionalize(tree.accounts_tree.get_leaf_path(0)),
+        account_indices: optionalize(tree.accounts_tree.get_leaf_indices(0)),
+        token_path: optionalize(tree.get_token_path(0, 0).unwrap()),
+        token_indices: optionalize(tree.get_token_indices(0, 0).unwrap()),
+    };
+    tree.update_balance(0, 0, usize_to_fr(100)).unwrap();
+
+    let accum_hash = poseidon_hash::<Bn256>(
+        hash_params,
+        &[
+            usize_to_fr(TRANSFER_OP),
+            bn256::Fr::zero(),
+            usize_to_fr(0),
+            usize_to_fr(0),
+            usize_to_fr(0),
+            usize_to_fr(10),
+        ],
+    )[0];
+
+    let circuit = TransferBatchCircuit {
+        batch_size: 1,
+        account_depth,
+        token_depth,
+        hash_params,
+        sign_params,
+        queue: vec![TransferCircuit::<Bn256> {
+            account_state_from,
+            account_state_to,
+            account_id_from: Some(usize_to_fr(0)),
+            account_id_to: Some(usize_to_fr(0)),
+            token_id: Some(usize_to_fr(0)),
+            amount: Some(usize_to_fr(10)),
+            nonce: Some(usize_to_fr(1)),
+            sign: transfer.sign.clone(),
+            pubkey: Some(pubkey.0.clone()),
+        }],
+        old_accum_hash: Some(bn256::Fr::zero()),
+        new_accum_hash: Some(accum_hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    };
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let result = circuit.synthesize(&mut cs);
+    assert!(result.is_err() || !cs.is_satisfied());
+}
+
+#[test]
+pub fn offchain_withdrawal_batch() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckeys: Vec<_> = (0..2).map(|_| PrivateKey::<Bn256>(rng.gen())).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| PublicKey::from_private(
+        seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    for (account_id, pubkey) in pubkeys.iter().enumerate() {
+        Deposit {
+            pubkey: Some(pubkey.clone()),
+            account_id,
+            token_id: 0,
+            amount: 100,
+        }.update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    let old_hash = bn256::Fr::zero();
+    let old_root = tree.get_root();
+    let mut accum_hash = old_hash;
+    let mut queue = Vec::new();
+
+    for (account_id, seckey) in seckeys.iter().enumerate() {
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(account_id as u32),
+            token_id: 0,
+            amount: Balance(10 * (account_id as u128 + 1)),
+            fee: Balance(account_id as u128 + 1),
+            nonce: Nonce(1),
+            valid_until: 0,
+            eth_address: ETH_ADDRESS,
+            sign: None,
+        };
+        withdrawal.sign_with_thread_rng(&secret(seckey), &domain, None, None);
+        assert_eq!(withdrawal.verify_signature(&pubkeys[account_id], &domain, None, None), Ok(()));
+
+        accum_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[
+                usize_to_fr(OFFCHAIN_WITHDRAWAL_OP),
+                accum_hash,
+                withdrawal.account_id.to_fr(),
+                usize_to_fr(withdrawal.token_id),
+                withdrawal.amount.to_fr(),
+                address_to_fr(&withdrawal.eth_address),
+            ],
+        )[0];
+
+        let account_state = withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+
+        queue.push(OffchainWithdrawalCircuit::<Bn256> {
+            account_state,
+            account_id: Some(withdrawal.account_id.to_fr()),
+            token_id: Some(usize_to_fr(withdrawal.token_id)),
+            amount: Some(withdrawal.amount.to_fr()),
+            fee: Some(withdrawal.fee.to_fr()),
+            nonce: Some(withdrawal.nonce.to_fr()),
+            valid_until: Some(usize_to_fr(withdrawal.valid_until)),
+            eth_address: Some(address_to_fr(&withdrawal.eth_address)),
+            sign: withdrawal.sign.clone(),
+            pubkey: Some(pubkeys[account_id].0.clone()),
+            is_noop: Some(false),
+        });
+    }
+
+    // fees of both withdrawals are credited to the operator account at once
+
+    let fee_account_state = credit_fee_and_record_state(&mut tree, AccountId(2), 0, Balance(3)).unwrap();
+
+    assert_eq!(fr_to_usize(tree.get_balance(0, 0).unwrap()), Ok(89));
+    assert_eq!(fr_to_usize(tree.get_balance(1, 0).unwrap()), Ok(78));
+    assert_eq!(fr_to_usize(tree.get_balance(2, 0).unwrap()), Ok(3));
+
+    let circuit = OffchainWithdrawalBatchCircuit {
+        batch_size: 2,
+        account_depth,
+        token_depth,
+        hash_params,
+        sign_params,
+        signing_domain: domain,
+        reject_zero_amount: false,
+        queue,
+        fee_account_state,
+        fee_account_id: Some(usize_to_fr(2)),
+        fee_token_id: Some(usize_to_fr(0)),
+        total_fee: Some(usize_to_fr(3)),
+        timestamp: Some(usize_to_fr(0)),
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(accum_hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    };
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    assert_eq!(cs.num_inputs(), 7);
+    assert_eq!(cs.get_input(1, "allocate public inputs/input old accum hash/input variable"), old_hash);
+    assert_eq!(cs.get_input(2, "allocate public inputs/input new accum hash/input variable"), accum_hash);
+    assert_eq!(cs.get_input(3, "allocate public inputs/input old root/input variable"), old_root);
+    assert_eq!(cs.get_input(4, "allocate public inputs/input new root/input variable"), tree.get_root());
+    assert_eq!(cs.get_input(5, "allocate total fee input/input total fee/input variable"), usize_to_fr(3));
+    assert_eq!(cs.get_input(6, "input timestamp/input variable"), bn256::Fr::zero());
+
+    // operator can't credit more than the signed fees
+
+    let mut overcharged = circuit;
+    overcharged.fee_account_state.new_balance = Some(usize_to_fr(4));
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    overcharged.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+}
+
+#[test]
+pub fn rejected_requests_leave_tree_untouched() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit { pubkey: Some(pubkey), account_id: 1, token_id: 0, amount: 100 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    // account 2 holds a balance but never registered a key
+    tree.update_balance(2, 0, usize_to_fr(50)).unwrap();
+    let root = tree.get_root();
+
+    assert_eq!(tree.update_balance(4, 0, usize_to_fr(1)), Err(TreeError::AccountOutOfRange(4).into()));
+    assert_eq!(tree.update_nonce(4, usize_to_fr(1)), Err(TreeError::AccountOutOfRange(4).into()));
+    assert_eq!(tree.get_token_path(1, 2), Err(TreeError::TokenOutOfRange(2).into()));
+    assert_eq!(tree.get_leaf_indices(4), Err(TreeError::AccountOutOfRange(4).into()));
+    assert_eq!(tree.get_leaf_path(1).unwrap().len(), account_depth);
+
+    let withdrawal = |account_id, amount, fee, nonce| OffchainWithdrawal {
+        account_id: AccountId(account_id),
+        token_id: 0,
+        amount: Balance(amount),
+        fee: Balance(fee),
+        nonce: Nonce(nonce),
+        valid_until: 0,
+        eth_address: ETH_ADDRESS,
+        sign: None,
+    };
+    let rejected = [
+        (withdrawal(4, 10, 0, 1), TreeError::AccountOutOfRange(4)),
+        (withdrawal(2, 10, 0, 1), TreeError::EmptyAccount(2)),
+        (withdrawal(1, 100, 1, 1), TreeError::InsufficientBalance { account_id: 1, token_id: 0 }),
+        (withdrawal(1, u128::MAX, 1, 1), TreeError::InsufficientBalance { account_id: 1, token_id: 0 }),
+        (withdrawal(1, 10, 0, 2), TreeError::NonceMismatch { account_id: 1, expected: Nonce(1), got: Nonce(2) }),
+        (withdrawal(1, 10, 0, 0), TreeError::NonceMismatch { account_id: 1, expected: Nonce(1), got: Nonce(0) }),
+    ];
+    for (request, err) in rejected.iter() {
+        assert_eq!(request.update_tree_and_record_state(&mut tree).err(), Some(err.clone().into()));
+        assert_eq!(tree.get_root(), root);
+    }
+
+    // the operator drops bad requests and fills the batch with the next one
+
+    let circuit_params = setup_offchain_withdraw_circuit(1, account_depth, token_depth, hash_params, sign_params).unwrap();
+    let params = shared_params();
+    let mut oper = Operator::new(account_depth, token_depth, 1, 1, 1, 1, &params,
+        &circuit_params, &circuit_params, &circuit_params, &circuit_params);
+    oper.tree = tree.clone();
+
+    let mut overdraft = withdrawal(1, 200, 0, 1);
+    overdraft.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+    let mut valid = withdrawal(1, 10, 0, 1);
+    valid.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+
+    oper.add_offchain_withdrawal(withdrawal(4, 10, 0, 1)).unwrap();
+    oper.add_offchain_withdrawal(overdraft).unwrap();
+    oper.add_offchain_withdrawal(valid.clone()).unwrap();
+    oper.execute_offchain_withdrawal_batch(0).unwrap();
+    assert!(oper.offchain_withdrawal_queue.is_empty());
+
+    valid.update_tree_and_record_state(&mut tree).unwrap();
+    credit_fee_and_record_state(&mut tree, AccountId(0), 0, Balance(0)).unwrap();
+    assert_eq!(oper.tree.get_root(), tree.get_root());
+
+    // nothing valid left, the tree stays as it was
+    let root = oper.tree.get_root();
+    oper.add_offchain_withdrawal(withdrawal(2, 10, 0, 1)).unwrap();
+    assert!(oper.execute_offchain_withdrawal_batch(0).is_err());
+    assert_eq!(oper.tree.get_root(), root);
+}
+
+#[test]
+pub fn typed_values() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+
+    let max = Balance(u128::MAX);
+    assert_eq!(Balance::try_from_fr(&max.to_fr()), Ok(max));
+    assert_eq!(Nonce::try_from_fr(&usize_to_fr(7)), Ok(Nonce(7)));
+
+    // values above the type range are rejected, not truncated
+    let above_u32 = Balance(u128::from(u32::MAX) + 1).to_fr();
+    assert_eq!(Nonce::try_from_fr(&above_u32), Err(RangeError("Nonce")));
+    assert_eq!(AccountId::try_from_fr(&above_u32), Err(RangeError("AccountId")));
+    let mut above_u128 = max.to_fr();
+    above_u128.add_assign(&bn256::Fr::one());
+    assert_eq!(Balance::try_from_fr(&above_u128), Err(RangeError("Balance")));
+
+    assert_eq!(max.checked_add(Balance(1)), None);
+    assert_eq!(Balance(1).checked_sub(Balance(2)), None);
+    assert_eq!(Balance(3).checked_sub(Balance(2)), Some(Balance(1)));
+    assert_eq!(Nonce(u32::MAX).next(), None);
+
+    let mut tree = AccountsTree::new(2, 1, hash_params, sign_params);
+    tree.update_balance(1, 0, usize_to_fr(50)).unwrap();
+    assert_eq!(tree.balance(AccountId(1), 0), Ok(Balance(50)));
+    assert_eq!(tree.nonce(AccountId(1)), Ok(Nonce(0)));
+    assert_eq!(tree.balance(AccountId(4), 0), Err(TreeError::AccountOutOfRange(4).into()));
+
+    tree.update_nonce(1, above_u32).unwrap();
+    assert_eq!(tree.nonce(AccountId(1)), Err(TreeError::OutOfRange(RangeError("Nonce")).into()));
+}
+
+#[test]
+pub fn nonce_overflow() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let domain = SigningDomain::default();
+    let seckey = SecretKey::from_seed(b"nonce overflow");
+    let pubkey = seckey.public_key(sign_params);
+
+    let mut tree = AccountsTree::new(2, 1, hash_params, sign_params);
+    OffchainDeposit { account_id: AccountId(1), pubkey: pubkey.clone(), token_id: 0, amount: Balance(100) }
+        .update_tree_and_record_state(&mut tree)
+        .unwrap();
+
+    // the first request signs nonce 1 and no nonce is skipped
+    let mismatch = |got| Err(TreeError::NonceMismatch { account_id: 1, expected: Nonce(1), got }.into());
+    assert_eq!(tree.check_next_nonce(1, Nonce(0)), mismatch(Nonce(0)));
+    assert_eq!(tree.check_next_nonce(1, Nonce(3)), mismatch(Nonce(3)));
+    assert_eq!(tree.check_next_nonce(1, Nonce(1)), Ok(Nonce(0)));
+    assert_eq!(TreeError::NonceMismatch { account_id: 1, expected: Nonce(1), got: Nonce(3) }.to_string(),
+        "Account 1 expects nonce 1, got 3");
+
+    let withdrawal = |nonce| {
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(0), nonce, valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+        };
+        seckey.sign_withdrawal(&mut withdrawal, &domain, hash_params, sign_params);
+        withdrawal
+    };
+
+    // a one withdrawal batch, the zero fee is credited to the empty account 0
+    let batch = |tree: &mut AccountsTree, queue, old_root| {
+        let fee_account_state = credit_fee_and_record_state(tree, AccountId(0), 0, Balance(0)).unwrap();
+        let accum_hash = poseidon_hash::<Bn256>(hash_params, &[
+            usize_to_fr(OFFCHAIN_WITHDRAWAL_OP), bn256::Fr::zero(), usize_to_fr(1), usize_to_fr(0), usize_to_fr(10),
+            address_to_fr(&ETH_ADDRESS),
+        ])[0];
+        OffchainWithdrawalBatchCircuit {
+            batch_size: 1,
+            account_depth: 2,
+            token_depth: 1,
+            hash_params,
+            sign_params,
+            signing_domain: domain,
+            reject_zero_amount: false,
+            queue: vec![queue],
+            fee_account_state,
+            fee_account_id: Some(usize_to_fr(0)),
+            fee_token_id: Some(usize_to_fr(0)),
+            total_fee: Some(usize_to_fr(0)),
+            timestamp: Some(usize_to_fr(0)),
+            old_accum_hash: Some(bn256::Fr::zero()),
+            new_accum_hash: Some(accum_hash),
+            old_account_root: Some(old_root),
+            new_account_root: Some(tree.get_root()),
+        }
+    };
+    let witness = |account_state, nonce, sign| OffchainWithdrawalCircuit::<Bn256> {
+        account_state,
+        account_id: Some(usize_to_fr(1)),
+        token_id: Some(usize_to_fr(0)),
+        amount: Some(usize_to_fr(10)),
+        fee: Some(usize_to_fr(0)),
+        nonce: Some(nonce),
+        valid_until: Some(usize_to_fr(0)),
+        eth_address: Some(address_to_fr(&ETH_ADDRESS)),
+        sign,
+        pubkey: Some(pubkey.0.clone()),
+        is_noop: Some(false),
+    };
+
+    // u32::MAX is the last nonce an account signs
+    tree.update_nonce(1, Nonce(u32::MAX - 1).to_fr()).unwrap();
+    let old_root = tree.get_root();
+    let last = withdrawal(Nonce(u32::MAX));
+    let account_state = last.update_tree_and_record_state(&mut tree).unwrap();
+    let queue = witness(account_state, last.nonce.to_fr(), last.sign.clone());
+    assert!(check_circuit(batch(&mut tree, queue, old_root)).is_ok());
+    assert_eq!(tree.nonce(AccountId(1)), Ok(Nonce(u32::MAX)));
+
+    // the next one would wrap around to 0
+    let root = tree.get_root();
+    assert_eq!(tree.check_next_nonce(1, Nonce(0)), Err(TreeError::NonceOverflow(1).into()));
+    assert_eq!(withdrawal(Nonce(0)).update_tree_and_record_state(&mut tree).err(), Some(TreeError::NonceOverflow(1).into()));
+    assert_eq!(tree.get_root(), root);
+
+    // as does a batch of updates, nothing of which is applied
+    let increment = LeafUpdate { account_id: 1, token_id: 0, pubkey: None, credit: 0, debit: 0, increment_nonce: true };
+    assert_eq!(tree.apply_batch(std::slice::from_ref(&increment)).err(), Some(TreeError::NonceOverflow(1).into()));
+    tree.update_nonce(1, Nonce(u32::MAX - 1).to_fr()).unwrap();
+    let before = tree.get_root();
+    assert_eq!(tree.apply_batch(&[increment.clone(), increment]).err(), Some(TreeError::NonceOverflow(1).into()));
+    assert_eq!(tree.get_root(), before);
+    tree.update_nonce(1, Nonce(u32::MAX).to_fr()).unwrap();
+    assert_eq!(tree.get_root(), root);
+
+    // and the circuit doesn't take 2^32, the field element after u32::MAX,
+    // even with a signature of it and a tree that holds it
+    let mut above_u32 = Nonce(u32::MAX).to_fr();
+    above_u32.add_assign(&bn256::Fr::one());
+    let account_path = (tree.get_leaf_path(1).unwrap(), tree.get_token_path(1, 0).unwrap());
+    let account_indices = (tree.get_leaf_indices(1).unwrap(), tree.get_token_indices(1, 0).unwrap());
+    tree.update_balance(1, 0, usize_to_fr(80)).unwrap();
+    tree.update_nonce(1, above_u32).unwrap();
+    let account_state = AccountState::from_transition(
+        AccountLeaf { pubkey: pubkey.0.clone(), nonce: Nonce(u32::MAX).to_fr(), balance: usize_to_fr(90) },
+        AccountLeaf { pubkey: pubkey.0.clone(), nonce: above_u32, balance: usize_to_fr(80) },
+        (&account_path.0, &account_path.1),
+        (&account_indices.0, &account_indices.1),
+    );
+
+    let [chain_id, rollup_address] = domain.to_fr();
+    let hash = poseidon_hash::<Bn256>(hash_params, &[
+        usize_to_fr(OFFCHAIN_WITHDRAWAL_OP), usize_to_fr(1), usize_to_fr(0), usize_to_fr(10), usize_to_fr(0),
+        above_u32, usize_to_fr(0), address_to_fr(&ETH_ADDRESS), chain_id, rollup_address,
+    ])[0];
+    let sign = seckey.expose_private_key().sign_raw_message(
+        &fr_to_sign_message(hash),
+        &mut thread_rng(),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+        NUM_BYTES_TO_SIGN,
+    );
+
+    let queue = witness(account_state, above_u32, Some(sign));
+    let unsatisfied = check_circuit(batch(&mut tree, queue, root)).unwrap_err();
+    assert!(unsatisfied.path.contains("check nonce overflow"), "{}", unsatisfied);
+}
+
+// s as an Fr, so it can be out of the Fs range
+fn synthesize_eddsa_verification(
+    pubkey: &PublicKey<Bn256>,
+    sign: &Signature<Bn256>,
+    s: bn256::Fr,
+    msg_hash: bn256::Fr,
+    sign_params: &AltJubjubBn256,
+) -> TestConstraintSystem<Bn256> {
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+
+    let pubkey_x = AllocatedNum::alloc(cs.namespace(|| "pubkey x"), || Ok(pubkey_x)).unwrap();
+    let pubkey_y = AllocatedNum::alloc(cs.namespace(|| "pubkey y"), || Ok(pubkey_y)).unwrap();
+    let r = EdwardsPoint::witness(cs.namespace(|| "r"), Some(sign.r.clone()), sign_params).unwrap();
+    let s = AllocatedNum::alloc(cs.namespace(|| "s"), || Ok(s)).unwrap();
+    let msg_hash = AllocatedNum::alloc(cs.namespace(|| "msg hash"), || Ok(msg_hash)).unwrap();
+
+    verify_eddsa(
+        cs.namespace(|| "verify eddsa"),
+        &pubkey_x,
+        &pubkey_y,
+        &r,
+        &s,
+        &msg_hash,
+        sign_params,
+    ).unwrap();
+
+    cs
+}
+
+#[test]
+pub fn eddsa_gadget_matches_offchain_signing() {
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1),
+        token_id: 0,
+        amount: Balance(10),
+        fee: Balance(0),
+        nonce: Nonce(1),
+        valid_until: 0,
+        eth_address: ETH_ADDRESS,
+        sign: None,
+    };
+    withdrawal.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+    let sign = withdrawal.sign.clone().unwrap();
+    let msg_hash = withdrawal.hash(&domain, None);
+
+    // signature made off-circuit verifies in-circuit
+
+    let cs = synthesize_eddsa_verification(&pubkey, &sign, fs_to_fr::<Bn256>(sign.s), msg_hash, sign_params);
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    // tampered s
+
+    let mut tampered_s = sign.s;
+    tampered_s.add_assign(&Fs::one());
+    let tampered = Signature::<Bn256> { r: sign.r.clone(), s: tampered_s };
+    let cs = synthesize_eddsa_verification(&pubkey, &tampered, fs_to_fr::<Bn256>(tampered.s), msg_hash, sign_params);
+    assert!(!cs.is_satisfied());
+
+    // tampered message
+
+    let mut tampered_hash = msg_hash;
+    tampered_hash.add_assign(&bn256::Fr::one());
+    let cs = synthesize_eddsa_verification(&pubkey, &sign, fs_to_fr::<Bn256>(sign.s), tampered_hash, sign_params);
+    assert!(!cs.is_satisfied());
+
+    // another public key
+
+    let other_pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+    let cs = synthesize_eddsa_verification(&other_pubkey, &sign, fs_to_fr::<Bn256>(sign.s), msg_hash, sign_params);
+    assert!(!cs.is_satisfied());
+}
+
+#[test]
+pub fn signature_scalar_range() {
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+
+    let seckey = PrivateKey::<Bn256>(thread_rng().gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1),
+        token_id: 0,
+        amount: Balance(10),
+        fee: Balance(0),
+        nonce: Nonce(1),
+        valid_until: 0,
+        eth_address: ETH_ADDRESS,
+        sign: None,
+    };
+    withdrawal.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+    let sign = withdrawal.sign.clone().unwrap();
+    let msg_hash = withdrawal.hash(&domain, None);
+
+    let s = fs_to_fr::<Bn256>(sign.s);
+    let cs = synthesize_eddsa_verification(&pubkey, &sign, s, msg_hash, sign_params);
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    // s + l has the same value in the scalar multiplication, only the range
+    // check refuses it
+
+    let order = bn256::Fr::from_repr(bn256::FrRepr(Fs::char().0)).unwrap();
+    let mut s_plus_order = s;
+    s_plus_order.add_assign(&order);
+    let cs = synthesize_eddsa_verification(&pubkey, &sign, s_plus_order, msg_hash, sign_params);
+    let unsatisfied = cs.which_is_unsatisfied().unwrap();
+    assert!(unsatisfied.contains("check s is below the subgroup order"), "{}", unsatisfied);
+}
+
+#[test]
+pub fn change_pubkey() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let old_seckey = PrivateKey::<Bn256>(rng.gen());
+    let old_pubkey = PublicKey::from_private(
+        &old_seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+    let new_seckey = PrivateKey::<Bn256>(rng.gen());
+    let new_pubkey = PublicKey::from_private(
+        &new_seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(old_pubkey.clone()),
+        account_id: 2,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let make_circuit = |tree: &mut AccountsTree, seckey: &PrivateKey<Bn256>| {
+        let mut change_pubkey = OffchainChangePubKey {
+            account_id: 2,
+            new_pubkey: new_pubkey.clone(),
+            nonce: Nonce(1),
+            sign: None,
+        };
+        change_pubkey.sign(seckey, hash_params, sign_params);
+
+        let (new_pubkey_x, new_pubkey_y) = new_pubkey.0.into_xy();
+        let old_hash = bn256::Fr::zero();
+        let new_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[usize_to_fr(CHANGE_PUBKEY_OP), old_hash, usize_to_fr(2), new_pubkey_x, new_pubkey_y],
+        )[0];
+
+        let old_root = tree.get_root();
+        let account_state = change_pubkey.update_tree_and_record_state(tree).unwrap();
+
+        ChangePubKeyBatchCircuit {
+            batch_size: 1,
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+            queue: vec![ChangePubKeyCircuit::<Bn256> {
+                account_state,
+                account_id: Some(usize_to_fr(2)),
+                new_pubkey: Some(new_pubkey.0.clone()),
+                nonce: Some(usize_to_fr(1)),
+                sign: change_pubkey.sign.clone(),
+                pubkey: Some(old_pubkey.0.clone()),
+            }],
+            old_accum_hash: Some(old_hash),
+            new_accum_hash: Some(new_hash),
+            old_account_root: Some(old_root),
+            new_account_root: Some(tree.get_root()),
+        }
+    };
+
+    // change signed with the new key is rejected
+
+    let mut forged_tree = tree.clone();
+    let circuit = make_circuit(&mut forged_tree, &new_seckey);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    // change signed with the old key updates the leaf
+
+    let circuit = make_circuit(&mut tree, &old_seckey);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    assert_eq!(tree.get_pubkey(2).unwrap().0.into_xy(), new_pubkey.0.into_xy());
+    assert_eq!(fr_to_usize(tree.get_nonce(2).unwrap()), Ok(1));
+    assert_eq!(fr_to_usize(tree.get_balance(2, 0).unwrap()), Ok(100));
+
+    // further requests are signed with the new key
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(2),
+        token_id: 0,
+        amount: Balance(10),
+        fee: Balance(0),
+        nonce: Nonce(2),
+        valid_until: 0,
+        eth_address: ETH_ADDRESS,
+        sign: None,
+    };
+    withdrawal.sign_with_thread_rng(&secret(&new_seckey), &domain, None, None);
+    assert_eq!(withdrawal.verify_signature(&tree.get_pubkey(2).unwrap(), &domain, None, None), Ok(()));
+}
+
+fn padded_deposit_batch_circuit(
+    tree: &mut AccountsTree,
+    deposits: &[Deposit],
+    deposit_batch: usize,
+    account_depth: usize,
+    token_depth: usize,
+    params: &Arc<Params<Bn256>>,
+) -> DepositBatchCircuit<Bn256> {
+    let old_hash = bn256::Fr::zero();
+    let old_root = tree.get_root();
+    let mut accum_hash = old_hash;
+    let config = BatchConfig { deposit_batch, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let mut builder = DepositBatchCircuit::builder(config, params);
+
+    for i in 0..deposit_batch {
+        let deposit = match deposits.get(i) {
+            Some(deposit) => DepositCircuit::<Bn256> {
+                account_state: deposit.update_tree_and_record_state(tree).unwrap(),
+                pubkey: Some(deposit.pubkey.clone().unwrap().0),
+                account_id: Some(usize_to_fr(deposit.account_id)),
+                token_id: Some(usize_to_fr(deposit.token_id)),
+                amount: Some(u128_to_fr(deposit.amount)),
+                is_noop: Some(false),
+                is_refund: Some(false),
+            },
+            None => DepositCircuit::<Bn256>::noop(account_depth, token_depth),
+        };
+
+        let (pubkey_x, pubkey_y) = deposit.pubkey.as_ref().unwrap().into_xy();
+        accum_hash = poseidon_hash::<Bn256>(
+            &params.hash_params,
+            &[
+                usize_to_fr(DEPOSIT_OP),
+                accum_hash,
+                pubkey_x,
+                pubkey_y,
+                deposit.account_id.unwrap(),
+                deposit.token_id.unwrap(),
+                deposit.amount.unwrap(),
+            ],
+        )[0];
+
+        builder = builder.push(deposit);
+    }
+
+    builder
+        .old_accum_hash(old_hash)
+        .new_accum_hash(accum_hash)
+        .old_account_root(old_root)
+        .new_account_root(tree.get_root())
+        .build()
+        .unwrap()
+}
+
+#[test]
+pub fn partially_filled_deposit_batch() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+    let deposit_batch = 8;
+
+    // one key per account, repeated deposits go to the same owner
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..(1 << account_depth)).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    let deposits: Vec<_> = (0..deposit_batch).map(|i| {
+        Deposit {
+            pubkey: Some(pubkeys[i % pubkeys.len()].clone()),
+            account_id: i % pubkeys.len(),
+            token_id: 0,
+            amount: 10,
+        }
+    }).collect();
+
+    // noop padding keeps the constraint system shape
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let old_root = tree.get_root();
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree, &deposits[..3], deposit_batch, account_depth, token_depth, &shared_params());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    let padded_constraints = cs.num_constraints();
+    assert!(old_root != tree.get_root());
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree, &deposits, deposit_batch, account_depth, token_depth, &shared_params());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    assert_eq!(cs.num_constraints(), padded_constraints);
+
+    // noop can't change the root
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let mut circuit = padded_deposit_batch_circuit(
+        &mut tree, &deposits[..3], deposit_batch, account_depth, token_depth, &shared_params());
+    circuit.new_account_root = Some(old_root);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    // prove batch with 3 real deposits
+
+    let dep_params = setup_deposit_circuit(deposit_batch, account_depth, token_depth, &shared_params()).unwrap();
+    let params = shared_params();
+    let mut oper = Operator::new(account_depth, token_depth, deposit_batch, 1, 1, 1, &params,
+        &dep_params, &dep_params, &dep_params, &dep_params);
+
+    for deposit in deposits[..3].iter() {
+        oper.add_deposit(deposit.clone()).unwrap();
+    }
+
+    let (public_inputs, proof) = oper.execute_deposit_batch().unwrap();
+    let verifying_key = prepare_verifying_key(&dep_params.vk);
+    assert!(verify_proof(&verifying_key, &proof, &public_inputs).unwrap());
+
+    assert_eq!(oper.deposit_queue.len(), 0);
+    assert_eq!(fr_to_usize(oper.tree.get_balance(0, 0).unwrap()), Ok(10));
+    assert_eq!(fr_to_usize(oper.tree.get_balance(2, 0).unwrap()), Ok(10));
+    assert_eq!(fr_to_usize(oper.tree.get_balance(3, 0).unwrap()), Ok(0));
+}
+
+#[test]
+pub fn deposit_batch_builder() {
+    let account_depth = 2;
+    let token_depth = 1;
+    let params = shared_params();
+    let config = BatchConfig { deposit_batch: 2, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+
+    let pubkey = SecretKey::from_seed(b"builder").public_key(jubjub_params());
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let deposit = Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 0, amount: 5 };
+    let expected = padded_deposit_batch_circuit(
+        &mut tree, &[deposit], config.deposit_batch, account_depth, token_depth, &params);
+    let witnesses = expected.deposit_queue.witnesses().unwrap().to_vec();
+
+    let filled = || DepositBatchCircuit::builder(config, &params)
+        .old_accum_hash(expected.old_accum_hash.unwrap())
+        .new_accum_hash(expected.new_accum_hash.unwrap())
+        .old_account_root(expected.old_account_root.unwrap())
+        .new_account_root(expected.new_account_root.unwrap());
+
+    let circuit = filled().push(witnesses[0].clone()).pad_with_noops().build().unwrap();
+    assert_eq!(shape(circuit.clone()).unwrap(), shape(expected.clone()).unwrap());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    // every error names what is missing or of the wrong length
+    let err = DepositBatchCircuit::builder(config, &params)
+        .old_accum_hash(bn256::Fr::zero())
+        .build()
+        .err()
+        .unwrap();
+    assert_eq!(err, BuildError::MissingField { deposit: None, field: "new_accum_hash" });
+    assert_eq!(err.to_string(), "new_accum_hash is not set");
+
+    assert_eq!(
+        filled().push(witnesses[0].clone()).build().err(),
+        Some(BuildError::WrongLength { deposit: None, field: "deposit_queue", expected: 2, actual: 1 }),
+    );
+
+    let mut no_amount = witnesses[0].clone();
+    no_amount.amount = None;
+    let err = filled().push(witnesses[1].clone()).push(no_amount).build().err().unwrap();
+    assert_eq!(err, BuildError::MissingField { deposit: Some(1), field: "amount" });
+    assert_eq!(err.to_string(), "amount of deposit 1 is not set");
+
+    let mut no_sibling = witnesses[0].clone();
+    no_sibling.account_state.account_path[1] = None;
+    assert_eq!(
+        filled().push(no_sibling).pad_with_noops().build().err(),
+        Some(BuildError::MissingField { deposit: Some(0), field: "account_path" }),
+    );
+
+    let mut short_path = witnesses[0].clone();
+    short_path.account_state.token_path.pop();
+    let err = filled().push(short_path).pad_with_noops().build().err().unwrap();
+    assert_eq!(err, BuildError::WrongLength { deposit: Some(0), field: "token_path", expected: 1, actual: 0 });
+    assert_eq!(err.to_string(), "token_path length of deposit 0 is 0, expected 1");
+
+    // setup time states have no witness at all
+    let empty = AccountState::<Bn256>::empty(account_depth, token_depth);
+    assert_eq!(empty.missing_field(), Some("old_balance"));
+    assert_eq!(empty.account_path.len(), account_depth);
+    assert_eq!(witnesses[0].account_state.missing_field(), None);
+
+    // a transition recorded by hand is the one the tree records
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let old_pubkey = tree.get_pubkey(1).unwrap();
+    let path = (tree.get_leaf_path(1).unwrap(), tree.get_token_path(1, 0).unwrap());
+    let indices = (tree.get_leaf_indices(1).unwrap(), tree.get_token_indices(1, 0).unwrap());
+    let recorded = OffchainDeposit { account_id: AccountId(1), pubkey: pubkey.clone(), token_id: 0, amount: Balance(5) }
+        .update_tree_and_record_state(&mut tree)
+        .unwrap();
+    let state = AccountState::from_transition(
+        AccountLeaf { pubkey: old_pubkey.0, nonce: bn256::Fr::zero(), balance: bn256::Fr::zero() },
+        AccountLeaf { pubkey: pubkey.0, nonce: bn256::Fr::zero(), balance: usize_to_fr(5) },
+        (&path.0, &path.1),
+        (&indices.0, &indices.1),
+    );
+    assert!(state == recorded);
+}
+
+#[test]
+pub fn deposit_amount_wrap_around_rejected() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    // record a zero deposit, then forge amount = -90 so that 100 + amount = 10 in the field
+
+    let mut circuit = padded_deposit_batch_circuit(
+        &mut tree,
+        &[Deposit { pubkey: Some(pubkey.clone()), account_id: 0, token_id: 0, amount: 0 }],
+        1, account_depth, token_depth, &shared_params(),
+    );
+
+    let mut amount = bn256::Fr::zero();
+    amount.sub_assign(&usize_to_fr(90));
+    circuit.deposit_queue.witnesses_mut().unwrap()[0].amount = Some(amount);
+    circuit.deposit_queue.witnesses_mut().unwrap()[0].account_state.new_balance = Some(usize_to_fr(10));
+
+    tree.update_balance(0, 0, usize_to_fr(10)).unwrap();
+    circuit.new_account_root = Some(tree.get_root());
+
+    let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+    circuit.new_accum_hash = Some(poseidon_hash::<Bn256>(
+        hash_params,
+        &[usize_to_fr(DEPOSIT_OP), bn256::Fr::zero(), pubkey_x, pubkey_y, usize_to_fr(0), usize_to_fr(0), amount],
+    )[0]);
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    let unsatisfied = cs.which_is_unsatisfied().unwrap();
+    assert!(unsatisfied.contains("check amount overflow"));
+}
+
+#[test]
+pub fn deposit_pubkey_overwrite_rejected() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..2).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    let deposit = |pubkey: &PublicKey<Bn256>, account_id| Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id,
+        token_id: 0,
+        amount: 10,
+    };
+
+    // empty leaf and the same pubkey are accepted
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree,
+        &[deposit(&pubkeys[0], 1), deposit(&pubkeys[0], 1)],
+        2, account_depth, token_depth, &shared_params(),
+    );
+
+    let report = check_circuit(circuit.clone()).unwrap();
+    assert_eq!(report.num_constraints, measure(circuit).unwrap().constraints);
+    assert_eq!(report.num_inputs, 5);
+
+    // occupied leaf with a different pubkey is rejected, the first deposit to an
+    // empty leaf is fine
+
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree,
+        &[deposit(&pubkeys[1], 2), deposit(&pubkeys[1], 1)],
+        2, account_depth, token_depth, &shared_params(),
+    );
+
+    let unsatisfied = check_circuit(circuit).unwrap_err();
+    assert_eq!(unsatisfied.operation(), Some(1));
+    assert!(unsatisfied.path.starts_with("verify deposit 1/"));
+    assert!(unsatisfied.path.ends_with("check pubkey not overwritten"));
+    assert!(unsatisfied.to_string().starts_with("Operation 1 is unsatisfied at verify deposit 1/"));
+
+    assert_eq!(which_operation("verify deposit 13/allocate account circuit/allocate accounts tree"), Some(13));
+    assert_eq!(which_operation("verify close account 2/check nonce"), Some(2));
+    assert_eq!(which_operation("enforce new root equivalence"), None);
+    assert_eq!(which_operation("allocate public inputs/old accum hash"), None);
+}
+
+#[test]
+pub fn deposit_pubkey_subgroup_check() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    // shift by the order 2 point (0, -1), still on curve but out of the subgroup
+
+    let mut minus_one = bn256::Fr::zero();
+    minus_one.sub_assign(&bn256::Fr::one());
+    let torsion = Point::<Bn256, _>::get_for_y(minus_one, false, sign_params).unwrap();
+    let deposit = Deposit {
+        pubkey: Some(PublicKey(pubkey.0.add(&torsion, sign_params))),
+        account_id: 1,
+        token_id: 0,
+        amount: 10,
+    };
+    assert!(!deposit.is_valid_pubkey(sign_params));
+
+    // operator rejects it before building the witness
+
+    let dummy_params = setup_deposit_circuit(1, account_depth, token_depth, &shared_params()).unwrap();
+    let params = shared_params();
+    let mut oper = Operator::new(account_depth, token_depth, 1, 1, 1, 1, &params,
+        &dummy_params, &dummy_params, &dummy_params, &dummy_params);
+    assert!(oper.add_deposit(deposit.clone()).is_err());
+
+    // and the circuit doesn't accept it
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree, &[deposit], 1, account_depth, token_depth, &shared_params());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    let unsatisfied = cs.which_is_unsatisfied().unwrap();
+    assert!(unsatisfied.contains("check subgroup"));
+
+    // point off the curve
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let x = AllocatedNum::alloc(cs.namespace(|| "x"), || Ok(bn256::Fr::one())).unwrap();
+    let y = AllocatedNum::alloc(cs.namespace(|| "y"), || Ok(bn256::Fr::one())).unwrap();
+    check_pubkey(cs.namespace(|| "check pubkey"), &x, &y, sign_params).unwrap();
+    let unsatisfied = cs.which_is_unsatisfied().unwrap();
+    assert!(unsatisfied.contains("on curve check"));
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let (x, y) = pubkey.0.into_xy();
+    let x = AllocatedNum::alloc(cs.namespace(|| "x"), || Ok(x)).unwrap();
+    let y = AllocatedNum::alloc(cs.namespace(|| "y"), || Ok(y)).unwrap();
+    check_pubkey(cs.namespace(|| "check pubkey"), &x, &y, sign_params).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+}
+
+#[test]
+pub fn witness_length_mismatch_is_an_error() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    // batch size doesn't match the queue
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let mut circuit = padded_deposit_batch_circuit(
+        &mut tree, &[], 2, account_depth, token_depth, &shared_params());
+    circuit.deposit_batch = 3;
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    match circuit.synthesize(&mut cs) {
+        Err(SynthesisError::Unsatisfiable) => {},
+        _ => panic!("expected unsatisfiable batch"),
+    }
+
+    // account path shorter than the tree depth
+
+    let mut account_state = DepositCircuit::<Bn256>::noop(account_depth, token_depth).account_state;
+    account_state.account_path.pop();
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let result = AccountCircuit::new(
+        cs.namespace(|| "allocate account circuit"),
+        account_depth,
+        token_depth,
+        hash_params,
+        &account_state,
+    );
+    match result {
+        Err(SynthesisError::IoError(err)) => assert!(err.to_string().contains("account path")),
+        _ => panic!("expected account path length error"),
+    }
+}
+
+#[test]
+pub fn account_id_out_of_depth_range() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let check = |num: usize, bits: &[bool]| {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let num = AllocatedNum::alloc(cs.namespace(|| "num"), || Ok(usize_to_fr(num))).unwrap();
+        let bits: Vec<_> = bits.iter().enumerate().map(|(i, bit)| Boolean::from(
+            AllocatedBit::alloc(cs.namespace(|| format!("bit {}", i)), Some(*bit)).unwrap()
+        )).collect();
+        check_decomposition_le(cs.namespace(|| "check decomposition"), &num, &bits).unwrap();
+        cs.is_satisfied()
+    };
+
+    assert!(check(2, &[false, true]));
+    assert!(!check(1 << account_depth, &[false, false]));
+    assert!(!check((1 << account_depth) + 2, &[false, true]));
+
+    // deposit to account 2^depth would alias leaf 0
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let mut circuit = padded_deposit_batch_circuit(
+        &mut tree,
+        &[Deposit { pubkey: Some(pubkey.clone()), account_id: 0, token_id: 0, amount: 10 }],
+        1, account_depth, token_depth, &shared_params(),
+    );
+
+    let account_id = usize_to_fr(1 << account_depth);
+    circuit.deposit_queue.witnesses_mut().unwrap()[0].account_id = Some(account_id);
+
+    let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+    circuit.new_accum_hash = Some(poseidon_hash::<Bn256>(
+        hash_params,
+        &[usize_to_fr(DEPOSIT_OP), bn256::Fr::zero(), pubkey_x, pubkey_y, account_id, usize_to_fr(0), usize_to_fr(10)],
+    )[0]);
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    let unsatisfied = cs.which_is_unsatisfied().unwrap();
+    assert!(unsatisfied.contains("account id consistence"));
+}
+
+#[test]
+pub fn deposit_batch_stats() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+    let deposit_batch = 3;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+    let deposits: Vec<_> = (0..deposit_batch).map(|account_id| Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id,
+        token_id: 0,
+        amount: 10,
+    }).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree, &deposits, deposit_batch, account_depth, token_depth, &shared_params());
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    // counting system agrees with the test one, with or without witness
+
+    let stats = measure(circuit).unwrap();
+    assert_eq!(stats.constraints, cs.num_constraints());
+    assert_eq!(stats.input_variables, cs.num_inputs());
+
+    let empty = DepositBatchCircuit::empty(
+        deposit_batch, account_depth, token_depth, &shared_params());
+    assert_eq!(measure(empty).unwrap(), stats);
+
+    let estimated = DepositBatchCircuit::estimated_constraints(
+        deposit_batch, account_depth, token_depth, &shared_params()).unwrap();
+    assert_eq!(estimated, stats.constraints);
+}
+
+#[test]
+pub fn public_inputs_round_trip() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree,
+        &[Deposit { pubkey: Some(pubkey), account_id: 1, token_id: 0, amount: 10 }],
+        1, account_depth, token_depth, &shared_params(),
+    );
+
+    let public_inputs = PublicInputs::<Bn256>::new(
+        circuit.old_accum_hash.unwrap(),
+        circuit.new_accum_hash.unwrap(),
+        circuit.old_account_root.unwrap(),
+        circuit.new_account_root.unwrap(),
+    );
+    assert_eq!(public_inputs.to_be_bytes().len(), 4 * 32);
+
+    let params = setup_deposit_circuit(1, account_depth, token_depth, &shared_params()).unwrap();
+    let proof = create_random_proof(circuit, &params, &mut rng).unwrap();
+    let verifying_key = prepare_verifying_key(&params.vk);
+    assert!(verify_block_proof(&verifying_key, &proof, &public_inputs).unwrap());
+
+    // any other order doesn't verify
+
+    let swapped = PublicInputs::<Bn256>::new(
+        public_inputs.old_account_root,
+        public_inputs.new_account_root,
+        public_inputs.old_accum_hash,
+        public_inputs.new_accum_hash,
+    );
+    assert!(!verify_block_proof(&verifying_key, &proof, &swapped).unwrap());
+}
+
+#[test]
+pub fn committed_deposit_batch() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let batch = padded_deposit_batch_circuit(
+        &mut tree,
+        &[Deposit { pubkey: Some(pubkey), account_id: 1, token_id: 0, amount: 10 }],
+        1, account_depth, token_depth, &shared_params(),
+    );
+
+    let public_inputs = PublicInputs::<Bn256>::new(
+        batch.old_accum_hash.unwrap(),
+        batch.new_accum_hash.unwrap(),
+        batch.old_account_root.unwrap(),
+        batch.new_account_root.unwrap(),
+    );
+    let commitment = compute_block_commitment(hash_params, &public_inputs);
+
+    // the uncommitted batch exposes all four values, the committed one only the hash
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    batch.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+    assert_eq!(cs.num_inputs(), 5);
+
+    let circuit = CommittedDepositBatchCircuit { batch };
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+    assert_eq!(cs.num_inputs(), 2);
+    assert_eq!(cs.get_input(1, "allocate public inputs/input block commitment/input variable"), commitment);
+
+    let params = generate_random_parameters(
+        CommittedDepositBatchCircuit {
+            batch: DepositBatchCircuit::empty(1, account_depth, token_depth, &shared_params()),
+        },
+        &mut rng,
+    ).unwrap();
+    let proof = create_random_proof(circuit, &params, &mut rng).unwrap();
+    let verifying_key = prepare_verifying_key(&params.vk);
+    assert!(verify_proof(&verifying_key, &proof, &[commitment]).unwrap());
+    assert!(!verify_proof(&verifying_key, &proof, &public_inputs.to_vec()).unwrap_or(false));
+}
+
+#[test]
+pub fn full_exit() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    // exit of the funded account and of the empty one
+
+    let old_hash = bn256::Fr::zero();
+    let old_root = tree.get_root();
+    let mut new_hash = old_hash;
+    let mut queue = Vec::new();
+    let mut amounts = Vec::new();
+
+    for &account_id in [1, 3].iter() {
+        let exit_pubkey = tree.get_pubkey(account_id).unwrap();
+        let (account_state, amount) = FullExit { account_id, token_id: 0 }
+            .update_tree_and_record_state(&mut tree).unwrap();
+
+        new_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[usize_to_fr(FULL_EXIT_OP), new_hash, usize_to_fr(account_id), usize_to_fr(0), pack_point(&exit_pubkey.0), u128_to_fr(amount)],
+        )[0];
+
+        queue.push(FullExitCircuit::<Bn256> {
+            account_state,
+            account_id: Some(usize_to_fr(account_id)),
+            token_id: Some(usize_to_fr(0)),
+        });
+        amounts.push(amount);
+    }
+
+    assert_eq!(amounts, vec![100, 0]);
+    assert_eq!(fr_to_usize(tree.get_balance(1, 0).unwrap()), Ok(0));
+
+    let circuit = FullExitBatchCircuit {
+        batch_size: 2,
+        account_depth,
+        token_depth,
+        hash_params,
+        queue,
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(new_hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    };
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    // exit must withdraw the whole balance
+
+    let mut partial = circuit;
+    partial.queue[0].account_state.new_balance = Some(usize_to_fr(50));
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    partial.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+}
+
+#[test]
+pub fn two_tokens_in_one_account() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    // deposit both tokens to the same account
+
+    let deposits: Vec<_> = [(0, 100), (1, 50)].iter().map(|&(token_id, amount)| Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 1,
+        token_id,
+        amount,
+    }).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree, &deposits, 2, account_depth, token_depth, &shared_params());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    assert_eq!(fr_to_usize(tree.get_balance(1, 0).unwrap()), Ok(100));
+    assert_eq!(fr_to_usize(tree.get_balance(1, 1).unwrap()), Ok(50));
+
+    // withdraw the second token only
+
+    let make_circuit = |tree: &mut AccountsTree, token_id: usize| {
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(1),
+            token_id: 1,
+            amount: Balance(20),
+            fee: Balance(0),
+            nonce: Nonce(1),
+            valid_until: 0,
+            eth_address: ETH_ADDRESS,
+            sign: None,
+        };
+        withdrawal.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+
+        let old_hash = bn256::Fr::zero();
+        let new_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[
+                usize_to_fr(OFFCHAIN_WITHDRAWAL_OP), old_hash, usize_to_fr(1), usize_to_fr(1), usize_to_fr(20),
+                address_to_fr(&ETH_ADDRESS),
+            ],
+        )[0];
+
+        let old_root = tree.get_root();
+        let account_state = withdrawal.update_tree_and_record_state(tree).unwrap();
+        let fee_account_state = credit_fee_and_record_state(tree, AccountId(0), 0, Balance(0)).unwrap();
+
+        OffchainWithdrawalBatchCircuit {
+            batch_size: 1,
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+            signing_domain: domain,
+            reject_zero_amount: false,
+            queue: vec![OffchainWithdrawalCircuit::<Bn256> {
+                account_state,
+                account_id: Some(usize_to_fr(1)),
+                token_id: Some(usize_to_fr(token_id)),
+                amount: Some(usize_to_fr(20)),
+                fee: Some(usize_to_fr(0)),
+                nonce: Some(usize_to_fr(1)),
+                valid_until: Some(usize_to_fr(0)),
+                eth_address: Some(address_to_fr(&ETH_ADDRESS)),
+                sign: withdrawal.sign.clone(),
+                pubkey: Some(pubkey.0.clone()),
+                is_noop: Some(false),
+            }],
+            fee_account_state,
+            fee_account_id: Some(usize_to_fr(0)),
+            fee_token_id: Some(usize_to_fr(0)),
+            total_fee: Some(usize_to_fr(0)),
+            timestamp: Some(usize_to_fr(0)),
+            old_accum_hash: Some(old_hash),
+            new_accum_hash: Some(new_hash),
+            old_account_root: Some(old_root),
+            new_account_root: Some(tree.get_root()),
+        }
+    };
+
+    // token id must match the balance leaf position
+
+    let circuit = make_circuit(&mut tree.clone(), 0);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let circuit = make_circuit(&mut tree, 1);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    assert_eq!(fr_to_usize(tree.get_balance(1, 0).unwrap()), Ok(100));
+    assert_eq!(fr_to_usize(tree.get_balance(1, 1).unwrap()), Ok(30));
+}
+
+#[test]
+pub fn offchain_withdrawal_expiry() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let make_circuit = |valid_until: usize, timestamp: usize| {
+        let mut tree = tree.clone();
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(1),
+            token_id: 0,
+            amount: Balance(20),
+            fee: Balance(0),
+            nonce: Nonce(1),
+            valid_until,
+            eth_address: ETH_ADDRESS,
+            sign: None,
+        };
+        withdrawal.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+
+        let old_hash = bn256::Fr::zero();
+        let new_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[
+                usize_to_fr(OFFCHAIN_WITHDRAWAL_OP), old_hash, usize_to_fr(1), usize_to_fr(0), usize_to_fr(20),
+                address_to_fr(&ETH_ADDRESS),
+            ],
+        )[0];
+
+        let old_root = tree.get_root();
+        let account_state = withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+        let fee_account_state = credit_fee_and_record_state(&mut tree, AccountId(0), 0, Balance(0)).unwrap();
+
+        OffchainWithdrawalBatchCircuit {
+            batch_size: 1,
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+            signing_domain: domain,
+            reject_zero_amount: false,
+            queue: vec![OffchainWithdrawalCircuit::<Bn256> {
+                account_state,
+                account_id: Some(usize_to_fr(1)),
+                token_id: Some(usize_to_fr(0)),
+                amount: Some(usize_to_fr(20)),
+                fee: Some(usize_to_fr(0)),
+                nonce: Some(usize_to_fr(1)),
+                valid_until: Some(usize_to_fr(valid_until)),
+                eth_address: Some(address_to_fr(&ETH_ADDRESS)),
+                sign: withdrawal.sign.clone(),
+                pubkey: Some(pubkey.0.clone()),
+                is_noop: Some(false),
+            }],
+            fee_account_state,
+            fee_account_id: Some(usize_to_fr(0)),
+            fee_token_id: Some(usize_to_fr(0)),
+            total_fee: Some(usize_to_fr(0)),
+            timestamp: Some(usize_to_fr(timestamp)),
+            old_accum_hash: Some(old_hash),
+            new_accum_hash: Some(new_hash),
+            old_account_root: Some(old_root),
+            new_account_root: Some(tree.get_root()),
+        }
+    };
+
+    // valid until the timestamp inclusive, 0 never expires
+
+    for &(valid_until, timestamp, is_valid) in [
+        (100, 99, true),
+        (100, 100, true),
+        (100, 101, false),
+        (0, 1_000_000, true),
+    ].iter() {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        make_circuit(valid_until, timestamp).synthesize(&mut cs).unwrap();
+        assert_eq!(cs.is_satisfied(), is_valid, "valid until {}, timestamp {}", valid_until, timestamp);
+    }
+
+    // the operator can't drop the expiry, it is signed
+
+    let mut circuit = make_circuit(100, 101);
+    circuit.queue[0].valid_until = Some(usize_to_fr(0));
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(20), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 100, eth_address: ETH_ADDRESS, sign: None,
+    };
+    assert!(!withdrawal.is_expired(100));
+    assert!(withdrawal.is_expired(101));
+}
+
+#[test]
+pub fn conditional_transfer() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let preimage: bn256::Fr = rng.gen();
+    let mut transfer = OffchainConditionalTransfer {
+        account_id_from: 1,
+        account_id_to: 2,
+        token_id: 0,
+        amount: 30,
+        nonce: Nonce(1),
+        hash_lock: OffchainConditionalTransfer::hash_lock(preimage, hash_params),
+        valid_until: 100,
+        sign: None,
+    };
+    transfer.sign(&seckey, hash_params, sign_params);
+    assert!(transfer.verify_signature(&pubkey, hash_params, sign_params).is_ok());
+
+    // the tree follows the witness path, so only the preimage and timeout checks may fail
+
+    let make_circuit = |timestamp: usize, preimage: bn256::Fr, is_refund: bool| {
+        let mut tree = tree.clone();
+        let path_timestamp = if is_refund { transfer.valid_until + 1 } else { transfer.valid_until };
+        let transferred = if is_refund { 0 } else { transfer.amount };
+
+        let old_hash = bn256::Fr::zero();
+        let new_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[usize_to_fr(CONDITIONAL_TRANSFER_OP), old_hash, usize_to_fr(1), usize_to_fr(2),
+                usize_to_fr(0), u128_to_fr(transferred)],
+        )[0];
+
+        let old_root = tree.get_root();
+        let (account_state_from, account_state_to) =
+            transfer.update_tree_and_record_state(&mut tree, path_timestamp).unwrap();
+
+        let circuit = ConditionalTransferBatchCircuit {
+            batch_size: 1,
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+            queue: vec![ConditionalTransferCircuit::<Bn256> {
+                account_state_from,
+                account_state_to,
+                account_id_from: Some(usize_to_fr(1)),
+                account_id_to: Some(usize_to_fr(2)),
+                token_id: Some(usize_to_fr(0)),
+                amount: Some(usize_to_fr(30)),
+                nonce: Some(usize_to_fr(1)),
+                hash_lock: Some(transfer.hash_lock),
+                valid_until: Some(usize_to_fr(100)),
+                preimage: Some(preimage),
+                is_refund: Some(is_refund),
+                sign: transfer.sign.clone(),
+                pubkey: Some(pubkey.0.clone()),
+            }],
+            timestamp: Some(usize_to_fr(timestamp)),
+            old_accum_hash: Some(old_hash),
+            new_accum_hash: Some(new_hash),
+            old_account_root: Some(old_root),
+            new_account_root: Some(tree.get_root()),
+        };
+
+        (circuit, tree)
+    };
+
+    // claim with the preimage until valid until
+
+    let (circuit, claimed) = make_circuit(100, preimage, false);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    assert_eq!(cs.num_inputs(), 6);
+    assert_eq!(fr_to_usize(claimed.get_balance(1, 0).unwrap()), Ok(70));
+    assert_eq!(fr_to_usize(claimed.get_balance(2, 0).unwrap()), Ok(30));
+
+    let (circuit, _) = make_circuit(100, rng.gen(), false);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    // refund after valid until, the preimage is not needed
+
+    let (circuit, refunded) = make_circuit(101, bn256::Fr::zero(), true);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    assert_eq!(fr_to_usize(refunded.get_balance(1, 0).unwrap()), Ok(100));
+    assert_eq!(fr_to_usize(refunded.get_balance(2, 0).unwrap()), Ok(0));
+    assert_eq!(fr_to_usize(refunded.get_nonce(1).unwrap()), Ok(1));
+
+    // neither path is available out of its time window
+
+    let (circuit, _) = make_circuit(101, preimage, false);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let (circuit, _) = make_circuit(100, preimage, true);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    // the receiver's overflow is found before the sender's leaf is written
+
+    let mut full = tree.clone();
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 2,
+        token_id: 0,
+        amount: u128::MAX,
+    }.update_tree_and_record_state(&mut full).unwrap();
+    let root = full.get_root();
+    assert_eq!(
+        transfer.update_tree_and_record_state(&mut full, 100).err(),
+        Some(TreeError::BalanceOverflow { account_id: 2, token_id: 0 }.into()),
+    );
+    assert_eq!(full.get_root(), root);
+
+    let to_itself = OffchainConditionalTransfer { account_id_to: 1, ..transfer.clone() };
+    assert_eq!(to_itself.update_tree_and_record_state(&mut full, 100).err(), Some(TreeError::SelfTransfer(1).into()));
+    assert_eq!(full.get_root(), root);
+}
+
+fn swap_batch_circuit<'a>(
+    tree: &AccountsTree,
+    swap: &Swap,
+    account_states: [AccountState<Bn256>; 4],
+    old_root: bn256::Fr,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &'a AltJubjubBn256,
+) -> SwapBatchCircuit<'a, Bn256> {
+    let old_hash = bn256::Fr::zero();
+    let new_hash = poseidon_hash::<Bn256>(
+        hash_params,
+        &[
+            usize_to_fr(SWAP_OP),
+            old_hash,
+            usize_to_fr(swap.a.account_id),
+            usize_to_fr(swap.a.token_id),
+            u128_to_fr(swap.a.amount),
+            usize_to_fr(swap.b.account_id),
+            usize_to_fr(swap.b.token_id),
+            u128_to_fr(swap.b.amount),
+        ],
+    )[0];
+
+    SwapBatchCircuit {
+        batch_size: 1,
+        account_depth: tree.accounts_tree.depth(),
+        token_depth: tree.accounts[0].balances_tree.depth(),
+        hash_params,
+        sign_params,
+        queue: vec![SwapCircuit::<Bn256> {
+            account_states,
+            account_id_a: Some(usize_to_fr(swap.a.account_id)),
+            token_id_a: Some(usize_to_fr(swap.a.token_id)),
+            amount_a: Some(u128_to_fr(swap.a.amount)),
+            nonce_a: Some(swap.a.nonce.to_fr()),
+            sign_a: swap.a.sign.clone(),
+            pubkey_a: Some(tree.get_pubkey(swap.a.account_id).unwrap().0),
+            account_id_b: Some(usize_to_fr(swap.b.account_id)),
+            token_id_b: Some(usize_to_fr(swap.b.token_id)),
+            amount_b: Some(u128_to_fr(swap.b.amount)),
+            nonce_b: Some(swap.b.nonce.to_fr()),
+            sign_b: swap.b.sign.clone(),
+            pubkey_b: Some(tree.get_pubkey(swap.b.account_id).unwrap().0),
+        }],
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(new_hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    }
+}
+
+#[test]
+pub fn atomic_swap() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckeys: Vec<_> = (0..2).map(|_| PrivateKey::<Bn256>(rng.gen())).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| PublicKey::from_private(
+        seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    // account 1 has token 0, account 2 has token 1
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    for (i, &(account_id, token_id, amount)) in [(1, 0, 100), (2, 1, 50)].iter().enumerate() {
+        Deposit {
+            pubkey: Some(pubkeys[i].clone()),
+            account_id,
+            token_id,
+            amount,
+        }.update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    let mut swap = Swap {
+        a: SwapHalf { account_id: 1, token_id: 0, amount: 30, nonce: Nonce(1), sign: None },
+        b: SwapHalf { account_id: 2, token_id: 1, amount: 20, nonce: Nonce(1), sign: None },
+    };
+    swap.sign_a(&seckeys[0], hash_params, sign_params);
+    swap.sign_b(&seckeys[1], hash_params, sign_params);
+    assert!(swap.is_valid());
+    assert!(swap.verify_signatures(&pubkeys[0], &pubkeys[1], hash_params, sign_params).is_ok());
+    assert!(swap.verify_signatures(&pubkeys[1], &pubkeys[0], hash_params, sign_params).is_err());
+
+    let mut swapped = tree.clone();
+    let account_states = swap.update_tree_and_record_state(&mut swapped).unwrap();
+    let circuit = swap_batch_circuit(
+        &swapped, &swap, account_states, tree.get_root(), hash_params, sign_params);
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    assert_eq!(fr_to_usize(swapped.get_balance(1, 0).unwrap()), Ok(70));
+    assert_eq!(fr_to_usize(swapped.get_balance(1, 1).unwrap()), Ok(20));
+    assert_eq!(fr_to_usize(swapped.get_balance(2, 0).unwrap()), Ok(30));
+    assert_eq!(fr_to_usize(swapped.get_balance(2, 1).unwrap()), Ok(30));
+    assert_eq!(fr_to_usize(swapped.get_nonce(1).unwrap()), Ok(1));
+    assert_eq!(fr_to_usize(swapped.get_nonce(2).unwrap()), Ok(1));
+
+    // b can't pay, so nothing is written, a's payment included
+
+    let overdrawn = Swap { a: swap.a.clone(), b: SwapHalf { amount: 60, ..swap.b.clone() } };
+    let mut unchanged = tree.clone();
+    assert_eq!(
+        overdrawn.update_tree_and_record_state(&mut unchanged).err(),
+        Some(TreeError::InsufficientBalance { account_id: 2, token_id: 1 }.into()),
+    );
+    assert_eq!(unchanged.get_root(), tree.get_root());
+
+    // both halves must be signed by their own party
+
+    let mut forged = circuit;
+    forged.queue[0].sign_b = swap.a.sign.clone();
+    forged.queue[0].pubkey_b = Some(pubkeys[0].0.clone());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    forged.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    // self swap: rejected off-circuit, and unsatisfiable with a consistent witness
+    // of the same chained updates
+
+    let mut self_swap = Swap {
+        a: SwapHalf { account_id: 1, token_id: 0, amount: 30, nonce: Nonce(1), sign: None },
+        b: SwapHalf { account_id: 1, token_id: 1, amount: 0, nonce: Nonce(2), sign: None },
+    };
+    self_swap.sign_a(&seckeys[0], hash_params, sign_params);
+    self_swap.sign_b(&seckeys[0], hash_params, sign_params);
+    assert!(!self_swap.is_valid());
+    assert_eq!(self_swap.update_tree_and_record_state(&mut tree.clone()).err(), Some(TreeError::SelfTransfer(1).into()));
+
+    let mut self_swapped = tree.clone();
+    let debit = |tree: &mut AccountsTree, half: &SwapHalf| OffchainWithdrawal {
+        account_id: AccountId(half.account_id as u32),
+        token_id: half.token_id,
+        amount: Balance(half.amount),
+        fee: Balance(0),
+        nonce: half.nonce,
+        valid_until: 0,
+        eth_address: ETH_ADDRESS,
+        sign: None,
+    }.update_tree_and_record_state(tree).unwrap();
+    let account_states = [
+        debit(&mut self_swapped, &self_swap.a),
+        credit_fee_and_record_state(&mut self_swapped, AccountId(1), 0, Balance(30)).unwrap(),
+        debit(&mut self_swapped, &self_swap.b),
+        credit_fee_and_record_state(&mut self_swapped, AccountId(1), 1, Balance(0)).unwrap(),
+    ];
+    let circuit = swap_batch_circuit(
+        &self_swapped, &self_swap, account_states, tree.get_root(), hash_params, sign_params);
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let result = circuit.synthesize(&mut cs);
+    assert!(result.is_err() || !cs.is_satisfied());
+}
+
+#[test]
+pub fn delegated_withdrawal() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckeys: Vec<_> = (0..2).map(|_| PrivateKey::<Bn256>(rng.gen())).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| PublicKey::from_private(
+        seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+    let (owner_seckey, spender_seckey) = (&seckeys[0], &seckeys[1]);
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkeys[0].clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let mut permit = WithdrawalPermit::new(1, pubkeys[1].clone(), 50, 100, 1);
+    permit.sign(owner_seckey, hash_params, sign_params);
+    assert!(permit.verify_signature(&pubkeys[0], hash_params, sign_params).is_ok());
+    assert!(permit.verify_signature(&pubkeys[1], hash_params, sign_params).is_err());
+
+    // serialized permit is the same permit
+
+    let mut bytes = Vec::new();
+    permit.write(&mut bytes).unwrap();
+    let decoded = WithdrawalPermit::read(&bytes[..], sign_params).unwrap();
+    assert_eq!(decoded.hash(hash_params), permit.hash(hash_params));
+    assert!(decoded.verify_signature(&pubkeys[0], hash_params, sign_params).is_ok());
+    assert!(WithdrawalPermit::new(1, pubkeys[1].clone(), 50, 100, 1).write(&mut Vec::new()).is_err());
+
+    let make_circuit = |
+        permit: &WithdrawalPermit,
+        amount: usize,
+        spender_seckey: &PrivateKey<Bn256>,
+        timestamp: usize,
+    | {
+        let mut tree = tree.clone();
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(1),
+            token_id: 0,
+            amount: Balance(amount as u128),
+            fee: Balance(0),
+            nonce: Nonce(1),
+            valid_until: 0,
+            eth_address: ETH_ADDRESS,
+            sign: None,
+        };
+        withdrawal.sign_with_thread_rng(&secret(spender_seckey), &domain, None, None);
+        let spender_pubkey = PublicKey::from_private(
+            spender_seckey,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+        );
+        let allowed = permit.allows(&withdrawal, timestamp)
+            && withdrawal.verify_signature(&permit.spender_pubkey, &domain, None, None).is_ok();
+
+        let old_hash = bn256::Fr::zero();
+        let new_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[
+                usize_to_fr(OFFCHAIN_WITHDRAWAL_OP), old_hash, usize_to_fr(1), usize_to_fr(0), usize_to_fr(amount),
+                address_to_fr(&ETH_ADDRESS),
+            ],
+        )[0];
+
+        let old_root = tree.get_root();
+        let account_state = withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+
+        let circuit = DelegatedWithdrawalBatchCircuit {
+            batch_size: 1,
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+            signing_domain: domain,
+            queue: vec![DelegatedWithdrawalCircuit::<Bn256> {
+                account_state,
+                account_id: Some(usize_to_fr(1)),
+                token_id: Some(usize_to_fr(0)),
+                amount: Some(usize_to_fr(amount)),
+                nonce: Some(usize_to_fr(1)),
+                valid_until: Some(usize_to_fr(0)),
+                eth_address: Some(address_to_fr(&ETH_ADDRESS)),
+                sign: withdrawal.sign.clone(),
+                spender_pubkey: Some(spender_pubkey.0),
+                permit_max_amount: Some(u128_to_fr(permit.max_amount)),
+                permit_valid_until: Some(usize_to_fr(permit.valid_until)),
+                permit_sign: permit.sign.clone(),
+                pubkey: Some(pubkeys[0].0.clone()),
+            }],
+            timestamp: Some(usize_to_fr(timestamp)),
+            old_accum_hash: Some(old_hash),
+            new_accum_hash: Some(new_hash),
+            old_account_root: Some(old_root),
+            new_account_root: Some(tree.get_root()),
+        };
+
+        (circuit, allowed)
+    };
+
+    let (circuit, allowed) = make_circuit(&permit, 40, spender_seckey, 100);
+    assert!(allowed);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    assert_eq!(cs.num_inputs(), 6);
+
+    // above the permit, after it expires, signed by another key or permitted by the spender
+
+    let mut self_permit = WithdrawalPermit::new(1, pubkeys[1].clone(), 50, 100, 1);
+    self_permit.sign(spender_seckey, hash_params, sign_params);
+
+    for (permit, amount, spender_seckey, timestamp) in [
+        (&permit, 51, spender_seckey, 100),
+        (&permit, 40, spender_seckey, 101),
+        (&permit, 40, owner_seckey, 100),
+        (&self_permit, 40, spender_seckey, 100),
+    ].iter() {
+        let (circuit, _) = make_circuit(permit, *amount, spender_seckey, *timestamp);
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        circuit.synthesize(&mut cs).unwrap();
+        assert!(!cs.is_satisfied());
+    }
+}
+
+// builds the slot witness by applying the operation to the tree, deposits and noops
+// are signed by a throwaway key since their signature is not bound to any leaf
+fn block_operation_circuit(
+    tree: &mut AccountsTree,
+    operation: Operation<Bn256>,
+    seckey: &PrivateKey<Bn256>,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> (BlockOperationCircuit<Bn256>, Vec<bn256::Fr>) {
+    let value = |fr: &Option<bn256::Fr>| fr_to_usize(fr.unwrap()).unwrap();
+    let account = |fr: &Option<bn256::Fr>| AccountId::try_from_fr(&fr.unwrap()).unwrap();
+    let balance = |fr: &Option<bn256::Fr>| Balance::try_from_fr(&fr.unwrap()).unwrap();
+    let address = |fr: &Option<bn256::Fr>| {
+        let mut bytes = vec![];
+        fr.unwrap().into_repr().write_be(&mut bytes).unwrap();
+        let mut address = [0u8; ADDRESS_BYTES];
+        address.copy_from_slice(&bytes[bytes.len() - ADDRESS_BYTES..]);
+        address
+    };
+    let zero = bn256::Fr::zero();
+    let throwaway_seckey = PrivateKey::<Bn256>(thread_rng().gen());
+
+    // returns leaf states, the signed message and pubdata [a, b, token, amount, fee, eth address, pk_x, pk_y]
+    let (account_state_a, account_state_b, mut message, signer, pubdata) = match &operation {
+        Operation::Noop => {
+            let account_state_a = credit_fee_and_record_state(tree, AccountId(0), 0, Balance(0)).unwrap();
+            let account_state_b = credit_fee_and_record_state(tree, AccountId(0), 0, Balance(0)).unwrap();
+            let message = OffchainWithdrawal {
+                account_id: AccountId(0), token_id: 0, amount: Balance(0), fee: Balance(0), nonce: Nonce(0),
+                valid_until: 0, eth_address: [0u8; ADDRESS_BYTES], sign: None,
+            };
+            (account_state_a, account_state_b, Err(message), &throwaway_seckey, vec![zero; 8])
+        },
+        Operation::Deposit { pubkey, account_id, token_id, amount } => {
+            let account_state_a = Deposit {
+                pubkey: Some(PublicKey(pubkey.clone().unwrap())),
+                account_id: value(account_id),
+                token_id: value(token_id),
+                amount: balance(amount).0,
+            }.update_tree_and_record_state(tree).unwrap();
+            let account_state_b = credit_fee_and_record_state(tree, AccountId(0), value(token_id), Balance(0)).unwrap();
+            let message = OffchainWithdrawal {
+                account_id: account(account_id),
+                token_id: value(token_id),
+                amount: balance(amount),
+                fee: Balance(0),
+                nonce: Nonce(0),
+                valid_until: 0,
+                eth_address: [0u8; ADDRESS_BYTES],
+                sign: None,
+            };
+            let (pubkey_x, pubkey_y) = pubkey.clone().unwrap().into_xy();
+            let pubdata = vec![account_id.unwrap(), zero, token_id.unwrap(), amount.unwrap(),
+                zero, zero, pubkey_x, pubkey_y];
+            (account_state_a, account_state_b, Err(message), &throwaway_seckey, pubdata)
+        },
+        Operation::Withdrawal { account_id, fee_account_id, token_id, amount, fee, nonce, eth_address } => {
+            let message = OffchainWithdrawal {
+                account_id: account(account_id),
+                token_id: value(token_id),
+                amount: balance(amount),
+                fee: balance(fee),
+                nonce: Nonce::try_from_fr(&nonce.unwrap()).unwrap(),
+                valid_until: 0,
+                eth_address: address(eth_address),
+                sign: None,
+            };
+            let account_state_a = message.update_tree_and_record_state(tree).unwrap();
+            let account_state_b = credit_fee_and_record_state(
+                tree, account(fee_account_id), value(token_id), balance(fee)).unwrap();
+            let pubdata = vec![account_id.unwrap(), fee_account_id.unwrap(), token_id.unwrap(),
+                amount.unwrap(), fee.unwrap(), eth_address.unwrap(), zero, zero];
+            (account_state_a, account_state_b, Err(message), seckey, pubdata)
+        },
+        Operation::Transfer { account_id_from, account_id_to, token_id, amount, nonce } => {
+            let message = Transfer {
+                account_id_from: value(account_id_from),
+                account_id_to: value(account_id_to),
+                token_id: value(token_id),
+                amount: balance(amount).0,
+                nonce: Nonce::try_from_fr(&nonce.unwrap()).unwrap(),
+                sign: None,
+            };
+            let (account_state_a, account_state_b) = message.update_tree_and_record_state(tree).unwrap();
+            let pubdata = vec![account_id_from.unwrap(), account_id_to.unwrap(), token_id.unwrap(),
+                amount.unwrap(), zero, zero, zero, zero];
+            (account_state_a, account_state_b, Ok(message), seckey, pubdata)
+        },
+    };
+
+    // transfers sign the transfer hash, everything else the withdrawal hash
+    let sign = match &mut message {
+        Ok(transfer) => {
+            transfer.sign(signer, hash_params, sign_params);
+            transfer.sign.clone()
+        },
+        Err(withdrawal) => {
+            withdrawal.sign_with_thread_rng(&secret(signer), &SigningDomain::default(), None, None);
+            withdrawal.sign.clone()
+        },
+    };
+    let pubkey = PublicKey::from_private(signer, FixedGenerators::SpendingKeyGenerator, sign_params);
+    let pubdata = [vec![usize_to_fr(operation.tag() as usize)], pubdata].concat();
+
+    let circuit = BlockOperationCircuit {
+        operation,
+        account_state_a,
+        account_state_b,
+        sign,
+        pubkey: Some(pubkey.0),
+    };
+
+    (circuit, pubdata)
+}
+
+fn block_circuit<'a>(
+    tree: &mut AccountsTree,
+    operations: Vec<(Operation<Bn256>, &PrivateKey<Bn256>)>,
+    account_depth: usize,
+    token_depth: usize,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &'a AltJubjubBn256,
+) -> BlockCircuit<'a, Bn256> {
+    let old_root = tree.get_root();
+    let old_hash = bn256::Fr::zero();
+    let mut hash = old_hash;
+
+    let block_size = operations.len();
+    let operations: Vec<_> = operations.into_iter().map(|(operation, seckey)| {
+        let (circuit, pubdata) = block_operation_circuit(
+            tree, operation, seckey, hash_params, sign_params);
+        hash = poseidon_hash::<Bn256>(hash_params, &[vec![usize_to_fr(BLOCK_OP), hash], pubdata].concat())[0];
+        circuit
+    }).collect();
+
+    BlockCircuit {
+        block_size,
+        account_depth,
+        token_depth,
+        hash_params,
+        sign_params,
+        signing_domain: SigningDomain::default(),
+        operations,
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    }
+}
+
+#[test]
+pub fn mixed_operation_block() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let other_seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let fr = |value: usize| Some(usize_to_fr(value));
+    let deposit = Operation::Deposit {
+        pubkey: Some(pubkey.0.clone()),
+        account_id: fr(1),
+        token_id: fr(1),
+        amount: fr(100),
+    };
+    let transfer = Operation::Transfer {
+        account_id_from: fr(1),
+        account_id_to: fr(2),
+        token_id: fr(1),
+        amount: fr(30),
+        nonce: fr(1),
+    };
+    let withdrawal = Operation::Withdrawal {
+        account_id: fr(1),
+        fee_account_id: fr(3),
+        token_id: fr(1),
+        amount: fr(10),
+        fee: fr(2),
+        nonce: fr(2),
+        eth_address: Some(address_to_fr(&ETH_ADDRESS)),
+    };
+
+    // every operation type in one block
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = block_circuit(
+        &mut tree,
+        vec![
+            (deposit.clone(), &seckey),
+            (Operation::Noop, &seckey),
+            (transfer.clone(), &seckey),
+            (withdrawal, &seckey),
+        ],
+        account_depth, token_depth, hash_params, sign_params,
+    );
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    let num_constraints = cs.num_constraints();
+
+    assert_eq!(fr_to_usize(tree.get_balance(1, 1).unwrap()), Ok(58));
+    assert_eq!(fr_to_usize(tree.get_balance(2, 1).unwrap()), Ok(30));
+    assert_eq!(fr_to_usize(tree.get_balance(3, 1).unwrap()), Ok(2));
+
+    // shape doesn't depend on the operation mix
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = block_circuit(
+        &mut tree,
+        vec![
+            (Operation::Noop, &seckey),
+            (deposit.clone(), &seckey),
+            (Operation::Noop, &seckey),
+            (Operation::Noop, &seckey),
+        ],
+        account_depth, token_depth, hash_params, sign_params,
+    );
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    assert_eq!(cs.num_constraints(), num_constraints);
+
+    // signed operations must be signed by the leaf owner
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = block_circuit(
+        &mut tree,
+        vec![
+            (deposit, &seckey),
+            (transfer, &other_seckey),
+        ],
+        account_depth, token_depth, hash_params, sign_params,
+    );
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+}
+
+#[test]
+pub fn exit_proof() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey),
+        account_id: 2,
+        token_id: 1,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    // the proof is against the published snapshot, later updates don't matter
+
+    let snapshot = tree.clone();
+    tree.update_balance(2, 1, usize_to_fr(0)).unwrap();
+
+    let circuit = snapshot.exit_witness(2, 1).unwrap();
+    assert_eq!(circuit.balance, Some(usize_to_fr(100)));
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+    assert_eq!(cs.num_inputs(), 7);
+
+    // claiming more than the balance, or the balance of another account, fails
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    ExitCircuit { balance: Some(usize_to_fr(101)), ..circuit.clone() }.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    ExitCircuit { account_id: Some(usize_to_fr(3)), ..circuit.clone() }.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    ExitCircuit { root: Some(tree.get_root()), ..circuit.clone() }.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let params = generate_random_parameters(
+        ExitCircuit::<Bn256> {
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+            root: None,
+            account_id: None,
+            token_id: None,
+            pubkey_x: None,
+            pubkey_y: None,
+            balance: None,
+            nonce: None,
+            account_path: vec![None; account_depth],
+            account_indices: vec![None; account_depth],
+            token_path: vec![None; token_depth],
+            token_indices: vec![None; token_depth],
+        },
+        &mut rng,
+    ).unwrap();
+
+    let public_inputs = circuit.public_inputs().unwrap();
+    let proof = create_random_proof(circuit, &params, &mut rng).unwrap();
+    let verifying_key = prepare_verifying_key(&params.vk);
+    assert!(verify_proof(&verifying_key, &proof, &public_inputs).unwrap());
+}
+
+#[test]
+pub fn circuit_family() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let single = BatchConfig { deposit_batch: 1, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let double = BatchConfig { deposit_batch: 2, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut family = CircuitFamily::new(shared_params());
+    let single_shape = family.register(single).unwrap();
+    let double_shape = family.register(double).unwrap();
+    assert_ne!(single_shape.hash, double_shape.hash);
+    assert_eq!(family.shape(&double), Some(double_shape));
+
+    // a filled circuit from the family has the shape of the empty one
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let padded = padded_deposit_batch_circuit(
+        &mut tree,
+        &[Deposit { pubkey: Some(pubkey), account_id: 1, token_id: 0, amount: 10 }],
+        2, account_depth, token_depth, &shared_params(),
+    );
+
+    let public_inputs = PublicInputs::<Bn256>::new(
+        padded.old_accum_hash.unwrap(),
+        padded.new_accum_hash.unwrap(),
+        padded.old_account_root.unwrap(),
+        padded.new_account_root.unwrap(),
+    );
+
+    assert!(family.circuit(single, padded.deposit_queue.witnesses().unwrap().to_vec(), &public_inputs).is_err());
+
+    let circuit = family.circuit(double, padded.deposit_queue.witnesses().unwrap().to_vec(), &public_inputs).unwrap();
+    assert_eq!(shape(circuit.clone()).unwrap(), double_shape);
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    // parameters of one config are rejected for another
+
+    let single_params = family.generate_parameters(single, &mut rng).unwrap();
+    family.check_parameters(single, &single_params).unwrap();
+    assert!(family.check_parameters(double, &single_params).is_err());
+
+    let mut bytes = Vec::new();
+    family.write_parameters(single, &single_params, &mut bytes).unwrap();
+    assert!(family.read_parameters(double, &bytes[..], false).is_err());
+
+    let loaded = family.read_parameters(single, &bytes[..], false).unwrap();
+    assert!(loaded == single_params);
+}
+
+#[test]
+pub fn batched_tree_update() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..2).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    // repeated accounts and tokens, so later witnesses depend on earlier updates
+    let deposits = [
+        Deposit { pubkey: Some(pubkeys[0].clone()), account_id: 1, token_id: 0, amount: 10 },
+        Deposit { pubkey: Some(pubkeys[1].clone()), account_id: 2, token_id: 1, amount: 20 },
+        Deposit { pubkey: Some(pubkeys[0].clone()), account_id: 1, token_id: 0, amount: 30 },
+        Deposit { pubkey: Some(pubkeys[0].clone()), account_id: 0, token_id: 1, amount: 40 },
+        Deposit { pubkey: Some(pubkeys[1].clone()), account_id: 2, token_id: 0, amount: 50 },
+    ];
+
+    let mut sequential_tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let mut batched_tree = sequential_tree.clone();
+
+    let sequential: Vec<_> = deposits.iter().map(
+        |deposit| deposit.update_tree_and_record_state(&mut sequential_tree).unwrap()
+    ).collect();
+
+    let updates: Vec<_> = deposits.iter().map(|deposit| deposit.leaf_update()).collect();
+    let batched = batched_tree.apply_batch(&updates).unwrap();
+
+    assert!(sequential == batched);
+    assert_eq!(sequential_tree.get_root(), batched_tree.get_root());
+
+    // an invalid update anywhere in the batch leaves the tree untouched
+
+    let root = batched_tree.get_root();
+    let withdrawal = LeafUpdate {
+        account_id: 1,
+        token_id: 0,
+        pubkey: None,
+        credit: 0,
+        debit: 41,
+        increment_nonce: true,
+    };
+
+    assert_eq!(
+        batched_tree.apply_batch(&[updates[0].clone(), withdrawal.clone()]).err(),
+        None,
+    );
+    let root_after = batched_tree.get_root();
+    assert_ne!(root_after, root);
+
+    assert_eq!(
+        batched_tree.apply_batch(&[updates[0].clone(), withdrawal]).err(),
+        Some(TreeError::InsufficientBalance { account_id: 1, token_id: 0 }.into()),
+    );
+    assert_eq!(
+        batched_tree.apply_batch(&[LeafUpdate { account_id: 4, ..updates[0].clone() }]).err(),
+        Some(TreeError::AccountOutOfRange(4).into()),
+    );
+    assert_eq!(batched_tree.get_root(), root_after);
+    assert_eq!(batched_tree.get_balance(1, 0).unwrap(), usize_to_fr(9));
+    assert_eq!(batched_tree.get_nonce(1).unwrap(), usize_to_fr(1));
+}
+
+#[test]
+pub fn accounts_tree_persistence() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 0, amount: 10 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 3, token_id: 1, amount: 20 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    Deposit { pubkey: Some(pubkey), account_id: 1, token_id: 1, amount: 30 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    OnchainWithdrawal { account_id: 3, token_id: 1, amount: None }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    tree.apply_batch(&[LeafUpdate {
+        account_id: 1,
+        token_id: 0,
+        pubkey: None,
+        credit: 0,
+        debit: 4,
+        increment_nonce: true,
+    }]).unwrap();
+
+    let dir = std::env::temp_dir();
+
+    for &with_nodes in [false, true].iter() {
+        let path = dir.join(format!("openplasma_tree_{}_{}.bin", std::process::id(), with_nodes));
+        tree.save(&path, with_nodes).unwrap();
+
+        let loaded = AccountsTree::load(&path, hash_params, sign_params).unwrap();
+        assert_eq!(loaded.get_root(), tree.get_root());
+        assert_eq!(loaded.get_balance(1, 0).unwrap(), usize_to_fr(6));
+        assert_eq!(loaded.get_balance(1, 1).unwrap(), usize_to_fr(30));
+        assert_eq!(loaded.get_balance(3, 1).unwrap(), usize_to_fr(0));
+        assert_eq!(loaded.get_nonce(1).unwrap(), usize_to_fr(1));
+        assert!(loaded.get_pubkey(1).unwrap().0 == tree.get_pubkey(1).unwrap().0);
+
+        // the loaded tree keeps working
+        let mut loaded = loaded;
+        let mut original = tree.clone();
+        let updates = [LeafUpdate {
+            account_id: 2,
+            token_id: 1,
+            pubkey: None,
+            credit: 5,
+            debit: 0,
+            increment_nonce: false,
+        }];
+        assert!(loaded.apply_batch(&updates).unwrap() == original.apply_batch(&updates).unwrap());
+        assert_eq!(loaded.get_root(), original.get_root());
+
+        // any flipped byte or a cut file is detected
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut corrupted = bytes.clone();
+        corrupted[bytes.len() / 2] ^= 1;
+        assert!(AccountsTree::read(&corrupted[..], hash_params, sign_params).is_err());
+        assert!(AccountsTree::read(&bytes[..bytes.len() - 1], hash_params, sign_params).is_err());
+    }
+}
+
+#[test]
+pub fn accounts_tree_rollback() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 0, amount: 10 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+
+    let initial = tree.clone();
+    let initial_root = tree.get_root();
+
+    // checkpoint per block, inner checkpoint per operation
+
+    let block = tree.checkpoint();
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 2, token_id: 1, amount: 20 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    let after_deposit = tree.get_root();
+
+    let operation = tree.checkpoint();
+    Transfer { account_id_from: 1, account_id_to: 2, token_id: 0, amount: 3, nonce: Nonce(1), sign: None }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    OnchainWithdrawal { account_id: 2, token_id: 1, amount: None }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    assert_ne!(tree.get_root(), after_deposit);
+
+    tree.rollback(operation).unwrap();
+    assert_eq!(tree.get_root(), after_deposit);
+    assert_eq!(tree.get_balance(1, 0).unwrap(), usize_to_fr(10));
+    assert_eq!(tree.get_balance(2, 1).unwrap(), usize_to_fr(20));
+    assert_eq!(tree.get_nonce(1).unwrap(), usize_to_fr(0));
+    assert_eq!(tree.rollback(operation), Err(TreeError::UnknownCheckpoint.into()));
+
+    // a committed operation is still rolled back with its block
+
+    let operation = tree.checkpoint();
+    tree.apply_batch(&[LeafUpdate {
+        account_id: 3,
+        token_id: 0,
+        pubkey: Some(pubkey),
+        credit: 7,
+        debit: 0,
+        increment_nonce: true,
+    }]).unwrap();
+    tree.commit(operation).unwrap();
+
+    tree.rollback(block).unwrap();
+    assert_eq!(tree.get_root(), initial_root);
+    for account_id in 0..(1 << account_depth) {
+        assert!(tree.get_pubkey(account_id).unwrap().0 == initial.get_pubkey(account_id).unwrap().0);
+        assert_eq!(tree.get_nonce(account_id).unwrap(), initial.get_nonce(account_id).unwrap());
+        for token_id in 0..(1 << token_depth) {
+            assert_eq!(tree.get_balance(account_id, token_id).unwrap(), initial.get_balance(account_id, token_id).unwrap());
+            assert_eq!(tree.get_token_path(account_id, token_id).unwrap(), initial.get_token_path(account_id, token_id).unwrap());
+        }
+        assert_eq!(tree.accounts_tree.get_leaf_path(account_id), initial.accounts_tree.get_leaf_path(account_id));
+    }
+
+    // nothing is journaled without a checkpoint, committed updates stay
+
+    let block = tree.checkpoint();
+    Deposit { pubkey: Some(initial.get_pubkey(1).unwrap()), account_id: 1, token_id: 1, amount: 5 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    tree.commit(block).unwrap();
+    assert_eq!(tree.rollback(block), Err(TreeError::UnknownCheckpoint.into()));
+    assert_eq!(tree.get_balance(1, 1).unwrap(), usize_to_fr(5));
+}
+
+#[test]
+pub fn sparse_tree() {
+    let hash_params = Bn256PoseidonParams::new::<BlakeHasher>();
+    let sign_params = AltJubjubBn256::new();
+    let token_depth = 2;
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..3).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    )).collect();
+
+    // a small tree has the root of the dense tree over all of its leaves
+
+    let mut tree = AccountsTree::new(4, token_depth, &hash_params, &sign_params);
+    let leaves = vec![tree.accounts[0].compress_to_leaf(); 1 << 4];
+    assert_eq!(tree.get_root(), PoseidonMerkleTree::<Bn256>::new(leaves, &hash_params).root());
+
+    for (i, pubkey) in pubkeys.iter().enumerate() {
+        Deposit {
+            pubkey: Some(pubkey.clone()),
+            account_id: 5 * i + 1,
+            token_id: i % 4,
+            amount: 10,
+        }.update_tree_and_record_state(&mut tree).unwrap();
+    }
+    let leaves: Vec<_> = (0..tree.accounts.len()).map(
+        |account_id| tree.accounts[account_id].compress_to_leaf()
+    ).collect();
+    assert_eq!(tree.get_root(), PoseidonMerkleTree::<Bn256>::new(leaves, &hash_params).root());
+    assert_eq!(tree.accounts.num_stored(), 3);
+
+    // depth 32 stores only what is written, untouched leaves have empty paths
+
+    let account_depth = 32;
+    let mut tree = AccountsTree::new(account_depth, token_depth, &hash_params, &sign_params);
+    assert_eq!(tree.accounts.len(), 1 << 32);
+    assert_eq!(tree.accounts_tree.num_stored_nodes(), 0);
+
+    let untouched = 3;
+    let empty_path: Vec<_> = (0..account_depth).map(|level| tree.accounts_tree.empty_node(level)).collect();
+    assert_eq!(tree.accounts_tree.get_leaf_path(untouched), empty_path);
+    assert_eq!(tree.accounts_tree.get_leaf_path((1 << 32) - 1), empty_path);
+
+    let account_id = (1 << 31) + 5;
+    Deposit {
+        pubkey: Some(pubkeys[0].clone()),
+        account_id,
+        token_id: 1,
+        amount: 10,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+    assert_eq!(tree.accounts_tree.num_stored_nodes(), account_depth + 1);
+    assert_eq!(tree.accounts.num_stored(), 1);
+
+    // the paths meet at the top, below it the untouched path is still empty
+    let path = tree.accounts_tree.get_leaf_path(untouched);
+    assert_eq!(path[..31], empty_path[..31]);
+    assert_ne!(path[31], empty_path[31]);
+    assert_eq!(tree.get_balance(account_id, 1), Ok(usize_to_fr(10)));
+
+    // its file holds only the stored account, balance and nodes
+
+    for &with_nodes in [false, true].iter() {
+        let mut bytes = Vec::new();
+        tree.write(&mut bytes, with_nodes).unwrap();
+        assert!(bytes.len() < 4096);
+
+        let loaded = AccountsTree::read(&bytes[..], &hash_params, &sign_params).unwrap();
+        assert_eq!(loaded.get_root(), tree.get_root());
+        assert_eq!(loaded.get_balance(account_id, 1), Ok(usize_to_fr(10)));
+        assert_eq!(loaded.accounts.num_stored(), 1);
+    }
+
+    // so are balances, a deep balances tree allocates nothing up front
+    let account: Account = Account::new(32, &hash_params, &sign_params);
+    assert_eq!(account.balances.len(), 1 << 32);
+    assert_eq!(account.balances.num_stored(), 0);
+    assert_eq!(account.balances_tree.num_stored_nodes(), 0);
+}
+
+#[test]
+pub fn merkle_proof() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 1, amount: 10 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 2, token_id: 0, amount: 20 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+
+    for account_id in 0..(1 << account_depth) {
+        assert!(tree.prove(account_id).unwrap().verify(hash_params));
+    }
+
+    let proof = tree.prove(1).unwrap();
+    let balance_proof = tree.prove_balance(1, 1).unwrap();
+    assert!(balance_proof.verify(hash_params));
+    assert_eq!(balance_proof.balance(), Some(usize_to_fr(10)));
+
+    // the same proof after a trip through json
+
+    let json = serde_json::to_string(&balance_proof).unwrap();
+    let decoded: BalanceProof = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, balance_proof);
+    assert!(decoded.verify(hash_params));
+
+    let mut forged = balance_proof.clone();
+    forged.balance.leaf[0] = usize_to_fr(11);
+    assert!(!forged.verify(hash_params));
+
+    // a proof made before an update does not verify against the new root,
+    // even for an account the update did not touch
+
+    Deposit { pubkey: Some(pubkey), account_id: 2, token_id: 0, amount: 5 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+
+    let stale = MerkleProof { root: tree.get_root(), ..proof.clone() };
+    assert!(proof.verify(hash_params));
+    assert!(!stale.verify(hash_params));
+    assert!(tree.prove(1).unwrap().verify(hash_params));
+    assert_eq!(tree.prove(1).unwrap().leaf, proof.leaf);
+}
+
+#[test]
+pub fn pubkey_index() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..3).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    assert_eq!(tree.first_empty_leaf(), Some(0));
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[0]), None);
+    // the empty account key is never registered
+    assert_eq!(tree.account_id_by_pubkey(&tree.get_pubkey(0).unwrap()), None);
+
+    Deposit { pubkey: Some(pubkeys[0].clone()), account_id: 0, token_id: 0, amount: 10 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    Deposit { pubkey: Some(pubkeys[1].clone()), account_id: 2, token_id: 0, amount: 10 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[0]), Some(0));
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[1]), Some(2));
+    assert_eq!(tree.first_empty_leaf(), Some(1));
+
+    // rotation moves the key, a rolled back rotation moves it back
+
+    let checkpoint = tree.checkpoint();
+    let nonce = tree.get_nonce(0).unwrap();
+    tree.update_account(0, pubkeys[2].clone(), nonce).unwrap();
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[0]), None);
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[2]), Some(0));
+
+    tree.rollback(checkpoint).unwrap();
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[0]), Some(0));
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[2]), None);
+
+    // the circuit doesn't forbid one key in two accounts, the index keeps both
+
+    tree.apply_batch(&[Deposit { pubkey: Some(pubkeys[1].clone()), account_id: 1, token_id: 0, amount: 5 }
+        .leaf_update()]).unwrap();
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[1]), Some(1));
+    assert_eq!(tree.first_empty_leaf(), Some(3));
+    tree.update_account(1, pubkeys[2].clone(), nonce).unwrap();
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[1]), Some(2));
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[2]), Some(1));
+
+    // the index is rebuilt on load
+
+    let mut bytes = Vec::new();
+    tree.write(&mut bytes, false).unwrap();
+    let loaded = AccountsTree::read(&bytes[..], hash_params, sign_params).unwrap();
+    for pubkey in pubkeys.iter() {
+        assert_eq!(loaded.account_id_by_pubkey(pubkey), tree.account_id_by_pubkey(pubkey));
+    }
+    assert_eq!(loaded.first_empty_leaf(), Some(3));
+
+    // the operator routes a key to one account only
+
+    let dummy_params = setup_deposit_circuit(1, account_depth, token_depth, &shared_params()).unwrap();
+    let params = shared_params();
+    let mut oper = Operator::new(account_depth, token_depth, 1, 1, 1, 1, &params,
+        &dummy_params, &dummy_params, &dummy_params, &dummy_params);
+    oper.tree = tree;
+
+    let deposit = |pubkey: &PublicKey<Bn256>, account_id| Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id,
+        token_id: 0,
+        amount: 1,
+    };
+    oper.add_deposit(deposit(&pubkeys[0], 0)).unwrap();
+    assert!(oper.add_deposit(deposit(&pubkeys[0], 3)).is_err());
+
+    let fresh = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+    oper.add_deposit(deposit(&fresh, 3)).unwrap();
+    oper.add_deposit(deposit(&fresh, 3)).unwrap();
+    assert!(oper.add_deposit(deposit(&fresh, 0)).is_err());
+}
+
+#[test]
+pub fn state_snapshot() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 1, amount: 10 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    // account 3 gets a balance without ever registering a key
+    Transfer { account_id_from: 1, account_id_to: 3, token_id: 1, amount: 4, nonce: Nonce(1), sign: None }
+        .update_tree_and_record_state(&mut tree).unwrap();
+
+    let accounts: Vec<_> = tree.iter_accounts().map(
+        |(account_id, _, nonce, balances)| (account_id, nonce, balances.to_vec())
+    ).collect();
+    assert_eq!(accounts, vec![
+        (1, usize_to_fr(1), vec![usize_to_fr(0), usize_to_fr(6)]),
+        (3, usize_to_fr(0), vec![usize_to_fr(0), usize_to_fr(4)]),
+    ]);
+
+    let snapshot = tree.export_snapshot();
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let decoded: StateSnapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, snapshot);
+
+    let rebuilt = AccountsTree::from_snapshot(&decoded, hash_params, sign_params).unwrap();
+    assert_eq!(rebuilt.get_root(), tree.get_root());
+    assert_eq!(rebuilt.account_id_by_pubkey(&pubkey), Some(1));
+    assert_eq!(rebuilt.first_empty_leaf(), Some(0));
+    assert_eq!(rebuilt.export_snapshot(), snapshot);
+
+    // a state that doesn't hash to the published root is rejected
+
+    let mut forged = snapshot.clone();
+    forged.accounts[1].balances[1] = usize_to_fr(5);
+    assert_eq!(
+        AccountsTree::from_snapshot(&forged, hash_params, sign_params).err(),
+        Some(TreeError::InvalidSnapshot("root mismatch").into()),
+    );
+
+    let mut reordered = snapshot.clone();
+    reordered.accounts.reverse();
+    assert!(AccountsTree::from_snapshot(&reordered, hash_params, sign_params).is_err());
+
+    let mut off_curve = snapshot;
+    off_curve.accounts[0].pubkey = (2..).map(usize_to_fr).find(
+        |packed| unpack_point::<Bn256>(*packed, sign_params).is_none()
+    ).unwrap();
+    assert!(AccountsTree::from_snapshot(&off_curve, hash_params, sign_params).is_err());
+}
+
+#[test]
+pub fn root_history() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    tree.finalize_block(0).unwrap();
+    let empty_root = tree.get_root();
+
+    Deposit { pubkey: Some(pubkey), account_id: 2, token_id: 1, amount: 100 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    tree.finalize_block(1).unwrap();
+    let deposit_root = tree.get_root();
+
+    Transfer { account_id_from: 2, account_id_to: 3, token_id: 1, amount: 30, nonce: Nonce(1), sign: None }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    tree.finalize_block(2).unwrap();
+
+    // not finalized yet, must be undone as well
+    tree.update_balance(2, 1, usize_to_fr(0)).unwrap();
+
+    assert_eq!(tree.history().get_root_at(0), Some(empty_root));
+    assert_eq!(tree.history().get_root_at(1), Some(deposit_root));
+    assert_eq!(tree.history().get_root_at(3), None);
+    assert!(tree.history().contains(&deposit_root));
+    assert!(!tree.history().contains(&tree.get_root()));
+
+    assert_eq!(tree.finalize_block(2).err(), Some(TreeError::BlockOutOfOrder(2).into()));
+    let checkpoint = tree.checkpoint();
+    assert_eq!(tree.finalize_block(3).err(), Some(TreeError::OpenCheckpoint.into()));
+    tree.commit(checkpoint).unwrap();
+
+    // the exit against block 1 sees the whole deposit
+
+    let circuit = tree.exit_witness_at(1, 2, 1, None).unwrap();
+    assert_eq!(circuit.root, Some(deposit_root));
+    assert_eq!(circuit.balance, Some(usize_to_fr(100)));
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    let old_state = tree.state_at(0, sign_params).unwrap();
+    assert_eq!(old_state.get_root(), empty_root);
+    assert_eq!(old_state.history().len(), 1);
+    assert_eq!(
+        tree.exit_witness_at(4, 2, 1, None).err(),
+        Some(TreeError::UnknownBlock(4).into()),
+    );
+
+    // history travels with the snapshot
+
+    let snapshot = tree.export_snapshot();
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let decoded: StateSnapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, snapshot);
+
+    let rebuilt = AccountsTree::from_snapshot(&decoded, hash_params, sign_params).unwrap();
+    assert_eq!(rebuilt.history(), tree.history());
+    let circuit = rebuilt.exit_witness_at(1, 2, 1, None).unwrap();
+    assert_eq!(circuit.balance, Some(usize_to_fr(100)));
+}
+
+// run with --features parallel as well, the serial reference below never goes
+// through the level by level hashing
+#[test]
+pub fn tree_hashing_determinism() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 8;
+    let token_depth = 2;
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..4).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    let updates: Vec<_> = (0..1000).map(|_| LeafUpdate {
+        account_id: rng.gen_range(0, 1 << account_depth),
+        token_id: rng.gen_range(0, 1 << token_depth),
+        pubkey: if rng.gen_weighted_bool(4) {
+            Some(pubkeys[rng.gen_range(0, pubkeys.len())].clone())
+        } else {
+            None
+        },
+        credit: u128::from(rng.gen_range(0u32, 100)),
+        debit: 0,
+        increment_nonce: rng.gen(),
+    }).collect();
+
+    let empty = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+
+    // leaf by leaf rehashing of whole paths
+    let mut serial = empty.clone();
+    for update in updates.iter() {
+        let balance = fr_to_u128_checked(&serial.get_balance(update.account_id, update.token_id).unwrap()).unwrap();
+        serial.update_balance(update.account_id, update.token_id, u128_to_fr(balance + update.credit)).unwrap();
+
+        let pubkey = update.pubkey.clone().unwrap_or_else(|| serial.get_pubkey(update.account_id).unwrap());
+        let mut nonce = serial.get_nonce(update.account_id).unwrap();
+        if update.increment_nonce {
+            nonce.add_assign(&bn256::Fr::one());
+        }
+        serial.update_account(update.account_id, pubkey, nonce).unwrap();
+    }
+
+    let mut batched = empty.clone();
+    let batched_states = batched.apply_batch(&updates).unwrap();
+
+    let mut one_by_one = empty;
+    let one_by_one_states: Vec<_> = updates.iter().map(
+        |update| one_by_one.apply_batch(std::slice::from_ref(update)).unwrap().remove(0)
+    ).collect();
+
+    assert!(batched_states == one_by_one_states);
+    assert_eq!(batched.get_root(), serial.get_root());
+    assert_eq!(one_by_one.get_root(), serial.get_root());
+
+    let rebuilt = AccountsTree::from_snapshot(&serial.export_snapshot(), hash_params, sign_params).unwrap();
+    assert_eq!(rebuilt.get_root(), serial.get_root());
+}
+
+// 16 accounts with 4 tokens each, as a binary and as a quad tree
+#[test]
+pub fn tree_arity() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_id = 9;
+    let token_id = 2;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+    let packed_pubkey = pack_point(&pubkey.0);
+    let nonce = usize_to_fr(3);
+
+    let constraints = |arity: usize, account_depth: usize, token_depth: usize| {
+        let balances = |balance| -> Vec<_> {
+            (0..4).map(
+                |id| vec![if id == token_id { usize_to_fr(balance) } else { bn256::Fr::zero() }]
+            ).collect()
+        };
+        let old_balances = PoseidonMerkleTree::<Bn256>::new_with_arity(balances(50), arity, hash_params);
+        let new_balances = PoseidonMerkleTree::<Bn256>::new_with_arity(balances(40), arity, hash_params);
+        assert_eq!(old_balances.depth(), token_depth);
+
+        let mut leaves = vec![vec![bn256::Fr::zero(); 3]; 16];
+        leaves[account_id] = vec![packed_pubkey, nonce, old_balances.root()];
+        let mut accounts = PoseidonMerkleTree::<Bn256>::new_with_arity(leaves, arity, hash_params);
+        assert_eq!(accounts.depth(), account_depth);
+
+        let old_root = accounts.root();
+        let state = AccountState::<Bn256> {
+            old_balance: Some(usize_to_fr(50)),
+            new_balance: Some(usize_to_fr(40)),
+            old_pubkey: Some(pubkey.0.clone()),
+            new_pubkey: Some(pubkey.0.clone()),
+            old_nonce: Some(nonce),
+            new_nonce: Some(nonce),
+            account_path: optionalize(accounts.get_leaf_path(account_id)),
+            account_indices: optionalize(accounts.get_leaf_indices(account_id)),
+            token_path: optionalize(old_balances.get_leaf_path(token_id)),
+            token_indices: optionalize(old_balances.get_leaf_indices(token_id)),
+        };
+
+        // batched rehashing agrees with the leaf by leaf one
+        let mut batched = accounts.clone();
+        accounts.update_leaf(account_id, vec![packed_pubkey, nonce, new_balances.root()]);
+        accounts.update_leaf(3, vec![usize_to_fr(1); 3]);
+        batched.set_leaf(account_id, vec![packed_pubkey, nonce, new_balances.root()]);
+        batched.set_leaf(3, vec![usize_to_fr(1); 3]);
+        assert_eq!(batched.refresh_leaf_path(account_id), accounts.get_leaf_path(account_id));
+        batched.refresh();
+        assert_eq!(batched.root(), accounts.root());
+
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let circuit = AccountCircuit::new_with_arity(
+            cs.namespace(|| "account"), account_depth, token_depth, arity, hash_params, &state,
+        ).unwrap();
+        let old_root_alloc = AllocatedNum::alloc(cs.namespace(|| "old root"), || Ok(old_root)).unwrap();
+        circuit.accounts_tree.verify_old_root(cs.namespace(|| "verify"), &old_root_alloc).unwrap();
+        let id = AllocatedNum::alloc(cs.namespace(|| "account id"), || Ok(usize_to_fr(account_id))).unwrap();
+        check_digit_decomposition_le(
+            cs.namespace(|| "account id digits"), &id, &circuit.accounts_tree.indices_alloc, arity,
+        ).unwrap();
+        assert!(cs.is_satisfied());
+
+        let mut new_leaf = circuit.accounts_tree.new_leaf_alloc.clone();
+        new_leaf[2] = circuit.balances_tree.calc_new_root(cs.namespace(|| "new balances root")).unwrap();
+        assert_eq!(new_leaf[2].get_value(), Some(new_balances.root()));
+
+        cs.num_constraints()
+    };
+
+    let binary = constraints(2, 4, 2);
+    let quad = constraints(4, 2, 1);
+    println!("binary tree: {} constraints, quad tree: {} constraints", binary, quad);
+    assert!(quad < binary);
+
+    // a digit of an arity 4 index is two bits
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let id = AllocatedNum::alloc(cs.namespace(|| "account id"), || Ok(usize_to_fr(1))).unwrap();
+    let bits: Vec<_> = (0..3).map(|i| Boolean::constant(i == 0)).collect();
+    assert!(check_digit_decomposition_le(cs.namespace(|| "odd bits"), &id, &bits, 4).is_err());
+}
+
+#[test]
+pub fn close_account() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let empty_tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    assert_eq!(empty_tree.empty_leaf(), empty_account_leaf::<Bn256>(token_depth, hash_params, sign_params));
+
+    let mut tree = empty_tree.clone();
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 2, token_id: 1, amount: 100 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+
+    let mut close = CloseAccount { account_id: 2, nonce: Nonce(1), sign: None };
+    close.sign(&seckey, hash_params, sign_params);
+    assert!(close.verify_signature(&pubkey, hash_params, sign_params).is_ok());
+
+    // balances have to be exited first
+
+    let root = tree.get_root();
+    assert_eq!(close.update_tree_and_record_state(&mut tree).err(), Some(TreeError::AccountNotEmpty(2).into()));
+    assert_eq!(CloseAccount { account_id: 1, nonce: Nonce(1), sign: None }.update_tree_and_record_state(&mut tree).err(),
+        Some(TreeError::EmptyAccount(1).into()));
+    assert_eq!(tree.get_root(), root);
+
+    FullExit { account_id: 2, token_id: 1 }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let make_circuit = |tree: &mut AccountsTree, close: &CloseAccount| {
+        let old_hash = bn256::Fr::zero();
+        let new_hash = poseidon_hash::<Bn256>(
+            hash_params,
+            &[usize_to_fr(CLOSE_ACCOUNT_OP), old_hash, usize_to_fr(close.account_id)],
+        )[0];
+
+        let old_root = tree.get_root();
+        let account_state = close.update_tree_and_record_state(tree).unwrap();
+
+        CloseAccountBatchCircuit {
+            batch_size: 1,
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+            queue: vec![CloseAccountCircuit::<Bn256> {
+                account_state,
+                account_id: Some(usize_to_fr(close.account_id)),
+                nonce: Some(close.nonce.to_fr()),
+                sign: close.sign.clone(),
+                pubkey: Some(pubkey.0.clone()),
+            }],
+            old_accum_hash: Some(old_hash),
+            new_accum_hash: Some(new_hash),
+            old_account_root: Some(old_root),
+            new_account_root: Some(tree.get_root()),
+        }
+    };
+
+    // signed by someone else
+
+    let mut forged = close.clone();
+    forged.sign(&PrivateKey::<Bn256>(rng.gen()), hash_params, sign_params);
+    let circuit = make_circuit(&mut tree.clone(), &forged);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    // the leaf is empty again and the slot can be registered anew
+
+    let circuit = make_circuit(&mut tree, &close);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    assert_eq!(tree.get_root(), empty_tree.get_root());
+    assert_eq!(tree.account_id_by_pubkey(&pubkey), None);
+    assert_eq!(tree.iter_accounts().count(), 0);
+
+    Deposit { pubkey: Some(pubkey.clone()), account_id: 2, token_id: 0, amount: 5 }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    assert_eq!(tree.account_id_by_pubkey(&pubkey), Some(2));
+}
+
+#[test]
+pub fn offchain_transfer() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let seckeys: Vec<_> = (0..2).map(|_| PrivateKey::<Bn256>(rng.gen())).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| PublicKey::from_private(
+        seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    for (account_id, pubkey) in pubkeys.iter().enumerate() {
+        Deposit { pubkey: Some(pubkey.clone()), account_id, token_id: 1, amount: 100 }
+            .update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    let transfer = |to_account_id, amount, fee, nonce| OffchainTransfer {
+        from_account_id: AccountId(0),
+        to_account_id: AccountId(to_account_id),
+        token_id: 1,
+        amount: Balance(amount),
+        fee: Balance(fee),
+        nonce: Nonce(nonce),
+        sign: None,
+    };
+
+    let mut signed = transfer(1, 30, 2, 1);
+    assert!(signed.verify_signature(&pubkeys[0], hash_params, sign_params).is_err());
+    signed.sign(&seckeys[0], hash_params, sign_params);
+    assert!(signed.verify_signature(&pubkeys[0], hash_params, sign_params).is_ok());
+    assert!(signed.verify_signature(&pubkeys[1], hash_params, sign_params).is_err());
+    assert_ne!(signed.hash(hash_params), transfer(1, 30, 3, 1).hash(hash_params));
+
+    // nothing reaches the tree unless both updates apply
+
+    let root = tree.get_root();
+    let rejected = [
+        (transfer(2, 30, 2, 1), TreeError::EmptyAccount(2)),
+        (transfer(4, 30, 2, 1), TreeError::AccountOutOfRange(4)),
+        (transfer(1, 99, 2, 1), TreeError::InsufficientBalance { account_id: 0, token_id: 1 }),
+        (transfer(1, 30, 2, 2), TreeError::NonceMismatch { account_id: 0, expected: Nonce(1), got: Nonce(2) }),
+    ];
+    for (transfer, err) in rejected.iter() {
+        assert_eq!(transfer.update_tree_and_record_state(&mut tree).err(), Some(err.clone().into()));
+        assert_eq!(tree.get_root(), root);
+    }
+
+    // the receiver record starts from the root the sender update left
+
+    let mut debited = tree.clone();
+    debited.update_balance(0, 1, usize_to_fr(68)).unwrap();
+    debited.update_nonce(0, usize_to_fr(1)).unwrap();
+
+    let (state_from, state_to) = signed.update_tree_and_record_state(&mut tree).unwrap();
+    assert_eq!(state_from.old_balance, Some(usize_to_fr(100)));
+    assert_eq!(state_from.new_balance, Some(usize_to_fr(68)));
+    assert_eq!(state_from.new_nonce, Some(usize_to_fr(1)));
+    assert_eq!(state_from.account_path, optionalize(debited.get_leaf_path(0).unwrap()));
+    assert_eq!(state_to.old_balance, Some(usize_to_fr(100)));
+    assert_eq!(state_to.new_balance, Some(usize_to_fr(130)));
+    assert_eq!(state_to.account_path, optionalize(debited.get_leaf_path(1).unwrap()));
+
+    debited.update_balance(1, 1, usize_to_fr(130)).unwrap();
+    assert_eq!(tree.get_root(), debited.get_root());
+}
+
+#[test]
+pub fn offchain_deposit() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..2).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let deposits = [
+        OffchainDeposit { account_id: AccountId(1), pubkey: pubkeys[0].clone(), token_id: 0, amount: Balance(40) },
+        OffchainDeposit { account_id: AccountId(1), pubkey: pubkeys[0].clone(), token_id: 1, amount: Balance(2) },
+    ];
+
+    let old_hash = bn256::Fr::zero();
+    let old_root = tree.get_root();
+    let mut accum_hash = old_hash;
+    let config = BatchConfig { deposit_batch: 2, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let mut builder = DepositBatchCircuit::builder(config, &shared_params());
+    for deposit in deposits.iter() {
+        let account_state = deposit.update_tree_and_record_state(&mut tree).unwrap();
+        accum_hash = deposit.hash(accum_hash, hash_params);
+        builder = builder.push(deposit.clone().into_circuit(account_state));
+    }
+    assert_eq!(tree.balance(AccountId(1), 0), Ok(Balance(40)));
+    assert_eq!(tree.nonce(AccountId(1)), Ok(Nonce(0)));
+
+    let circuit = builder
+        .old_accum_hash(old_hash)
+        .new_accum_hash(accum_hash)
+        .old_account_root(old_root)
+        .new_account_root(tree.get_root())
+        .build()
+        .unwrap();
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    // a registered leaf keeps its pubkey, the circuit can't prove the overwrite
+
+    let root = tree.get_root();
+    let overwrite = OffchainDeposit { account_id: AccountId(1), pubkey: pubkeys[1].clone(), token_id: 0, amount: Balance(1) };
+    assert_eq!(overwrite.update_tree_and_record_state(&mut tree).err(), Some(TreeError::PubkeyMismatch(1).into()));
+    let overflow = OffchainDeposit { account_id: AccountId(1), pubkey: pubkeys[0].clone(), token_id: 0, amount: Balance(u128::MAX) };
+    assert_eq!(overflow.update_tree_and_record_state(&mut tree).err(),
+        Some(TreeError::BalanceOverflow { account_id: 1, token_id: 0 }.into()));
+    assert_eq!(tree.get_root(), root);
+}
+
+#[test]
+pub fn offchain_withdrawal_json() {
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+
+    let seckey = PrivateKey::<Bn256>(thread_rng().gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1),
+        token_id: 0,
+        amount: Balance(u128::MAX),
+        fee: Balance(2),
+        nonce: Nonce(3),
+        valid_until: 100,
+        eth_address: ETH_ADDRESS,
+        sign: None,
+    };
+    let unsigned: OffchainWithdrawal = serde_json::from_str(&serde_json::to_string(&withdrawal).unwrap()).unwrap();
+    assert!(unsigned.sign.is_none());
+
+    withdrawal.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+    let json = serde_json::to_value(&withdrawal).unwrap();
+    assert_eq!(json["account_id"], "1");
+    assert_eq!(json["amount"], u128::MAX.to_string());
+    assert_eq!(json["nonce"], "3");
+    assert_eq!(json["sign"]["s"].as_str().unwrap().len(), 66);
+
+    let decoded: OffchainWithdrawal = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(decoded.hash(&domain, None), withdrawal.hash(&domain, None));
+    assert_eq!(decoded.verify_signature(&pubkey, &domain, None, None), Ok(()));
+
+    // a broken signature is rejected while decoding
+
+    let mut off_curve = json.clone();
+    off_curve["sign"]["r_y"] = serde_json::Value::from(format!("0x{:064x}", 2));
+    assert!(serde_json::from_value::<OffchainWithdrawal>(off_curve).is_err());
+
+    let mut not_canonical = json.clone();
+    not_canonical["sign"]["s"] = serde_json::Value::from(format!("0x{}", "ff".repeat(32)));
+    assert!(serde_json::from_value::<OffchainWithdrawal>(not_canonical).is_err());
+
+    let mut numeric = json;
+    numeric["nonce"] = serde_json::Value::from(3);
+    assert!(serde_json::from_value::<OffchainWithdrawal>(numeric).is_err());
+}
+
+#[test]
+pub fn operation_encoding() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+
+    let mut rng = thread_rng();
+    let balance = |rng: &mut rand::ThreadRng| Balance((u128::from(rng.gen::<u64>()) << 64) | u128::from(rng.gen::<u64>()));
+
+    for _ in 0..8 {
+        let seckey = PrivateKey::<Bn256>(rng.gen());
+        let pubkey = PublicKey::from_private(&seckey, FixedGenerators::SpendingKeyGenerator, sign_params);
+
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(rng.gen()),
+            token_id: rng.gen::<u32>() as usize,
+            amount: balance(&mut rng),
+            fee: balance(&mut rng),
+            nonce: Nonce(rng.gen()),
+            valid_until: rng.gen::<u32>() as usize,
+            eth_address: rng.gen(),
+            sign: None,
+        };
+        assert_eq!(withdrawal.encode().err(), Some(EncodingError::Unsigned.into()));
+        withdrawal.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+        let bytes = withdrawal.encode().unwrap();
+        assert_eq!(bytes.len(), OFFCHAIN_WITHDRAWAL_BYTES);
+        let decoded = OffchainWithdrawal::decode(&bytes, None).unwrap();
+        assert_eq!(decoded.encode().unwrap(), bytes);
+        assert_eq!(decoded.verify_signature(&pubkey, &domain, None, None), Ok(()));
+
+        let mut transfer = OffchainTransfer {
+            from_account_id: AccountId(rng.gen()),
+            to_account_id: AccountId(rng.gen()),
+            token_id: rng.gen::<u32>() as usize,
+            amount: balance(&mut rng),
+            fee: balance(&mut rng),
+            nonce: Nonce(rng.gen()),
+            sign: None,
+        };
+        transfer.sign(&seckey, hash_params, sign_params);
+        let bytes = transfer.encode().unwrap();
+        assert_eq!(bytes.len(), OFFCHAIN_TRANSFER_BYTES);
+        let decoded = OffchainTransfer::decode(&bytes, sign_params).unwrap();
+        assert_eq!(decoded.encode().unwrap(), bytes);
+        assert!(decoded.verify_signature(&pubkey, hash_params, sign_params).is_ok());
+
+        let deposit = OffchainDeposit {
+            account_id: AccountId(rng.gen()),
+            pubkey: pubkey.clone(),
+            token_id: rng.gen::<u32>() as usize,
+            amount: balance(&mut rng),
+        };
+        let bytes = deposit.encode().unwrap();
+        assert_eq!(bytes.len(), OFFCHAIN_DEPOSIT_BYTES);
+        let decoded = OffchainDeposit::decode(&bytes, sign_params).unwrap();
+        assert_eq!(decoded.encode().unwrap(), bytes);
+        assert_eq!(decoded.pubkey.0.into_xy(), pubkey.0.into_xy());
+    }
+
+    // every other length is an explicit error
+
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    withdrawal.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+    let bytes = withdrawal.encode().unwrap();
+
+    assert_eq!(OffchainWithdrawal::decode(&bytes[..1], None).err(),
+        Some(EncodingError::Truncated { expected: OFFCHAIN_WITHDRAWAL_BYTES, actual: 1 }.into()));
+    for len in 2..bytes.len() {
+        assert_eq!(OffchainWithdrawal::decode(&bytes[..len], None).err(),
+            Some(EncodingError::Truncated { expected: OFFCHAIN_WITHDRAWAL_BYTES, actual: len }.into()));
+    }
+    let mut overlong = bytes.clone();
+    overlong.push(0);
+    assert_eq!(OffchainWithdrawal::decode(&overlong, None).err(),
+        Some(EncodingError::Overlong { expected: OFFCHAIN_WITHDRAWAL_BYTES, actual: OFFCHAIN_WITHDRAWAL_BYTES + 1 }.into()));
+
+    let mut version = bytes.clone();
+    version[0] = ENCODING_VERSION + 1;
+    assert_eq!(OffchainWithdrawal::decode(&version, None).err(),
+        Some(EncodingError::UnsupportedVersion(ENCODING_VERSION + 1).into()));
+    assert_eq!(OffchainTransfer::decode(&bytes, sign_params).err(),
+        Some(EncodingError::UnexpectedOpType(OFFCHAIN_WITHDRAWAL_OP as u8).into()));
+
+    let sign_offset = OFFCHAIN_WITHDRAWAL_BYTES - 64;
+    let mut bad_r = bytes.clone();
+    bad_r[sign_offset..sign_offset + 31].copy_from_slice(&[0xff; 31]);
+    bad_r[sign_offset + 31] = 0x7f;
+    assert_eq!(OffchainWithdrawal::decode(&bad_r, None).err(), Some(EncodingError::InvalidPoint.into()));
+    let mut bad_s = bytes;
+    bad_s[sign_offset + 32..].copy_from_slice(&[0xff; 32]);
+    assert_eq!(OffchainWithdrawal::decode(&bad_s, None).err(), Some(EncodingError::NonCanonicalScalar.into()));
+}
+
+#[test]
+pub fn deterministic_signing() {
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+
+    let seckey = PrivateKey::<Bn256>(Fs::from_str("123456789").unwrap());
+    let pubkey = PublicKey::from_private(&seckey, FixedGenerators::SpendingKeyGenerator, sign_params);
+
+    let withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(1), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    let signed = |withdrawal: &OffchainWithdrawal| {
+        let mut withdrawal = withdrawal.clone();
+        withdrawal.sign_deterministic(&secret(&seckey), &domain, None, None);
+        withdrawal
+    };
+
+    let first = signed(&withdrawal);
+    let second = signed(&withdrawal);
+    assert_eq!(first.verify_signature(&pubkey, &domain, None, None), Ok(()));
+    assert_eq!(first.encode().unwrap(), second.encode().unwrap());
+
+    // pinned, so the nonce derivation can't change silently between releases
+    let (r_x, _) = first.sign.as_ref().unwrap().r.into_xy();
+    assert_eq!(format!("0x{}", r_x.to_hex()), "0x0a88cb4c6d2550aa9da35f8bc39463003edfcd25da6775e6ffdf746366baf634");
+
+    let other = signed(&OffchainWithdrawal { nonce: Nonce(2), ..withdrawal.clone() });
+    assert_eq!(other.verify_signature(&pubkey, &domain, None, None), Ok(()));
+    assert_ne!(other.sign.unwrap().r.into_xy(), first.sign.unwrap().r.into_xy());
+
+    // an external rng with a fixed seed reproduces the signature as well
+    let with_seed = |seed: u32| {
+        let mut withdrawal = withdrawal.clone();
+        withdrawal.sign(&secret(&seckey), &domain, None, None, &mut XorShiftRng::from_seed([seed, 2, 3, 4]));
+        withdrawal.encode().unwrap()
+    };
+    assert_eq!(with_seed(1), with_seed(1));
+    assert_ne!(with_seed(1), with_seed(5));
+}
+
+#[test]
+pub fn withdrawal_signature_errors() {
+    let domain = SigningDomain::default();
+    let other_hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,8,57);
+    let sign_params = jubjub_params();
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(&seckey, FixedGenerators::SpendingKeyGenerator, sign_params);
+    let other_pubkey = PublicKey::from_private(&PrivateKey::<Bn256>(rng.gen()), FixedGenerators::SpendingKeyGenerator, sign_params);
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    assert_eq!(withdrawal.verify_signature(&pubkey, &domain, None, None), Err(SignatureError::MissingSignature.into()));
+
+    withdrawal.sign(&secret(&seckey), &domain, Some(&other_hash_params), None, &mut rng);
+    assert_eq!(withdrawal.verify_signature(&pubkey, &domain, Some(&other_hash_params), None), Ok(()));
+    assert_eq!(withdrawal.verify_signature(&pubkey, &domain, None, None), Err(SignatureError::VerificationFailed.into()));
+
+    withdrawal.sign(&secret(&seckey), &domain, None, None, &mut rng);
+    assert_eq!(withdrawal.verify_signature(&pubkey, &domain, None, None), Ok(()));
+    assert_eq!(withdrawal.verify_signature(&other_pubkey, &domain, None, None), Err(SignatureError::VerificationFailed.into()));
+
+    // the point of order two is on the curve but out of the subgroup
+    let mut minus_one = bn256::Fr::one();
+    minus_one.negate();
+    let low_order = Point::<Bn256, Unknown>::from_xy(bn256::Fr::zero(), minus_one, sign_params).unwrap();
+    withdrawal.sign.as_mut().unwrap().r = low_order.clone();
+    assert_eq!(withdrawal.verify_signature(&pubkey, &domain, None, None), Err(SignatureError::InvalidPoint.into()));
+    withdrawal.sign(&secret(&seckey), &domain, None, None, &mut rng);
+    assert_eq!(withdrawal.verify_signature(&PublicKey(low_order), &domain, None, None), Err(SignatureError::InvalidPoint.into()));
+}
+
+#[test]
+pub fn signature_batch_verification() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let mut rng = thread_rng();
+
+    let mut requests: Vec<_> = (0..8).map(|account_id| {
+        let seckey = PrivateKey::<Bn256>(rng.gen());
+        let pubkey = PublicKey::from_private(&seckey, FixedGenerators::SpendingKeyGenerator, sign_params);
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(account_id), token_id: 0, amount: Balance(10), fee: Balance(1), nonce: Nonce(1),
+            valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+        };
+        withdrawal.sign(&secret(&seckey), &domain, None, None, &mut rng);
+        (withdrawal, pubkey)
+    }).collect();
+
+    let all_valid: Vec<_> = requests.iter().map(|_| Ok(())).collect();
+    assert_eq!(verify_signatures_batch(&requests, &domain, hash_params, sign_params), all_valid);
+    assert_eq!(verify_signatures_combined(&requests, &domain, hash_params, sign_params, &mut rng), all_valid);
+    assert!(verify_signatures_batch(&[], &domain, hash_params, sign_params).is_empty());
+
+    // an unsigned request, one signed by another key, a tampered amount and a
+    // low order r, each reported at its own position
+    let mut minus_one = bn256::Fr::one();
+    minus_one.negate();
+    let low_order = Point::<Bn256, Unknown>::from_xy(bn256::Fr::zero(), minus_one, sign_params).unwrap();
+
+    requests[1].0.sign = None;
+    requests[3].1 = requests[4].1.clone();
+    requests[5].0.amount = Balance(11);
+    requests[6].0.sign.as_mut().unwrap().r = low_order;
+
+    let expected = vec![
+        Ok(()),
+        Err(SignatureError::MissingSignature.into()),
+        Ok(()),
+        Err(SignatureError::VerificationFailed.into()),
+        Ok(()),
+        Err(SignatureError::VerificationFailed.into()),
+        Err(SignatureError::InvalidPoint.into()),
+        Ok(()),
+    ];
+    assert_eq!(verify_signatures_batch(&requests, &domain, hash_params, sign_params), expected);
+    assert_eq!(verify_signatures_combined(&requests, &domain, hash_params, sign_params, &mut rng), expected);
+
+    // failures the combination catches on its own, without verifying one by one
+    let refused = vec![requests[1].clone(), requests[6].clone(), requests[0].clone()];
+    assert_eq!(
+        verify_signatures_combined(&refused, &domain, hash_params, sign_params, &mut rng),
+        vec![Err(SignatureError::MissingSignature.into()), Err(SignatureError::InvalidPoint.into()), Ok(())],
+    );
+}
+
+#[test]
+pub fn signing_domain() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let address = hex::decode("5fbdb2315678afecb367f032d93f642f64180aa3").unwrap();
+    let mainnet = SigningDomain::from_address(1, &address).unwrap();
+    let testnet = SigningDomain::from_address(5, &address).unwrap();
+    let other_contract = SigningDomain::from_address(1, &[0xaa; 20]).unwrap();
+    assert_eq!(SigningDomain::from_address(1, &address[1..]), Err(ConversionError::WrongLength { expected: ADDRESS_BYTES, actual: 19 }.into()));
+    assert_eq!(SigningDomain::from_address(1, &[0; 32]), Err(ConversionError::WrongLength { expected: ADDRESS_BYTES, actual: 32 }.into()));
+    assert_eq!(mainnet.rollup_address_fr::<bn256::Fr>(), bn256::Fr::from_str("546584486846459126461364135121053344201067465379").unwrap());
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(&seckey, FixedGenerators::SpendingKeyGenerator, sign_params);
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(20), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    withdrawal.sign(&secret(&seckey), &testnet, None, None, &mut rng);
+    assert_eq!(withdrawal.verify_signature(&pubkey, &testnet, None, None), Ok(()));
+    for domain in [mainnet, other_contract, SigningDomain::default()].iter() {
+        assert_eq!(
+            withdrawal.verify_signature(&pubkey, domain, None, None),
+            Err(SignatureError::VerificationFailed.into()),
+        );
+    }
+
+    // the batch circuit of one deployment refuses requests signed for another
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let old_hash = bn256::Fr::zero();
+    let new_hash = poseidon_hash::<Bn256>(
+        hash_params,
+        &[
+            usize_to_fr(OFFCHAIN_WITHDRAWAL_OP), old_hash, usize_to_fr(1), usize_to_fr(0), usize_to_fr(20),
+            address_to_fr(&ETH_ADDRESS),
+        ],
+    )[0];
+    let old_root = tree.get_root();
+    let account_state = withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+    let fee_account_state = credit_fee_and_record_state(&mut tree, AccountId(0), 0, Balance(0)).unwrap();
+
+    let circuit = |signing_domain| OffchainWithdrawalBatchCircuit {
+        batch_size: 1,
+        account_depth,
+        token_depth,
+        hash_params,
+        sign_params,
+        signing_domain,
+        reject_zero_amount: false,
+        queue: vec![OffchainWithdrawalCircuit::<Bn256> {
+            account_state: account_state.clone(),
+            account_id: Some(usize_to_fr(1)),
+            token_id: Some(usize_to_fr(0)),
+            amount: Some(usize_to_fr(20)),
+            fee: Some(usize_to_fr(0)),
+            nonce: Some(usize_to_fr(1)),
+            valid_until: Some(usize_to_fr(0)),
+            eth_address: Some(address_to_fr(&ETH_ADDRESS)),
+            sign: withdrawal.sign.clone(),
+            pubkey: Some(pubkey.0.clone()),
+            is_noop: Some(false),
+        }],
+        fee_account_state: fee_account_state.clone(),
+        fee_account_id: Some(usize_to_fr(0)),
+        fee_token_id: Some(usize_to_fr(0)),
+        total_fee: Some(usize_to_fr(0)),
+        timestamp: Some(usize_to_fr(0)),
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(new_hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    };
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit(testnet).synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    // the domain is a constant, not an input
+    assert_eq!(cs.num_inputs(), 7);
+
+    for domain in [mainnet, other_contract].iter() {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        circuit(*domain).synthesize(&mut cs).unwrap();
+        assert!(!cs.is_satisfied());
+    }
+}
+
+#[test]
+pub fn withdrawal_eth_address() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let domain = SigningDomain::default();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    // EIP-55 checksums, all lower or all upper case carry none
+    for checksummed in [
+        "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+        "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+        "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+        "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+    ].iter() {
+        let address = serde_address::from_hex(checksummed).unwrap();
+        assert_eq!(serde_address::to_checksum_hex(&address), *checksummed);
+        assert_eq!(serde_address::from_hex(&checksummed.to_lowercase()), Ok(address));
+        assert_eq!(serde_address::from_hex(&format!("0x{}", checksummed[2..].to_uppercase())), Ok(address));
+    }
+    assert_eq!(serde_address::from_hex("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"), Ok(ETH_ADDRESS));
+    assert!(serde_address::from_hex("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD").is_err());
+    assert!(serde_address::from_hex("0x5aaeb6053f3e94c9b9a09f33669435e7ef1bea").is_err());
+    assert!(serde_address::from_hex("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").is_err());
+
+    // the address is signed, a request paid out to another one doesn't verify
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(&seckey, FixedGenerators::SpendingKeyGenerator, sign_params);
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(20), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    withdrawal.sign(&secret(&seckey), &domain, None, None, &mut rng);
+    assert_eq!(withdrawal.verify_signature(&pubkey, &domain, None, None), Ok(()));
+
+    let mut redirected = withdrawal.clone();
+    redirected.eth_address[ADDRESS_BYTES - 1] ^= 1;
+    assert!(redirected.hash(&domain, None) != withdrawal.hash(&domain, None));
+    assert_eq!(redirected.verify_signature(&pubkey, &domain, None, None), Err(SignatureError::VerificationFailed.into()));
+
+    // json carries the checksummed form, the pubdata the raw bytes
+    let json = serde_json::to_value(&withdrawal).unwrap();
+    assert_eq!(json["eth_address"], "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+    let mut bad_checksum = json.clone();
+    bad_checksum["eth_address"] = serde_json::json!("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD");
+    assert!(serde_json::from_value::<OffchainWithdrawal>(bad_checksum).is_err());
+
+    let mut pubdata = Pubdata::default();
+    pubdata.push_withdrawal(&withdrawal).unwrap();
+    match &Pubdata::parse(pubdata.as_bytes(), sign_params).unwrap()[..] {
+        [PubdataOp::Withdrawal(parsed)] => assert_eq!(parsed.eth_address, ETH_ADDRESS),
+        _ => panic!("one withdrawal is parsed"),
+    }
+
+    // the circuit binds the address to the signature and to the accum hash
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let old_hash = bn256::Fr::zero();
+    let old_root = tree.get_root();
+    let account_state = withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+    let fee_account_state = credit_fee_and_record_state(&mut tree, AccountId(0), 0, Balance(0)).unwrap();
+
+    let circuit = |eth_address: &[u8; ADDRESS_BYTES]| OffchainWithdrawalBatchCircuit {
+        batch_size: 1,
+        account_depth,
+        token_depth,
+        hash_params,
+        sign_params,
+        signing_domain: domain,
+        reject_zero_amount: false,
+        queue: vec![OffchainWithdrawalCircuit::<Bn256> {
+            account_state: account_state.clone(),
+            account_id: Some(usize_to_fr(1)),
+            token_id: Some(usize_to_fr(0)),
+            amount: Some(usize_to_fr(20)),
+            fee: Some(usize_to_fr(0)),
+            nonce: Some(usize_to_fr(1)),
+            valid_until: Some(usize_to_fr(0)),
+            eth_address: Some(address_to_fr(eth_address)),
+            sign: withdrawal.sign.clone(),
+            pubkey: Some(pubkey.0.clone()),
+            is_noop: Some(false),
+        }],
+        fee_account_state: fee_account_state.clone(),
+        fee_account_id: Some(usize_to_fr(0)),
+        fee_token_id: Some(usize_to_fr(0)),
+        total_fee: Some(usize_to_fr(0)),
+        timestamp: Some(usize_to_fr(0)),
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(withdrawal.record_hash(old_hash, hash_params)),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    };
+
+    assert!(check_circuit(circuit(&withdrawal.eth_address)).is_ok());
+    let unsatisfied = check_circuit(circuit(&redirected.eth_address)).unwrap_err();
+    assert!(unsatisfied.path.contains("verify signature"), "{}", unsatisfied);
+}
+
+#[test]
+pub fn key_derivation() {
+    let sign_params = jubjub_params();
+    let vectors: serde_json::Value = serde_json::from_str(include_str!("key_derivation_vectors.json")).unwrap();
+
+    for vector in vectors["vectors"].as_array().unwrap() {
+        let field = |name: &str| vector[name].as_str().unwrap().to_string();
+        let mut signature = [0u8; 65];
+        signature.copy_from_slice(&hex::decode(&field("signature")[2..]).unwrap());
+
+        let (seckey, pubkey) = derive_keypair(&signature, sign_params);
+        let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+        assert_eq!(format!("{}", seckey.expose_private_key().0.into_repr()), field("private_key"));
+        assert_eq!(format!("{}", pubkey_x.into_repr()), field("pubkey_x"));
+        assert_eq!(format!("{}", pubkey_y.into_repr()), field("pubkey_y"));
+        assert!(pubkey.0.as_prime_order(sign_params).is_some());
+    }
+
+    // any bit of the signature changes the key
+    let signature = [7u8; 65];
+    let mut flipped = signature;
+    flipped[31] ^= 1;
+    assert!(derive_private_key(&signature).expose_private_key().0 != derive_private_key(&flipped).expose_private_key().0);
+}
+
+#[test]
+pub fn secret_key() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+
+    let seckey = SecretKey::from_seed(b"correct horse battery staple");
+    assert!(seckey.expose_private_key().0 == SecretKey::from_seed(b"correct horse battery staple").expose_private_key().0);
+    assert!(seckey.expose_private_key().0 != SecretKey::from_seed(b"correct horse battery stapler").expose_private_key().0);
+
+    let pubkey = seckey.public_key(sign_params);
+    let raw_pubkey = PublicKey::from_private(seckey.expose_private_key(), FixedGenerators::SpendingKeyGenerator, sign_params);
+    assert_eq!(pubkey.0.into_xy(), raw_pubkey.0.into_xy());
+
+    // the same signature as sign_deterministic with the raw key
+    let withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    let mut signed = withdrawal.clone();
+    seckey.sign_withdrawal(&mut signed, &domain, hash_params, sign_params);
+    assert_eq!(signed.verify_signature(&pubkey, &domain, None, None), Ok(()));
+    let mut expected = withdrawal;
+    expected.sign_deterministic(&secret(seckey.expose_private_key()), &domain, None, None);
+    assert_eq!(signed.encode().unwrap(), expected.encode().unwrap());
+
+    let mut wiped = SecretKey::from_seed(b"correct horse battery staple");
+    zeroize::Zeroize::zeroize(&mut wiped);
+    assert!(wiped.expose_private_key().0.is_zero());
+}
+
+#[test]
+pub fn signature_malleability() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+
+    let seckey = SecretKey::from_seed(b"malleability");
+    let pubkey = seckey.public_key(sign_params);
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    seckey.sign_withdrawal(&mut withdrawal, &domain, hash_params, sign_params);
+    let r = withdrawal.sign.as_ref().unwrap().r.clone();
+    assert_eq!(withdrawal.verify_signature(&pubkey, &domain, None, None), Ok(()));
+
+    // small order points: the identity, (0, -1) of order 2, (x, 0) of order 4,
+    // and the signed r with the order 2 point added
+    let mut minus_one = bn256::Fr::one();
+    minus_one.negate();
+    let order_two = Point::<Bn256, Unknown>::from_xy(bn256::Fr::zero(), minus_one, sign_params).unwrap();
+    let order_four = Point::<Bn256, Unknown>::get_for_y(bn256::Fr::zero(), false, sign_params).unwrap();
+    assert!(order_four.double(sign_params).eq(&order_two));
+    let mixed = r.add(&order_two, sign_params);
+
+    for (name, point) in [
+        ("identity", Point::<Bn256, Unknown>::zero()),
+        ("order 2", order_two),
+        ("order 4", order_four),
+        ("r + order 2", mixed),
+    ].iter() {
+        let mut forged = withdrawal.clone();
+        forged.sign.as_mut().unwrap().r = point.clone();
+        assert_eq!(
+            forged.verify_signature(&pubkey, &domain, None, None),
+            Err(SignatureError::InvalidPoint.into()),
+            "r {}", name,
+        );
+        assert_eq!(
+            withdrawal.verify_signature(&PublicKey(point.clone()), &domain, None, None),
+            Err(SignatureError::InvalidPoint.into()),
+            "pubkey {}", name,
+        );
+        let requests = vec![(forged.clone(), pubkey.clone())];
+        assert_eq!(verify_signatures_batch(&requests, &domain, hash_params, sign_params), vec![Err(SignatureError::InvalidPoint.into())]);
+        assert_eq!(
+            verify_signatures_combined(&requests, &domain, hash_params, sign_params, &mut thread_rng()),
+            vec![Err(SignatureError::InvalidPoint.into())],
+        );
+
+        assert_eq!(
+            OffchainWithdrawal::decode(&forged.encode().unwrap(), None).err(),
+            Some(EncodingError::InvalidPoint.into()),
+            "encoded r {}", name,
+        );
+        assert!(serde_json::from_value::<OffchainWithdrawal>(serde_json::to_value(&forged).unwrap()).is_err(), "json r {}", name);
+    }
+
+    // s at or above the subgroup order: the order itself, the order + 1, 2^256 - 1
+    let order = Fs::char();
+    let mut order_plus_one = order;
+    order_plus_one.add_nocarry(&FsRepr::from(1));
+    let bytes = withdrawal.encode().unwrap();
+    let json = serde_json::to_value(&withdrawal).unwrap();
+
+    for (name, repr) in [
+        ("order", order),
+        ("order + 1", order_plus_one),
+        ("2^256 - 1", FsRepr([u64::MAX; 4])),
+    ].iter() {
+        let mut forged = bytes.clone();
+        let len = forged.len();
+        repr.write_le(&mut forged[len - 32..]).unwrap();
+        assert_eq!(
+            OffchainWithdrawal::decode(&forged, None).err(),
+            Some(EncodingError::NonCanonicalScalar.into()),
+            "encoded s {}", name,
+        );
+
+        let mut forged = json.clone();
+        forged["sign"]["s"] = serde_json::Value::from(format!("{}", repr));
+        assert!(serde_json::from_value::<OffchainWithdrawal>(forged).is_err(), "json s {}", name);
+    }
+
+    // the canonical encodings still decode to a valid signature
+    let decoded = OffchainWithdrawal::decode(&bytes, None).unwrap();
+    assert_eq!(decoded.verify_signature(&pubkey, &domain, None, None), Ok(()));
+    let decoded: OffchainWithdrawal = serde_json::from_value(json).unwrap();
+    assert_eq!(decoded.verify_signature(&pubkey, &domain, None, None), Ok(()));
+}
+
+#[test]
+pub fn musig_account() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+    let mut rng = thread_rng();
+
+    let custodian = SecretKey::from_seed(b"custodian");
+    let client = SecretKey::from_seed(b"client");
+    let aggregate = AggregateKey::new(
+        vec![custodian.public_key(sign_params), client.public_key(sign_params)],
+        sign_params,
+    ).unwrap();
+    let pubkey = aggregate.pubkey().clone();
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(20), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    let message = fr_to_sign_message(withdrawal.hash(&domain, None));
+    assert_eq!(message.len(), MAX_MESSAGE_BYTES);
+
+    // round one: commitments, round two: nonces, then partial signatures
+    let mut sessions: Vec<_> = [&custodian, &client].iter().map(
+        |seckey| SigningSession::new(seckey, &aggregate, &message, sign_params, &mut rng).unwrap()
+    ).collect();
+    let commitments: Vec<_> = sessions.iter().map(|session| session.commitment()).collect();
+    let nonces: Vec<_> = sessions.iter_mut().map(
+        |session| session.reveal_nonce(commitments.clone()).unwrap()
+    ).collect();
+    let partials: Vec<_> = sessions.into_iter().map(
+        |session| session.partial_sign(&nonces, sign_params).unwrap()
+    ).collect();
+
+    withdrawal.sign = Some(aggregate_signatures(&aggregate, &message, &nonces, &partials, sign_params).unwrap());
+    assert_eq!(withdrawal.verify_signature(&pubkey, &domain, None, None), Ok(()));
+    for signer in aggregate.pubkeys() {
+        assert_eq!(
+            withdrawal.verify_signature(signer, &domain, None, None),
+            Err(SignatureError::VerificationFailed.into()),
+        );
+    }
+
+    // the leaf holds the aggregate key, the circuit is the usual one
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }.update_tree_and_record_state(&mut tree).unwrap();
+
+    let old_hash = bn256::Fr::zero();
+    let new_hash = poseidon_hash::<Bn256>(
+        hash_params,
+        &[
+            usize_to_fr(OFFCHAIN_WITHDRAWAL_OP), old_hash, usize_to_fr(1), usize_to_fr(0), usize_to_fr(20),
+            address_to_fr(&ETH_ADDRESS),
+        ],
+    )[0];
+    let old_root = tree.get_root();
+    let account_state = withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+    let fee_account_state = credit_fee_and_record_state(&mut tree, AccountId(0), 0, Balance(0)).unwrap();
+
+    let circuit = OffchainWithdrawalBatchCircuit {
+        batch_size: 1,
+        account_depth,
+        token_depth,
+        hash_params,
+        sign_params,
+        signing_domain: domain,
+        reject_zero_amount: false,
+        queue: vec![OffchainWithdrawalCircuit::<Bn256> {
+            account_state,
+            account_id: Some(usize_to_fr(1)),
+            token_id: Some(usize_to_fr(0)),
+            amount: Some(usize_to_fr(20)),
+            fee: Some(usize_to_fr(0)),
+            nonce: Some(usize_to_fr(1)),
+            valid_until: Some(usize_to_fr(0)),
+            eth_address: Some(address_to_fr(&ETH_ADDRESS)),
+            sign: withdrawal.sign.clone(),
+            pubkey: Some(pubkey.0.clone()),
+            is_noop: Some(false),
+        }],
+        fee_account_state,
+        fee_account_id: Some(usize_to_fr(0)),
+        fee_token_id: Some(usize_to_fr(0)),
+        total_fee: Some(usize_to_fr(0)),
+        timestamp: Some(usize_to_fr(0)),
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(new_hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    };
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    // a tampered partial signature is named, a nonce has to match its commitment
+
+    let mut tampered = partials.clone();
+    tampered[1].0.add_assign(&Fs::one());
+    assert_eq!(
+        aggregate_signatures(&aggregate, &message, &nonces, &tampered, sign_params).err(),
+        Some(MusigError::InvalidPartialSignature(1)),
+    );
+    assert_eq!(
+        aggregate_signatures(&aggregate, &message, &nonces, &partials[..1], sign_params).err(),
+        Some(MusigError::WrongSignerCount { expected: 2, actual: 1 }),
+    );
+
+    let mut session = SigningSession::new(&custodian, &aggregate, &message, sign_params, &mut rng).unwrap();
+    let other = SigningSession::new(&client, &aggregate, &message, sign_params, &mut rng).unwrap();
+    let commitments = vec![session.commitment(), other.commitment()];
+    assert_eq!(
+        SigningSession::new(&custodian, &aggregate, &message, sign_params, &mut rng).unwrap()
+            .partial_sign(&nonces, sign_params).err(),
+        Some(MusigError::CommitmentsMissing),
+    );
+    let nonce = session.reveal_nonce(commitments).unwrap();
+    // the client swaps its nonce for an earlier one after seeing the commitments
+    assert_eq!(
+        session.partial_sign(&[nonce, nonces[1].clone()], sign_params).err(),
+        Some(MusigError::CommitmentMismatch(1)),
+    );
+
+    let stranger = SecretKey::from_seed(b"stranger");
+    assert_eq!(
+        SigningSession::new(&stranger, &aggregate, &message, sign_params, &mut rng).err(),
+        Some(MusigError::NotASigner),
+    );
+}
+
+#[test]
+pub fn shared_params_cache() {
+    let params = shared_params();
+    assert!(Arc::ptr_eq(&params, &shared_params()));
+    assert!(std::ptr::eq(poseidon_params(), &params.hash_params));
+    assert!(std::ptr::eq(jubjub_params(), &params.sign_params));
+
+    // None is the same as passing the params explicitly
+    let built = Params::new();
+    let domain = SigningDomain::default();
+    let seckey = SecretKey::from_seed(b"shared params");
+    let pubkey = seckey.public_key(&built.sign_params);
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(0), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    assert_eq!(withdrawal.hash(&domain, None), withdrawal.hash(&domain, Some(&built.hash_params)));
+    withdrawal.sign_deterministic(&seckey, &domain, None, None);
+    assert_eq!(
+        withdrawal.verify_signature(&pubkey, &domain, Some(&built.hash_params), Some(&built.sign_params)),
+        Ok(()),
+    );
+
+    // the deposit circuit owns its params and can be moved to a proving thread
+    let mut tree = AccountsTree::new(2, 1, poseidon_params(), jubjub_params());
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree,
+        &[Deposit { pubkey: Some(pubkey), account_id: 1, token_id: 0, amount: 10 }],
+        1, 2, 1, &params,
+    );
+    let satisfied = std::thread::spawn(move || {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        circuit.synthesize(&mut cs).unwrap();
+        cs.is_satisfied()
+    }).join().unwrap();
+    assert!(satisfied);
+}
+
+#[test]
+pub fn prover_pipeline() {
+    let account_depth = 4;
+    let token_depth = 1;
+    let config = BatchConfig { deposit_batch: 2, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..2).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    )).collect();
+
+    let path = std::env::temp_dir().join(format!("openplasma_deposit_key_{}.bin", std::process::id()));
+    let generated = generate_parameters(config, &path).unwrap();
+    let (loaded_config, params) = load_parameters(&path).unwrap();
+    assert_eq!(loaded_config, config);
+    assert!(params == generated);
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let deposits = [
+        Deposit { pubkey: Some(pubkeys[0].clone()), account_id: 3, token_id: 0, amount: 10 },
+        Deposit { pubkey: Some(pubkeys[1].clone()), account_id: 12, token_id: 1, amount: 20 },
+    ];
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree, &deposits, 2, account_depth, token_depth, &shared_params());
+    let public_inputs = PublicInputs::<Bn256>::new(
+        circuit.old_accum_hash.unwrap(),
+        circuit.new_accum_hash.unwrap(),
+        circuit.old_account_root.unwrap(),
+        circuit.new_account_root.unwrap(),
+    );
+
+    let proof = prove_deposit_block(&params, circuit).unwrap();
+    let verifying_key = prepare_verifying_key(&params.vk);
+    let layout = load_layout(&path).unwrap();
+    assert_eq!(layout, CircuitFamily::new(shared_params()).layout(config).unwrap());
+    assert!(verify_block(&verifying_key, &proof, &layout, &public_inputs));
+
+    let mut wrong_root = public_inputs.clone();
+    wrong_root.new_account_root = public_inputs.old_account_root;
+    assert!(!verify_block(&verifying_key, &proof, &layout, &wrong_root));
+
+    // a batch of another size is refused before proving
+    let single = padded_deposit_batch_circuit(
+        &mut tree, &deposits[..1], 1, account_depth, token_depth, &shared_params());
+    assert!(prove_deposit_block(&params, single).is_err());
+
+    // a key whose header names another config or isn't a key file is rejected
+    let bytes = std::fs::read(&path).unwrap();
+    let mut other_batch = bytes.clone();
+    other_batch[8] = 1;
+    std::fs::write(&path, &other_batch).unwrap();
+    assert!(load_parameters(&path).is_err());
+
+    let mut not_a_key = bytes;
+    not_a_key[0] ^= 1;
+    std::fs::write(&path, &not_a_key).unwrap();
+    assert!(load_parameters(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(layout_path(&path)).unwrap();
+}
+
+#[test]
+pub fn verifier_export() {
+    // the generators pin the coordinate order: G2 is x_im, x_re, y_im, y_re
+    // as in EIP-197, the circom and zksync verifiers and their Pairing.P2()
+    let generators = VerifyingKey::<Bn256> {
+        alpha_g1: G1Affine::one(),
+        beta_g1: G1Affine::one(),
+        beta_g2: G2Affine::one(),
+        gamma_g2: G2Affine::one(),
+        delta_g1: G1Affine::one(),
+        delta_g2: G2Affine::one(),
+        ic: vec![G1Affine::one(); 5],
+    };
+    let json: serde_json::Value = serde_json::from_str(&export_vk_json(&generators)).unwrap();
+    assert_eq!(json["n_inputs"], 4);
+    assert_eq!(json["alpha_g1"], serde_json::json!([
+        "0x0000000000000000000000000000000000000000000000000000000000000001",
+        "0x0000000000000000000000000000000000000000000000000000000000000002",
+    ]));
+    assert_eq!(json["gamma_g2"], serde_json::json!([
+        [
+            "0x198e9393920d483a7260bfb731fb5d25f1aa493335a9e71297e485b7aef312c2",
+            "0x1800deef121f1e76426a00665e5c4479674322d4f75edadd46debd5cd992f6ed",
+        ],
+        [
+            "0x090689d0585ff075ec9e99ad690c3395bc4b313370b38ef355acdadcd122975b",
+            "0x12c85ea5db8c6deb4aab71808dcb408fe3d1e7690c43d37b4ce6cc0166fa7daa",
+        ],
+    ]));
+
+    // any key reads back, the real part first is not a curve point
+    let g1 = |rng: &mut XorShiftRng| G1Affine::one().mul(rng.gen::<bn256::Fr>()).into_affine();
+    let g2 = |rng: &mut XorShiftRng| G2Affine::one().mul(rng.gen::<bn256::Fr>()).into_affine();
+    let mut rng = XorShiftRng::from_seed([0x5dee_ce66, 1, 2, 3]);
+    let vk = VerifyingKey::<Bn256> {
+        alpha_g1: g1(&mut rng),
+        beta_g1: g1(&mut rng),
+        beta_g2: g2(&mut rng),
+        gamma_g2: g2(&mut rng),
+        delta_g1: g1(&mut rng),
+        delta_g2: g2(&mut rng),
+        ic: (0..5).map(|_| g1(&mut rng)).collect(),
+    };
+    let exported = export_vk_json(&vk);
+    assert!(import_vk_json(&exported).unwrap() == vk);
+
+    let mut swapped: serde_json::Value = serde_json::from_str(&exported).unwrap();
+    for pair in swapped["delta_g2"].as_array_mut().unwrap() {
+        pair.as_array_mut().unwrap().reverse();
+    }
+    assert!(import_vk_json(&swapped.to_string()).is_err());
+    let mut short: serde_json::Value = serde_json::from_str(&exported).unwrap();
+    short["ic"].as_array_mut().unwrap().pop();
+    assert!(import_vk_json(&short.to_string()).is_err());
+
+    // the contract embeds the same words and takes one input per ic point but the first
+    let solidity = export_solidity_verifier(&vk, 4).unwrap();
+    let [[x_im, x_re], _] = serde_json::from_value::<[[String; 2]; 2]>(swapped["gamma_g2"].take()).unwrap();
+    assert!(solidity.contains(&format!("uint256 constant GAMMA_X_IM = {};", x_im)));
+    assert!(solidity.contains(&format!("uint256 constant GAMMA_X_RE = {};", x_re)));
+    assert!(solidity.contains("uint256[4] calldata input"));
+    assert!(solidity.contains("ecMul([IC4_X, IC4_Y], input[3])"));
+    assert!(!solidity.contains("IC5_X"));
+    assert_eq!(export_solidity_verifier(&vk, 3), Err(SynthesisError::MalformedVerifyingKey.into()));
+}
+
+#[test]
+pub fn proof_eth_encoding() {
+    let account_depth = 2;
+    let token_depth = 1;
+    let params = shared_params();
+    let circuit_params = setup_deposit_circuit(1, account_depth, token_depth, &params).unwrap();
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    );
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let deposits = [Deposit { pubkey: Some(pubkey), account_id: 1, token_id: 1, amount: 5 }];
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree, &deposits, 1, account_depth, token_depth, &params);
+    let public_inputs = PublicInputs::<Bn256>::new(
+        circuit.old_accum_hash.unwrap(),
+        circuit.new_accum_hash.unwrap(),
+        circuit.old_account_root.unwrap(),
+        circuit.new_account_root.unwrap(),
+    );
+    let proof = prove_deposit_block(&circuit_params, circuit).unwrap();
+
+    // a, b and c are the words verifyProof takes, b imaginary part first
+    let bytes = proof_to_eth_bytes(&proof);
+    assert_eq!(bytes.len(), PROOF_ETH_BYTES);
+    assert_eq!(&bytes[..64], proof.a.into_uncompressed().as_ref());
+    assert_eq!(&bytes[64..192], proof.b.into_uncompressed().as_ref());
+    assert_eq!(&bytes[192..], proof.c.into_uncompressed().as_ref());
+
+    let decoded = proof_from_eth_bytes(&bytes).unwrap();
+    assert!(decoded == proof);
+    let verifying_key = prepare_verifying_key(&circuit_params.vk);
+    let layout = CircuitFamily::new(Arc::clone(&params)).layout(BatchConfig { deposit_batch: 1, account_depth, token_depth, leaf_version: LeafVersion::V0 }).unwrap();
+    assert!(verify_block(&verifying_key, &decoded, &layout, &public_inputs));
+
+    // the inputs are one big endian word each in the PublicInputs order
+    let inputs = public_inputs_to_eth_bytes(&public_inputs);
+    assert_eq!(inputs.len(), 4 * 32);
+    for (word, input) in inputs.chunks(32).zip(public_inputs.to_vec()) {
+        let mut repr = bn256::FrRepr::default();
+        repr.read_be(word).unwrap();
+        assert_eq!(bn256::Fr::from_repr(repr).unwrap(), input);
+    }
+
+    // zero words are the point at infinity as for the precompiles
+    let mut infinity = proof.clone();
+    infinity.a = G1Affine::zero();
+    infinity.b = G2Affine::zero();
+    let infinity_bytes = proof_to_eth_bytes(&infinity);
+    assert!(infinity_bytes[..192].iter().all(|byte| *byte == 0));
+    assert!(proof_from_eth_bytes(&infinity_bytes).unwrap() == infinity);
+
+    // off the curve, a flag bit, the wrong length and b with swapped parts are rejected
+    let mut off_curve = bytes;
+    off_curve[63] ^= 1;
+    assert!(proof_from_eth_bytes(&off_curve).is_err());
+    let mut flagged = [0u8; PROOF_ETH_BYTES];
+    flagged[0] = 0x40;
+    assert!(proof_from_eth_bytes(&flagged).is_err());
+    assert!(proof_from_eth_bytes(&bytes[..PROOF_ETH_BYTES - 1]).is_err());
+    let mut swapped = bytes;
+    swapped[64..192].copy_from_slice(&[&bytes[96..128], &bytes[64..96], &bytes[160..192], &bytes[128..160]].concat());
+    assert!(proof_from_eth_bytes(&swapped).is_err());
+}
+
+#[test]
+pub fn proving_pool() {
+    let account_depth = 2;
+    let token_depth = 1;
+    let params = shared_params();
+    let circuit_params = Arc::new(setup_deposit_circuit(1, account_depth, token_depth, &params).unwrap());
+    let verifying_key = prepare_verifying_key(&circuit_params.vk);
+    let layout = CircuitFamily::new(Arc::clone(&params)).layout(BatchConfig { deposit_batch: 1, account_depth, token_depth, leaf_version: LeafVersion::V0 }).unwrap();
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    );
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let mut block = |block_number: u64, amount: u128| {
+        let deposits = [Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 0, amount }];
+        let circuit = padded_deposit_batch_circuit(
+            &mut tree, &deposits, 1, account_depth, token_depth, &params);
+        let public_inputs = PublicInputs::<Bn256>::new(
+            circuit.old_accum_hash.unwrap(),
+            circuit.new_accum_hash.unwrap(),
+            circuit.old_account_root.unwrap(),
+            circuit.new_account_root.unwrap(),
+        );
+        ProofJob { block_number, circuit, public_inputs }
+    };
+
+    // three blocks at once, delivered in block order whichever finishes first
+    let pool = Pool::new(3, Arc::clone(&circuit_params));
+    for block_number in 1..=3 {
+        pool.submit(block(block_number, u128::from(block_number)));
+    }
+    for block_number in 1..=3 {
+        let result = pool.results().recv().unwrap();
+        assert_eq!(result.block_number, block_number);
+        assert!(verify_block(&verifying_key, &result.proof.unwrap(), &layout, &result.public_inputs));
+    }
+    assert_eq!(pool.cancel(3), 0);
+    drop(pool);
+
+    // a panic is delivered as an error and the worker goes on with the next
+    // job, a cancelled job comes back in its place in the order
+    let thirteen = bn256::Fr::from_str("13").unwrap();
+    let prove_params = Arc::clone(&circuit_params);
+    let pool = Pool::with_prover(1, move |circuit| {
+        if circuit.deposit_queue.witnesses().unwrap()[0].amount == Some(thirteen) {
+            panic!("unlucky block");
+        }
+        prove_deposit_block(&prove_params, circuit)
+    });
+    pool.submit(block(4, 13));
+    pool.submit(block(5, 5));
+    pool.submit(block(6, 6));
+    assert_eq!(pool.cancel(5), 1);
+
+    let panicked = pool.results().recv().unwrap();
+    assert_eq!(panicked.block_number, 4);
+    assert!(matches!(panicked.proof, Err(ProofError::Panicked(ref msg)) if msg == "unlucky block"));
+    let cancelled = pool.results().recv().unwrap();
+    assert_eq!(cancelled.block_number, 5);
+    assert!(matches!(cancelled.proof, Err(ProofError::Cancelled)));
+    let proven = pool.results().recv().unwrap();
+    assert_eq!(proven.block_number, 6);
+    assert!(verify_block(&verifying_key, &proven.proof.unwrap(), &layout, &proven.public_inputs));
+}
+
+#[test]
+pub fn lazy_deposit_queue() {
+    let account_depth = 3;
+    let token_depth = 1;
+    let deposit_batch = 3;
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    );
+    let deposits = [
+        Deposit { pubkey: Some(pubkey.clone()), account_id: 2, token_id: 0, amount: 7 },
+        Deposit { pubkey: Some(pubkey), account_id: 2, token_id: 1, amount: 9 },
+    ];
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let held = padded_deposit_batch_circuit(
+        &mut tree, &deposits, deposit_batch, account_depth, token_depth, &shared_params());
+
+    // the source is asked for every deposit in order, once per synthesis
+    let witnesses = held.deposit_queue.witnesses().unwrap().to_vec();
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let source_asked = Arc::clone(&asked);
+    let lazy = DepositBatchCircuit {
+        deposit_queue: DepositQueue::lazy(move |i| {
+            source_asked.lock().unwrap().push(i);
+            witnesses[i].clone()
+        }),
+        ..held.clone()
+    };
+    assert!(lazy.deposit_queue.witnesses().is_none());
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    lazy.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+    assert_eq!(*asked.lock().unwrap(), vec![0, 1, 2]);
+    assert_eq!(shape(lazy).unwrap(), shape(held.clone()).unwrap());
+    assert_eq!(asked.lock().unwrap().len(), 6);
+
+    // a produced witness that doesn't fit the public inputs is caught the same way
+    let mut wrong = held.deposit_queue.witnesses().unwrap().to_vec();
+    wrong[1].amount = Some(usize_to_fr(10));
+    let circuit = DepositBatchCircuit {
+        deposit_queue: DepositQueue::lazy(move |i| wrong[i].clone()),
+        ..held
+    };
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+}
+
+#[test]
+pub fn batched_block_proofs() {
+    let account_depth = 2;
+    let token_depth = 1;
+    let params = shared_params();
+    let circuit_params = setup_deposit_circuit(1, account_depth, token_depth, &params).unwrap();
+    let verifying_key = prepare_verifying_key(&circuit_params.vk);
+    let layout = CircuitFamily::new(Arc::clone(&params)).layout(BatchConfig { deposit_batch: 1, account_depth, token_depth, leaf_version: LeafVersion::V0 }).unwrap();
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    );
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let blocks: Vec<_> = (1..=3).map(|amount| {
+        let deposits = [Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 0, amount }];
+        let circuit = padded_deposit_batch_circuit(
+            &mut tree, &deposits, 1, account_depth, token_depth, &params);
+        let public_inputs = PublicInputs::<Bn256>::new(
+            circuit.old_accum_hash.unwrap(),
+            circuit.new_accum_hash.unwrap(),
+            circuit.old_account_root.unwrap(),
+            circuit.new_account_root.unwrap(),
+        );
+        let proof = prove_deposit_block(&circuit_params, circuit).unwrap();
+        assert!(verify_block(&verifying_key, &proof, &layout, &public_inputs));
+        (proof, public_inputs)
+    }).collect();
+
+    for count in 1..=3 {
+        assert!(batch_verify(&circuit_params.vk, &ProofBatch::new(&blocks[..count])));
+    }
+
+    // the challenge is keccak256 of the eth encoding mod r, as a contract computes it
+    let empty = ProofBatch::new(&[]);
+    assert!(empty.to_eth_bytes().is_empty());
+    assert_eq!(
+        empty.challenge(),
+        bn256::Fr::from_str("1924180730567573949438414972962865885128629851683618892617351438379423999084").unwrap(),
+    );
+    assert!(!batch_verify(&circuit_params.vk, &empty));
+    assert_eq!(ProofBatch::new(&blocks).to_eth_bytes().len(), 3 * (256 + 4 * 32));
+
+    // inputs of another proof, a proof of no block and another key all fail
+    let mut swapped = ProofBatch::new(&blocks[..2]);
+    let inputs = swapped.proofs[0].1.clone();
+    swapped.proofs[0].1 = swapped.proofs[1].1.clone();
+    swapped.proofs[1].1 = inputs;
+    assert!(!batch_verify(&circuit_params.vk, &swapped));
+
+    let mut tampered = ProofBatch::new(&blocks[..2]);
+    tampered.proofs[1].0.c = tampered.proofs[0].0.c;
+    assert!(!batch_verify(&circuit_params.vk, &tampered));
+
+    let other_params = setup_deposit_circuit(1, account_depth, token_depth, &params).unwrap();
+    assert!(!batch_verify(&other_params.vk, &ProofBatch::new(&blocks[..2])));
+
+    let mut short_key = circuit_params.vk.clone();
+    short_key.ic.pop();
+    assert!(!batch_verify(&short_key, &ProofBatch { proofs: blocks[..1].to_vec() }));
+}
+
+#[test]
+pub fn aggregated_block_proofs() {
+    let account_depth = 2;
+    let token_depth = 1;
+    let params = shared_params();
+    let circuit_params = setup_deposit_circuit(1, account_depth, token_depth, &params).unwrap();
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    );
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let blocks: Vec<_> = (1..=4).map(|amount| {
+        let deposits = [Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 0, amount }];
+        let circuit = padded_deposit_batch_circuit(
+            &mut tree, &deposits, 1, account_depth, token_depth, &params);
+        let public_inputs = PublicInputs::<Bn256>::new(
+            circuit.old_accum_hash.unwrap(),
+            circuit.new_accum_hash.unwrap(),
+            circuit.old_account_root.unwrap(),
+            circuit.new_account_root.unwrap(),
+        );
+        (prove_deposit_block(&circuit_params, circuit).unwrap(), public_inputs)
+    }).collect();
+    let inputs: Vec<_> = blocks.iter().map(|(_, public_inputs)| public_inputs.clone()).collect();
+
+    let srs = AggregationSrs::generate(4, &mut rng).unwrap();
+    let srs_vk = srs.verifier_key();
+    for count in [1, 2, 4].iter() {
+        let proof = aggregate(&srs, &blocks[..*count]).unwrap();
+        assert_eq!(proof.tipp.rounds.len(), count.trailing_zeros() as usize);
+        assert!(verify_aggregated(&circuit_params.vk, &srs_vk, &inputs[..*count], &proof));
+    }
+
+    assert_eq!(aggregate(&srs, &blocks[..3]).err(), Some(AggregationError::NotPowerOfTwo(3)));
+    assert_eq!(aggregate(&srs, &[]).err(), Some(AggregationError::NotPowerOfTwo(0)));
+    let small_srs = AggregationSrs::generate(2, &mut rng).unwrap();
+    assert_eq!(aggregate(&small_srs, &blocks).err(), Some(AggregationError::TooManyProofs { proofs: 4, max: 2 }));
+    assert!(AggregationSrs::generate(3, &mut rng).is_err());
+
+    // inputs of other blocks or in another order, another count, key or srs fail
+    let proof = aggregate(&srs, &blocks).unwrap();
+    let mut swapped = inputs.clone();
+    swapped.swap(0, 1);
+    assert!(!verify_aggregated(&circuit_params.vk, &srs_vk, &swapped, &proof));
+    assert!(!verify_aggregated(&circuit_params.vk, &srs_vk, &inputs[..2], &proof));
+    let other_params = setup_deposit_circuit(1, account_depth, token_depth, &params).unwrap();
+    assert!(!verify_aggregated(&other_params.vk, &srs_vk, &inputs, &proof));
+    assert!(!verify_aggregated(&circuit_params.vk, &small_srs.verifier_key(), &inputs, &proof));
+
+    // a proof that doesn't verify can't be aggregated into one that does
+    let mut tampered = blocks.clone();
+    tampered[3].0.c = tampered[2].0.c;
+    assert!(!verify_aggregated(&circuit_params.vk, &srs_vk, &inputs, &aggregate(&srs, &tampered).unwrap()));
+
+    // nor can the aggregated proof be changed
+    let mut changed = proof.clone();
+    changed.tipp.rounds[1].com_left = changed.tipp.rounds[1].com_right;
+    assert!(!verify_aggregated(&circuit_params.vk, &srs_vk, &inputs, &changed));
+    let mut changed = proof.clone();
+    changed.mipp.rounds[0].z_left = changed.mipp.rounds[0].z_right;
+    assert!(!verify_aggregated(&circuit_params.vk, &srs_vk, &inputs, &changed));
+    let mut changed = proof.clone();
+    changed.openings.tipp_w.alpha = changed.openings.tipp_w.beta;
+    assert!(!verify_aggregated(&circuit_params.vk, &srs_vk, &inputs, &changed));
+    let mut changed = proof;
+    changed.mipp.v.beta = changed.tipp.v.beta;
+    assert!(!verify_aggregated(&circuit_params.vk, &srs_vk, &inputs, &changed));
+}
+
+#[cfg(feature = "plonk")]
+#[test]
+pub fn plonk_backend() {
+    use openplasma_circuits::plonk::{ PlonkSrs, PlonkProvingKey, PlonkVerifyingKey, plonk_setup, plonk_prove, plonk_verify };
+
+    let account_depth = 2;
+    let token_depth = 1;
+    let params = shared_params();
+    let circuit_params = setup_deposit_circuit(1, account_depth, token_depth, &params).unwrap();
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    );
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let deposits = [Deposit { pubkey: Some(pubkey), account_id: 1, token_id: 0, amount: 7 }];
+    let circuit = padded_deposit_batch_circuit(&mut tree, &deposits, 1, account_depth, token_depth, &params);
+    let public_inputs = PublicInputs::<Bn256>::new(
+        circuit.old_accum_hash.unwrap(),
+        circuit.new_accum_hash.unwrap(),
+        circuit.old_account_root.unwrap(),
+        circuit.new_account_root.unwrap(),
+    );
+    let config = BatchConfig {
+        deposit_batch: 1,
+        account_depth,
+        token_depth,
+        leaf_version: circuit.leaf_version,
+    };
+
+    // one universal srs, too small for the circuit it is refused
+    let srs = PlonkSrs::generate(1 << 17, &mut rng);
+    assert!(plonk_setup(config, &PlonkSrs::generate(1 << 10, &mut rng)).is_err());
+    let (pk, vk) = plonk_setup(config, &srs).unwrap();
+    assert_eq!(vk.layout, CircuitFamily::new(shared_params()).layout(config).unwrap());
+    assert!(vk.domain_size <= srs.max_gates());
+
+    // the same witness proves with both backends against the same inputs
+    let groth16 = prove_deposit_block(&circuit_params, circuit.clone()).unwrap();
+    assert!(verify_block(&prepare_verifying_key(&circuit_params.vk), &groth16, &vk.layout, &public_inputs));
+    let proof = plonk_prove(&pk, circuit.clone()).unwrap();
+    assert!(plonk_verify(&vk, &proof, &public_inputs));
+
+    // other inputs, a changed proof or another key fail
+    let mut other_inputs = public_inputs.clone();
+    other_inputs.new_account_root = other_inputs.old_account_root;
+    assert!(!plonk_verify(&vk, &proof, &other_inputs));
+    let mut changed = proof.clone();
+    changed.a_eval = changed.b_eval;
+    assert!(!plonk_verify(&vk, &changed, &public_inputs));
+    let mut changed = proof.clone();
+    changed.w_zeta = changed.w_zeta_omega;
+    assert!(!plonk_verify(&vk, &changed, &public_inputs));
+    let mut other_vk = vk.clone();
+    other_vk.sigmas.swap(0, 1);
+    assert!(!plonk_verify(&other_vk, &proof, &public_inputs));
+
+    // the keys round trip
+    let mut bytes = Vec::new();
+    vk.write(&mut bytes).unwrap();
+    assert_eq!(PlonkVerifyingKey::read(&bytes[..]).unwrap(), vk);
+    assert!(PlonkProvingKey::read(&bytes[..]).is_err());
+    let mut bytes = Vec::new();
+    pk.write(&mut bytes).unwrap();
+    assert_eq!(PlonkProvingKey::read(&bytes[..]).unwrap(), pk);
+
+    // a witness that doesn't satisfy the circuit or of another config is refused
+    let mut bad = circuit.clone();
+    bad.new_account_root = bad.old_account_root;
+    assert!(matches!(plonk_prove(&pk, bad), Err(OpenPlasmaError::Circuit(SynthesisError::Unsatisfiable))));
+    let mut other = circuit;
+    other.deposit_batch = 2;
+    assert!(matches!(plonk_prove(&pk, other), Err(OpenPlasmaError::Io(_))));
+}
+
+#[test]
+pub fn sha256_deposit_accumulator() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+    let params = shared_params();
+
+    // sha256("abc") with the top 3 bits cleared, as the contract truncates it
+    assert_eq!(
+        compute_pubdata_commitment(b"abc"),
+        bn256::Fr::from_str("11972312713768178226791969297712321251811143278991161852801995824771111065005").unwrap(),
+    );
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..3).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let deposits = [
+        OffchainDeposit { account_id: AccountId(1), pubkey: pubkeys[0].clone(), token_id: 0, amount: Balance(40) },
+        OffchainDeposit { account_id: AccountId(2), pubkey: pubkeys[1].clone(), token_id: 1, amount: Balance(u64::MAX as u128) },
+        OffchainDeposit { account_id: AccountId(3), pubkey: pubkeys[2].clone(), token_id: 1, amount: Balance(7) },
+    ];
+
+    let old_hash: bn256::Fr = rng.gen();
+    let old_root = tree.get_root();
+    let mut accum_hash = old_hash;
+    let mut pubdata = Vec::new();
+    let config = BatchConfig { deposit_batch: 4, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let mut builder = DepositBatchCircuit::builder(config, &params);
+    for deposit in deposits.iter() {
+        let account_state = deposit.update_tree_and_record_state(&mut tree).unwrap();
+        accum_hash = deposit.pubdata_hash(accum_hash).unwrap();
+        pubdata.push(deposit.encode().unwrap());
+        builder = builder.push(deposit.clone().into_circuit(account_state));
+    }
+
+    // the contract chains the calldata the same way
+    let chained = pubdata.iter().fold(old_hash, |hash, bytes| accumulate_pubdata(hash, bytes));
+    assert_eq!(chained, accum_hash);
+
+    // the noop padding is not part of the calldata
+    let batch = builder
+        .pad_with_noops()
+        .old_accum_hash(old_hash)
+        .new_accum_hash(accum_hash)
+        .old_account_root(old_root)
+        .new_account_root(tree.get_root())
+        .build()
+        .unwrap();
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    Sha256DepositBatchCircuit { batch: batch.clone() }.synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    // the poseidon batch doesn't take the sha256 hash and the other way round
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    batch.clone().synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let mut wrong_hash = batch.clone();
+    wrong_hash.new_accum_hash = Some(deposits[2].pubdata_hash(old_hash).unwrap());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    Sha256DepositBatchCircuit { batch: wrong_hash }.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    // the cost per deposit of either accumulator
+    let per_deposit = |sha256: bool| {
+        let constraints = |deposit_batch| {
+            let batch = DepositBatchCircuit::empty(deposit_batch, account_depth, token_depth, &params);
+            if sha256 {
+                measure(Sha256DepositBatchCircuit { batch }).unwrap().constraints
+            } else {
+                measure(batch).unwrap().constraints
+            }
+        };
+        constraints(2) - constraints(1)
+    };
+    let poseidon = per_deposit(false);
+    let sha256 = per_deposit(true);
+    println!(
+        "constraints per deposit at account depth {}: poseidon {}, sha256 {}",
+        account_depth, poseidon, sha256,
+    );
+    assert!(sha256 > poseidon);
+}
+
+// constraints of the tree hash at one level: the root of a depth 1 path less
+// the leaf hash alone
+fn merkle_level_constraints<H: TreeHasher<Bn256>>(params: &H::Params) -> usize {
+    let constraints = |depth: usize| {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let leaf = vec![AllocatedNum::alloc(cs.namespace(|| "leaf"), || Ok(bn256::Fr::one())).unwrap()];
+        let path: Vec<_> = (0..depth).map(|i| AllocatedNum::alloc(
+            cs.namespace(|| format!("sibling {}", i)),
+            || Ok(bn256::Fr::one()),
+        ).unwrap()).collect();
+        let indices: Vec<_> = (0..depth).map(|i| Boolean::from(AllocatedBit::alloc(
+            cs.namespace(|| format!("index {}", i)),
+            Some(true),
+        ).unwrap())).collect();
+
+        let before = cs.num_constraints();
+        calc_root_with_hasher::<Bn256, H, _>(cs.namespace(|| "root"), params, 2, &leaf, &path, &indices).unwrap();
+        assert!(cs.is_satisfied());
+        cs.num_constraints() - before
+    };
+    constraints(1) - constraints(0)
+}
+
+#[test]
+pub fn rescue_hasher() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    // the gadget computes rescue_hash, inputs of any length
+    let mut rng = thread_rng();
+    for len in [0, 1, 2, 4, 5].iter() {
+        let input: Vec<bn256::Fr> = (0..*len).map(|_| rng.gen()).collect();
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let input_alloc: Vec<_> = input.iter().enumerate().map(|(i, value)| AllocatedNum::alloc(
+            cs.namespace(|| format!("input {}", i)),
+            || Ok(*value),
+        ).unwrap()).collect();
+        let hash = Rescue::hash_circuit(cs.namespace(|| "hash"), rescue_params(), &input_alloc).unwrap();
+        assert!(cs.is_satisfied());
+        assert_eq!(hash.get_value(), Some(rescue_hash(rescue_params(), &input)));
+        assert_ne!(Rescue::hash(rescue_params(), &input), <Poseidon as TreeHasher<Bn256>>::hash(hash_params, &input));
+    }
+    // trailing zeros are not padding
+    assert_ne!(
+        rescue_hash(rescue_params(), &[bn256::Fr::one()]),
+        rescue_hash(rescue_params(), &[bn256::Fr::one(), bn256::Fr::zero()]),
+    );
+
+    // the same deposit into a rescue tree
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+    let poseidon_tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let mut tree = AccountsTree::<Rescue>::new_with_hasher(account_depth, token_depth, rescue_params(), sign_params);
+    assert_ne!(tree.get_root(), poseidon_tree.get_root());
+
+    let old_root = tree.get_root();
+    let account_state = tree.apply_batch(&[LeafUpdate {
+        account_id: 1,
+        token_id: 0,
+        pubkey: Some(pubkey.clone()),
+        credit: 5,
+        debit: 0,
+        increment_nonce: false,
+    }]).unwrap().remove(0);
+    let deposit = DepositCircuit::<Bn256> {
+        account_state,
+        pubkey: Some(pubkey.0.clone()),
+        account_id: Some(usize_to_fr(1)),
+        token_id: Some(usize_to_fr(0)),
+        amount: Some(usize_to_fr(5)),
+        is_noop: Some(false),
+        is_refund: Some(false),
+    };
+
+    let process = |rescue: bool| {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let old_hash = AllocatedNum::alloc(cs.namespace(|| "old hash"), || Ok(bn256::Fr::zero())).unwrap();
+        let root = AllocatedNum::alloc(cs.namespace(|| "old root"), || Ok(old_root)).unwrap();
+        let (_, new_root) = if rescue {
+            deposit.process_deposit::<_, Rescue>(
+                cs.namespace(|| "deposit"), account_depth, token_depth, rescue_params(), sign_params, hash_params,
+                &old_hash, &root, DepositAccumulator::Poseidon, false, LeafVersion::V0,
+            ).unwrap()
+        } else {
+            deposit.process_deposit::<_, Poseidon>(
+                cs.namespace(|| "deposit"), account_depth, token_depth, hash_params, sign_params, hash_params,
+                &old_hash, &root, DepositAccumulator::Poseidon, false, LeafVersion::V0,
+            ).unwrap()
+        };
+        (cs.is_satisfied(), new_root.get_value())
+    };
+    assert_eq!(process(true), (true, Some(tree.get_root())));
+    assert!(!process(false).0);
+
+    // a signature of the rescue hash doesn't verify for the poseidon one and
+    // the other way round
+    let domain = SigningDomain::default();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let signer = PublicKey::from_private(&seckey, FixedGenerators::SpendingKeyGenerator, sign_params);
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1),
+        token_id: 0,
+        amount: Balance(10),
+        fee: Balance(0),
+        nonce: Nonce(1),
+        valid_until: 0,
+        eth_address: ETH_ADDRESS,
+        sign: None,
+    };
+    withdrawal.sign_with_hasher::<Rescue, _>(&secret(&seckey), &domain, rescue_params(), None, &mut rng);
+    assert_eq!(withdrawal.verify_signature_with_hasher::<Rescue>(&signer, &domain, rescue_params(), None), Ok(()));
+    assert_eq!(withdrawal.verify_signature(&signer, &domain, None, None), Err(SignatureError::VerificationFailed.into()));
+
+    withdrawal.sign_with_thread_rng(&secret(&seckey), &domain, None, None);
+    assert_eq!(withdrawal.verify_signature(&signer, &domain, None, None), Ok(()));
+    assert_eq!(
+        withdrawal.verify_signature_with_hasher::<Rescue>(&signer, &domain, rescue_params(), None),
+        Err(SignatureError::VerificationFailed.into()),
+    );
+
+    let poseidon = merkle_level_constraints::<Poseidon>(hash_params);
+    let rescue = merkle_level_constraints::<Rescue>(rescue_params());
+    println!("constraints per merkle level: poseidon {}, rescue {}", poseidon, rescue);
+    assert!(poseidon > 0 && rescue > 0);
+}
+
+#[test]
+pub fn packed_pubkey_leaf() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    // subgroup points, the neutral element and the empty pubkey come back from
+    // their packed value, P + (0, -1) packs as P unless x = 0
+    let mut rng = thread_rng();
+    let mut points: Vec<Point<Bn256, Unknown>> = (0..8).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    ).0).collect();
+    points.push(Point::zero());
+    points.push(empty_pubkey::<Bn256>(sign_params));
+    for point in points.iter() {
+        let packed = pack_point(point);
+        assert_eq!(unpack_point::<Bn256>(packed, sign_params).map(|point| point.into_xy()), Some(point.into_xy()));
+
+        let (mut x, mut y) = point.into_xy();
+        x.negate();
+        y.negate();
+        let shifted = Point::<Bn256, Unknown>::from_xy(x, y, sign_params).unwrap();
+        assert_eq!(pack_point(&shifted) == packed, !x.is_zero());
+    }
+    assert!(pack_point(&points[points.len() - 1]).is_zero());
+
+    // the gadget packs the same value and rejects the other sign of x, y = 0
+    // packs to zero for either
+    for point in points.iter() {
+        let (x, y) = point.into_xy();
+        let mut negated_x = x;
+        negated_x.negate();
+
+        for (claimed_x, satisfied) in [(x, true), (negated_x, x.is_zero() || y.is_zero())].iter() {
+            let mut cs = TestConstraintSystem::<Bn256>::new();
+            let packed = AllocatedNum::alloc(cs.namespace(|| "packed"), || Ok(pack_point(point))).unwrap();
+            let x_alloc = AllocatedNum::alloc(cs.namespace(|| "x"), || Ok(*claimed_x)).unwrap();
+            let y_alloc = AllocatedNum::alloc(cs.namespace(|| "y"), || Ok(y)).unwrap();
+            let computed = pack_point_gadget(cs.namespace(|| "pack"), &x_alloc, &y_alloc).unwrap();
+            cs.enforce(
+                || "enforce packed",
+                |lc| lc + computed.get_variable(),
+                |lc| lc + TestConstraintSystem::<Bn256>::one(),
+                |lc| lc + packed.get_variable(),
+            );
+            assert_eq!(cs.is_satisfied(), *satisfied);
+        }
+    }
+
+    // a tree with a few accounts and its snapshot of the old leaf layout
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    for (account_id, point) in points[..3].iter().enumerate() {
+        Deposit {
+            pubkey: Some(PublicKey(point.clone())),
+            account_id,
+            token_id: account_id % 2,
+            amount: 10 + account_id as u128,
+        }.update_tree_and_record_state(&mut tree).unwrap();
+    }
+    let snapshot = tree.export_snapshot();
+
+    let legacy_leaves = (0..tree.accounts.len()).map(|account_id| {
+        let account = &tree.accounts[account_id];
+        let (x, y) = account.pubkey.0.into_xy();
+        vec![x, y, account.nonce, account.balances_tree.root()]
+    }).collect();
+    let legacy = LegacyStateSnapshot {
+        root: PoseidonMerkleTree::<Bn256>::new(legacy_leaves, hash_params).root(),
+        account_depth,
+        token_depth,
+        accounts: snapshot.accounts.iter().map(|account| {
+            let (pubkey_x, pubkey_y) = unpack_point::<Bn256>(account.pubkey, sign_params).unwrap().into_xy();
+            LegacyAccountSnapshot {
+                account_id: account.account_id,
+                pubkey_x,
+                pubkey_y,
+                nonce: account.nonce,
+                balances: account.balances.clone(),
+            }
+        }).collect(),
+    };
+    assert_ne!(legacy.root, tree.get_root());
+
+    let migrated = migrate_snapshot(&legacy, hash_params, sign_params).unwrap();
+    assert_eq!((migrated.root, &migrated.accounts), (snapshot.root, &snapshot.accounts));
+    assert!(migrated.unfinalized_accounts.is_empty());
+    assert_eq!(AccountsTree::from_snapshot(&migrated, hash_params, sign_params).unwrap().get_root(), tree.get_root());
+
+    // an old snapshot in the serialized format, the history is dropped
+    let json = serde_json::to_string(&legacy).unwrap();
+    assert_eq!(serde_json::from_str::<LegacyStateSnapshot>(&json).unwrap(), legacy);
+
+    let mut forged = legacy.clone();
+    forged.accounts[1].balances[0] = usize_to_fr(5);
+    assert_eq!(
+        migrate_snapshot(&forged, hash_params, sign_params).err(),
+        Some(TreeError::InvalidSnapshot("legacy root mismatch").into()),
+    );
+
+    // the leaf hash of 3 elements against 4, one pubkey pack and a deposit
+    let leaf_hash = |len: usize| {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let leaf: Vec<_> = (0..len).map(|i| AllocatedNum::alloc(
+            cs.namespace(|| format!("leaf {}", i)),
+            || Ok(bn256::Fr::one()),
+        ).unwrap()).collect();
+        let before = cs.num_constraints();
+        Poseidon::hash_circuit(cs.namespace(|| "hash"), hash_params, &leaf).unwrap();
+        cs.num_constraints() - before
+    };
+    let pack = {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let (x, y) = points[0].into_xy();
+        let x = AllocatedNum::alloc(cs.namespace(|| "x"), || Ok(x)).unwrap();
+        let y = AllocatedNum::alloc(cs.namespace(|| "y"), || Ok(y)).unwrap();
+        pack_point_gadget(cs.namespace(|| "pack"), &x, &y).unwrap();
+        cs.num_constraints()
+    };
+    let params = shared_params();
+    let deposit = {
+        let constraints = |deposit_batch| measure(
+            DepositBatchCircuit::empty(deposit_batch, account_depth, token_depth, &params)
+        ).unwrap().constraints;
+        constraints(2) - constraints(1)
+    };
+    println!(
+        "leaf hash constraints: 4 elements {}, 3 elements {}; pack pubkey {}; deposit at account depth {}: {}",
+        leaf_hash(4), leaf_hash(3), pack, account_depth, deposit,
+    );
+    assert_eq!(leaf_hash(3), leaf_hash(4));
+}
+
+#[test]
+pub fn less_or_equal_gadget() {
+    let bits = 3;
+    let alloc = |cs: &mut TestConstraintSystem<Bn256>, a: usize, b: usize| {
+        let a = AllocatedNum::alloc(cs.namespace(|| "a"), || Ok(usize_to_fr(a))).unwrap();
+        let b = AllocatedNum::alloc(cs.namespace(|| "b"), || Ok(usize_to_fr(b))).unwrap();
+        (a, b)
+    };
+
+    for a in 0..1 << bits {
+        for b in 0..1 << bits {
+            let mut cs = TestConstraintSystem::<Bn256>::new();
+            let (a_alloc, b_alloc) = alloc(&mut cs, a, b);
+            enforce_less_or_equal(cs.namespace(|| "le"), &a_alloc, &b_alloc, bits).unwrap();
+            assert_eq!(cs.is_satisfied(), a <= b, "enforce {} <= {}", a, b);
+
+            let mut cs = TestConstraintSystem::<Bn256>::new();
+            let (a_alloc, b_alloc) = alloc(&mut cs, a, b);
+            let le = is_less_or_equal(cs.namespace(|| "le"), &a_alloc, &b_alloc, bits).unwrap();
+            assert_eq!(le.get_value(), Some(a <= b), "{} <= {}", a, b);
+            assert!(cs.is_satisfied());
+        }
+    }
+
+    // a = b + 1 fails at every width, up to the top of the range
+    for bits in [1, 8, 64] {
+        let top = if bits == 64 { usize::MAX - 1 } else { (1 << bits) - 2 };
+        for b in [0, top] {
+            let mut cs = TestConstraintSystem::<Bn256>::new();
+            let (a_alloc, b_alloc) = alloc(&mut cs, b + 1, b);
+            enforce_less_or_equal(cs.namespace(|| "le"), &a_alloc, &b_alloc, bits).unwrap();
+            assert!(!cs.is_satisfied());
+
+            // the prover can't flip the result either
+            let mut cs = TestConstraintSystem::<Bn256>::new();
+            let (a_alloc, b_alloc) = alloc(&mut cs, b + 1, b);
+            let le = is_less_or_equal(cs.namespace(|| "le"), &a_alloc, &b_alloc, bits).unwrap();
+            assert_eq!(le.get_value(), Some(false));
+            assert!(cs.is_satisfied());
+            cs.set(&format!("le/shifted difference into bits/allocate bit {}/boolean", bits), usize_to_fr(1));
+            assert!(!cs.is_satisfied());
+        }
+    }
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let (a_alloc, b_alloc) = alloc(&mut cs, 0, 0);
+    assert!(is_less_or_equal(cs.namespace(|| "too wide"), &a_alloc, &b_alloc, 253).is_err());
+}
+
+#[test]
+pub fn select_and_is_zero_gadgets() {
+    let alloc = |cs: &mut TestConstraintSystem<Bn256>, name: &str, value: usize| {
+        AllocatedNum::alloc(cs.namespace(|| name.to_string()), || Ok(usize_to_fr(value))).unwrap()
+    };
+
+    for value in [0, 1, 7] {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let num = alloc(&mut cs, "num", value);
+        let zero = is_zero(cs.namespace(|| "is zero"), &num).unwrap();
+        assert_eq!(zero.get_value(), Some(value == 0));
+        assert_eq!(cs.num_constraints(), 3);
+        assert!(cs.is_satisfied());
+
+        // the prover can't claim the other answer
+        cs.set("is zero/allocate is zero/boolean", usize_to_fr((value != 0) as usize));
+        for inv in [0, 1, 7] {
+            cs.set("is zero/allocate inverse/num", usize_to_fr(inv));
+            assert!(!cs.is_satisfied());
+        }
+    }
+
+    for cond in [false, true] {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let if_true = alloc(&mut cs, "if true", 3);
+        let if_false = alloc(&mut cs, "if false", 5);
+        let cond = Boolean::from(AllocatedBit::alloc(cs.namespace(|| "cond"), Some(cond)).unwrap());
+        let selected = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select"), &if_true, &if_false, &cond,
+        ).unwrap();
+        let expected = if cond.get_value().unwrap() { 3 } else { 5 };
+        assert_eq!(selected.get_value(), Some(usize_to_fr(expected)));
+        assert!(cs.is_satisfied());
+        cs.set("select/conditional select result/num", usize_to_fr(8 - expected));
+        assert!(!cs.is_satisfied());
+
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let bit = Boolean::from(AllocatedBit::alloc(cs.namespace(|| "cond"), cond.get_value()).unwrap());
+        for (name, cond) in [("num", bit.clone()), ("not num", bit.not())] {
+            let num = boolean_to_allocated_num(cs.namespace(|| name), &cond).unwrap();
+            assert_eq!(num.get_value(), Some(usize_to_fr(cond.get_value().unwrap() as usize)));
+        }
+        assert_eq!(cs.num_constraints(), 3);
+        assert!(cs.is_satisfied());
+        cs.set("num/allocate num/num", usize_to_fr(2));
+        assert!(!cs.is_satisfied());
+    }
+}
+
+#[test]
+pub fn block_builder() {
+    let account_depth = 2;
+    let token_depth = 1;
+    let config = BatchConfig { deposit_batch: 3, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let params = shared_params();
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..2).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    )).collect();
+    let deposits = [
+        OffchainDeposit { account_id: AccountId(1), pubkey: pubkeys[0].clone(), token_id: 0, amount: Balance(40) },
+        OffchainDeposit { account_id: AccountId(2), pubkey: pubkeys[1].clone(), token_id: 1, amount: Balance(2) },
+    ];
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let mut other_depth = AccountsTree::new(account_depth + 1, token_depth, poseidon_params(), jubjub_params());
+    assert!(matches!(
+        BlockBuilder::new(&mut other_depth, config, &params, bn256::Fr::zero()),
+        Err(OpenPlasmaError::Block(BlockError::ConfigMismatch)),
+    ));
+
+    let old_root = tree.get_root();
+    let mut builder = BlockBuilder::new(&mut tree, config, &params, bn256::Fr::zero()).unwrap();
+    for deposit in deposits.iter() {
+        builder.push_deposit(deposit.clone()).unwrap();
+    }
+
+    // a refused deposit changes nothing
+    let overwrite = OffchainDeposit { account_id: AccountId(1), pubkey: pubkeys[1].clone(), token_id: 0, amount: Balance(1) };
+    assert!(matches!(
+        builder.push_deposit(overwrite),
+        Err(OpenPlasmaError::Tree(TreeError::PubkeyMismatch(1))),
+    ));
+    assert_eq!(builder.len(), 2);
+
+    let (circuit, public_inputs, pubdata) = builder.seal().unwrap();
+    assert_eq!(public_inputs.old_account_root, old_root);
+    assert_eq!(public_inputs.new_account_root, tree.get_root());
+
+    // the noop padding is absorbed but not in the pubdata
+    assert_eq!(pubdata.as_bytes().len(), 2 * OFFCHAIN_DEPOSIT_BYTES);
+    for (deposit, bytes) in deposits.iter().zip(pubdata.as_bytes().chunks(OFFCHAIN_DEPOSIT_BYTES)) {
+        let decoded = OffchainDeposit::decode(bytes, jubjub_params()).unwrap();
+        assert_eq!(decoded.encode().unwrap(), deposit.encode().unwrap());
+    }
+    let deposits_hash = deposits.iter().fold(bn256::Fr::zero(), |hash, deposit| deposit.hash(hash, poseidon_params()));
+    assert_ne!(public_inputs.new_accum_hash, deposits_hash);
+
+    // the next block starts from the sealed one
+    let mut next = BlockBuilder::new(&mut tree, config, &params, public_inputs.new_accum_hash).unwrap();
+    for _ in 0..3 {
+        next.push_deposit(deposits[0].clone()).unwrap();
+    }
+    assert!(matches!(next.push_deposit(deposits[0].clone()), Err(OpenPlasmaError::Block(BlockError::BlockFull))));
+    let (_, next_inputs, _) = next.seal().unwrap();
+    assert_eq!(next_inputs.old_accum_hash, public_inputs.new_accum_hash);
+    assert_eq!(next_inputs.old_account_root, public_inputs.new_account_root);
+
+    // proves and verifies against the sealed public inputs alone
+    let circuit_params = CircuitFamily::new(Arc::clone(&params)).generate_parameters(config, &mut rng).unwrap();
+    let proof = prove_deposit_block(&circuit_params, circuit).unwrap();
+    let verifying_key = prepare_verifying_key(&circuit_params.vk);
+    let layout = CircuitFamily::new(Arc::clone(&params)).layout(config).unwrap();
+    assert!(verify_block(&verifying_key, &proof, &layout, &public_inputs));
+    assert!(!verify_block(&verifying_key, &proof, &layout, &next_inputs));
+}
+
+#[test]
+pub fn withdrawal_mempool() {
+    let domain = SigningDomain::default();
+    let seckeys: Vec<_> = [b"mempool 1", b"mempool 2"].iter().map(|seed| SecretKey::from_seed(*seed)).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| seckey.public_key(jubjub_params())).collect();
+
+    let mut tree = AccountsTree::new(2, 1, poseidon_params(), jubjub_params());
+    for (i, (pubkey, amount)) in pubkeys.iter().zip([100, 10]).enumerate() {
+        let deposit = OffchainDeposit { account_id: AccountId(i as u32 + 1), pubkey: pubkey.clone(), token_id: 0, amount: Balance(amount) };
+        deposit.update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    let signed = |account: usize, nonce: u32, amount: u128, valid_until: usize| {
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(account as u32 + 1), token_id: 0, amount: Balance(amount), fee: Balance(1),
+            nonce: Nonce(nonce), valid_until, eth_address: ETH_ADDRESS, sign: None,
+        };
+        withdrawal.sign_deterministic(&seckeys[account], &domain, None, None);
+        withdrawal
+    };
+
+    let mempool = Arc::new(Mempool::new(domain, SecretKey::from_seed(b"operator")));
+
+    // requests arrive out of order and from several threads
+    std::thread::scope(|scope| {
+        let first = scope.spawn(|| {
+            for nonce in [2, 4, 1] {
+                mempool.insert(signed(0, nonce, 10, 0), &pubkeys[0], Nonce(0), Balance(100)).unwrap();
+            }
+        });
+        let second = scope.spawn(|| {
+            // more than the balance, then a request that would fit
+            mempool.insert(signed(1, 1, 20, 0), &pubkeys[1], Nonce(0), Balance(10)).unwrap();
+            mempool.insert(signed(1, 2, 1, 0), &pubkeys[1], Nonce(0), Balance(10)).unwrap();
+        });
+        first.join().unwrap();
+        second.join().unwrap();
+    });
+    assert_eq!(mempool.len(), 5);
+
+    assert_eq!(
+        mempool.insert(signed(0, 1, 5, 0), &pubkeys[0], Nonce(0), Balance(100)),
+        Err(MempoolError::Duplicate { account_id: 1, nonce: 1 }),
+    );
+    assert_eq!(
+        mempool.insert(signed(0, 0, 5, 0), &pubkeys[0], Nonce(0), Balance(100)),
+        Err(MempoolError::StaleNonce { account_id: 1, nonce: 0 }),
+    );
+    assert_eq!(
+        mempool.insert(signed(0, 3, 5, 0), &pubkeys[1], Nonce(0), Balance(100)),
+        Err(MempoolError::InvalidSignature(SignatureError::VerificationFailed.into())),
+    );
+    let mut unsigned = signed(0, 3, 5, 0);
+    unsigned.sign = None;
+    assert_eq!(
+        mempool.insert(unsigned, &pubkeys[0], Nonce(0), Balance(100)),
+        Err(MempoolError::InvalidSignature(SignatureError::MissingSignature.into())),
+    );
+
+    // nonce 4 waits for 3, account 2 waits for the balance of its nonce 1
+    let batch = mempool.take_batch(10, &tree, 0).unwrap();
+    let taken: Vec<_> = batch.iter().map(|withdrawal| (withdrawal.account_id.0, withdrawal.nonce.0)).collect();
+    assert_eq!(taken, vec![(1, 1), (1, 2)]);
+    assert_eq!(mempool.len(), 3);
+
+    // taken nonces are used before their block reaches the tree
+    assert_eq!(
+        mempool.insert(signed(0, 2, 5, 0), &pubkeys[0], Nonce(0), Balance(100)),
+        Err(MempoolError::StaleNonce { account_id: 1, nonce: 2 }),
+    );
+    for withdrawal in batch.iter() {
+        withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    mempool.insert(signed(0, 3, 10, 0), &pubkeys[0], Nonce(2), Balance(78)).unwrap();
+    let topup = OffchainDeposit { account_id: AccountId(2), pubkey: pubkeys[1].clone(), token_id: 0, amount: Balance(20) };
+    topup.update_tree_and_record_state(&mut tree).unwrap();
+
+    // one request per account and round
+    let batch = mempool.take_batch(3, &tree, 0).unwrap();
+    let taken: Vec<_> = batch.iter().map(|withdrawal| (withdrawal.account_id.0, withdrawal.nonce.0)).collect();
+    assert_eq!(taken, vec![(1, 3), (2, 1), (1, 4)]);
+    for withdrawal in batch.iter() {
+        withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    // an expired request is dropped, the one after it waits for a new one
+    mempool.insert(signed(0, 5, 1, 10), &pubkeys[0], Nonce(4), Balance(56)).unwrap();
+    mempool.insert(signed(0, 6, 1, 0), &pubkeys[0], Nonce(4), Balance(56)).unwrap();
+    let batch = mempool.take_batch(10, &tree, 11).unwrap();
+    let taken: Vec<_> = batch.iter().map(|withdrawal| (withdrawal.account_id.0, withdrawal.nonce.0)).collect();
+    assert_eq!(taken, vec![(2, 2)]);
+    assert_eq!(mempool.len(), 1);
+    mempool.insert(signed(0, 5, 1, 0), &pubkeys[0], Nonce(4), Balance(56)).unwrap();
+    assert_eq!(mempool.take_batch(10, &tree, 11).unwrap().len(), 2);
+    assert!(mempool.is_empty());
+}
+
+#[test]
+pub fn priority_queue_ingestion() {
+    let sign_params = jubjub_params();
+    let mut rng = thread_rng();
+    let pubkey_bytes = |pubkey: &PublicKey<Bn256>| {
+        let mut bytes = [0u8; 32];
+        pubkey.write(&mut bytes[..]).unwrap();
+        bytes
+    };
+    let op = |serial_id: u64, pubkey_bytes: [u8; 32]| PriorityOp {
+        serial_id,
+        account_id: AccountId(serial_id as u32 + 1),
+        token_id: 0,
+        pubkey_bytes,
+        amount: Balance(u128::from(serial_id) + 10),
+        eth_block: 1000 + serial_id,
+    };
+
+    let pubkeys: Vec<_> = (0..2).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+
+    // a y with no point, and the identity, which is not a key
+    let off_curve = (2..).map(usize_to_fr).find(
+        |y| Point::<Bn256, Unknown>::get_for_y(*y, false, sign_params).is_none()
+    ).unwrap();
+    let mut off_curve_bytes = [0u8; 32];
+    off_curve.into_repr().write_le(&mut off_curve_bytes[..]).unwrap();
+    let identity_bytes = pubkey_bytes(&PublicKey(Point::zero()));
+
+    let events: Vec<_> = [
+        op(0, pubkey_bytes(&pubkeys[0])),
+        op(1, off_curve_bytes),
+        op(2, identity_bytes),
+        op(3, pubkey_bytes(&pubkeys[1])),
+    ].iter().map(|op| op.encode_event()).collect();
+
+    let mut queue = PriorityQueue::new(0);
+    for data in events.iter() {
+        assert_eq!(data.len(), DEPOSIT_EVENT_BYTES);
+        queue.push(decode_deposit_event(data).unwrap()).unwrap();
+    }
+    assert_eq!(queue.len(), 4);
+
+    // events are neither replayed nor skipped
+    assert_eq!(queue.push(decode_deposit_event(&events[2]).unwrap()), Err(PriorityQueueError::Duplicate(2)));
+    assert_eq!(queue.push(op(5, identity_bytes)), Err(PriorityQueueError::Gap { expected: 4, actual: 5 }));
+
+    assert_eq!(decode_deposit_event(&events[0][1..]), Err(DecodeError::WrongLength { expected: 192, actual: 191 }));
+    let mut too_large = events[0].clone();
+    too_large[4 * 32 + 15] = 1;
+    assert_eq!(decode_deposit_event(&too_large), Err(DecodeError::ValueOutOfRange("amount")));
+
+    // exactly a batch, in the order of the contract; malformed keys are ops
+    // the caller rejects
+    assert_eq!(queue.drain(5), Err(PriorityQueueError::NotEnoughOps { requested: 5, queued: 4 }));
+    let drained = queue.drain(3).unwrap();
+    assert_eq!(drained.iter().map(|op| op.op().serial_id).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert_eq!(queue.len(), 1);
+
+    let deposits: Vec<_> = drained.iter().map(|op| op.op().into_deposit(sign_params)).collect();
+    assert!(matches!(deposits[1], Err(DecodeError::InvalidPubkey)));
+    assert!(matches!(deposits[2], Err(DecodeError::InvalidPubkey)));
+
+    let mut tree = AccountsTree::new(3, 1, poseidon_params(), jubjub_params());
+    let config = BatchConfig { deposit_batch: 2, account_depth: 3, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), bn256::Fr::zero()).unwrap();
+    for deposit in deposits.into_iter().flatten() {
+        builder.push_deposit(deposit).unwrap();
+    }
+    let last = queue.drain(1).unwrap()[0].op().into_deposit(sign_params).unwrap();
+    assert_eq!(last.pubkey.0.into_xy(), pubkeys[1].0.into_xy());
+    builder.push_deposit(last).unwrap();
+
+    let (_, _, pubdata) = builder.seal().unwrap();
+    let decoded: Vec<_> = pubdata.as_bytes().chunks(OFFCHAIN_DEPOSIT_BYTES).map(
+        |bytes| OffchainDeposit::decode(bytes, sign_params).unwrap().account_id
+    ).collect();
+    assert_eq!(decoded, vec![AccountId(1), AccountId(4)]);
+
+    // the block builder refunds the malformed keys in the middle of the
+    // queue: they keep their slots and their place in the accum hash, the
+    // ops after them are still applied in order
+    let mut tree = AccountsTree::new(3, 1, poseidon_params(), jubjub_params());
+    let config = BatchConfig { deposit_batch: 4, ..config };
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), bn256::Fr::zero()).unwrap();
+    for data in events.iter() {
+        builder.push_priority_op(&decode_deposit_event(data).unwrap()).unwrap();
+    }
+    let (circuit, public_inputs, pubdata) = builder.seal().unwrap();
+    assert_eq!(tree.processed_ops().next_serial_id(), 4);
+    assert_eq!(tree.balance(AccountId(2), 0), Ok(Balance(0)));
+    assert_eq!(tree.balance(AccountId(4), 0), Ok(Balance(13)));
+    let ops = Pubdata::parse(pubdata.as_bytes(), sign_params).unwrap();
+    assert!(matches!(ops[..], [PubdataOp::Deposit(_), PubdataOp::Refund(_), PubdataOp::Refund(_), PubdataOp::Deposit(_)]));
+    assert!(matches!(ops[1], PubdataOp::Refund(ref refund) if refund.amount == Balance(11) && refund.pubkey.0.into_xy() == Point::<Bn256, Unknown>::zero().into_xy()));
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+    let empty = AccountsTree::new(3, 1, poseidon_params(), jubjub_params());
+    verify_block_against_pubdata(&empty, &pubdata, &public_inputs, DepositAccumulator::Poseidon, 0, AccountId(0), 0, sign_params).unwrap();
+
+    // the sha256 accumulator takes the refunds under their own op type, as
+    // the pubdata has them
+    let sha256_batch = DepositBatchCircuit { new_accum_hash: Some(pubdata.commitment(bn256::Fr::zero())), ..circuit };
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    Sha256DepositBatchCircuit { batch: sha256_batch }.synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    // as well as a key the account doesn't have and a balance that would
+    // overflow; ids outside the tree are refused
+    let applied_root = tree.get_root();
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), public_inputs.new_accum_hash).unwrap();
+    builder.push_priority_op(&PriorityOp { account_id: AccountId(1), ..op(4, pubkey_bytes(&pubkeys[1])) }).unwrap();
+    let overflow = PriorityOp { account_id: AccountId(4), amount: Balance(u128::MAX), ..op(5, pubkey_bytes(&pubkeys[1])) };
+    builder.push_priority_op(&overflow).unwrap();
+    assert!(matches!(
+        builder.push_priority_op(&PriorityOp { account_id: AccountId(8), ..op(6, pubkey_bytes(&pubkeys[1])) }),
+        Err(OpenPlasmaError::Tree(TreeError::AccountOutOfRange(8))),
+    ));
+    let (circuit, public_inputs, pubdata) = builder.seal().unwrap();
+    assert_eq!(public_inputs.new_account_root, applied_root);
+    assert_eq!(tree.processed_ops().next_serial_id(), 6);
+    assert!(Pubdata::parse(pubdata.as_bytes(), sign_params).unwrap().iter().all(|op| matches!(op, PubdataOp::Refund(_))));
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+}
+
+#[test]
+pub fn pubdata_replay() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let config = BatchConfig { deposit_batch: 3, account_depth: 2, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let params = shared_params();
+    let domain = SigningDomain::default();
+    let fee_account_id = AccountId(0);
+
+    let mut rng = thread_rng();
+    let seckeys: Vec<_> = (0..3).map(|_| PrivateKey::<Bn256>(rng.gen())).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(
+        |seckey| PublicKey::from_private(seckey, FixedGenerators::SpendingKeyGenerator, sign_params)
+    ).collect();
+    let deposit = |account: usize, amount: u128| OffchainDeposit {
+        account_id: AccountId(account as u32 + 1), pubkey: pubkeys[account].clone(), token_id: 0, amount: Balance(amount),
+    };
+
+    let mut operator = AccountsTree::new(config.account_depth, config.token_depth, hash_params, sign_params);
+    let mut blocks = Vec::new();
+
+    // a deposit block, its pubdata commitment is the accum hash of the sha256 circuit
+    let old_hash = bn256::Fr::zero();
+    let mut builder = BlockBuilder::new(&mut operator, config, &params, old_hash).unwrap();
+    builder.push_deposit(deposit(0, 100)).unwrap();
+    builder.push_deposit(deposit(1, 50)).unwrap();
+    let (circuit, _, pubdata) = builder.seal().unwrap();
+    let sha256_batch = DepositBatchCircuit {
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(pubdata.commitment(old_hash)),
+        ..circuit
+    };
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    Sha256DepositBatchCircuit { batch: sha256_batch }.synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+    blocks.push((pubdata, operator.get_root()));
+
+    // a withdrawal and a transfer with fees, credited after them
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(2), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    withdrawal.sign_deterministic(&secret(&seckeys[0]), &domain, None, None);
+    let mut transfer = OffchainTransfer {
+        from_account_id: AccountId(2), to_account_id: AccountId(1), token_id: 0, amount: Balance(5),
+        fee: Balance(1), nonce: Nonce(1), sign: None,
+    };
+    transfer.sign(&seckeys[1], hash_params, sign_params);
+
+    let mut pubdata = Pubdata::default();
+    withdrawal.update_tree_and_record_state(&mut operator).unwrap();
+    pubdata.push_withdrawal(&withdrawal).unwrap();
+    transfer.update_tree_and_record_state(&mut operator).unwrap();
+    pubdata.push_transfer(&transfer).unwrap();
+    credit_fee_and_record_state(&mut operator, fee_account_id, 0, Balance(3)).unwrap();
+    assert_eq!(pubdata.len(), 2);
+    assert_eq!(pubdata.as_bytes().len(), OFFCHAIN_WITHDRAWAL_BYTES + OFFCHAIN_TRANSFER_BYTES);
+    blocks.push((pubdata, operator.get_root()));
+
+    let mut builder = BlockBuilder::new(&mut operator, config, &params, old_hash).unwrap();
+    builder.push_deposit(deposit(0, 1)).unwrap();
+    builder.push_deposit(deposit(2, 7)).unwrap();
+    let (_, _, pubdata) = builder.seal().unwrap();
+    blocks.push((pubdata, operator.get_root()));
+
+    // a watcher rebuilds every root from the calldata alone
+    let mut watcher = AccountsTree::new(config.account_depth, config.token_depth, hash_params, sign_params);
+    for (pubdata, root) in blocks.iter() {
+        let ops = Pubdata::parse(pubdata.as_bytes(), sign_params).unwrap();
+        assert_eq!(ops.len(), pubdata.len());
+        replay_pubdata(&mut watcher, &ops, fee_account_id, 0).unwrap();
+        assert_eq!(watcher.get_root(), *root);
+    }
+    assert_eq!(watcher.balance(AccountId(1), 0).unwrap(), Balance(100 - 12 + 5 + 1));
+    assert_eq!(watcher.balance(fee_account_id, 0).unwrap(), Balance(3));
+
+    // a block replayed twice fails on the nonce and changes nothing
+    let ops = Pubdata::parse(blocks[1].0.as_bytes(), sign_params).unwrap();
+    assert!(matches!(ops[0], PubdataOp::Withdrawal(_)));
+    assert!(replay_pubdata(&mut watcher, &ops, fee_account_id, 0).is_err());
+    assert_eq!(watcher.get_root(), blocks[2].1);
+
+    let bytes = blocks[0].0.as_bytes();
+    assert_eq!(
+        Pubdata::parse(&bytes[..bytes.len() - 1], sign_params).err(),
+        Some(EncodingError::Truncated { expected: OFFCHAIN_DEPOSIT_BYTES, actual: OFFCHAIN_DEPOSIT_BYTES - 1 }.into()),
+    );
+    let mut unknown = bytes.to_vec();
+    unknown[1] = 0;
+    assert_eq!(Pubdata::parse(&unknown, sign_params).err(), Some(EncodingError::UnexpectedOpType(0).into()));
+}
+
+#[test]
+pub fn journal_replay() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let config = BatchConfig { deposit_batch: 2, account_depth: 2, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let params = shared_params();
+    let domain = SigningDomain::default();
+    let header = JournalHeader { account_depth: 2, token_depth: 1, fee_account_id: AccountId(0), fee_token_id: 0 };
+
+    let mut rng = thread_rng();
+    let seckeys: Vec<_> = (0..2).map(|_| PrivateKey::<Bn256>(rng.gen())).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(
+        |seckey| PublicKey::from_private(seckey, FixedGenerators::SpendingKeyGenerator, sign_params)
+    ).collect();
+    let deposit = |account: usize, amount: u128| OffchainDeposit {
+        account_id: AccountId(account as u32 + 1), pubkey: pubkeys[account].clone(), token_id: 0, amount: Balance(amount),
+    };
+
+    // the operator journals every block it applies
+    let mut operator = AccountsTree::new(config.account_depth, config.token_depth, hash_params, sign_params);
+    let mut blocks = Vec::new();
+    let mut accum_hash = bn256::Fr::zero();
+    let mut journal_block = |operator: &AccountsTree, old_root, pubdata: Pubdata| {
+        let block = JournalBlock {
+            block_number: blocks.len() as u64 + 1,
+            old_root,
+            new_root: operator.get_root(),
+            accum_hash: pubdata.commitment(accum_hash),
+            pubdata,
+        };
+        accum_hash = block.accum_hash;
+        blocks.push(block);
+    };
+
+    let old_root = operator.get_root();
+    let mut builder = BlockBuilder::new(&mut operator, config, &params, bn256::Fr::zero()).unwrap();
+    builder.push_deposit(deposit(0, 100)).unwrap();
+    builder.push_deposit(deposit(1, 50)).unwrap();
+    let (_, _, pubdata) = builder.seal().unwrap();
+    journal_block(&operator, old_root, pubdata);
+
+    let old_root = operator.get_root();
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(2), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    withdrawal.sign_deterministic(&secret(&seckeys[0]), &domain, None, None);
+    let mut pubdata = Pubdata::default();
+    withdrawal.update_tree_and_record_state(&mut operator).unwrap();
+    pubdata.push_withdrawal(&withdrawal).unwrap();
+    credit_fee_and_record_state(&mut operator, header.fee_account_id, 0, Balance(2)).unwrap();
+    journal_block(&operator, old_root, pubdata);
+
+    let old_root = operator.get_root();
+    let mut builder = BlockBuilder::new(&mut operator, config, &params, bn256::Fr::zero()).unwrap();
+    builder.push_deposit(deposit(1, 7)).unwrap();
+    let (_, _, pubdata) = builder.seal().unwrap();
+    journal_block(&operator, old_root, pubdata);
+
+    let write_journal = |blocks: &[JournalBlock]| {
+        let mut writer = JournalWriter::new(Vec::new(), &header).unwrap();
+        for block in blocks.iter() {
+            writer.append(block).unwrap();
+        }
+        writer.into_inner()
+    };
+
+    // a replica rebuilds the tree from the journal file alone
+    let path = std::env::temp_dir().join(format!("openplasma_journal_{}.bin", std::process::id()));
+    std::fs::write(&path, write_journal(&blocks)).unwrap();
+    let file = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+    let (replica, replayed) = replay_journal(file, hash_params, sign_params).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(replayed, 3);
+    assert_eq!(replica.get_root(), operator.get_root());
+    assert_eq!(replica.balance(AccountId(1), 0).unwrap(), Balance(88));
+    assert_eq!(replica.balance(AccountId(2), 0).unwrap(), Balance(57));
+    assert_eq!(replica.balance(header.fee_account_id, 0).unwrap(), Balance(2));
+
+    let empty = write_journal(&[]);
+    assert_eq!(replay_journal(&empty[..], hash_params, sign_params).unwrap().1, 0);
+
+    // the first block that doesn't agree is reported
+    let mut tampered = blocks.clone();
+    tampered[1].new_root = bn256::Fr::one();
+    tampered[2].old_root = bn256::Fr::one();
+    let journal = write_journal(&tampered);
+    let err = replay_journal(&journal[..], hash_params, sign_params).err().unwrap();
+    assert!(matches!(err, ReplayError::NewRootMismatch { block_number: 2, .. }));
+    assert!(err.to_string().starts_with("Block 2 "));
+
+    let mut tampered = blocks.clone();
+    tampered[2].accum_hash = bn256::Fr::one();
+    let journal = write_journal(&tampered);
+    assert!(matches!(
+        replay_journal(&journal[..], hash_params, sign_params),
+        Err(ReplayError::AccumMismatch { block_number: 3, .. }),
+    ));
+
+    // a block left out of the journal breaks the numbering
+    let journal = write_journal(&[blocks[0].clone(), blocks[2].clone()]);
+    assert!(matches!(
+        replay_journal(&journal[..], hash_params, sign_params),
+        Err(ReplayError::BlockOutOfOrder { expected: 2, actual: 3 }),
+    ));
+
+    // a torn append and a flipped byte are caught by the block checksum
+    let journal = write_journal(&blocks);
+    assert!(matches!(
+        replay_journal(&journal[..journal.len() - 1], hash_params, sign_params),
+        Err(ReplayError::Corrupt { block_number: 3 }),
+    ));
+    let mut flipped = journal.clone();
+    let last = flipped.len() - 9;
+    flipped[last] ^= 1;
+    assert!(matches!(
+        replay_journal(&flipped[..], hash_params, sign_params),
+        Err(ReplayError::Corrupt { block_number: 3 }),
+    ));
+    assert!(matches!(
+        replay_journal(&journal[1..], hash_params, sign_params),
+        Err(ReplayError::IoError(_)),
+    ));
+}
+
+#[test]
+pub fn exit_package() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 3;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    for account_id in 0..5 {
+        let pubkey = PublicKey::from_private(
+            &PrivateKey::<Bn256>(rng.gen()),
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+        );
+        Deposit {
+            pubkey: Some(pubkey),
+            account_id,
+            token_id: account_id % 2,
+            amount: 10 * (account_id as u128 + 1),
+        }.update_tree_and_record_state(&mut tree).unwrap();
+    }
+    let snapshot = tree.export_snapshot();
+    let root = tree.get_root();
+
+    let params = generate_random_parameters(
+        ExitCircuit::<Bn256> {
+            account_depth,
+            token_depth,
+            hash_params,
+            sign_params,
+            root: None,
+            account_id: None,
+            token_id: None,
+            pubkey_x: None,
+            pubkey_y: None,
+            balance: None,
+            nonce: None,
+            account_path: vec![None; account_depth],
+            account_indices: vec![None; account_depth],
+            token_path: vec![None; token_depth],
+            token_indices: vec![None; token_depth],
+        },
+        &mut rng,
+    ).unwrap();
+
+    // from the snapshot to a proof the contract verifies
+    let package = generate_exit(&snapshot, root, AccountId(3), 1, Some(&params), hash_params, sign_params).unwrap();
+    assert_eq!(package.account.account_id, 3);
+    assert!(package.proof.verify(hash_params));
+    assert_eq!(package.proof.balance(), Some(usize_to_fr(40)));
+    assert_eq!(package.proof.account.root, root);
+
+    let public_inputs = package.circuit.public_inputs().unwrap();
+    assert_eq!(package.public_inputs.len(), 32 * public_inputs.len());
+    assert_eq!(&package.public_inputs[..32], &PublicInputs::<Bn256>::new(root, root, root, root).to_be_bytes()[..32]);
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    package.circuit.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    let proof = proof_from_eth_bytes(&package.groth16_proof.unwrap()).unwrap();
+    let verifying_key = prepare_verifying_key(&params.vk);
+    assert!(verify_proof(&verifying_key, &proof, &public_inputs).unwrap());
+
+    // without keys everything but the proof
+    let package = generate_exit(&snapshot, root, AccountId(0), 0, None, hash_params, sign_params).unwrap();
+    assert!(package.groth16_proof.is_none());
+    assert!(package.proof.verify(hash_params));
+
+    // a snapshot of another root, an empty account and ids out of the tree
+    assert!(matches!(
+        generate_exit(&snapshot, usize_to_fr(1), AccountId(3), 1, None, hash_params, sign_params),
+        Err(ExitError::RootMismatch { .. }),
+    ));
+    assert!(matches!(
+        generate_exit(&snapshot, root, AccountId(5), 0, None, hash_params, sign_params),
+        Err(ExitError::EmptyAccount(5)),
+    ));
+    assert!(matches!(
+        generate_exit(&snapshot, root, AccountId(8), 0, None, hash_params, sign_params),
+        Err(ExitError::PlasmaError(OpenPlasmaError::Tree(TreeError::AccountOutOfRange(8)))),
+    ));
+    assert!(matches!(
+        generate_exit(&snapshot, root, AccountId(3), 2, None, hash_params, sign_params),
+        Err(ExitError::PlasmaError(OpenPlasmaError::Tree(TreeError::TokenOutOfRange(2)))),
+    ));
+
+    // a snapshot whose accounts don't hash to its root is refused
+    let mut forged = snapshot.clone();
+    forged.accounts[3].balances[1] = usize_to_fr(1000);
+    assert!(matches!(
+        generate_exit(&forged, root, AccountId(3), 1, None, hash_params, sign_params),
+        Err(ExitError::PlasmaError(OpenPlasmaError::Tree(TreeError::InvalidSnapshot(_)))),
+    ));
+}
+
+#[test]
+pub fn withdrawal_signing_json() {
+    let sign_params = jubjub_params();
+    let domain = SigningDomain::default();
+    let seed = b"wallet seed";
+
+    let json = sign_withdrawal_json(seed, 3, 1, "340282366920938463463374607431768211455", "5", 7, &ETH_ADDRESS, &domain).unwrap();
+    let withdrawal: OffchainWithdrawal = serde_json::from_str(&json).unwrap();
+    assert_eq!(withdrawal.account_id, AccountId(3));
+    assert_eq!(withdrawal.amount, Balance(u128::MAX));
+    assert_eq!(withdrawal.nonce, Nonce(7));
+    assert_eq!(withdrawal.eth_address, ETH_ADDRESS);
+    assert!(json.contains(r#""eth_address":"0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed""#));
+
+    let pubkey = SecretKey::from_seed(seed).public_key(sign_params);
+    assert!(withdrawal.verify_signature(&pubkey, &domain, None, None).is_ok());
+    assert_eq!(sign_withdrawal_json(seed, 3, 1, "340282366920938463463374607431768211455", "5", 7, &ETH_ADDRESS, &domain).unwrap(), json);
+
+    let other = SecretKey::from_seed(b"other seed").public_key(sign_params);
+    assert!(withdrawal.verify_signature(&other, &domain, None, None).is_err());
+
+    assert!(sign_withdrawal_json(seed, 3, 1, "-1", "0", 7, &ETH_ADDRESS, &domain).is_err());
+    assert!(sign_withdrawal_json(seed, 3, 1, "1", "1.5", 7, &ETH_ADDRESS, &domain).is_err());
+}
+
+#[test]
+pub fn fr_string_conversions() {
+    let sign_params = jubjub_params();
+    let mut rng = XorShiftRng::from_seed([0x3dbe6259, 0x8d313d76, 0x3237db17, 0xe5bc0654]);
+
+    // random elements and the edges of the field through every representation
+    let mut max = bn256::Fr::zero();
+    max.sub_assign(&bn256::Fr::one());
+    let mut samples: Vec<bn256::Fr> = (0..200).map(|_| rng.gen()).collect();
+    samples.extend_from_slice(&[bn256::Fr::zero(), bn256::Fr::one(), usize_to_fr(1 << 63), max]);
+    for fr in samples.iter() {
+        assert_eq!(fr_from_be_bytes(&fr_to_be_bytes(fr)), Ok(*fr));
+        assert_eq!(fr_from_hex(&fr_to_hex(fr)), Ok(*fr));
+        assert_eq!(fr_from_dec_string(&fr_to_dec_string(fr)), Ok(*fr));
+        assert_eq!(fr_to_hex(fr).len(), 66);
+    }
+
+    assert_eq!(fr_to_dec_string(&bn256::Fr::zero()), "0");
+    assert_eq!(fr_to_dec_string(&usize_to_fr(10_000_000_000_000_000_000)), "10000000000000000000");
+    assert_eq!(
+        fr_to_dec_string(&max),
+        "21888242871839275222246405745257275088548364400416034343698204186575808495616",
+    );
+    assert_eq!(fr_from_hex("0x1"), Ok(bn256::Fr::one()));
+    assert_eq!(fr_from_hex("0xFF"), Ok(usize_to_fr(255)));
+    assert_eq!(fr_from_hex(&format!("0x{}1", "0".repeat(80))), Ok(bn256::Fr::one()));
+    assert_eq!(fr_from_dec_string("00255"), Ok(usize_to_fr(255)));
+
+    // the modulus and anything above it
+    let modulus = "21888242871839275222246405745257275088548364400416034343698204186575808495617";
+    assert_eq!(fr_from_dec_string(modulus), Err(ConversionError::OutOfField.into()));
+    assert_eq!(fr_from_dec_string(&"9".repeat(100)), Err(ConversionError::OutOfField.into()));
+    assert_eq!(
+        fr_from_hex("0x30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001"),
+        Err(ConversionError::OutOfField.into()),
+    );
+    assert_eq!(fr_from_hex(&format!("0x1{}", "0".repeat(64))), Err(ConversionError::OutOfField.into()));
+    assert_eq!(fr_from_be_bytes(&[0xff; 32]), Err(ConversionError::OutOfField.into()));
+    assert_eq!(fr_from_be_bytes(&[0; 31]), Err(ConversionError::WrongLength { expected: 32, actual: 31 }.into()));
+
+    // malformed strings
+    assert_eq!(fr_from_hex("ff"), Err(ConversionError::MissingHexPrefix.into()));
+    assert_eq!(fr_from_hex("0x"), Err(ConversionError::InvalidHex.into()));
+    assert_eq!(fr_from_hex("0xfg"), Err(ConversionError::InvalidHex.into()));
+    assert_eq!(fr_from_hex("0x 1"), Err(ConversionError::InvalidHex.into()));
+    assert_eq!(fr_from_dec_string(""), Err(ConversionError::InvalidDecimal.into()));
+    assert_eq!(fr_from_dec_string("-1"), Err(ConversionError::InvalidDecimal.into()));
+    assert_eq!(fr_from_dec_string("1e3"), Err(ConversionError::InvalidDecimal.into()));
+    assert_eq!(fr_from_dec_string("0x1"), Err(ConversionError::InvalidDecimal.into()));
+
+    for _ in 0..20 {
+        let pubkey = PublicKey::<Bn256>::from_private(
+            &PrivateKey(rng.gen()),
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+        );
+        let (x, y) = point_to_hex_xy(&pubkey.0);
+        assert!(point_from_hex_xy(&x, &y, sign_params).unwrap() == pubkey.0);
+    }
+    let (x, _) = point_to_hex_xy(&Point::<Bn256, Unknown>::zero());
+    assert!(matches!(point_from_hex_xy(&x, &x, sign_params), Err(OpenPlasmaError::Conversion(ConversionError::NotOnCurve))));
+    assert!(matches!(point_from_hex_xy(&x, "1", sign_params), Err(OpenPlasmaError::Conversion(ConversionError::MissingHexPrefix))));
+}
+
+#[test]
+pub fn fr_bytes_le_conversions() {
+    let mut rng = XorShiftRng::from_seed([0x5dbe6259, 0x8d313d76, 0x3237db17, 0xe5bc0654]);
+
+    let mut max = bn256::Fr::zero();
+    max.sub_assign(&bn256::Fr::one());
+    let mut samples: Vec<bn256::Fr> = (0..200).map(|_| rng.gen()).collect();
+    samples.extend_from_slice(&[bn256::Fr::zero(), bn256::Fr::one(), max]);
+    for fr in samples.iter() {
+        let bytes = fr_to_bytes_le(*fr, 32).unwrap();
+        assert_eq!(bytes_le_to_fr(&bytes), Ok(*fr));
+        assert_eq!(bytes.iter().rev().cloned().collect::<Vec<_>>(), fr_to_be_bytes(fr).to_vec());
+
+        let padded = fr_to_bytes_le(*fr, 40).unwrap();
+        assert_eq!(padded.len(), 40);
+        assert_eq!(bytes_le_to_fr(&padded), Ok(*fr));
+
+        // the sign message is the low 31 bytes, exactly the value with its top 6 bits cleared
+        let message = fr_to_sign_message(*fr);
+        assert_eq!(&message[..], &bytes[..31]);
+        let truncated = bytes_le_to_fr(&message).unwrap();
+        assert_eq!(fr_to_bytes_le(truncated, 31).unwrap(), message.to_vec());
+        assert_eq!(fr_to_sign_message(truncated), message);
+    }
+
+    // a value that needs more bytes than asked for is an error, not a truncation
+    assert_eq!(fr_to_bytes_le(usize_to_fr(255), 1), Ok(vec![255]));
+    assert_eq!(fr_to_bytes_le(usize_to_fr(256), 1), Err(ConversionError::DoesNotFit { bytes: 1 }.into()));
+    assert_eq!(fr_to_bytes_le(usize_to_fr(256), 2), Ok(vec![0, 1]));
+    assert_eq!(fr_to_bytes_le(max, 31), Err(ConversionError::DoesNotFit { bytes: 31 }.into()));
+    assert_eq!(fr_to_bytes_le(bn256::Fr::zero(), 0), Ok(vec![]));
+    assert_eq!(bytes_le_to_fr(&[]), Ok(bn256::Fr::zero()));
+
+    // the modulus and values above it
+    let mut modulus = fr_to_bytes_le(max, 32).unwrap();
+    modulus[0] += 1;
+    assert_eq!(bytes_le_to_fr(&modulus), Err(ConversionError::OutOfField.into()));
+    assert_eq!(bytes_le_to_fr(&[0xff; 32]), Err(ConversionError::OutOfField.into()));
+    let mut long = vec![0u8; 33];
+    long[32] = 1;
+    assert_eq!(bytes_le_to_fr(&long), Err(ConversionError::OutOfField.into()));
+}
+
+#[test]
+pub fn u128_amounts() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    // an 18 decimals amount far above 2^64
+    let amount = 5_000_000u128 * 10u128.pow(18);
+    assert!(amount > u128::from(u64::MAX));
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let circuit = padded_deposit_batch_circuit(
+        &mut tree,
+        &[Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 0, amount }],
+        1, account_depth, token_depth, &shared_params(),
+    );
+    assert_eq!(tree.balance(AccountId(1), 0), Ok(Balance(amount)));
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    // an amount and a balance of 2^128 don't pass the range check
+    let mut two_pow_128 = u128_to_fr(u128::MAX);
+    two_pow_128.add_assign(&bn256::Fr::one());
+    let mut forged = circuit;
+    forged.deposit_queue.witnesses_mut().unwrap()[0].amount = Some(two_pow_128);
+    forged.deposit_queue.witnesses_mut().unwrap()[0].account_state.new_balance = Some(two_pow_128);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    forged.synthesize(&mut cs).unwrap();
+    assert!(cs.which_is_unsatisfied().unwrap().contains("check amount overflow"));
+
+    for value in [0, 1, u128::from(u64::MAX) + 1, amount, u128::MAX].iter() {
+        assert_eq!(fr_to_u128_checked(&u128_to_fr(*value)), Ok(*value));
+    }
+    assert_eq!(fr_to_u128_checked(&two_pow_128), Err(ConversionError::DoesNotFit { bytes: 16 }.into()));
+    assert_eq!(Balance::try_from_fr(&two_pow_128), Err(RangeError("Balance")));
+    assert_eq!(Balance::from(7usize), Balance(7));
+}
+
+#[test]
+pub fn fr_to_usize_out_of_range() {
+    let usize_bytes = std::mem::size_of::<usize>();
+    assert_eq!(fr_to_usize(usize_to_fr(usize::MAX)), Ok(usize::MAX));
+    assert_eq!(
+        fr_to_usize(u128_to_fr(usize::MAX as u128 + 1)),
+        Err(ConversionError::DoesNotFit { bytes: usize_bytes }.into()),
+    );
+
+    let mut modulus_minus_one = bn256::Fr::zero();
+    modulus_minus_one.sub_assign(&bn256::Fr::one());
+    assert_eq!(fr_to_usize(modulus_minus_one), Err(ConversionError::DoesNotFit { bytes: usize_bytes }.into()));
+
+    // a corrupted balance leaf doesn't wrap into a small balance that passes the withdrawal check
+    let seckey = SecretKey::from_seed(b"corrupted balance");
+    let mut tree = AccountsTree::new(2, 1, poseidon_params(), jubjub_params());
+    let deposit = OffchainDeposit {
+        account_id: AccountId(1), pubkey: seckey.public_key(jubjub_params()), token_id: 0, amount: Balance(100),
+    };
+    deposit.update_tree_and_record_state(&mut tree).unwrap();
+    tree.update_balance(1, 0, modulus_minus_one).unwrap();
+
+    let withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(1),
+        nonce: Nonce(1), valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    assert_eq!(
+        withdrawal.update_tree_and_record_state(&mut tree).err(),
+        Some(TreeError::OutOfRange(RangeError("Balance")).into()),
+    );
+}
+
+fn deposit_step(account_depth: usize, token_depth: usize) -> impl Strategy<Value = DepositStep> {
+    (0..1u32 << account_depth, 0..1usize << token_depth, any::<u64>()).prop_map(
+        |(account_id, token_id, amount)| DepositStep {
+            account_id: AccountId(account_id),
+            token_id,
+            amount: Balance(u128::from(amount)),
+        },
+    )
+}
+
+proptest! {
+    // a case synthesizes four batches
+    #![proptest_config(ProptestConfig::with_cases(8))]
+
+    #[test]
+    fn deposit_scenarios_match_the_tree(
+        steps in prop::collection::vec(deposit_step(2, 1), 1..4),
+        operation in any::<prop::sample::Index>(),
+        level in 0..2usize,
+    ) {
+        let params = shared_params();
+        let mut tree = AccountsTree::new(2, 1, poseidon_params(), jubjub_params());
+        let scenario = DepositScenario { steps };
+        let circuit = check_scenario(&scenario, &mut tree, &params).unwrap();
+
+        for step in scenario.steps.iter() {
+            prop_assert!(tree.balance(step.account_id, step.token_id).unwrap() >= step.amount);
+        }
+
+        let operation = operation.index(scenario.steps.len());
+        let mutations = [
+            Mutation::Amount { operation },
+            Mutation::PathSibling { operation, level },
+            Mutation::Nonce { operation },
+        ];
+        for mutation in mutations.iter() {
+            let unsatisfied = check_mutation::<DepositScenario>(&circuit, *mutation).unwrap();
+            prop_assert_eq!(unsatisfied.operation(), Some(operation), "{:?} at {}", mutation, unsatisfied.path);
+        }
+    }
+}
+
+#[test]
+pub fn scenario_errors() {
+    let params = shared_params();
+    let step = DepositStep { account_id: AccountId(1), token_id: 0, amount: Balance(5) };
+
+    // the tree rejects what the circuit can't prove either
+    let mut tree = AccountsTree::new(2, 1, poseidon_params(), jubjub_params());
+    let out_of_tree = DepositScenario { steps: vec![DepositStep { token_id: 2, ..step }] };
+    assert!(matches!(
+        check_scenario(&out_of_tree, &mut tree, &params),
+        Err(ScenarioError::Tree(OpenPlasmaError::Tree(TreeError::TokenOutOfRange(2)))),
+    ));
+
+    let scenario = DepositScenario { steps: vec![step] };
+    let circuit = check_scenario(&scenario, &mut tree, &params).unwrap();
+    assert_eq!(DepositScenario::new_root(&circuit), Some(tree.get_root()));
+
+    // a claimed root the tree doesn't end at
+    let mut wrong_root = circuit.clone();
+    wrong_root.new_account_root = Some(bn256::Fr::one());
+    assert!(check_circuit(wrong_root).is_err());
+
+    let mutation = Mutation::Amount { operation: 1 };
+    assert!(matches!(
+        check_mutation::<DepositScenario>(&circuit, mutation),
+        Err(ScenarioError::NotApplicable(m)) if m == mutation,
+    ));
+    let mutation = Mutation::PathSibling { operation: 0, level: 2 };
+    assert!(matches!(
+        check_mutation::<DepositScenario>(&circuit, mutation),
+        Err(ScenarioError::NotApplicable(m)) if m == mutation,
+    ));
+}
+
+#[test]
+pub fn plasma_cli() {
+    let cli = |args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_plasma-cli")).args(args).output().unwrap();
+        // a failed verification is still a result, other failures are errors on stderr
+        let stream = if output.stdout.is_empty() { &output.stderr } else { &output.stdout };
+        (output.status.success(), serde_json::from_slice::<serde_json::Value>(stream).unwrap())
+    };
+
+    // the keys of SecretKey::from_seed
+    let seckey = SecretKey::from_seed(&[1, 2]);
+    let pubkey = fr_to_hex(&pack_point(&seckey.public_key(jubjub_params()).0));
+    let (ok, keys) = cli(&["keygen", "--seed", "0x0102"]);
+    assert!(ok);
+    assert_eq!(keys["seed"], "0x0102");
+    assert_eq!(keys["pubkey"], pubkey.as_str());
+    let (_, random) = cli(&["--pretty", "keygen"]);
+    assert!(random["seed"] != keys["seed"]);
+
+    // the request sign_withdrawal_json makes, verified against the packed pubkey
+    let (ok, signed) = cli(&["sign-withdrawal", "--account-id", "3", "--amount", "1000000000000000000000", "--nonce", "1",
+        "--eth-address", "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", "--seed", "0102"]);
+    assert!(ok);
+    let expected = sign_withdrawal_json(&[1, 2], 3, 0, "1000000000000000000000", "0", 1, &ETH_ADDRESS, &SigningDomain::default()).unwrap();
+    assert_eq!(signed, serde_json::from_str::<serde_json::Value>(&expected).unwrap());
+
+    let path = std::env::temp_dir().join(format!("openplasma_cli_{}.json", std::process::id()));
+    std::fs::write(&path, expected).unwrap();
+    let file = path.to_str().unwrap();
+    assert_eq!(cli(&["verify-withdrawal", "--pubkey", &pubkey, "--file", file]), (true, serde_json::json!({ "valid": true })));
+    let (ok, other_chain) = cli(&["verify-withdrawal", "--pubkey", &pubkey, "--file", file, "--chain-id", "5"]);
+    assert!(!ok);
+    assert_eq!(other_chain["valid"], false);
+
+    let (ok, missing) = cli(&["sign-withdrawal", "--amount", "1"]);
+    assert!(!ok);
+    assert_eq!(missing["error"], "--seed is required");
+
+    let (ok, bad_checksum) = cli(&["sign-withdrawal", "--account-id", "3", "--amount", "1", "--nonce", "1",
+        "--eth-address", "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD", "--seed", "0102"]);
+    assert!(!ok);
+    assert_eq!(bad_checksum["error"], "--eth-address: 0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD has a wrong checksum");
+
+    // a tree file and its json snapshot show the same accounts
+    let mut tree = AccountsTree::new(2, 1, poseidon_params(), jubjub_params());
+    OffchainDeposit { account_id: AccountId(1), pubkey: seckey.public_key(jubjub_params()), token_id: 1, amount: Balance(7) }
+        .update_tree_and_record_state(&mut tree)
+        .unwrap();
+    let tree_path = std::env::temp_dir().join(format!("openplasma_cli_{}.bin", std::process::id()));
+    tree.save(&tree_path, false).unwrap();
+    std::fs::write(&path, serde_json::to_string(&tree.export_snapshot()).unwrap()).unwrap();
+
+    let (ok, from_tree) = cli(&["inspect-snapshot", tree_path.to_str().unwrap()]);
+    assert!(ok);
+    assert_eq!(from_tree["root"], fr_to_hex(&tree.get_root()).as_str());
+    assert_eq!(from_tree["accounts"][0]["account_id"], 1);
+    assert_eq!(from_tree["accounts"][0]["pubkey"], pubkey.as_str());
+    assert_eq!(cli(&["inspect-snapshot", file]), (true, from_tree));
+
+    let mut forged = tree.export_snapshot();
+    forged.root = bn256::Fr::one();
+    std::fs::write(&path, serde_json::to_string(&forged).unwrap()).unwrap();
+    assert!(!cli(&["inspect-snapshot", file]).0);
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&tree_path).unwrap();
+}
+
+#[test]
+pub fn demo_block() {
+    let out = std::env::temp_dir().join(format!("openplasma_demo_block_{}", std::process::id()));
+    let demo = |args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_demo-block"))
+        .args(args)
+        .args(["--out", out.to_str().unwrap()])
+        .output()
+        .unwrap()
+        .status
+        .code();
+
+    // witness check only, a noop pads the deposit batch
+    assert_eq!(demo(&["--batch", "2", "--deposits", "1", "--withdrawals", "1", "--depth", "2", "--no-prove"]), Some(0));
+
+    let deposits = Pubdata::parse(&std::fs::read(out.join("deposit.pubdata")).unwrap(), jubjub_params()).unwrap();
+    assert_eq!(deposits.len(), 1);
+    assert!(matches!(deposits[0], PubdataOp::Deposit(ref deposit) if deposit.account_id == AccountId(2)));
+    let withdrawals = Pubdata::parse(&std::fs::read(out.join("withdrawal.pubdata")).unwrap(), jubjub_params()).unwrap();
+    assert!(matches!(withdrawals[0], PubdataOp::Withdrawal(ref withdrawal) if withdrawal.nonce == Nonce(1)));
+    // the four block inputs, the withdrawal block also has the total fee and the timestamp
+    assert_eq!(std::fs::read(out.join("deposit.inputs")).unwrap().len(), 4 * 32);
+    assert_eq!(std::fs::read(out.join("withdrawal.inputs")).unwrap().len(), 6 * 32);
+    assert!(!out.join("deposit.proof").exists());
+
+    // the fee account, one funded account and three deposits need depth 3
+    assert_eq!(demo(&["--batch", "3", "--withdrawals", "1", "--depth", "2", "--no-prove"]), Some(2));
+    assert_eq!(demo(&["--batch"]), Some(2));
+
+    std::fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+pub fn first_deposit_empty_leaf() {
+    let params = shared_params();
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let (account_depth, token_depth) = (2, 1);
+
+    // the circuit takes the empty leaf from tree::empty, the same as the tree
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    assert_eq!(
+        tree.empty_leaf(),
+        empty_account_leaf_with_hasher::<Bn256, Poseidon>(token_depth, BINARY_ARITY, hash_params, sign_params),
+    );
+    let rescue_tree = PoseidonMerkleTree::<Bn256, Rescue>::new_with_arity(
+        vec![vec![bn256::Fr::zero()]; 1 << (2 * token_depth)], 4, rescue_params(),
+    );
+    assert_eq!(
+        rescue_tree.root(),
+        empty_balances_root_with_hasher::<Bn256, Rescue>(token_depth, 4, rescue_params()),
+    );
+
+    // a first deposit into the empty leaf, then one into the same account
+    let step = DepositStep { account_id: AccountId(1), token_id: 0, amount: Balance(5) };
+    let scenario = DepositScenario { steps: vec![step, step] };
+    let circuit = check_scenario(&scenario, &mut tree, &params).unwrap();
+    assert_eq!(tree.balance(AccountId(1), 0), Ok(Balance(10)));
+    assert_eq!(tree.nonce(AccountId(1)), Ok(Nonce(0)));
+
+    let first_deposit = |change: &dyn Fn(&mut AccountState<Bn256>)| {
+        let mut circuit = circuit.clone();
+        change(&mut circuit.deposit_queue.witnesses_mut().unwrap()[0].account_state);
+        check_circuit(circuit)
+    };
+
+    // an empty leaf has nonce 0, a nonzero old nonce isn't the empty leaf
+    assert!(first_deposit(&|state| {
+        state.old_nonce = Some(usize_to_fr(1));
+        state.new_nonce = Some(usize_to_fr(1));
+    }).is_err());
+
+    // the new leaf starts from nonce 0 and a balance of exactly the amount
+    assert!(first_deposit(&|state| state.new_nonce = Some(usize_to_fr(1))).is_err());
+    assert!(first_deposit(&|state| {
+        state.old_balance = Some(usize_to_fr(1));
+        state.new_balance = Some(usize_to_fr(6));
+    }).is_err());
+}
+
+// a withdrawal batch of one withdrawal per fee from funded accounts 0.., the
+// fees go to the account after them. the public inputs claim the fee total
+fn withdrawal_batch_with_fees(
+    fees: &[u128],
+    account_depth: usize,
+) -> (OffchainWithdrawalBatchCircuit<'static, Bn256>, PublicInputs<Bn256>) {
+    withdrawal_batch_with_amounts(&vec![10; fees.len()], fees, account_depth)
+}
+
+fn withdrawal_batch_with_amounts(
+    amounts: &[u128],
+    fees: &[u128],
+    account_depth: usize,
+) -> (OffchainWithdrawalBatchCircuit<'static, Bn256>, PublicInputs<Bn256>) {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let domain = SigningDomain::default();
+    let token_depth = 1;
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let seckeys: Vec<_> = (0..fees.len()).map(|i| SecretKey::from_seed(format!("fee payer {}", i).as_bytes())).collect();
+    for (account_id, seckey) in seckeys.iter().enumerate() {
+        OffchainDeposit {
+            account_id: AccountId(account_id as u32),
+            pubkey: seckey.public_key(sign_params),
+            token_id: 0,
+            amount: Balance(100),
+        }.update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    let old_hash = bn256::Fr::zero();
+    let old_root = tree.get_root();
+    let mut accum_hash = old_hash;
+    let mut queue = Vec::new();
+    let mut total_fee = Balance(0);
+
+    for (account_id, ((seckey, fee), amount)) in seckeys.iter().zip(fees.iter()).zip(amounts.iter()).enumerate() {
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(account_id as u32),
+            token_id: 0,
+            amount: Balance(*amount),
+            fee: Balance(*fee),
+            nonce: Nonce(1),
+            valid_until: 0,
+            eth_address: ETH_ADDRESS,
+            sign: None,
+        };
+        seckey.sign_withdrawal(&mut withdrawal, &domain, hash_params, sign_params);
+
+        let account_state = withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+        accum_hash = withdrawal.record_hash(accum_hash, hash_params);
+        total_fee = total_fee.checked_add(withdrawal.fee).unwrap();
+
+        queue.push(OffchainWithdrawalCircuit::<Bn256> {
+            account_state,
+            account_id: Some(withdrawal.account_id.to_fr()),
+            token_id: Some(usize_to_fr(withdrawal.token_id)),
+            amount: Some(withdrawal.amount.to_fr()),
+            fee: Some(withdrawal.fee.to_fr()),
+            nonce: Some(withdrawal.nonce.to_fr()),
+            valid_until: Some(usize_to_fr(withdrawal.valid_until)),
+            eth_address: Some(address_to_fr(&withdrawal.eth_address)),
+            sign: withdrawal.sign.clone(),
+            pubkey: Some(seckey.public_key(sign_params).0),
+            is_noop: Some(false),
+        });
+    }
+
+    let fee_account_id = AccountId(fees.len() as u32);
+    let fee_account_state = credit_fee_and_record_state(&mut tree, fee_account_id, 0, total_fee).unwrap();
+    let public_inputs = PublicInputs::<Bn256>::new(old_hash, accum_hash, old_root, tree.get_root())
+        .with_total_fee(total_fee.to_fr());
+
+    let circuit = OffchainWithdrawalBatchCircuit {
+        batch_size: fees.len(),
+        account_depth,
+        token_depth,
+        hash_params,
+        sign_params,
+        signing_domain: domain,
+        reject_zero_amount: false,
+        queue,
+        fee_account_state,
+        fee_account_id: Some(fee_account_id.to_fr()),
+        fee_token_id: Some(usize_to_fr(0)),
+        total_fee: public_inputs.total_fee,
+        timestamp: Some(usize_to_fr(0)),
+        old_accum_hash: Some(public_inputs.old_accum_hash),
+        new_accum_hash: Some(public_inputs.new_accum_hash),
+        old_account_root: Some(public_inputs.old_account_root),
+        new_account_root: Some(public_inputs.new_account_root),
+    };
+
+    (circuit, public_inputs)
+}
+
+#[test]
+pub fn withdrawal_batch_total_fee() {
+    let (circuit, public_inputs) = withdrawal_batch_with_fees(&[0, 3, 5], 2);
+
+    // the total fee is the input after the block inputs, the timestamp follows it
+    assert_eq!(public_inputs.total_fee, Some(Balance(8).to_fr()));
+    let mut inputs = public_inputs.to_vec();
+    assert_eq!(inputs.len(), 5);
+    assert_eq!(inputs[4], Balance(8).to_fr());
+    inputs.push(usize_to_fr(0));
+
+    let mut rng = thread_rng();
+    let params = setup_offchain_withdraw_circuit(3, 2, 1, poseidon_params(), jubjub_params()).unwrap();
+    let proof = create_random_proof(circuit, &params, &mut rng).unwrap();
+    let verifying_key = prepare_verifying_key(&params.vk);
+    assert!(verify_proof(&verifying_key, &proof, &inputs).unwrap());
+}
+
+#[test]
+pub fn withdrawal_batch_total_fee_mismatch() {
+    let (circuit, public_inputs) = withdrawal_batch_with_fees(&[1, 2], 2);
+    assert_eq!(public_inputs.total_fee, Some(Balance(3).to_fr()));
+
+    // the operator can't claim a total other than the fees it credited
+    for claimed in [0, 2, 4].iter() {
+        let mut mismatched = circuit.clone();
+        mismatched.total_fee = Some(Balance(*claimed).to_fr());
+        assert_eq!(check_circuit(mismatched).unwrap_err().path, "enforce total fee equivalence");
+    }
+
+    // nor have an honest proof verified with another total
+    let mut rng = thread_rng();
+    let params = setup_offchain_withdraw_circuit(2, 2, 1, poseidon_params(), jubjub_params()).unwrap();
+    let proof = create_random_proof(circuit, &params, &mut rng).unwrap();
+    let verifying_key = prepare_verifying_key(&params.vk);
+
+    let inputs = |total_fee: u128| {
+        let mut inputs = public_inputs.clone().with_total_fee(Balance(total_fee).to_fr()).to_vec();
+        inputs.push(usize_to_fr(0));
+        inputs
+    };
+    assert!(verify_proof(&verifying_key, &proof, &inputs(3)).unwrap());
+    assert!(!verify_proof(&verifying_key, &proof, &inputs(4)).unwrap());
+}
+
+#[test]
+pub fn state_diff_sync() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let pubkey = |account_id: usize| SecretKey::from_seed(format!("replica account {}", account_id).as_bytes())
+        .public_key(sign_params);
+
+    let mut tree = AccountsTree::new(3, 1, hash_params, sign_params);
+    let genesis = tree.export_snapshot();
+    let mut replica = genesis.clone();
+    let mut last_diff = None;
+
+    // ten blocks: six registrations, a transfer, a closed account, an empty
+    // block and the closed slot registered again
+    for block in 0..10 {
+        let old = tree.export_snapshot();
+        match block {
+            0..=5 => {
+                tree.update_account(block, pubkey(block), bn256::Fr::zero()).unwrap();
+                tree.update_balance(block, 0, usize_to_fr(10 * (block + 1))).unwrap();
+            },
+            6 => {
+                tree.update_balance(0, 0, usize_to_fr(5)).unwrap();
+                tree.update_balance(1, 0, usize_to_fr(25)).unwrap();
+                tree.update_nonce(0, usize_to_fr(1)).unwrap();
+            },
+            7 => {
+                tree.update_balance(2, 0, bn256::Fr::zero()).unwrap();
+                tree.clear_account(2).unwrap();
+            },
+            8 => {},
+            _ => {
+                tree.update_account(2, pubkey(9), bn256::Fr::zero()).unwrap();
+                tree.update_balance(2, 1, usize_to_fr(7)).unwrap();
+            },
+        }
+        let new = tree.export_snapshot();
+
+        let diff = StateSnapshot::diff(&old, &new);
+        match block {
+            6 => assert_eq!(diff.accounts.iter().map(|account| account.account_id).collect::<Vec<_>>(), vec![0, 1]),
+            7 => assert_eq!((diff.accounts.len(), &diff.removed[..]), (0, &[2][..])),
+            8 => assert_eq!((diff.accounts.len(), diff.removed.len(), diff.old_root), (0, 0, diff.new_root)),
+            _ => assert_eq!(diff.accounts.len(), 1),
+        }
+
+        // the replica only ever sees the encoded diff
+        let bytes = diff.to_bytes();
+        assert!(bytes.len() < serde_json::to_vec(&diff).unwrap().len());
+        let decoded = StateDiff::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, diff);
+
+        replica.apply_diff(&decoded).unwrap();
+        assert_eq!(replica.root, tree.get_root());
+        assert_eq!(replica.accounts, new.accounts);
+        last_diff = Some(diff);
+    }
+    assert_eq!(AccountsTree::from_snapshot(&replica, hash_params, sign_params).unwrap().get_root(), tree.get_root());
+
+    // roots, the account id, pubkey, nonce and two balances of 32 bytes each,
+    // and a length for every sequence
+    let last_diff = last_diff.unwrap();
+    assert_eq!(last_diff.to_bytes().len(), 2 * 32 + 8 + (8 + 32 + 32 + 8 + 2 * 32) + 8 + 8 + 8);
+
+    // a diff applies only on top of the state it was made from
+    let synced = replica.clone();
+    assert!(matches!(replica.apply_diff(&last_diff), Err(OpenPlasmaError::Tree(TreeError::BaseRootMismatch { .. }))));
+    let mut stale = genesis.clone();
+    assert!(matches!(stale.apply_diff(&last_diff), Err(OpenPlasmaError::Tree(TreeError::BaseRootMismatch { .. }))));
+    assert_eq!((&replica, &stale), (&synced, &genesis));
+
+    // a forged account doesn't hash to the new root, the snapshot is kept
+    let mut forged = StateSnapshot::diff(&genesis, &tree.export_snapshot());
+    forged.accounts[0].balances[0] = usize_to_fr(1000);
+    assert!(matches!(
+        stale.apply_diff(&forged),
+        Err(OpenPlasmaError::Tree(TreeError::InvalidSnapshot("root mismatch")))
+    ));
+    assert_eq!(stale, genesis);
+
+    assert!(matches!(StateDiff::from_bytes(&last_diff.to_bytes()[..40]), Err(OpenPlasmaError::Io(_))));
+
+    // the operator applies two priority ops, the replica syncs the diff and
+    // takes over: its queue resumes after them and its next block goes on
+    let events: Vec<_> = (0..3u64).map(|serial_id| {
+        let account_id = 6 + serial_id as usize % 2;
+        let mut pubkey_bytes = [0u8; 32];
+        pubkey(account_id).write(&mut pubkey_bytes[..]).unwrap();
+        PriorityOp {
+            serial_id,
+            account_id: AccountId(account_id as u32),
+            token_id: 0,
+            pubkey_bytes,
+            amount: Balance(u128::from(serial_id) + 1),
+            eth_block: 1000 + serial_id,
+        }.encode_event()
+    }).collect();
+    let config = BatchConfig { deposit_batch: 2, account_depth: 3, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let old = tree.export_snapshot();
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), bn256::Fr::zero()).unwrap();
+    for data in events[..2].iter() {
+        builder.push_priority_op(&decode_deposit_event(data).unwrap()).unwrap();
+    }
+    let (_, public_inputs, _) = builder.seal().unwrap();
+
+    let diff = StateSnapshot::diff(&old, &tree.export_snapshot());
+    assert_eq!((&diff.processed_ops[..], diff.unprocessed_ops.len()), (&[0, 1][..], 0));
+    let mut replica = synced;
+    replica.apply_diff(&StateDiff::from_bytes(&diff.to_bytes()).unwrap()).unwrap();
+    assert_eq!(&replica.processed_ops, tree.processed_ops());
+
+    let mut replica_tree = AccountsTree::from_snapshot(&replica, hash_params, sign_params).unwrap();
+    let mut queue = PriorityQueue::resume(replica_tree.processed_ops());
+    let ingested: Vec<_> = events.iter().map(|data| queue.push(decode_deposit_event(data).unwrap())).collect();
+    assert_eq!(ingested, vec![Err(PriorityQueueError::Duplicate(0)), Err(PriorityQueueError::Duplicate(1)), Ok(())]);
+    let mut builder = BlockBuilder::new(&mut replica_tree, config, &shared_params(), public_inputs.new_accum_hash).unwrap();
+    builder.push_queued_op(&queue.drain(1).unwrap()[0]).unwrap();
+    builder.seal().unwrap();
+    assert_eq!(replica_tree.processed_ops().next_serial_id(), 3);
+
+    // a diff whose ops the replica already processed is refused
+    let mut replayed = replica.clone();
+    replayed.root = diff.old_root;
+    assert!(matches!(
+        replayed.apply_diff(&diff),
+        Err(OpenPlasmaError::Tree(TreeError::AlreadyProcessed(0)))
+    ));
+}
+
+#[test]
+pub fn open_plasma_error() {
+    let tree = AccountsTree::new(2, 1, poseidon_params(), jubjub_params());
+
+    // the module errors come wrapped, and `?` mixes them in one function
+    let read = |account_id: usize, hex: &str| -> Result<bn256::Fr, OpenPlasmaError> {
+        let mut balance = tree.get_balance(account_id, 0)?;
+        balance.add_assign(&fr_from_hex(hex)?);
+        Ok(balance)
+    };
+    assert_eq!(read(1, "0x01"), Ok(usize_to_fr(1)));
+    assert_eq!(read(4, "0x01"), Err(OpenPlasmaError::Tree(TreeError::AccountOutOfRange(4))));
+    assert_eq!(read(1, "01"), Err(OpenPlasmaError::Conversion(ConversionError::MissingHexPrefix)));
+
+    let err = read(4, "0x01").unwrap_err();
+    assert_eq!(err.to_string(), "Tree error: Account 4 is out of the tree");
+    assert_eq!(std::error::Error::source(&err).unwrap().to_string(), "Account 4 is out of the tree");
+
+    // a bellman io error, reading parameters, is an io error
+    let io = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "parameters");
+    assert!(matches!(OpenPlasmaError::from(SynthesisError::IoError(io)), OpenPlasmaError::Io(_)));
+    assert!(matches!(
+        OpenPlasmaError::from(SynthesisError::Unsatisfiable),
+        OpenPlasmaError::Circuit(SynthesisError::Unsatisfiable),
+    ));
+    assert_eq!(
+        OpenPlasmaError::from(LeafError::WrongTag(LeafVersion::V1)),
+        TreeError::InvalidLeaf(LeafError::WrongTag(LeafVersion::V1)).into(),
+    );
+}
+
+#[test]
+pub fn circuit_layout() {
+    let params = shared_params();
+    let config = BatchConfig { deposit_batch: 2, account_depth: 2, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let layout = CircuitFamily::new(Arc::clone(&params)).layout(config).unwrap();
+
+    let semantics: Vec<_> = layout.inputs.iter().map(|input| (input.index, input.semantic)).collect();
+    assert_eq!(semantics, vec![
+        (0, InputSemantic::OldAccumHash),
+        (1, InputSemantic::NewAccumHash),
+        (2, InputSemantic::OldRoot),
+        (3, InputSemantic::NewRoot),
+    ]);
+    assert_eq!(layout.index_of(InputSemantic::NewRoot), Some(3));
+    assert_eq!(layout.index_of(InputSemantic::TotalFee), None);
+    assert_eq!(layout.inputs[3].name, "allocate public inputs/input new root");
+
+    // the layout names the inputs a filled circuit allocates, in their order
+    let mut tree = AccountsTree::new(config.account_depth, config.token_depth, poseidon_params(), jubjub_params());
+    let mut block = BlockBuilder::new(&mut tree, config, &params, bn256::Fr::zero()).unwrap();
+    block.push_deposit(OffchainDeposit {
+        account_id: AccountId(1),
+        pubkey: SecretKey::from_seed(b"layout").public_key(jubjub_params()),
+        token_id: 0,
+        amount: Balance(5),
+    }).unwrap();
+    let (circuit, public_inputs, _) = block.seal().unwrap();
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+    assert_eq!(cs.num_inputs(), layout.inputs.len() + 1);
+    for input in layout.inputs.iter() {
+        let value = cs.get_input(input.index + 1, &format!("{}/input variable", input.name));
+        assert_eq!(Some(value), public_inputs.get(input.semantic));
+    }
+    assert_eq!(layout.input_vector(&public_inputs), Some(public_inputs.to_vec()));
+
+    // the ranges cover every constraint once and in order
+    assert_eq!(layout.constraints, measure(circuit).unwrap().constraints);
+    let mut end = 0;
+    for range in layout.namespaces.iter() {
+        assert_eq!(range.start, end);
+        assert!(range.end > range.start);
+        end = range.end;
+    }
+    assert_eq!(end, layout.constraints);
+    let names: Vec<_> = layout.namespaces.iter().map(|range| range.name.as_str()).collect();
+    assert_eq!(&names[..3], &["allocate public inputs", "verify deposit 0", "verify deposit 1"]);
+    assert!(names.contains(&"enforce new root equivalence"));
+
+    let json = serde_json::to_string(&layout).unwrap();
+    assert!(json.contains(r#""semantic":"new_root""#));
+    assert_eq!(serde_json::from_str::<CircuitLayout>(&json).unwrap(), layout);
+
+    // a withdrawal batch has the total fee and then the timestamp after the
+    // block inputs. PublicInputs holds no timestamp, so it can't fill the vector
+    let (circuit, public_inputs) = withdrawal_batch_with_fees(&[1, 2], 2);
+    let layout = CircuitLayout::of(circuit.clone()).unwrap();
+    assert_eq!(layout.index_of(InputSemantic::TotalFee), Some(4));
+    assert_eq!(layout.index_of(InputSemantic::Timestamp), Some(5));
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.num_inputs(), layout.inputs.len() + 1);
+    for input in layout.inputs[..5].iter() {
+        let value = cs.get_input(input.index + 1, &format!("{}/input variable", input.name));
+        assert_eq!(Some(value), public_inputs.get(input.semantic));
+    }
+    assert_eq!(layout.input_vector(&public_inputs), None);
+}
+
+#[test]
+pub fn setup_circuit_shape() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+
+    let mut rng = thread_rng();
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    );
+
+    for &(deposit_batch, account_depth, token_depth) in [(1, 2, 1), (2, 3, 2), (3, 4, 1)].iter() {
+        let config = BatchConfig { deposit_batch, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+        let setup = DepositBatchCircuit::for_setup(config, &shared_params());
+
+        // one real deposit, the rest of the batch noops
+        let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+        let filled = padded_deposit_batch_circuit(
+            &mut tree,
+            &[Deposit { pubkey: Some(pubkey.clone()), account_id: 1, token_id: 0, amount: 10 }],
+            deposit_batch, account_depth, token_depth, &shared_params(),
+        );
+
+        assert_eq!(measure(setup.clone()).unwrap(), measure(filled.clone()).unwrap());
+        assert_eq!(shape(setup).unwrap(), shape(filled).unwrap());
+    }
+
+    // a setup witness of another depth doesn't synthesize into a smaller shape
+    let config = BatchConfig { deposit_batch: 2, account_depth: 3, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let mut setup = DepositBatchCircuit::for_setup(config, &shared_params());
+    setup.deposit_queue = vec![DepositCircuit::empty(2, 1); 2].into();
+    assert!(measure(setup).is_err());
+}
+
+#[test]
+pub fn deposit_replay_protection() {
+    let sign_params = jubjub_params();
+    let mut rng = thread_rng();
+    let config = BatchConfig { deposit_batch: 2, account_depth: 3, token_depth: 1, leaf_version: LeafVersion::V0 };
+
+    let events: Vec<_> = (0..3u64).map(|serial_id| {
+        let pubkey = PublicKey::from_private(
+            &PrivateKey::<Bn256>(rng.gen()),
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+        );
+        let mut pubkey_bytes = [0u8; 32];
+        pubkey.write(&mut pubkey_bytes[..]).unwrap();
+
+        PriorityOp {
+            serial_id,
+            account_id: AccountId(serial_id as u32 + 1),
+            token_id: 0,
+            pubkey_bytes,
+            amount: Balance(u128::from(serial_id) + 10),
+            eth_block: 1000 + serial_id,
+        }.encode_event()
+    }).collect();
+
+    // block 1 takes ops 0 and 1, the snapshot is saved with them processed
+    let mut tree = AccountsTree::new(3, 1, poseidon_params(), sign_params);
+    let mut queue = PriorityQueue::resume(tree.processed_ops());
+    for data in events.iter() {
+        queue.push(decode_deposit_event(data).unwrap()).unwrap();
+    }
+
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), bn256::Fr::zero()).unwrap();
+    for op in queue.drain(2).unwrap().iter() {
+        builder.push_queued_op(op).unwrap();
+    }
+    let (_, block_inputs, _) = builder.seal_checked(bn256::Fr::zero()).unwrap();
+    let accum_hash = block_inputs.new_accum_hash;
+    tree.finalize_block(1).unwrap();
+
+    let saved = serde_json::to_string(&tree.export_snapshot()).unwrap();
+    let snapshot: StateSnapshot = serde_json::from_str(&saved).unwrap();
+    assert_eq!(snapshot.processed_ops.next_serial_id(), 2);
+
+    // the operator dies in the middle of block 2
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), accum_hash).unwrap();
+    builder.push_queued_op(&queue.drain(1).unwrap()[0]).unwrap();
+    drop(builder);
+
+    // and restarts from the snapshot, the contract's events are ingested
+    // again from the first one
+    let mut tree = AccountsTree::from_snapshot(&snapshot, poseidon_params(), sign_params).unwrap();
+    assert_eq!(tree.get_root(), snapshot.root);
+
+    let mut queue = PriorityQueue::resume(tree.processed_ops());
+    let replayed: Vec<_> = events.iter().map(
+        |data| queue.push(decode_deposit_event(data).unwrap())
+    ).collect();
+    assert_eq!(replayed, vec![Err(PriorityQueueError::Duplicate(0)), Err(PriorityQueueError::Duplicate(1)), Ok(())]);
+
+    // an op applied before is refused by the builder as well, the state is
+    // unchanged
+    let applied = decode_deposit_event(&events[1]).unwrap();
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), accum_hash).unwrap();
+    assert!(matches!(
+        builder.push_priority_op(&applied),
+        Err(OpenPlasmaError::Tree(TreeError::AlreadyProcessed(1)))
+    ));
+    assert!(builder.is_empty());
+    drop(builder);
+    assert_eq!(tree.export_snapshot(), snapshot);
+
+    // a rolled back op is processed again
+    let checkpoint = tree.checkpoint();
+    let op = decode_deposit_event(&events[2]).unwrap();
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), accum_hash).unwrap();
+    builder.push_priority_op(&op).unwrap();
+    drop(builder);
+    assert!(tree.processed_ops().contains(2));
+    tree.rollback(checkpoint).unwrap();
+    assert_eq!(tree.get_root(), snapshot.root);
+    assert_eq!(tree.processed_ops(), &snapshot.processed_ops);
+
+    // block 2 has to chain onto block 1's accum hash
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), bn256::Fr::zero()).unwrap();
+    builder.push_priority_op(&op).unwrap();
+    match builder.seal_checked(accum_hash) {
+        Err(OpenPlasmaError::Block(BlockError::AccumHashMismatch { expected, actual })) => {
+            assert_eq!(expected, accum_hash);
+            assert_eq!(actual, bn256::Fr::zero());
+        },
+        _ => panic!("a block off the expected accum hash is sealed"),
+    }
+
+    let mut tree = AccountsTree::from_snapshot(&snapshot, poseidon_params(), sign_params).unwrap();
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), accum_hash).unwrap();
+    builder.push_queued_op(&queue.drain(1).unwrap()[0]).unwrap();
+    let (circuit, public_inputs, _) = builder.seal_checked(accum_hash).unwrap();
+    assert_eq!(public_inputs.old_accum_hash, accum_hash);
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.synthesize(&mut cs).unwrap();
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    // every op applied exactly once
+    let mut processed = ProcessedOps::new();
+    for serial_id in 0..3 {
+        assert!(processed.insert(serial_id));
+    }
+    assert_eq!(tree.processed_ops(), &processed);
+    let balances: Vec<_> = tree.iter_accounts().map(|(account_id, _, _, balances)| (account_id, balances[0])).collect();
+    assert_eq!(balances, (0..3).map(|serial_id| (serial_id + 1, usize_to_fr(serial_id + 10))).collect::<Vec<_>>());
+}
+
+#[test]
+pub fn merkle_update_gadget() {
+    let hash_params = poseidon_params();
+    let leaf_index = 11;
+    let new_leaf = vec![usize_to_fr(7), usize_to_fr(8)];
+
+    // a leaf of two fields, not the account layout
+    for &(arity, depth) in [(2, 4), (4, 2)].iter() {
+        let leaves: Vec<_> = (0..16).map(|i| vec![usize_to_fr(i), usize_to_fr(2 * i)]).collect();
+        let mut tree = PoseidonMerkleTree::<Bn256>::new_with_arity(leaves.clone(), arity, hash_params);
+        assert_eq!(tree.depth(), depth);
+
+        let old_root = tree.root();
+        let state = MerkleUpdateState::<Bn256> {
+            old_leaf: optionalize(leaves[leaf_index].clone()),
+            new_leaf: optionalize(new_leaf.clone()),
+            path: optionalize(tree.get_leaf_path(leaf_index)),
+            indices: optionalize(tree.get_leaf_indices(leaf_index)),
+        };
+        tree.update_leaf(leaf_index, new_leaf.clone());
+
+        let update = |state: &MerkleUpdateState<Bn256>| {
+            let mut cs = TestConstraintSystem::<Bn256>::new();
+            let gadget = MerkleUpdateGadget::new_with_arity(
+                cs.namespace(|| "update"), 2, depth, arity, hash_params, state,
+            ).unwrap();
+            let old_root_alloc = AllocatedNum::alloc(cs.namespace(|| "old root"), || Ok(old_root)).unwrap();
+            gadget.verify_old_root(cs.namespace(|| "verify old root"), &old_root_alloc).unwrap();
+            let calculated_old_root = gadget.calc_old_root(cs.namespace(|| "calculate old root")).unwrap();
+            let new_root = gadget.calc_new_root(cs.namespace(|| "calculate new root")).unwrap();
+
+            (cs.is_satisfied(), calculated_old_root.get_value(), new_root.get_value())
+        };
+
+        assert_eq!(update(&state), (true, Some(old_root), Some(tree.root())));
+
+        // a sibling or an index off the path doesn't verify the old root
+        let mut wrong_sibling = state.clone();
+        wrong_sibling.path[0] = Some(usize_to_fr(1));
+        assert!(!update(&wrong_sibling).0);
+
+        let mut wrong_index = state.clone();
+        wrong_index.indices[0] = wrong_index.indices[0].map(|bit| !bit);
+        assert!(!update(&wrong_index).0);
+
+        // the leaf width is the caller's
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        assert!(MerkleUpdateGadget::new_with_arity(
+            cs.namespace(|| "update"), 3, depth, arity, hash_params, &state,
+        ).is_err());
+    }
+
+    // AccountCircuit on the gadget has the constraints it had before, counted
+    // when it was built on TreeCircuit
+    for &(account_depth, token_depth, arity, constraints) in [(3, 2, 2usize, 2037), (2, 1, 4, 1368)].iter() {
+        let index_bits = arity.trailing_zeros() as usize;
+        let state = AccountState::<Bn256> {
+            old_balance: Some(bn256::Fr::zero()),
+            new_balance: Some(bn256::Fr::zero()),
+            old_pubkey: Some(Point::zero()),
+            new_pubkey: Some(Point::zero()),
+            old_nonce: Some(bn256::Fr::zero()),
+            new_nonce: Some(bn256::Fr::zero()),
+            account_path: vec![Some(bn256::Fr::zero()); account_depth * (arity - 1)],
+            account_indices: vec![Some(false); account_depth * index_bits],
+            token_path: vec![Some(bn256::Fr::zero()); token_depth * (arity - 1)],
+            token_indices: vec![Some(false); token_depth * index_bits],
+        };
+
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        AccountCircuit::new_with_arity(
+            cs.namespace(|| "account"), account_depth, token_depth, arity, hash_params, &state,
+        ).unwrap();
+        assert_eq!(cs.num_constraints(), constraints);
+    }
+}
+
+#[test]
+pub fn witness_validation() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let config = BatchConfig { deposit_batch: 3, account_depth: 3, token_depth: 1, leaf_version: LeafVersion::V0 };
+
+    let mut rng = thread_rng();
+    let pubkeys: Vec<_> = (0..2).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+    let deposit = |account_id: u32, pubkey: &PublicKey<Bn256>| OffchainDeposit {
+        account_id: AccountId(account_id),
+        pubkey: pubkey.clone(),
+        token_id: 0,
+        amount: Balance(10),
+    };
+
+    // a sealed block validates, a corrupted path names its slot
+    let mut tree = AccountsTree::new(3, 1, hash_params, sign_params);
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), bn256::Fr::zero()).unwrap();
+    builder.push_deposit(deposit(1, &pubkeys[0])).unwrap();
+    builder.push_deposit(deposit(5, &pubkeys[1])).unwrap();
+    let (circuit, _, _) = builder.seal().unwrap();
+    circuit.validate_witness().unwrap();
+
+    let corrupted = |slot: usize, corrupt: &dyn Fn(&mut DepositCircuit<Bn256>)| {
+        let mut deposits = circuit.deposit_queue.witnesses().unwrap().to_vec();
+        corrupt(&mut deposits[slot]);
+        DepositBatchCircuit { deposit_queue: deposits.into(), ..circuit.clone() }
+    };
+
+    let batch = corrupted(1, &|deposit| deposit.account_state.account_path[0].as_mut().unwrap().add_assign(&bn256::Fr::one()));
+    assert_eq!(batch.validate_witness(), Err(WitnessError::OldRootMismatch { slot: 1 }));
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    batch.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let batch = corrupted(0, &|deposit| deposit.amount = Some(usize_to_fr(11)));
+    assert_eq!(batch.validate_witness(), Err(WitnessError::InconsistentLeaf {
+        slot: 0, reason: "new balance is not the old one plus the amount",
+    }));
+    let batch = corrupted(1, &|deposit| { deposit.account_state.token_path.pop(); });
+    assert_eq!(batch.validate_witness().unwrap_err().slot(), Some(1));
+
+    // the noop's path is never checked, its root is the previous one
+    let batch = corrupted(2, &|deposit| deposit.account_state.account_path[0] = Some(usize_to_fr(3)));
+    batch.validate_witness().unwrap();
+
+    let wrong_root = DepositBatchCircuit { new_account_root: Some(bn256::Fr::zero()), ..circuit.clone() };
+    assert_eq!(wrong_root.validate_witness(), Err(WitnessError::NewRootMismatch));
+
+    // a balance changed behind the tree's back makes the deposit to it fail
+    // at seal, not in the prover
+    let mut tree = AccountsTree::new(3, 1, hash_params, sign_params);
+    tree.accounts[5].balances[0] = usize_to_fr(7);
+    let mut builder = BlockBuilder::new(&mut tree, config, &shared_params(), bn256::Fr::zero()).unwrap();
+    builder.push_deposit(deposit(1, &pubkeys[0])).unwrap();
+    builder.push_deposit(deposit(5, &pubkeys[1])).unwrap();
+    match builder.seal() {
+        Err(OpenPlasmaError::Block(BlockError::InvalidWitness(err))) => assert_eq!(err, WitnessError::OldRootMismatch { slot: 1 }),
+        _ => panic!("a stale witness is sealed"),
+    }
+
+    // the same for a withdrawal batch, the fee credit is the slot after the
+    // last withdrawal
+    let (circuit, _) = withdrawal_batch_with_fees(&[1, 2], 2);
+    circuit.validate_witness().unwrap();
+
+    let mut batch = circuit.clone();
+    batch.queue[1].account_state.account_path[1] = Some(usize_to_fr(3));
+    assert_eq!(batch.validate_witness(), Err(WitnessError::OldRootMismatch { slot: 1 }));
+
+    let mut batch = circuit.clone();
+    batch.queue[0].nonce = Some(usize_to_fr(5));
+    assert_eq!(batch.validate_witness(), Err(WitnessError::InconsistentLeaf {
+        slot: 0, reason: "nonce is not the old one plus one",
+    }));
+
+    let mut batch = circuit.clone();
+    batch.fee_account_state.token_path[0] = Some(usize_to_fr(3));
+    assert_eq!(batch.validate_witness(), Err(WitnessError::OldRootMismatch { slot: 2 }));
+
+    let mut batch = circuit.clone();
+    batch.new_accum_hash = Some(bn256::Fr::zero());
+    assert_eq!(batch.validate_witness(), Err(WitnessError::AccumHashMismatch));
+}
+
+#[test]
+pub fn poseidon_sponge() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let params = shared_params();
+    let mut rng = thread_rng();
+
+    // absorbed in two pieces and squeezed past the rate of 4, the gadget gives
+    // the values of the native sponge; 3, 4 and 5 inputs are around a block
+    for len in 0..10 {
+        let input: Vec<bn256::Fr> = (0..len).map(|_| rng.gen()).collect();
+        let split = len / 3;
+
+        let mut sponge = PoseidonSponge::<Bn256>::new(hash_params);
+        sponge.absorb(&input[..split]);
+        sponge.absorb(&input[split..]);
+        let squeezed: Vec<_> = (0..6).map(|_| sponge.squeeze()).collect();
+        assert_eq!(squeezed[0], PoseidonSponge::<Bn256>::hash(hash_params, &input));
+
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let allocated: Vec<_> = input.iter().enumerate().map(|(i, value)| {
+            AllocatedNum::alloc(cs.namespace(|| format!("input {}", i)), || Ok(*value)).unwrap()
+        }).collect();
+        let mut gadget = SpongeGadget::new::<TestConstraintSystem<Bn256>>(hash_params);
+        gadget.absorb(cs.namespace(|| "absorb first"), &allocated[..split]).unwrap();
+        gadget.absorb(cs.namespace(|| "absorb rest"), &allocated[split..]).unwrap();
+        for (i, value) in squeezed.iter().enumerate() {
+            let output = gadget.squeeze(cs.namespace(|| format!("squeeze {}", i))).unwrap();
+            assert_eq!(output.get_value(), Some(*value));
+        }
+        assert!(cs.is_satisfied());
+    }
+
+    // absorbing after a squeeze pads again, in and out of the circuit
+    let input: Vec<bn256::Fr> = (0..3).map(|_| rng.gen()).collect();
+    let mut sponge = PoseidonSponge::<Bn256>::new(hash_params);
+    sponge.absorb(&input[..1]);
+    let first = sponge.squeeze();
+    sponge.absorb(&input[1..]);
+    let second = sponge.squeeze();
+    assert_ne!(second, PoseidonSponge::<Bn256>::hash(hash_params, &input));
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let allocated: Vec<_> = input.iter().enumerate().map(|(i, value)| {
+        AllocatedNum::alloc(cs.namespace(|| format!("input {}", i)), || Ok(*value)).unwrap()
+    }).collect();
+    let mut gadget = SpongeGadget::new::<TestConstraintSystem<Bn256>>(hash_params);
+    gadget.absorb(cs.namespace(|| "absorb first"), &allocated[..1]).unwrap();
+    assert_eq!(gadget.squeeze(cs.namespace(|| "squeeze first")).unwrap().get_value(), Some(first));
+    gadget.absorb(cs.namespace(|| "absorb rest"), &allocated[1..]).unwrap();
+    assert_eq!(gadget.squeeze(cs.namespace(|| "squeeze second")).unwrap().get_value(), Some(second));
+    assert!(cs.is_satisfied());
+
+    // the one of the padding tells trailing zeroes and a full block apart,
+    // poseidon_hash doesn't
+    let x: bn256::Fr = rng.gen();
+    let zero = bn256::Fr::zero();
+    let sponge_hash = |input: &[bn256::Fr]| PoseidonSponge::<Bn256>::hash(hash_params, input);
+    assert_eq!(poseidon_hash::<Bn256>(hash_params, &[x]), poseidon_hash::<Bn256>(hash_params, &[x, zero]));
+    assert_ne!(sponge_hash(&[x]), sponge_hash(&[x, zero]));
+    assert_ne!(sponge_hash(&[]), sponge_hash(&[zero]));
+    assert_ne!(sponge_hash(&[x, zero, zero, zero]), sponge_hash(&[x, zero, zero, zero, zero]));
+
+    // a deposit batch with the sponge accumulator
+    let account_depth = 2;
+    let token_depth = 1;
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let deposits: Vec<_> = (1..3).map(|i| OffchainDeposit {
+        account_id: AccountId(i),
+        pubkey: PublicKey::from_private(
+            &PrivateKey::<Bn256>(rng.gen()),
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+        ),
+        token_id: 0,
+        amount: Balance(10 * i as u128),
+    }).collect();
+
+    let old_hash: bn256::Fr = rng.gen();
+    let old_root = tree.get_root();
+    let mut accum_hash = old_hash;
+    let mut poseidon_accum_hash = old_hash;
+    let config = BatchConfig { deposit_batch: 2, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let mut builder = DepositBatchCircuit::builder(config, &params);
+    for deposit in deposits.iter() {
+        let account_state = deposit.update_tree_and_record_state(&mut tree).unwrap();
+        accum_hash = deposit.sponge_hash(accum_hash, hash_params);
+        poseidon_accum_hash = deposit.hash(poseidon_accum_hash, hash_params);
+        builder = builder.push(deposit.clone().into_circuit(account_state));
+    }
+    assert_ne!(accum_hash, poseidon_accum_hash);
+
+    let batch = builder
+        .old_accum_hash(old_hash)
+        .new_accum_hash(accum_hash)
+        .old_account_root(old_root)
+        .new_account_root(tree.get_root())
+        .build()
+        .unwrap();
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    SpongeDepositBatchCircuit { batch: batch.clone() }.synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    // the default accumulator is unchanged and doesn't take the sponge hash
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    batch.clone().synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    let mut poseidon_batch = batch.clone();
+    poseidon_batch.new_accum_hash = Some(poseidon_accum_hash);
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    poseidon_batch.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    SpongeDepositBatchCircuit { batch: poseidon_batch }.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+}
+
+#[test]
+pub fn zero_amount_rejection() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let params = shared_params();
+    let domain = SigningDomain::default();
+    let seckey = SecretKey::from_seed(b"dust");
+    let pubkey = seckey.public_key(sign_params);
+
+    let signed = |amount: u128| {
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(1), token_id: 0, amount: Balance(amount), fee: Balance(0),
+            nonce: Nonce(1), valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+        };
+        withdrawal.sign_deterministic(&seckey, &domain, None, None);
+        withdrawal
+    };
+
+    // the mempool takes MIN_AMOUNT and up, the amount is checked before the signature
+    assert_eq!(MIN_AMOUNT, Balance(1));
+    let mempool = Mempool::new(domain, SecretKey::from_seed(b"operator"));
+    assert_eq!(
+        mempool.insert(signed(0), &pubkey, Nonce(0), Balance(0)),
+        Err(MempoolError::BelowMinimum { account_id: 1, amount: Balance(0), minimum: MIN_AMOUNT }),
+    );
+    let mut unsigned = signed(0);
+    unsigned.sign = None;
+    assert!(matches!(mempool.insert(unsigned, &pubkey, Nonce(0), Balance(0)), Err(MempoolError::BelowMinimum { .. })));
+    mempool.insert(signed(1), &pubkey, Nonce(0), Balance(0)).unwrap();
+
+    let mempool = Mempool::new(domain, SecretKey::from_seed(b"operator")).with_min_amount(Balance(5));
+    assert_eq!(
+        mempool.insert(signed(4), &pubkey, Nonce(0), Balance(0)),
+        Err(MempoolError::BelowMinimum { account_id: 1, amount: Balance(4), minimum: Balance(5) }),
+    );
+    mempool.insert(signed(5), &pubkey, Nonce(0), Balance(0)).unwrap();
+    Mempool::new(domain, SecretKey::from_seed(b"operator")).with_min_amount(Balance(0)).insert(signed(0), &pubkey, Nonce(0), Balance(0)).unwrap();
+
+    // a dust op the contract recorded is in its accum hash: the queue
+    // rejects it as a deposit and keeps its place as a refund
+    let mut pubkey_bytes = [0u8; 32];
+    pubkey.write(&mut pubkey_bytes[..]).unwrap();
+    let op = |serial_id: u64, amount: u128| PriorityOp {
+        serial_id,
+        account_id: AccountId(1),
+        token_id: 0,
+        pubkey_bytes,
+        amount: Balance(amount),
+        eth_block: 1000,
+    };
+    let mut queue = PriorityQueue::new(0).with_min_amount(Balance(10));
+    assert_eq!(
+        queue.push(op(0, 9)),
+        Err(PriorityQueueError::BelowMinimum { serial_id: 0, amount: Balance(9), minimum: Balance(10) }),
+    );
+    queue.push(op(1, 10)).unwrap();
+    assert_eq!(queue.push(op(0, 10)), Err(PriorityQueueError::Duplicate(0)));
+    assert_eq!(queue.len(), 2);
+
+    let mut tree = AccountsTree::new(2, 1, hash_params, sign_params);
+    let config = BatchConfig { deposit_batch: 2, account_depth: 2, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let mut builder = BlockBuilder::new(&mut tree, config, &params, bn256::Fr::zero()).unwrap();
+    for op in queue.drain(2).unwrap().iter() {
+        builder.push_queued_op(op).unwrap();
+    }
+    let (circuit, _, pubdata) = builder.seal().unwrap();
+    assert!(matches!(
+        Pubdata::parse(pubdata.as_bytes(), sign_params).unwrap()[..],
+        [PubdataOp::Refund(ref refund), PubdataOp::Deposit(_)] if refund.amount == Balance(9),
+    ));
+    assert!(check_circuit(circuit).is_ok());
+    assert_eq!(tree.processed_ops().next_serial_id(), 2);
+    assert_eq!(tree.balance(AccountId(1), 0), Ok(Balance(10)));
+
+    // a zero deposit is provable unless the batch rejects it, noops keep
+    // their zero amount either way
+    let account_depth = 2;
+    let token_depth = 1;
+    let config = BatchConfig { deposit_batch: 3, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let batch = |amounts: &[u128], reject: bool| {
+        let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+        let old_root = tree.get_root();
+        let mut accum_hash = bn256::Fr::zero();
+        let mut builder = DepositBatchCircuit::builder(config, &params).reject_zero_amount(reject);
+        for (i, amount) in amounts.iter().enumerate() {
+            let deposit = OffchainDeposit { account_id: AccountId(i as u32), pubkey: pubkey.clone(), token_id: 0, amount: Balance(*amount) };
+            let account_state = deposit.update_tree_and_record_state(&mut tree).unwrap();
+            accum_hash = deposit.hash(accum_hash, hash_params);
+            builder = builder.push(deposit.into_circuit(account_state));
+        }
+        let builder = builder.pad_with_noops();
+        for _ in amounts.len()..config.deposit_batch {
+            accum_hash = DepositCircuit::noop(account_depth, token_depth).absorb(accum_hash, hash_params);
+        }
+        builder
+            .old_accum_hash(bn256::Fr::zero())
+            .new_accum_hash(accum_hash)
+            .old_account_root(old_root)
+            .new_account_root(tree.get_root())
+            .build()
+            .unwrap()
+    };
+    let is_satisfied = |circuit: DepositBatchCircuit<Bn256>| {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        circuit.synthesize(&mut cs).unwrap();
+        cs.is_satisfied()
+    };
+
+    assert!(is_satisfied(batch(&[1], true)));
+    assert!(is_satisfied(batch(&[0, 1], false)));
+    assert!(!is_satisfied(batch(&[0, 1], true)));
+    assert!(!is_satisfied(batch(&[1, 0], true)));
+
+    // one constraint per deposit
+    let constraints = |reject| measure(DepositBatchCircuit {
+        reject_zero_amount: reject,
+        ..DepositBatchCircuit::for_setup(config, &params)
+    }).unwrap().constraints;
+    assert_eq!(constraints(true), constraints(false) + config.deposit_batch);
+
+    // withdrawals have no noops, every amount is checked
+    let is_satisfied = |amounts: &[u128], reject: bool| {
+        let (mut circuit, _) = withdrawal_batch_with_amounts(amounts, &[1, 0], 2);
+        circuit.reject_zero_amount = reject;
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        circuit.synthesize(&mut cs).unwrap();
+        cs.is_satisfied()
+    };
+    assert!(is_satisfied(&[1, 10], true));
+    assert!(is_satisfied(&[0, 10], false));
+    assert!(!is_satisfied(&[0, 10], true));
+    assert!(!is_satisfied(&[10, 0], true));
+}
+
+#[test]
+pub fn leaf_version_migration() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let params = shared_params();
+    let mut rng = thread_rng();
+
+    // the codec puts the tag first and checks it on the way back
+    let account = LeafAccount { pubkey: rng.gen(), nonce: usize_to_fr(3), balances_root: rng.gen() };
+    let v0 = LeafVersion::V0.encode(&account);
+    let v1 = LeafVersion::V1.encode(&account);
+    assert_eq!(v0, vec![account.pubkey, account.nonce, account.balances_root]);
+    assert_eq!(v1[0], usize_to_fr(1));
+    assert_eq!(&v1[1..], &v0[..]);
+    assert_eq!(LeafVersion::V1.decode(&v1), Ok(account));
+    assert_eq!(LeafVersion::V0.decode(&v0), Ok(account));
+    assert_eq!(LeafVersion::V1.decode(&[usize_to_fr(2), v0[0], v0[1], v0[2]]), Err(LeafError::WrongTag(LeafVersion::V1).into()));
+    assert_eq!(LeafVersion::V0.decode(&v1), Err(LeafError::WrongLength {
+        version: LeafVersion::V0, expected: 3, actual: 4,
+    }.into()));
+    assert_eq!(LeafVersion::V0.next(), Some(LeafVersion::V1));
+    assert_eq!(LeafVersion::LATEST.next(), None);
+
+    // 10 accounts in a v0 tree
+    let account_depth = 4;
+    let token_depth = 1;
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let pubkeys: Vec<_> = (0..10).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )).collect();
+    for (i, pubkey) in pubkeys.iter().enumerate() {
+        OffchainDeposit {
+            account_id: AccountId(i as u32),
+            pubkey: pubkey.clone(),
+            token_id: i % 2,
+            amount: Balance(100 + i as u128),
+        }.update_tree_and_record_state(&mut tree).unwrap();
+    }
+    let snapshot = tree.export_snapshot();
+    assert_eq!(snapshot.leaf_version, LeafVersion::V0);
+    assert_eq!(snapshot.accounts.len(), 10);
+
+    // snapshots from before versioning are v0
+    let mut json: serde_json::Value = serde_json::to_value(&snapshot).unwrap();
+    json.as_object_mut().unwrap().remove("leaf_version");
+    assert_eq!(serde_json::from_value::<StateSnapshot>(json).unwrap(), snapshot);
+
+    let (migrated, transcript) = migrate_leaves(&snapshot, hash_params, sign_params).unwrap();
+    assert_eq!(migrated.leaf_version, LeafVersion::V1);
+    assert_eq!(migrated.accounts, snapshot.accounts);
+    assert_ne!(migrated.root, snapshot.root);
+    assert_eq!((transcript.old_root, transcript.new_root), (snapshot.root, migrated.root));
+    assert_eq!(transcript.accounts.len(), 10);
+    transcript.verify(hash_params).unwrap();
+
+    // the transcript survives json and a changed account no longer verifies
+    let json = serde_json::to_string(&transcript).unwrap();
+    assert_eq!(serde_json::from_str::<MigrationTranscript>(&json).unwrap(), transcript);
+    let mut tampered = transcript.clone();
+    tampered.accounts[4].1.nonce = usize_to_fr(1);
+    assert!(tampered.verify(hash_params).is_err());
+    let mut tampered = transcript.clone();
+    tampered.new_root = snapshot.root;
+    assert!(tampered.verify(hash_params).is_err());
+
+    // v1 is the latest, there is nothing to migrate to
+    assert!(migrate_leaves(&migrated, hash_params, sign_params).is_err());
+
+    // the migrated tree rehashes to the new root and keeps its version in the tree file
+    let mut tree = AccountsTree::from_snapshot(&migrated, hash_params, sign_params).unwrap();
+    assert_eq!(tree.leaf_version(), LeafVersion::V1);
+    assert_eq!(tree.get_root(), migrated.root);
+    assert_eq!(tree.prove(3).unwrap().calc_root(hash_params), migrated.root);
+    let mut bytes = Vec::new();
+    tree.write(&mut bytes, false).unwrap();
+    let loaded = AccountsTree::read(&bytes[..], hash_params, sign_params).unwrap();
+    assert_eq!(loaded.leaf_version(), LeafVersion::V1);
+    assert_eq!(loaded.get_root(), migrated.root);
+
+    // a deposit to an existing and a new account proven under v1
+    let config = BatchConfig { deposit_batch: 2, account_depth, token_depth, leaf_version: LeafVersion::V1 };
+    let v0_config = BatchConfig { leaf_version: LeafVersion::V0, ..config };
+    let deposits = [
+        OffchainDeposit { account_id: AccountId(2), pubkey: pubkeys[2].clone(), token_id: 0, amount: Balance(5) },
+        OffchainDeposit { account_id: AccountId(12), pubkey: pubkeys[0].clone(), token_id: 1, amount: Balance(7) },
+    ];
+    let old_root = tree.get_root();
+    let mut accum_hash = bn256::Fr::zero();
+    let mut builder = DepositBatchCircuit::builder(config, &params);
+    for deposit in deposits.iter() {
+        let account_state = deposit.update_tree_and_record_state(&mut tree).unwrap();
+        accum_hash = deposit.hash(accum_hash, hash_params);
+        builder = builder.push(deposit.clone().into_circuit(account_state));
+    }
+    let batch = builder
+        .old_accum_hash(bn256::Fr::zero())
+        .new_accum_hash(accum_hash)
+        .old_account_root(old_root)
+        .new_account_root(tree.get_root())
+        .build()
+        .unwrap();
+    batch.validate_witness().unwrap();
+
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    batch.clone().synthesize(&mut cs).unwrap();
+    assert!(cs.is_satisfied());
+
+    // the v0 circuit hashes the leaves untagged and misses the roots
+    let mut v0_batch = batch.clone();
+    v0_batch.leaf_version = LeafVersion::V0;
+    assert_eq!(v0_batch.validate_witness(), Err(WitnessError::OldRootMismatch { slot: 0 }));
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    v0_batch.synthesize(&mut cs).unwrap();
+    assert!(!cs.is_satisfied());
+
+    // a block builder refuses a tree of another version
+    assert!(BlockBuilder::new(&mut tree, v0_config, &params, bn256::Fr::zero()).is_err());
+
+    let mut family = CircuitFamily::new(Arc::clone(&params));
+    assert_ne!(family.register(config).unwrap().hash, family.register(v0_config).unwrap().hash);
+    let groth_params = family.generate_parameters(config, &mut rng).unwrap();
+    let public_inputs = PublicInputs::<Bn256>::new(
+        batch.old_accum_hash.unwrap(),
+        batch.new_accum_hash.unwrap(),
+        batch.old_account_root.unwrap(),
+        batch.new_account_root.unwrap(),
+    );
+    let proof = prove_deposit_block(&groth_params, batch).unwrap();
+    let verifying_key = prepare_verifying_key(&groth_params.vk);
+    let layout = family.layout(config).unwrap();
+    assert!(verify_block(&verifying_key, &proof, &layout, &public_inputs));
+}
+
+// keeps the names and fields of the spans opened while it is the default
+// subscriber, that is all instrumentation asks of it
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+struct SpanRecorder {
+    spans: Mutex<Vec<(&'static str, Vec<&'static str>)>>,
+}
+
+#[cfg(feature = "tracing")]
+struct FieldNames<'a>(&'a mut Vec<&'static str>);
+
+#[cfg(feature = "tracing")]
+impl<'a> tracing::field::Visit for FieldNames<'a> {
+    fn record_debug(&mut self, field: &tracing::field::Field, _: &dyn std::fmt::Debug) {
+        self.0.push(field.name());
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanRecorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut fields = Vec::new();
+        span.record(&mut FieldNames(&mut fields));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push((span.metadata().name(), fields));
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+        values.record(&mut FieldNames(fields));
+    }
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, _: &tracing::Event<'_>) {}
+
+    fn enter(&self, _: &tracing::span::Id) {}
+
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[test]
+pub fn instrumentation() {
+    let account_depth = 2;
+    let token_depth = 1;
+    let config = BatchConfig { deposit_batch: 2, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let params = shared_params();
+    let domain = SigningDomain::default();
+    let seckeys: Vec<_> = [b"metrics 1", b"metrics 2"].iter().map(|seed| SecretKey::from_seed(*seed)).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| seckey.public_key(jubjub_params())).collect();
+
+    let before = metrics().snapshot();
+
+    let run = || {
+        let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+        let mut builder = BlockBuilder::new(&mut tree, config, &params, bn256::Fr::zero()).unwrap();
+        for (i, pubkey) in pubkeys.iter().enumerate() {
+            let deposit = OffchainDeposit { account_id: AccountId(i as u32 + 1), pubkey: pubkey.clone(), token_id: 0, amount: Balance(10) };
+            builder.push_deposit(deposit).unwrap();
+        }
+        let (circuit, public_inputs, _) = builder.seal().unwrap();
+
+        let mut family = CircuitFamily::new(Arc::clone(&params));
+        let circuit_params = family.generate_parameters(config, &mut thread_rng()).unwrap();
+        let proof = prove_deposit_block(&circuit_params, circuit).unwrap();
+        let layout = family.layout(config).unwrap();
+        assert!(verify_block(&prepare_verifying_key(&circuit_params.vk), &proof, &layout, &public_inputs));
+
+        let update = LeafUpdate { account_id: 1, token_id: 0, pubkey: None, credit: 5, debit: 0, increment_nonce: false };
+        tree.apply_batch(&[update]).unwrap();
+
+        // signed by the other account
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(1), token_id: 0, amount: Balance(5), fee: Balance(1),
+            nonce: Nonce(1), valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+        };
+        withdrawal.sign_deterministic(&seckeys[1], &domain, None, None);
+        assert_eq!(
+            Mempool::new(domain, SecretKey::from_seed(b"operator")).insert(withdrawal, &pubkeys[0], Nonce(0), Balance(5)),
+            Err(MempoolError::InvalidSignature(SignatureError::VerificationFailed.into())),
+        );
+    };
+
+    #[cfg(feature = "tracing")]
+    {
+        let recorder = Arc::new(SpanRecorder::default());
+        tracing::subscriber::with_default(Arc::clone(&recorder), run);
+
+        let spans = recorder.spans.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names.iter().filter(|name| **name == "witness.deposit").count(), 2);
+        for name in ["block_builder.seal", "prover.prove", "prover.verify", "tree.apply_batch"] {
+            assert!(names.contains(&name), "no {} span", name);
+        }
+        // the sizes when opened, the duration when closed
+        let (_, seal_fields) = spans.iter().find(|(name, _)| *name == "block_builder.seal").unwrap();
+        assert_eq!(seal_fields, &vec!["batch", "deposits", "depth", "duration_us"]);
+    }
+    #[cfg(not(feature = "tracing"))]
+    run();
+
+    // other tests run alongside and count too
+    let after = metrics().snapshot();
+    assert!(after.ops_processed >= before.ops_processed + 2);
+    assert!(after.signatures_rejected > before.signatures_rejected);
+    assert!(after.proofs_generated > before.proofs_generated);
+    let names: Vec<_> = after.counters().iter().map(|(name, _)| *name).collect();
+    assert_eq!(names, vec![
+        "openplasma_ops_processed_total",
+        "openplasma_signatures_rejected_total",
+        "openplasma_proofs_generated_total",
+    ]);
+}
+
+#[test]
+pub fn padded_withdrawal_batch() {
+    let hash_params = poseidon_params();
+    let domain = SigningDomain::default();
+    let sign_params = jubjub_params();
+    let account_depth = 2;
+    let token_depth = 1;
+
+    // the generic pubkey the circuit compares with is the one of the key
+    assert_eq!(
+        pack_point(&padding_pubkey::<Bn256>(sign_params).0),
+        pack_point(&padding_secret_key().public_key(sign_params).0),
+    );
+
+    let seckeys: Vec<_> = [b"padding 1", b"padding 2"].iter().map(|seed| SecretKey::from_seed(*seed)).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| seckey.public_key(sign_params)).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    for (i, pubkey) in pubkeys.iter().enumerate() {
+        OffchainDeposit { account_id: AccountId(i as u32), pubkey: pubkey.clone(), token_id: 0, amount: Balance(100) }
+            .update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    let old_hash = bn256::Fr::zero();
+    let old_root = tree.get_root();
+    let mut accum_hash = old_hash;
+    let mut queue = Vec::new();
+    for (i, seckey) in seckeys.iter().enumerate() {
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(i as u32), token_id: 0, amount: Balance(10), fee: Balance(1),
+            nonce: Nonce(1), valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+        };
+        withdrawal.sign_deterministic(seckey, &domain, None, None);
+        accum_hash = withdrawal.record_hash(accum_hash, hash_params);
+        let account_state = withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+        queue.push(OffchainWithdrawalCircuit::<Bn256> {
+            account_state,
+            account_id: Some(withdrawal.account_id.to_fr()),
+            token_id: Some(usize_to_fr(withdrawal.token_id)),
+            amount: Some(withdrawal.amount.to_fr()),
+            fee: Some(withdrawal.fee.to_fr()),
+            nonce: Some(withdrawal.nonce.to_fr()),
+            valid_until: Some(usize_to_fr(withdrawal.valid_until)),
+            eth_address: Some(address_to_fr(&withdrawal.eth_address)),
+            sign: withdrawal.sign.clone(),
+            pubkey: Some(pubkeys[i].0.clone()),
+            is_noop: Some(false),
+        });
+    }
+
+    // 2 real and 2 padded withdrawals, the noops change neither the accum
+    // hash nor the root
+    let noop = OffchainWithdrawalCircuit::noop(account_depth, token_depth, &domain, hash_params, sign_params);
+    queue.extend([noop.clone(), noop.clone()]);
+    let fee_account_state = credit_fee_and_record_state(&mut tree, AccountId(2), 0, Balance(2)).unwrap();
+
+    let circuit = OffchainWithdrawalBatchCircuit {
+        batch_size: 4,
+        account_depth,
+        token_depth,
+        hash_params,
+        sign_params,
+        signing_domain: domain,
+        reject_zero_amount: true,
+        queue,
+        fee_account_state,
+        fee_account_id: Some(usize_to_fr(2)),
+        fee_token_id: Some(usize_to_fr(0)),
+        total_fee: Some(usize_to_fr(2)),
+        timestamp: Some(usize_to_fr(0)),
+        old_accum_hash: Some(old_hash),
+        new_accum_hash: Some(accum_hash),
+        old_account_root: Some(old_root),
+        new_account_root: Some(tree.get_root()),
+    };
+    circuit.validate_witness().unwrap();
+    let padded = check_circuit(circuit.clone()).unwrap();
+
+    // a batch of noops alone has the same shape
+    let mut empty_tree = AccountsTree::new(account_depth, token_depth, hash_params, sign_params);
+    let empty_root = empty_tree.get_root();
+    let fee_account_state = credit_fee_and_record_state(&mut empty_tree, AccountId(2), 0, Balance(0)).unwrap();
+    let only_noops = OffchainWithdrawalBatchCircuit {
+        queue: vec![noop.clone(); 4],
+        fee_account_state,
+        total_fee: Some(bn256::Fr::zero()),
+        new_accum_hash: Some(old_hash),
+        old_account_root: Some(empty_root),
+        new_account_root: Some(empty_tree.get_root()),
+        ..circuit.clone()
+    };
+    only_noops.validate_witness().unwrap();
+    assert_eq!(check_circuit(only_noops).unwrap().num_constraints, padded.num_constraints);
+
+    // a noop signed by a real account is refused, even for the zero record
+    let mut foreign = noop.clone();
+    let mut record = OffchainWithdrawal {
+        account_id: AccountId(0), token_id: 0, amount: Balance(0), fee: Balance(0),
+        nonce: Nonce(0), valid_until: 0, eth_address: [0; ADDRESS_BYTES], sign: None,
+    };
+    record.sign_deterministic(&seckeys[0], &domain, None, None);
+    foreign.sign = record.sign;
+    foreign.pubkey = Some(pubkeys[0].0.clone());
+    foreign.account_state.old_pubkey = Some(pubkeys[0].0.clone());
+    foreign.account_state.new_pubkey = Some(pubkeys[0].0.clone());
+    let mut with_foreign = circuit.clone();
+    with_foreign.queue[3] = foreign;
+    assert!(matches!(
+        with_foreign.validate_witness(),
+        Err(WitnessError::InconsistentLeaf { slot: 3, reason: "noop is not signed with the padding key" }),
+    ));
+    assert!(check_circuit(with_foreign).unwrap_err().path.contains("check noop pubkey"));
+
+    // a real withdrawal flagged as a noop would skip its leaf, its record isn't zero
+    let mut skipped = circuit.clone();
+    skipped.queue[0].is_noop = Some(true);
+    assert!(matches!(
+        skipped.validate_witness(),
+        Err(WitnessError::InconsistentLeaf { slot: 0, reason: "noop record is not zero" }),
+    ));
+    assert!(check_circuit(skipped).is_err());
+
+    // nor can a noop pay anything out
+    let mut paying = circuit;
+    paying.queue[2].amount = Some(usize_to_fr(5));
+    assert!(paying.validate_witness().is_err());
+    assert!(check_circuit(paying).is_err());
+}
+
+#[test]
+pub fn risk_limits() {
+    let domain = SigningDomain::default();
+    let account_depth = 2;
+    let token_depth = 1;
+    let config = BatchConfig { deposit_batch: 3, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let params = shared_params();
+    let seckeys: Vec<_> = [b"limits 1", b"limits 2"].iter().map(|seed| SecretKey::from_seed(*seed)).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| seckey.public_key(jubjub_params())).collect();
+    let deposit = |account: u32, amount: u128| OffchainDeposit {
+        account_id: AccountId(account), pubkey: pubkeys[account as usize - 1].clone(), token_id: 0, amount: Balance(amount),
+    };
+
+    let limits = Limits::default()
+        .with_max_withdrawal(Balance(50))
+        .with_max_balance(Balance(150))
+        .with_max_block_value(Balance(95));
+    let json = serde_json::to_string(&limits).unwrap();
+    assert_eq!(serde_json::from_str::<Limits>(&json).unwrap(), limits);
+    assert_eq!(Limits::default().max_balance, None);
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    for account in 1..=2 {
+        deposit(account, 100).update_tree_and_record_state(&mut tree).unwrap();
+    }
+
+    // max balance is checked on the balance after the deposit: 100 + 60 is
+    // refused, 100 + 50 is the limit itself
+    let root = tree.get_root();
+    let mut builder = BlockBuilder::new(&mut tree, config, &params, bn256::Fr::zero()).unwrap().with_limits(limits);
+    assert!(matches!(
+        builder.push_deposit(deposit(1, 60)),
+        Err(OpenPlasmaError::Block(BlockError::LimitExceeded(LimitViolation::MaxBalance { account_id: 1, token_id: 0, balance: Balance(160), limit: Balance(150) }))),
+    ));
+    assert!(builder.is_empty());
+    builder.push_deposit(deposit(1, 50)).unwrap();
+
+    // max block value on the sum of the block, the deposit over it is
+    // deferred to the next block
+    builder.push_deposit(deposit(2, 40)).unwrap();
+    let deferred = deposit(2, 10);
+    let refused = builder.push_deposit(deferred.clone()).unwrap_err();
+    assert_eq!(refused.to_string(), "Block error: Limit exceeded: Block value 100 would be above the limit 95");
+    assert_eq!(builder.len(), 2);
+    let (_, public_inputs, _) = builder.seal().unwrap();
+    assert_ne!(public_inputs.new_account_root, root);
+
+    let mut next = BlockBuilder::new(&mut tree, config, &params, public_inputs.new_accum_hash).unwrap().with_limits(limits);
+    next.push_deposit(deferred).unwrap();
+    next.seal().unwrap();
+    assert_eq!(tree.balance(AccountId(1), 0), Ok(Balance(150)));
+    assert_eq!(tree.balance(AccountId(2), 0), Ok(Balance(150)));
+
+    // priority ops can't be deferred past each other: one over a limit ends
+    // the block and goes first into the next one. ops come in serial id order
+    let mut pubkey_bytes = [0u8; 32];
+    pubkeys[1].write(&mut pubkey_bytes[..]).unwrap();
+    let op = |serial_id: u64, amount: u128| PriorityOp {
+        serial_id, account_id: AccountId(2), token_id: 0, pubkey_bytes, amount: Balance(amount), eth_block: 1,
+    };
+    let block_limits = Limits::default().with_max_block_value(Balance(95));
+    let mut ops_tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let mut builder = BlockBuilder::new(&mut ops_tree, config, &params, bn256::Fr::zero()).unwrap().with_limits(block_limits);
+    builder.push_deposit(deposit(1, 50)).unwrap();
+    assert!(matches!(
+        builder.push_priority_op(&op(0, 60)),
+        Err(OpenPlasmaError::Block(BlockError::LimitEndsBlock(LimitViolation::MaxBlockValue { value: Balance(110), limit: Balance(95) }))),
+    ));
+    assert!(matches!(builder.push_deposit(deposit(1, 1)), Err(OpenPlasmaError::Block(BlockError::BlockFull))));
+    assert_eq!(builder.len(), 1);
+    let (_, public_inputs, _) = builder.seal().unwrap();
+    assert!(!ops_tree.processed_ops().contains(0));
+
+    let mut next = BlockBuilder::new(&mut ops_tree, config, &params, public_inputs.new_accum_hash).unwrap().with_limits(block_limits);
+    assert!(matches!(
+        next.push_priority_op(&op(1, 60)),
+        Err(OpenPlasmaError::Block(BlockError::PriorityOpOutOfOrder { expected: 0, actual: 1 })),
+    ));
+    next.push_priority_op(&op(0, 60)).unwrap();
+    assert!(matches!(next.push_priority_op(&op(1, 100)), Err(OpenPlasmaError::Block(BlockError::LimitEndsBlock(_)))));
+    let (_, public_inputs, _) = next.seal().unwrap();
+
+    // alone over the limit it is not applied in an empty block either, it
+    // stays next until it is refunded
+    let mut alone = BlockBuilder::new(&mut ops_tree, config, &params, public_inputs.new_accum_hash).unwrap().with_limits(block_limits);
+    assert!(matches!(
+        alone.push_priority_op(&op(1, 100)),
+        Err(OpenPlasmaError::Block(BlockError::LimitExceeded(LimitViolation::MaxBlockValue { value: Balance(100), limit: Balance(95) }))),
+    ));
+    assert!(alone.is_empty());
+    alone.refund_priority_op(&op(1, 100)).unwrap();
+    let (circuit, _, pubdata) = alone.seal().unwrap();
+    assert!(check_circuit(circuit).is_ok());
+    assert!(matches!(Pubdata::parse(pubdata.as_bytes(), jubjub_params()).unwrap()[..], [PubdataOp::Refund(_)]));
+    assert_eq!(ops_tree.processed_ops().next_serial_id(), 2);
+    assert_eq!(ops_tree.balance(AccountId(2), 0), Ok(Balance(60)));
+
+    // the mempool refuses a withdrawal above max withdrawal or above a whole
+    // block before checking the signature
+    let signed = |account: u32, nonce: u32, amount: u128| {
+        let mut withdrawal = OffchainWithdrawal {
+            account_id: AccountId(account), token_id: 0, amount: Balance(amount), fee: Balance(0),
+            nonce: Nonce(nonce), valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+        };
+        withdrawal.sign_deterministic(&seckeys[account as usize - 1], &domain, None, None);
+        withdrawal
+    };
+    let mempool = Mempool::new(domain, SecretKey::from_seed(b"operator")).with_limits(limits);
+    assert_eq!(
+        mempool.insert(signed(1, 1, 51), &pubkeys[0], Nonce(0), Balance(150)),
+        Err(MempoolError::LimitExceeded(LimitViolation::MaxWithdrawal { account_id: 1, amount: Balance(51), limit: Balance(50) })),
+    );
+    let over_block = Mempool::new(domain, SecretKey::from_seed(b"operator")).with_limits(Limits::default().with_max_block_value(Balance(30)));
+    assert_eq!(
+        over_block.insert(signed(1, 1, 40), &pubkeys[0], Nonce(0), Balance(150)),
+        Err(MempoolError::LimitExceeded(LimitViolation::MaxBlockValue { value: Balance(40), limit: Balance(30) })),
+    );
+
+    // take_batch stops at max block value, the rest waits for the next batch
+    for (account, nonce) in [(1, 1), (1, 2), (2, 1)] {
+        let balance = tree.balance(AccountId(account), 0).unwrap();
+        mempool.insert(signed(account, nonce, 40), &pubkeys[account as usize - 1], Nonce(0), balance).unwrap();
+    }
+    let batch = mempool.take_batch(10, &tree, 0).unwrap();
+    let taken: Vec<_> = batch.iter().map(|withdrawal| (withdrawal.account_id.0, withdrawal.nonce.0)).collect();
+    assert_eq!(taken, vec![(1, 1), (2, 1)]);
+    assert_eq!(mempool.len(), 1);
+    for withdrawal in batch {
+        withdrawal.update_tree_and_record_state(&mut tree).unwrap();
+    }
+    let batch = mempool.take_batch(10, &tree, 0).unwrap();
+    assert_eq!(batch.len(), 1);
+    assert_eq!((batch[0].account_id.0, batch[0].nonce.0), (1, 2));
+}
+
+#[test]
+pub fn pubdata_audit() {
+    let hash_params = poseidon_params();
+    let sign_params = jubjub_params();
+    let config = BatchConfig { deposit_batch: 3, account_depth: 2, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let params = shared_params();
+    let domain = SigningDomain::default();
+    let fee_account_id = AccountId(0);
+
+    let seckeys: Vec<_> = [b"audit 1", b"audit 2"].iter().map(|seed| SecretKey::from_seed(*seed)).collect();
+    let pubkeys: Vec<_> = seckeys.iter().map(|seckey| seckey.public_key(sign_params)).collect();
+    let deposit = |account: usize, amount: u128| OffchainDeposit {
+        account_id: AccountId(account as u32 + 1), pubkey: pubkeys[account].clone(), token_id: 0, amount: Balance(amount),
+    };
+
+    // a deposit block proven with the sha256 accumulator, whose accum hash is
+    // the pubdata commitment
+    let mut tree = AccountsTree::new(config.account_depth, config.token_depth, hash_params, sign_params);
+    let before = tree.clone();
+    let old_hash = bn256::Fr::zero();
+    let mut builder = BlockBuilder::new(&mut tree, config, &params, old_hash).unwrap();
+    builder.push_deposit(deposit(0, 100)).unwrap();
+    builder.push_deposit(deposit(1, 50)).unwrap();
+    let (circuit, poseidon_inputs, pubdata) = builder.seal().unwrap();
+    let public_inputs = PublicInputs::<Bn256>::new(
+        old_hash,
+        pubdata.commitment(old_hash),
+        poseidon_inputs.old_account_root,
+        poseidon_inputs.new_account_root,
+    );
+    let sha256_batch = Sha256DepositBatchCircuit { batch: DepositBatchCircuit {
+        new_accum_hash: Some(public_inputs.new_accum_hash),
+        ..circuit
+    } };
+    assert_eq!(check_circuit(sha256_batch.clone()).map(|_| ()), Ok(()));
+
+    verify_block_against_pubdata(&before, &pubdata, &public_inputs, DepositAccumulator::Sha256, 1, fee_account_id, 0, sign_params).unwrap();
+    assert_eq!(before.get_root(), public_inputs.old_account_root);
+
+    // the witness and the public inputs stay correct and the circuit is
+    // satisfied, the published pubdata says 51 in place of 50
+    let mut bytes = pubdata.as_bytes().to_vec();
+    bytes[2 * OFFCHAIN_DEPOSIT_BYTES - 16] ^= 1;
+    let corrupted = Pubdata::from_bytes(&bytes).unwrap();
+    assert!(check_circuit(sha256_batch).is_ok());
+    match verify_block_against_pubdata(&before, &corrupted, &public_inputs, DepositAccumulator::Sha256, 1, fee_account_id, 0, sign_params) {
+        Err(AuditError::NewRootMismatch { expected, actual }) => {
+            assert_eq!(expected, public_inputs.new_account_root);
+            assert_ne!(actual, expected);
+        },
+        other => panic!("audit of the corrupted pubdata gave {:?}", other),
+    }
+
+    // an operation that doesn't decode is named by its index
+    let mut bytes = pubdata.as_bytes().to_vec();
+    let pubkey_start = OFFCHAIN_DEPOSIT_BYTES + HEADER_BYTES + 4;
+    bytes[pubkey_start..pubkey_start + 32].copy_from_slice(&[0xff; 32]);
+    let malformed = Pubdata::from_bytes(&bytes).unwrap();
+    assert!(matches!(
+        verify_block_against_pubdata(&before, &malformed, &public_inputs, DepositAccumulator::Sha256, 1, fee_account_id, 0, sign_params),
+        Err(AuditError::Malformed { index: 1, error: OpenPlasmaError::Encoding(EncodingError::InvalidPoint) }),
+    ));
+
+    // the block builder's poseidon accum hash absorbs the noop it padded the
+    // batch with, the audit needs the accumulator and the noop count
+    verify_block_against_pubdata(&before, &pubdata, &poseidon_inputs, DepositAccumulator::Poseidon, 1, fee_account_id, 0, sign_params).unwrap();
+    for (accumulator, noops) in [(DepositAccumulator::Poseidon, 0), (DepositAccumulator::Sponge, 1), (DepositAccumulator::Sha256, 1)] {
+        let err = verify_block_against_pubdata(&before, &pubdata, &poseidon_inputs, accumulator, noops, fee_account_id, 0, sign_params).unwrap_err();
+        assert!(matches!(err, AuditError::AccumMismatch { .. }));
+    }
+
+    // the replay runs on a throwaway tree of the accounts the block touches,
+    // with the roots of the whole state
+    let touched = tree.with_accounts(&[1].iter().cloned().collect());
+    assert_eq!(touched.get_root(), tree.get_root());
+    assert!(touched.accounts_tree.num_stored_nodes() < tree.accounts_tree.num_stored_nodes());
+    assert_eq!(touched.accounts.num_stored(), 1);
+
+    // the state has to be the one the block starts from
+    assert!(matches!(
+        verify_block_against_pubdata(&tree, &pubdata, &public_inputs, DepositAccumulator::Sha256, 1, fee_account_id, 0, sign_params),
+        Err(AuditError::OldRootMismatch { .. }),
+    ));
+
+    // an operation that doesn't apply is named with the root it was applied at
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(1000), fee: Balance(1), nonce: Nonce(1),
+        valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    withdrawal.sign_deterministic(&seckeys[0], &domain, None, None);
+    let mut overdrawn = Pubdata::default();
+    overdrawn.push_deposit(&deposit(1, 10)).unwrap();
+    overdrawn.push_withdrawal(&withdrawal).unwrap();
+    let mut after_deposit = tree.clone();
+    deposit(1, 10).update_tree_and_record_state(&mut after_deposit).unwrap();
+    let next_inputs = PublicInputs::<Bn256>::new(
+        public_inputs.new_accum_hash,
+        overdrawn.commitment(public_inputs.new_accum_hash),
+        tree.get_root(),
+        tree.get_root(),
+    );
+    let err = verify_block_against_pubdata(&tree, &overdrawn, &next_inputs, DepositAccumulator::Sha256, 1, fee_account_id, 0, sign_params).unwrap_err();
+    match &err {
+        AuditError::Inapplicable { index: 1, root, .. } => assert_eq!(*root, after_deposit.get_root()),
+        other => panic!("audit of the overdrawn block gave {:?}", other),
+    }
+    assert!(err.to_string().starts_with("Operation 1 doesn't apply at root "));
+    assert!(matches!(
+        verify_block_against_pubdata(&tree, &overdrawn, &next_inputs, DepositAccumulator::Poseidon, 1, fee_account_id, 0, sign_params),
+        Err(AuditError::NotADeposit { index: 1 }),
+    ));
+}
+
+#[test]
+pub fn path_cache() {
+    let hash_params = poseidon_params();
+    let mut rng = thread_rng();
+
+    // cached paths are the paths of the refreshed tree after any updates
+    for &arity in [2, 4].iter() {
+        let leaves: Vec<_> = (0..64).map(|_| vec![rng.gen::<bn256::Fr>()]).collect();
+        let mut cached = PoseidonMerkleTree::<Bn256>::new_with_arity(leaves.clone(), arity, hash_params);
+        let mut uncached = PoseidonMerkleTree::<Bn256>::new_with_arity(leaves, arity, hash_params);
+        let mut cache = PathCache::default();
+        for _ in 0..40 {
+            let leaf_index = rng.gen_range(0, 4) * 17 % 64;
+            let path = cached.cached_leaf_path(&mut cache, leaf_index);
+            assert_eq!(path, uncached.get_leaf_path(leaf_index));
+
+            let leaf = vec![rng.gen::<bn256::Fr>()];
+            cached.set_cached_leaf(&mut cache, leaf_index, leaf.clone());
+            uncached.update_leaf(leaf_index, leaf);
+        }
+        cached.refresh();
+        assert_eq!(cached.root(), uncached.root());
+    }
+
+    // 16 deposits to 2 accounts, the block builder's witnesses and root are
+    // those of applying the deposits one by one
+    let (account_depth, token_depth) = (4, 1);
+    let config = BatchConfig { deposit_batch: 16, account_depth, token_depth, leaf_version: LeafVersion::V0 };
+    let params = shared_params();
+    let pubkeys: Vec<_> = (0..2).map(|_| PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        jubjub_params(),
+    )).collect();
+    let deposits: Vec<_> = (0..16).map(|i| OffchainDeposit {
+        account_id: AccountId([3, 12][i % 3 / 2]),
+        pubkey: pubkeys[i % 3 / 2].clone(),
+        token_id: i % 2,
+        amount: Balance(i as u128 + 1),
+    }).collect();
+
+    let mut tree = AccountsTree::new(account_depth, token_depth, poseidon_params(), jubjub_params());
+    let mut uncached = tree.clone();
+    let states: Vec<_> = deposits.iter().map(
+        |deposit| deposit.update_tree_and_record_state(&mut uncached).unwrap()
+    ).collect();
+
+    let mut builder = BlockBuilder::new(&mut tree, config, &params, bn256::Fr::zero()).unwrap();
+    for deposit in deposits.iter() {
+        builder.push_deposit(deposit.clone()).unwrap();
+    }
+    let (circuit, public_inputs, _) = builder.seal().unwrap();
+    let witnesses = circuit.deposit_queue.witnesses().unwrap();
+    for (witness, state) in witnesses.iter().zip(states.iter()) {
+        assert!(witness.account_state == *state);
+    }
+    assert_eq!(public_inputs.new_account_root, uncached.get_root());
+    assert_eq!(tree.get_root(), uncached.get_root());
+
+    // a builder dropped unsealed leaves the tree rehashed
+    let mut builder = BlockBuilder::new(&mut tree, config, &params, public_inputs.new_accum_hash).unwrap();
+    builder.push_deposit(deposits[0].clone()).unwrap();
+    drop(builder);
+    deposits[0].update_tree_and_record_state(&mut uncached).unwrap();
+    assert_eq!(tree.get_root(), uncached.get_root());
+}
+
+#[test]
+pub fn receipts() {
+    let domain = SigningDomain::default();
+    let seckey = SecretKey::from_seed(b"receipt account");
+    let pubkey = seckey.public_key(jubjub_params());
+    let operator_pubkey = SecretKey::from_seed(b"operator").public_key(jubjub_params());
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: AccountId(1), token_id: 0, amount: Balance(10), fee: Balance(1),
+        nonce: Nonce(1), valid_until: 0, eth_address: ETH_ADDRESS, sign: None,
+    };
+    withdrawal.sign_deterministic(&seckey, &domain, None, None);
+
+    // the request is promised by the last of the inclusion blocks
+    let mempool = Mempool::new(domain, SecretKey::from_seed(b"operator")).with_inclusion_blocks(3).unwrap();
+    mempool.set_next_block(7);
+    let receipt = mempool.insert(withdrawal.clone(), &pubkey, Nonce(0), Balance(11)).unwrap();
+    assert_eq!(receipt.verify(&operator_pubkey), Ok(()));
+    assert_eq!((receipt.account_id, receipt.nonce, receipt.promised_block), (AccountId(1), Nonce(1), Some(9)));
+    assert!(receipt.covers(&withdrawal, &domain));
+    assert_eq!(receipt, Receipt::for_withdrawal(&withdrawal, &domain, Some(9), &SecretKey::from_seed(b"operator")));
+    assert_eq!(receipt.verify(&pubkey), Err(SignatureError::VerificationFailed.into()));
+
+    // a refused request gets no receipt
+    assert!(matches!(mempool.insert(withdrawal.clone(), &pubkey, Nonce(0), Balance(11)), Err(MempoolError::Duplicate { .. })));
+
+    // a request the next batch can't execute is accepted without a block:
+    // behind a gap, underfunded, or over max block value with the promised
+    // requests before it
+    let next = |nonce: u32, amount: u128| {
+        let mut next = OffchainWithdrawal { nonce: Nonce(nonce), amount: Balance(amount), ..withdrawal.clone() };
+        next.sign_deterministic(&seckey, &domain, None, None);
+        next
+    };
+    let gapped = mempool.insert(next(3, 10), &pubkey, Nonce(0), Balance(100)).unwrap();
+    assert_eq!((gapped.promised_block, gapped.verify(&operator_pubkey)), (None, Ok(())));
+    let fresh = || Mempool::new(domain, SecretKey::from_seed(b"operator")).with_inclusion_blocks(3).unwrap();
+    assert!(matches!(
+        Mempool::new(domain, SecretKey::from_seed(b"operator")).with_inclusion_blocks(0),
+        Err(OpenPlasmaError::Block(BlockError::NoInclusionBlocks))
+    ));
+    assert_eq!(fresh().insert(withdrawal.clone(), &pubkey, Nonce(0), Balance(10)).unwrap().promised_block, None);
+    let limited = fresh().with_limits(Limits::default().with_max_block_value(Balance(15)));
+    assert_eq!(limited.insert(next(1, 10), &pubkey, Nonce(0), Balance(100)).unwrap().promised_block, Some(2));
+    let other_seckey = SecretKey::from_seed(b"receipt account 2");
+    let mut over = OffchainWithdrawal { account_id: AccountId(2), ..withdrawal.clone() };
+    over.sign_deterministic(&other_seckey, &domain, None, None);
+    let other_pubkey = other_seckey.public_key(jubjub_params());
+    assert_eq!(limited.insert(over.clone(), &other_pubkey, Nonce(0), Balance(100)).unwrap().promised_block, None);
+
+    // every batch is a block, the next nonce is promised the block after,
+    // against the balance the batch left
+    let mut tree = AccountsTree::new(2, 1, poseidon_params(), jubjub_params());
+    OffchainDeposit { account_id: AccountId(1), pubkey: pubkey.clone(), token_id: 0, amount: Balance(22) }
+        .update_tree_and_record_state(&mut tree).unwrap();
+    assert!(matches!(mempool.take_batch(0, &tree, 0), Err(MempoolError::BatchTooSmall { requested: 0, promised: 1 })));
+    assert_eq!(mempool.take_batch(10, &tree, 0).unwrap().len(), 1);
+    assert_eq!(mempool.insert(next(2, 10), &pubkey, Nonce(0), Balance(22)).unwrap().promised_block, Some(10));
+    let underfunded = fresh();
+    underfunded.insert(withdrawal.clone(), &pubkey, Nonce(0), Balance(22)).unwrap();
+    underfunded.take_batch(10, &tree, 0).unwrap();
+    assert_eq!(underfunded.insert(next(2, 11), &pubkey, Nonce(0), Balance(22)).unwrap().promised_block, None);
+
+    // every field is signed
+    let tampered = [
+        Receipt { promised_block: Some(20), ..receipt.clone() },
+        Receipt { promised_block: None, ..receipt.clone() },
+        Receipt { nonce: Nonce(2), ..receipt.clone() },
+        Receipt { account_id: AccountId(2), ..receipt.clone() },
+        Receipt { request_hash: bn256::Fr::one(), ..receipt.clone() },
+    ];
+    for receipt in tampered.iter() {
+        assert_eq!(receipt.verify(&operator_pubkey), Err(SignatureError::VerificationFailed.into()));
+    }
+    let mut other = withdrawal.clone();
+    other.amount = Balance(11);
+    assert!(!receipt.covers(&other, &domain));
+
+    // bytes
+    let bytes = receipt.encode();
+    assert_eq!(bytes.len(), RECEIPT_BYTES);
+    assert_eq!(Receipt::decode(&bytes, None).unwrap(), receipt);
+    assert_eq!(
+        Receipt::decode(&bytes[..RECEIPT_BYTES - 1], None).err(),
+        Some(EncodingError::Truncated { expected: RECEIPT_BYTES, actual: RECEIPT_BYTES - 1 }.into()),
+    );
+    let mut flipped = bytes.clone();
+    flipped[HEADER_BYTES + 32 + 4 + 4] ^= 1;
+    let decoded = Receipt::decode(&flipped, None).unwrap();
+    assert_eq!(decoded.promised_block, Some(10));
+    assert_eq!(decoded.verify(&operator_pubkey), Err(SignatureError::VerificationFailed.into()));
+    let mut overflow = bytes.clone();
+    overflow[HEADER_BYTES..HEADER_BYTES + 32].copy_from_slice(&[0xff; 32]);
+    assert_eq!(Receipt::decode(&overflow, None).err(), Some(EncodingError::ValueOutOfRange("request hash").into()));
+
+    // json
+    let json = serde_json::to_string(&receipt).unwrap();
+    let decoded: Receipt = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, receipt);
+    let tampered_json = json.replace("\"promised_block\":9", "\"promised_block\":10");
+    assert_ne!(tampered_json, json);
+    let decoded: Receipt = serde_json::from_str(&tampered_json).unwrap();
+    assert_eq!(decoded.verify(&operator_pubkey), Err(SignatureError::VerificationFailed.into()));
+}
+
+#[test]
+pub fn genesis() {
+    let config = BatchConfig { deposit_batch: 2, account_depth: 3, token_depth: 1, leaf_version: LeafVersion::V0 };
+    let params = shared_params();
+    let pubkeys: Vec<_> = [b"genesis 1", b"genesis 2", b"genesis 3"].iter().map(
+        |seed| SecretKey::from_seed(*seed).public_key(jubjub_params())
+    ).collect();
+
+    let genesis = Genesis::new(3, 1)
+        .with_account(GenesisAccount::new(AccountId(1), &pubkeys[0]).with_balance(0, Balance(1000)).with_balance(1, Balance(5)))
+        .with_account(GenesisAccount::new(AccountId(4), &pubkeys[1]).with_balance(1, Balance(70)))
+        .with_account(GenesisAccount::new(AccountId(7), &pubkeys[2]));
+
+    // the committed file rebuilds to the recorded root
+    let file: Genesis = serde_json::from_str(include_str!("genesis.json")).unwrap();
+    assert_eq!(file, genesis);
+    let (mut tree, root) = file.build().unwrap();
+    assert_eq!(fr_to_hex(&root), "0x03f5abc5e1c8c83d0e2852f7ee1476e285621042511a30bb20a15e36bb058ffa");
+    assert_eq!(tree.get_root(), root);
+
+    // the same tree as depositing the balances by hand
+    let mut by_hand = AccountsTree::new(3, 1, poseidon_params(), jubjub_params());
+    for (account, pubkey) in genesis.accounts.iter().zip(pubkeys.iter()) {
+        let deposit = |token_id: usize, amount: Balance| OffchainDeposit { account_id: account.account_id, pubkey: pubkey.clone(), token_id, amount };
+        deposit(0, Balance(0)).update_tree_and_record_state(&mut by_hand).unwrap();
+        for (token_id, amount) in account.balances.iter() {
+            deposit(*token_id, *amount).update_tree_and_record_state(&mut by_hand).unwrap();
+        }
+    }
+    assert_eq!(by_hand.get_root(), root);
+    assert_eq!(tree.account_id_by_pubkey(&pubkeys[1]), Some(4));
+
+    // refused before anything is hashed
+    let with = |change: &dyn Fn(&mut Genesis)| {
+        let mut changed = genesis.clone();
+        change(&mut changed);
+        changed.build().err()
+    };
+    assert_eq!(with(&|genesis| genesis.accounts[2].account_id = AccountId(4)), Some(TreeError::DuplicateAccount(4).into()));
+    assert_eq!(with(&|genesis| genesis.accounts[2].account_id = AccountId(8)), Some(TreeError::AccountOutOfRange(8).into()));
+    assert_eq!(
+        with(&|genesis| { genesis.accounts[0].balances.insert(2, Balance(1)); }),
+        Some(TreeError::TokenOutOfRange(2).into()),
+    );
+    assert_eq!(with(&|genesis| genesis.accounts[1].pubkey_y.add_assign(&bn256::Fr::one())), Some(TreeError::InvalidPubkey(4).into()));
+    assert_eq!(
+        with(&|genesis| { genesis.accounts[1].pubkey_x = bn256::Fr::zero(); genesis.accounts[1].pubkey_y = bn256::Fr::one(); }),
+        Some(TreeError::InvalidPubkey(4).into()),
+    );
+    assert_eq!(with(&|genesis| genesis.depth = 33), Some(TreeError::DepthTooLarge(33).into()));
+
+    // the block builder takes it like any other tree
+    let mut builder = BlockBuilder::new(&mut tree, config, &params, bn256::Fr::zero()).unwrap();
+    builder.push_deposit(OffchainDeposit { account_id: AccountId(4), pubkey: pubkeys[1].clone(), token_id: 0, amount: Balance(3) }).unwrap();
+    let (_, public_inputs, _) = builder.seal().unwrap();
+    assert_eq!(public_inputs.old_account_root, root);
+    assert_eq!(tree.balance(AccountId(4), 0), Ok(Balance(3)));
 }